@@ -7,9 +7,10 @@ use quote::quote;
 fn main() -> Result<(), Box<dyn Error>> {
     let out_dir = std::env::var_os("OUT_DIR").unwrap();
 
-    read_rgb_response(&Path::new(&out_dir))?;
-    read_xyz_response(&Path::new(&out_dir))?;
-    read_light_sources(&Path::new(&out_dir))?;
+    read_rgb_response(Path::new(&out_dir))?;
+    read_xyz_response(Path::new(&out_dir))?;
+    read_light_sources(Path::new(&out_dir))?;
+    read_metals(Path::new(&out_dir))?;
 
     println!("cargo:rerun-if-changed=build.rs");
     Ok(())
@@ -75,7 +76,7 @@ fn read_xyz_response(out_dir: &Path) -> Result<(), Box<dyn Error>> {
     let mut y_response = vec![];
     let mut z_response = vec![];
 
-    let mut min_wavelength = std::f32::INFINITY;
+    let mut min_wavelength = f32::INFINITY;
     let mut max_wavelength = 0.0f32;
 
     println!("cargo:rerun-if-changed=data/ciexyz65_1.csv");
@@ -135,9 +136,10 @@ struct XyzResponse {
 
 fn read_light_sources(out_dir: &Path) -> Result<(), Box<dyn Error>> {
     let mut d65_spectrum = vec![];
+    let mut d65_points = vec![];
     let mut a_spectrum = vec![];
 
-    let mut min_wavelength = std::f32::INFINITY;
+    let mut min_wavelength = f32::INFINITY;
     let mut max_wavelength = 0.0f32;
 
     println!("cargo:rerun-if-changed=data/d65.csv");
@@ -151,6 +153,7 @@ fn read_light_sources(out_dir: &Path) -> Result<(), Box<dyn Error>> {
         max_wavelength = max_wavelength.max(wavelength);
 
         d65_spectrum.push(quote!(#intensity));
+        d65_points.push((wavelength, intensity));
     }
 
     println!("cargo:rerun-if-changed=data/a.csv");
@@ -167,6 +170,47 @@ fn read_light_sources(out_dir: &Path) -> Result<(), Box<dyn Error>> {
         a_spectrum.push(quote!(#intensity));
     }
 
+    // The fluorescent light sources are spiky, so they are scaled to the
+    // same luminance as D65, instead of to a value at one wavelength.
+    let luminance_response = read_luminance_response()?;
+    let d65_luminance = |min: f32, max: f32| {
+        let points: Vec<_> = d65_points
+            .iter()
+            .cloned()
+            .filter(|&(wavelength, _)| wavelength >= min && wavelength <= max)
+            .collect();
+        luminance(&points, &luminance_response)
+    };
+
+    let mut fluorescent = vec![];
+    for name in &["f2", "f7", "f11"] {
+        let path = format!("data/{}.csv", name);
+        println!("cargo:rerun-if-changed={}", path);
+
+        let mut points = vec![];
+        for record_result in csv::Reader::from_path(&path)?.deserialize() {
+            let LightIntensity {
+                wavelength,
+                intensity,
+            } = record_result?;
+            points.push((wavelength, intensity));
+        }
+
+        let min = points.first().map_or(0.0, |&(wavelength, _)| wavelength);
+        let max = points.last().map_or(0.0, |&(wavelength, _)| wavelength);
+        let scale = d65_luminance(min, max) / luminance(&points, &luminance_response);
+        let values = points.iter().map(|&(_, intensity)| intensity * scale);
+
+        let name = quote::format_ident!("{}", name.to_uppercase());
+        fluorescent.push(quote! {
+            pub const #name: Spectrum = Spectrum::Array {
+                min: #min,
+                max: #max,
+                points: Cow::Borrowed(&[#(#values),*])
+            };
+        });
+    }
+
     fs::write(
         out_dir.join("light_source.rs"),
         quote! {
@@ -184,6 +228,8 @@ fn read_light_sources(out_dir: &Path) -> Result<(), Box<dyn Error>> {
                     max: #max_wavelength,
                     points: Cow::Borrowed(&[#(#a_spectrum),*])
                 };
+
+                #(#fluorescent)*
         }
         .to_string(),
     )?;
@@ -191,8 +237,143 @@ fn read_light_sources(out_dir: &Path) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// The Y response of the standard observer, for each whole wavelength.
+fn read_luminance_response() -> Result<Vec<(f32, f32)>, Box<dyn Error>> {
+    let mut response = vec![];
+    for record_result in csv::Reader::from_path("data/ciexyz65_1.csv")?.deserialize() {
+        let XyzResponse { wavelength, y, .. } = record_result?;
+        response.push((wavelength, y));
+    }
+
+    Ok(response)
+}
+
+/// The sum of a spectrum's points, weighted by the luminance response at
+/// their wavelengths.
+fn luminance(points: &[(f32, f32)], response: &[(f32, f32)]) -> f32 {
+    points
+        .iter()
+        .map(|&(wavelength, intensity)| {
+            let y = response
+                .iter()
+                .find(|&&(response_wavelength, _)| response_wavelength == wavelength)
+                .map_or(0.0, |&(_, y)| y);
+            intensity * y
+        })
+        .sum()
+}
+
 #[derive(Debug, Deserialize)]
 struct LightIntensity {
     wavelength: f32,
     intensity: f32,
 }
+
+fn read_metals(out_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let MetalData {
+        min_wavelength: gold_min,
+        max_wavelength: gold_max,
+        n: gold_n,
+        k: gold_k,
+    } = read_metal("data/gold.csv")?;
+    let MetalData {
+        min_wavelength: copper_min,
+        max_wavelength: copper_max,
+        n: copper_n,
+        k: copper_k,
+    } = read_metal("data/copper.csv")?;
+    let MetalData {
+        min_wavelength: aluminum_min,
+        max_wavelength: aluminum_max,
+        n: aluminum_n,
+        k: aluminum_k,
+    } = read_metal("data/aluminum.csv")?;
+
+    fs::write(
+        out_dir.join("metal.rs"),
+        quote! {
+                use std::borrow::Cow;
+                use crate::project::spectra::Spectrum;
+
+                pub const GOLD_N: Spectrum = Spectrum::Array {
+                    min: #gold_min,
+                    max: #gold_max,
+                    points: Cow::Borrowed(&[#(#gold_n),*])
+                };
+                pub const GOLD_K: Spectrum = Spectrum::Array {
+                    min: #gold_min,
+                    max: #gold_max,
+                    points: Cow::Borrowed(&[#(#gold_k),*])
+                };
+
+                pub const COPPER_N: Spectrum = Spectrum::Array {
+                    min: #copper_min,
+                    max: #copper_max,
+                    points: Cow::Borrowed(&[#(#copper_n),*])
+                };
+                pub const COPPER_K: Spectrum = Spectrum::Array {
+                    min: #copper_min,
+                    max: #copper_max,
+                    points: Cow::Borrowed(&[#(#copper_k),*])
+                };
+
+                pub const ALUMINUM_N: Spectrum = Spectrum::Array {
+                    min: #aluminum_min,
+                    max: #aluminum_max,
+                    points: Cow::Borrowed(&[#(#aluminum_n),*])
+                };
+                pub const ALUMINUM_K: Spectrum = Spectrum::Array {
+                    min: #aluminum_min,
+                    max: #aluminum_max,
+                    points: Cow::Borrowed(&[#(#aluminum_k),*])
+                };
+        }
+        .to_string(),
+    )?;
+
+    Ok(())
+}
+
+/// Reads the complex index of refraction of a metal, which has to be
+/// sampled at even intervals.
+fn read_metal(path: &str) -> Result<MetalData, Box<dyn Error>> {
+    let mut n_spectrum = vec![];
+    let mut k_spectrum = vec![];
+
+    let mut min_wavelength = f32::INFINITY;
+    let mut max_wavelength = 0.0f32;
+
+    println!("cargo:rerun-if-changed={}", path);
+    for record_result in csv::Reader::from_path(path)?.deserialize() {
+        let ComplexIor { wavelength, n, k } = record_result?;
+
+        min_wavelength = min_wavelength.min(wavelength);
+        max_wavelength = max_wavelength.max(wavelength);
+
+        n_spectrum.push(n);
+        k_spectrum.push(k);
+    }
+
+    Ok(MetalData {
+        min_wavelength,
+        max_wavelength,
+        n: n_spectrum,
+        k: k_spectrum,
+    })
+}
+
+/// The wavelength range and the evenly spaced samples of a metal's complex
+/// index of refraction.
+struct MetalData {
+    min_wavelength: f32,
+    max_wavelength: f32,
+    n: Vec<f32>,
+    k: Vec<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComplexIor {
+    wavelength: f32,
+    n: f32,
+    k: f32,
+}
@@ -0,0 +1,69 @@
+use std::{
+    error::Error,
+    ffi::OsString,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+/// Writes a file by letting `write` create a temporary file next to it, and
+/// then replacing the old file with it. The old file is left untouched until
+/// the new one is complete and synced to the disk, so a crash or a power loss
+/// can't leave a half written file behind.
+pub(crate) fn write_atomically<T, E: Into<Box<dyn Error>>>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, E>,
+) -> Result<T, Box<dyn Error>> {
+    let temporary_path = temporary_path(path);
+
+    let result = write(&temporary_path)
+        .map_err(Into::into)
+        .and_then(|result| {
+            File::open(&temporary_path)?.sync_all()?;
+            fs::rename(&temporary_path, path)?;
+            Ok(result)
+        });
+
+    match result {
+        Ok(result) => {
+            sync_directory(path);
+            Ok(result)
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&temporary_path);
+            Err(error)
+        }
+    }
+}
+
+/// The temporary file has the same extension as the final file, since some
+/// writers pick the file format from it.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(path.file_stem().unwrap_or_default());
+    file_name.push(".partial");
+
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+
+    path.with_file_name(file_name)
+}
+
+/// Makes the rename itself durable. It's only possible on Unix, and a failure
+/// only means that the old file may come back after a power loss.
+#[cfg(unix)]
+fn sync_directory(path: &Path) {
+    let directory = match path.parent() {
+        Some(directory) if directory.as_os_str().is_empty() => Path::new("."),
+        Some(directory) => directory,
+        None => return,
+    };
+
+    if let Ok(directory) = File::open(directory) {
+        let _ = directory.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) {}
@@ -0,0 +1,118 @@
+use std::error::Error;
+
+use crate::{
+    project::{meshes::Meshes, textures::Textures, WorldObject},
+    shapes::Shape,
+    spatial::bvh::Bvh,
+};
+
+/// Textures are not downscaled below this size.
+const MIN_TEXTURE_SIZE: usize = 64;
+
+/// Parses a number of bytes, with an optional `K`, `M`, `G` or `T` suffix
+/// for binary kilo-, mega-, giga- and terabytes.
+pub(crate) fn parse_size(value: &str) -> Result<usize, Box<dyn Error>> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().find(|(_, c)| c.is_alphabetic()) {
+        Some((index, _)) => (&value[..index], &value[index..]),
+        None => (value, ""),
+    };
+
+    let multiplier: usize = match &*unit.to_ascii_uppercase() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("unknown size unit: '{}'", unit).into()),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: '{}'", value))?;
+
+    if number <= 0.0 {
+        return Err("the size has to be positive".into());
+    }
+
+    Ok((number * multiplier as f64) as usize)
+}
+
+/// Estimates how much memory the scene will use when it's built. This covers
+/// the source meshes and textures, as well as the triangles and the BVH.
+pub(crate) fn estimate_scene_memory(
+    objects: &[WorldObject],
+    meshes: &Meshes,
+    textures: &Textures,
+) -> usize {
+    let triangles: usize = objects
+        .iter()
+        .map(|object| match *object {
+            WorldObject::Mesh { file, .. } => meshes.triangle_count(file),
+            _ => 0,
+        })
+        .sum();
+    let shapes = objects.len() + triangles;
+
+    meshes.memory_size()
+        + textures.memory_size()
+        + shapes * std::mem::size_of::<Shape>()
+        + Bvh::<&Shape>::estimate_memory_size(shapes)
+}
+
+/// Simplifies the scene until its estimated size fits within the budget, by
+/// quantizing the mesh vertices and downscaling the textures. The budget is
+/// per render thread, so it's multiplied by `threads`.
+pub(crate) fn fit_scene(
+    thread_budget: usize,
+    threads: usize,
+    objects: &[WorldObject],
+    meshes: &mut Meshes,
+    textures: &mut Textures,
+) {
+    let budget = thread_budget.saturating_mul(threads.max(1));
+    let mut estimate = estimate_scene_memory(objects, meshes, textures);
+    if estimate <= budget {
+        return;
+    }
+
+    println!(
+        "warning: the scene is estimated to use {}, which is more than the budget of {} ({} for each of {} threads)",
+        crate::format_bytes(estimate),
+        crate::format_bytes(budget),
+        crate::format_bytes(thread_budget),
+        threads
+    );
+
+    let (quantized, removed) = meshes.quantize();
+    if quantized > 0 {
+        println!(
+            "warning: quantized the vertices of {} meshes to 16 bits per axis, removing {} triangles",
+            quantized, removed
+        );
+        estimate = estimate_scene_memory(objects, meshes, textures);
+    }
+
+    while estimate > budget {
+        let resized_textures = textures.downscale(MIN_TEXTURE_SIZE);
+        if resized_textures == 0 {
+            break;
+        }
+
+        println!("warning: downscaled {} textures", resized_textures);
+        estimate = estimate_scene_memory(objects, meshes, textures);
+    }
+
+    if estimate > budget {
+        println!(
+            "warning: the simplified scene is still estimated to use {}",
+            crate::format_bytes(estimate)
+        );
+    } else {
+        println!(
+            "the simplified scene is estimated to use {}",
+            crate::format_bytes(estimate)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use cgmath::Point3;
+use collision::Aabb3;
+
+/// The corners of a box, as offsets from its minimum corner in units of its
+/// size.
+const CORNERS: [[f32; 3]; 8] = [
+    [0.0, 0.0, 0.0],
+    [1.0, 0.0, 0.0],
+    [1.0, 1.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0],
+    [0.0, 1.0, 1.0],
+];
+
+/// The edges of a box, as pairs of corner indices.
+const EDGES: [[usize; 2]; 12] = [
+    [0, 1],
+    [1, 2],
+    [2, 3],
+    [3, 0],
+    [4, 5],
+    [5, 6],
+    [6, 7],
+    [7, 4],
+    [0, 4],
+    [1, 5],
+    [2, 6],
+    [3, 7],
+];
+
+/// Writes the bounding boxes as a wireframe. The format is PLY if the file
+/// name ends with `.ply`, and OBJ otherwise.
+pub(crate) fn write_wireframe(
+    path: &Path,
+    boxes: &[(usize, Aabb3<f32>)],
+) -> Result<(), Box<dyn Error>> {
+    let is_ply = path
+        .extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("ply"));
+
+    let mut file = BufWriter::new(File::create(path)?);
+
+    if is_ply {
+        write_ply(&mut file, boxes)?;
+    } else {
+        write_obj(&mut file, boxes)?;
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+/// Each depth gets its own group, so they can be toggled separately.
+fn write_obj(file: &mut impl Write, boxes: &[(usize, Aabb3<f32>)]) -> Result<(), Box<dyn Error>> {
+    writeln!(file, "# {} bounding boxes", boxes.len())?;
+
+    let mut current_depth = None;
+
+    for (box_index, &(depth, aabb)) in boxes.iter().enumerate() {
+        if current_depth != Some(depth) {
+            writeln!(file, "g depth_{}", depth)?;
+            current_depth = Some(depth);
+        }
+
+        for corner in &corners(aabb) {
+            writeln!(file, "v {} {} {}", corner.x, corner.y, corner.z)?;
+        }
+
+        // OBJ indices start at 1.
+        let first_vertex = box_index * CORNERS.len() + 1;
+        for &[from, to] in &EDGES {
+            writeln!(file, "l {} {}", first_vertex + from, first_vertex + to)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The depth of each box is stored as a vertex property.
+fn write_ply(file: &mut impl Write, boxes: &[(usize, Aabb3<f32>)]) -> Result<(), Box<dyn Error>> {
+    writeln!(file, "ply")?;
+    writeln!(file, "format ascii 1.0")?;
+    writeln!(file, "element vertex {}", boxes.len() * CORNERS.len())?;
+    writeln!(file, "property float x")?;
+    writeln!(file, "property float y")?;
+    writeln!(file, "property float z")?;
+    writeln!(file, "property uint depth")?;
+    writeln!(file, "element edge {}", boxes.len() * EDGES.len())?;
+    writeln!(file, "property int vertex1")?;
+    writeln!(file, "property int vertex2")?;
+    writeln!(file, "end_header")?;
+
+    for &(depth, aabb) in boxes {
+        for corner in &corners(aabb) {
+            writeln!(file, "{} {} {} {}", corner.x, corner.y, corner.z, depth)?;
+        }
+    }
+
+    for box_index in 0..boxes.len() {
+        let first_vertex = box_index * CORNERS.len();
+        for &[from, to] in &EDGES {
+            writeln!(file, "{} {}", first_vertex + from, first_vertex + to)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn corners(aabb: Aabb3<f32>) -> [Point3<f32>; 8] {
+    let size = aabb.max - aabb.min;
+    let mut corners = [aabb.min; 8];
+
+    for (corner, offset) in corners.iter_mut().zip(&CORNERS) {
+        corner.x += size.x * offset[0];
+        corner.y += size.y * offset[1];
+        corner.z += size.z * offset[2];
+    }
+
+    corners
+}
@@ -11,7 +11,6 @@ use collision::{Ray, Ray3};
 use crate::film::Area;
 
 use crate::{
-    math::DIST_EPSILON,
     project::eval_context::{EvalContext, Evaluate, EvaluateOr},
     world::World,
 };
@@ -22,6 +21,10 @@ pub(crate) enum Camera {
         view_plane: f32,
         focus_distance: f32,
         aperture: f32,
+
+        /// Anamorphic squeeze factor. Widens the horizontal field of view
+        /// and makes the bokeh elliptical.
+        squeeze: f32,
     },
 }
 
@@ -36,16 +39,25 @@ impl Camera {
                 fov,
                 focus_distance,
                 aperture,
+                squeeze,
             } => {
                 let fov: f32 = fov.evaluate(eval_context)?;
                 let fov_radians: Rad<_> = cgmath::Deg(fov * 0.5f32).into();
                 let view_plane = fov_radians.cos() / fov_radians.sin();
 
+                let squeeze: f32 = squeeze.evaluate_or(eval_context, 1.0)?;
+                if squeeze <= 0.0 {
+                    return Err(
+                        format!("the camera squeeze must be positive, got {}", squeeze).into(),
+                    );
+                }
+
                 Ok(Camera::Perspective {
                     transform: transform.evaluate(eval_context)?,
                     view_plane,
                     focus_distance: focus_distance.evaluate_or(eval_context, 1.0)?,
                     aperture: aperture.evaluate_or(eval_context, 0.0)?,
+                    squeeze,
                 })
             }
         }
@@ -64,6 +76,14 @@ impl Camera {
         Area::new(from, size)
     }
 
+    /// Roughly how much the rays through a pixel spread out, as the width of
+    /// a pixel at a distance of 1.
+    pub fn pixel_spread(&self, width: usize, height: usize) -> f32 {
+        match *self {
+            Camera::Perspective { view_plane, .. } => 2.0 / (width.max(height) as f32 * view_plane),
+        }
+    }
+
     pub fn ray_towards<R: Rng>(&self, target: &Point2<f32>, rng: &mut R) -> Ray3<f32> {
         match *self {
             Camera::Perspective {
@@ -71,8 +91,9 @@ impl Camera {
                 view_plane,
                 focus_distance,
                 aperture,
+                squeeze,
             } => {
-                let focus_x = target.x / view_plane * focus_distance;
+                let focus_x = target.x * squeeze / view_plane * focus_distance;
                 let focus_y = target.y / view_plane * focus_distance;
 
                 let target = Point3::new(focus_x, -focus_y, -focus_distance);
@@ -80,7 +101,7 @@ impl Camera {
                 let (origin, direction) = if aperture > 0.0 {
                     let sqrt_r = (aperture * rng.gen::<f32>()).sqrt();
                     let psi = consts::PI * 2.0 * rng.gen::<f32>();
-                    let lens_x = sqrt_r * psi.cos();
+                    let lens_x = sqrt_r * psi.cos() / squeeze;
                     let lens_y = sqrt_r * psi.sin();
                     let origin = Point3::new(lens_x, lens_y, 0.0);
                     (origin, target - origin)
@@ -105,6 +126,7 @@ impl Camera {
                 view_plane,
                 focus_distance,
                 aperture,
+                squeeze,
             } => {
                 let inv_transform = if let Some(t) = transform.invert() {
                     t
@@ -121,7 +143,7 @@ impl Camera {
                 let origin = if aperture > 0.0 {
                     let sqrt_r = (aperture * rng.gen::<f32>()).sqrt();
                     let psi = consts::PI * 2.0 * rng.gen::<f32>();
-                    let lens_x = sqrt_r * psi.cos();
+                    let lens_x = sqrt_r * psi.cos() / squeeze;
                     let lens_y = sqrt_r * psi.sin();
                     Point3::new(lens_x, lens_y, 0.0)
                 } else {
@@ -133,7 +155,7 @@ impl Camera {
                 let distance = direction.magnitude();
                 let ray = Ray::new(world_origin, direction / distance);
                 if let Some(hit) = world.intersect(ray) {
-                    if hit.distance < distance - DIST_EPSILON {
+                    if hit.distance < distance - world.ray_epsilon(target, distance) {
                         return None;
                     }
                 }
@@ -146,7 +168,7 @@ impl Camera {
                 let view_plane_target = -local_target / local_target.z;
                 let focus_x = view_plane_target.x;
                 let focus_y = -view_plane_target.y;
-                let target_x = focus_x * view_plane;
+                let target_x = focus_x * view_plane / squeeze;
                 let target_y = focus_y * view_plane;
 
                 Some((Point2::new(target_x, target_y), ray))
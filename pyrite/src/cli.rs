@@ -0,0 +1,704 @@
+use std::{error::Error, time::Duration};
+
+use cgmath::{Point2, Vector2};
+
+use crate::{
+    examples::Example, film::Area, notify::Webhook, renderer::default_threads,
+    stream::StreamTarget, tonemap::Tonemap,
+};
+
+/// Overrides the number of render threads when `--threads` isn't used.
+const THREADS_VARIABLE: &str = "PYRITE_THREADS";
+
+pub(crate) enum Command {
+    Render(Options),
+    MergeRegion(MergeRegionOptions),
+    Merge(MergeOptions),
+    Develop(DevelopOptions),
+    ExportBvh(ExportBvhOptions),
+    Queue(QueueOptions),
+    New(NewOptions),
+    Diff(DiffOptions),
+    SelfTest,
+}
+
+impl Command {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut args = args.peekable();
+
+        match args.peek().map(|arg| &**arg) {
+            Some("merge-region") => {
+                args.next();
+                MergeRegionOptions::parse(args).map(Command::MergeRegion)
+            }
+            Some("merge") => {
+                args.next();
+                MergeOptions::parse(args).map(Command::Merge)
+            }
+            Some("develop") => {
+                args.next();
+                DevelopOptions::parse(args).map(Command::Develop)
+            }
+            Some("export-bvh") => {
+                args.next();
+                ExportBvhOptions::parse(args).map(Command::ExportBvh)
+            }
+            Some("queue") => {
+                args.next();
+                QueueOptions::parse(args).map(Command::Queue)
+            }
+            Some("new") => {
+                args.next();
+                NewOptions::parse(args).map(Command::New)
+            }
+            Some("diff") => {
+                args.next();
+                DiffOptions::parse(args).map(Command::Diff)
+            }
+            Some("selftest") => Ok(Command::SelfTest),
+            _ => Options::parse(args).map(Command::Render),
+        }
+    }
+}
+
+pub(crate) struct Options {
+    pub project_path: String,
+    pub dry_run: bool,
+    pub watch: bool,
+    pub frames: Option<(u32, u32)>,
+    pub region: Option<Area<usize>>,
+    pub film_path: Option<String>,
+    pub resume: bool,
+    pub exr: bool,
+    pub exr_mipmaps: bool,
+    pub bands: Option<usize>,
+    pub memory_budget: Option<usize>,
+    pub trace_pixel: Option<Point2<usize>>,
+    pub trace_file: Option<String>,
+    pub threads: Option<usize>,
+    pub low_priority: bool,
+    pub cpu_limit: Option<u32>,
+    /// Stop the render if a tile panics, instead of skipping the tile.
+    pub strict: bool,
+    pub notify_webhook: Option<Webhook>,
+    pub stream: Option<StreamTarget>,
+    pub profile: Option<Profile>,
+    /// Multiplies the world's quality, for faster or more detailed renders.
+    pub quality: Option<f32>,
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut project_path = None;
+        let mut dry_run = false;
+        let mut watch = false;
+        let mut frames = None;
+        let mut region = None;
+        let mut film_path = None;
+        let mut resume = false;
+        let mut exr = false;
+        let mut exr_mipmaps = false;
+        let mut bands = None;
+        let mut memory_budget = None;
+        let mut trace_pixel = None;
+        let mut trace_file = None;
+        let mut threads = None;
+        let mut low_priority = false;
+        let mut cpu_limit = None;
+        let mut strict = false;
+        let mut notify_webhook = None;
+        let mut stream = None;
+        let mut profile = None;
+        let mut quality = None;
+
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--dry-run" => dry_run = true,
+                "--watch" => watch = true,
+                "--frame" => {
+                    let value = args
+                        .next()
+                        .ok_or("--frame: missing frame number or range")?;
+                    frames =
+                        Some(parse_frames(&value).map_err(|error| format!("--frame: {}", error))?);
+                }
+                "--region" => {
+                    let value = args.next().ok_or("--region: missing region")?;
+                    region =
+                        Some(parse_region(&value).map_err(|error| format!("--region: {}", error))?);
+                }
+                "--film" => film_path = Some(args.next().ok_or("--film: missing file path")?),
+                "--resume" => resume = true,
+                "--bands" => {
+                    let value = args.next().ok_or("--bands: missing number of bands")?;
+                    match value.parse() {
+                        Ok(count) if count > 0 => bands = Some(count),
+                        _ => return Err(format!("--bands: invalid number: '{}'", value).into()),
+                    }
+                }
+                "--memory-budget" => {
+                    let value = args.next().ok_or("--memory-budget: missing size")?;
+                    memory_budget = Some(
+                        crate::budget::parse_size(&value)
+                            .map_err(|error| format!("--memory-budget: {}", error))?,
+                    );
+                }
+                "--trace-pixel" => {
+                    let value = args.next().ok_or("--trace-pixel: missing pixel")?;
+                    trace_pixel = Some(
+                        parse_pixel(&value).map_err(|error| format!("--trace-pixel: {}", error))?,
+                    );
+                }
+                "--trace-file" => {
+                    trace_file = Some(args.next().ok_or("--trace-file: missing file path")?)
+                }
+                "--threads" => {
+                    let value = args.next().ok_or("--threads: missing number of threads")?;
+                    threads = Some(
+                        parse_threads(&value).map_err(|error| format!("--threads: {}", error))?,
+                    );
+                }
+                "--low-priority" => low_priority = true,
+                "--strict" => strict = true,
+                "--cpu-limit" => {
+                    let value = args.next().ok_or("--cpu-limit: missing percentage")?;
+                    match value.trim_end_matches('%').parse() {
+                        Ok(percent) if percent > 0 && percent <= 100 => cpu_limit = Some(percent),
+                        _ => {
+                            return Err(format!(
+                                "--cpu-limit: expected a percentage between 1 and 100, got '{}'",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "--notify-webhook" => {
+                    let value = args.next().ok_or("--notify-webhook: missing URL")?;
+                    notify_webhook = Some(
+                        Webhook::parse(&value)
+                            .map_err(|error| format!("--notify-webhook: {}", error))?,
+                    );
+                }
+                "--stream" => {
+                    let value = args.next().ok_or("--stream: missing URL")?;
+                    stream = Some(
+                        StreamTarget::parse(&value)
+                            .map_err(|error| format!("--stream: {}", error))?,
+                    );
+                }
+                "--profile" => {
+                    let value = args.next().ok_or("--profile: missing profile name")?;
+                    profile = Some(
+                        Profile::parse(&value).map_err(|error| format!("--profile: {}", error))?,
+                    );
+                }
+                "--quality" => {
+                    let value = args.next().ok_or("--quality: missing multiplier")?;
+                    match value.parse::<f32>() {
+                        Ok(multiplier) if multiplier > 0.0 && multiplier.is_finite() => {
+                            quality = Some(multiplier)
+                        }
+                        _ => {
+                            return Err(format!(
+                                "--quality: expected a positive number, got '{}'",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "--exr" => exr = true,
+                "--exr-mipmaps" => {
+                    exr = true;
+                    exr_mipmaps = true;
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option: {}", flag).into())
+                }
+                path => {
+                    if project_path.is_some() {
+                        return Err("only one project file can be rendered at a time".into());
+                    }
+
+                    project_path = Some(path.to_owned());
+                }
+            }
+        }
+
+        if watch && (dry_run || frames.is_some() || memory_budget.is_some()) {
+            return Err(
+                "--watch can't be combined with --dry-run, --frame or --memory-budget".into(),
+            );
+        }
+
+        if bands.is_some() && (watch || region.is_some() || film_path.is_some()) {
+            return Err("--bands can't be combined with --watch, --region or --film".into());
+        }
+
+        if trace_pixel.is_some() && (watch || bands.is_some()) {
+            return Err("--trace-pixel can't be combined with --watch or --bands".into());
+        }
+
+        if resume && (film_path.is_none() || watch) {
+            return Err("--resume requires --film and can't be combined with --watch".into());
+        }
+
+        if trace_file.is_some() && trace_pixel.is_none() {
+            return Err("--trace-file requires --trace-pixel".into());
+        }
+
+        if threads.is_none() {
+            if let Ok(value) = std::env::var(THREADS_VARIABLE) {
+                threads = Some(
+                    parse_threads(&value)
+                        .map_err(|error| format!("{}: {}", THREADS_VARIABLE, error))?,
+                );
+            }
+        }
+
+        Ok(Options {
+            project_path: project_path.ok_or("missing project file")?,
+            dry_run,
+            watch,
+            frames,
+            region,
+            film_path,
+            resume,
+            exr,
+            exr_mipmaps,
+            bands,
+            memory_budget,
+            trace_pixel,
+            trace_file,
+            threads,
+            low_priority,
+            cpu_limit,
+            strict,
+            notify_webhook,
+            stream,
+            profile,
+            quality,
+        })
+    }
+}
+
+/// Defaults for different kinds of machines, that are used where neither the
+/// command line nor the project sets anything else.
+#[derive(Copy, Clone)]
+pub(crate) enum Profile {
+    /// Leaves room for other programs and saves battery.
+    Laptop,
+    /// Leaves a core for the user.
+    Workstation,
+    /// Uses the whole machine, without anyone watching the image.
+    Farm,
+}
+
+impl Profile {
+    fn parse(value: &str) -> Result<Self, Box<dyn Error>> {
+        match value {
+            "laptop" => Ok(Profile::Laptop),
+            "workstation" => Ok(Profile::Workstation),
+            "farm" => Ok(Profile::Farm),
+            _ => Err(format!(
+                "unknown profile: '{}', expected laptop, workstation or farm",
+                value
+            )
+            .into()),
+        }
+    }
+
+    pub fn threads(self) -> usize {
+        match self {
+            Profile::Laptop => (default_threads() / 2).max(1),
+            Profile::Workstation => (default_threads() - 1).max(1),
+            Profile::Farm => num_cpus::get(),
+        }
+    }
+
+    pub fn tile_size(self) -> usize {
+        match self {
+            Profile::Laptop => 16,
+            Profile::Workstation => 32,
+            Profile::Farm => 64,
+        }
+    }
+
+    /// How often the image is saved while rendering.
+    pub fn image_interval(self) -> Duration {
+        match self {
+            Profile::Laptop => Duration::from_secs(60),
+            Profile::Workstation => Duration::from_secs(20),
+            Profile::Farm => Duration::from_secs(300),
+        }
+    }
+
+    /// How many bytes of textures are kept between frames and reloads.
+    pub fn texture_cache_size(self) -> usize {
+        match self {
+            Profile::Laptop => 512 << 20,
+            Profile::Workstation => 2 << 30,
+            Profile::Farm => 8 << 30,
+        }
+    }
+}
+
+pub(crate) struct MergeRegionOptions {
+    pub project_path: String,
+    pub film_path: String,
+    pub region_paths: Vec<String>,
+}
+
+impl MergeRegionOptions {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut paths = args.collect::<Vec<_>>().into_iter();
+
+        let project_path = paths.next().ok_or("missing project file")?;
+        let film_path = paths.next().ok_or("missing film file")?;
+        let region_paths: Vec<_> = paths.collect();
+
+        if region_paths.is_empty() {
+            return Err("missing region film files".into());
+        }
+
+        Ok(MergeRegionOptions {
+            project_path,
+            film_path,
+            region_paths,
+        })
+    }
+}
+
+pub(crate) struct MergeOptions {
+    pub output_path: String,
+    pub film_paths: Vec<String>,
+    /// An image is developed as well, if it's set.
+    pub project_path: Option<String>,
+}
+
+impl MergeOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut output_path = None;
+        let mut film_paths = Vec::new();
+        let mut project_path = None;
+
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--output" => output_path = Some(args.next().ok_or("--output: missing file path")?),
+                "--project" => {
+                    project_path = Some(args.next().ok_or("--project: missing project file")?)
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option: {}", flag).into())
+                }
+                path => film_paths.push(path.to_owned()),
+            }
+        }
+
+        if film_paths.is_empty() {
+            return Err("missing film files".into());
+        }
+
+        Ok(MergeOptions {
+            output_path: output_path.ok_or("missing --output film file")?,
+            film_paths,
+            project_path,
+        })
+    }
+}
+
+pub(crate) struct DevelopOptions {
+    pub film_path: String,
+    /// The project's filter and white balance are used if it's set.
+    pub project_path: Option<String>,
+    pub output_path: Option<String>,
+    pub tonemap: Tonemap,
+    /// In stops, where each stop doubles the brightness.
+    pub exposure: f32,
+    /// An image to place behind the render's uncovered pixels.
+    pub backplate_path: Option<String>,
+    /// In stops, like `exposure`.
+    pub backplate_exposure: f32,
+}
+
+impl DevelopOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut film_path = None;
+        let mut project_path = None;
+        let mut output_path = None;
+        let mut tonemap = Tonemap::Clamp;
+        let mut exposure = 0.0;
+        let mut backplate_path = None;
+        let mut backplate_exposure = 0.0;
+
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--project" => {
+                    project_path = Some(args.next().ok_or("--project: missing project file")?)
+                }
+                "--output" => output_path = Some(args.next().ok_or("--output: missing file path")?),
+                "--tonemap" => {
+                    let value = args.next().ok_or("--tonemap: missing tone mapping")?;
+                    tonemap =
+                        Tonemap::parse(&value).map_err(|error| format!("--tonemap: {}", error))?;
+                }
+                "--exposure" => {
+                    let value = args.next().ok_or("--exposure: missing exposure")?;
+                    exposure = value
+                        .parse()
+                        .map_err(|_| format!("--exposure: invalid exposure: '{}'", value))?;
+                }
+                "--backplate" => {
+                    backplate_path = Some(args.next().ok_or("--backplate: missing image file")?)
+                }
+                "--backplate-exposure" => {
+                    let value = args
+                        .next()
+                        .ok_or("--backplate-exposure: missing exposure")?;
+                    backplate_exposure = value.parse().map_err(|_| {
+                        format!("--backplate-exposure: invalid exposure: '{}'", value)
+                    })?;
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option: {}", flag).into())
+                }
+                path if film_path.is_none() => film_path = Some(path.to_owned()),
+                _ => return Err("only one film file can be developed at a time".into()),
+            }
+        }
+
+        Ok(DevelopOptions {
+            film_path: film_path.ok_or("missing film file")?,
+            project_path,
+            output_path,
+            tonemap,
+            exposure,
+            backplate_path,
+            backplate_exposure,
+        })
+    }
+}
+
+pub(crate) struct ExportBvhOptions {
+    pub project_path: String,
+    pub output_path: String,
+    pub depth: Option<usize>,
+    pub frame: Option<u32>,
+}
+
+impl ExportBvhOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut paths = Vec::new();
+        let mut depth = None;
+        let mut frame = None;
+
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--depth" => {
+                    let value = args.next().ok_or("--depth: missing depth")?;
+                    depth = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("--depth: invalid depth: '{}'", value))?,
+                    );
+                }
+                "--frame" => {
+                    let value = args.next().ok_or("--frame: missing frame number")?;
+                    frame = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("--frame: invalid frame number: '{}'", value))?,
+                    );
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option: {}", flag).into())
+                }
+                path => paths.push(path.to_owned()),
+            }
+        }
+
+        let mut paths = paths.into_iter();
+        let project_path = paths.next().ok_or("missing project file")?;
+        let output_path = paths.next().ok_or("missing output file")?;
+
+        if paths.next().is_some() {
+            return Err("too many files".into());
+        }
+
+        Ok(ExportBvhOptions {
+            project_path,
+            output_path,
+            depth,
+            frame,
+        })
+    }
+}
+
+pub(crate) struct QueueOptions {
+    pub queue_path: String,
+}
+
+impl QueueOptions {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut paths = args.collect::<Vec<_>>().into_iter();
+
+        let queue_path = paths.next().ok_or("missing queue file")?;
+
+        if paths.next().is_some() {
+            return Err("only one queue file can be used at a time".into());
+        }
+
+        Ok(QueueOptions { queue_path })
+    }
+}
+
+pub(crate) struct DiffOptions {
+    pub reference_path: String,
+    pub test_path: String,
+    /// Where to write the FLIP heatmap.
+    pub output_path: String,
+    pub pixels_per_degree: f32,
+}
+
+impl DiffOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut paths = Vec::new();
+        let mut output_path = None;
+        let mut pixels_per_degree = crate::diff::DEFAULT_PIXELS_PER_DEGREE;
+
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--output" => output_path = Some(args.next().ok_or("--output: missing file path")?),
+                "--ppd" => {
+                    let value = args.next().ok_or("--ppd: missing pixels per degree")?;
+                    match value.parse::<f32>() {
+                        Ok(ppd) if ppd > 0.0 && ppd.is_finite() => pixels_per_degree = ppd,
+                        _ => {
+                            return Err(format!(
+                                "--ppd: expected a positive number, got '{}'",
+                                value
+                            )
+                            .into())
+                        }
+                    }
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option: {}", flag).into())
+                }
+                path => paths.push(path.to_owned()),
+            }
+        }
+
+        let mut paths = paths.into_iter();
+        let reference_path = paths.next().ok_or("missing reference image")?;
+        let test_path = paths.next().ok_or("missing image to compare with")?;
+
+        if paths.next().is_some() {
+            return Err("only two images can be compared at a time".into());
+        }
+
+        Ok(DiffOptions {
+            reference_path,
+            test_path,
+            output_path: output_path.unwrap_or_else(|| "diff.png".into()),
+            pixels_per_degree,
+        })
+    }
+}
+
+pub(crate) struct NewOptions {
+    pub example: Example,
+    pub directory: String,
+}
+
+impl NewOptions {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut args = args.collect::<Vec<_>>().into_iter();
+
+        let example = Example::parse(&args.next().ok_or("missing example name")?)?;
+        let directory = args.next().unwrap_or_else(|| example.name().into());
+
+        if args.next().is_some() {
+            return Err("only one example can be written at a time".into());
+        }
+
+        Ok(NewOptions { example, directory })
+    }
+}
+
+/// Parses either a single frame, like `12`, or an inclusive range, like `1..48`.
+fn parse_frames(value: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let (first, last) = if let Some(separator) = value.find("..") {
+        (&value[..separator], &value[separator + 2..])
+    } else {
+        (value, value)
+    };
+
+    let first: u32 = first
+        .parse()
+        .map_err(|_| format!("invalid frame number: '{}'", first))?;
+    let last: u32 = last
+        .parse()
+        .map_err(|_| format!("invalid frame number: '{}'", last))?;
+
+    if last < first {
+        return Err(format!("the range {} ends before it starts", value).into());
+    }
+
+    Ok((first, last))
+}
+
+/// Parses a pixel region, written as `x,y,width,height`.
+fn parse_region(value: &str) -> Result<Area<usize>, Box<dyn Error>> {
+    let numbers = value
+        .split(',')
+        .map(|number| {
+            number
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid number: '{}'", number))
+        })
+        .collect::<Result<Vec<usize>, _>>()?;
+
+    match *numbers {
+        [x, y, width, height] if width > 0 && height > 0 => {
+            Ok(Area::new(Point2::new(x, y), Vector2::new(width, height)))
+        }
+        [_, _, _, _] => Err("the region can't be empty".into()),
+        _ => Err("expected x,y,width,height".into()),
+    }
+}
+
+/// Parses a positive number of threads.
+fn parse_threads(value: &str) -> Result<usize, Box<dyn Error>> {
+    match value.trim().parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("invalid number of threads: '{}'", value).into()),
+    }
+}
+
+/// Parses a pixel position, written as `x,y`.
+fn parse_pixel(value: &str) -> Result<Point2<usize>, Box<dyn Error>> {
+    let numbers = value
+        .split(',')
+        .map(|number| {
+            number
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid number: '{}'", number))
+        })
+        .collect::<Result<Vec<usize>, _>>()?;
+
+    match *numbers {
+        [x, y] => Ok(Point2::new(x, y)),
+        _ => Err("expected x,y".into()),
+    }
+}
+
+pub(crate) fn usage(name: &str) -> String {
+    format!(
+        "usage: {0} [--dry-run] [--watch] [--frame frame|first..last] [--region x,y,width,height] [--film film_file [--resume]] [--bands count] [--memory-budget size] [--exr] [--exr-mipmaps] [--trace-pixel x,y [--trace-file trace_file]] [--threads count] [--low-priority] [--cpu-limit percent] [--profile laptop|workstation|farm] [--quality multiplier] [--strict] [--notify-webhook url] [--stream tcp://host:port] project_file\n       {0} merge-region project_file film_file region_film_file...\n       {0} merge --output film_file [--project project_file] film_file...\n       {0} develop [--project project_file] [--tonemap clamp|reinhard|aces] [--exposure stops] [--backplate image_file [--backplate-exposure stops]] [--output image_file] film_file\n       {0} export-bvh [--depth depth] [--frame frame] project_file output_file\n       {0} queue queue_file\n       {0} new cornell-box|dispersion-prism|material-ball [directory]\n       {0} diff [--output heatmap_file] [--ppd pixels_per_degree] reference_image image\n       {0} selftest",
+        name
+    )
+}
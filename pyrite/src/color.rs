@@ -1,21 +1,69 @@
-use std::error::Error;
+use std::{error::Error, iter::FromIterator};
 
 use palette::{LinSrgb, Srgb};
 
-use crate::project::{
-    expressions::Vector,
-    program::{ProgramFn, ProgramValue},
+use crate::{
+    noise::NoiseKind,
+    project::{
+        expressions::Vector,
+        program::{texture_color, volume_value, ProgramFn, ProgramValue},
+    },
 };
 
+/// The largest number of wavelengths that are evaluated together.
+pub(crate) const LANES: usize = 4;
+
+/// The light intensity for each wavelength in a group of up to `LANES`
+/// wavelengths. Unused lanes have unspecified values.
 #[derive(Copy, Clone)]
-#[repr(transparent)]
 pub(crate) struct Light {
-    pub value: f32,
+    pub values: [f32; LANES],
+}
+
+impl Light {
+    fn splat(value: f32) -> Self {
+        Light {
+            values: [value; LANES],
+        }
+    }
+
+    #[inline]
+    fn zip(self, other: Light, function: impl Fn(f32, f32) -> f32) -> Self {
+        let mut values = self.values;
+        for (value, &other) in values.iter_mut().zip(&other.values) {
+            *value = function(*value, other);
+        }
+        Light { values }
+    }
+
+    /// Calculates the intensity for each of the input's wavelengths.
+    #[inline]
+    fn from_wavelengths(wavelengths: &[f32], function: impl Fn(f32) -> f32) -> Self {
+        let mut values = [0.0; LANES];
+        for (value, &wavelength) in values.iter_mut().zip(wavelengths) {
+            *value = function(wavelength);
+        }
+        Light { values }
+    }
+
+    fn from_rgb(wavelengths: &[f32], color: LinSrgb) -> Self {
+        let LinSrgb {
+            red, green, blue, ..
+        } = color;
+
+        Light::from_wavelengths(wavelengths, |wavelength| {
+            let red_response = red * crate::rgb::response::RED.get(wavelength);
+            let green_response = green * crate::rgb::response::GREEN.get(wavelength);
+            let blue_response = blue * crate::rgb::response::BLUE.get(wavelength);
+
+            red_response + green_response + blue_response
+        })
+    }
 }
 
 impl<I: WavelengthInput> ProgramValue<I> for Light {
     fn from_number(number: f32) -> Result<Self, Box<dyn Error>> {
-        Ok(Light { value: number })
+        Ok(Light::splat(number))
     }
 
     fn from_vector(_x: f32, _y: f32, _z: f32, _w: f32) -> Result<Self, Box<dyn Error>> {
@@ -36,45 +84,27 @@ impl<I: WavelengthInput> ProgramValue<I> for Light {
             let green: f32 = registers.pop();
             let red: f32 = registers.pop();
 
-            let LinSrgb {
-                red, green, blue, ..
-            } = Srgb::new(red, green, blue).into_linear();
-
-            let red_response = red * crate::rgb::response::RED.get(input.wavelength());
-            let green_response = green * crate::rgb::response::GREEN.get(input.wavelength());
-            let blue_response = blue * crate::rgb::response::BLUE.get(input.wavelength());
-
-            Light {
-                value: red_response + green_response + blue_response,
-            }
+            Light::from_rgb(
+                input.wavelengths(),
+                Srgb::new(red, green, blue).into_linear(),
+            )
         }))
     }
 
     fn spectrum() -> Result<Option<ProgramFn<I, Self>>, Box<dyn Error>> {
-        Ok(Some(|registers, input, resources| Light {
-            value: resources
-                .spectra
-                .get(registers.pop())
-                .get(input.wavelength()),
+        Ok(Some(|registers, input, resources| {
+            let spectrum = resources.spectra.get(registers.pop());
+            Light::from_wavelengths(input.wavelengths(), |wavelength| spectrum.get(wavelength))
         }))
     }
 
     fn texture() -> Result<Option<ProgramFn<I, Self>>, Box<dyn Error>> {
         Ok(Some(|registers, input, resources| {
-            let texture = resources.textures.get(registers.pop());
-            let uv: Vector = registers.pop();
-
-            let LinSrgb {
-                red, green, blue, ..
-            } = texture.get_color(uv.into()).color;
-
-            let red_response = red * crate::rgb::response::RED.get(input.wavelength());
-            let green_response = green * crate::rgb::response::GREEN.get(input.wavelength());
-            let blue_response = blue * crate::rgb::response::BLUE.get(input.wavelength());
-
-            Light {
-                value: red_response + green_response + blue_response,
-            }
+            // The texture is only sampled once for all of the wavelengths.
+            Light::from_rgb(
+                input.wavelengths(),
+                texture_color(registers, resources).color,
+            )
         }))
     }
 
@@ -82,9 +112,7 @@ impl<I: WavelengthInput> ProgramValue<I> for Light {
         Ok(|registers, _, _| {
             let rhs: Light = registers.pop();
             let lhs: Light = registers.pop();
-            Light {
-                value: lhs.value + rhs.value,
-            }
+            lhs.zip(rhs, |lhs, rhs| lhs + rhs)
         })
     }
 
@@ -92,9 +120,7 @@ impl<I: WavelengthInput> ProgramValue<I> for Light {
         Ok(|registers, _, _| {
             let rhs: Light = registers.pop();
             let lhs: Light = registers.pop();
-            Light {
-                value: lhs.value - rhs.value,
-            }
+            lhs.zip(rhs, |lhs, rhs| lhs - rhs)
         })
     }
 
@@ -102,9 +128,7 @@ impl<I: WavelengthInput> ProgramValue<I> for Light {
         Ok(|registers, _, _| {
             let rhs: Light = registers.pop();
             let lhs: Light = registers.pop();
-            Light {
-                value: lhs.value * rhs.value,
-            }
+            lhs.zip(rhs, |lhs, rhs| lhs * rhs)
         })
     }
 
@@ -112,20 +136,16 @@ impl<I: WavelengthInput> ProgramValue<I> for Light {
         Ok(|registers, _, _| {
             let rhs: Light = registers.pop();
             let lhs: Light = registers.pop();
-            Light {
-                value: lhs.value / rhs.value,
-            }
+            lhs.zip(rhs, |lhs, rhs| lhs / rhs)
         })
     }
 
     fn mix() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
         Ok(|registers, _, _| {
             let amount = registers.pop::<f32>().min(1.0).max(0.0);
-            let rhs: f32 = registers.pop();
-            let lhs: f32 = registers.pop();
-            Light {
-                value: lhs * (1.0 - amount) + rhs * amount,
-            }
+            let rhs: Light = registers.pop();
+            let lhs: Light = registers.pop();
+            lhs.zip(rhs, |lhs, rhs| lhs * (1.0 - amount) + rhs * amount)
         })
     }
     fn fresnel() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
@@ -134,24 +154,242 @@ impl<I: WavelengthInput> ProgramValue<I> for Light {
             let normal: Vector = registers.pop();
             let env_ior: f32 = registers.pop();
             let ior: f32 = registers.pop();
-            Light {
-                value: crate::math::fresnel(ior, env_ior, normal.into(), incident.into()),
-            }
+            Light::splat(crate::math::fresnel(
+                ior,
+                env_ior,
+                normal.into(),
+                incident.into(),
+            ))
+        })
+    }
+    fn conductor_fresnel() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let incident: Vector = registers.pop();
+            let normal: Vector = registers.pop();
+            let k: Light = registers.pop();
+            let n: Light = registers.pop();
+            n.zip(k, |n, k| {
+                crate::math::conductor_fresnel(n, k, normal.into(), incident.into())
+            })
+        })
+    }
+    fn angle_profile() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, resources| {
+            let incident: Vector = registers.pop();
+            let normal: Vector = registers.pop();
+            let curve = resources.spectra.get(registers.pop());
+            Light::splat(curve.get(crate::math::incidence_angle(normal.into(), incident.into())))
+        })
+    }
+    fn direction_region() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let direction: Vector = registers.pop();
+            let incident: Vector = registers.pop();
+            let softness: f32 = registers.pop();
+            let angle: f32 = registers.pop();
+            Light::splat(crate::math::direction_region(
+                direction.into(),
+                angle,
+                softness,
+                incident.into(),
+            ))
         })
     }
     fn blackbody() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
         Ok(|registers, input, _| {
+            let normalize: f32 = registers.pop();
             let temperature: f32 = registers.pop();
-            let wavelength = input.wavelength() * 1.0e-9;
-            let power_term = 3.74183e-16 * wavelength.powi(-5);
 
-            Light {
-                value: power_term / ((1.4388e-2 / (wavelength * temperature)).exp() - 1.0),
-            }
+            let planck = |wavelength: f32| {
+                let wavelength = wavelength * 1.0e-9;
+                let power_term = 3.74183e-16 * wavelength.powi(-5);
+
+                power_term / ((1.4388e-2 / (wavelength * temperature)).exp() - 1.0)
+            };
+            let scale = if normalize != 0.0 {
+                1.0 / planck(560.0)
+            } else {
+                1.0
+            };
+
+            Light::from_wavelengths(input.wavelengths(), |wavelength| planck(wavelength) * scale)
         })
     }
+    fn daylight_sky() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, input, _| {
+            let sun: Vector = registers.pop();
+            let incident: Vector = registers.pop();
+            let turbidity: f32 = registers.pop();
+
+            // The color is the same for all of the wavelengths.
+            let (luminance, x, y) =
+                crate::daylight::Sky::new(sun.into(), turbidity).color(incident.into());
+
+            Light::from_wavelengths(input.wavelengths(), |wavelength| {
+                luminance * crate::daylight::spectrum(x, y, wavelength)
+            })
+        })
+    }
+    fn sunlight() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, input, _| {
+            let sun: Vector = registers.pop();
+            let turbidity: f32 = registers.pop();
+
+            Light::from_wavelengths(input.wavelengths(), |wavelength| {
+                crate::daylight::sunlight(sun.into(), turbidity, wavelength)
+            })
+        })
+    }
+
+    fn noise() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let position: Vector = registers.pop();
+            let gain: f32 = registers.pop();
+            let lacunarity: f32 = registers.pop();
+            let octaves: f32 = registers.pop();
+            let scale: f32 = registers.pop();
+            let kind: f32 = registers.pop();
+            let value = crate::noise::fractal(
+                NoiseKind::from_number(kind),
+                position.into(),
+                scale,
+                octaves as u32,
+                lacunarity,
+                gain,
+            );
+            Light::splat(value)
+        })
+    }
+
+    fn volume() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, resources| Light::splat(volume_value(registers, resources)))
+    }
 }
 
 pub(crate) trait WavelengthInput {
-    fn wavelength(&self) -> f32;
+    /// The wavelengths to evaluate, where there are at most `LANES`.
+    fn wavelengths(&self) -> &[f32];
+}
+
+/// A group of up to `LANES` wavelengths, that are evaluated together.
+#[derive(Copy, Clone)]
+pub(crate) struct Wavelengths {
+    values: [f32; LANES],
+    count: usize,
+}
+
+impl Wavelengths {
+    pub fn as_slice(&self) -> &[f32] {
+        &self.values[..self.count]
+    }
+}
+
+impl FromIterator<f32> for Wavelengths {
+    fn from_iter<T: IntoIterator<Item = f32>>(iter: T) -> Self {
+        let mut values = [0.0; LANES];
+        let mut count = 0;
+
+        for wavelength in iter {
+            assert!(count < LANES, "too many wavelengths");
+            values[count] = wavelength;
+            count += 1;
+        }
+
+        Wavelengths { values, count }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::{
+        project::{
+            self,
+            program::{ExecutionContext, ProgramCompiler, Resources},
+        },
+        ImageSettings, SpectrumSamplingInput,
+    };
+
+    /// Loads a project that uses `white` as the image's white point, and
+    /// evaluates it at `wavelength`.
+    fn evaluate_white(name: &str, white: &str, wavelength: f32) -> f32 {
+        let directory =
+            std::env::temp_dir().join(format!("pyrite-color-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("project.lua");
+        std::fs::write(
+            &path,
+            format!(
+                "return {{
+                    image = {{width = 1, height = 1, white = {}}},
+                    renderer = renderer.simple {{pixel_samples = 1, spectrum_samples = 1, bounces = 1}},
+                    camera = camera.perspective {{fov = 40, transform = transform.look_at {{from = vector(0, -1, 0), to = vector(0, 0, 0)}}}},
+                    world = {{objects = {{}}}},
+                }}",
+                white
+            ),
+        )
+        .unwrap();
+
+        let data = project::load_project(&path).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let arena = Bump::new();
+        let programs = ProgramCompiler::new(&arena);
+        let image =
+            ImageSettings::from_project(data.project.image, programs, &data.expressions).unwrap();
+        let mut exe = ExecutionContext::new(Resources {
+            spectra: &data.spectra,
+            textures: &data.textures,
+        });
+
+        exe.run(image.white.unwrap(), &SpectrumSamplingInput { wavelength })
+            .values[0]
+    }
+
+    fn planck(wavelength: f32, temperature: f32) -> f32 {
+        let wavelength = wavelength * 1.0e-9;
+        3.74183e-16 * wavelength.powi(-5) / ((1.4388e-2 / (wavelength * temperature)).exp() - 1.0)
+    }
+
+    #[test]
+    fn blackbody() {
+        let expected = planck(500.0, 5000.0);
+        let value = evaluate_white("blackbody", "blackbody(5000)", 500.0);
+        assert!(
+            ((value - expected) / expected).abs() < 1.0e-3,
+            "expected {}, got {}",
+            expected,
+            value
+        );
+    }
+
+    #[test]
+    fn normalized_blackbody() {
+        let value = evaluate_white("normalized", "blackbody(5000, {normalize = true})", 560.0);
+        assert!((value - 1.0).abs() < 1.0e-3, "expected 1, got {}", value);
+
+        let expected = planck(450.0, 5000.0) / planck(560.0, 5000.0);
+        let value = evaluate_white("normalized", "blackbody(5000, {normalize = true})", 450.0);
+        assert!(
+            ((value - expected) / expected).abs() < 1.0e-3,
+            "expected {}, got {}",
+            expected,
+            value
+        );
+
+        let expected = planck(560.0, 5000.0);
+        let value = evaluate_white(
+            "unnormalized",
+            "blackbody(5000, {normalize = false})",
+            560.0,
+        );
+        assert!(
+            ((value - expected) / expected).abs() < 1.0e-3,
+            "expected {}, got {}",
+            expected,
+            value
+        );
+    }
 }
@@ -0,0 +1,200 @@
+//! The daylight model from "A Practical Analytic Model for Daylight" by
+//! Preetham et al. The luminance and chromaticity of the sky are fitted to
+//! the sun's position and the turbidity of the air, and turned into spectra
+//! with the CIE daylight components. Luminances are in kcd/m², so the sky and
+//! the sun's illuminance, in klux, are on the same scale.
+
+use std::f32::consts::PI;
+
+use cgmath::{InnerSpace, Vector3};
+
+/// The illuminance of the sun outside of the atmosphere, in klux.
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+
+/// The temperature of the sun's surface, in kelvin.
+const SOLAR_TEMPERATURE: f32 = 5778.0;
+
+/// The shortest wavelength in the daylight component tables, in nanometers.
+const COMPONENTS_START: f32 = 300.0;
+const COMPONENTS_STEP: f32 = 10.0;
+
+/// The mean spectrum of daylight, and the two components that change its
+/// color, from 300 to 830 nm.
+const S0: [f32; 54] = [
+    0.04, 6.0, 29.6, 55.3, 57.3, 61.8, 61.5, 68.8, 63.4, 65.8, 94.8, 104.8, 105.9, 96.8, 113.9,
+    125.6, 125.5, 121.3, 121.3, 113.5, 113.1, 110.8, 106.5, 108.8, 105.3, 104.4, 100.0, 96.0, 95.1,
+    89.1, 90.5, 90.3, 88.4, 84.0, 85.1, 81.9, 82.6, 84.9, 81.3, 71.9, 74.3, 76.4, 63.3, 71.7, 77.0,
+    65.2, 47.7, 68.6, 65.0, 66.0, 61.0, 53.3, 58.9, 61.9,
+];
+const S1: [f32; 54] = [
+    0.02, 4.5, 22.4, 42.0, 40.6, 41.6, 38.0, 42.4, 38.5, 35.0, 43.4, 46.3, 43.9, 37.1, 36.7, 35.9,
+    32.6, 27.9, 24.3, 20.1, 16.2, 13.2, 8.6, 6.1, 4.2, 1.9, 0.0, -1.6, -3.5, -3.5, -5.8, -7.2,
+    -8.6, -9.5, -10.9, -10.7, -12.0, -14.0, -13.6, -12.0, -13.3, -12.9, -10.6, -11.6, -12.2, -10.2,
+    -7.8, -11.2, -10.4, -10.6, -9.7, -8.3, -9.3, -9.8,
+];
+const S2: [f32; 54] = [
+    0.0, 2.0, 4.0, 8.5, 7.8, 6.7, 5.3, 6.1, 3.0, 1.2, -1.1, -0.5, -0.7, -1.2, -2.6, -2.9, -2.8,
+    -2.6, -2.6, -1.8, -1.5, -1.3, -1.2, -1.0, -0.5, -0.3, 0.0, 0.2, 0.5, 2.1, 3.2, 4.1, 4.7, 5.1,
+    6.7, 7.3, 8.6, 9.8, 10.2, 8.3, 9.6, 8.5, 7.0, 7.6, 8.0, 6.7, 5.2, 7.4, 6.8, 7.0, 6.4, 5.5, 6.1,
+    6.5,
+];
+
+/// The sky for a certain sun position and turbidity.
+pub(crate) struct Sky {
+    sun: Vector3<f32>,
+    /// The Perez coefficients for the luminance, x and y.
+    perez: [[f32; 5]; 3],
+    /// The luminance, x and y at the zenith, divided by the Perez function
+    /// there.
+    zenith: [f32; 3],
+}
+
+impl Sky {
+    /// The model is made for the sun above the horizon, so lower suns are
+    /// treated as if they were at the horizon.
+    pub fn new(sun: Vector3<f32>, turbidity: f32) -> Self {
+        let sun = sun.normalize();
+        let theta_sun = sun.z.max(0.0).min(1.0).acos();
+        let t = turbidity;
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let angles = [
+            theta_sun * theta_sun * theta_sun,
+            theta_sun * theta_sun,
+            theta_sun,
+            1.0,
+        ];
+        let chromaticity = |matrix: [[f32; 4]; 3]| {
+            let turbidities = [t * t, t, 1.0];
+            let mut sum = 0.0;
+            for (row, turbidity) in matrix.iter().zip(&turbidities) {
+                for (value, angle) in row.iter().zip(&angles) {
+                    sum += turbidity * value * angle;
+                }
+            }
+            sum
+        };
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut zenith = [luminance.max(0.0), x, y];
+        for (value, coefficients) in zenith.iter_mut().zip(&perez) {
+            *value /= perez_function(coefficients, 1.0, theta_sun);
+        }
+
+        Sky { sun, perez, zenith }
+    }
+
+    /// The luminance and the x and y chromaticity in `direction`. The sky
+    /// below the horizon is the same as at the horizon.
+    pub fn color(&self, direction: Vector3<f32>) -> (f32, f32, f32) {
+        let direction = direction.normalize();
+        let cos_theta = direction.z.max(0.001);
+        let gamma = direction.dot(self.sun).max(-1.0).min(1.0).acos();
+
+        let value = |index: usize| {
+            self.zenith[index] * perez_function(&self.perez[index], cos_theta, gamma)
+        };
+
+        (value(0), value(1), value(2))
+    }
+}
+
+fn perez_function(coefficients: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    let cos_gamma = gamma.cos();
+
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+/// The relative spectral power of daylight with the chromaticity `x`, `y`,
+/// which is 1 at 560 nm, where its luminance is roughly measured.
+pub(crate) fn spectrum(x: f32, y: f32, wavelength: f32) -> f32 {
+    let denominator = 0.0241 + 0.2562 * x - 0.7341 * y;
+    let m1 = (-1.3515 - 1.7703 * x + 5.9114 * y) / denominator;
+    let m2 = (0.0300 - 31.4424 * x + 30.0717 * y) / denominator;
+
+    let position = ((wavelength - COMPONENTS_START) / COMPONENTS_STEP)
+        .max(0.0)
+        .min((S0.len() - 1) as f32);
+    let index = (position as usize).min(S0.len() - 2);
+    let fraction = position - index as f32;
+    let component = |table: &[f32; 54]| table[index] + (table[index + 1] - table[index]) * fraction;
+
+    (component(&S0) + m1 * component(&S1) + m2 * component(&S2)) * 0.01
+}
+
+/// The chromaticity of the CIE daylight illuminant with the correlated
+/// color `temperature`, in kelvin, which is valid from 4000 to 25000 K.
+pub(crate) fn chromaticity(temperature: f32) -> (f32, f32) {
+    let t = temperature.max(4000.0).min(25000.0);
+    let x = if t <= 7000.0 {
+        -4.6070e9 / (t * t * t) + 2.9678e6 / (t * t) + 0.09911e3 / t + 0.244063
+    } else {
+        -2.0064e9 / (t * t * t) + 1.9018e6 / (t * t) + 0.24748e3 / t + 0.237040
+    };
+    let y = -3.0 * x * x + 2.87 * x - 0.275;
+
+    (x, y)
+}
+
+/// The spectral illuminance from the sun at the ground, in klux at 560 nm.
+/// The atmosphere scatters it by Rayleigh and aerosol scattering, but the
+/// absorption by ozone and water vapor is left out.
+pub(crate) fn sunlight(sun: Vector3<f32>, turbidity: f32, wavelength: f32) -> f32 {
+    let sun = sun.normalize();
+    if sun.z <= 0.0 {
+        return 0.0;
+    }
+
+    // The relative length of the path through the atmosphere.
+    let theta = sun.z.min(1.0).acos().to_degrees();
+    let air_mass = 1.0 / (sun.z + 0.15 * (93.885 - theta).powf(-1.253));
+
+    let micrometers = wavelength * 0.001;
+    let rayleigh = (-0.008735 * micrometers.powf(-4.08) * air_mass).exp();
+    let beta = 0.04608 * turbidity - 0.04586;
+    let aerosol = (-beta * micrometers.powf(-1.3) * air_mass).exp();
+
+    SOLAR_ILLUMINANCE * planck(wavelength) / planck(560.0) * rayleigh * aerosol
+}
+
+/// The relative spectral radiance of the sun's surface.
+fn planck(wavelength: f32) -> f32 {
+    let wavelength = wavelength * 1.0e-9;
+    wavelength.powi(-5) / ((1.4388e-2 / (wavelength * SOLAR_TEMPERATURE)).exp() - 1.0)
+}
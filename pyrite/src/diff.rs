@@ -0,0 +1,460 @@
+//! Compares two images, with the root mean square error and the FLIP
+//! difference from "FLIP: A Difference Evaluator for Alternating Images" by
+//! Andersson et al. FLIP models how visible the differences are, when the
+//! images are flipped back and forth on a screen, and is calculated for the
+//! displayed images, which are clamped to [0, 1].
+
+use std::{error::Error, f32::consts::PI, path::Path};
+
+use palette::{Pixel, Srgb};
+
+use crate::exr::{self, LinearImage};
+
+/// The viewing distance, as pixels per degree. The default is a 0.7 meter
+/// wide 4K screen at 0.7 meters.
+pub(crate) const DEFAULT_PIXELS_PER_DEGREE: f32 = 67.0;
+
+/// The exponents and the break points of the color difference.
+const COLOR_EXPONENT: f32 = 0.7;
+const FEATURE_EXPONENT: f32 = 0.5;
+const COLOR_BREAK: f32 = 0.4;
+const COLOR_BREAK_VALUE: f32 = 0.95;
+
+/// The width of the feature detectors, in degrees.
+const FEATURE_WIDTH: f32 = 0.082;
+
+/// The contrast sensitivity of the achromatic, red-green and blue-yellow
+/// channels, as the weights and widths of two Gaussians.
+const CONTRAST_SENSITIVITY: [[(f32, f32); 2]; 3] = [
+    [(1.0, 0.0047), (0.0, 1.0e-5)],
+    [(1.0, 0.0053), (0.0, 1.0e-5)],
+    [(34.1, 0.04), (13.5, 0.025)],
+];
+
+const LINEAR_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412_386_56, 0.357_591_5, 0.180_450_5],
+    [0.212_636_82, 0.715_183, 0.072_180_2],
+    [0.019_330_62, 0.119_197_16, 0.950_372_6],
+];
+
+const XYZ_TO_LINEAR: [[f32; 3]; 3] = [
+    [3.241_003_3, -1.537_399, -0.498_615_87],
+    [-0.969_224_26, 1.875_93, 0.041_554_22],
+    [0.055_639_42, -0.204_011_2, 1.057_149],
+];
+
+pub(crate) struct Difference {
+    pub rmse: f32,
+    pub mean_flip: f32,
+    pub max_flip: f32,
+    /// The FLIP value of each pixel.
+    pub flip: Vec<f32>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Difference {
+    /// Colors the FLIP values from black, for no difference, over purple and
+    /// orange, to pale yellow.
+    pub fn heatmap(&self) -> image::RgbImage {
+        const COLORS: [[f32; 3]; 5] = [
+            [0.0, 0.0, 4.0],
+            [81.0, 18.0, 124.0],
+            [183.0, 55.0, 121.0],
+            [252.0, 137.0, 97.0],
+            [252.0, 253.0, 191.0],
+        ];
+
+        image::ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let value = self.flip[(x + y * self.width) as usize].max(0.0).min(1.0);
+            let position = value * (COLORS.len() - 1) as f32;
+            let index = (position as usize).min(COLORS.len() - 2);
+            let amount = position - index as f32;
+
+            let mut pixel = [0; 3];
+            for (channel, pixel) in pixel.iter_mut().enumerate() {
+                let from = COLORS[index][channel];
+                let to = COLORS[index + 1][channel];
+                *pixel = (from + (to - from) * amount).round() as u8;
+            }
+            image::Rgb(pixel)
+        })
+    }
+}
+
+/// Loads an OpenEXR file as it is, or another image format as sRGB.
+pub(crate) fn load(path: &Path) -> Result<LinearImage, Box<dyn Error>> {
+    let is_exr = path
+        .extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("exr"));
+
+    if is_exr {
+        return exr::read(path);
+    }
+
+    let image = image::open(path)?.into_rgb();
+    Ok(image::ImageBuffer::from_fn(
+        image.width(),
+        image.height(),
+        |x, y| {
+            let image::Rgb([red, green, blue]) = *image.get_pixel(x, y);
+            let color: Srgb<f32> = Srgb::new(red, green, blue).into_format();
+            image::Rgb(color.into_linear().into_raw())
+        },
+    ))
+}
+
+pub(crate) fn compare(
+    reference: &LinearImage,
+    test: &LinearImage,
+    pixels_per_degree: f32,
+) -> Result<Difference, Box<dyn Error>> {
+    if reference.dimensions() != test.dimensions() {
+        return Err(format!(
+            "the images have different sizes, {}x{} and {}x{}",
+            reference.width(),
+            reference.height(),
+            test.width(),
+            test.height()
+        )
+        .into());
+    }
+
+    let (width, height) = reference.dimensions();
+    if width == 0 || height == 0 {
+        return Err("the images are empty".into());
+    }
+
+    let squared_error: f64 = reference
+        .iter()
+        .zip(test.iter())
+        .map(|(&a, &b)| f64::from(a - b).powi(2))
+        .sum();
+    let rmse = (squared_error / (u64::from(width) * u64::from(height) * 3) as f64).sqrt() as f32;
+
+    let reference = Opponent::new(reference);
+    let test = Opponent::new(test);
+
+    let color = color_difference(&reference, &test, pixels_per_degree);
+    let features = feature_difference(&reference, &test, pixels_per_degree);
+
+    let flip: Vec<f32> = color
+        .iter()
+        .zip(&features)
+        .map(|(&color, &feature)| color.powf(1.0 - feature))
+        .collect();
+
+    Ok(Difference {
+        rmse,
+        mean_flip: flip.iter().sum::<f32>() / flip.len() as f32,
+        max_flip: flip.iter().cloned().fold(0.0, f32::max),
+        flip,
+        width,
+        height,
+    })
+}
+
+/// An image in the YCxCz opponent color space, with one plane per channel.
+struct Opponent {
+    channels: [Plane; 3],
+}
+
+impl Opponent {
+    fn new(image: &LinearImage) -> Self {
+        let (width, height) = image.dimensions();
+        let mut channels = [
+            Plane::new(width, height),
+            Plane::new(width, height),
+            Plane::new(width, height),
+        ];
+
+        for (index, pixel) in image.pixels().enumerate() {
+            let mut color = pixel.0;
+            for value in &mut color {
+                *value = value.max(0.0).min(1.0);
+            }
+
+            let ycxcz = xyz_to_ycxcz(transform(LINEAR_TO_XYZ, color));
+            for (channel, &value) in channels.iter_mut().zip(&ycxcz) {
+                channel.values[index] = value;
+            }
+        }
+
+        Opponent { channels }
+    }
+}
+
+/// Filters the images like the eye does at the viewing distance, and finds
+/// the perceived color difference of each pixel, in [0, 1].
+fn color_difference(reference: &Opponent, test: &Opponent, pixels_per_degree: f32) -> Vec<f32> {
+    let max_width = CONTRAST_SENSITIVITY
+        .iter()
+        .flat_map(|channel| channel.iter().map(|&(_, width)| width))
+        .fold(0.0, f32::max);
+    let radius = (3.0 * (max_width / (2.0 * PI * PI)).sqrt() * pixels_per_degree).ceil() as i32;
+
+    let filter = |image: &Opponent| -> Vec<[f32; 3]> {
+        let filtered: Vec<Plane> = image
+            .channels
+            .iter()
+            .zip(&CONTRAST_SENSITIVITY)
+            .map(|(channel, &gaussians)| {
+                let kernels: Vec<(f32, Vec<f32>)> = gaussians
+                    .iter()
+                    .filter(|&&(weight, _)| weight > 0.0)
+                    .map(|&(weight, width)| {
+                        let kernel: Vec<f32> = (-radius..=radius)
+                            .map(|x| {
+                                let x = x as f32 / pixels_per_degree;
+                                (-PI * PI * x * x / width).exp()
+                            })
+                            .collect();
+                        (weight * (PI / width).sqrt(), kernel)
+                    })
+                    .collect();
+
+                // The 2D kernel is a sum of separable Gaussians, and is
+                // normalized to sum to 1.
+                let total: f32 = kernels
+                    .iter()
+                    .map(|(weight, kernel)| weight * kernel.iter().sum::<f32>().powi(2))
+                    .sum();
+
+                let mut sum = Plane::new(channel.width, channel.height);
+                for (weight, kernel) in &kernels {
+                    let filtered = channel.convolve(kernel, kernel);
+                    for (sum, value) in sum.values.iter_mut().zip(filtered.values) {
+                        *sum += value * weight / total;
+                    }
+                }
+                sum
+            })
+            .collect();
+
+        (0..filtered[0].values.len())
+            .map(|index| {
+                let ycxcz = [
+                    filtered[0].values[index],
+                    filtered[1].values[index],
+                    filtered[2].values[index],
+                ];
+                let mut color = transform(XYZ_TO_LINEAR, ycxcz_to_xyz(ycxcz));
+                for value in &mut color {
+                    *value = value.max(0.0).min(1.0);
+                }
+                hunt(xyz_to_lab(transform(LINEAR_TO_XYZ, color)))
+            })
+            .collect()
+    };
+
+    let max_difference = hyab(
+        hunt(xyz_to_lab(transform(LINEAR_TO_XYZ, [0.0, 1.0, 0.0]))),
+        hunt(xyz_to_lab(transform(LINEAR_TO_XYZ, [0.0, 0.0, 1.0]))),
+    )
+    .powf(COLOR_EXPONENT);
+    let color_break = COLOR_BREAK * max_difference;
+
+    filter(reference)
+        .into_iter()
+        .zip(filter(test))
+        .map(|(a, b)| {
+            let difference = hyab(a, b).powf(COLOR_EXPONENT);
+
+            // Small differences are compressed into the lower part of the
+            // range.
+            if difference < color_break {
+                COLOR_BREAK_VALUE / color_break * difference
+            } else {
+                COLOR_BREAK_VALUE
+                    + (difference - color_break) / (max_difference - color_break)
+                        * (1.0 - COLOR_BREAK_VALUE)
+            }
+        })
+        .collect()
+}
+
+/// Finds the difference in edges and points of each pixel, in [0, 1].
+fn feature_difference(reference: &Opponent, test: &Opponent, pixels_per_degree: f32) -> Vec<f32> {
+    let deviation = 0.5 * FEATURE_WIDTH * pixels_per_degree;
+    let radius = (3.0 * deviation).ceil() as i32;
+
+    let gaussian: Vec<f32> = (-radius..=radius)
+        .map(|x| (-(x * x) as f32 / (2.0 * deviation * deviation)).exp())
+        .collect();
+    let gaussian_sum: f32 = gaussian.iter().sum();
+
+    // The positive and negative weights of the derivatives each sum to 1.
+    let normalize = |kernel: Vec<f32>| -> Vec<f32> {
+        let positive: f32 = kernel.iter().filter(|&&value| value > 0.0).sum();
+        let negative: f32 = -kernel.iter().filter(|&&value| value < 0.0).sum::<f32>();
+        kernel
+            .into_iter()
+            .map(|value| {
+                if value > 0.0 {
+                    value / (positive * gaussian_sum)
+                } else {
+                    value / (negative * gaussian_sum)
+                }
+            })
+            .collect()
+    };
+
+    let first = normalize(
+        (-radius..=radius)
+            .zip(&gaussian)
+            .map(|(x, &g)| -(x as f32) * g)
+            .collect(),
+    );
+    let second = normalize(
+        (-radius..=radius)
+            .zip(&gaussian)
+            .map(|(x, &g)| ((x * x) as f32 / (deviation * deviation) - 1.0) * g)
+            .collect(),
+    );
+
+    let features = |image: &Opponent| -> (Vec<f32>, Vec<f32>) {
+        let channel = &image.channels[0];
+        let mut lightness = Plane::new(channel.width, channel.height);
+        for (lightness, &y) in lightness.values.iter_mut().zip(&channel.values) {
+            *lightness = (y + 16.0) / 116.0;
+        }
+
+        let magnitude = |x: Plane, y: Plane| -> Vec<f32> {
+            x.values
+                .iter()
+                .zip(&y.values)
+                .map(|(x, y)| (x * x + y * y).sqrt())
+                .collect()
+        };
+
+        let edges = magnitude(
+            lightness.convolve(&first, &gaussian),
+            lightness.convolve(&gaussian, &first),
+        );
+        let points = magnitude(
+            lightness.convolve(&second, &gaussian),
+            lightness.convolve(&gaussian, &second),
+        );
+        (edges, points)
+    };
+
+    let (reference_edges, reference_points) = features(reference);
+    let (test_edges, test_points) = features(test);
+
+    (0..reference_edges.len())
+        .map(|index| {
+            let difference = (reference_edges[index] - test_edges[index])
+                .abs()
+                .max((reference_points[index] - test_points[index]).abs());
+            (difference / 2.0f32.sqrt()).powf(FEATURE_EXPONENT)
+        })
+        .collect()
+}
+
+struct Plane {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl Plane {
+    fn new(width: u32, height: u32) -> Self {
+        Plane {
+            width,
+            height,
+            values: vec![0.0; (width * height) as usize],
+        }
+    }
+
+    /// Filters with a separable kernel, where both parts have an odd length
+    /// and are centered. The edges are extended.
+    fn convolve(&self, horizontal: &[f32], vertical: &[f32]) -> Plane {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let index =
+            |x: i32, y: i32| (x.max(0).min(width - 1) + y.max(0).min(height - 1) * width) as usize;
+
+        let mut rows = Plane::new(self.width, self.height);
+        let radius = (horizontal.len() / 2) as i32;
+        for y in 0..height {
+            for x in 0..width {
+                rows.values[(x + y * width) as usize] = horizontal
+                    .iter()
+                    .enumerate()
+                    .map(|(offset, weight)| {
+                        weight * self.values[index(x + offset as i32 - radius, y)]
+                    })
+                    .sum();
+            }
+        }
+
+        let mut result = Plane::new(self.width, self.height);
+        let radius = (vertical.len() / 2) as i32;
+        for y in 0..height {
+            for x in 0..width {
+                result.values[(x + y * width) as usize] = vertical
+                    .iter()
+                    .enumerate()
+                    .map(|(offset, weight)| {
+                        weight * rows.values[index(x, y + offset as i32 - radius)]
+                    })
+                    .sum();
+            }
+        }
+
+        result
+    }
+}
+
+fn transform(matrix: [[f32; 3]; 3], color: [f32; 3]) -> [f32; 3] {
+    let mut result = [0.0; 3];
+    for (result, row) in result.iter_mut().zip(&matrix) {
+        *result = row[0] * color[0] + row[1] * color[1] + row[2] * color[2];
+    }
+    result
+}
+
+/// The XYZ of white, which the other colors are relative to.
+fn white() -> [f32; 3] {
+    transform(LINEAR_TO_XYZ, [1.0, 1.0, 1.0])
+}
+
+fn xyz_to_ycxcz(xyz: [f32; 3]) -> [f32; 3] {
+    let white = white();
+    let [x, y, z] = [xyz[0] / white[0], xyz[1] / white[1], xyz[2] / white[2]];
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+fn ycxcz_to_xyz(ycxcz: [f32; 3]) -> [f32; 3] {
+    let white = white();
+    let y = (ycxcz[0] + 16.0) / 116.0;
+    let x = ycxcz[1] / 500.0 + y;
+    let z = y - ycxcz[2] / 200.0;
+    [x * white[0], y * white[1], z * white[2]]
+}
+
+fn xyz_to_lab(xyz: [f32; 3]) -> [f32; 3] {
+    let white = white();
+    let delta: f32 = 6.0 / 29.0;
+    let f = |t: f32| {
+        if t > delta.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * delta * delta) + 4.0 / 29.0
+        }
+    };
+
+    let x = f(xyz[0] / white[0]);
+    let y = f(xyz[1] / white[1]);
+    let z = f(xyz[2] / white[2]);
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+/// Scales the chroma by the lightness, since dark colors look less colorful.
+fn hunt(lab: [f32; 3]) -> [f32; 3] {
+    let [l, a, b] = lab;
+    [l, 0.01 * l * a, 0.01 * l * b]
+}
+
+fn hyab(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (a[0] - b[0]).abs() + ((a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
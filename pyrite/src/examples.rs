@@ -0,0 +1,289 @@
+//! Example projects for `pyrite new`. Each example is a project file and
+//! the geometry it needs, which is generated when it's written.
+
+use std::{error::Error, f32::consts::PI, fmt::Write as _, path::Path};
+
+use cgmath::{Point3, Vector3};
+
+#[derive(Copy, Clone)]
+pub(crate) enum Example {
+    CornellBox,
+    DispersionPrism,
+    MaterialBall,
+}
+
+impl Example {
+    pub fn parse(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "cornell-box" => Ok(Example::CornellBox),
+            "dispersion-prism" => Ok(Example::DispersionPrism),
+            "material-ball" => Ok(Example::MaterialBall),
+            _ => Err(format!(
+                "unknown example: '{}', expected cornell-box, dispersion-prism or material-ball",
+                name
+            )
+            .into()),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Example::CornellBox => "cornell-box",
+            Example::DispersionPrism => "dispersion-prism",
+            Example::MaterialBall => "material-ball",
+        }
+    }
+
+    /// The file names and contents of the example.
+    fn files(self) -> Vec<(&'static str, String)> {
+        match self {
+            Example::CornellBox => vec![
+                (
+                    "project.lua",
+                    include_str!("examples/cornell_box.lua").into(),
+                ),
+                ("box.obj", cornell_box()),
+            ],
+            Example::DispersionPrism => vec![
+                (
+                    "project.lua",
+                    include_str!("examples/dispersion_prism.lua").into(),
+                ),
+                ("prism.obj", prism()),
+            ],
+            Example::MaterialBall => vec![(
+                "project.lua",
+                include_str!("examples/material_ball.lua").into(),
+            )],
+        }
+    }
+}
+
+/// Writes the example to `directory`, which is created if it doesn't exist.
+/// Existing files are never replaced.
+pub(crate) fn write(example: Example, directory: &Path) -> Result<(), Box<dyn Error>> {
+    let files = example.files();
+
+    for (name, _) in &files {
+        let path = directory.join(name);
+        if path.exists() {
+            return Err(format!("{} already exists", path.display()).into());
+        }
+    }
+
+    std::fs::create_dir_all(directory)
+        .map_err(|error| format!("could not create {}: {}", directory.display(), error))?;
+
+    for (name, contents) in files {
+        let path = directory.join(name);
+        std::fs::write(&path, contents)
+            .map_err(|error| format!("could not write {}: {}", path.display(), error))?;
+    }
+
+    Ok(())
+}
+
+/// Builds an OBJ file from named objects, that are made of convex polygons.
+/// The polygons are split into triangles, since meshes can only have
+/// triangles.
+struct ObjWriter {
+    text: String,
+    vertices: usize,
+}
+
+impl ObjWriter {
+    fn new() -> Self {
+        ObjWriter {
+            text: String::new(),
+            vertices: 0,
+        }
+    }
+
+    fn object(&mut self, name: &str) {
+        writeln!(self.text, "o {}", name).unwrap();
+    }
+
+    fn face(&mut self, corners: &[Point3<f32>]) {
+        for corner in corners {
+            writeln!(self.text, "v {} {} {}", corner.x, corner.y, corner.z).unwrap();
+        }
+
+        let first = self.vertices + 1;
+        for index in 1..corners.len() - 1 {
+            writeln!(
+                self.text,
+                "f {} {} {}",
+                first,
+                first + index,
+                first + index + 1
+            )
+            .unwrap();
+        }
+
+        self.vertices += corners.len();
+    }
+
+    /// A box with its bottom at `base`, rotated around Z by `angle` degrees.
+    fn block(&mut self, base: Point3<f32>, size: Vector3<f32>, angle: f32) {
+        let (sin, cos) = (angle * PI / 180.0).sin_cos();
+        let corner = |x: f32, y: f32, z: f32| {
+            let (x, y) = ((x - 0.5) * size.x, (y - 0.5) * size.y);
+            Point3::new(
+                base.x + x * cos - y * sin,
+                base.y + x * sin + y * cos,
+                base.z + z * size.z,
+            )
+        };
+
+        let c = [
+            corner(0.0, 0.0, 0.0),
+            corner(1.0, 0.0, 0.0),
+            corner(1.0, 1.0, 0.0),
+            corner(0.0, 1.0, 0.0),
+            corner(0.0, 0.0, 1.0),
+            corner(1.0, 0.0, 1.0),
+            corner(1.0, 1.0, 1.0),
+            corner(0.0, 1.0, 1.0),
+        ];
+
+        self.face(&[c[3], c[2], c[1], c[0]]);
+        self.face(&[c[4], c[5], c[6], c[7]]);
+        self.face(&[c[0], c[1], c[5], c[4]]);
+        self.face(&[c[1], c[2], c[6], c[5]]);
+        self.face(&[c[2], c[3], c[7], c[6]]);
+        self.face(&[c[3], c[0], c[4], c[7]]);
+    }
+}
+
+/// The walls, light and blocks of the Cornell box, in meters, with the
+/// opening towards negative Y.
+fn cornell_box() -> String {
+    let (width, depth, height) = (5.56, 5.59, 5.49);
+    let corner = |x: f32, y: f32, z: f32| Point3::new(x * width, y * depth, z * height);
+
+    let mut obj = ObjWriter::new();
+
+    obj.object("floor");
+    obj.face(&[
+        corner(0.0, 0.0, 0.0),
+        corner(1.0, 0.0, 0.0),
+        corner(1.0, 1.0, 0.0),
+        corner(0.0, 1.0, 0.0),
+    ]);
+
+    obj.object("ceiling");
+    obj.face(&[
+        corner(0.0, 0.0, 1.0),
+        corner(0.0, 1.0, 1.0),
+        corner(1.0, 1.0, 1.0),
+        corner(1.0, 0.0, 1.0),
+    ]);
+
+    obj.object("back");
+    obj.face(&[
+        corner(0.0, 1.0, 0.0),
+        corner(1.0, 1.0, 0.0),
+        corner(1.0, 1.0, 1.0),
+        corner(0.0, 1.0, 1.0),
+    ]);
+
+    obj.object("left");
+    obj.face(&[
+        corner(0.0, 0.0, 0.0),
+        corner(0.0, 1.0, 0.0),
+        corner(0.0, 1.0, 1.0),
+        corner(0.0, 0.0, 1.0),
+    ]);
+
+    obj.object("right");
+    obj.face(&[
+        corner(1.0, 0.0, 0.0),
+        corner(1.0, 0.0, 1.0),
+        corner(1.0, 1.0, 1.0),
+        corner(1.0, 1.0, 0.0),
+    ]);
+
+    // The light is slightly below the ceiling, to keep them apart.
+    let light_z = height - 0.01;
+    obj.object("light");
+    obj.face(&[
+        Point3::new(2.13, 2.27, light_z),
+        Point3::new(2.13, 3.32, light_z),
+        Point3::new(3.43, 3.32, light_z),
+        Point3::new(3.43, 2.27, light_z),
+    ]);
+
+    obj.object("tall");
+    obj.block(
+        Point3::new(3.68, 3.51, 0.0),
+        Vector3::new(1.65, 1.65, 3.3),
+        17.0,
+    );
+
+    obj.object("short");
+    obj.block(
+        Point3::new(1.86, 1.69, 0.0),
+        Vector3::new(1.65, 1.65, 1.65),
+        -17.0,
+    );
+
+    obj.text
+}
+
+/// An equilateral prism, standing on one of its sides, with its length along
+/// Y. It's lifted a bit, so its bottom doesn't overlap with the floor.
+fn prism() -> String {
+    let (side, length, lift) = (1.2f32, 2.0f32, 0.005f32);
+    let half = side * 0.5;
+    let top = lift + side * (3.0f32).sqrt() * 0.5;
+    let end = |y: f32| {
+        [
+            Point3::new(-half, y, lift),
+            Point3::new(half, y, lift),
+            Point3::new(0.0, y, top),
+        ]
+    };
+    let [a1, b1, c1] = end(-length * 0.5);
+    let [a2, b2, c2] = end(length * 0.5);
+
+    let mut obj = ObjWriter::new();
+    obj.object("prism");
+    obj.face(&[a1, b1, c1]);
+    obj.face(&[c2, b2, a2]);
+    obj.face(&[a1, a2, b2, b1]);
+    obj.face(&[b1, b2, c2, c1]);
+    obj.face(&[c1, c2, a2, a1]);
+
+    obj.text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write, Example};
+    use crate::cli::Options;
+
+    /// Every example should load and get ready to render.
+    #[test]
+    fn dry_run_examples() {
+        for &name in &["cornell-box", "dispersion-prism", "material-ball"] {
+            let example = Example::parse(name).unwrap();
+            let directory = std::env::temp_dir().join(format!(
+                "pyrite-example-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&directory);
+            write(example, &directory).unwrap();
+
+            let project_path = directory.join("project.lua");
+            let args = vec!["--dry-run".into(), project_path.to_string_lossy().into()];
+            let options = Options::parse(args.into_iter()).unwrap();
+            let result = crate::run(&options, None, &mut crate::texture_cache(&options));
+            std::fs::remove_dir_all(&directory).unwrap();
+
+            if let Err(error) = result {
+                panic!("the {} example failed: {}", name, error);
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+-- The Cornell box, with a ceiling light and two blocks. The walls are in
+-- `box.obj`, with one object for each surface.
+
+local white = {surface = material.diffuse {color = 0.75}}
+local red = {surface = material.diffuse {color = rgb(0.63, 0.07, 0.05)}}
+local green = {surface = material.diffuse {color = rgb(0.12, 0.45, 0.15)}}
+
+return {
+    image = {width = 512, height = 512, white = blackbody(4000, {normalize = true})},
+
+    renderer = renderer.bidirectional {
+        pixel_samples = 200,
+        spectrum_samples = 10,
+        tile_size = 32,
+        light_samples = 4,
+        bounces = 6,
+        light_bounces = 6,
+    },
+
+    camera = camera.perspective {
+        fov = 37.7,
+        transform = transform.look_at {
+            from = vector(2.78, -8, 2.74),
+            to = vector(2.78, 0, 2.74),
+            up = vector {z = 1},
+        },
+    },
+
+    world = {
+        objects = {
+            shape.mesh {
+                file = "box.obj",
+                smooth = false,
+                materials = {
+                    light = {surface = material.emission {color = blackbody(4000, {normalize = true}) * 20}},
+                    left = red,
+                    right = green,
+                    floor = white,
+                    ceiling = white,
+                    back = white,
+                    tall = white,
+                    short = white,
+                },
+            },
+        },
+    },
+}
@@ -0,0 +1,53 @@
+-- A glass prism that splits the light from a small, bright lamp into a
+-- rainbow on the floor. The prism is in `prism.obj`.
+
+local glass = {
+    surface = material.refractive {
+        ior = 1.5168,
+        dispersion = 0.0042,
+        color = 1,
+    },
+}
+
+return {
+    image = {width = 768, height = 432},
+
+    renderer = renderer.bidirectional {
+        pixel_samples = 400,
+        spectrum_samples = 10,
+        tile_size = 32,
+        light_samples = 4,
+        bounces = 8,
+        light_bounces = 8,
+    },
+
+    camera = camera.perspective {
+        fov = 40,
+        transform = transform.look_at {
+            from = vector(0, -6, 4),
+            to = vector(0.5, 0, 0.3),
+            up = vector {z = 1},
+        },
+    },
+
+    world = {
+        sky = 0.01,
+        objects = {
+            shape.mesh {
+                file = "prism.obj",
+                smooth = false,
+                materials = {prism = glass},
+            },
+            shape.plane {
+                origin = vector(0, 0, 0),
+                normal = vector {z = 1},
+                material = {surface = material.diffuse {color = 0.8}},
+            },
+            shape.sphere {
+                position = vector(-4, 0, 1.2),
+                radius = 0.05,
+                material = {surface = material.emission {color = light_source.d65 * 4000}},
+            },
+        },
+    },
+}
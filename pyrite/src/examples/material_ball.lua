@@ -0,0 +1,54 @@
+-- A row of balls with some of the built in materials, on a floor that is lit
+-- by the sky and a warm point light.
+
+local ball = shape.sphere {radius = 0.5, position = vector(0, 0, 0.5)}
+
+local materials = {
+    material.diffuse {color = rgb(0.8, 0.3, 0.2)},
+    material.principled {base_color = rgb(0.2, 0.4, 0.8), roughness = 0.3, clearcoat = 1},
+    material.conductor {ior = metal.gold, roughness = 0.1},
+    material.refractive {ior = 1.5, color = 1},
+    material.conductor {ior = metal.copper},
+}
+
+local objects = {
+    shape.plane {
+        origin = vector(0, 0, 0),
+        normal = vector {z = 1},
+        material = {surface = material.diffuse {color = 0.5}},
+    },
+    light.point {position = vector(-2, -3, 4), radius = 0.2, color = blackbody(3000, {normalize = true}) * 200},
+}
+
+for i, surface in ipairs(materials) do
+    table.insert(objects, ball:with {
+        position = ball.position:with {x = (i - 3) * 1.2},
+        material = {surface = surface},
+    })
+end
+
+return {
+    image = {width = 768, height = 384},
+
+    renderer = renderer.simple {
+        pixel_samples = 200,
+        spectrum_samples = 10,
+        tile_size = 32,
+        light_samples = 4,
+        bounces = 8,
+    },
+
+    camera = camera.perspective {
+        fov = 40,
+        transform = transform.look_at {
+            from = vector(0, -6, 2),
+            to = vector(0, 0, 0.5),
+            up = vector {z = 1},
+        },
+    },
+
+    world = {
+        sky = rgb(0.5, 0.6, 0.8),
+        objects = objects,
+    },
+}
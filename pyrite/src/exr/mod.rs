@@ -0,0 +1,702 @@
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use cgmath::{Point2, Vector2};
+
+const MAGIC: u32 = 20000630;
+const VERSION: u32 = 2;
+const TILED_FLAG: u32 = 0x200;
+const LONG_NAMES_FLAG: u32 = 0x400;
+const UNSUPPORTED_FLAGS: u32 = 0x800 | 0x1000;
+
+const PIXEL_TYPE_UINT: i32 = 0;
+const PIXEL_TYPE_HALF: i32 = 1;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+const LEVEL_MODE_ONE_LEVEL: u8 = 0;
+const LEVEL_MODE_MIPMAP: u8 = 1;
+
+const TILE_HEADER_SIZE: u64 = 5 * 4;
+/// The most pixels in an image that is read, so a broken header can't make
+/// it allocate everything.
+const MAX_PIXELS: usize = 1 << 28;
+
+mod piz;
+
+/// A linear RGB image.
+pub(crate) type LinearImage = image::ImageBuffer<image::Rgb<f32>, Vec<f32>>;
+
+/// Writes an uncompressed, tiled OpenEXR file with 32 bit float channels.
+///
+/// The image is placed at `origin` in the display window, which makes it
+/// possible to write regions of larger images. Mipmaps are generated with a
+/// box filter and rounded down in size.
+pub(crate) fn write_tiled(
+    path: &Path,
+    image: &LinearImage,
+    origin: Point2<u32>,
+    display_size: Vector2<u32>,
+    tile_size: u32,
+    mipmaps: bool,
+) -> io::Result<()> {
+    if image.width() == 0 || image.height() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't write an empty image",
+        ));
+    }
+
+    let mut levels = vec![image.clone()];
+    if mipmaps {
+        while let Some(level) = downsample(levels.last().unwrap()) {
+            levels.push(level);
+        }
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+
+    file.write_all(&MAGIC.to_le_bytes())?;
+    file.write_all(&(VERSION | TILED_FLAG).to_le_bytes())?;
+
+    let mut channels = Vec::new();
+    for &name in &["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        channels.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved bytes
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+
+    let data_window = window(origin, Vector2::new(image.width(), image.height()));
+    let display_window = window(Point2::new(0, 0), display_size);
+
+    let mut tiles = Vec::with_capacity(9);
+    tiles.extend_from_slice(&tile_size.to_le_bytes());
+    tiles.extend_from_slice(&tile_size.to_le_bytes());
+    tiles.push(if mipmaps {
+        LEVEL_MODE_MIPMAP
+    } else {
+        LEVEL_MODE_ONE_LEVEL
+    });
+
+    write_attribute(&mut file, "channels", "chlist", &channels)?;
+    write_attribute(&mut file, "compression", "compression", &[0])?;
+    write_attribute(&mut file, "dataWindow", "box2i", &data_window)?;
+    write_attribute(&mut file, "displayWindow", "box2i", &display_window)?;
+    write_attribute(&mut file, "lineOrder", "lineOrder", &[0])?;
+    write_attribute(
+        &mut file,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    write_attribute(&mut file, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(
+        &mut file,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    write_attribute(&mut file, "tiles", "tiledesc", &tiles)?;
+    file.write_all(&[0])?;
+
+    let header_size = 8
+        + attribute_size("channels", "chlist", channels.len())
+        + attribute_size("compression", "compression", 1)
+        + attribute_size("dataWindow", "box2i", data_window.len())
+        + attribute_size("displayWindow", "box2i", display_window.len())
+        + attribute_size("lineOrder", "lineOrder", 1)
+        + attribute_size("pixelAspectRatio", "float", 4)
+        + attribute_size("screenWindowCenter", "v2f", 8)
+        + attribute_size("screenWindowWidth", "float", 4)
+        + attribute_size("tiles", "tiledesc", tiles.len())
+        + 1;
+
+    let tile_count: u64 = levels
+        .iter()
+        .map(|level| {
+            let (tiles_x, tiles_y) = tile_count(level, tile_size);
+            u64::from(tiles_x * tiles_y)
+        })
+        .sum();
+
+    // The offset table comes before the tiles, so the tile sizes have to be
+    // known in advance.
+    let mut offset = header_size + tile_count * 8;
+    for level in &levels {
+        for_each_tile(level, tile_size, |_, size| -> io::Result<()> {
+            file.write_all(&offset.to_le_bytes())?;
+            offset += TILE_HEADER_SIZE + u64::from(size.x * size.y) * 3 * 4;
+            Ok(())
+        })?;
+    }
+
+    for (level_index, level) in levels.iter().enumerate() {
+        for_each_tile(level, tile_size, |tile, size| -> io::Result<()> {
+            file.write_all(&(tile.x as i32).to_le_bytes())?;
+            file.write_all(&(tile.y as i32).to_le_bytes())?;
+            file.write_all(&(level_index as i32).to_le_bytes())?;
+            file.write_all(&(level_index as i32).to_le_bytes())?;
+            file.write_all(&((size.x * size.y * 3 * 4) as i32).to_le_bytes())?;
+
+            let from = tile * tile_size;
+            for y in from.y..from.y + size.y {
+                // Each line is stored one channel at the time, in the same
+                // order as the channel list.
+                for &channel in &[2, 1, 0] {
+                    for x in from.x..from.x + size.x {
+                        let value = level.get_pixel(x, y)[channel];
+                        file.write_all(&value.to_le_bytes())?;
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+    }
+
+    file.flush()
+}
+
+fn window(origin: Point2<u32>, size: Vector2<u32>) -> Vec<u8> {
+    let mut window = Vec::with_capacity(16);
+    window.extend_from_slice(&(origin.x as i32).to_le_bytes());
+    window.extend_from_slice(&(origin.y as i32).to_le_bytes());
+    window.extend_from_slice(&((origin.x + size.x) as i32 - 1).to_le_bytes());
+    window.extend_from_slice(&((origin.y + size.y) as i32 - 1).to_le_bytes());
+    window
+}
+
+fn write_attribute(file: &mut impl Write, name: &str, ty: &str, value: &[u8]) -> io::Result<()> {
+    file.write_all(name.as_bytes())?;
+    file.write_all(&[0])?;
+    file.write_all(ty.as_bytes())?;
+    file.write_all(&[0])?;
+    file.write_all(&(value.len() as i32).to_le_bytes())?;
+    file.write_all(value)
+}
+
+fn attribute_size(name: &str, ty: &str, value_size: usize) -> u64 {
+    (name.len() + 1 + ty.len() + 1 + 4 + value_size) as u64
+}
+
+fn tile_count(image: &LinearImage, tile_size: u32) -> (u32, u32) {
+    (
+        (image.width() + tile_size - 1) / tile_size,
+        (image.height() + tile_size - 1) / tile_size,
+    )
+}
+
+/// Visits the tiles of an image in increasing Y order, together with their
+/// size. The tiles along the right and bottom edges may be cut off.
+fn for_each_tile<E>(
+    image: &LinearImage,
+    tile_size: u32,
+    mut visit: impl FnMut(Point2<u32>, Vector2<u32>) -> Result<(), E>,
+) -> Result<(), E> {
+    let (tiles_x, tiles_y) = tile_count(image, tile_size);
+
+    for y in 0..tiles_y {
+        for x in 0..tiles_x {
+            let size = Vector2::new(
+                (image.width() - x * tile_size).min(tile_size),
+                (image.height() - y * tile_size).min(tile_size),
+            );
+            visit(Point2::new(x, y), size)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the data window of an OpenEXR file, with scan lines or tiles. The
+/// file may be uncompressed or use RLE, ZIP or PIZ compression. Only the
+/// first level of tiled files is read, and a missing color channel is
+/// replaced by `Y`, or 0.
+pub(crate) fn read(path: &Path) -> Result<LinearImage, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let mut reader = Reader {
+        data: &data,
+        position: 0,
+    };
+
+    if reader.u32()? != MAGIC {
+        return Err("not an OpenEXR file".into());
+    }
+
+    let version = reader.u32()?;
+    if version & UNSUPPORTED_FLAGS != 0 {
+        return Err("deep and multi-part files are not supported".into());
+    }
+    let tiled = version & TILED_FLAG != 0;
+    let max_name = if version & LONG_NAMES_FLAG != 0 {
+        255
+    } else {
+        31
+    };
+
+    let mut channels = Vec::new();
+    let mut compression = None;
+    let mut data_window = None;
+    let mut tile_size = None;
+
+    loop {
+        let name = reader.string(max_name)?;
+        if name.is_empty() {
+            break;
+        }
+        let ty = reader.string(max_name)?;
+        let size = reader.u32()? as usize;
+        let mut value = Reader {
+            data: reader.bytes(size)?,
+            position: 0,
+        };
+
+        match (&*name, &*ty) {
+            ("channels", "chlist") => loop {
+                let name = value.string(max_name)?;
+                if name.is_empty() {
+                    break;
+                }
+                let pixel_type = value.u32()? as i32;
+                value.bytes(4)?;
+                let sampling = (value.u32()?, value.u32()?);
+                if sampling != (1, 1) {
+                    return Err(format!("the channel {} is subsampled", name).into());
+                }
+                channels.push((name, pixel_type));
+            },
+            ("compression", "compression") => {
+                compression = Some(Compression::parse(value.bytes(1)?[0])?)
+            }
+            ("dataWindow", "box2i") => {
+                let min_x = value.u32()? as i32;
+                let min_y = value.u32()? as i32;
+                let max_x = value.u32()? as i32;
+                let max_y = value.u32()? as i32;
+                data_window = Some((min_x, min_y, max_x, max_y));
+            }
+            ("tiles", "tiledesc") => tile_size = Some((value.u32()?, value.u32()?)),
+            _ => {}
+        }
+    }
+
+    let compression = compression.ok_or("the compression is missing")?;
+    let table = reader.position;
+
+    let (min_x, min_y, max_x, max_y) = data_window.ok_or("the data window is missing")?;
+    if max_x < min_x || max_y < min_y {
+        return Err("the image is empty".into());
+    }
+    let window_size = |min: i32, max: i32| {
+        max.checked_sub(min)
+            .and_then(|size| size.checked_add(1))
+            .and_then(|size| u32::try_from(size).ok())
+    };
+    let (width, height) = window_size(min_x, max_x)
+        .zip(window_size(min_y, max_y))
+        .ok_or("the data window is too large")?;
+    let pixels = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|&pixels| pixels <= MAX_PIXELS)
+        .ok_or_else(|| format!("{}x{} pixels are too many", width, height))?;
+
+    for (name, pixel_type) in &channels {
+        if ![PIXEL_TYPE_UINT, PIXEL_TYPE_HALF, PIXEL_TYPE_FLOAT].contains(pixel_type) {
+            return Err(format!("the channel {} has an unknown type", name).into());
+        }
+    }
+
+    // Each pixel gets a value from every channel, and the ones that aren't
+    // colors are skipped.
+    let mut values = vec![[None; 3]; pixels];
+    let targets: Vec<Option<usize>> = channels
+        .iter()
+        .map(|(name, _)| match &**name {
+            "R" => Some(0),
+            "G" => Some(1),
+            "B" => Some(2),
+            "Y" => Some(3),
+            _ => None,
+        })
+        .collect();
+    let mut gray = vec![0.0; pixels];
+
+    let mut read_lines =
+        |packed: &[u8], from: Point2<u32>, size: Vector2<u32>| -> Result<(), Box<dyn Error>> {
+            let data = compression.decompress(packed, &channels, size)?;
+            let reader = &mut Reader {
+                data: &data,
+                position: 0,
+            };
+
+            for y in from.y..from.y + size.y {
+                for ((_, pixel_type), &target) in channels.iter().zip(&targets) {
+                    for x in from.x..from.x + size.x {
+                        let value = match *pixel_type {
+                            PIXEL_TYPE_UINT => reader.u32()? as f32,
+                            PIXEL_TYPE_HALF => half_to_f32(reader.u16()?),
+                            _ => f32::from_bits(reader.u32()?),
+                        };
+
+                        if x >= width || y >= height {
+                            continue;
+                        }
+
+                        let index = (x + y * width) as usize;
+                        match target {
+                            Some(3) => gray[index] = value,
+                            Some(channel) => values[index][channel] = Some(value),
+                            None => {}
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        };
+
+    if let Some((tile_width, tile_height)) = tile_size.filter(|_| tiled) {
+        if tile_width == 0 || tile_height == 0 {
+            return Err("the tiles are empty".into());
+        }
+
+        let tiles_x = (width - 1) / tile_width + 1;
+        let tiles_y = (height - 1) / tile_height + 1;
+
+        // The first level comes first in the offset table.
+        for index in 0..(tiles_x * tiles_y) as usize {
+            reader.seek_chunk(table, index)?;
+            let tile_x = reader.u32()?;
+            let tile_y = reader.u32()?;
+            reader.bytes(8)?;
+            let packed_size = reader.u32()? as usize;
+
+            if tile_x >= tiles_x || tile_y >= tiles_y {
+                return Err(format!("the tile {}, {} is outside the image", tile_x, tile_y).into());
+            }
+
+            let from = Point2::new(tile_x * tile_width, tile_y * tile_height);
+            let size = Vector2::new(
+                (width - from.x).min(tile_width),
+                (height - from.y).min(tile_height),
+            );
+            read_lines(reader.bytes(packed_size)?, from, size)?;
+        }
+    } else if tiled {
+        return Err("the tile description is missing".into());
+    } else {
+        let block_lines = compression.block_lines();
+        let blocks = (height - 1) / block_lines + 1;
+
+        for index in 0..blocks as usize {
+            reader.seek_chunk(table, index)?;
+            let line = reader.u32()? as i32;
+            let packed_size = reader.u32()? as usize;
+
+            let y = line
+                .checked_sub(min_y)
+                .and_then(|y| u32::try_from(y).ok())
+                .filter(|&y| y < height)
+                .ok_or_else(|| format!("the line {} is outside the image", line))?;
+            read_lines(
+                reader.bytes(packed_size)?,
+                Point2::new(0, y),
+                Vector2::new(width, (height - y).min(block_lines)),
+            )?;
+        }
+    }
+
+    Ok(image::ImageBuffer::from_fn(width, height, |x, y| {
+        let index = (x + y * width) as usize;
+        let [red, green, blue] = values[index];
+        let gray = gray[index];
+        image::Rgb([
+            red.unwrap_or(gray),
+            green.unwrap_or(gray),
+            blue.unwrap_or(gray),
+        ])
+    }))
+}
+
+#[derive(Copy, Clone)]
+enum Compression {
+    None,
+    Rle,
+    /// Zlib, one line at the time.
+    Zips,
+    /// Zlib, 16 lines at the time.
+    Zip,
+    Piz,
+}
+
+impl Compression {
+    fn parse(value: u8) -> Result<Self, Box<dyn Error>> {
+        match value {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Rle),
+            2 => Ok(Compression::Zips),
+            3 => Ok(Compression::Zip),
+            4 => Ok(Compression::Piz),
+            5 => Err("PXR24 compression is not supported".into()),
+            6 | 7 => Err("B44 compression is not supported".into()),
+            8 | 9 => Err("DWA compression is not supported".into()),
+            _ => Err(format!("unknown compression: {}", value).into()),
+        }
+    }
+
+    /// The number of scan lines that are compressed together.
+    fn block_lines(self) -> u32 {
+        match self {
+            Compression::None | Compression::Rle | Compression::Zips => 1,
+            Compression::Zip => 16,
+            Compression::Piz => 32,
+        }
+    }
+
+    /// Decompresses a block of `size` pixels, with `channels` interleaved on
+    /// each line.
+    fn decompress<'a>(
+        self,
+        packed: &'a [u8],
+        channels: &[(String, i32)],
+        size: Vector2<u32>,
+    ) -> Result<Cow<'a, [u8]>, Box<dyn Error>> {
+        let channel_sizes: Vec<usize> = channels
+            .iter()
+            .map(|&(_, pixel_type)| if pixel_type == PIXEL_TYPE_HALF { 1 } else { 2 })
+            .collect();
+        let unpacked_size =
+            channel_sizes.iter().sum::<usize>() * 2 * size.x as usize * size.y as usize;
+
+        // Blocks that wouldn't get any smaller are stored as they are.
+        if packed.len() >= unpacked_size {
+            return Ok(Cow::Borrowed(packed));
+        }
+
+        let unpacked = match self {
+            Compression::None => Cow::Borrowed(packed),
+            Compression::Rle => Cow::Owned(unpredict(decode_runs(packed)?)),
+            Compression::Zips | Compression::Zip => Cow::Owned(unpredict(
+                miniz_oxide::inflate::decompress_to_vec_zlib(packed)
+                    .map_err(|error| format!("invalid ZIP data: {:?}", error))?,
+            )),
+            Compression::Piz => Cow::Owned(piz::decompress(
+                packed,
+                &channel_sizes,
+                size.x as usize,
+                size.y as usize,
+            )?),
+        };
+
+        if unpacked.len() != unpacked_size {
+            return Err("a block has the wrong size after decompression".into());
+        }
+
+        Ok(unpacked)
+    }
+}
+
+/// Decodes runs of repeated bytes, where each run starts with a signed count.
+/// Negative counts are followed by that many bytes, and positive counts are
+/// followed by a single byte that is repeated one more time than the count.
+fn decode_runs(packed: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut reader = Reader {
+        data: packed,
+        position: 0,
+    };
+    let mut unpacked = Vec::with_capacity(packed.len() * 2);
+
+    while reader.position < packed.len() {
+        let count = reader.bytes(1)?[0] as i8;
+        if count < 0 {
+            unpacked.extend_from_slice(reader.bytes(-(count as isize) as usize)?);
+        } else {
+            let value = reader.bytes(1)?[0];
+            unpacked.resize(unpacked.len() + count as usize + 1, value);
+        }
+    }
+
+    Ok(unpacked)
+}
+
+/// Undoes the preparation for RLE and ZIP compression, where each byte was
+/// replaced by its difference to the previous one, and the bytes at even and
+/// odd positions were moved to the first and second half.
+fn unpredict(mut data: Vec<u8>) -> Vec<u8> {
+    for index in 1..data.len() {
+        data[index] = data[index - 1].wrapping_add(data[index]).wrapping_sub(128);
+    }
+
+    let (first, second) = data.split_at((data.len() + 1) / 2);
+    let mut output = Vec::with_capacity(data.len());
+    for (index, &byte) in first.iter().enumerate() {
+        output.push(byte);
+        if let Some(&byte) = second.get(index) {
+            output.push(byte);
+        }
+    }
+
+    output
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.data.len())
+            .ok_or("unexpected end of file")?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self, max_length: usize) -> Result<String, Box<dyn Error>> {
+        let start = self.position;
+        while self.bytes(1)?[0] != 0 {
+            if self.position - start > max_length {
+                return Err("a name is too long".into());
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&self.data[start..self.position - 1]).into_owned())
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Moves to the chunk at `index` in the offset table at `table`.
+    fn seek_chunk(&mut self, table: usize, index: usize) -> Result<(), Box<dyn Error>> {
+        self.position = table + index * 8;
+        self.position = self.u64()? as usize;
+        Ok(())
+    }
+}
+
+/// Converts a 16 bit float to a 32 bit float.
+fn half_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((half >> 10) & 0x1f);
+    let mantissa = f32::from(half & 0x3ff);
+
+    match exponent {
+        0 => sign * mantissa * 2.0f32.powi(-24),
+        31 if mantissa == 0.0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2.0f32.powi(exponent - 15),
+    }
+}
+
+/// Makes the next mipmap level, or returns `None` if the image is already a
+/// single pixel.
+fn downsample(image: &LinearImage) -> Option<LinearImage> {
+    if image.width() == 1 && image.height() == 1 {
+        return None;
+    }
+
+    let width = (image.width() / 2).max(1);
+    let height = (image.height() / 2).max(1);
+
+    Some(image::ImageBuffer::from_fn(width, height, |x, y| {
+        let mut sum = [0.0; 3];
+        let mut count = 0.0;
+
+        for source_y in y * 2..(y * 2 + 2).min(image.height()) {
+            for source_x in x * 2..(x * 2 + 2).min(image.width()) {
+                let pixel = image.get_pixel(source_x, source_y);
+                for (sum, &value) in sum.iter_mut().zip(&pixel.0) {
+                    *sum += value;
+                }
+                count += 1.0;
+            }
+        }
+
+        image::Rgb([sum[0] / count, sum[1] / count, sum[2] / count])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Point2, Vector2};
+
+    use super::{decode_runs, read, unpredict, write_tiled, LinearImage};
+
+    #[test]
+    fn rle_blocks_are_restored() {
+        let packed = [
+            255, 0, 8, 128, 250, 133, 128, 130, 128, 128, 121, 8, 128, 251, 137, 128, 126, 128, 128,
+        ];
+
+        let mut expected = vec![0; 20];
+        expected.extend_from_slice(&[5, 9, 5, 9, 7, 7, 7, 7, 7, 7]);
+        assert_eq!(unpredict(decode_runs(&packed).unwrap()), expected);
+    }
+
+    #[test]
+    fn broken_data_windows_are_rejected() {
+        let path = std::env::temp_dir().join(format!("pyrite-exr-{}.exr", std::process::id()));
+        let image = LinearImage::from_pixel(4, 4, image::Rgb([0.5, 0.5, 0.5]));
+        write_tiled(
+            &path,
+            &image,
+            Point2::new(0, 0),
+            Vector2::new(4, 4),
+            4,
+            false,
+        )
+        .unwrap();
+        let file = std::fs::read(&path).unwrap();
+        assert!(read(&path).is_ok());
+
+        let name = b"dataWindow\0box2i\0";
+        let window = file
+            .windows(name.len())
+            .position(|bytes| bytes == name)
+            .unwrap()
+            + name.len()
+            + 4;
+
+        for &(min_x, min_y, max_x, max_y) in &[
+            (3, 0, 0, 3),
+            (i32::MIN, 0, i32::MAX, 3),
+            (0, 0, 1 << 20, 1 << 20),
+        ] {
+            let mut broken = file.clone();
+            for (index, value) in [min_x, min_y, max_x, max_y].iter().enumerate() {
+                broken[window + index * 4..window + index * 4 + 4]
+                    .copy_from_slice(&value.to_le_bytes());
+            }
+
+            std::fs::write(&path, &broken).unwrap();
+            assert!(read(&path).is_err());
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
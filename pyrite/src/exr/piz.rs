@@ -0,0 +1,396 @@
+//! The PIZ compression of OpenEXR files, where the 16 bit words of each
+//! channel are mapped to a smaller range, transformed with a Haar wavelet and
+//! Huffman coded.
+
+use std::error::Error;
+
+use super::Reader;
+
+const BITMAP_SIZE: usize = 8192;
+const WORD_RANGE: usize = 1 << 16;
+/// The number of Huffman symbols, with room for the run length symbol.
+const SYMBOL_COUNT: usize = WORD_RANGE + 1;
+const MAX_CODE_LENGTH: usize = 58;
+const SHORT_ZERO_RUN: u64 = 59;
+const LONG_ZERO_RUN: u64 = 63;
+const SHORTEST_LONG_RUN: u64 = 2 + LONG_ZERO_RUN - SHORT_ZERO_RUN;
+/// Codes up to this length are decoded with a single table lookup.
+const TABLE_BITS: usize = 14;
+
+/// Decompresses a block of `lines` lines, where each channel has `width`
+/// values that are `channel_sizes` 16 bit words large. The result has the
+/// channels interleaved on each line, like uncompressed data.
+pub(super) fn decompress(
+    packed: &[u8],
+    channel_sizes: &[usize],
+    width: usize,
+    lines: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut reader = Reader {
+        data: packed,
+        position: 0,
+    };
+
+    let line_words = channel_sizes.iter().sum::<usize>() * width;
+    let mut words = vec![0; line_words * lines];
+    if words.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let min_non_zero = reader.u16()? as usize;
+    let max_non_zero = reader.u16()? as usize;
+    if max_non_zero >= BITMAP_SIZE {
+        return Err("the PIZ bitmap is too large".into());
+    }
+
+    let mut bitmap = [0; BITMAP_SIZE];
+    if min_non_zero <= max_non_zero {
+        bitmap[min_non_zero..=max_non_zero]
+            .copy_from_slice(reader.bytes(max_non_zero - min_non_zero + 1)?);
+    }
+    let (lut, max_value) = reverse_lut(&bitmap);
+
+    let length = reader.u32()? as usize;
+    decode_huffman(reader.bytes(length)?, &mut words)?;
+
+    // The channels are stored one after the other, and each of their words
+    // is transformed separately.
+    let mut start = 0;
+    for &size in channel_sizes {
+        let end = start + width * lines * size;
+        for offset in 0..size {
+            decode_wavelet(
+                &mut words[start + offset..end],
+                width,
+                size,
+                lines,
+                width * size,
+                max_value,
+            );
+        }
+        start = end;
+    }
+
+    for word in &mut words {
+        *word = lut[*word as usize];
+    }
+
+    let mut channel_starts = Vec::with_capacity(channel_sizes.len());
+    let mut start = 0;
+    for &size in channel_sizes {
+        channel_starts.push(start);
+        start += width * lines * size;
+    }
+
+    let mut unpacked = Vec::with_capacity(words.len() * 2);
+    for _ in 0..lines {
+        for (start, &size) in channel_starts.iter_mut().zip(channel_sizes) {
+            let end = *start + width * size;
+            for word in &words[*start..end] {
+                unpacked.extend_from_slice(&word.to_le_bytes());
+            }
+            *start = end;
+        }
+    }
+
+    Ok(unpacked)
+}
+
+/// Makes a table from the compact range of the words to their original
+/// values, which are the ones that are set in `bitmap`, as well as 0. The
+/// largest value in the compact range is also returned.
+fn reverse_lut(bitmap: &[u8; BITMAP_SIZE]) -> (Vec<u16>, u16) {
+    let mut lut = vec![0; WORD_RANGE];
+    let mut count = 0;
+
+    for value in 0..WORD_RANGE {
+        if value == 0 || bitmap[value >> 3] & (1 << (value & 7)) != 0 {
+            lut[count] = value as u16;
+            count += 1;
+        }
+    }
+
+    (lut, (count - 1) as u16)
+}
+
+/// Undoes a 2D Haar wavelet transform of `width` by `height` values, that are
+/// `x_step` and `y_step` words apart.
+fn decode_wavelet(
+    data: &mut [u16],
+    width: usize,
+    x_step: usize,
+    height: usize,
+    y_step: usize,
+    max_value: u16,
+) {
+    // Values that fit in 14 bits don't need to wrap around.
+    let decode: fn(u16, u16) -> (u16, u16) = if max_value < (1 << 14) {
+        decode_14
+    } else {
+        decode_16
+    };
+
+    let size = width.min(height);
+    let mut p = 1;
+    while p <= size {
+        p <<= 1;
+    }
+    p >>= 1;
+    let mut p2 = p;
+    p >>= 1;
+
+    while p >= 1 {
+        let (x_step_1, x_step_2) = (x_step * p, x_step * p2);
+        let (y_step_1, y_step_2) = (y_step * p, y_step * p2);
+        let last_y = y_step * (height - p2);
+        let last_x = x_step * (width - p2);
+
+        let mut y = 0;
+        while y <= last_y {
+            let mut x = y;
+            while x <= y + last_x {
+                let i01 = x + x_step_1;
+                let i10 = x + y_step_1;
+                let i11 = i10 + x_step_1;
+
+                let (a00, a10) = decode(data[x], data[i10]);
+                let (a01, a11) = decode(data[i01], data[i11]);
+                let (b00, b01) = decode(a00, a01);
+                let (b10, b11) = decode(a10, a11);
+                data[x] = b00;
+                data[i01] = b01;
+                data[i10] = b10;
+                data[i11] = b11;
+
+                x += x_step_2;
+            }
+
+            // The last column, if the width isn't divisible.
+            if width & p != 0 {
+                let i10 = x + y_step_1;
+                let (a00, a10) = decode(data[x], data[i10]);
+                data[x] = a00;
+                data[i10] = a10;
+            }
+
+            y += y_step_2;
+        }
+
+        // The last line, if the height isn't divisible.
+        if height & p != 0 {
+            let mut x = y;
+            while x <= y + last_x {
+                let i01 = x + x_step_1;
+                let (a00, a01) = decode(data[x], data[i01]);
+                data[x] = a00;
+                data[i01] = a01;
+
+                x += x_step_2;
+            }
+        }
+
+        p2 = p;
+        p >>= 1;
+    }
+}
+
+/// Turns an average and a difference back into two values.
+fn decode_14(low: u16, high: u16) -> (u16, u16) {
+    let low = i32::from(low as i16);
+    let high = i32::from(high as i16);
+    let a = low + (high & 1) + (high >> 1);
+    let b = a - high;
+
+    (a as i16 as u16, b as i16 as u16)
+}
+
+/// Like `decode_14`, but for values that wrapped around.
+fn decode_16(low: u16, high: u16) -> (u16, u16) {
+    let low = i32::from(low);
+    let high = i32::from(high);
+    let b = (low - (high >> 1)) & 0xffff;
+    let a = (high + b - 0x8000) & 0xffff;
+
+    (a as u16, b as u16)
+}
+
+enum TableEntry {
+    Empty,
+    Short {
+        symbol: u32,
+        length: usize,
+    },
+    /// Codes that start with the entry's bits, as their length, code and
+    /// symbol.
+    Long(Vec<(usize, u64, u32)>),
+}
+
+/// Decodes Huffman coded words, where the largest symbol repeats the
+/// previous word a number of times.
+fn decode_huffman(packed: &[u8], words: &mut [u16]) -> Result<(), Box<dyn Error>> {
+    let mut reader = Reader {
+        data: packed,
+        position: 0,
+    };
+
+    let min_symbol = reader.u32()? as usize;
+    let max_symbol = reader.u32()? as usize;
+    reader.bytes(4)?;
+    let bit_count = reader.u32()? as usize;
+    reader.bytes(4)?;
+
+    if min_symbol >= SYMBOL_COUNT || max_symbol >= SYMBOL_COUNT {
+        return Err("the Huffman table is too large".into());
+    }
+
+    let table = &packed[reader.position..];
+    let mut position = 0;
+    let mut read_bits = |count| {
+        if position + count > table.len() * 8 {
+            return Err("unexpected end of the Huffman table");
+        }
+        let bits = peek_bits(table, position, count);
+        position += count;
+        Ok(bits)
+    };
+
+    // The code lengths are stored with runs of unused symbols shortened.
+    let mut lengths = vec![0; SYMBOL_COUNT];
+    let mut symbol = min_symbol;
+    while symbol <= max_symbol {
+        let length = read_bits(6)?;
+        let zeros = if length == LONG_ZERO_RUN {
+            read_bits(8)? + SHORTEST_LONG_RUN
+        } else if length >= SHORT_ZERO_RUN {
+            length - SHORT_ZERO_RUN + 2
+        } else {
+            lengths[symbol] = length as usize;
+            symbol += 1;
+            continue;
+        };
+
+        symbol += zeros as usize;
+        if symbol > max_symbol + 1 {
+            return Err("the Huffman table has too many symbols".into());
+        }
+    }
+
+    let codes = &table[(position + 7) / 8..];
+    if bit_count > codes.len() * 8 {
+        return Err("the Huffman coded data is too short".into());
+    }
+
+    let table = decoding_table(&lengths)?;
+
+    let mut position = 0;
+    let mut written = 0usize;
+    while position < bit_count {
+        let (symbol, length) = match &table[peek_bits(codes, position, TABLE_BITS) as usize] {
+            &TableEntry::Short { symbol, length } => (symbol, length),
+            TableEntry::Long(long_codes) => long_codes
+                .iter()
+                .find(|&&(length, code, _)| peek_bits(codes, position, length) == code)
+                .map(|&(length, _, symbol)| (symbol, length))
+                .ok_or("invalid Huffman code")?,
+            TableEntry::Empty => return Err("invalid Huffman code".into()),
+        };
+
+        position += length;
+        if position > bit_count {
+            return Err("invalid Huffman code".into());
+        }
+
+        if symbol as usize == max_symbol {
+            let count = peek_bits(codes, position, 8) as usize;
+            position += 8;
+            if position > bit_count {
+                return Err("the Huffman coded data is too short".into());
+            }
+
+            let previous = match written.checked_sub(1) {
+                Some(index) => words[index],
+                None => return Err("a run has no value to repeat".into()),
+            };
+            let run = words
+                .get_mut(written..written + count)
+                .ok_or("the Huffman coded data is too long")?;
+            for word in run {
+                *word = previous;
+            }
+            written += count;
+        } else {
+            *words
+                .get_mut(written)
+                .ok_or("the Huffman coded data is too long")? = symbol as u16;
+            written += 1;
+        }
+    }
+
+    if written != words.len() {
+        return Err("the Huffman coded data is too short".into());
+    }
+
+    Ok(())
+}
+
+/// Assigns canonical codes to the symbols, where longer codes come before
+/// shorter codes, and makes a table for looking them up.
+fn decoding_table(lengths: &[usize]) -> Result<Vec<TableEntry>, Box<dyn Error>> {
+    let mut next_codes = [0u64; MAX_CODE_LENGTH + 1];
+    for &length in lengths {
+        next_codes[length] += 1;
+    }
+
+    let mut code = 0;
+    for length in (1..=MAX_CODE_LENGTH).rev() {
+        let next_code = (code + next_codes[length]) >> 1;
+        next_codes[length] = code;
+        code = next_code;
+    }
+
+    let mut table: Vec<_> = (0..1 << TABLE_BITS).map(|_| TableEntry::Empty).collect();
+    for (symbol, &length) in lengths.iter().enumerate() {
+        if length == 0 {
+            continue;
+        }
+
+        let code = next_codes[length];
+        next_codes[length] += 1;
+        if code >> length != 0 {
+            return Err("invalid Huffman code length".into());
+        }
+
+        let symbol = symbol as u32;
+        if length <= TABLE_BITS {
+            let first = (code << (TABLE_BITS - length)) as usize;
+            for entry in &mut table[first..first + (1 << (TABLE_BITS - length))] {
+                if !matches!(entry, TableEntry::Empty) {
+                    return Err("invalid Huffman table".into());
+                }
+                *entry = TableEntry::Short { symbol, length };
+            }
+        } else {
+            let entry = &mut table[(code >> (length - TABLE_BITS)) as usize];
+            match entry {
+                TableEntry::Empty => *entry = TableEntry::Long(vec![(length, code, symbol)]),
+                TableEntry::Long(long_codes) => long_codes.push((length, code, symbol)),
+                TableEntry::Short { .. } => return Err("invalid Huffman table".into()),
+            }
+        }
+    }
+
+    Ok(table)
+}
+
+/// Reads `count` bits, starting from the highest bit of the first byte. Bits
+/// after the end are 0.
+fn peek_bits(data: &[u8], position: usize, count: usize) -> u64 {
+    let first = position / 8;
+    let mut bits = 0u128;
+    for index in first..first + 9 {
+        bits = (bits << 8) | u128::from(data.get(index).copied().unwrap_or(0));
+    }
+
+    let shift = 9 * 8 - position % 8 - count;
+    (bits >> shift) as u64 & ((1 << count) - 1)
+}
@@ -1,3 +1,10 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
 use crossbeam::atomic::AtomicCell;
 
 use noisy_float::prelude::*;
@@ -6,15 +13,43 @@ use cgmath::{BaseNum, Point2, Vector2};
 
 use rand::Rng;
 
+use crate::math::utils::Distribution;
+
+/// Identifies raw film files, followed by a format version number. See
+/// `Film::save` for the rest of the format.
+const FILM_MAGIC: &[u8; 8] = b"PYRFILM\0";
+/// Version 2 added coverage, version 3 added the sampler state, and version 4
+/// added the holdout flag.
+const FILM_VERSION: u32 = 4;
+
+/// Where a render is in its random sequences. The seed is picked for each
+/// new film, and `samples` is the number of samples per pixel that have been
+/// rendered into it, so a resumed render continues the sequences instead of
+/// repeating the same samples.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SamplerState {
+    pub seed: u64,
+    pub samples: u32,
+}
+
 pub struct Film {
     width: usize,
     height: usize,
+    region: Area<usize>,
     aspect_ratio: AspectRatio,
     grains_per_pixel: usize,
     wavelength_start: f32,
     wavelength_width: f32,
     grains_per_wavelength: f32,
+    wavelength_distribution: Option<Distribution>,
     grains: Vec<Grain>,
+    /// How much of each pixel is covered by surfaces, instead of the
+    /// background.
+    coverage: Vec<Grain>,
+    sampler: AtomicCell<SamplerState>,
+    /// Camera rays that miss every surface leave the pixels black, instead of
+    /// showing the background.
+    holdout: bool,
 }
 
 impl Film {
@@ -24,22 +59,72 @@ impl Film {
         grains_per_pixel: usize,
         wavelength_span: (f32, f32),
     ) -> Self {
-        let length = width * height * grains_per_pixel;
+        Self::with_region(
+            width,
+            height,
+            Area::new(Point2::new(0, 0), Vector2::new(width, height)),
+            grains_per_pixel,
+            wavelength_span,
+        )
+    }
+
+    /// Creates a sparse film, where only the pixels within `region` are
+    /// stored. Anything outside it is discarded when exposed.
+    pub fn with_region(
+        width: usize,
+        height: usize,
+        region: Area<usize>,
+        grains_per_pixel: usize,
+        wavelength_span: (f32, f32),
+    ) -> Self {
+        let length = region.size.x * region.size.y * grains_per_pixel;
         let (wavelength_start, wavelength_end) = wavelength_span;
         let wavelength_width = wavelength_end - wavelength_start;
 
         Self {
             width,
             height,
+            region,
             aspect_ratio: AspectRatio::new(width, height),
             grains_per_pixel,
             wavelength_start,
             wavelength_width,
             grains_per_wavelength: grains_per_pixel as f32 / wavelength_width,
+            wavelength_distribution: None,
             grains: std::iter::repeat_with(Grain::new).take(length).collect(),
+            coverage: std::iter::repeat_with(Grain::new)
+                .take(region.size.x * region.size.y)
+                .collect(),
+            sampler: AtomicCell::new(SamplerState {
+                seed: rand::random(),
+                samples: 0,
+            }),
+            holdout: false,
         }
     }
 
+    pub fn sampler_state(&self) -> SamplerState {
+        self.sampler.load()
+    }
+
+    pub fn set_sampler_state(&self, state: SamplerState) {
+        self.sampler.store(state);
+    }
+
+    pub fn holdout(&self) -> bool {
+        self.holdout
+    }
+
+    pub fn set_holdout(&mut self, holdout: bool) {
+        self.holdout = holdout;
+    }
+
+    /// The number of bytes a film with the given dimensions will occupy.
+    pub fn memory_size(width: usize, height: usize, grains_per_pixel: usize) -> usize {
+        width * height * (grains_per_pixel + 1) * std::mem::size_of::<Grain>()
+            + std::mem::size_of::<Film>()
+    }
+
     pub fn width(&self) -> usize {
         self.width
     }
@@ -48,38 +133,114 @@ impl Film {
         self.height
     }
 
+    /// The part of the image that is stored in this film.
+    pub fn region(&self) -> Area<usize> {
+        self.region
+    }
+
+    pub fn grains_per_pixel(&self) -> usize {
+        self.grains_per_pixel
+    }
+
+    pub fn wavelength_span(&self) -> (f32, f32) {
+        (
+            self.wavelength_start,
+            self.wavelength_start + self.wavelength_width,
+        )
+    }
+
     pub fn get_pixel(&self, position: Point2<usize>) -> Option<&[Grain]> {
-        if position.x >= self.width || position.y >= self.height {
+        let index = self.pixel_index(position)? * self.grains_per_pixel;
+        Some(&self.grains[index..index + self.grains_per_pixel])
+    }
+
+    fn pixel_index(&self, position: Point2<usize>) -> Option<usize> {
+        if !self.region.contains(position) {
             return None;
         }
 
-        let index = (position.x + position.y * self.width) * self.grains_per_pixel;
-        Some(&self.grains[index..index + self.grains_per_pixel])
+        let x = position.x - self.region.from.x;
+        let y = position.y - self.region.from.y;
+        Some(x + y * self.region.size.x)
     }
 
     pub fn get_pixel_f(&self, position: Point2<f32>) -> Option<&[Grain]> {
         self.get_pixel(self.aspect_ratio.to_pixel(position)?)
     }
 
+    /// Makes `sample_wavelength` pick wavelengths in proportion to `weights`,
+    /// which are evenly spread over the wavelength span. Exposed samples are
+    /// weighted to make up for it.
+    pub fn sample_wavelengths_by(&mut self, weights: &[f32]) {
+        self.wavelength_distribution = Distribution::new(weights);
+    }
+
     pub fn sample_wavelength<R: Rng>(&self, rng: &mut R) -> f32 {
+        if let Some(distribution) = &self.wavelength_distribution {
+            return self.wavelength_start + distribution.sample(rng) * self.wavelength_width;
+        }
+
         rng.gen_range(
             self.wavelength_start,
             self.wavelength_start + self.wavelength_width,
         )
     }
 
+    /// The weight of a sample, compensating for how often its wavelength is
+    /// picked.
+    fn sample_weight(&self, sample: &Sample) -> f32 {
+        match &self.wavelength_distribution {
+            Some(distribution) => {
+                let position = (sample.wavelength - self.wavelength_start) / self.wavelength_width;
+                sample.weight / distribution.density(position)
+            }
+            None => sample.weight,
+        }
+    }
+
     fn wavelength_to_grain(&self, wavelength: f32) -> usize {
-        ((wavelength - self.wavelength_start) * self.grains_per_wavelength) as usize
+        // Sampled wavelengths can be rounded up to the end of the span.
+        let grain = ((wavelength - self.wavelength_start) * self.grains_per_wavelength) as usize;
+        grain.min(self.grains_per_pixel - 1)
     }
 
     pub fn expose(&self, position: Point2<f32>, sample: Sample) {
         let grain_index = self.wavelength_to_grain(sample.wavelength);
 
         if let Some(pixel) = self.get_pixel_f(position) {
-            pixel[grain_index].expose(sample.brightness, sample.weight);
+            pixel[grain_index].expose(sample.brightness, self.sample_weight(&sample));
         }
     }
 
+    /// Records whether a camera ray through `position` hit a surface.
+    pub fn expose_coverage(&self, position: Point2<f32>, covered: bool) {
+        let index = self
+            .aspect_ratio
+            .to_pixel(position)
+            .and_then(|pixel| self.pixel_index(pixel));
+
+        if let Some(index) = index {
+            self.coverage[index].expose(if covered { 1.0 } else { 0.0 }, 1.0);
+        }
+    }
+
+    /// The coverage of each pixel in the region, in the same order as
+    /// `developed_pixels`. Pixels without any coverage samples, such as from
+    /// older films, are fully covered.
+    pub fn developed_coverage(&self) -> impl Iterator<Item = f32> + '_ {
+        self.coverage
+            .iter()
+            .map(|grain| grain.develop().unwrap_or(1.0))
+    }
+
+    /// The number of camera samples in each pixel of the region, in the same
+    /// order as `developed_pixels`.
+    pub fn sample_counts(&self) -> impl Iterator<Item = f32> + '_ {
+        self.coverage
+            .iter()
+            .map(|grain| grain.data.load().weight.raw())
+    }
+
     pub fn get_pixel_ref_f(&self, position: Point2<f32>) -> Option<DetachedPixel> {
         Some(DetachedPixel {
             grains: self.get_pixel_f(position)?,
@@ -89,7 +250,7 @@ impl Film {
     pub fn to_pixel_sample(&self, sample: &Sample) -> PixelSample {
         PixelSample {
             value: sample.brightness,
-            weight: sample.weight,
+            weight: self.sample_weight(sample),
             grain: self.wavelength_to_grain(sample.wavelength),
         }
     }
@@ -97,6 +258,197 @@ impl Film {
     pub fn developed_pixels(&self) -> DevelopedPixels<'_> {
         DevelopedPixels::new(self)
     }
+
+    /// Adds the samples from another film, which may cover a smaller region
+    /// of the same image.
+    pub fn merge(&self, other: &Film) -> Result<(), Box<dyn Error>> {
+        if self.width != other.width || self.height != other.height {
+            return Err(format!(
+                "the image sizes are different ({}x{} and {}x{})",
+                self.width, self.height, other.width, other.height
+            )
+            .into());
+        }
+
+        if self.grains_per_pixel != other.grains_per_pixel
+            || self.wavelength_span() != other.wavelength_span()
+        {
+            return Err("the spectrum settings are different".into());
+        }
+
+        if self.holdout != other.holdout {
+            return Err("only one of the films holds out the background".into());
+        }
+
+        if !self.region.contains_area(&other.region) {
+            return Err("the region is outside this film".into());
+        }
+
+        for y in 0..other.region.size.y {
+            for x in 0..other.region.size.x {
+                let position = other.region.from + Vector2::new(x, y);
+                let pixel = self
+                    .get_pixel(position)
+                    .expect("pixel should be in the region");
+                let other_pixel = other
+                    .get_pixel(position)
+                    .expect("pixel should be in the region");
+
+                for (grain, other_grain) in pixel.iter().zip(other_pixel) {
+                    grain.merge(other_grain);
+                }
+
+                let coverage = self
+                    .pixel_index(position)
+                    .expect("pixel should be in the region");
+                let other_coverage = other
+                    .pixel_index(position)
+                    .expect("pixel should be in the region");
+                self.coverage[coverage].merge(&other.coverage[other_coverage]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the raw, undeveloped film to a file. Films from separate
+    /// renders of the same image can be added together, since it stores
+    /// sums instead of averages. Everything is little endian:
+    ///
+    /// * The magic bytes, and the version as a `u32`.
+    /// * The image width and height, the region's x, y, width and height,
+    ///   and the number of grains per pixel, as `u32`.
+    /// * The first wavelength and the width of the wavelength span, in
+    ///   nanometers, as `f32`.
+    /// * The sampler's seed as a `u64`, and its number of rendered samples
+    ///   per pixel as a `u32`.
+    /// * Whether the background is held out, as a `u32` that is 1 or 0.
+    /// * Each grain, row by row within the region and with the grains of
+    ///   each pixel in order of wavelength, as two `f32`: the sum of the
+    ///   weighted samples and the sum of their weights.
+    /// * The coverage of each pixel, as two `f32`: the number of camera
+    ///   samples that hit a surface, and the total number of camera samples.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(FILM_MAGIC)?;
+        for value in &[
+            FILM_VERSION,
+            self.width as u32,
+            self.height as u32,
+            self.region.from.x as u32,
+            self.region.from.y as u32,
+            self.region.size.x as u32,
+            self.region.size.y as u32,
+            self.grains_per_pixel as u32,
+        ] {
+            file.write_all(&value.to_le_bytes())?;
+        }
+        file.write_all(&self.wavelength_start.to_le_bytes())?;
+        file.write_all(&self.wavelength_width.to_le_bytes())?;
+
+        let sampler = self.sampler_state();
+        file.write_all(&sampler.seed.to_le_bytes())?;
+        file.write_all(&sampler.samples.to_le_bytes())?;
+        file.write_all(&(self.holdout as u32).to_le_bytes())?;
+
+        for grain in self.grains.iter().chain(&self.coverage) {
+            let GrainData {
+                accumulator,
+                weight,
+            } = grain.data.load();
+            file.write_all(&accumulator.raw().to_le_bytes())?;
+            file.write_all(&weight.raw().to_le_bytes())?;
+        }
+
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Reads a raw film that was written by `save`. Films from before the
+    /// sampler state was stored get a new seed, and no samples.
+    pub fn load(path: &Path) -> Result<Film, Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != FILM_MAGIC {
+            return Err(format!("{} is not a film file", path.display()).into());
+        }
+
+        let version = read_u32(&mut file)?;
+        if version == 0 || version > FILM_VERSION {
+            return Err(format!("unsupported film version: {}", version).into());
+        }
+
+        let width = read_u32(&mut file)? as usize;
+        let height = read_u32(&mut file)? as usize;
+        let region = Area::new(
+            Point2::new(read_u32(&mut file)? as usize, read_u32(&mut file)? as usize),
+            Vector2::new(read_u32(&mut file)? as usize, read_u32(&mut file)? as usize),
+        );
+        let grains_per_pixel = read_u32(&mut file)? as usize;
+        let wavelength_start = read_f32(&mut file)?;
+        let wavelength_width = read_f32(&mut file)?;
+        let sampler = if version >= 3 {
+            let mut seed = [0; 8];
+            file.read_exact(&mut seed)?;
+            Some(SamplerState {
+                seed: u64::from_le_bytes(seed),
+                samples: read_u32(&mut file)?,
+            })
+        } else {
+            None
+        };
+        let holdout = version >= 4 && read_u32(&mut file)? != 0;
+
+        if !Area::new(Point2::new(0, 0), Vector2::new(width, height)).contains_area(&region) {
+            return Err("the film region is outside the image".into());
+        }
+
+        let mut film = Film::with_region(
+            width,
+            height,
+            region,
+            grains_per_pixel,
+            (wavelength_start, wavelength_start + wavelength_width),
+        );
+        film.set_holdout(holdout);
+        if let Some(sampler) = sampler {
+            film.set_sampler_state(sampler);
+        }
+
+        // Version 1 films have no coverage, and are treated as fully covered.
+        let coverage: &[Grain] = if version >= 2 { &film.coverage } else { &[] };
+
+        for grain in film.grains.iter().chain(coverage) {
+            let accumulator = N32::try_new(read_f32(&mut file)?);
+            let weight = N32::try_new(read_f32(&mut file)?);
+
+            match (accumulator, weight) {
+                (Some(accumulator), Some(weight)) => grain.data.store(GrainData {
+                    accumulator,
+                    weight,
+                }),
+                _ => return Err("the film contains invalid values".into()),
+            }
+        }
+
+        Ok(film)
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> std::io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
 }
 
 #[repr(transparent)]
@@ -115,19 +467,29 @@ impl Grain {
         self.increment(value * weight, weight);
     }
 
-    pub fn develop(&self) -> f32 {
+    /// The weighted average of the exposed values, or `None` if nothing has
+    /// been exposed yet.
+    pub fn develop(&self) -> Option<f32> {
         let GrainData {
             weight,
             accumulator,
         } = self.data.load();
 
         if weight > 0.0 {
-            (accumulator / weight).into()
+            Some((accumulator / weight).into())
         } else {
-            0.0
+            None
         }
     }
 
+    fn merge(&self, other: &Grain) {
+        let GrainData {
+            accumulator,
+            weight,
+        } = other.data.load();
+        self.increment(accumulator.raw(), weight.raw());
+    }
+
     fn increment(&self, increment: f32, weight: f32) {
         let mut currant_data = self.data.load();
         loop {
@@ -233,6 +595,7 @@ enum Orientation {
     Vertical,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct Area<S> {
     pub from: Point2<S>,
     pub size: Vector2<S>,
@@ -252,6 +615,46 @@ impl<S> Area<S> {
     {
         self.from + self.size / (S::one() + S::one())
     }
+
+    pub fn contains(&self, point: Point2<S>) -> bool
+    where
+        S: BaseNum,
+    {
+        point.x >= self.from.x
+            && point.y >= self.from.y
+            && point.x < self.from.x + self.size.x
+            && point.y < self.from.y + self.size.y
+    }
+
+    /// The smallest area that contains both areas.
+    pub fn union(&self, other: &Area<S>) -> Area<S>
+    where
+        S: BaseNum,
+    {
+        let min = |a: S, b: S| if a < b { a } else { b };
+        let max = |a: S, b: S| if a > b { a } else { b };
+
+        let from = Point2::new(
+            min(self.from.x, other.from.x),
+            min(self.from.y, other.from.y),
+        );
+        let to = Point2::new(
+            max(self.from.x + self.size.x, other.from.x + other.size.x),
+            max(self.from.y + self.size.y, other.from.y + other.size.y),
+        );
+
+        Area::new(from, to - from)
+    }
+
+    pub fn contains_area(&self, other: &Area<S>) -> bool
+    where
+        S: BaseNum,
+    {
+        other.from.x >= self.from.x
+            && other.from.y >= self.from.y
+            && other.from.x + other.size.x <= self.from.x + self.size.x
+            && other.from.y + other.size.y <= self.from.y + self.size.y
+    }
 }
 
 #[derive(Clone)]
@@ -278,7 +681,7 @@ impl<'a> Iterator for DevelopedPixels<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let end = self.index + self.film.grains_per_pixel;
 
-        let result = if end < self.film.grains.len() {
+        let result = if end <= self.film.grains.len() {
             Some(Spectrum {
                 min: self.film.wavelength_start,
                 max: self.film.wavelength_start + self.film.wavelength_width,
@@ -313,8 +716,34 @@ impl<'a> Spectrum<'a> {
                 let float_index = normalized * self.grains.len() as f32;
                 let index = (float_index.floor() as usize).min(self.grains.len() - 1);
 
-                self.grains[index].develop()
+                self.grains[index]
+                    .develop()
+                    .unwrap_or_else(|| self.estimate_missing(index))
+            }
+        }
+    }
+
+    /// Estimates the value of a grain without samples from its closest
+    /// neighbors. Pixels with fewer samples, such as from crops or resumed
+    /// renders, would otherwise be darkened by their missing wavelengths.
+    fn estimate_missing(&self, index: usize) -> f32 {
+        let below = self.grains[..index]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, grain)| grain.develop().map(|value| (i, value)));
+        let above = self.grains[index + 1..]
+            .iter()
+            .enumerate()
+            .find_map(|(i, grain)| grain.develop().map(|value| (index + 1 + i, value)));
+
+        match (below, above) {
+            (Some((below_index, below_value)), Some((above_index, above_value))) => {
+                let mix = (index - below_index) as f32 / (above_index - below_index) as f32;
+                below_value * (1.0 - mix) + above_value * mix
             }
+            (Some((_, value)), None) | (None, Some((_, value))) => value,
+            (None, None) => 0.0,
         }
     }
 
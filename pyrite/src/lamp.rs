@@ -1,34 +1,57 @@
-use std;
+use std::{self, f32::consts::PI};
 
 use cgmath::{InnerSpace, Point2, Point3, Vector3};
 use collision::Ray3;
 
 use rand::Rng;
 
-use crate::math::utils::{sample_cone, sample_hemisphere, sample_sphere};
-use crate::shapes::{Intersection, Shape, SurfaceData};
-use crate::{materials::Material, tracer::LightProgram};
+use crate::math::utils::{sample_cone, sample_hemisphere, sample_sphere, Distribution};
+use crate::shapes::{Intersection, Primitive, SurfaceData};
+use crate::{
+    materials::{MaterialId, Materials},
+    texture::Texture,
+    tracer::LightProgram,
+};
 
 pub(crate) enum Lamp<'p> {
+    /// A light that is infinitely far away, and covers a cone of directions.
+    /// The color is the irradiance of a surface that faces it.
     Directional {
         direction: Vector3<f32>,
-        width: f32,
+        cos_half: f32,
         color: LightProgram<'p>,
+        link: Option<usize>,
     },
-    Point(Point3<f32>, LightProgram<'p>),
-    Shape(&'p Shape<'p>),
+    /// A point, or a sphere if it has a radius. Both are equally bright.
+    Point {
+        position: Point3<f32>,
+        radius: f32,
+        color: LightProgram<'p>,
+        link: Option<usize>,
+    },
+    Shape(&'p dyn Primitive),
 }
 
 impl<'p> Lamp<'p> {
+    /// The light's index among the named lights, for light linking.
+    /// Emissive shapes are named by their material.
+    pub fn link(&self, materials: &Materials<'p>) -> Option<usize> {
+        match *self {
+            Lamp::Directional { link, .. } | Lamp::Point { link, .. } => link,
+            Lamp::Shape(shape) => materials.get(shape.material()).light_link(),
+        }
+    }
+
     pub fn sample(&self, rng: &mut impl Rng, target: Point3<f32>) -> Sample<'_> {
         match *self {
             Lamp::Directional {
                 direction,
-                width,
+                cos_half,
                 color,
+                ..
             } => {
-                let dir = if width > 0.0 {
-                    sample_cone(rng, direction, width)
+                let dir = if cos_half < 1.0 {
+                    sample_cone(rng, direction, cos_half)
                 } else {
                     direction
                 };
@@ -40,20 +63,45 @@ impl<'p> Lamp<'p> {
                     weight: 1.0,
                 }
             }
-            Lamp::Point(ref center, color) => {
-                let v = center - target;
+            Lamp::Point {
+                position,
+                radius,
+                color,
+                ..
+            } => {
+                let v = position - target;
                 let distance = v.magnitude2();
+                let weight = 4.0 * std::f32::consts::PI / distance;
+                let radius2 = radius * radius;
+
+                if radius2 >= distance || radius <= 0.0 {
+                    return Sample {
+                        direction: v.normalize(),
+                        sq_distance: Some(distance),
+                        surface: Surface::Color(color),
+                        weight,
+                    };
+                }
+
+                // Picks a direction towards the visible part of the sphere,
+                // and stops the shadow ray at its surface.
+                let cos_half = (1.0 - radius2 / distance).sqrt();
+                let direction = sample_cone(rng, v.normalize(), cos_half);
+                let along = direction.dot(v);
+                let surface_distance = along - (along * along - distance + radius2).max(0.0).sqrt();
+
                 Sample {
-                    direction: v.normalize(),
-                    sq_distance: Some(distance),
+                    direction,
+                    sq_distance: Some(surface_distance * surface_distance),
                     surface: Surface::Color(color),
-                    weight: 4.0 * std::f32::consts::PI / distance,
+                    weight,
                 }
             }
             Lamp::Shape(ref shape) => {
                 let Intersection {
                     distance,
                     surface_point,
+                    ..
                 } = shape
                     .sample_towards(rng, &target)
                     .expect("trying to use infinite shape in direct lighting");
@@ -71,9 +119,10 @@ impl<'p> Lamp<'p> {
                     direction,
                     sq_distance: Some(sq_distance),
                     surface: Surface::Physical {
+                        position: surface_point.position,
                         normal: normal.vector(),
                         texture,
-                        material: shape.get_material(),
+                        material: shape.material(),
                     },
                     weight,
                 }
@@ -84,10 +133,15 @@ impl<'p> Lamp<'p> {
     pub fn sample_ray(&self, rng: &mut impl Rng) -> Option<RaySample<'_>> {
         match *self {
             Lamp::Directional { .. } => None,
-            Lamp::Point(center, color) => {
+            Lamp::Point {
+                position,
+                radius,
+                color,
+                ..
+            } => {
                 let direction = sample_sphere(rng);
                 Some(RaySample {
-                    ray: Ray3::new(center, direction),
+                    ray: Ray3::new(position + direction * radius, direction),
                     surface: Surface::Color(color),
                     weight: (4.0 * std::f32::consts::PI),
                 })
@@ -102,9 +156,10 @@ impl<'p> Lamp<'p> {
                 Some(RaySample {
                     ray: Ray3::new(surface_point.position, direction),
                     surface: Surface::Physical {
+                        position: surface_point.position,
                         normal: normal.vector(),
                         texture,
-                        material: shape.get_material(),
+                        material: shape.material(),
                     },
                     weight: shape.surface_area(),
                 })
@@ -113,6 +168,169 @@ impl<'p> Lamp<'p> {
     }
 }
 
+/// Picks sky directions in proportion to the brightness of an environment
+/// map, so bright spots like the sun are found without hitting them by
+/// chance.
+pub(crate) struct SkySampler {
+    rotation: f32,
+    width: usize,
+    /// Picks a row, from the top of the image.
+    rows: Distribution,
+    /// Picks a column within each row. Black rows are never picked.
+    columns: Vec<Option<Distribution>>,
+}
+
+impl SkySampler {
+    /// Returns `None` if the image is black.
+    pub fn new(texture: &Texture, rotation: f32) -> Option<Self> {
+        let (width, height) = (texture.width(), texture.height());
+        let mut row_weights = Vec::with_capacity(height);
+        let mut columns = Vec::with_capacity(height);
+
+        for y in 0..height {
+            // The rows near the poles cover smaller parts of the sky.
+            let elevation = (0.5 - (y as f32 + 0.5) / height as f32) * PI;
+            let weights: Vec<f32> = (0..width)
+                .map(|x| {
+                    let color = texture.color_at(x, y).color;
+                    (0.2126 * color.red + 0.7152 * color.green + 0.0722 * color.blue).max(0.0)
+                        * elevation.cos()
+                })
+                .collect();
+
+            row_weights.push(weights.iter().sum());
+            columns.push(Distribution::new(&weights));
+        }
+
+        Some(SkySampler {
+            rotation,
+            width,
+            rows: Distribution::new(&row_weights)?,
+            columns,
+        })
+    }
+
+    /// The width and height of the environment map.
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width, self.columns.len())
+    }
+
+    pub fn rotation(&self) -> f32 {
+        self.rotation
+    }
+
+    /// Picks a direction and returns it with its probability density, over
+    /// solid angle.
+    pub fn sample(&self, rng: &mut impl Rng) -> (Vector3<f32>, f32) {
+        let row = self.rows.sample(rng);
+        let index = ((row * self.columns.len() as f32) as usize).min(self.columns.len() - 1);
+        let column = match self.columns[index] {
+            Some(ref columns) => columns.sample(rng),
+            None => rng.gen(),
+        };
+
+        let azimuth = (0.5 - column) * 2.0 * PI + self.rotation;
+        let elevation = (0.5 - row) * PI;
+        let (sin_azimuth, cos_azimuth) = azimuth.sin_cos();
+        let (sin_elevation, cos_elevation) = elevation.sin_cos();
+        let direction = Vector3::new(
+            cos_elevation * cos_azimuth,
+            cos_elevation * sin_azimuth,
+            sin_elevation,
+        );
+
+        (direction, self.pdf(direction))
+    }
+
+    /// The probability density of picking `direction`, over solid angle.
+    pub fn pdf(&self, direction: Vector3<f32>) -> f32 {
+        let uv = crate::math::equirectangular(direction, self.rotation);
+        let row = (1.0 - uv.y).max(0.0).min(1.0);
+        let index = ((row * self.columns.len() as f32) as usize).min(self.columns.len() - 1);
+        let column_density = match self.columns[index] {
+            Some(ref columns) => columns.density(uv.x),
+            None => return 0.0,
+        };
+
+        let cos_elevation = (1.0 - direction.z * direction.z).max(0.0).sqrt();
+        if cos_elevation <= 0.0 {
+            return 0.0;
+        }
+
+        self.rows.density(row) * column_density / (2.0 * PI * PI * cos_elevation)
+    }
+}
+
+/// A parallelogram that the sky is seen through, such as a window. Sampling
+/// points on it finds the visible parts of the sky much more often than
+/// sampling the whole sky, when it's only seen through small openings.
+pub(crate) struct Portal {
+    corner: Point3<f32>,
+    width: Vector3<f32>,
+    height: Vector3<f32>,
+    normal: Vector3<f32>,
+    area: f32,
+}
+
+impl Portal {
+    pub fn new(corner: Point3<f32>, width: Vector3<f32>, height: Vector3<f32>) -> Self {
+        let normal = width.cross(height);
+        let area = normal.magnitude();
+
+        Portal {
+            corner,
+            width,
+            height,
+            normal: normal / area,
+            area,
+        }
+    }
+
+    /// Picks a direction from `position`, through the portal, and returns it
+    /// with its probability density, over solid angle.
+    pub fn sample(&self, rng: &mut impl Rng, position: Point3<f32>) -> (Vector3<f32>, f32) {
+        let point = self.corner + self.width * rng.gen::<f32>() + self.height * rng.gen::<f32>();
+        let offset = point - position;
+        let sq_distance = offset.magnitude2();
+        let direction = offset / sq_distance.sqrt();
+
+        (direction, self.density(direction, sq_distance))
+    }
+
+    /// The probability density of picking `direction` from `position`, over
+    /// solid angle. It's 0 for directions that miss the portal.
+    pub fn pdf(&self, position: Point3<f32>, direction: Vector3<f32>) -> f32 {
+        let denominator = self.normal.dot(direction);
+        if denominator == 0.0 {
+            return 0.0;
+        }
+
+        let distance = self.normal.dot(self.corner - position) / denominator;
+        if !(distance > 0.0) {
+            return 0.0;
+        }
+
+        // The position along each edge, from the corner.
+        let local = position + direction * distance - self.corner;
+        let u = local.cross(self.height).dot(self.normal) / self.area;
+        let v = self.width.cross(local).dot(self.normal) / self.area;
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            return 0.0;
+        }
+
+        self.density(direction, distance * distance)
+    }
+
+    fn density(&self, direction: Vector3<f32>, sq_distance: f32) -> f32 {
+        let cos = self.normal.dot(direction).abs();
+        if cos > 0.0 {
+            sq_distance / (self.area * cos)
+        } else {
+            0.0
+        }
+    }
+}
+
 pub(crate) struct Sample<'a> {
     pub direction: Vector3<f32>,
     pub sq_distance: Option<f32>,
@@ -122,9 +340,10 @@ pub(crate) struct Sample<'a> {
 
 pub(crate) enum Surface<'a> {
     Physical {
+        position: Point3<f32>,
         normal: Vector3<f32>,
         texture: Point2<f32>,
-        material: &'a Material<'a>,
+        material: MaterialId,
     },
     Color(LightProgram<'a>),
 }
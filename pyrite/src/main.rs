@@ -1,107 +1,1299 @@
 #![cfg_attr(test, allow(dead_code))]
 
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use image;
 
 use std::{
     error::Error,
-    io::{stdout, Write},
+    fs::File,
+    io::{stdout, BufWriter, Write},
     ops::{Add, AddAssign, Div, Mul},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
 
-use cgmath::Vector2;
+use cgmath::{Point2, Vector2};
 
 use palette::{ComponentWise, FromColor, LinSrgb, Pixel, Srgb, Xyz};
 
 use bumpalo::Bump;
 
 use color::{Light, WavelengthInput};
-use film::{Film, Spectrum};
+use film::{Area, Film, SamplerState, Spectrum};
 use project::{
     eval_context::EvalContext,
     expressions::Expressions,
     meshes::Meshes,
     program::{ExecutionContext, Program, ProgramCompiler, ProgramInput, Resources},
+    textures::TextureCache,
     ProjectData,
 };
+use sequence::RenderedFrame;
+use tonemap::Tonemap;
 
+mod atomic_file;
+mod budget;
+mod bvh_export;
 mod cameras;
+mod cli;
 mod color;
+mod daylight;
+mod diff;
+mod examples;
+mod exr;
 mod film;
 mod lamp;
 mod light_source;
 mod materials;
 mod math;
+mod merl;
+mod metal;
+mod noise;
+mod notify;
+mod priority;
 mod project;
+mod queue;
 mod renderer;
 mod rgb;
+mod selftest;
+mod sequence;
 mod shapes;
 mod spatial;
+mod stream;
 mod texture;
+mod tonemap;
 mod tracer;
 mod utils;
+mod volume;
 mod world;
 mod xyz;
 
 fn main() {
     let mut args = std::env::args();
     let name = args.next().unwrap_or("pyrite".into());
+
+    let options = match cli::Command::parse(args) {
+        Ok(cli::Command::Render(options)) => options,
+        Ok(cli::Command::MergeRegion(options)) => {
+            if let Err(error) = merge_region(&options) {
+                eprintln!("{}", error);
+            }
+            return;
+        }
+        Ok(cli::Command::Merge(options)) => {
+            if let Err(error) = merge_films(&options) {
+                eprintln!("{}", error);
+            }
+            return;
+        }
+        Ok(cli::Command::Develop(options)) => {
+            if let Err(error) = develop_film(&options) {
+                eprintln!("{}", error);
+            }
+            return;
+        }
+        Ok(cli::Command::ExportBvh(options)) => {
+            if let Err(error) = export_bvh(&options) {
+                eprintln!("{}", error);
+            }
+            return;
+        }
+        Ok(cli::Command::Queue(options)) => {
+            match queue::load(Path::new(&options.queue_path)) {
+                Ok(jobs) => {
+                    if !queue::run(jobs, render_and_notify) {
+                        std::process::exit(1);
+                    }
+                }
+                Err(error) => {
+                    eprintln!("error while loading the queue: {}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Ok(cli::Command::New(options)) => {
+            let directory = Path::new(&options.directory);
+            match examples::write(options.example, directory) {
+                Ok(()) => println!(
+                    "wrote the {} example to {}",
+                    options.example.name(),
+                    directory.join("project.lua").display()
+                ),
+                Err(error) => {
+                    eprintln!("error while writing the example: {}", error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Ok(cli::Command::Diff(options)) => {
+            if let Err(error) = diff_images(&options) {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::SelfTest) => {
+            if !selftest::run() {
+                eprintln!("the self test found too many artifacts");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            eprintln!("{}", cli::usage(&name));
+            return;
+        }
+    };
+
+    if options.low_priority {
+        if let Err(error) = priority::lower_priority() {
+            eprintln!("warning: could not lower the priority: {}", error);
+        }
+    }
+
+    if options.watch {
+        watch(&options);
+    }
+
+    if let Err(error) = render_and_notify(&options) {
+        eprintln!("{}", error);
+    }
+}
+
+/// Renders the project and reports the result to the webhook, if there is
+/// one. A failed notification doesn't fail the render.
+fn render_and_notify(options: &cli::Options) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let result = render_project(options);
+
+    if let Some(ref webhook) = options.notify_webhook {
+        let project_path = Path::new(&options.project_path);
+        let (output_path, thumbnail_path) = match options.frames {
+            Some((_, last)) => (
+                manifest_path(project_path, options.region),
+                render_path(project_path, Some(last), options.region),
+            ),
+            None => {
+                let path = render_path(project_path, None, options.region);
+                (path.clone(), path)
+            }
+        };
+
+        let report = notify::Report {
+            scene: project_path,
+            duration: start.elapsed(),
+            output_path: &output_path,
+            thumbnail_path: result.as_ref().ok().map(|_| &*thumbnail_path),
+            error: result.as_ref().err().map(|error| &**error),
+        };
+
+        if let Err(error) = notify::send(webhook, &report) {
+            eprintln!("warning: could not notify the webhook: {}", error);
+        }
+    }
+
+    result
+}
+
+const DEFAULT_FRAME_RATE: f32 = 24.0;
+
+/// How often the image is saved while rendering, without a profile.
+const IMAGE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// How many bytes of textures are kept between loads, without a profile.
+const TEXTURE_CACHE_SIZE: usize = 2 << 30;
+
+fn texture_cache(options: &cli::Options) -> TextureCache {
+    TextureCache::new(
+        options
+            .profile
+            .map_or(TEXTURE_CACHE_SIZE, cli::Profile::texture_cache_size),
+    )
+}
+
+/// Renders the project once, or each frame in the frame range.
+fn render_project(options: &cli::Options) -> Result<(), Box<dyn Error>> {
+    match options.frames {
+        Some((first, last)) => {
+            let manifest_path = manifest_path(Path::new(&options.project_path), options.region);
+            let mut frame_rate = DEFAULT_FRAME_RATE;
+            let mut rendered_frames = Vec::new();
+            let mut texture_cache = texture_cache(options);
+
+            for frame in first..=last {
+                println!("rendering frame {} of {}..{}", frame, first, last);
+
+                if let Some((rendered_frame, project_frame_rate)) =
+                    run(options, Some(frame), &mut texture_cache)?
+                {
+                    frame_rate = project_frame_rate;
+                    rendered_frames.push(rendered_frame);
+                }
+
+                // The manifest is updated after each frame, so it's usable
+                // even if the sequence is interrupted.
+                if !rendered_frames.is_empty() {
+                    if let Err(error) = atomic_file::write_atomically(&manifest_path, |path| {
+                        sequence::write_manifest(path, frame_rate, &rendered_frames)
+                    }) {
+                        eprintln!("error while writing the frame manifest: {}", error);
+                    }
+                }
+            }
+        }
+        None => {
+            run(options, None, &mut texture_cache(options))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders the project, or a frame of it. Frames return a summary for the
+/// sequence manifest, together with the project's frame rate. Textures are
+/// reused from, and kept in, `texture_cache`.
+fn run(
+    options: &cli::Options,
+    frame: Option<u32>,
+    texture_cache: &mut TextureCache,
+) -> Result<Option<(RenderedFrame, f32)>, Box<dyn Error>> {
+    let arena = Bump::new();
+
+    let ProjectData {
+        expressions,
+        mut meshes,
+        spectra,
+        mut textures,
+        project,
+    } = project::reload_project(&options.project_path, Meshes::new(), texture_cache)
+        .map_err(|error| format!("error while loading project file: {}", error))?;
+
+    if let Some(budget) = options.memory_budget {
+        let threads = options
+            .threads
+            .or(project.renderer.shared().threads)
+            .or_else(|| options.profile.map(cli::Profile::threads))
+            .unwrap_or_else(renderer::default_threads);
+        budget::fit_scene(
+            budget,
+            threads,
+            &project.world.objects,
+            &mut meshes,
+            &mut textures,
+        );
+    }
+
+    let programs = ProgramCompiler::new(&arena);
+    let resources = Resources {
+        spectra: &spectra,
+        textures: &textures,
+    };
+
+    let eval_context = EvalContext {
+        expressions: &expressions,
+        frame: frame.unwrap_or(0) as f32,
+    };
+
+    let (image, context) = parse_project(
+        project,
+        programs,
+        eval_context,
+        &meshes,
+        resources,
+        &arena,
+        options,
+    )
+    .map_err(|error| format!("error while parsing project: {}", error))?;
+
+    print_statistics(&image, &context, &meshes, &arena, options.bands);
+
+    if let Some(frame) = frame {
+        println!(
+            "timecode: {} at {} fps",
+            sequence::timecode(frame, image.frame_rate),
+            image.frame_rate
+        );
+    }
+
+    if options.dry_run {
+        println!("dry run: stopping before rendering");
+        return Ok(None);
+    }
+
+    if let Some(pixel) = options.trace_pixel {
+        trace_pixel(pixel, &image, &context, options, frame)?;
+        return Ok(None);
+    }
+
+    let render_path = render_path(Path::new(&options.project_path), frame, options.region);
+    let render_start = Instant::now();
+
+    if let Some(bands) = options.bands {
+        render_bands(&image, &context, bands, &render_path, options)?;
+    } else {
+        let checkpoint_path = film_path(options, frame);
+        let film = match &checkpoint_path {
+            Some(checkpoint_path) if options.resume => {
+                resume_film(&image, &context, options.region, checkpoint_path)?
+            }
+            _ => create_film(&image, &context, options.region)?,
+        };
+        render(
+            &film,
+            &image,
+            &context,
+            &render_path,
+            checkpoint_path.as_deref(),
+        );
+        save_film(&film, options, frame)?;
+
+        if options.exr {
+            let pixels = develop_linear(&film, &image, context.resources, 2.0);
+            let region = film.region();
+            save_exr(
+                &pixels,
+                region.from,
+                &image,
+                &render_path,
+                options.exr_mipmaps,
+            )?;
+        }
+    }
+
+    let render_time = render_start.elapsed();
+
+    Ok(frame.map(|frame| {
+        let rendered_frame = RenderedFrame::new(
+            frame,
+            image.frame_rate,
+            render_path,
+            render_time,
+            &context.world.statistics,
+        );
+        (rendered_frame, image.frame_rate)
+    }))
+}
+
+fn render_path(project_path: &Path, frame: Option<u32>, region: Option<Area<usize>>) -> PathBuf {
+    let project_dir = project_path.parent().unwrap_or(project_path);
+    let mut name = String::from("render");
+
+    if let Some(frame) = frame {
+        name.push_str(&format!("_{:04}", frame));
+    }
+
+    // Regions are saved as cropped images, so they shouldn't replace the full
+    // image.
+    if region.is_some() {
+        name.push_str("_region");
+    }
+
+    name.push_str(".png");
+    project_dir.join(name)
+}
+
+fn manifest_path(project_path: &Path, region: Option<Area<usize>>) -> PathBuf {
+    let render_path = render_path(project_path, None, region);
+    let stem = render_path
+        .file_stem()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or("render");
+    render_path.with_file_name(format!("{}_manifest.json", stem))
+}
+
+/// Traces the paths of a single pixel and writes their vertices to a file,
+/// instead of rendering the image.
+fn trace_pixel(
+    pixel: Point2<usize>,
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    options: &cli::Options,
+    frame: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let width = image_settings.width as usize;
+    let height = image_settings.height as usize;
+
+    if pixel.x >= width || pixel.y >= height {
+        return Err(format!(
+            "the pixel {},{} is outside the {}x{} image",
+            pixel.x, pixel.y, width, height
+        )
+        .into());
+    }
+
+    let trace_path = match &options.trace_file {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let render_path = render_path(Path::new(&options.project_path), frame, None);
+            let stem = render_path
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .unwrap_or("render");
+            render_path.with_file_name(format!("{}_trace_{}_{}.json", stem, pixel.x, pixel.y))
+        }
+    };
+
+    let vertices = renderer::pixel_trace::trace_pixel(
+        &config.renderer,
+        pixel,
+        width,
+        height,
+        &config.camera,
+        &config.world,
+        config.resources,
+    );
+
+    renderer::pixel_trace::write_trace(&trace_path, pixel, &vertices)
+        .map_err(|error| format!("error while writing the pixel trace: {}", error))?;
+
+    println!(
+        "wrote {} path vertices for pixel {},{} to {}",
+        vertices.len(),
+        pixel.x,
+        pixel.y,
+        trace_path.display()
+    );
+
+    Ok(())
+}
+
+/// Renders the image as a sequence of row bands, with one film at the time.
+/// Each band is written to the image as soon as it's done, so neither the
+/// film nor the image has to fit in memory all at once. The image is
+/// replaced when all bands are done. EXR files are written for each band.
+fn render_bands(
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    bands: usize,
+    render_path: &Path,
+    options: &cli::Options,
+) -> Result<(), Box<dyn Error>> {
+    atomic_file::write_atomically(render_path, |path| -> Result<(), Box<dyn Error>> {
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(File::create(path)?),
+            image_settings.width,
+            image_settings.height,
+        );
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut rows = writer.stream_writer();
+
+        render_bands_into(
+            image_settings,
+            config,
+            bands,
+            render_path,
+            options,
+            &mut rows,
+        )?;
+
+        rows.finish()?;
+        Ok(())
+    })
+    .map_err(|error| format!("error while writing {}: {}", render_path.display(), error))?;
+
+    println!("saved {}", render_path.display());
+    Ok(())
+}
+
+/// Renders the bands from top to bottom and writes their rows to `output`.
+fn render_bands_into(
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    bands: usize,
+    render_path: &Path,
+    options: &cli::Options,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    let width = image_settings.width as usize;
+    let height = image_settings.height as usize;
+    let band_height = band_height(height, bands);
+    let band_count = (height + band_height - 1) / band_height;
+
+    for band in 0..band_count {
+        let from = band * band_height;
+        let rows = band_height.min(height - from);
+        println!(
+            "rendering band {} of {}, rows {} to {}",
+            band + 1,
+            band_count,
+            from,
+            from + rows - 1
+        );
+
+        let region = Area::new(Point2::new(0, from), Vector2::new(width, rows));
+        let film = create_film(image_settings, config, Some(region))?;
+
+        // The last image is the finished band.
+        let mut band_pixels = None;
+        render_film(
+            &film,
+            image_settings,
+            config,
+            |pixels| band_pixels = Some(pixels.clone()),
+            |_| {},
+        );
+
+        if let Some(band_pixels) = band_pixels {
+            output.write_all(&band_pixels)?;
+        }
+
+        if options.exr {
+            let band_pixels = develop_linear(&film, image_settings, config.resources, 2.0);
+            let band_path = band_path(render_path, band, band_count);
+            save_exr(
+                &band_pixels,
+                region.from,
+                image_settings,
+                &band_path,
+                options.exr_mipmaps,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The path of a band's own image, like `render_band_03.png`.
+fn band_path(render_path: &Path, band: usize, band_count: usize) -> PathBuf {
+    let stem = render_path
+        .file_stem()
+        .and_then(std::ffi::OsStr::to_str)
+        .unwrap_or("render");
+    let digits = band_count.to_string().len();
+
+    render_path.with_file_name(format!(
+        "{}_band_{:0digits$}.png",
+        stem,
+        band + 1,
+        digits = digits
+    ))
+}
+
+/// The number of rows in each band, when splitting the image into `bands`
+/// bands. The last band may be smaller.
+fn band_height(height: usize, bands: usize) -> usize {
+    ((height + bands - 1) / bands).max(1)
+}
+
+/// Saves a tiled EXR file next to the PNG image, with the same name.
+fn save_exr(
+    pixels: &exr::LinearImage,
+    origin: Point2<usize>,
+    image_settings: &ImageSettings<'_>,
+    render_path: &Path,
+    mipmaps: bool,
+) -> Result<(), Box<dyn Error>> {
+    let exr_path = render_path.with_extension("exr");
+
+    atomic_file::write_atomically(&exr_path, |path| {
+        exr::write_tiled(
+            path,
+            pixels,
+            Point2::new(origin.x as u32, origin.y as u32),
+            Vector2::new(image_settings.width, image_settings.height),
+            EXR_TILE_SIZE,
+            mipmaps,
+        )
+    })
+    .map_err(|error| format!("error while writing {}: {}", exr_path.display(), error))?;
+
+    println!("saved {}", exr_path.display());
+    Ok(())
+}
+
+const EXR_TILE_SIZE: u32 = 64;
+
+fn save_film(
+    film: &Film,
+    options: &cli::Options,
+    frame: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let film_path = if let Some(film_path) = film_path(options, frame) {
+        film_path
+    } else {
+        return Ok(());
+    };
+
+    atomic_file::write_atomically(&film_path, |path| film.save(path))
+        .map_err(|error| format!("error while writing film: {}", error).into())
+}
+
+/// The path of the film for a frame, if films are saved. Frames get their own
+/// films, with the frame number after the file stem.
+fn film_path(options: &cli::Options, frame: Option<u32>) -> Option<PathBuf> {
+    let film_path = Path::new(options.film_path.as_ref()?);
+
+    let film_path = match frame {
+        Some(frame) => {
+            let stem = film_path
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .unwrap_or("render");
+            let extension = film_path
+                .extension()
+                .and_then(std::ffi::OsStr::to_str)
+                .unwrap_or("film");
+            film_path.with_file_name(format!("{}_{:04}.{}", stem, frame, extension))
+        }
+        None => film_path.to_owned(),
+    };
+
+    Some(film_path)
+}
+
+/// Creates a film that continues from the samples and sampler state in the
+/// film at `film_path`, or a new film if it doesn't exist yet.
+fn resume_film(
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    region: Option<Area<usize>>,
+    film_path: &Path,
+) -> Result<Film, Box<dyn Error>> {
+    let film = create_film(image_settings, config, region)?;
+
+    if !film_path.exists() {
+        println!(
+            "{} doesn't exist, starting a new render",
+            film_path.display()
+        );
+        return Ok(film);
+    }
+
+    let saved = Film::load(film_path)
+        .map_err(|error| format!("error while reading {}: {}", film_path.display(), error))?;
+    let sampler = saved.sampler_state();
+    if sampler.samples == 0 {
+        return Err(format!(
+            "{} has no sampler state, so it can't be resumed",
+            film_path.display()
+        )
+        .into());
+    }
+
+    film.merge(&saved)
+        .map_err(|error| format!("could not resume {}: {}", film_path.display(), error))?;
+    film.set_sampler_state(sampler);
+    println!(
+        "resuming {} from {} samples per pixel",
+        film_path.display(),
+        sampler.samples
+    );
+
+    Ok(film)
+}
+
+/// Adds the samples from region films to a full film, and develops the result
+/// as the project's image.
+fn merge_region(options: &cli::MergeRegionOptions) -> Result<(), Box<dyn Error>> {
+    let film_path = Path::new(&options.film_path);
+    let film = Film::load(film_path)
+        .map_err(|error| format!("error while reading {}: {}", film_path.display(), error))?;
+
+    for region_path in &options.region_paths {
+        let region_path = Path::new(region_path);
+        let region = Film::load(region_path)
+            .map_err(|error| format!("error while reading {}: {}", region_path.display(), error))?;
+        film.merge(&region)
+            .map_err(|error| format!("could not merge {}: {}", region_path.display(), error))?;
+        println!("merged {}", region_path.display());
+    }
+
+    atomic_file::write_atomically(film_path, |path| film.save(path))
+        .map_err(|error| format!("error while writing film: {}", error))?;
+
+    let ProjectData {
+        expressions,
+        spectra,
+        textures,
+        project,
+        ..
+    } = project::load_project(&options.project_path)
+        .map_err(|error| format!("error while loading project file: {}", error))?;
+
     let arena = Bump::new();
+    let programs = ProgramCompiler::new(&arena);
+    let resources = Resources {
+        spectra: &spectra,
+        textures: &textures,
+    };
+    let image = ImageSettings::from_project(project.image, programs, &expressions)
+        .map_err(|error| format!("error while parsing project: {}", error))?;
+
+    let render_path = render_path(Path::new(&options.project_path), None, None);
+    let pixels = develop(&film, &image, resources, 2.0);
+    atomic_file::write_atomically(&render_path, |path| pixels.save(path))
+        .map_err(|error| format!("error while writing image: {}", error))?;
+    println!("saved {}", render_path.display());
+
+    Ok(())
+}
+
+/// Adds up films from separate renders of the same image, such as from
+/// different computers. Their sums are added together, so films with more
+/// samples have more influence on the result.
+fn merge_films(options: &cli::MergeOptions) -> Result<(), Box<dyn Error>> {
+    let films = options
+        .film_paths
+        .iter()
+        .map(|film_path| {
+            let film_path = Path::new(film_path);
+            Film::load(film_path)
+                .map_err(|error| format!("error while reading {}: {}", film_path.display(), error))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let first = &films[0];
+    let region = films
+        .iter()
+        .skip(1)
+        .fold(first.region(), |region, film| region.union(&film.region()));
+    let mut merged = Film::with_region(
+        first.width(),
+        first.height(),
+        region,
+        first.grains_per_pixel(),
+        first.wavelength_span(),
+    );
+    merged.set_holdout(first.holdout());
+
+    for (film, film_path) in films.iter().zip(&options.film_paths) {
+        merged
+            .merge(film)
+            .map_err(|error| format!("could not merge {}: {}", film_path, error))?;
+        println!("merged {}", film_path);
+    }
+
+    let (min_samples, max_samples) = merged
+        .sample_counts()
+        .fold(None, |range: Option<(f32, f32)>, samples| {
+            Some(range.map_or((samples, samples), |(min, max)| {
+                (min.min(samples), max.max(samples))
+            }))
+        })
+        .unwrap_or((0.0, 0.0));
+    println!(
+        "{} to {} samples per pixel",
+        min_samples as u64, max_samples as u64
+    );
 
-    if let Some(project_path) = args.next() {
+    let output_path = Path::new(&options.output_path);
+    atomic_file::write_atomically(output_path, |path| merged.save(path))
+        .map_err(|error| format!("error while writing film: {}", error))?;
+    println!("saved {}", output_path.display());
+
+    if let Some(project_path) = &options.project_path {
         let ProjectData {
             expressions,
-            meshes,
             spectra,
             textures,
             project,
-        } = match project::load_project(&project_path) {
-            Ok(project) => project,
-            Err(error) => {
-                eprintln!("error while loading project file: {}", error);
-                return;
-            }
-        };
+            ..
+        } = project::load_project(project_path)
+            .map_err(|error| format!("error while loading project file: {}", error))?;
 
+        let arena = Bump::new();
         let programs = ProgramCompiler::new(&arena);
         let resources = Resources {
             spectra: &spectra,
             textures: &textures,
         };
+        let image = ImageSettings::from_project(project.image, programs, &expressions)
+            .map_err(|error| format!("error while parsing project: {}", error))?;
+
+        let render_path = render_path(Path::new(project_path), None, None);
+        let pixels = develop(&merged, &image, resources, 2.0);
+        atomic_file::write_atomically(&render_path, |path| pixels.save(path))
+            .map_err(|error| format!("error while writing image: {}", error))?;
+        println!("saved {}", render_path.display());
+    }
+
+    Ok(())
+}
+
+/// Develops a saved film again, with another exposure and tone mapping.
+fn develop_film(options: &cli::DevelopOptions) -> Result<(), Box<dyn Error>> {
+    let film_path = Path::new(&options.film_path);
+    let film = Film::load(film_path)
+        .map_err(|error| format!("error while reading {}: {}", film_path.display(), error))?;
+
+    let arena = Bump::new();
+    let programs = ProgramCompiler::new(&arena);
+    let mut spectra = project::spectra::Spectra::new();
+    let mut textures = project::textures::Textures::new();
+    let mut image = ImageSettings {
+        width: film.width() as u32,
+        height: film.height() as u32,
+        file: None,
+        frame_rate: DEFAULT_FRAME_RATE,
+        filter: None,
+        white: None,
+        holdout: film.holdout(),
+    };
+
+    if let Some(project_path) = &options.project_path {
+        let project_data = project::load_project(project_path)
+            .map_err(|error| format!("error while loading project file: {}", error))?;
+        image = ImageSettings::from_project(
+            project_data.project.image,
+            programs,
+            &project_data.expressions,
+        )
+        .map_err(|error| format!("error while parsing project: {}", error))?;
+        spectra = project_data.spectra;
+        textures = project_data.textures;
+    }
+
+    let resources = Resources {
+        spectra: &spectra,
+        textures: &textures,
+    };
+    let mut linear = develop_linear(&film, &image, resources, 2.0);
+    let mut exposure = 2.0f32.powf(options.exposure);
+
+    if let Some(backplate_path) = &options.backplate_path {
+        if !film.holdout() {
+            return Err(
+                "the background isn't held out, render with `holdout = true` in the image settings"
+                    .into(),
+            );
+        }
+
+        let backplate = load_backplate(Path::new(backplate_path), &film)
+            .map_err(|error| format!("error while reading backplate: {}", error))?;
+        composite_over(
+            &mut linear,
+            film.developed_coverage(),
+            &backplate,
+            exposure,
+            2.0f32.powf(options.backplate_exposure),
+        );
+        exposure = 1.0;
+    }
+
+    let pixels = encode_srgb(&linear, exposure, options.tonemap);
+
+    let output_path = match &options.output_path {
+        Some(output_path) => PathBuf::from(output_path),
+        None => film_path.with_extension("png"),
+    };
+    atomic_file::write_atomically(&output_path, |path| pixels.save(path))
+        .map_err(|error| format!("error while writing image: {}", error))?;
+    println!("saved {}", output_path.display());
+
+    Ok(())
+}
+
+/// Loads a backplate as linear colors, and fits it to the film's region. It's
+/// stretched if it has a different size than the image.
+fn load_backplate(path: &Path, film: &Film) -> Result<exr::LinearImage, Box<dyn Error>> {
+    let mut backplate = image::open(path)?.into_rgb();
+    let (width, height) = (film.width() as u32, film.height() as u32);
+
+    if backplate.dimensions() != (width, height) {
+        backplate = image::imageops::resize(
+            &backplate,
+            width,
+            height,
+            image::imageops::FilterType::Triangle,
+        );
+    }
+
+    let region = film.region();
+    Ok(image::ImageBuffer::from_fn(
+        region.size.x as u32,
+        region.size.y as u32,
+        |x, y| {
+            let image::Rgb([red, green, blue]) =
+                *backplate.get_pixel(x + region.from.x as u32, y + region.from.y as u32);
+            let color: Srgb<f32> = Srgb::new(red, green, blue).into_format();
+            image::Rgb(color.into_linear().into_raw())
+        },
+    ))
+}
+
+/// Places the render over the backplate, where the background is replaced in
+/// proportion to its coverage. The render is premultiplied, since the
+/// background was held out, so the backplate is only added where the camera
+/// rays missed.
+fn composite_over(
+    linear: &mut exr::LinearImage,
+    coverage: impl Iterator<Item = f32>,
+    backplate: &exr::LinearImage,
+    exposure: f32,
+    backplate_exposure: f32,
+) {
+    for ((pixel, coverage), background) in linear.pixels_mut().zip(coverage).zip(backplate.pixels())
+    {
+        let transparency = (1.0 - coverage).max(0.0) * backplate_exposure;
+        for (value, background) in pixel.0.iter_mut().zip(&background.0) {
+            *value = *value * exposure + background * transparency;
+        }
+    }
+}
+
+/// Prints how much two images differ, and writes a heatmap of where they
+/// differ.
+fn diff_images(options: &cli::DiffOptions) -> Result<(), Box<dyn Error>> {
+    let load = |path: &str| {
+        diff::load(Path::new(path))
+            .map_err(|error| format!("error while reading {}: {}", path, error))
+    };
+    let reference = load(&options.reference_path)?;
+    let test = load(&options.test_path)?;
+
+    let difference = diff::compare(&reference, &test, options.pixels_per_degree)?;
+    println!("RMSE: {}", difference.rmse);
+    println!(
+        "FLIP: {} (max {})",
+        difference.mean_flip, difference.max_flip
+    );
 
-        match parse_project(project, programs, &expressions, &meshes, resources, &arena) {
-            Ok((image, context)) => render(image, context, project_path),
-            Err(error) => eprintln!("error while parsing project: {}", error),
+    let output_path = Path::new(&options.output_path);
+    difference
+        .heatmap()
+        .save(output_path)
+        .map_err(|error| format!("error while writing {}: {}", output_path.display(), error))?;
+
+    Ok(())
+}
+
+/// Writes the bounding boxes of the project's BVH to a wireframe file.
+fn export_bvh(options: &cli::ExportBvhOptions) -> Result<(), Box<dyn Error>> {
+    let arena = Bump::new();
+
+    let ProjectData {
+        expressions,
+        meshes,
+        spectra,
+        textures,
+        project,
+    } = project::load_project(&options.project_path)
+        .map_err(|error| format!("error while loading project file: {}", error))?;
+
+    let eval_context = EvalContext {
+        expressions: &expressions,
+        frame: options.frame.unwrap_or(0) as f32,
+    };
+
+    let world = world::World::from_project(
+        project.world,
+        eval_context,
+        ProgramCompiler::new(&arena),
+        &expressions,
+        &meshes,
+        Resources {
+            spectra: &spectra,
+            textures: &textures,
+        },
+        &arena,
+    )
+    .map_err(|error| format!("error while parsing project: {}", error))?;
+
+    let boxes = world.finite_objects.bounding_boxes(options.depth);
+    let output_path = Path::new(&options.output_path);
+
+    bvh_export::write_wireframe(output_path, &boxes)
+        .map_err(|error| format!("error while writing {}: {}", output_path.display(), error))?;
+
+    let max_depth = boxes.iter().map(|&(depth, _)| depth).max().unwrap_or(0);
+    println!(
+        "wrote {} bounding boxes, down to depth {}, to {}",
+        boxes.len(),
+        max_depth,
+        output_path.display()
+    );
+
+    Ok(())
+}
+
+/// Renders the project, and renders it again each time the project file
+/// changes. The geometry is kept between renders if only the shading, the
+/// camera or the render settings have changed.
+fn watch(options: &cli::Options) -> ! {
+    let project_path = Path::new(&options.project_path);
+    let mut modified = modified_time(project_path);
+    let mut texture_cache = texture_cache(options);
+    let mut project = match project::reload_project(project_path, Meshes::new(), &mut texture_cache)
+    {
+        Ok(project) => project,
+        Err(error) => {
+            eprintln!("error while loading project file: {}", error);
+            wait_for_reload(project_path, &mut modified, None, &mut texture_cache)
+        }
+    };
+
+    loop {
+        project = match watch_session(options, project, &mut modified, &mut texture_cache) {
+            Ok(project) => project,
+            Err(error) => {
+                eprintln!("{}", error);
+                wait_for_reload(project_path, &mut modified, None, &mut texture_cache)
+            }
         };
-    } else {
-        eprintln!("usage: {} project_file", name);
     }
 }
 
-fn parse_project<'p>(
+/// How much the reloaded shading programs may allocate before the scene is
+/// rebuilt, when the scene itself is smaller than this.
+const MIN_SHADING_ARENA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Builds the scene and keeps it until a reloaded project changes the
+/// geometry or the resources. The reloaded project is then returned, so the
+/// scene can be built again from scratch.
+fn watch_session(
+    options: &cli::Options,
+    project: ProjectData,
+    modified: &mut Option<SystemTime>,
+    texture_cache: &mut TextureCache,
+) -> Result<ProjectData, Box<dyn Error>> {
+    let ProjectData {
+        expressions,
+        meshes,
+        spectra,
+        textures,
+        project,
+    } = project;
+
+    let arena = Bump::new();
+    let programs = ProgramCompiler::new(&arena);
+    let resources = Resources {
+        spectra: &spectra,
+        textures: &textures,
+    };
+
+    let eval_context = EvalContext {
+        expressions: &expressions,
+        frame: 0.0,
+    };
+
+    let (mut image, mut context) = parse_project(
+        project,
+        programs,
+        eval_context,
+        &meshes,
+        resources,
+        &arena,
+        options,
+    )
+    .map_err(|error| format!("error while parsing project: {}", error))?;
+
+    print_statistics(&image, &context, &meshes, &arena, None);
+
+    // The reloaded shading is compiled into the same arena as the scene, and
+    // the old programs can't be freed while the scene refers to the arena.
+    // The scene is rebuilt with a new arena when the old programs would take
+    // as much space as the scene itself.
+    let built_size = arena.allocated_bytes();
+    let shading_limit = built_size.max(MIN_SHADING_ARENA_SIZE);
+
+    let project_path = Path::new(&options.project_path);
+    let render_path = render_path(project_path, None, options.region);
+    let film = create_film(&image, &context, options.region)?;
+    render(&film, &image, &context, &render_path, None);
+    save_film(&film, options, None)?;
+
+    let mut meshes = Some(meshes);
+
+    loop {
+        let ProjectData {
+            expressions: new_expressions,
+            meshes: new_meshes,
+            spectra: new_spectra,
+            textures: new_textures,
+            project: new_project,
+        } = wait_for_reload(project_path, modified, meshes.take(), texture_cache);
+
+        let eval_context = EvalContext {
+            expressions: &new_expressions,
+            frame: 0.0,
+        };
+
+        // The compiled programs refer to spectra and textures by ID, so they
+        // have to be the same for the shading to be replaceable.
+        let same_resources = new_spectra == spectra && new_textures.has_same_files(&textures);
+        let same_geometry = same_resources
+            && context
+                .world
+                .has_same_geometry(&new_project.world, eval_context)
+                .unwrap_or(false);
+
+        let arena_full = arena.allocated_bytes() - built_size > shading_limit;
+
+        if !same_geometry || arena_full {
+            if same_geometry {
+                println!(
+                    "old shading programs have filled the scene's memory, rebuilding the scene"
+                );
+            } else {
+                println!("the geometry has changed, rebuilding the scene");
+            }
+
+            return Ok(ProjectData {
+                expressions: new_expressions,
+                meshes: new_meshes,
+                spectra: new_spectra,
+                textures: new_textures,
+                project: new_project,
+            });
+        }
+
+        println!("only the shading has changed, keeping the geometry");
+        let result = reload_shading(
+            &mut image,
+            &mut context,
+            new_project,
+            eval_context,
+            programs,
+            &new_meshes,
+        )
+        .map_err(|error| format!("error while parsing project: {}", error).into());
+        meshes = Some(new_meshes);
+
+        let result = result.and_then(|()| {
+            // The change is shown quickly with the preview renderer, before
+            // it's rendered in full.
+            if let Some(preview) = context.renderer.preview() {
+                let renderer = std::mem::replace(&mut context.renderer, preview);
+                let result = create_film(&image, &context, options.region)
+                    .map(|film| render(&film, &image, &context, &render_path, None));
+                context.renderer = renderer;
+                result?;
+            }
+
+            let film = create_film(&image, &context, options.region)?;
+            render(&film, &image, &context, &render_path, None);
+            save_film(&film, options, None)
+        });
+
+        if let Err(error) = result {
+            eprintln!("{}", error);
+        }
+    }
+}
+
+fn reload_shading<'p>(
+    image_settings: &mut ImageSettings<'p>,
+    config: &mut RenderContext<'p>,
     project: project::Project,
+    eval_context: EvalContext<'_>,
+    programs: ProgramCompiler<'p>,
+    meshes: &Meshes,
+) -> Result<(), Box<dyn Error>> {
+    let expressions = eval_context.expressions;
+
+    let camera = cameras::Camera::from_project(project.camera, eval_context)?;
+    let image = ImageSettings::from_project(project.image, programs, expressions)?;
+    config.world.reload_shading(
+        project.world,
+        eval_context,
+        programs,
+        expressions,
+        meshes,
+        config.resources,
+    )?;
+
+    config.camera = camera;
+    let strict = config.renderer.strict;
+    config.renderer = renderer::Renderer::from_project(project.renderer);
+    config.renderer.strict = strict;
+    *image_settings = image;
+
+    Ok(())
+}
+
+/// Waits until the project file has been modified and loads it again. It
+/// keeps waiting if the project can't be loaded.
+fn wait_for_reload(
+    project_path: &Path,
+    modified: &mut Option<SystemTime>,
+    mut meshes: Option<Meshes>,
+    texture_cache: &mut TextureCache,
+) -> ProjectData {
+    loop {
+        println!("waiting for changes in {}...", project_path.display());
+
+        while modified_time(project_path) == *modified {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        *modified = modified_time(project_path);
+
+        let meshes = meshes.take().unwrap_or_else(Meshes::new);
+        let project = project::reload_project(project_path, meshes, texture_cache);
+
+        match project {
+            Ok(project) => return project,
+            Err(error) => eprintln!("error while loading project file: {}", error),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn parse_project<'p>(
+    mut project: project::Project,
     programs: ProgramCompiler<'p>,
-    expressions: &Expressions,
+    eval_context: EvalContext<'_>,
     meshes: &Meshes,
     resources: Resources<'p>,
     arena: &'p Bump,
+    options: &cli::Options,
 ) -> Result<(ImageSettings<'p>, RenderContext<'p>), Box<dyn Error>> {
-    let eval_context = EvalContext { expressions };
+    let expressions = eval_context.expressions;
+
+    // The profile only fills in what the project leaves out.
+    if let Some(profile) = options.profile {
+        let shared = project.renderer.shared_mut();
+        shared.threads = shared.threads.or_else(|| Some(profile.threads()));
+        shared.tile_size = shared
+            .tile_size
+            .take()
+            .or_else(|| Some(project::TileSize::Fixed(profile.tile_size())));
+    }
+
+    if let Some(quality) = options.quality {
+        project.world.quality = Some(project.world.quality.unwrap_or(1.0) * quality);
+    }
+
+    // Command line and environment overrides take precedence over the project.
+    let mut renderer = renderer::Renderer::from_project(project.renderer);
+    if let Some(threads) = options.threads {
+        renderer.threads = threads;
+    }
+    renderer.strict = options.strict;
 
     let config = RenderContext {
         camera: cameras::Camera::from_project(project.camera, eval_context)?,
-        renderer: renderer::Renderer::from_project(project.renderer),
+        pool: renderer::RayonPool::new(renderer.threads, options.cpu_limit)?,
+        renderer,
         world: world::World::from_project(
             project.world,
             eval_context,
             programs,
             expressions,
             meshes,
+            resources,
             &arena,
         )?,
         resources,
+        stream: options
+            .stream
+            .as_ref()
+            .map(|target| stream::Stream::connect(target).map(Mutex::new))
+            .transpose()
+            .map_err(|error| format!("could not connect to the stream viewer: {}", error))?,
+        image_interval: options
+            .profile
+            .map_or(IMAGE_INTERVAL, cli::Profile::image_interval),
     };
 
     let image = ImageSettings::from_project(project.image, programs, expressions)?;
@@ -109,150 +1301,289 @@ fn parse_project<'p>(
     Ok((image, config))
 }
 
-fn render<P: AsRef<Path>>(
-    image_settings: ImageSettings<'_>,
-    config: RenderContext<'_>,
-    project_path: P,
+fn print_statistics(
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    meshes: &Meshes,
+    arena: &Bump,
+    bands: Option<usize>,
 ) {
-    let image_size = Vector2::new(image_settings.width, image_settings.height);
+    let statistics = &config.world.statistics;
+    let height = image_settings.height as usize;
+    let film_memory = Film::memory_size(
+        image_settings.width as usize,
+        bands.map_or(height, |bands| band_height(height, bands)),
+        config.renderer.spectrum_bins,
+    );
+    let texture_memory = config.resources.textures.memory_size();
+    let mesh_memory = meshes.memory_size();
+
+    // The arena holds the shapes and compiled programs, while the BVH, the
+    // film, the textures and the source meshes are separate allocations.
+    let peak_memory = arena.allocated_bytes()
+        + statistics.bvh_memory
+        + film_memory
+        + texture_memory
+        + mesh_memory;
+
+    println!(
+        "threads: {} ({} logical cores)",
+        config.pool.threads(),
+        num_cpus::get()
+    );
+    println!("scene statistics:");
+    println!("  triangles: {}", statistics.triangles);
+    println!("  other shapes: {}", statistics.shapes);
+    println!("  infinite planes: {}", statistics.planes);
+    println!("  mesh instances: {}", statistics.mesh_instances);
+    println!("  curves: {}", statistics.curves);
+    println!("  points: {}", statistics.points);
+    println!(
+        "  instances: {}, sharing {} triangles",
+        statistics.instances, statistics.instanced_triangles
+    );
+    println!("  lights: {}", statistics.lights);
+    println!(
+        "  BVH: {} nodes, {}",
+        statistics.bvh_nodes,
+        format_bytes(statistics.bvh_memory)
+    );
+    println!(
+        "  textures: {}, {}",
+        config.resources.textures.len(),
+        format_bytes(texture_memory)
+    );
+    println!("  shape data: {}", format_bytes(statistics.shape_memory));
+    println!("  mesh data: {}", format_bytes(mesh_memory));
+    println!("  film: {}", format_bytes(film_memory));
+    println!("  estimated peak memory: {}", format_bytes(peak_memory));
+}
 
-    let mut pool = renderer::RayonPool;
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
 
-    let mut pixels = image::ImageBuffer::new(image_size.x, image_size.y);
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
 
-    let rgb_curves = None; /*image_settings.rgb_curves.map(|(red, green, blue)| {
-                               (
-                                   Interpolated { points: red },
-                                   Interpolated { points: green },
-                                   Interpolated { points: blue },
-                               )
-                           });*/
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
 
-    let project_path = project_path.as_ref();
-    let render_path = project_path
-        .parent()
-        .unwrap_or(project_path)
-        .join("render.png");
+    format!("{:.1} {}", value, UNITS[unit])
+}
 
-    /*let f = |mut tile: Tile| {
-        config.renderer.render_tile(&mut tile, &config.camera, &config.world);
-    };*/
+fn create_film(
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    region: Option<Area<usize>>,
+) -> Result<Film, Box<dyn Error>> {
+    let width = image_settings.width as usize;
+    let height = image_settings.height as usize;
+
+    let mut film = match region {
+        Some(region) => {
+            let image_area = Area::new(Point2::new(0, 0), Vector2::new(width, height));
+            if !image_area.contains_area(&region) {
+                return Err(format!(
+                    "the region {},{},{},{} is outside the {}x{} image",
+                    region.from.x, region.from.y, region.size.x, region.size.y, width, height
+                )
+                .into());
+            }
 
-    let film = Film::new(
-        image_size.x as usize,
-        image_size.y as usize,
-        config.renderer.spectrum_bins,
+            Film::with_region(
+                width,
+                height,
+                region,
+                config.renderer.spectrum_bins,
+                config.renderer.spectrum_span,
+            )
+        }
+        None => Film::new(
+            width,
+            height,
+            config.renderer.spectrum_bins,
+            config.renderer.spectrum_span,
+        ),
+    };
+    film.set_holdout(image_settings.holdout);
+
+    // Wavelengths are picked in proportion to the light sources' spectra, in
+    // steps of about 1 nm.
+    let (wavelength_start, wavelength_end) = config.renderer.spectrum_span;
+    let steps = (wavelength_end - wavelength_start).ceil().max(1.0) as usize;
+    film.sample_wavelengths_by(&config.world.emission_spectrum(
         config.renderer.spectrum_span,
+        steps,
+        config.resources,
+    ));
+
+    Ok(film)
+}
+
+fn render(
+    film: &Film,
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    render_path: &Path,
+    checkpoint_path: Option<&Path>,
+) {
+    render_film(
+        film,
+        image_settings,
+        config,
+        |pixels| {
+            if let Err(e) = atomic_file::write_atomically(render_path, |path| pixels.save(path)) {
+                println!("\rerror while writing image: {}", e);
+            }
+        },
+        |film| {
+            if let Some(checkpoint_path) = checkpoint_path {
+                if let Err(e) =
+                    atomic_file::write_atomically(checkpoint_path, |path| film.save(path))
+                {
+                    println!("\rerror while writing film: {}", e);
+                }
+            }
+        },
     );
+}
 
-    let mut filter_exe = ExecutionContext::new(config.resources);
-    let mut filter = image_settings.filter.map(|white| {
-        move |intensity: f32, wavelength: f32| {
-            intensity
-                * filter_exe
-                    .run(white, &SpectrumSamplingInput { wavelength })
-                    .value
-        }
-    });
+/// Renders the film and passes developed images of it to `save_image`, both
+/// while rendering and when it's done. The film is passed to `save_film`
+/// after each pass but the last, and passes that a resumed film already has
+/// the samples for are skipped.
+fn render_film(
+    film: &Film,
+    image_settings: &ImageSettings<'_>,
+    config: &RenderContext<'_>,
+    mut save_image: impl FnMut(&image::RgbImage),
+    mut save_film: impl FnMut(&Film),
+) {
+    let mut pool = config.pool.clone();
 
-    let mut white_balance_exe = ExecutionContext::new(config.resources);
-    let mut white_balance = image_settings.white.map(|white| {
-        let mut wavelength = config.renderer.spectrum_span.0;
-        let mut max = 0.0f32;
-        let mut d65_max = 0.0f32;
+    /*let f = |mut tile: Tile| {
+        config.renderer.render_tile(&mut tile, &config.camera, &config.world);
+    };*/
 
-        while wavelength < config.renderer.spectrum_span.1 {
-            max = max.max(
-                white_balance_exe
-                    .run(white, &SpectrumSamplingInput { wavelength })
-                    .value,
-            );
-            d65_max = d65_max.max(light_source::D65.get(wavelength));
-            wavelength += 1.0;
-        }
+    let mut last_print: Option<Instant> = None;
+    let mut last_image: Instant = Instant::now();
+    let mut last_stream: Instant = Instant::now();
+
+    // The tile sizes are measured on the first pass, and the samples are
+    // kept.
+    let tuned_renderer;
+    let renderer = if config.renderer.auto_tile_size() {
+        let start = Instant::now();
+        tuned_renderer = config.renderer.tune_tile_size(
+            film,
+            &mut pool,
+            |status| {
+                print!("\r{}... {:2}%", status.message, status.progress);
+                stdout().flush().unwrap();
+            },
+            &config.camera,
+            &config.world,
+            config.resources,
+        );
+        println!(
+            "\rpicked tile size {} in {:.1} seconds",
+            tuned_renderer.tile_size,
+            start.elapsed().as_secs_f64()
+        );
+        &tuned_renderer
+    } else {
+        &config.renderer
+    };
 
-        move |intensity: f32, wavelength: f32| {
-            let white_intensity = white_balance_exe
-                .run(white, &SpectrumSamplingInput { wavelength })
-                .value
-                / max;
-            let neutral = intensity / white_intensity.max(0.000001);
-            neutral * (light_source::D65.get(wavelength) / d65_max)
+    // The image is saved after each pass of the sample schedule, except the
+    // last one, which is saved below. The photons from the first pass are
+    // reused in the later passes.
+    let passes = renderer.passes();
+    let mut photon_maps = renderer::PhotonMaps::default();
+    let mut samples = 0;
+    for (index, renderer) in passes.iter().enumerate() {
+        samples += renderer.pixel_samples();
+        let sampler = film.sampler_state();
+        if samples <= sampler.samples {
+            continue;
         }
-    });
 
-    let mut spectrum_get = |spectrum: &Spectrum, wavelength: f32| {
-        let intensity = spectrum.get(wavelength);
+        // A pass may already have some of its samples, from measuring the
+        // tile sizes.
+        let remaining = (samples - sampler.samples).min(renderer.pixel_samples());
+        let renderer = &renderer.with_pixel_samples(remaining);
 
-        let filtered = if let Some(filter) = &mut filter {
-            filter(intensity, wavelength)
+        let pass_name = if passes.len() > 1 {
+            format!("pass {}/{}: ", index + 1, passes.len())
         } else {
-            intensity
+            String::new()
         };
 
-        if let Some(white_balance) = &mut white_balance {
-            white_balance(filtered, wavelength)
-        } else {
-            filtered
-        }
-    };
-
-    let mut last_print: Option<Instant> = None;
-    let mut last_image: Instant = Instant::now();
+        renderer.render(
+            film,
+            &mut pool,
+            |status| {
+                let time_since_print = last_print.map(|last_print| Instant::now() - last_print);
 
-    config.renderer.render(
-        &film,
-        &mut pool,
-        |status| {
-            let time_since_print = last_print.map(|last_print| Instant::now() - last_print);
-
-            let should_print = time_since_print
-                .map(|time| time.as_millis() >= 500)
-                .unwrap_or(true);
-
-            if should_print {
-                print!("\r{}... {:2}%", status.message, status.progress);
-                stdout().flush().unwrap();
-                last_print = Some(Instant::now());
-
-                let time_since_image = Instant::now() - last_image;
-                if time_since_image.as_secs() >= 20 {
-                    let begin_iter = Instant::now();
-                    for (spectrum, pixel) in film.developed_pixels().zip(pixels.pixels_mut()) {
-                        let rgb: Srgb<u8> = if let Some((red, green, blue)) = &rgb_curves {
-                            let color = spectrum_to_rgb(30.0, spectrum, &red, &green, &blue);
-                            Srgb::from_linear(color).into_format()
-                        } else {
-                            let color = spectrum_to_xyz(
-                                spectrum.spectrum_width(),
-                                30.0,
-                                spectrum,
-                                |s, w| spectrum_get(s, w),
-                            );
-                            Srgb::from_color(color).into_format()
-                        };
-
-                        *pixel = image::Rgb(rgb.into_raw());
-                    }
-                    let diff = (Instant::now() - begin_iter).as_millis() as f64 / 1000.0;
+                let should_print = time_since_print
+                    .map(|time| time.as_millis() >= 500)
+                    .unwrap_or(true);
 
+                if should_print {
                     print!(
-                        "\r{}... {:2}% - updated image in {} seconds",
-                        status.message, status.progress, diff
+                        "\r{}{}... {:2}%",
+                        pass_name, status.message, status.progress
                     );
                     stdout().flush().unwrap();
-                    if let Err(e) = pixels.save(&render_path) {
-                        println!("\rerror while writing image: {}", e);
+                    last_print = Some(Instant::now());
+
+                    let time_since_image = Instant::now() - last_image;
+                    if time_since_image >= config.image_interval {
+                        let begin_iter = Instant::now();
+                        let pixels = develop(film, image_settings, config.resources, 30.0);
+                        let diff = (Instant::now() - begin_iter).as_millis() as f64 / 1000.0;
+
+                        print!(
+                            "\r{}{}... {:2}% - updated image in {} seconds",
+                            pass_name, status.message, status.progress, diff
+                        );
+                        stdout().flush().unwrap();
+                        save_image(&pixels);
+                        last_image = Instant::now();
                     }
-                    last_image = Instant::now();
                 }
-            }
-        },
-        &config.camera,
-        &config.world,
-        config.resources,
-    );
 
+                if let Some(stream) = &config.stream {
+                    if last_stream.elapsed() >= stream::INTERVAL {
+                        let pixels = develop(film, image_settings, config.resources, 30.0);
+                        stream.lock().unwrap().send(film, &pixels, status.progress);
+                        last_stream = Instant::now();
+                    }
+                }
+            },
+            &config.camera,
+            &config.world,
+            config.resources,
+            &mut photon_maps,
+        );
+        film.set_sampler_state(SamplerState { samples, ..sampler });
+
+        if index + 1 < passes.len() {
+            save_film(film);
+            let pixels = develop(film, image_settings, config.resources, 30.0);
+            if let Some(stream) = &config.stream {
+                stream.lock().unwrap().send(film, &pixels, 100);
+                last_stream = Instant::now();
+            }
+            save_image(&pixels);
+            last_image = Instant::now();
+        }
+    }
     /*crossbeam::scope(|scope| {
         print!(" 0%");
         stdout().flush().unwrap();
@@ -293,25 +1624,125 @@ fn render<P: AsRef<Path>>(
 
     println!("\nSaving final result...");
 
+    let pixels = develop(film, image_settings, config.resources, 2.0);
+    if let Some(stream) = &config.stream {
+        stream.lock().unwrap().send(film, &pixels, 100);
+    }
+    save_image(&pixels);
+
+    println!("Done!")
+}
+
+/// Converts the film's spectra to an sRGB image, applying the project's
+/// filter and white balance.
+fn develop(
+    film: &Film,
+    image_settings: &ImageSettings<'_>,
+    resources: Resources<'_>,
+    step_size: f32,
+) -> image::RgbImage {
+    let linear = develop_linear(film, image_settings, resources, step_size);
+    encode_srgb(&linear, 1.0, Tonemap::Clamp)
+}
+
+/// Scales the colors by `exposure`, maps them to the displayable range, and
+/// converts them to 8 bit sRGB.
+fn encode_srgb(linear: &exr::LinearImage, exposure: f32, tonemap: Tonemap) -> image::RgbImage {
+    let mut pixels = image::ImageBuffer::new(linear.width(), linear.height());
+
+    for (color, pixel) in linear.pixels().zip(pixels.pixels_mut()) {
+        let color = tonemap.apply(*LinSrgb::from_raw(&color.0) * exposure);
+        let rgb: Srgb<u8> = Srgb::from_linear(color).into_format();
+        *pixel = image::Rgb(rgb.into_raw());
+    }
+
+    pixels
+}
+
+/// Converts the film's spectra to a linear RGB image, without clamping.
+fn develop_linear(
+    film: &Film,
+    image_settings: &ImageSettings<'_>,
+    resources: Resources<'_>,
+    step_size: f32,
+) -> exr::LinearImage {
+    let region = film.region();
+    let mut pixels = image::ImageBuffer::new(region.size.x as u32, region.size.y as u32);
+
+    let rgb_curves = None; /*image_settings.rgb_curves.map(|(red, green, blue)| {
+                               (
+                                   Interpolated { points: red },
+                                   Interpolated { points: green },
+                                   Interpolated { points: blue },
+                               )
+                           });*/
+
+    let mut filter_exe = ExecutionContext::new(resources);
+    let mut filter = image_settings.filter.map(|white| {
+        move |intensity: f32, wavelength: f32| {
+            intensity
+                * filter_exe
+                    .run(white, &SpectrumSamplingInput { wavelength })
+                    .values[0]
+        }
+    });
+
+    let mut white_balance_exe = ExecutionContext::new(resources);
+    let mut white_balance = image_settings.white.map(|white| {
+        let (mut wavelength, wavelength_end) = film.wavelength_span();
+        let mut max = 0.0f32;
+        let mut d65_max = 0.0f32;
+
+        while wavelength < wavelength_end {
+            max = max.max(
+                white_balance_exe
+                    .run(white, &SpectrumSamplingInput { wavelength })
+                    .values[0],
+            );
+            d65_max = d65_max.max(light_source::D65.get(wavelength));
+            wavelength += 1.0;
+        }
+
+        move |intensity: f32, wavelength: f32| {
+            let white_intensity = white_balance_exe
+                .run(white, &SpectrumSamplingInput { wavelength })
+                .values[0]
+                / max;
+            let neutral = intensity / white_intensity.max(0.000001);
+            neutral * (light_source::D65.get(wavelength) / d65_max)
+        }
+    });
+
+    let mut spectrum_get = |spectrum: &Spectrum, wavelength: f32| {
+        let intensity = spectrum.get(wavelength);
+
+        let filtered = if let Some(filter) = &mut filter {
+            filter(intensity, wavelength)
+        } else {
+            intensity
+        };
+
+        if let Some(white_balance) = &mut white_balance {
+            white_balance(filtered, wavelength)
+        } else {
+            filtered
+        }
+    };
+
     for (spectrum, pixel) in film.developed_pixels().zip(pixels.pixels_mut()) {
-        let rgb: Srgb<u8> = if let Some((red, green, blue)) = &rgb_curves {
-            let color = spectrum_to_rgb(2.0, spectrum, &red, &green, &blue);
-            Srgb::from_linear(color).into_format()
+        let rgb: LinSrgb = if let Some((red, green, blue)) = &rgb_curves {
+            spectrum_to_rgb(step_size, spectrum, &red, &green, &blue)
         } else {
-            let color = spectrum_to_xyz(spectrum.spectrum_width(), 2.0, spectrum, |s, w| {
+            let color = spectrum_to_xyz(spectrum.spectrum_width(), step_size, spectrum, |s, w| {
                 spectrum_get(s, w)
             });
-            Srgb::from_color(color).into_format()
+            LinSrgb::from_color(color)
         };
 
         *pixel = image::Rgb(rgb.into_raw());
     }
 
-    if let Err(e) = pixels.save(&render_path) {
-        println!("error while writing image: {}", e);
-    }
-
-    println!("Done!")
+    pixels
 }
 
 fn spectrum_to_rgb(
@@ -408,14 +1839,20 @@ struct RenderContext<'p> {
     world: world::World<'p>,
     renderer: renderer::Renderer,
     resources: Resources<'p>,
+    pool: renderer::RayonPool,
+    /// Receives developed images while rendering, if it's set.
+    stream: Option<Mutex<stream::Stream>>,
+    image_interval: Duration,
 }
 
 struct ImageSettings<'a> {
     width: u32,
     height: u32,
     file: Option<String>,
+    frame_rate: f32,
     filter: Option<Program<'a, SpectrumSamplingInput, Light>>,
     white: Option<Program<'a, SpectrumSamplingInput, Light>>,
+    holdout: bool,
 }
 
 impl<'a> ImageSettings<'a> {
@@ -428,20 +1865,29 @@ impl<'a> ImageSettings<'a> {
             width,
             height,
             file,
+            frame_rate,
             filter,
             white,
+            holdout,
         } = project;
 
+        let frame_rate = frame_rate.unwrap_or(DEFAULT_FRAME_RATE);
+        if frame_rate <= 0.0 {
+            return Err(format!("the frame rate must be positive, got {}", frame_rate).into());
+        }
+
         Ok(ImageSettings {
             width,
             height,
             file,
+            frame_rate,
             filter: filter
                 .map(|filter| programs.compile(&filter, expressions))
                 .transpose()?,
             white: white
                 .map(|white| programs.compile(&white, expressions))
                 .transpose()?,
+            holdout: holdout.unwrap_or(false),
         })
     }
 }
@@ -460,10 +1906,16 @@ impl ProgramInput for SpectrumSamplingInput {
     fn texture_coordinates() -> Result<project::program::InputFn<Self>, Box<dyn Error>> {
         Err("texture coordinates cannot be used while sampling a constant spectrum".into())
     }
+    fn texture_footprint() -> Result<project::program::InputFn<Self>, Box<dyn Error>> {
+        Err("texture footprints cannot be used while sampling a constant spectrum".into())
+    }
+    fn position() -> Result<project::program::InputFn<Self>, Box<dyn Error>> {
+        Err("the position cannot be used while sampling a constant spectrum".into())
+    }
 }
 
 impl WavelengthInput for SpectrumSamplingInput {
-    fn wavelength(&self) -> f32 {
-        self.wavelength
+    fn wavelengths(&self) -> &[f32] {
+        std::slice::from_ref(&self.wavelength)
     }
 }
@@ -1,45 +1,164 @@
-use std::error::Error;
+use std::{collections::HashMap, error::Error, f32::consts::PI, sync::Arc};
 
 use rand::Rng;
 
-use cgmath::{InnerSpace, Point3, Vector3};
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector2, Vector3};
 use collision::Ray3;
 
 use crate::{
-    color::Light,
-    math,
+    color::{Light, Wavelengths, LANES},
+    math::{self, utils::Interpolated},
+    merl::MeasuredBrdf,
     project::{
         eval_context::{EvalContext, Evaluate, EvaluateOr},
-        expressions::{Expressions, Vector},
+        expressions::{Expression, Expressions, Vector},
         program::{ExecutionContext, Program, ProgramCompiler},
-        SurfaceMaterial as ProjectMaterial,
+        EmissionUnit, SurfaceMaterial as ProjectMaterial,
     },
     shapes::Normal,
-    tracer::{self, Emit, LightProgram, NormalInput, Reflect, Reflection, RenderContext},
+    texture::Footprint,
+    tracer::{self, Cutoffs, Emit, LightProgram, NormalInput, Reflect, Reflection, RenderContext},
+    xyz,
 };
 
+pub(crate) struct Materials<'p> {
+    materials: Vec<Material<'p>>,
+}
+
+impl<'p> Materials<'p> {
+    pub fn new() -> Self {
+        Materials {
+            materials: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, id: MaterialId) -> &Material<'p> {
+        self.materials.get(id.0).expect("missing material")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Material<'p>> {
+        self.materials.iter()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = MaterialId> {
+        (0..self.materials.len()).map(MaterialId)
+    }
+
+    pub fn insert(&mut self, material: Material<'p>) -> MaterialId {
+        let id = MaterialId(self.materials.len());
+        self.materials.push(material);
+        id
+    }
+
+    /// Scales the emission with physical units, where `area` is the total
+    /// surface area with each material, in square meters.
+    pub fn convert_emission_units(
+        &mut self,
+        area: impl Fn(MaterialId) -> Option<f32>,
+        programs: ProgramCompiler<'p>,
+        exe: &mut ExecutionContext<'p>,
+    ) -> Result<(), Box<dyn Error>> {
+        for (index, material) in self.materials.iter_mut().enumerate() {
+            material
+                .surface
+                .convert_emission_units(area(MaterialId(index)), programs, exe)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Default)]
+#[repr(transparent)]
+pub(crate) struct MaterialId(usize);
+
+/// The distance between the texture coordinates that bump heights are
+/// compared at.
+const BUMP_STEP: f32 = 0.001;
+
 pub(crate) struct Material<'p> {
     surface: SurfaceMaterial<'p>,
     normal_map: Option<Program<'p, NormalInput, Vector>>,
+    bump: Option<Program<'p, NormalInput, f32>>,
+    opacity: Option<Program<'p, NormalInput, f32>>,
+    double_sided: bool,
+    priority: u32,
+    throughput_cutoff: Option<f32>,
+    max_distance: Option<f32>,
+    light_links: LightLinks,
+    /// The material's index among the named lights, if it's one of them.
+    light: Option<usize>,
+}
+
+/// Which of the named lights light a material directly, by their index.
+/// Lights without names always do.
+enum LightLinks {
+    All,
+    Include(Vec<usize>),
+    Exclude(Vec<usize>),
 }
 
 impl<'p> Material<'p> {
+    /// The lights are looked up in `light_names`, which has the index of
+    /// each named light.
     pub fn from_project(
         project: crate::project::Material,
         eval_context: EvalContext,
         programs: ProgramCompiler<'p>,
         expressions: &Expressions,
+        light_names: &HashMap<String, usize>,
     ) -> Result<Self, Box<dyn Error>> {
         let crate::project::Material {
             surface,
             normal_map,
+            bump,
+            opacity,
+            double_sided,
+            priority,
+            throughput_cutoff,
+            max_distance,
+            include_lights,
+            exclude_lights,
+            light_name,
         } = project;
 
+        let find_lights = |names: Vec<String>| {
+            names
+                .into_iter()
+                .map(|name| {
+                    light_names
+                        .get(&name)
+                        .cloned()
+                        .ok_or_else(|| format!("unknown light: '{}'", name))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let light_links = match (include_lights, exclude_lights) {
+            (Some(_), Some(_)) => {
+                return Err("a material can't both include and exclude lights".into())
+            }
+            (Some(names), None) => LightLinks::Include(find_lights(names)?),
+            (None, Some(names)) => LightLinks::Exclude(find_lights(names)?),
+            (None, None) => LightLinks::All,
+        };
+
         Ok(Material {
             surface: SurfaceMaterial::from_project(surface, eval_context, programs, expressions)?,
             normal_map: normal_map
                 .map(|normal_map| programs.compile(&normal_map, expressions))
                 .transpose()?,
+            bump: bump
+                .map(|bump| programs.compile(&bump, expressions))
+                .transpose()?,
+            opacity: opacity
+                .map(|opacity| programs.compile(&opacity, expressions))
+                .transpose()?,
+            double_sided,
+            priority,
+            throughput_cutoff,
+            max_distance,
+            light_links,
+            light: light_name.map(|name| light_names[&name]),
         })
     }
 
@@ -49,37 +168,144 @@ impl<'p> Material<'p> {
         ray_in: Ray3<f32>,
         position: Point3<f32>,
         normal: Vector3<f32>,
+        tangent: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Reflection<'_> {
-        self.surface.reflect(light, ray_in, position, normal, rng)
+        self.surface
+            .reflect(light, ray_in, position, normal, tangent, texture, rng, exe)
     }
 
     pub fn get_emission(
         &self,
         light: &mut tracer::Light,
         ray_in: Vector3<f32>,
+        position: Point3<f32>,
         normal: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Option<Program<RenderContext, Light>> {
-        self.surface.get_emission(light, ray_in, normal, rng)
+        self.surface
+            .get_emission(light, ray_in, position, normal, texture, rng, exe)
     }
 
     pub fn is_emissive(&self) -> bool {
         self.surface.is_emissive()
     }
 
-    pub fn apply_normal_map(
+    /// Adds the colors of the material's emissive parts to `colors`.
+    pub fn emission_colors(&self, colors: &mut Vec<LightProgram<'p>>) {
+        self.surface.emission_colors(colors)
+    }
+
+    /// The index of refraction inside the material, if light can pass into
+    /// it.
+    pub fn ior(&self, light: &mut tracer::Light) -> Option<f32> {
+        self.surface.ior(light)
+    }
+
+    /// Decides which material's inside wins where refractive objects overlap.
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    /// Checks if a light lights the material directly, where `light` is its
+    /// index among the named lights.
+    pub fn is_lit_by(&self, light: Option<usize>) -> bool {
+        match (&self.light_links, light) {
+            (LightLinks::Include(lights), Some(light)) => lights.contains(&light),
+            (LightLinks::Exclude(lights), Some(light)) => !lights.contains(&light),
+            (LightLinks::All, _) | (_, None) => true,
+        }
+    }
+
+    /// Checks if some of the named lights don't light the material directly.
+    pub fn has_light_links(&self) -> bool {
+        match self.light_links {
+            LightLinks::All => false,
+            LightLinks::Include(_) | LightLinks::Exclude(_) => true,
+        }
+    }
+
+    /// The index of the material among the named lights, if its surfaces are
+    /// a named light.
+    pub fn light_link(&self) -> Option<usize> {
+        self.light
+    }
+
+    /// The renderer's `cutoffs`, with the material's overrides.
+    pub fn cutoffs(&self, cutoffs: Cutoffs) -> Cutoffs {
+        Cutoffs {
+            throughput: self.throughput_cutoff.unwrap_or(cutoffs.throughput),
+            max_distance: self.max_distance.unwrap_or(cutoffs.max_distance),
+        }
+    }
+
+    /// Picks if a ray passes through the surface, where it's partly
+    /// transparent.
+    pub fn passes_through(
+        &self,
+        input: &NormalInput,
+        rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
+    ) -> bool {
+        match self.opacity {
+            Some(opacity) => exe.run(opacity, input) <= rng.gen::<f32>(),
+            None => false,
+        }
+    }
+
+    /// Opaque materials block every ray, so their surfaces don't need to be
+    /// looked up for `passes_through`.
+    pub fn is_opaque(&self) -> bool {
+        self.opacity.is_none()
+    }
+
+    /// One sided materials are invisible from behind.
+    pub fn is_double_sided(&self) -> bool {
+        self.double_sided
+    }
+
+    /// The normal after normal and bump mapping, which is kept on the same
+    /// side of the surface as the geometric normal in `input`.
+    pub fn shading_normal(
         &self,
         normal: Normal,
         input: NormalInput,
         exe: &mut ExecutionContext<'p>,
     ) -> Vector3<f32> {
-        if let Some(normal_map) = self.normal_map {
-            let new_normal: Vector3<f32> = exe.run(normal_map, &input).into();
-            normal.from_space(new_normal).normalize()
+        if self.normal_map.is_none() && self.bump.is_none() {
+            return normal.vector();
+        }
+
+        let mut new_normal: Vector3<f32> = if let Some(normal_map) = self.normal_map {
+            exe.run(normal_map, &input).into()
         } else {
-            normal.vector()
+            Vector3::unit_z()
+        };
+
+        if let Some(bump) = self.bump {
+            let height = exe.run(bump, &input);
+            let slope = |exe: &mut ExecutionContext<'p>, offset: Vector2<f32>| {
+                let offset_input = NormalInput {
+                    texture: input.texture + offset,
+                    ..input
+                };
+                (exe.run(bump, &offset_input) - height) / BUMP_STEP
+            };
+            let u_slope = slope(exe, Vector2::new(BUMP_STEP, 0.0));
+            let v_slope = slope(exe, Vector2::new(0.0, BUMP_STEP));
+
+            new_normal = new_normal.normalize() - Vector3::new(u_slope, v_slope, 0.0);
         }
+
+        math::facing_shading_normal(
+            normal.from_space(new_normal).normalize(),
+            input.normal,
+            input.incident,
+        )
     }
 }
 
@@ -87,9 +313,13 @@ pub(crate) enum SurfaceMaterial<'p> {
     Diffuse(Diffuse<'p>),
     Emission(Emission<'p>),
     Mirror(Mirror<'p>),
+    Glossy(Glossy<'p>),
+    BlinnPhong(BlinnPhong<'p>),
     Refractive(Refractive<'p>),
     Mix(Mix<'p>),
     FresnelMix(FresnelMix<'p>),
+    Measured(Measured<'p>),
+    Fluorescent(Fluorescent<'p>),
 }
 
 impl<'p> SurfaceMaterial<'p> {
@@ -103,27 +333,79 @@ impl<'p> SurfaceMaterial<'p> {
             ProjectMaterial::Diffuse { color } => SurfaceMaterial::Diffuse(Diffuse {
                 color: programs.compile(&color, expressions)?,
             }),
-            ProjectMaterial::Emission { color } => SurfaceMaterial::Emission(Emission {
+            ProjectMaterial::Emission {
+                color,
+                back_color,
+                intensity,
+            } => SurfaceMaterial::Emission(Emission {
                 color: programs.compile(&color, expressions)?,
+                back_color: back_color
+                    .map(|back_color| programs.compile(&back_color, expressions))
+                    .transpose()?,
+                intensity: intensity
+                    .map(|(intensity, unit)| {
+                        intensity
+                            .evaluate(eval_context)
+                            .map(|intensity: f32| (intensity, unit))
+                    })
+                    .transpose()?,
             }),
             ProjectMaterial::Mirror { color } => SurfaceMaterial::Mirror(Mirror {
                 color: programs.compile(&color, expressions)?,
             }),
+            ProjectMaterial::Glossy {
+                color,
+                roughness,
+                bitangent_roughness,
+                rotation,
+            } => {
+                let roughness: f32 = roughness.evaluate(eval_context)?;
+                let bitangent_roughness =
+                    bitangent_roughness.evaluate_or(eval_context, roughness)?;
+                let rotation: f32 = rotation.evaluate_or(eval_context, 0.0)?;
+
+                SurfaceMaterial::Glossy(Glossy {
+                    color: programs.compile(&color, expressions)?,
+                    alpha_x: roughness_to_alpha(roughness),
+                    alpha_y: roughness_to_alpha(bitangent_roughness),
+                    rotation: rotation.to_radians(),
+                })
+            }
+            ProjectMaterial::BlinnPhong { color, exponent } => {
+                let exponent: f32 = exponent.evaluate(eval_context)?;
+
+                SurfaceMaterial::BlinnPhong(BlinnPhong {
+                    color: programs.compile(&color, expressions)?,
+                    exponent: exponent.max(0.0),
+                })
+            }
             ProjectMaterial::Refractive {
                 color,
                 ior,
                 env_ior,
                 dispersion,
                 env_dispersion,
-            } => SurfaceMaterial::Refractive(Refractive {
-                color: programs.compile(&color, expressions)?,
-                ior: ior.evaluate(eval_context)?,
-                env_ior: env_ior.evaluate_or(eval_context, 1.0)?,
-                dispersion: dispersion.evaluate_or(eval_context, 0.0)?,
-                env_dispersion: env_dispersion.evaluate_or(eval_context, 0.0)?,
-            }),
+                roughness,
+            } => {
+                let roughness: f32 = roughness.evaluate_or(eval_context, 0.0)?;
+
+                SurfaceMaterial::Refractive(Refractive {
+                    color: programs.compile(&color, expressions)?,
+                    ior: ior.evaluate(eval_context)?,
+                    env_ior: env_ior
+                        .map(|env_ior| env_ior.evaluate(eval_context))
+                        .transpose()?,
+                    dispersion: dispersion.evaluate_or(eval_context, 0.0)?,
+                    env_dispersion: env_dispersion.evaluate_or(eval_context, 0.0)?,
+                    alpha: if roughness > 0.0 {
+                        Some(roughness_to_alpha(roughness))
+                    } else {
+                        None
+                    },
+                })
+            }
             ProjectMaterial::Mix { amount, lhs, rhs } => SurfaceMaterial::Mix(Mix {
-                factor: amount.evaluate(eval_context)?,
+                factor: programs.compile(&amount, expressions)?,
                 a: Box::new(SurfaceMaterial::from_project(
                     *lhs,
                     eval_context,
@@ -146,7 +428,9 @@ impl<'p> SurfaceMaterial<'p> {
                 refract,
             } => SurfaceMaterial::FresnelMix(FresnelMix {
                 ior: ior.evaluate(eval_context)?,
-                env_ior: env_ior.evaluate_or(eval_context, 1.0)?,
+                env_ior: env_ior
+                    .map(|env_ior| env_ior.evaluate(eval_context))
+                    .transpose()?,
                 dispersion: dispersion.evaluate_or(eval_context, 0.0)?,
                 env_dispersion: env_dispersion.evaluate_or(eval_context, 0.0)?,
                 reflect: Box::new(SurfaceMaterial::from_project(
@@ -162,6 +446,22 @@ impl<'p> SurfaceMaterial<'p> {
                     expressions,
                 )?),
             }),
+            ProjectMaterial::Measured { brdf, color } => SurfaceMaterial::Measured(Measured {
+                brdf,
+                color: programs.compile(&color, expressions)?,
+            }),
+            ProjectMaterial::Fluorescent {
+                color,
+                excitation,
+                emission,
+                efficiency,
+            } => SurfaceMaterial::Fluorescent(Fluorescent::new(
+                programs.compile(&color, expressions)?,
+                programs.compile(&Expression::Number(1.0), expressions)?,
+                excitation,
+                emission,
+                efficiency.evaluate_or(eval_context, 1.0)?,
+            )?),
         })
     }
 
@@ -171,19 +471,34 @@ impl<'p> SurfaceMaterial<'p> {
         ray_in: Ray3<f32>,
         position: Point3<f32>,
         normal: Vector3<f32>,
+        tangent: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Reflection<'_> {
         match self {
             SurfaceMaterial::Diffuse(material) => material.reflect(ray_in, position, normal, rng),
-            SurfaceMaterial::Emission(material) => material.reflect(),
+            SurfaceMaterial::Emission(material) => material.reflect(ray_in, normal),
             SurfaceMaterial::Mirror(material) => material.reflect(ray_in, position, normal),
+            SurfaceMaterial::Glossy(material) => {
+                material.reflect(ray_in, position, normal, tangent, rng)
+            }
+            SurfaceMaterial::BlinnPhong(material) => {
+                material.reflect(ray_in, position, normal, rng)
+            }
             SurfaceMaterial::Refractive(material) => {
                 material.reflect(light, ray_in, position, normal, rng)
             }
             SurfaceMaterial::Mix(material) => {
-                material.reflect(light, ray_in, position, normal, rng)
+                material.reflect(light, ray_in, position, normal, tangent, texture, rng, exe)
             }
             SurfaceMaterial::FresnelMix(material) => {
+                material.reflect(light, ray_in, position, normal, tangent, texture, rng, exe)
+            }
+            SurfaceMaterial::Measured(material) => {
+                material.reflect(light, ray_in, position, normal, rng)
+            }
+            SurfaceMaterial::Fluorescent(material) => {
                 material.reflect(light, ray_in, position, normal, rng)
             }
         }
@@ -193,18 +508,100 @@ impl<'p> SurfaceMaterial<'p> {
         &self,
         light: &mut tracer::Light,
         ray_in: Vector3<f32>,
+        position: Point3<f32>,
         normal: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Option<Program<RenderContext, Light>> {
         match self {
-            SurfaceMaterial::Emission(material) => material.get_emission(),
-            SurfaceMaterial::Mix(material) => material.get_emission(light, ray_in, normal, rng),
+            SurfaceMaterial::Emission(material) => material.get_emission(ray_in, normal),
+            SurfaceMaterial::Mix(material) => {
+                material.get_emission(light, ray_in, position, normal, texture, rng, exe)
+            }
             SurfaceMaterial::FresnelMix(material) => {
-                material.get_emission(light, ray_in, normal, rng)
+                material.get_emission(light, ray_in, position, normal, texture, rng, exe)
             }
             SurfaceMaterial::Diffuse(_)
             | SurfaceMaterial::Mirror(_)
-            | SurfaceMaterial::Refractive(_) => None,
+            | SurfaceMaterial::Glossy(_)
+            | SurfaceMaterial::BlinnPhong(_)
+            | SurfaceMaterial::Refractive(_)
+            | SurfaceMaterial::Measured(_)
+            | SurfaceMaterial::Fluorescent(_) => None,
+        }
+    }
+
+    fn ior(&self, light: &mut tracer::Light) -> Option<f32> {
+        match self {
+            SurfaceMaterial::Refractive(material) => {
+                Some(with_dispersion(material.ior, material.dispersion, light))
+            }
+            SurfaceMaterial::FresnelMix(material) => {
+                Some(with_dispersion(material.ior, material.dispersion, light))
+            }
+            SurfaceMaterial::Mix(material) => {
+                material.a.ior(light).or_else(|| material.b.ior(light))
+            }
+            SurfaceMaterial::Diffuse(_)
+            | SurfaceMaterial::Emission(_)
+            | SurfaceMaterial::Mirror(_)
+            | SurfaceMaterial::Glossy(_)
+            | SurfaceMaterial::BlinnPhong(_)
+            | SurfaceMaterial::Measured(_)
+            | SurfaceMaterial::Fluorescent(_) => None,
+        }
+    }
+
+    fn emission_colors(&self, colors: &mut Vec<LightProgram<'p>>) {
+        match self {
+            SurfaceMaterial::Emission(material) => {
+                colors.push(material.color);
+                colors.extend(material.back_color);
+            }
+            SurfaceMaterial::Mix(material) => {
+                material.a.emission_colors(colors);
+                material.b.emission_colors(colors);
+            }
+            SurfaceMaterial::FresnelMix(material) => {
+                material.reflect.emission_colors(colors);
+                material.refract.emission_colors(colors);
+            }
+            SurfaceMaterial::Diffuse(_)
+            | SurfaceMaterial::Mirror(_)
+            | SurfaceMaterial::Glossy(_)
+            | SurfaceMaterial::BlinnPhong(_)
+            | SurfaceMaterial::Refractive(_)
+            | SurfaceMaterial::Measured(_)
+            | SurfaceMaterial::Fluorescent(_) => {}
+        }
+    }
+
+    fn convert_emission_units(
+        &mut self,
+        area: Option<f32>,
+        programs: ProgramCompiler<'p>,
+        exe: &mut ExecutionContext<'p>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            SurfaceMaterial::Emission(material) => material.convert_units(area, programs, exe),
+            SurfaceMaterial::Mix(material) => {
+                material.a.convert_emission_units(area, programs, exe)?;
+                material.b.convert_emission_units(area, programs, exe)
+            }
+            SurfaceMaterial::FresnelMix(material) => {
+                material
+                    .reflect
+                    .convert_emission_units(area, programs, exe)?;
+                material.refract.convert_emission_units(area, programs, exe)
+            }
+            SurfaceMaterial::Diffuse(_)
+            | SurfaceMaterial::Mirror(_)
+            | SurfaceMaterial::Glossy(_)
+            | SurfaceMaterial::BlinnPhong(_)
+            | SurfaceMaterial::Refractive(_)
+            | SurfaceMaterial::Measured(_)
+            | SurfaceMaterial::Fluorescent(_) => Ok(()),
         }
     }
 
@@ -217,7 +614,11 @@ impl<'p> SurfaceMaterial<'p> {
             }
             SurfaceMaterial::Diffuse(_)
             | SurfaceMaterial::Mirror(_)
-            | SurfaceMaterial::Refractive(_) => false,
+            | SurfaceMaterial::Glossy(_)
+            | SurfaceMaterial::BlinnPhong(_)
+            | SurfaceMaterial::Refractive(_)
+            | SurfaceMaterial::Measured(_)
+            | SurfaceMaterial::Fluorescent(_) => false,
         }
     }
 }
@@ -254,17 +655,235 @@ fn lambertian(_ray_in: Vector3<f32>, ray_out: Vector3<f32>, normal: Vector3<f32>
     2.0 * normal.dot(ray_out).abs()
 }
 
+/// The resolution of the absorbed wavelengths of fluorescent materials, in
+/// nanometers.
+const EXCITATION_STEP: f32 = 1.0;
+
+pub(crate) struct Fluorescent<'p> {
+    color: LightProgram<'p>,
+    /// The color of the bounces where the light changes wavelength, which
+    /// have their whole weight in the probability instead.
+    white: LightProgram<'p>,
+    /// The first absorbed wavelength, and the cumulative distribution of the
+    /// absorption from there, in steps of `EXCITATION_STEP`.
+    excitation_start: f32,
+    excitation_cdf: Vec<f32>,
+    /// The total absorption, over all wavelengths, times the efficiency.
+    absorbed: f32,
+    /// The emission spectrum, scaled to add up to 1.
+    emission: Interpolated,
+}
+
+impl<'p> Fluorescent<'p> {
+    fn new(
+        color: LightProgram<'p>,
+        white: LightProgram<'p>,
+        excitation: Vec<(f32, f32)>,
+        emission: Vec<(f32, f32)>,
+        efficiency: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let excitation = Interpolated { points: excitation };
+        let start = excitation.points[0].0;
+        let end = excitation.points[excitation.points.len() - 1].0;
+        let steps = ((end - start) / EXCITATION_STEP).ceil().max(1.0) as usize;
+
+        let mut excitation_cdf = Vec::with_capacity(steps + 1);
+        let mut absorbed = 0.0;
+        excitation_cdf.push(0.0);
+        for step in 0..steps {
+            let wavelength = start + (step as f32 + 0.5) * EXCITATION_STEP;
+            absorbed += excitation.get(wavelength).max(0.0) * EXCITATION_STEP;
+            excitation_cdf.push(absorbed);
+        }
+
+        let emitted: f32 = emission
+            .windows(2)
+            .map(|pair| (pair[1].0 - pair[0].0) * (pair[0].1 + pair[1].1) * 0.5)
+            .sum();
+
+        if absorbed <= 0.0 || emitted <= 0.0 {
+            return Err("fluorescence needs an excitation and an emission above 0".into());
+        }
+
+        for value in &mut excitation_cdf {
+            *value /= absorbed;
+        }
+
+        Ok(Fluorescent {
+            color,
+            white,
+            excitation_start: start,
+            excitation_cdf,
+            absorbed: absorbed * efficiency,
+            emission: Interpolated {
+                points: emission
+                    .into_iter()
+                    .map(|(wavelength, value)| (wavelength, value / emitted))
+                    .collect(),
+            },
+        })
+    }
+
+    /// Reflects diffusely, or picks a shorter wavelength that the light was
+    /// absorbed at, before it was emitted at the current wavelength.
+    fn reflect(
+        &self,
+        light: &mut tracer::Light,
+        ray_in: Ray3<f32>,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+        rng: &mut impl Rng,
+    ) -> Reflection<'_> {
+        let n = if ray_in.direction.dot(normal) < 0.0 {
+            normal
+        } else {
+            -normal
+        };
+        let reflected = Ray3::new(position, math::utils::sample_hemisphere(rng, n));
+
+        let wavelength = light.colored();
+        let fluorescence = self.absorbed * self.emission.get(wavelength);
+        let probability = fluorescence / (1.0 + fluorescence);
+
+        if rng.gen::<f32>() < probability {
+            let absorbed = self.sample_excitation(rng.gen());
+            light.fluoresce(absorbed);
+
+            // Each photon loses some of its energy.
+            let weight = fluorescence * (absorbed / wavelength) / probability;
+            Reflect(reflected, self.white, weight, Some(lambertian))
+        } else {
+            Reflect(
+                reflected,
+                self.color,
+                1.0 / (1.0 - probability),
+                Some(lambertian),
+            )
+        }
+    }
+
+    fn sample_excitation(&self, u: f32) -> f32 {
+        let index = match self
+            .excitation_cdf
+            .binary_search_by(|probe| probe.partial_cmp(&u).unwrap())
+        {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        }
+        .min(self.excitation_cdf.len() - 2);
+
+        let from = self.excitation_cdf[index];
+        let to = self.excitation_cdf[index + 1];
+        let offset = if to > from {
+            (u - from) / (to - from)
+        } else {
+            0.5
+        };
+
+        self.excitation_start + (index as f32 + offset) * EXCITATION_STEP
+    }
+}
+
+/// The luminous efficacy of light at 555 nm, in lumens per watt.
+const LUMINOUS_EFFICACY: f32 = 683.0;
+
+/// The wavelengths that emission with physical units is measured within.
+const VISIBLE_SPECTRUM: (f32, f32) = (380.0, 780.0);
+
 pub(crate) struct Emission<'p> {
     pub color: LightProgram<'p>,
+    /// The color on the back side, if it's different from the front.
+    pub back_color: Option<LightProgram<'p>>,
+    /// The intensity and its unit, until the color has been scaled to it.
+    intensity: Option<(f32, EmissionUnit)>,
 }
 
 impl<'p> Emission<'p> {
-    fn reflect(&self) -> Reflection<'_> {
-        Emit(self.color)
+    /// Scales the color so the surface emits its intensity, assuming that it
+    /// emits the same amount in every direction on one side. The color is
+    /// measured where it faces the incoming ray, and the back side is scaled
+    /// by the same amount.
+    fn convert_units(
+        &mut self,
+        area: Option<f32>,
+        programs: ProgramCompiler<'p>,
+        exe: &mut ExecutionContext<'p>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (intensity, unit) = match self.intensity.take() {
+            Some(intensity) => intensity,
+            None => return Ok(()),
+        };
+
+        let (start, end) = VISIBLE_SPECTRUM;
+        let steps = (end - start) as usize;
+        let mut power = 0.0;
+        let mut luminous_power = 0.0;
+
+        for first in (0..steps).step_by(LANES) {
+            let wavelengths: Wavelengths = (first..steps.min(first + LANES))
+                .map(|step| start + step as f32 + 0.5)
+                .collect();
+            let input = RenderContext {
+                wavelengths,
+                normal: Vector3::new(0.0, 0.0, 1.0),
+                incident: Vector3::new(0.0, 0.0, -1.0),
+                texture: Point2::new(0.0, 0.0),
+                footprint: Footprint::default(),
+                position: Point3::origin(),
+            };
+            let values = exe.run(self.color, &input).values;
+
+            for (&wavelength, &value) in wavelengths.as_slice().iter().zip(&values) {
+                power += value;
+                luminous_power += value * xyz::response::Y.get(wavelength);
+            }
+        }
+
+        // The radiance is spread over a hemisphere, where it adds up to pi
+        // times as much per square meter.
+        let scale = match (unit, area) {
+            (EmissionUnit::Nits, _) => intensity / (LUMINOUS_EFFICACY * luminous_power),
+            (EmissionUnit::Lumens, Some(area)) => {
+                intensity / (LUMINOUS_EFFICACY * luminous_power * PI * area)
+            }
+            (EmissionUnit::Watts, Some(area)) => intensity / (power * PI * area),
+            (EmissionUnit::Lumens, None) | (EmissionUnit::Watts, None) => {
+                return Err(
+                    "emission in watts or lumens needs shapes with a finite surface area".into(),
+                )
+            }
+        };
+
+        if !scale.is_finite() {
+            return Err("emission with physical units needs a visible color".into());
+        }
+
+        self.color = programs.scale(self.color, scale)?;
+        self.back_color = self
+            .back_color
+            .map(|back_color| programs.scale(back_color, scale))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// The color that is seen from the direction of `ray_in`.
+    fn color(&self, ray_in: Vector3<f32>, normal: Vector3<f32>) -> LightProgram<'p> {
+        match self.back_color {
+            Some(back_color) if ray_in.dot(normal) > 0.0 => back_color,
+            _ => self.color,
+        }
     }
 
-    fn get_emission(&self) -> Option<Program<RenderContext, Light>> {
-        Some(self.color)
+    fn reflect(&self, ray_in: Ray3<f32>, normal: Vector3<f32>) -> Reflection<'_> {
+        Emit(self.color(ray_in.direction, normal))
+    }
+
+    fn get_emission(
+        &self,
+        ray_in: Vector3<f32>,
+        normal: Vector3<f32>,
+    ) -> Option<Program<RenderContext, Light>> {
+        Some(self.color(ray_in, normal))
     }
 }
 
@@ -296,47 +915,287 @@ impl<'p> Mirror<'p> {
     }
 }
 
+/// A rough mirror, with the GGX microfacet distribution. The roughness can be
+/// different along the surface's tangent and bitangent, for brushed metal.
+/// The tangent follows the texture coordinates where there are any.
+pub(crate) struct Glossy<'p> {
+    pub color: LightProgram<'p>,
+    alpha_x: f32,
+    alpha_y: f32,
+    /// Rotates the tangent around the normal, in radians.
+    rotation: f32,
+}
+
+impl<'p> Glossy<'p> {
+    fn reflect(
+        &self,
+        ray_in: Ray3<f32>,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+        tangent: Vector3<f32>,
+        rng: &mut impl Rng,
+    ) -> Reflection<'_> {
+        let n = if ray_in.direction.dot(normal) < 0.0 {
+            normal
+        } else {
+            -normal
+        };
+
+        let tangent = tangent - n * n.dot(tangent);
+        let (x_axis, y_axis) = if tangent.magnitude2() > 0.0 && tangent.magnitude2().is_finite() {
+            let tangent = tangent.normalize();
+            (tangent, n.cross(tangent))
+        } else {
+            math::utils::basis(n)
+        };
+        let (sin, cos) = self.rotation.sin_cos();
+        let (x_axis, y_axis) = (x_axis * cos + y_axis * sin, y_axis * cos - x_axis * sin);
+        let frame = (x_axis, y_axis, n);
+
+        let half = sample_microfacet(rng, frame, self.alpha_x, self.alpha_y);
+        let incoming = -ray_in.direction;
+        let reflected = half * (2.0 * incoming.dot(half)) - incoming;
+
+        // Reflections below the surface are absorbed.
+        let weight = if reflected.dot(n) > 0.0 {
+            microfacet_weight(frame, half, incoming, reflected, self.alpha_x, self.alpha_y)
+        } else {
+            0.0
+        };
+
+        Reflect(Ray3::new(position, reflected), self.color, weight, None)
+    }
+}
+
+pub(crate) struct BlinnPhong<'p> {
+    pub color: LightProgram<'p>,
+    exponent: f32,
+}
+
+impl<'p> BlinnPhong<'p> {
+    fn reflect(
+        &self,
+        ray_in: Ray3<f32>,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+        rng: &mut impl Rng,
+    ) -> Reflection<'_> {
+        let n = if ray_in.direction.dot(normal) < 0.0 {
+            normal
+        } else {
+            -normal
+        };
+
+        // The half vector is sampled in proportion to the lobe, which
+        // spreads the reflections around the mirror direction.
+        let (x_axis, y_axis) = math::utils::basis(n);
+        let cos_theta = rng.gen::<f32>().powf(1.0 / (self.exponent + 1.0));
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let angle = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let half =
+            x_axis * (sin_theta * angle.cos()) + y_axis * (sin_theta * angle.sin()) + n * cos_theta;
+
+        let incoming = -ray_in.direction.normalize();
+        let reflected = half * (2.0 * incoming.dot(half)) - incoming;
+
+        // The lobe is normalized by (exponent + 8) / 8π, which is only
+        // approximate, so the weight is kept from adding energy at low
+        // exponents. Reflections below the surface are absorbed.
+        let weight = if reflected.dot(n) > 0.0 && incoming.dot(half) > 0.0 {
+            let scale = (self.exponent + 8.0) / (self.exponent + 1.0);
+            (scale * incoming.dot(half) * reflected.dot(n)).min(1.0)
+        } else {
+            0.0
+        };
+
+        Reflect(Ray3::new(position, reflected), self.color, weight, None)
+    }
+}
+
+/// Samples a microfacet normal from the GGX distribution, around the Z axis
+/// of `frame`, as in "Microfacet Models for Refraction through Rough
+/// Surfaces" by Walter et al.
+fn sample_microfacet(
+    rng: &mut impl Rng,
+    frame: (Vector3<f32>, Vector3<f32>, Vector3<f32>),
+    alpha_x: f32,
+    alpha_y: f32,
+) -> Vector3<f32> {
+    let (x_axis, y_axis, n) = frame;
+    let u: f32 = rng.gen();
+    let v: f32 = rng.gen();
+    let angle = 2.0 * std::f32::consts::PI * u;
+    let phi = (alpha_y * angle.sin()).atan2(alpha_x * angle.cos());
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let alpha2 =
+        1.0 / (cos_phi * cos_phi / (alpha_x * alpha_x) + sin_phi * sin_phi / (alpha_y * alpha_y));
+    let tan2_theta = alpha2 * v / (1.0 - v);
+    let cos_theta = 1.0 / (1.0 + tan2_theta).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+    (x_axis * (sin_theta * cos_phi) + y_axis * (sin_theta * sin_phi) + n * cos_theta).normalize()
+}
+
+/// The weight of a direction that was scattered by a sampled microfacet. It's
+/// the same for reflection and refraction, since the probability of choosing
+/// either is already part of the sampling.
+fn microfacet_weight(
+    frame: (Vector3<f32>, Vector3<f32>, Vector3<f32>),
+    half: Vector3<f32>,
+    incoming: Vector3<f32>,
+    outgoing: Vector3<f32>,
+    alpha_x: f32,
+    alpha_y: f32,
+) -> f32 {
+    let (x_axis, y_axis, n) = frame;
+    let to_local = |v: Vector3<f32>| Vector3::new(v.dot(x_axis), v.dot(y_axis), v.dot(n));
+
+    let in_cos = incoming.dot(n);
+    if in_cos <= 0.0 {
+        return 0.0;
+    }
+
+    let masking = 1.0
+        / (1.0
+            + smith_lambda(to_local(incoming), alpha_x, alpha_y)
+            + smith_lambda(to_local(outgoing), alpha_x, alpha_y));
+    masking * incoming.dot(half).abs() / (in_cos * half.dot(n))
+}
+
+/// The part of the microfacets that are hidden from `direction`, which is in
+/// tangent space.
+fn smith_lambda(direction: Vector3<f32>, alpha_x: f32, alpha_y: f32) -> f32 {
+    let z2 = direction.z * direction.z;
+    if z2 == 0.0 {
+        return std::f32::INFINITY;
+    }
+
+    let slope2 = (direction.x * direction.x * alpha_x * alpha_x
+        + direction.y * direction.y * alpha_y * alpha_y)
+        / z2;
+    ((1.0 + slope2).sqrt() - 1.0) * 0.5
+}
+
+/// A measured BRDF, that is tinted by `color`. The measurements are in RGB, so
+/// each path is limited to a single wavelength.
+pub(crate) struct Measured<'p> {
+    brdf: Arc<MeasuredBrdf>,
+    pub color: LightProgram<'p>,
+}
+
+impl<'p> Measured<'p> {
+    fn reflect(
+        &self,
+        light: &mut tracer::Light,
+        ray_in: Ray3<f32>,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+        rng: &mut impl Rng,
+    ) -> Reflection<'_> {
+        let n = if ray_in.direction.dot(normal) < 0.0 {
+            normal
+        } else {
+            -normal
+        };
+
+        let (x_axis, y_axis) = math::utils::basis(n);
+        let to_local = |v: Vector3<f32>| Vector3::new(v.dot(x_axis), v.dot(y_axis), v.dot(n));
+
+        let incoming = to_local(-ray_in.direction.normalize());
+        let outgoing = self.brdf.sample(rng, incoming);
+        let reflected = x_axis * outgoing.x + y_axis * outgoing.y + n * outgoing.z;
+
+        // Reflections below the surface are absorbed.
+        let weight = if incoming.z > 0.0 && outgoing.z > 0.0 {
+            let [red, green, blue] = self.brdf.evaluate(incoming, outgoing);
+            let wavelength = light.colored();
+            let reflectance = red * crate::rgb::response::RED.get(wavelength)
+                + green * crate::rgb::response::GREEN.get(wavelength)
+                + blue * crate::rgb::response::BLUE.get(wavelength);
+
+            reflectance * outgoing.z / self.brdf.pdf(incoming, outgoing)
+        } else {
+            0.0
+        };
+
+        Reflect(Ray3::new(position, reflected), self.color, weight, None)
+    }
+}
+
+/// The roughness is squared, to make it look more linear, and kept above 0
+/// to avoid dividing by 0.
+fn roughness_to_alpha(roughness: f32) -> f32 {
+    (roughness * roughness).max(1.0e-4)
+}
+
+/// Picks one of the materials at each intersection, with a factor that can
+/// vary over the surface.
 pub(crate) struct Mix<'p> {
-    factor: f32,
+    factor: Program<'p, NormalInput, f32>,
     pub a: Box<SurfaceMaterial<'p>>,
     pub b: Box<SurfaceMaterial<'p>>,
 }
 
 impl<'p> Mix<'p> {
+    fn pick(
+        &self,
+        ray_in: Vector3<f32>,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+        texture: Point2<f32>,
+        rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
+    ) -> &SurfaceMaterial<'p> {
+        let input = NormalInput {
+            normal,
+            incident: ray_in,
+            texture,
+            footprint: Footprint::default(),
+            position,
+        };
+
+        if exe.run(self.factor, &input) < rng.gen() {
+            &self.a
+        } else {
+            &self.b
+        }
+    }
+
     fn reflect(
         &self,
         light: &mut tracer::Light,
         ray_in: Ray3<f32>,
         position: Point3<f32>,
         normal: Vector3<f32>,
+        tangent: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Reflection<'_> {
-        if self.factor < rng.gen() {
-            self.a.reflect(light, ray_in, position, normal, rng)
-        } else {
-            self.b.reflect(light, ray_in, position, normal, rng)
-        }
+        self.pick(ray_in.direction, position, normal, texture, rng, exe)
+            .reflect(light, ray_in, position, normal, tangent, texture, rng, exe)
     }
 
     fn get_emission(
         &self,
         light: &mut tracer::Light,
         ray_in: Vector3<f32>,
+        position: Point3<f32>,
         normal: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Option<Program<RenderContext, Light>> {
-        if self.factor < rng.gen() {
-            self.a.get_emission(light, ray_in, normal, rng)
-        } else {
-            self.b.get_emission(light, ray_in, normal, rng)
-        }
+        self.pick(ray_in, position, normal, texture, rng, exe)
+            .get_emission(light, ray_in, position, normal, texture, rng, exe)
     }
 }
 
 pub(crate) struct FresnelMix<'p> {
     ior: f32,
     dispersion: f32,
-    env_ior: f32,
+    /// Taken from the surrounding media if it's `None`.
+    env_ior: Option<f32>,
     env_dispersion: f32,
     pub reflect: Box<SurfaceMaterial<'p>>,
     pub refract: Box<SurfaceMaterial<'p>>,
@@ -349,12 +1208,16 @@ impl<'p> FresnelMix<'p> {
         ray_in: Ray3<f32>,
         position: Point3<f32>,
         normal: Vector3<f32>,
+        tangent: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Reflection<'_> {
+        let env_ior = self.env_ior.unwrap_or_else(|| light.outside_ior());
         if self.dispersion != 0.0 || self.env_dispersion != 0.0 {
             let wl = light.colored() * 0.001;
             let ior = self.ior + self.dispersion / (wl * wl);
-            let env_ior = self.env_ior + self.env_dispersion / (wl * wl);
+            let env_ior = env_ior + self.env_dispersion / (wl * wl);
             let child = fresnel_mix(
                 ior,
                 env_ior,
@@ -364,18 +1227,18 @@ impl<'p> FresnelMix<'p> {
                 normal,
                 rng,
             );
-            child.reflect(light, ray_in, position, normal, rng)
+            child.reflect(light, ray_in, position, normal, tangent, texture, rng, exe)
         } else {
             let child = fresnel_mix(
                 self.ior,
-                self.env_ior,
+                env_ior,
                 &self.reflect,
                 &self.refract,
                 ray_in.direction,
                 normal,
                 rng,
             );
-            child.reflect(light, ray_in, position, normal, rng)
+            child.reflect(light, ray_in, position, normal, tangent, texture, rng, exe)
         }
     }
 
@@ -383,13 +1246,17 @@ impl<'p> FresnelMix<'p> {
         &self,
         light: &mut tracer::Light,
         ray_in: Vector3<f32>,
+        position: Point3<f32>,
         normal: Vector3<f32>,
+        texture: Point2<f32>,
         rng: &mut impl Rng,
+        exe: &mut ExecutionContext<'p>,
     ) -> Option<Program<RenderContext, Light>> {
+        let env_ior = self.env_ior.unwrap_or_else(|| light.outside_ior());
         if self.dispersion != 0.0 || self.env_dispersion != 0.0 {
             let wl = light.colored() * 0.001;
             let ior = self.ior + self.dispersion / (wl * wl);
-            let env_ior = self.env_ior + self.env_dispersion / (wl * wl);
+            let env_ior = env_ior + self.env_dispersion / (wl * wl);
             let child = fresnel_mix(
                 ior,
                 env_ior,
@@ -399,31 +1266,31 @@ impl<'p> FresnelMix<'p> {
                 normal,
                 rng,
             );
-            child.get_emission(light, ray_in, normal, rng)
+            child.get_emission(light, ray_in, position, normal, texture, rng, exe)
         } else {
             let child = fresnel_mix(
                 self.ior,
-                self.env_ior,
+                env_ior,
                 &self.reflect,
                 &self.refract,
                 ray_in,
                 normal,
                 rng,
             );
-            child.get_emission(light, ray_in, normal, rng)
+            child.get_emission(light, ray_in, position, normal, texture, rng, exe)
         }
     }
 }
 
-fn fresnel_mix<'a, R: Rng>(
+fn fresnel_mix<'a, 'p, R: Rng>(
     ior: f32,
     env_ior: f32,
-    reflect: &'a SurfaceMaterial<'a>,
-    refract: &'a SurfaceMaterial<'a>,
+    reflect: &'a SurfaceMaterial<'p>,
+    refract: &'a SurfaceMaterial<'p>,
     ray_in: Vector3<f32>,
     normal: Vector3<f32>,
     rng: &mut R,
-) -> &'a SurfaceMaterial<'a> {
+) -> &'a SurfaceMaterial<'p> {
     let factor = if ray_in.dot(normal) < 0.0 {
         math::utils::schlick(env_ior, ior, normal, ray_in)
     } else {
@@ -441,8 +1308,11 @@ pub(crate) struct Refractive<'p> {
     color: LightProgram<'p>,
     ior: f32,
     dispersion: f32,
-    env_ior: f32,
+    /// Taken from the surrounding media if it's `None`.
+    env_ior: Option<f32>,
     env_dispersion: f32,
+    /// Makes it frosted, with the GGX microfacet distribution, if it's set.
+    alpha: Option<f32>,
 }
 
 impl<'p> Refractive<'p> {
@@ -454,25 +1324,37 @@ impl<'p> Refractive<'p> {
         normal: Vector3<f32>,
         rng: &mut impl Rng,
     ) -> Reflection<'_> {
-        if self.dispersion != 0.0 || self.env_dispersion != 0.0 {
+        let env_ior = self.env_ior.unwrap_or_else(|| light.outside_ior());
+        let (ior, env_ior) = if self.dispersion != 0.0 || self.env_dispersion != 0.0 {
             let wl = light.colored() * 0.001;
-            let ior = self.ior + self.dispersion / (wl * wl);
-            let env_ior = self.env_ior + self.env_dispersion / (wl * wl);
-            refract(ior, env_ior, self.color, ray_in, position, normal, rng)
-        } else {
-            refract(
-                self.ior,
-                self.env_ior,
-                self.color,
-                ray_in,
-                position,
-                normal,
-                rng,
+            (
+                self.ior + self.dispersion / (wl * wl),
+                env_ior + self.env_dispersion / (wl * wl),
             )
+        } else {
+            (self.ior, env_ior)
+        };
+
+        match self.alpha {
+            Some(alpha) => rough_refract(
+                ior, env_ior, alpha, self.color, ray_in, position, normal, rng,
+            ),
+            None => refract(ior, env_ior, self.color, ray_in, position, normal, rng),
         }
     }
 }
 
+/// Applies Cauchy's equation for the light's wavelength, if there's any
+/// dispersion.
+fn with_dispersion(ior: f32, dispersion: f32, light: &mut tracer::Light) -> f32 {
+    if dispersion != 0.0 {
+        let wl = light.colored() * 0.001;
+        ior + dispersion / (wl * wl)
+    } else {
+        ior
+    }
+}
+
 fn refract<'a, R: Rng>(
     ior: f32,
     env_ior: f32,
@@ -521,3 +1403,53 @@ fn refract<'a, R: Rng>(
         return Reflect(Ray3::new(position, tdir), color, tp, None);
     }
 }
+
+/// Reflects or refracts through a sampled microfacet, where the choice is
+/// made from the microfacet's Fresnel reflectance.
+fn rough_refract<'a, R: Rng>(
+    ior: f32,
+    env_ior: f32,
+    alpha: f32,
+    color: Program<'a, RenderContext, Light>,
+    ray_in: Ray3<f32>,
+    position: Point3<f32>,
+    normal: Vector3<f32>,
+    rng: &mut R,
+) -> Reflection<'a> {
+    let into = ray_in.direction.dot(normal) < 0.0;
+    let n = if into { normal } else { -normal };
+    let (from_ior, to_ior) = if into { (env_ior, ior) } else { (ior, env_ior) };
+
+    let (x_axis, y_axis) = math::utils::basis(n);
+    let frame = (x_axis, y_axis, n);
+    let half = sample_microfacet(rng, frame, alpha, alpha);
+
+    let incoming = -ray_in.direction;
+    let cos_in = incoming.dot(half);
+    let reflectance = if cos_in > 0.0 {
+        math::utils::schlick(from_ior, to_ior, half, ray_in.direction)
+    } else {
+        1.0
+    };
+
+    let eta = from_ior / to_ior;
+    let cos2_out = 1.0 - eta * eta * (1.0 - cos_in * cos_in);
+
+    let (direction, valid) = if cos2_out < 0.0 || rng.gen::<f32>() < reflectance {
+        let reflected = half * (2.0 * cos_in) - incoming;
+        (reflected, reflected.dot(n) > 0.0)
+    } else {
+        let refracted =
+            (ray_in.direction * eta + half * (eta * cos_in - cos2_out.sqrt())).normalize();
+        (refracted, refracted.dot(n) < 0.0)
+    };
+
+    // Directions that end up on the wrong side of the surface are absorbed.
+    let weight = if valid {
+        microfacet_weight(frame, half, incoming, direction, alpha, alpha)
+    } else {
+        0.0
+    };
+
+    Reflect(Ray3::new(position, direction), color, weight, None)
+}
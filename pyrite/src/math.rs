@@ -1,14 +1,22 @@
-use cgmath::Vector3;
+use cgmath::{InnerSpace, Point2, Point3, Vector3};
+use collision::Ray3;
 
 pub const DIST_EPSILON: f32 = 0.0001;
 
+/// The rounding error of positions, relative to their size.
+const RELATIVE_EPSILON: f32 = 1.0e-5;
+
+/// The smallest ray epsilon in meters, for positions very close to the
+/// origin.
+const MIN_EPSILON: f32 = 1.0e-7;
+
 pub mod utils {
     use std;
 
     use rand::Rng;
 
     use super::DIST_EPSILON;
-    use cgmath::{InnerSpace, Vector3};
+    use cgmath::{InnerSpace, Matrix3, Matrix4, SquareMatrix, Vector3};
 
     pub struct Interpolated<T = Vec<(f32, f32)>> {
         pub points: T,
@@ -64,6 +72,64 @@ pub mod utils {
         }
     }
 
+    /// A piecewise constant distribution over `[0, 1)`, for picking values in
+    /// proportion to a list of evenly spaced weights.
+    pub struct Distribution {
+        cdf: Vec<f32>,
+    }
+
+    impl Distribution {
+        /// Returns `None` if the weights don't add up to anything.
+        pub fn new(weights: &[f32]) -> Option<Self> {
+            let mut cdf = Vec::with_capacity(weights.len() + 1);
+            let mut sum = 0.0;
+            cdf.push(sum);
+            for &weight in weights {
+                sum += weight.max(0.0);
+                cdf.push(sum);
+            }
+
+            if !(sum > 0.0 && sum.is_finite()) {
+                return None;
+            }
+
+            for value in &mut cdf {
+                *value /= sum;
+            }
+
+            Some(Distribution { cdf })
+        }
+
+        pub fn sample<R: ?Sized + Rng>(&self, rng: &mut R) -> f32 {
+            let target: f32 = rng.gen();
+            let index = match self
+                .cdf
+                .binary_search_by(|value| value.partial_cmp(&target).unwrap())
+            {
+                Ok(index) => index.min(self.cdf.len() - 2),
+                Err(index) => index - 1,
+            };
+
+            let start = self.cdf[index];
+            let size = self.cdf[index + 1] - start;
+            let offset = if size > 0.0 {
+                (target - start) / size
+            } else {
+                0.0
+            };
+
+            (index as f32 + offset) / (self.cdf.len() - 1) as f32
+        }
+
+        /// The probability density at `x`, where 1 is the density of a
+        /// uniform distribution.
+        pub fn density(&self, x: f32) -> f32 {
+            let bins = self.cdf.len() - 1;
+            let index = ((x * bins as f32) as usize).min(bins - 1);
+            (self.cdf[index + 1] - self.cdf[index]) * bins as f32
+        }
+    }
+
     pub fn schlick(
         ref_index1: f32,
         ref_index2: f32,
@@ -114,6 +180,18 @@ pub mod utils {
         (y, z)
     }
 
+    /// The average scale of a transform, from how much it scales volumes.
+    pub fn average_scale(transform: Matrix4<f32>) -> f32 {
+        Matrix3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        )
+        .determinant()
+        .abs()
+        .cbrt()
+    }
+
     pub fn sample_cone<R: ?Sized + Rng>(
         rng: &mut R,
         direction: Vector3<f32>,
@@ -154,14 +232,225 @@ pub mod utils {
         let z = direction.normalize_to(s.z.abs());
         x + y + z
     }
+
+    /// The highest degree of the polynomials that `polynomial_roots` solves.
+    pub const MAX_DEGREE: usize = 4;
+
+    /// The roots of a polynomial, in increasing order. There are at most as
+    /// many as its degree.
+    #[derive(Copy, Clone, Default)]
+    pub struct Roots {
+        values: [f64; MAX_DEGREE],
+        len: usize,
+    }
+
+    impl Roots {
+        fn push(&mut self, root: f64) {
+            self.values[self.len] = root;
+            self.len += 1;
+        }
+
+        fn last(&self) -> Option<f64> {
+            self.as_slice().last().copied()
+        }
+
+        pub fn as_slice(&self) -> &[f64] {
+            &self.values[..self.len]
+        }
+    }
+
+    impl IntoIterator for Roots {
+        type Item = f64;
+        type IntoIter = std::iter::Take<std::array::IntoIter<f64, MAX_DEGREE>>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            IntoIterator::into_iter(self.values).take(self.len)
+        }
+    }
+
+    /// Finds the roots of a polynomial within `[min, max]`, in increasing
+    /// order. The coefficients start with the constant term, and the degree
+    /// can be at most `MAX_DEGREE`.
+    ///
+    /// The roots of the derivative split the range into parts where the
+    /// polynomial is monotonic, and each part has at most one root, which is
+    /// found by bisection.
+    pub fn polynomial_roots(coefficients: &[f64], min: f64, max: f64) -> Roots {
+        let degree = coefficients.len().saturating_sub(1);
+        assert!(
+            degree <= MAX_DEGREE,
+            "polynomials of degree {} are not supported",
+            degree
+        );
+        let evaluate = |x: f64| coefficients.iter().rev().fold(0.0, |sum, &c| sum * x + c);
+
+        let mut roots = Roots::default();
+        if degree == 0 {
+            return roots;
+        }
+
+        let mut derivative = [0.0; MAX_DEGREE];
+        for (power, &c) in coefficients.iter().enumerate().skip(1) {
+            derivative[power - 1] = c * power as f64;
+        }
+        let turning_points = polynomial_roots(&derivative[..degree], min, max);
+
+        let mut low_bound = min;
+        let bounds = turning_points
+            .into_iter()
+            .chain(std::iter::once(max))
+            .map(|high_bound| {
+                let range = (low_bound, high_bound);
+                low_bound = high_bound;
+                range
+            });
+
+        for (mut low, mut high) in bounds {
+            let (low_value, high_value) = (evaluate(low), evaluate(high));
+
+            if low_value == 0.0 {
+                if roots.last() != Some(low) {
+                    roots.push(low);
+                }
+                continue;
+            }
+
+            if low_value.signum() == high_value.signum() {
+                continue;
+            }
+
+            for _ in 0..64 {
+                let middle = (low + high) * 0.5;
+                if evaluate(middle).signum() == low_value.signum() {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+
+            roots.push((low + high) * 0.5);
+        }
+
+        if roots.len < degree && evaluate(max) == 0.0 && roots.last() != Some(max) {
+            roots.push(max);
+        }
+
+        roots
+    }
 }
 
-pub fn fresnel(ior: f32, env_ior: f32, normal: Vector3<f32>, incident: Vector3<f32>) -> f32 {
-    use cgmath::InnerSpace;
+/// How far a ray has to travel from a hit at `position`, `distance` away
+/// from the previous ray's origin, before it can hit anything. It grows with
+/// both, since larger numbers have larger rounding errors, so it works for
+/// both huge and tiny scenes. `scale` is the length of one unit in meters.
+pub fn ray_epsilon(position: Point3<f32>, distance: f32, scale: f32) -> f32 {
+    let size = position.x.abs().max(position.y.abs()).max(position.z.abs());
+    MIN_EPSILON / scale + (size + distance.abs()) * RELATIVE_EPSILON
+}
+
+/// Moves the origin of a ray that leaves a surface `epsilon` away from it, to
+/// the side the ray is going.
+pub fn offset_ray(mut ray: Ray3<f32>, normal: Vector3<f32>, epsilon: f32) -> Ray3<f32> {
+    if ray.direction.dot(normal) < 0.0 {
+        ray.origin -= normal * epsilon;
+    } else {
+        ray.origin += normal * epsilon;
+    }
+
+    ray
+}
 
+pub fn fresnel(ior: f32, env_ior: f32, normal: Vector3<f32>, incident: Vector3<f32>) -> f32 {
     if incident.dot(normal) < 0.0 {
         utils::schlick(env_ior, ior, normal, incident)
     } else {
         utils::schlick(ior, env_ior, -normal, incident)
     }
 }
+
+/// The angle between the incident direction and the normal, in degrees. It's
+/// between 0 and 90, no matter which side of the surface is hit.
+pub fn incidence_angle(normal: Vector3<f32>, incident: Vector3<f32>) -> f32 {
+    let cos = incident.normalize().dot(normal.normalize()).abs().min(1.0);
+    cos.acos().to_degrees()
+}
+
+/// How much `incident` is within `angle` degrees of `direction`. It's 1
+/// inside, and fades smoothly to 0 over the next `softness` degrees.
+pub fn direction_region(
+    direction: Vector3<f32>,
+    angle: f32,
+    softness: f32,
+    incident: Vector3<f32>,
+) -> f32 {
+    let cos = incident
+        .normalize()
+        .dot(direction.normalize())
+        .max(-1.0)
+        .min(1.0);
+    let outside = cos.acos().to_degrees() - angle;
+
+    if outside <= 0.0 {
+        1.0
+    } else if outside >= softness {
+        0.0
+    } else {
+        let t = outside / softness;
+        1.0 - t * t * (3.0 - 2.0 * t)
+    }
+}
+
+/// Maps a direction to coordinates in an equirectangular image, where Z is up
+/// and the middle of the image faces X. The image is turned counterclockwise
+/// around Z by `rotation` radians.
+pub fn equirectangular(direction: Vector3<f32>, rotation: f32) -> Point2<f32> {
+    let direction = direction.normalize();
+    let azimuth = direction.y.atan2(direction.x) - rotation;
+    let elevation = direction.z.max(-1.0).min(1.0).asin();
+
+    Point2::new(
+        (0.5 - azimuth / (2.0 * std::f32::consts::PI)).rem_euclid(1.0),
+        0.5 + elevation / std::f32::consts::PI,
+    )
+}
+
+/// Keeps a mapped shading normal on the same side as the geometric normal,
+/// as seen from `incident`. Normals that face the other way are turned to be
+/// almost perpendicular to `incident`.
+pub fn facing_shading_normal(
+    shading: Vector3<f32>,
+    geometric: Vector3<f32>,
+    incident: Vector3<f32>,
+) -> Vector3<f32> {
+    let side = incident.dot(geometric);
+    if incident.dot(shading) * side > 0.0 {
+        return shading;
+    }
+
+    let perpendicular = shading - incident * incident.dot(shading);
+    (perpendicular + geometric * 0.01).normalize()
+}
+
+/// The exact Fresnel reflectance of unpolarized light on a conductor, with
+/// the complex index of refraction `n + ik`.
+pub fn conductor_fresnel(n: f32, k: f32, normal: Vector3<f32>, incident: Vector3<f32>) -> f32 {
+    let cos = incident.dot(normal).abs().min(1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let n2 = n * n;
+    let k2 = k * k;
+
+    let t0 = n2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * n2 * k2).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
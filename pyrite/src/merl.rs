@@ -0,0 +1,225 @@
+//! Measured isotropic BRDFs in the binary format of the MERL BRDF database,
+//! from "A Data-Driven Reflectance Model" by Matusik et al.
+//!
+//! The reflectance is tabulated over the half vector and difference vector
+//! angles of Rusinkiewicz's parameterization, and the files start with the
+//! size of each dimension, as three `i32`, followed by all of the red, then
+//! green, then blue values, as `f64`.
+
+use std::{error::Error, f32::consts::PI, path::Path};
+
+use cgmath::{InnerSpace, Vector3};
+use rand::Rng;
+
+const THETA_H_SAMPLES: usize = 90;
+const THETA_D_SAMPLES: usize = 90;
+const PHI_D_SAMPLES: usize = 180;
+const SAMPLES: usize = THETA_H_SAMPLES * THETA_D_SAMPLES * PHI_D_SAMPLES;
+
+/// The stored values are scaled differently for each color channel.
+const SCALE: [f32; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+pub(crate) struct MeasuredBrdf {
+    values: Vec<[f32; 3]>,
+    /// The cumulative distribution of the reflectance over the half vector
+    /// angle bins, for sampling half vectors.
+    theta_h_cdf: Vec<f32>,
+}
+
+impl MeasuredBrdf {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read(path)?;
+        if data.len() < 12 {
+            return Err("the file is too short".into());
+        }
+
+        let dimensions: Vec<i64> = data[..12]
+            .chunks(4)
+            .map(|bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64)
+            .collect();
+        if dimensions.iter().product::<i64>() != SAMPLES as i64 {
+            return Err(format!(
+                "expected {}x{}x{} samples, but found {}x{}x{}",
+                THETA_H_SAMPLES,
+                THETA_D_SAMPLES,
+                PHI_D_SAMPLES,
+                dimensions[0],
+                dimensions[1],
+                dimensions[2]
+            )
+            .into());
+        }
+
+        let values = &data[12..];
+        if values.len() != SAMPLES * 3 * 8 {
+            return Err(format!(
+                "expected {} bytes of samples, but found {}",
+                SAMPLES * 3 * 8,
+                values.len()
+            )
+            .into());
+        }
+
+        let value = |index: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&values[index * 8..(index + 1) * 8]);
+            f64::from_le_bytes(bytes) as f32
+        };
+
+        // Missing samples are negative.
+        let values: Vec<[f32; 3]> = (0..SAMPLES)
+            .map(|index| {
+                [
+                    (value(index) * SCALE[0]).max(0.0),
+                    (value(index + SAMPLES) * SCALE[1]).max(0.0),
+                    (value(index + SAMPLES * 2) * SCALE[2]).max(0.0),
+                ]
+            })
+            .collect();
+
+        let bin_size = THETA_D_SAMPLES * PHI_D_SAMPLES;
+        let mut weights: Vec<f32> = values
+            .chunks(bin_size)
+            .map(|bin| bin.iter().map(|&[r, g, b]| r + g + b).sum())
+            .collect();
+
+        // Black materials are sampled as if they were uniformly glossy.
+        if weights.iter().sum::<f32>() <= 0.0 {
+            weights = (0..THETA_H_SAMPLES)
+                .map(|index| {
+                    let (from, to) = theta_h_bin(index);
+                    from.cos() - to.cos()
+                })
+                .collect();
+        }
+
+        let total: f32 = weights.iter().sum();
+        let mut theta_h_cdf = Vec::with_capacity(THETA_H_SAMPLES + 1);
+        let mut sum = 0.0;
+        theta_h_cdf.push(0.0);
+        for weight in weights {
+            sum += weight / total;
+            theta_h_cdf.push(sum);
+        }
+
+        Ok(MeasuredBrdf {
+            values,
+            theta_h_cdf,
+        })
+    }
+
+    /// The red, green and blue reflectance between two directions, that are
+    /// both pointing away from the surface, in a frame where Z is the normal.
+    pub fn evaluate(&self, incoming: Vector3<f32>, outgoing: Vector3<f32>) -> [f32; 3] {
+        let half = (incoming + outgoing).normalize();
+        let theta_h = half.z.max(-1.0).min(1.0).acos();
+        let phi_h = half.y.atan2(half.x);
+
+        // The difference vector is the incoming direction, in a frame where
+        // the half vector is the normal.
+        let difference = rotate_y(rotate_z(incoming, -phi_h), -theta_h);
+        let theta_d = difference.z.max(-1.0).min(1.0).acos();
+        let mut phi_d = difference.y.atan2(difference.x);
+
+        // The table only covers half of the circle, because of reciprocity.
+        if phi_d < 0.0 {
+            phi_d += PI;
+        }
+
+        let theta_h_index = theta_h_index(theta_h);
+        let theta_d_index = to_index(theta_d / (PI * 0.5), THETA_D_SAMPLES);
+        let phi_d_index = to_index(phi_d / PI, PHI_D_SAMPLES);
+
+        self.values[phi_d_index
+            + theta_d_index * PHI_D_SAMPLES
+            + theta_h_index * PHI_D_SAMPLES * THETA_D_SAMPLES]
+    }
+
+    /// Picks an outgoing direction, either from a cosine weighted hemisphere
+    /// or from a half vector with an angle that is picked in proportion to
+    /// the reflectance.
+    pub fn sample(&self, rng: &mut impl Rng, incoming: Vector3<f32>) -> Vector3<f32> {
+        let u: f32 = rng.gen();
+        let v: f32 = rng.gen();
+
+        if rng.gen::<bool>() {
+            let radius = u.sqrt();
+            let angle = 2.0 * PI * v;
+            return Vector3::new(
+                radius * angle.cos(),
+                radius * angle.sin(),
+                (1.0 - u).max(0.0).sqrt(),
+            );
+        }
+
+        let index = match self
+            .theta_h_cdf
+            .binary_search_by(|probe| probe.partial_cmp(&u).unwrap())
+        {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        }
+        .min(THETA_H_SAMPLES - 1);
+
+        let (from, to) = theta_h_bin(index);
+        let cos_theta = from.cos() + (to.cos() - from.cos()) * rng.gen::<f32>();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let angle = 2.0 * PI * v;
+        let half = Vector3::new(sin_theta * angle.cos(), sin_theta * angle.sin(), cos_theta);
+
+        half * (2.0 * incoming.dot(half)) - incoming
+    }
+
+    /// The probability density of picking `outgoing` with `sample`.
+    pub fn pdf(&self, incoming: Vector3<f32>, outgoing: Vector3<f32>) -> f32 {
+        let cosine_pdf = outgoing.z.max(0.0) / PI;
+
+        let half = (incoming + outgoing).normalize();
+        let index = theta_h_index(half.z.max(-1.0).min(1.0).acos());
+        let (from, to) = theta_h_bin(index);
+        let probability = self.theta_h_cdf[index + 1] - self.theta_h_cdf[index];
+        let half_pdf = probability / (2.0 * PI * (from.cos() - to.cos()));
+        let half_vector_pdf = half_pdf / (4.0 * incoming.dot(half).abs());
+
+        0.5 * (cosine_pdf + half_vector_pdf)
+    }
+}
+
+/// The half vector angle is sampled more densely near the normal, where
+/// highlights are.
+fn theta_h_index(theta_h: f32) -> usize {
+    if theta_h <= 0.0 {
+        return 0;
+    }
+
+    to_index((theta_h / (PI * 0.5)).sqrt(), THETA_H_SAMPLES)
+}
+
+/// The range of half vector angles in a bin.
+fn theta_h_bin(index: usize) -> (f32, f32) {
+    let from = index as f32 / THETA_H_SAMPLES as f32;
+    let to = (index + 1) as f32 / THETA_H_SAMPLES as f32;
+    (from * from * PI * 0.5, to * to * PI * 0.5)
+}
+
+fn to_index(normalized: f32, samples: usize) -> usize {
+    ((normalized * samples as f32).max(0.0) as usize).min(samples - 1)
+}
+
+fn rotate_z(vector: Vector3<f32>, angle: f32) -> Vector3<f32> {
+    let (sin, cos) = angle.sin_cos();
+    Vector3::new(
+        vector.x * cos - vector.y * sin,
+        vector.x * sin + vector.y * cos,
+        vector.z,
+    )
+}
+
+fn rotate_y(vector: Vector3<f32>, angle: f32) -> Vector3<f32> {
+    let (sin, cos) = angle.sin_cos();
+    Vector3::new(
+        vector.x * cos + vector.z * sin,
+        vector.y,
+        vector.z * cos - vector.x * sin,
+    )
+}
@@ -0,0 +1,218 @@
+//! Procedural noise, for patterns like marble, clouds and terrain that vary
+//! smoothly through space. Each kind of noise is between 0 and 1, and a
+//! number of octaves can be added up into fractal noise.
+
+use cgmath::{InnerSpace, Point3, Vector3};
+
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum NoiseKind {
+    Perlin,
+    Simplex,
+    /// The distance to the closest of a set of scattered points, which forms
+    /// cells.
+    Worley,
+}
+
+impl NoiseKind {
+    /// Kinds are passed to programs as numbers.
+    pub fn to_number(self) -> f32 {
+        match self {
+            NoiseKind::Perlin => 0.0,
+            NoiseKind::Simplex => 1.0,
+            NoiseKind::Worley => 2.0,
+        }
+    }
+
+    pub fn from_number(number: f32) -> Self {
+        if number == 1.0 {
+            NoiseKind::Simplex
+        } else if number == 2.0 {
+            NoiseKind::Worley
+        } else {
+            NoiseKind::Perlin
+        }
+    }
+}
+
+/// Fractal noise at `position` times `scale`. Each of the `octaves` has
+/// `lacunarity` times the frequency and `gain` times the amplitude of the
+/// previous one, and the sum is divided by the total amplitude.
+pub(crate) fn fractal(
+    kind: NoiseKind,
+    position: Point3<f32>,
+    scale: f32,
+    octaves: u32,
+    lacunarity: f32,
+    gain: f32,
+) -> f32 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = scale;
+
+    for _ in 0..octaves.max(1) {
+        let point = position * frequency;
+        let value = match kind {
+            NoiseKind::Perlin => perlin(point) * 0.5 + 0.5,
+            NoiseKind::Simplex => simplex(point) * 0.5 + 0.5,
+            NoiseKind::Worley => worley(point),
+        };
+
+        sum += value.max(0.0).min(1.0) * amplitude;
+        total_amplitude += amplitude;
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+
+    if total_amplitude > 0.0 {
+        sum / total_amplitude
+    } else {
+        0.0
+    }
+}
+
+/// Gradient noise from "Improving Noise" by Ken Perlin, roughly between -1
+/// and 1.
+fn perlin(point: Point3<f32>) -> f32 {
+    let cell = point.map(f32::floor);
+    let offset = point - cell;
+    let [x, y, z] = [cell.x as i32, cell.y as i32, cell.z as i32];
+
+    let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        gradient(
+            hash(x + dx, y + dy, z + dz),
+            offset - Vector3::new(dx as f32, dy as f32, dz as f32),
+        )
+    };
+
+    let (u, v, w) = (fade(offset.x), fade(offset.y), fade(offset.z));
+
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+/// Simplex noise, as described in "Simplex noise demystified" by Stefan
+/// Gustavson, roughly between -1 and 1. It has fewer directional artifacts
+/// than Perlin noise.
+fn simplex(point: Point3<f32>) -> f32 {
+    const SKEW: f32 = 1.0 / 3.0;
+    const UNSKEW: f32 = 1.0 / 6.0;
+
+    let skew = (point.x + point.y + point.z) * SKEW;
+    let cell = Point3::new(
+        (point.x + skew).floor(),
+        (point.y + skew).floor(),
+        (point.z + skew).floor(),
+    );
+    let unskew = (cell.x + cell.y + cell.z) * UNSKEW;
+    let offset = point - (cell - Vector3::new(unskew, unskew, unskew));
+
+    // The simplex is found by walking along the axes, from the largest
+    // offset to the smallest.
+    let (first, second) = if offset.x >= offset.y {
+        if offset.y >= offset.z {
+            ([1, 0, 0], [1, 1, 0])
+        } else if offset.x >= offset.z {
+            ([1, 0, 0], [1, 0, 1])
+        } else {
+            ([0, 0, 1], [1, 0, 1])
+        }
+    } else if offset.y < offset.z {
+        ([0, 0, 1], [0, 1, 1])
+    } else if offset.x < offset.z {
+        ([0, 1, 0], [0, 1, 1])
+    } else {
+        ([0, 1, 0], [1, 1, 0])
+    };
+
+    let [x, y, z] = [cell.x as i32, cell.y as i32, cell.z as i32];
+    let corners = [[0, 0, 0], first, second, [1, 1, 1]];
+
+    let mut sum = 0.0;
+    for (index, &[dx, dy, dz]) in corners.iter().enumerate() {
+        let corner_offset = offset - Vector3::new(dx as f32, dy as f32, dz as f32)
+            + Vector3::new(1.0, 1.0, 1.0) * (index as f32 * UNSKEW);
+        let falloff = 0.6 - corner_offset.magnitude2();
+
+        if falloff > 0.0 {
+            let falloff = falloff * falloff;
+            sum += falloff * falloff * gradient(hash(x + dx, y + dy, z + dz), corner_offset);
+        }
+    }
+
+    sum * 32.0
+}
+
+/// The distance to the closest feature point, with one point in each unit
+/// cell, limited to 1.
+fn worley(point: Point3<f32>) -> f32 {
+    let cell = point.map(f32::floor);
+    let [x, y, z] = [cell.x as i32, cell.y as i32, cell.z as i32];
+    let mut min_distance2 = std::f32::INFINITY;
+
+    for dz in -1..=1 {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let hash = hash(x + dx, y + dy, z + dz);
+                let fraction = |bits: u32| ((hash >> bits) & 0x3ff) as f32 / 1024.0;
+                let feature = Point3::new(
+                    (x + dx) as f32 + fraction(0),
+                    (y + dy) as f32 + fraction(10),
+                    (z + dz) as f32 + fraction(20),
+                );
+
+                min_distance2 = min_distance2.min((feature - point).magnitude2());
+            }
+        }
+    }
+
+    min_distance2.sqrt().min(1.0)
+}
+
+/// The dot product of `offset` and one of the 12 directions towards the
+/// edges of a cube, picked by `hash`.
+fn gradient(hash: u32, offset: Vector3<f32>) -> f32 {
+    const GRADIENTS: [[f32; 3]; 12] = [
+        [1.0, 1.0, 0.0],
+        [-1.0, 1.0, 0.0],
+        [1.0, -1.0, 0.0],
+        [-1.0, -1.0, 0.0],
+        [1.0, 0.0, 1.0],
+        [-1.0, 0.0, 1.0],
+        [1.0, 0.0, -1.0],
+        [-1.0, 0.0, -1.0],
+        [0.0, 1.0, 1.0],
+        [0.0, -1.0, 1.0],
+        [0.0, 1.0, -1.0],
+        [0.0, -1.0, -1.0],
+    ];
+
+    Vector3::from(GRADIENTS[(hash % 12) as usize]).dot(offset)
+}
+
+/// Scrambles the coordinates of a cell, so neighbouring cells get unrelated
+/// values.
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x2c1b_3c6d);
+    hash ^= hash >> 12;
+    hash = hash.wrapping_mul(0x297a_2d39);
+    hash ^= hash >> 15;
+    hash
+}
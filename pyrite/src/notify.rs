@@ -0,0 +1,186 @@
+use std::{
+    error::Error,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+use image::GenericImageView;
+
+use crate::utils::{join_authority, json_string, split_authority};
+
+/// How long to wait for the webhook server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest width and height of the thumbnail.
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// An HTTP endpoint that receives a JSON report when a render is done.
+#[derive(Clone)]
+pub(crate) struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Webhook {
+    /// Parses a URL like `http://example.com:8080/render-done`. HTTPS isn't
+    /// supported.
+    pub fn parse(url: &str) -> Result<Self, Box<dyn Error>> {
+        let rest = if url.starts_with("http://") {
+            &url["http://".len()..]
+        } else if url.starts_with("https://") {
+            return Err("only http:// URLs are supported".into());
+        } else {
+            return Err(format!("expected an http:// URL, got '{}'", url).into());
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = split_authority(authority, Some(80))
+            .map_err(|error| format!("invalid URL '{}': {}", url, error))?;
+
+        Ok(Webhook {
+            host,
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// The outcome of a render, as reported to the webhook.
+pub(crate) struct Report<'a> {
+    pub scene: &'a Path,
+    pub duration: Duration,
+    pub output_path: &'a Path,
+    /// An image to make a thumbnail from, if there is one.
+    pub thumbnail_path: Option<&'a Path>,
+    pub error: Option<&'a dyn Error>,
+}
+
+/// Sends the report as a JSON object, with an embedded PNG thumbnail.
+pub(crate) fn send(webhook: &Webhook, report: &Report) -> Result<(), Box<dyn Error>> {
+    let thumbnail = report
+        .thumbnail_path
+        .and_then(|path| make_thumbnail(path).ok());
+
+    let mut body = String::new();
+    body.push('{');
+    write!(
+        body,
+        "\"scene\":{},",
+        json_string(&report.scene.to_string_lossy())
+    )?;
+    write!(
+        body,
+        "\"status\":\"{}\",",
+        if report.error.is_some() {
+            "failed"
+        } else {
+            "finished"
+        }
+    )?;
+    match report.error {
+        Some(error) => write!(body, "\"error\":{},", json_string(&error.to_string()))?,
+        None => body.push_str("\"error\":null,"),
+    }
+    write!(
+        body,
+        "\"duration_seconds\":{},",
+        report.duration.as_secs_f64()
+    )?;
+    write!(
+        body,
+        "\"output_path\":{},",
+        json_string(&report.output_path.to_string_lossy())
+    )?;
+    match thumbnail {
+        Some(thumbnail) => write!(
+            body,
+            "\"thumbnail\":\"data:image/png;base64,{}\"",
+            base64(&thumbnail)
+        )?,
+        None => body.push_str("\"thumbnail\":null"),
+    }
+    body.push('}');
+
+    post(webhook, &body)
+}
+
+fn post(webhook: &Webhook, body: &str) -> Result<(), Box<dyn Error>> {
+    let address = (&*webhook.host, webhook.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("could not resolve '{}'", webhook.host))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: pyrite\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        webhook.path,
+        join_authority(&webhook.host, webhook.port),
+        body.len()
+    )?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some(_) => Err(format!("the server responded with '{}'", status_line).into()),
+        None => Err("the server sent an invalid response".into()),
+    }
+}
+
+fn make_thumbnail(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let image = image::open(path)?;
+    let thumbnail = if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+        image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    } else {
+        image
+    };
+
+    let mut png = Vec::new();
+    thumbnail.write_to(&mut png, image::ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = chunk.get(1).cloned().unwrap_or(0) as u32;
+        let b2 = chunk.get(2).cloned().unwrap_or(0) as u32;
+        let triple = (b0 << 16) | (b1 << 8) | b2;
+
+        result.push(ALPHABET[(triple >> 18) as usize & 63] as char);
+        result.push(ALPHABET[(triple >> 12) as usize & 63] as char);
+        if chunk.len() > 1 {
+            result.push(ALPHABET[(triple >> 6) as usize & 63] as char);
+        } else {
+            result.push('=');
+        }
+        if chunk.len() > 2 {
+            result.push(ALPHABET[triple as usize & 63] as char);
+        } else {
+            result.push('=');
+        }
+    }
+
+    result
+}
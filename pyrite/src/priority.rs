@@ -0,0 +1,58 @@
+use std::{
+    error::Error,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The nice value for low priority renders. It's high enough to let
+/// interactive programs go first, while still using idle CPU time.
+#[cfg(unix)]
+const LOW_PRIORITY_NICENESS: libc::c_int = 10;
+
+/// Lowers the scheduling priority of the current thread. Threads that are
+/// started afterwards inherit the lower priority, so this should be done
+/// before any worker threads are started.
+#[cfg(unix)]
+pub(crate) fn lower_priority() -> Result<(), Box<dyn Error>> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICENESS) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lower_priority() -> Result<(), Box<dyn Error>> {
+    Err("lowering the priority is not supported on this platform".into())
+}
+
+/// Keeps a thread's average CPU usage at a percentage, by sleeping in
+/// proportion to how long it has been working.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    idle_ratio: f32,
+}
+
+impl Throttle {
+    /// `cpu_limit` is a percentage between 1 and 100.
+    pub fn new(cpu_limit: u32) -> Self {
+        let limit = cpu_limit.max(1).min(100) as f32 / 100.0;
+
+        Throttle {
+            idle_ratio: (1.0 - limit) / limit,
+        }
+    }
+
+    /// Runs `work` and then rests for long enough to stay within the limit.
+    pub fn run<T>(&self, work: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = work();
+
+        if self.idle_ratio > 0.0 {
+            let rest = start.elapsed().as_secs_f32() * self.idle_ratio;
+            thread::sleep(Duration::from_secs_f32(rest));
+        }
+
+        result
+    }
+}
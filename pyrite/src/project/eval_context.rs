@@ -5,6 +5,7 @@ use super::expressions::Expressions;
 #[derive(Clone, Copy)]
 pub struct EvalContext<'a> {
     pub expressions: &'a Expressions,
+    pub frame: f32,
 }
 
 pub trait Evaluate<T> {
@@ -8,17 +8,24 @@ use cgmath::{
     ElementWise, EuclideanSpace, Point2, Point3, Quaternion, Vector2, Vector3, Vector4, VectorSpace,
 };
 
-use palette::LinSrgb;
-
-use crate::{light_source, texture::ColorEncoding};
+use collision::Aabb3;
+use palette::{LinSrgb, Srgb};
+
+use crate::{
+    light_source, metal,
+    noise::NoiseKind,
+    texture::{ColorEncoding, Footprint, TextureFilter, TextureWrap},
+    volume::{RawFormat, RawVolume},
+};
 
 use super::{
     eval_context::{EvalContext, Evaluate},
     parse_context::{Parse, ParseContext},
-    program::{ProgramFn, ProgramValue},
+    program::{texture_color, volume_value, ProgramFn, ProgramValue},
     spectra::{Spectrum, SpectrumId},
     tables::{TableExt, TableId},
-    textures::TextureId,
+    textures::{TextureId, VolumeId},
+    units::Quantity,
 };
 
 pub struct Expressions {
@@ -29,6 +36,52 @@ impl Expressions {
     pub fn get(&self, id: ExpressionId) -> &ComplexExpression {
         self.expressions.get(id.0).expect("missing expression")
     }
+
+    /// Copies `expression` and everything it depends on.
+    pub fn snapshot(&self, expression: Expression) -> ExpressionSnapshot {
+        let mut snapshot = ExpressionSnapshot {
+            root: expression,
+            expressions: Vec::new(),
+        };
+        let mut ids = HashMap::new();
+        snapshot.root = self.copy_into(expression, &mut snapshot.expressions, &mut ids);
+
+        snapshot
+    }
+
+    fn copy_into(
+        &self,
+        expression: Expression,
+        copies: &mut Vec<ComplexExpression>,
+        ids: &mut HashMap<ExpressionId, ExpressionId>,
+    ) -> Expression {
+        let id = match expression {
+            Expression::Number(_) => return expression,
+            Expression::Complex(id) => id,
+        };
+
+        if let Some(&copy_id) = ids.get(&id) {
+            return Expression::Complex(copy_id);
+        }
+
+        let mut copy = self.get(id).clone();
+        copy.for_each_operand(|operand| *operand = self.copy_into(*operand, copies, ids));
+
+        let copy_id = ExpressionId(copies.len());
+        copies.push(copy);
+        ids.insert(id, copy_id);
+
+        Expression::Complex(copy_id)
+    }
+}
+
+/// A self contained copy of an expression, that can be compared with
+/// expressions from other projects. Spectra, textures and volumes are still
+/// compared by ID, so the projects need to have loaded the same ones.
+#[derive(PartialEq)]
+pub struct ExpressionSnapshot {
+    root: Expression,
+    expressions: Vec<ComplexExpression>,
 }
 
 pub struct ExpressionLoader<'lua> {
@@ -106,7 +159,7 @@ impl<'lua> ExpressionEntry<'lua> {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum Expression {
     Number(f64),
     Complex(ExpressionId),
@@ -115,11 +168,16 @@ pub enum Expression {
 impl<'lua> Parse<'lua> for Expression {
     type Input = rlua::Value<'lua>;
 
-    fn parse<'a>(context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+    fn parse<'a>(mut context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
         if let Ok(number) = context.expect_number() {
             return Ok(Expression::Number(number));
         }
 
+        // Colors can also be hex codes or names.
+        if let rlua::Value::String(text) = context.value().clone() {
+            return context.parse_library_call("parse_color", text);
+        }
+
         let table = if let Ok(table) = context.expect_table() {
             table
         } else {
@@ -188,6 +246,7 @@ impl Evaluate<Quaternion<f32>> for Expression {
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub enum ComplexExpression {
     Vector {
         x: Expression,
@@ -199,6 +258,8 @@ pub enum ComplexExpression {
         red: Expression,
         green: Expression,
         blue: Expression,
+        /// The color as a spectrum, if it's constant.
+        spectrum: Option<SpectrumId>,
     },
     Binary {
         operator: BinaryOperator,
@@ -214,16 +275,173 @@ pub enum ComplexExpression {
         ior: Expression,
         env_ior: Expression,
     },
+    /// The Fresnel reflectance of a metal, with the complex index of
+    /// refraction `n + ik`.
+    ConductorFresnel {
+        n: Expression,
+        k: Expression,
+    },
     Blackbody {
         temperature: Expression,
+        /// Scales the spectrum to 1 at 560 nm, so it's only a color.
+        normalize: bool,
+    },
+    /// A curve over the angle between the incident direction and the normal,
+    /// in degrees. It's stored as a spectrum, where the angles are the
+    /// wavelengths.
+    AngleProfile {
+        curve: SpectrumId,
+    },
+    /// Selects the incident directions within `angle` degrees of
+    /// `direction`, such as a part of the sky.
+    DirectionRegion {
+        direction: Expression,
+        angle: Expression,
+        softness: Expression,
+    },
+    /// The sky from the daylight model, for a sun in the `sun` direction.
+    DaylightSky {
+        sun: Expression,
+        turbidity: Expression,
+    },
+    /// The sunlight that reaches the ground, from the daylight model.
+    Sunlight {
+        sun: Expression,
+        turbidity: Expression,
     },
     Spectrum {
         points: SpectrumId,
     },
+    /// An image, that is looked up by the texture coordinates, after they
+    /// have been scaled by `scale`, rotated by `rotation` degrees and moved by
+    /// `offset`.
     Texture {
         texture: TextureId,
+        filter: TextureFilter,
+        wrap: TextureWrap,
+        offset: Expression,
+        scale: Expression,
+        rotation: Expression,
+    },
+    /// An equirectangular image, that is looked up by the incident
+    /// direction. It's turned around Z by `rotation` radians.
+    Environment {
+        texture: TextureId,
+        rotation: f32,
     },
     DebugNormal,
+    /// Fractal noise at the surface position.
+    Noise {
+        kind: NoiseKind,
+        scale: f32,
+        octaves: u32,
+        lacunarity: f32,
+        gain: f32,
+    },
+    /// A 3D texture, that is looked up by the surface position. The voxels
+    /// fill `bounds`.
+    Volume {
+        volume: VolumeId,
+        bounds: Aabb3<f32>,
+    },
+    Position,
+    Length {
+        vector: Expression,
+    },
+    Abs {
+        value: Expression,
+    },
+    Min {
+        lhs: Expression,
+        rhs: Expression,
+    },
+    Max {
+        lhs: Expression,
+        rhs: Expression,
+    },
+    SmoothMin {
+        lhs: Expression,
+        rhs: Expression,
+        radius: Expression,
+    },
+}
+
+impl ComplexExpression {
+    fn for_each_operand(&mut self, mut function: impl FnMut(&mut Expression)) {
+        match self {
+            ComplexExpression::Vector { x, y, z, w } => {
+                function(x);
+                function(y);
+                function(z);
+                function(w);
+            }
+            ComplexExpression::Rgb {
+                red, green, blue, ..
+            } => {
+                function(red);
+                function(green);
+                function(blue);
+            }
+            ComplexExpression::Binary { lhs, rhs, .. }
+            | ComplexExpression::Min { lhs, rhs }
+            | ComplexExpression::Max { lhs, rhs } => {
+                function(lhs);
+                function(rhs);
+            }
+            ComplexExpression::Mix { amount, lhs, rhs } => {
+                function(amount);
+                function(lhs);
+                function(rhs);
+            }
+            ComplexExpression::Fresnel { ior, env_ior } => {
+                function(ior);
+                function(env_ior);
+            }
+            ComplexExpression::ConductorFresnel { n, k } => {
+                function(n);
+                function(k);
+            }
+            ComplexExpression::Blackbody { temperature, .. } => function(temperature),
+            ComplexExpression::DirectionRegion {
+                direction,
+                angle,
+                softness,
+            } => {
+                function(direction);
+                function(angle);
+                function(softness);
+            }
+            ComplexExpression::DaylightSky { sun, turbidity }
+            | ComplexExpression::Sunlight { sun, turbidity } => {
+                function(sun);
+                function(turbidity);
+            }
+            ComplexExpression::Texture {
+                offset,
+                scale,
+                rotation,
+                ..
+            } => {
+                function(offset);
+                function(scale);
+                function(rotation);
+            }
+            ComplexExpression::Length { vector } => function(vector),
+            ComplexExpression::Abs { value } => function(value),
+            ComplexExpression::SmoothMin { lhs, rhs, radius } => {
+                function(lhs);
+                function(rhs);
+                function(radius);
+            }
+            ComplexExpression::AngleProfile { .. }
+            | ComplexExpression::Spectrum { .. }
+            | ComplexExpression::Environment { .. }
+            | ComplexExpression::DebugNormal
+            | ComplexExpression::Noise { .. }
+            | ComplexExpression::Volume { .. }
+            | ComplexExpression::Position => {}
+        }
+    }
 }
 
 impl<'lua> Parse<'lua> for ComplexExpression {
@@ -239,11 +457,35 @@ impl<'lua> Parse<'lua> for ComplexExpression {
                 z: context.parse_field("z")?,
                 w: context.parse_field("w")?,
             }),
-            "rgb" => Ok(ComplexExpression::Rgb {
-                red: context.parse_field("red")?,
-                green: context.parse_field("green")?,
-                blue: context.parse_field("blue")?,
-            }),
+            "rgb" => {
+                let red = context.parse_field("red")?;
+                let green = context.parse_field("green")?;
+                let blue = context.parse_field("blue")?;
+
+                let spectrum = if let (
+                    Expression::Number(red),
+                    Expression::Number(green),
+                    Expression::Number(blue),
+                ) = (red, green, blue)
+                {
+                    let id = context.value().get_id()?;
+                    Some(if let Some(points) = context.spectra.get(id) {
+                        points
+                    } else {
+                        let color = Srgb::new(red as f32, green as f32, blue as f32).into_linear();
+                        context.spectra.insert(id, Spectrum::from_rgb(color))
+                    })
+                } else {
+                    None
+                };
+
+                Ok(ComplexExpression::Rgb {
+                    red,
+                    green,
+                    blue,
+                    spectrum,
+                })
+            }
             "binary" => Ok(ComplexExpression::Binary {
                 operator: context.parse_field("operator")?,
                 lhs: context.parse_field("lhs")?,
@@ -258,8 +500,53 @@ impl<'lua> Parse<'lua> for ComplexExpression {
                 ior: context.parse_field("ior")?,
                 env_ior: context.parse_field("env_ior")?,
             }),
+            "conductor_fresnel" => Ok(ComplexExpression::ConductorFresnel {
+                n: context.parse_field("n")?,
+                k: context.parse_field("k")?,
+            }),
             "blackbody" => Ok(ComplexExpression::Blackbody {
-                temperature: context.parse_field("temperature")?,
+                temperature: context.parse_quantity_field("temperature", Quantity::Temperature)?,
+                normalize: context
+                    .expect_field::<Option<bool>>("normalize")?
+                    .unwrap_or(false),
+            }),
+            "angle_profile" => {
+                let id = context.value().get_id()?;
+                let curve = if let Some(curve) = context.spectra.get(id) {
+                    curve
+                } else {
+                    let points: Vec<(f32, f32)> =
+                        context.with_field("points", |points: ParseContext<rlua::Value>| {
+                            Ok(rlua_serde::from_value(points.value().clone())?)
+                        })?;
+
+                    if points.is_empty() {
+                        return Err("the angle profile is empty".into());
+                    }
+                    if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                        return Err("the angles in the profile have to increase".into());
+                    }
+                    if points.iter().any(|&(angle, _)| angle < 0.0 || angle > 90.0) {
+                        return Err("the angles in the profile have to be from 0 to 90".into());
+                    }
+
+                    context.spectra.insert(id, Spectrum::Curve { points })
+                };
+
+                Ok(ComplexExpression::AngleProfile { curve })
+            }
+            "direction_region" => Ok(ComplexExpression::DirectionRegion {
+                direction: context.parse_field("direction")?,
+                angle: context.parse_quantity_field("angle", Quantity::Angle)?,
+                softness: context.parse_quantity_field("softness", Quantity::Angle)?,
+            }),
+            "daylight_sky" => Ok(ComplexExpression::DaylightSky {
+                sun: context.parse_field("sun")?,
+                turbidity: context.parse_field("turbidity")?,
+            }),
+            "sunlight" => Ok(ComplexExpression::Sunlight {
+                sun: context.parse_field("sun")?,
+                turbidity: context.parse_field("turbidity")?,
             }),
             "spectrum" => {
                 let id = context.value().get_id()?;
@@ -280,7 +567,13 @@ impl<'lua> Parse<'lua> for ComplexExpression {
                     let name: String = context.expect_field("name")?;
                     let spectrum = match &*name {
                         "a" => light_source::A,
+                        "d50" => Spectrum::daylight(5003.0),
+                        "d55" => Spectrum::daylight(5503.0),
                         "d65" => light_source::D65,
+                        "d75" => Spectrum::daylight(7504.0),
+                        "f2" => light_source::F2,
+                        "f7" => light_source::F7,
+                        "f11" => light_source::F11,
                         _ => return Err(format!("unknown builtin spectrum: {}", name).into()),
                     };
                     context.spectra.insert(id, spectrum)
@@ -288,20 +581,161 @@ impl<'lua> Parse<'lua> for ComplexExpression {
 
                 Ok(ComplexExpression::Spectrum { points })
             }
+            "metal" => {
+                let id = context.value().get_id()?;
+                let points = if let Some(points) = context.spectra.get(id) {
+                    points
+                } else {
+                    let (n, k) = match context.expect_field::<Option<String>>("path")? {
+                        Some(path) => context.spectra.load_metal(&path)?,
+                        None => {
+                            let name: String = context.expect_field("name")?;
+                            match &*name {
+                                "gold" => (metal::GOLD_N, metal::GOLD_K),
+                                "copper" => (metal::COPPER_N, metal::COPPER_K),
+                                "aluminum" => (metal::ALUMINUM_N, metal::ALUMINUM_K),
+                                _ => return Err(format!("unknown builtin metal: {}", name).into()),
+                            }
+                        }
+                    };
+                    let spectrum = match &*context.expect_field::<String>("component")? {
+                        "n" => n,
+                        "k" => k,
+                        component => {
+                            return Err(format!("unknown metal component: {}", component).into())
+                        }
+                    };
+                    context.spectra.insert(id, spectrum)
+                };
+
+                Ok(ComplexExpression::Spectrum { points })
+            }
             "texture" => {
                 let encoding = match &*context.expect_field::<String>("encoding")? {
                     "linear" => ColorEncoding::Linear,
                     "srgb" => ColorEncoding::Srgb,
                     encoding => Err(format!("unknown color encoding: {}", encoding))?,
                 };
+                let filter = match &*context.expect_field::<String>("filter")? {
+                    "bicubic" => TextureFilter::Bicubic,
+                    "bilinear" => TextureFilter::Bilinear,
+                    "ewa" => TextureFilter::Ewa,
+                    filter => Err(format!("unknown texture filter: {}", filter))?,
+                };
+                let wrap = match &*context.expect_field::<String>("wrap")? {
+                    "repeat" => TextureWrap::Repeat,
+                    "clamp" => TextureWrap::Clamp,
+                    "mirror" => TextureWrap::Mirror,
+                    wrap => Err(format!("unknown texture wrap mode: {}", wrap))?,
+                };
 
                 Ok(ComplexExpression::Texture {
                     texture: context
                         .textures
                         .load(context.expect_field::<String>("path")?, encoding)?,
+                    filter,
+                    wrap,
+                    offset: context.parse_field("offset")?,
+                    scale: context.parse_field("scale")?,
+                    rotation: context.parse_quantity_field("rotation", Quantity::Angle)?,
+                })
+            }
+            "environment" => {
+                let rotation = match context.parse_quantity_field("rotation", Quantity::Angle)? {
+                    None => 0.0,
+                    Some(Expression::Number(rotation)) => rotation as f32,
+                    Some(_) => return Err("the rotation has to be a number".into()),
+                };
+
+                Ok(ComplexExpression::Environment {
+                    texture: context.textures.load(
+                        context.expect_field::<String>("path")?,
+                        ColorEncoding::Linear,
+                    )?,
+                    rotation: rotation.to_radians(),
                 })
             }
             "debug_normal" => Ok(ComplexExpression::DebugNormal),
+            "noise" => {
+                let kind = match &*context.expect_field::<String>("noise")? {
+                    "perlin" => NoiseKind::Perlin,
+                    "simplex" => NoiseKind::Simplex,
+                    "worley" => NoiseKind::Worley,
+                    kind => Err(format!("unknown kind of noise: {}", kind))?,
+                };
+                let octaves = context.expect_field::<Option<u32>>("octaves")?.unwrap_or(1);
+                if octaves == 0 {
+                    return Err("noise needs at least one octave".into());
+                }
+
+                Ok(ComplexExpression::Noise {
+                    kind,
+                    scale: context.expect_field::<Option<f32>>("scale")?.unwrap_or(1.0),
+                    octaves,
+                    lacunarity: context
+                        .expect_field::<Option<f32>>("lacunarity")?
+                        .unwrap_or(2.0),
+                    gain: context.expect_field::<Option<f32>>("gain")?.unwrap_or(0.5),
+                })
+            }
+            "volume" => {
+                let path = context.expect_field::<String>("path")?;
+                let raw = if path.ends_with(".vol") {
+                    None
+                } else {
+                    let resolution = match context
+                        .expect_field::<Option<Vec<usize>>>("resolution")?
+                        .as_deref()
+                    {
+                        Some(&[x, y, z]) if x > 0 && y > 0 && z > 0 => [x, y, z],
+                        Some(_) => {
+                            return Err("the resolution must be three positive numbers".into())
+                        }
+                        None => return Err("raw volumes need a resolution".into()),
+                    };
+                    let format = match &*context.expect_field::<String>("format")? {
+                        "u8" => RawFormat::U8,
+                        "f32" => RawFormat::F32,
+                        format => Err(format!("unknown volume format: {}", format))?,
+                    };
+
+                    Some(RawVolume { resolution, format })
+                };
+
+                let volume = context.textures.load_volume(path, raw)?;
+                let min = context.expect_field::<Option<Vec<f32>>>("min")?;
+                let max = context.expect_field::<Option<Vec<f32>>>("max")?;
+                let bounds = match (min.as_deref(), max.as_deref()) {
+                    (None, None) => context.textures.volume_bounds(volume),
+                    (Some(&[min_x, min_y, min_z]), Some(&[max_x, max_y, max_z])) => Aabb3::new(
+                        Point3::new(min_x, min_y, min_z),
+                        Point3::new(max_x, max_y, max_z),
+                    ),
+                    _ => return Err("the volume's min and max must both be three numbers".into()),
+                };
+
+                Ok(ComplexExpression::Volume { volume, bounds })
+            }
+            "position" => Ok(ComplexExpression::Position),
+            "length" => Ok(ComplexExpression::Length {
+                vector: context.parse_field("vector")?,
+            }),
+            "abs" => Ok(ComplexExpression::Abs {
+                value: context.parse_field("value")?,
+            }),
+            "min" => Ok(ComplexExpression::Min {
+                lhs: context.parse_field("lhs")?,
+                rhs: context.parse_field("rhs")?,
+            }),
+            "max" => Ok(ComplexExpression::Max {
+                lhs: context.parse_field("lhs")?,
+                rhs: context.parse_field("rhs")?,
+            }),
+            "smooth_min" => Ok(ComplexExpression::SmoothMin {
+                lhs: context.parse_field("lhs")?,
+                rhs: context.parse_field("rhs")?,
+                radius: context.parse_field("radius")?,
+            }),
             name => Err(format!("unexpected expression type: '{}'", name).into()),
         }
     }
@@ -318,7 +752,9 @@ impl<T: ExpressionValue> Evaluate<T> for ComplexExpression {
 
                 T::from_vector(x, y, z, w)
             }
-            ComplexExpression::Rgb { red, green, blue } => {
+            ComplexExpression::Rgb {
+                red, green, blue, ..
+            } => {
                 let red: f32 = red.evaluate(context)?;
                 let green: f32 = green.evaluate(context)?;
                 let blue: f32 = blue.evaluate(context)?;
@@ -342,26 +778,50 @@ impl<T: ExpressionValue> Evaluate<T> for ComplexExpression {
                 let rhs: T = rhs.evaluate(context)?;
                 T::mix(lhs, rhs, amount)
             }
-            ComplexExpression::Fresnel { .. } => {
+            ComplexExpression::Fresnel { .. } | ComplexExpression::ConductorFresnel { .. } => {
                 Err("cannot evaluate Fresnel functions as constants".into())
             }
             ComplexExpression::Blackbody { .. } => {
                 Err("cannot evaluate black-body functions as constants".into())
             }
+            ComplexExpression::AngleProfile { .. } => {
+                Err("cannot evaluate angle profiles as constants".into())
+            }
+            ComplexExpression::DirectionRegion { .. } => {
+                Err("cannot evaluate direction regions as constants".into())
+            }
+            ComplexExpression::DaylightSky { .. } | ComplexExpression::Sunlight { .. } => {
+                Err("cannot evaluate daylight as a constant".into())
+            }
             ComplexExpression::Spectrum { .. } => {
                 Err("cannot evaluate spectra as constants".into())
             }
             ComplexExpression::Texture { .. } => {
                 Err("cannot evaluate textures as constants".into())
             }
+            ComplexExpression::Environment { .. } => {
+                Err("cannot evaluate environment maps as constants".into())
+            }
             ComplexExpression::DebugNormal { .. } => {
                 Err("cannot evaluate surface normals as constants".into())
             }
+            ComplexExpression::Noise { .. } => Err("cannot evaluate noise as a constant".into()),
+            ComplexExpression::Volume { .. } => {
+                Err("cannot evaluate 3D textures as constants".into())
+            }
+            ComplexExpression::Position
+            | ComplexExpression::Length { .. }
+            | ComplexExpression::Abs { .. }
+            | ComplexExpression::Min { .. }
+            | ComplexExpression::Max { .. }
+            | ComplexExpression::SmoothMin { .. } => {
+                Err("cannot evaluate distance field functions as constants".into())
+            }
         }
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum BinaryOperator {
     Add,
     Sub,
@@ -492,12 +952,9 @@ impl<I> ProgramValue<I> for Vector {
     }
     fn texture() -> Result<Option<ProgramFn<I, Self>>, Box<dyn Error>> {
         Ok(Some(|registers, _, resources| {
-            let texture = resources.textures.get(registers.pop());
-            let uv: Vector = registers.pop();
-
             let LinSrgb {
                 red, green, blue, ..
-            } = texture.get_color(uv.into()).color;
+            } = texture_color(registers, resources).color;
 
             let x = (red * 2.0) - 1.0;
             let y = (green * 2.0) - 1.0;
@@ -552,9 +1009,49 @@ impl<I> ProgramValue<I> for Vector {
             Vector(Vector4::new(value, value, value, value))
         })
     }
+    fn conductor_fresnel() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("conductor Fresnel functions cannot be used as vectors".into())
+    }
+    fn angle_profile() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("angle profiles cannot be used as vectors".into())
+    }
+    fn direction_region() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("direction regions cannot be used as vectors".into())
+    }
     fn blackbody() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
         Err("black-body functions cannot be used as vectors".into())
     }
+    fn daylight_sky() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("daylight cannot be used as vectors".into())
+    }
+    fn sunlight() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("sunlight cannot be used as vectors".into())
+    }
+    fn noise() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let position: Vector = registers.pop();
+            let gain: f32 = registers.pop();
+            let lacunarity: f32 = registers.pop();
+            let octaves: f32 = registers.pop();
+            let scale: f32 = registers.pop();
+            let kind: f32 = registers.pop();
+            let value = crate::noise::fractal(
+                NoiseKind::from_number(kind),
+                position.into(),
+                scale,
+                octaves as u32,
+                lacunarity,
+                gain,
+            );
+            Vector(Vector4::new(value, value, value, value))
+        })
+    }
+    fn volume() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, resources| {
+            let value = volume_value(registers, resources);
+            Vector(Vector4::new(value, value, value, value))
+        })
+    }
 }
 
 impl Add for Vector {
@@ -625,6 +1122,33 @@ impl Into<Point3<f32>> for Vector {
     }
 }
 
+impl From<Point3<f32>> for Vector {
+    fn from(point: Point3<f32>) -> Self {
+        Vector(point.to_vec().extend(0.0))
+    }
+}
+
+/// Footprints are passed as vectors, with the major axis first.
+impl Into<Footprint> for Vector {
+    fn into(self) -> Footprint {
+        Footprint {
+            major: Vector2::new(self.0.x, self.0.y),
+            minor: Vector2::new(self.0.z, self.0.w),
+        }
+    }
+}
+
+impl From<Footprint> for Vector {
+    fn from(footprint: Footprint) -> Self {
+        Vector(Vector4::new(
+            footprint.major.x,
+            footprint.major.y,
+            footprint.minor.x,
+            footprint.minor.y,
+        ))
+    }
+}
+
 impl Into<Quaternion<f32>> for Vector {
     fn into(self) -> Quaternion<f32> {
         Quaternion::new(self.0.x, self.0.y, self.0.z, self.0.w)
@@ -634,3 +1158,29 @@ impl Into<Quaternion<f32>> for Vector {
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[repr(transparent)]
 pub struct ExpressionId(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::{BinaryOperator, ComplexExpression, Expression, ExpressionId, Expressions};
+
+    #[test]
+    fn snapshots_ignore_expression_ids() {
+        let sum = |lhs, rhs| ComplexExpression::Binary {
+            operator: BinaryOperator::Add,
+            lhs: Expression::Complex(ExpressionId(lhs)),
+            rhs: Expression::Number(rhs),
+        };
+
+        let first = Expressions {
+            expressions: vec![ComplexExpression::Position, sum(0, 1.0)],
+        };
+        let second = Expressions {
+            expressions: vec![sum(2, 1.0), sum(2, 2.0), ComplexExpression::Position],
+        };
+
+        let snapshot = first.snapshot(Expression::Complex(ExpressionId(1)));
+        assert!(snapshot == second.snapshot(Expression::Complex(ExpressionId(0))));
+        assert!(snapshot != second.snapshot(Expression::Complex(ExpressionId(1))));
+        assert!(snapshot != first.snapshot(Expression::Complex(ExpressionId(0))));
+    }
+}
@@ -164,13 +164,62 @@ function vector(x, y, z, w)
     return properties
 end
 
-function blackbody(temperature)
-    local properties = {type = "blackbody", temperature = temperature}
+function blackbody(temperature, properties)
+    properties = properties or {}
+    properties.type = "blackbody"
+    properties.temperature = temperature
     _pyrite.make_expression(properties)
 
     return properties
 end
 
+function direction_region(properties)
+    properties.type = "direction_region"
+    properties.softness = properties.softness or 0
+    _pyrite.make_expression(properties)
+
+    return properties
+end
+
+local function make_noise(kind)
+    return function(properties)
+        properties = properties or {}
+        properties.type = "noise"
+        properties.noise = kind
+        _pyrite.make_expression(properties)
+
+        return properties
+    end
+end
+
+noise = {
+    perlin = make_noise("perlin"),
+    simplex = make_noise("simplex"),
+    worley = make_noise("worley"),
+}
+
+local function make_distance_function(kind, ...)
+    local names = {...}
+    return function(...)
+        local properties = {type = kind}
+        for i, name in ipairs(names) do
+            properties[name] = select(i, ...)
+        end
+        _pyrite.make_expression(properties)
+
+        return properties
+    end
+end
+
+distance = {
+    position = make_distance_function("position"),
+    length = make_distance_function("length", "vector"),
+    abs = make_distance_function("abs", "value"),
+    min = make_distance_function("min", "lhs", "rhs"),
+    max = make_distance_function("max", "lhs", "rhs"),
+    smooth_min = make_distance_function("smooth_min", "lhs", "rhs", "radius"),
+}
+
 function spectrum(properties)
     properties.type = "spectrum"
     _pyrite.make_expression(properties)
@@ -179,6 +228,10 @@ function spectrum(properties)
 end
 
 function rgb(red, green, blue)
+    if type(red) == "string" then
+        return _pyrite.parse_color(red)
+    end
+
     local properties = {
         type = "rgb",
         red = red or 0.0,
@@ -190,12 +243,210 @@ function rgb(red, green, blue)
     return properties
 end
 
-function texture(path, encoding)
-    local properties = {
-        type = "texture",
-        path = path,
-        encoding = encoding or "srgb",
-    }
+-- The CSS color names.
+_pyrite.color_names = {
+    aliceblue = "#f0f8ff",
+    antiquewhite = "#faebd7",
+    aqua = "#00ffff",
+    aquamarine = "#7fffd4",
+    azure = "#f0ffff",
+    beige = "#f5f5dc",
+    bisque = "#ffe4c4",
+    black = "#000000",
+    blanchedalmond = "#ffebcd",
+    blue = "#0000ff",
+    blueviolet = "#8a2be2",
+    brown = "#a52a2a",
+    burlywood = "#deb887",
+    cadetblue = "#5f9ea0",
+    chartreuse = "#7fff00",
+    chocolate = "#d2691e",
+    coral = "#ff7f50",
+    cornflowerblue = "#6495ed",
+    cornsilk = "#fff8dc",
+    crimson = "#dc143c",
+    cyan = "#00ffff",
+    darkblue = "#00008b",
+    darkcyan = "#008b8b",
+    darkgoldenrod = "#b8860b",
+    darkgray = "#a9a9a9",
+    darkgreen = "#006400",
+    darkgrey = "#a9a9a9",
+    darkkhaki = "#bdb76b",
+    darkmagenta = "#8b008b",
+    darkolivegreen = "#556b2f",
+    darkorange = "#ff8c00",
+    darkorchid = "#9932cc",
+    darkred = "#8b0000",
+    darksalmon = "#e9967a",
+    darkseagreen = "#8fbc8f",
+    darkslateblue = "#483d8b",
+    darkslategray = "#2f4f4f",
+    darkslategrey = "#2f4f4f",
+    darkturquoise = "#00ced1",
+    darkviolet = "#9400d3",
+    deeppink = "#ff1493",
+    deepskyblue = "#00bfff",
+    dimgray = "#696969",
+    dimgrey = "#696969",
+    dodgerblue = "#1e90ff",
+    firebrick = "#b22222",
+    floralwhite = "#fffaf0",
+    forestgreen = "#228b22",
+    fuchsia = "#ff00ff",
+    gainsboro = "#dcdcdc",
+    ghostwhite = "#f8f8ff",
+    gold = "#ffd700",
+    goldenrod = "#daa520",
+    gray = "#808080",
+    green = "#008000",
+    greenyellow = "#adff2f",
+    grey = "#808080",
+    honeydew = "#f0fff0",
+    hotpink = "#ff69b4",
+    indianred = "#cd5c5c",
+    indigo = "#4b0082",
+    ivory = "#fffff0",
+    khaki = "#f0e68c",
+    lavender = "#e6e6fa",
+    lavenderblush = "#fff0f5",
+    lawngreen = "#7cfc00",
+    lemonchiffon = "#fffacd",
+    lightblue = "#add8e6",
+    lightcoral = "#f08080",
+    lightcyan = "#e0ffff",
+    lightgoldenrodyellow = "#fafad2",
+    lightgray = "#d3d3d3",
+    lightgreen = "#90ee90",
+    lightgrey = "#d3d3d3",
+    lightpink = "#ffb6c1",
+    lightsalmon = "#ffa07a",
+    lightseagreen = "#20b2aa",
+    lightskyblue = "#87cefa",
+    lightslategray = "#778899",
+    lightslategrey = "#778899",
+    lightsteelblue = "#b0c4de",
+    lightyellow = "#ffffe0",
+    lime = "#00ff00",
+    limegreen = "#32cd32",
+    linen = "#faf0e6",
+    magenta = "#ff00ff",
+    maroon = "#800000",
+    mediumaquamarine = "#66cdaa",
+    mediumblue = "#0000cd",
+    mediumorchid = "#ba55d3",
+    mediumpurple = "#9370db",
+    mediumseagreen = "#3cb371",
+    mediumslateblue = "#7b68ee",
+    mediumspringgreen = "#00fa9a",
+    mediumturquoise = "#48d1cc",
+    mediumvioletred = "#c71585",
+    midnightblue = "#191970",
+    mintcream = "#f5fffa",
+    mistyrose = "#ffe4e1",
+    moccasin = "#ffe4b5",
+    navajowhite = "#ffdead",
+    navy = "#000080",
+    oldlace = "#fdf5e6",
+    olive = "#808000",
+    olivedrab = "#6b8e23",
+    orange = "#ffa500",
+    orangered = "#ff4500",
+    orchid = "#da70d6",
+    palegoldenrod = "#eee8aa",
+    palegreen = "#98fb98",
+    paleturquoise = "#afeeee",
+    palevioletred = "#db7093",
+    papayawhip = "#ffefd5",
+    peachpuff = "#ffdab9",
+    peru = "#cd853f",
+    pink = "#ffc0cb",
+    plum = "#dda0dd",
+    powderblue = "#b0e0e6",
+    purple = "#800080",
+    rebeccapurple = "#663399",
+    red = "#ff0000",
+    rosybrown = "#bc8f8f",
+    royalblue = "#4169e1",
+    saddlebrown = "#8b4513",
+    salmon = "#fa8072",
+    sandybrown = "#f4a460",
+    seagreen = "#2e8b57",
+    seashell = "#fff5ee",
+    sienna = "#a0522d",
+    silver = "#c0c0c0",
+    skyblue = "#87ceeb",
+    slateblue = "#6a5acd",
+    slategray = "#708090",
+    slategrey = "#708090",
+    snow = "#fffafa",
+    springgreen = "#00ff7f",
+    steelblue = "#4682b4",
+    tan = "#d2b48c",
+    teal = "#008080",
+    thistle = "#d8bfd8",
+    tomato = "#ff6347",
+    turquoise = "#40e0d0",
+    violet = "#ee82ee",
+    wheat = "#f5deb3",
+    white = "#ffffff",
+    whitesmoke = "#f5f5f5",
+    yellow = "#ffff00",
+    yellowgreen = "#9acd32",
+}
+
+-- Parses a hex code, like "#e0a040" or "#fa4", or a CSS color name into an
+-- sRGB color.
+_pyrite.parse_color = function(text)
+    local name = text:lower():gsub("%s", "")
+    local digits = (_pyrite.color_names[name] or name):match("^#(%x+)$")
+    if digits ~= nil and #digits == 3 then
+        digits = digits:gsub("%x", "%0%0")
+    end
+
+    if digits == nil or #digits ~= 6 then
+        error("unknown color: '" .. text .. "'", 2)
+    end
+
+    return rgb(
+        tonumber(digits:sub(1, 2), 16) / 255,
+        tonumber(digits:sub(3, 4), 16) / 255,
+        tonumber(digits:sub(5, 6), 16) / 255
+    )
+end
+
+function texture(path, encoding, properties)
+    if type(properties) ~= "table" then
+        properties = {filter = properties}
+    end
+
+    properties.type = "texture"
+    properties.path = path
+    properties.encoding = encoding or "srgb"
+    properties.filter = properties.filter or "bicubic"
+    properties.wrap = properties.wrap or "repeat"
+    properties.offset = properties.offset or 0
+    properties.scale = properties.scale or 1
+    properties.rotation = properties.rotation or 0
+    _pyrite.make_expression(properties)
+
+    return properties
+end
+
+function environment(path, properties)
+    properties = properties or {}
+    properties.type = "environment"
+    properties.path = path
+    _pyrite.make_expression(properties)
+
+    return properties
+end
+
+function volume(path, properties)
+    properties = properties or {}
+    properties.type = "volume"
+    properties.path = path
+    properties.format = properties.format or "u8"
     _pyrite.make_expression(properties)
 
     return properties
@@ -212,16 +463,56 @@ shape = {
         _pyrite.make_basic(properties)
         return properties
     end,
+    disk = function(properties)
+        properties.type = "disk"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    torus = function(properties)
+        properties.type = "torus"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    box = function(properties)
+        properties.type = "box"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
     mesh = function(properties)
         properties.type = "mesh"
         _pyrite.make_basic(properties)
         return properties
     end,
+    curve = function(properties)
+        properties.type = "curve"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    hair = function(properties)
+        properties.type = "hair"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    point_cloud = function(properties)
+        properties.type = "point_cloud"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
     ray_marched = function(properties)
         properties.type = "ray_marched"
         _pyrite.make_basic(properties)
         return properties
     end,
+    csg = function(properties)
+        properties.type = "csg"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    instance = function(properties)
+        properties.type = "instance"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
 
 ray_marched = {
@@ -235,6 +526,11 @@ ray_marched = {
         _pyrite.make_basic(properties)
         return properties
     end,
+    distance_field = function(properties)
+        properties.type = "distance_field"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
 
 quaternion_julia = {}
@@ -257,6 +553,13 @@ material = {
     end,
     emission = function(properties)
         properties.type = "emission"
+        if properties.profile ~= nil then
+            properties.color = properties.color * properties.profile
+            if properties.back_color ~= nil then
+                properties.back_color = properties.back_color * properties.profile
+            end
+            properties.profile = nil
+        end
         _pyrite.make_expression(properties)
         return properties
     end,
@@ -265,18 +568,266 @@ material = {
         _pyrite.make_expression(properties)
         return properties
     end,
+    glossy = function(properties)
+        properties.type = "glossy"
+        _pyrite.make_expression(properties)
+        return properties
+    end,
+    blinn_phong = function(properties)
+        properties.type = "blinn_phong"
+        _pyrite.make_expression(properties)
+        return properties
+    end,
     refractive = function(properties)
         properties.type = "refractive"
         _pyrite.make_expression(properties)
         return properties
     end,
+    measured = function(properties)
+        properties.type = "measured"
+        properties.color = properties.color or 1
+        _pyrite.make_expression(properties)
+        return properties
+    end,
+    fluorescent = function(properties)
+        properties.type = "fluorescent"
+        _pyrite.make_expression(properties)
+        return properties
+    end,
+    blend = function(properties)
+        return mix {lhs = properties.lhs, rhs = properties.rhs, amount = properties.factor}
+    end,
+    conductor = function(properties)
+        local color = conductor_fresnel(properties.ior.n, properties.ior.k)
+        if properties.color ~= nil then
+            color = properties.color * color
+        end
+
+        if properties.roughness ~= nil then
+            return material.glossy {
+                color = color,
+                roughness = properties.roughness,
+                bitangent_roughness = properties.bitangent_roughness,
+                rotation = properties.rotation,
+            }
+        else
+            return material.mirror {color = color}
+        end
+    end,
+    coated = function(properties)
+        local coat
+        if properties.roughness ~= nil then
+            coat = material.glossy {color = 1, roughness = properties.roughness}
+        else
+            coat = material.mirror {color = 1}
+        end
+
+        local base = properties.base
+        if properties.tint ~= nil then
+            base = _pyrite.tint_material(base, properties.tint)
+        end
+
+        return fresnel_mix {
+            reflect = coat,
+            refract = base,
+            ior = properties.ior or 1.5,
+            env_ior = properties.env_ior,
+        }
+    end,
+    principled = function(properties)
+        local base_color = properties.base_color or 0.8
+        local roughness = properties.roughness or 0.5
+
+        -- The specular amount is mapped to an index of refraction, where 0.5
+        -- is 4% reflectance at normal incidence.
+        local ior = properties.ior or 1.5
+        if properties.specular ~= nil then
+            local r0 = math.sqrt(0.08 * properties.specular)
+            ior = (1 + r0) / (1 - r0)
+        end
+
+        local function reflection(color)
+            if roughness == 0 then
+                return material.mirror {color = color}
+            else
+                return material.glossy {color = color, roughness = roughness}
+            end
+        end
+
+        local surface = fresnel_mix {
+            reflect = reflection(1),
+            refract = material.diffuse {color = base_color},
+            ior = ior,
+        }
+
+        local transmission = properties.transmission or 0
+        if transmission ~= 0 then
+            surface = mix {
+                lhs = surface,
+                rhs = material.refractive {color = base_color, ior = ior, roughness = roughness},
+                amount = transmission,
+            }
+        end
+
+        local metallic = properties.metallic or 0
+        if metallic ~= 0 then
+            surface = mix {lhs = surface, rhs = reflection(base_color), amount = metallic}
+        end
+
+        local clearcoat = properties.clearcoat or 0
+        if clearcoat ~= 0 then
+            surface = mix {
+                lhs = surface,
+                rhs = material.coated {
+                    base = surface,
+                    roughness = properties.clearcoat_roughness or 0.03,
+                },
+                amount = clearcoat,
+            }
+        end
+
+        -- Both halves are twice as bright, to make up for only picking one of
+        -- them at a time.
+        if properties.emission ~= nil then
+            surface = mix {
+                lhs = _pyrite.tint_material(surface, 2),
+                rhs = material.emission {
+                    color = properties.emission * (2 * (properties.emission_strength or 1)),
+                },
+                amount = 0.5,
+            }
+        end
+
+        return surface
+    end,
 }
 
+-- Multiplies the colors of a material and its parts with a tint.
+_pyrite.tint_material = function(surface, tint)
+    if surface.type == "mix" then
+        return surface:with {
+            lhs = _pyrite.tint_material(surface.lhs, tint),
+            rhs = _pyrite.tint_material(surface.rhs, tint),
+        }
+    elseif surface.type == "fresnel_mix" then
+        return surface:with {
+            reflect = _pyrite.tint_material(surface.reflect, tint),
+            refract = _pyrite.tint_material(surface.refract, tint),
+        }
+    else
+        return surface:with {color = tint * surface.color}
+    end
+end
+
+-- Approximates a material from an MTL file with a principled material. The
+-- colors are lists of red, green and blue, and the maps are file paths.
+_pyrite.mtl_material = function(mtl)
+    local function color(values, map)
+        if map ~= nil then
+            return texture(map)
+        elseif values ~= nil and math.max(values[1], values[2], values[3]) > 0 then
+            return rgb(values[1], values[2], values[3])
+        end
+    end
+
+    -- Illumination models 0 and 1 have no highlights, and 4, 6, 7 and 9 are
+    -- glass.
+    local illum = mtl.illum or 2
+    local glass = illum == 4 or illum == 6 or illum == 7 or illum == 9
+    local transmission = 1 - (mtl.d or 1)
+    if glass then
+        transmission = 1
+    end
+
+    local specular = 0
+    if mtl.ks ~= nil and illum > 1 then
+        specular = math.min(math.max(mtl.ks[1], mtl.ks[2], mtl.ks[3]), 1)
+    end
+
+    -- The roughness gives about as wide highlights as the Phong exponent.
+    local roughness = (2 / ((mtl.ns or 0) + 2)) ^ 0.25
+
+    local properties = {
+        surface = material.principled {
+            base_color = color(mtl.kd, mtl.map_kd) or 0.8,
+            roughness = roughness,
+            specular = transmission == 0 and specular or nil,
+            ior = mtl.ni ~= nil and mtl.ni > 1 and mtl.ni or nil,
+            transmission = transmission,
+            emission = color(mtl.ke, mtl.map_ke),
+        },
+    }
+
+    if mtl.map_bump ~= nil then
+        properties.bump = texture(mtl.map_bump, "linear") * (0.001 * mtl.bump_multiplier)
+    end
+
+    return properties
+end
+
 light_source = {}
+function conductor_fresnel(n, k)
+    local properties = {type = "conductor_fresnel", n = n, k = k}
+    _pyrite.make_expression(properties)
+
+    return properties
+end
+
+metal = {
+    load = function(path)
+        local n = {type = "metal", path = path, component = "n"}
+        _pyrite.make_expression(n)
+        local k = {type = "metal", path = path, component = "k"}
+        _pyrite.make_expression(k)
+
+        return {n = n, k = k}
+    end,
+}
+for _, name in ipairs({"gold", "copper", "aluminum"}) do
+    local n = {type = "metal", name = name, component = "n"}
+    _pyrite.make_expression(n)
+    local k = {type = "metal", name = name, component = "k"}
+    _pyrite.make_expression(k)
+
+    metal[name] = {n = n, k = k}
+end
+
+emission_profile = {
+    -- The points are pairs of an angle from the normal, in degrees, and a
+    -- value.
+    curve = function(points)
+        local properties = {type = "angle_profile", points = points}
+        _pyrite.make_expression(properties)
+
+        return properties
+    end,
+    -- Higher powers make a narrower beam, and 0 is the same as no profile.
+    cosine_power = function(power)
+        local points = {}
+        for angle = 0, 90 do
+            table.insert(points, {angle, math.cos(math.rad(angle)) ^ power})
+        end
+
+        return emission_profile.curve(points)
+    end,
+}
+
 light_source.d65 = {type = "light_source", name = "d65"}
 _pyrite.make_expression(light_source.d65)
 light_source.a = {type = "light_source", name = "a"}
 _pyrite.make_expression(light_source.a)
+light_source.d50 = {type = "light_source", name = "d50"}
+_pyrite.make_expression(light_source.d50)
+light_source.d55 = {type = "light_source", name = "d55"}
+_pyrite.make_expression(light_source.d55)
+light_source.d75 = {type = "light_source", name = "d75"}
+_pyrite.make_expression(light_source.d75)
+light_source.f2 = {type = "light_source", name = "f2"}
+_pyrite.make_expression(light_source.f2)
+light_source.f7 = {type = "light_source", name = "f7"}
+_pyrite.make_expression(light_source.f7)
+light_source.f11 = {type = "light_source", name = "f11"}
+_pyrite.make_expression(light_source.f11)
 
 transform = {
     look_at = function(properties)
@@ -284,6 +835,31 @@ transform = {
         _pyrite.make_basic(properties)
         return properties
     end,
+    keyframes = function(keyframes)
+        local properties = {type = "keyframes", keyframes = keyframes}
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    translate = function(offset)
+        local properties = {type = "translate", offset = offset}
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    rotate = function(properties)
+        properties.type = "rotate"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    scale = function(factor)
+        local properties = {type = "scale", factor = factor}
+        _pyrite.make_basic(properties)
+        return properties
+    end,
+    chain = function(transforms)
+        local properties = {type = "chain", transforms = transforms}
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
 
 camera = {
@@ -310,12 +886,237 @@ renderer = {
         _pyrite.make_basic(properties)
         return properties
     end,
+    preview = function(properties)
+        properties.type = "preview"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
 
 light = {
+    directional = function(properties)
+        properties.type = "directional_light"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
     point = function(properties)
         properties.type = "point_light"
         _pyrite.make_basic(properties)
         return properties
     end,
+    portal = function(properties)
+        properties.type = "portal"
+        _pyrite.make_basic(properties)
+        return properties
+    end,
 }
+
+-- Daylight
+
+-- The direction at an `azimuth`, clockwise from `north`, and an `elevation`
+-- above the horizon, both in radians.
+function _pyrite.horizontal_direction(azimuth, elevation, up, north)
+    up = up or {x = 0, y = 0, z = 1}
+    north = north or {x = 0, y = 1, z = 0}
+    local east = {
+        x = north.y * up.z - north.z * up.y,
+        y = north.z * up.x - north.x * up.z,
+        z = north.x * up.y - north.y * up.x,
+    }
+    local horizontal = math.cos(elevation)
+    local function component(axis)
+        return north[axis] * horizontal * math.cos(azimuth)
+            + east[axis] * horizontal * math.sin(azimuth)
+            + up[axis] * math.sin(elevation)
+    end
+
+    return vector(component("x"), component("y"), component("z"))
+end
+
+-- Finds the position of the sun from a `latitude` and `longitude`, in
+-- degrees, a `date` like "2024-06-21", a local `time` like "14:30" and a
+-- `timezone`, in hours from UTC. Uses the NOAA approximation, which is within
+-- a fraction of a degree. The result has the `azimuth`, in degrees clockwise
+-- from north, the `elevation`, in degrees above the horizon, and the
+-- `direction` towards the sun, where `up` and `north` are Z and Y by default.
+function sun_position(properties)
+    local year, month, day = string.match(properties.date or "", "^(%d+)-(%d+)-(%d+)$")
+    if year == nil then
+        error("sun_position: expected a date like \"2024-06-21\"")
+    end
+    year, month, day = tonumber(year), tonumber(month), tonumber(day)
+
+    local hour, minute, second = string.match(properties.time or "", "^(%d+):(%d+):?(%d*)$")
+    if hour == nil then
+        error("sun_position: expected a time like \"14:30\"")
+    end
+    hour = tonumber(hour) + tonumber(minute) / 60 + (tonumber(second) or 0) / 3600
+
+    local leap = (year % 4 == 0 and year % 100 ~= 0) or year % 400 == 0
+    local month_days = {31, leap and 29 or 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31}
+    local day_of_year = day
+    for index = 1, month - 1 do
+        day_of_year = day_of_year + month_days[index]
+    end
+
+    -- The fractional year, in radians.
+    local year_angle = 2 * math.pi / (leap and 366 or 365) * (day_of_year - 1 + (hour - 12) / 24)
+    local equation_of_time = 229.18 * (0.000075
+        + 0.001868 * math.cos(year_angle) - 0.032077 * math.sin(year_angle)
+        - 0.014615 * math.cos(2 * year_angle) - 0.040849 * math.sin(2 * year_angle))
+    local declination = 0.006918
+        - 0.399912 * math.cos(year_angle) + 0.070257 * math.sin(year_angle)
+        - 0.006758 * math.cos(2 * year_angle) + 0.000907 * math.sin(2 * year_angle)
+        - 0.002697 * math.cos(3 * year_angle) + 0.00148 * math.sin(3 * year_angle)
+
+    local solar_minutes = hour * 60 + equation_of_time
+        + 4 * (properties.longitude or 0) - 60 * (properties.timezone or 0)
+    local hour_angle = math.rad(solar_minutes / 4 - 180)
+    local latitude = math.rad(properties.latitude or 0)
+
+    local sin_elevation = math.sin(latitude) * math.sin(declination)
+        + math.cos(latitude) * math.cos(declination) * math.cos(hour_angle)
+    local elevation = math.asin(math.max(-1, math.min(1, sin_elevation)))
+    local azimuth = math.atan(
+        math.sin(hour_angle),
+        math.cos(hour_angle) * math.sin(latitude) - math.tan(declination) * math.cos(latitude)
+    ) + math.pi
+
+    return {
+        azimuth = math.deg(azimuth) % 360,
+        elevation = math.deg(elevation),
+        direction = _pyrite.horizontal_direction(azimuth, elevation, properties.up, properties.north),
+    }
+end
+
+-- A physically based sky and sun, from the Preetham daylight model. The sun
+-- is either a `direction` towards it, or an `azimuth` and `elevation` in
+-- degrees, like from `sun_position`. The `turbidity` is how hazy the air is,
+-- from 2 for a very clear day to 10 for a hazy one. The result has the `sky`
+-- and the `sun`, which is a directional light, and both are scaled by the
+-- optional `intensity`.
+function daylight(properties)
+    local direction = properties.direction
+    if direction == nil then
+        direction = _pyrite.horizontal_direction(
+            math.rad(properties.azimuth or 180),
+            math.rad(properties.elevation or 45),
+            properties.up,
+            properties.north
+        )
+    end
+
+    local turbidity = properties.turbidity or 3
+    if turbidity < 2 or turbidity > 10 then
+        error("daylight: the turbidity must be between 2 and 10")
+    end
+
+    local intensity = properties.intensity or 1
+    local sky = {type = "daylight_sky", sun = direction, turbidity = turbidity}
+    _pyrite.make_expression(sky)
+    local sunlight = {type = "sunlight", sun = direction, turbidity = turbidity}
+    _pyrite.make_expression(sunlight)
+
+    return {
+        sky = sky * intensity,
+        sun = light.directional {
+            direction = direction,
+            angular_diameter = 0.53,
+            color = sunlight * intensity,
+        },
+    }
+end
+
+-- Procedural generation
+
+generate = {}
+
+-- Collects the results of `make(index)` for each index from 1 to `count`.
+-- Returned lists are flattened into the result and nil is skipped, so `make`
+-- can return any number of objects.
+function generate.list(count, make)
+    local result = {}
+
+    for index = 1, count do
+        local item = make(index)
+
+        if type(item) == "table" and item.type == nil then
+            for _, inner in ipairs(item) do table.insert(result, inner) end
+        elseif item ~= nil then
+            table.insert(result, item)
+        end
+    end
+
+    return result
+end
+
+-- Joins lists of objects into one list.
+function generate.concat(...)
+    local result = {}
+
+    for _, list in ipairs({...}) do
+        for _, item in ipairs(list) do table.insert(result, item) end
+    end
+
+    return result
+end
+
+_pyrite.random_mt = {}
+_pyrite.random_mt.__index = _pyrite.random_mt
+
+-- A random number generator that gives the same numbers for the same seed,
+-- on all platforms.
+function generate.random(seed)
+    -- The seed is mixed with SplitMix64, to make similar seeds give different
+    -- sequences.
+    local state = math.floor(seed or 0) + 0x9E3779B97F4A7C15
+    state = (state ~ (state >> 30)) * 0xBF58476D1CE4E5B9
+    state = (state ~ (state >> 27)) * 0x94D049BB133111EB
+    state = state ~ (state >> 31)
+    if state == 0 then state = 1 end
+
+    return setmetatable({state = state}, _pyrite.random_mt)
+end
+
+-- A random number between `min` and `max`, or between 0 and 1.
+function _pyrite.random_mt:number(min, max)
+    -- Xorshift, with 53 bits of the state as the fraction.
+    local state = self.state
+    state = state ~ (state << 13)
+    state = state ~ (state >> 7)
+    state = state ~ (state << 17)
+    self.state = state
+
+    local fraction = (state >> 11) / 9007199254740992.0
+    min = min or 0.0
+    max = max or 1.0
+
+    return min + (max - min) * fraction
+end
+
+-- A random integer from `min` to `max`, including `max`.
+function _pyrite.random_mt:integer(min, max)
+    return math.min(math.floor(self:number(min, max + 1)), max)
+end
+
+-- A random vector between `min` and `max`, that can be numbers or vectors.
+function _pyrite.random_mt:vector(min, max)
+    local function component(value, name)
+        if type(value) == "table" then
+            return value[name] or 0.0
+        else
+            return value or 0.0
+        end
+    end
+
+    return vector(
+        self:number(component(min, "x"), component(max, "x")),
+        self:number(component(min, "y"), component(max, "y")),
+        self:number(component(min, "z"), component(max, "z"))
+    )
+end
+
+-- Picks a random item from a list.
+function _pyrite.random_mt:choose(list)
+    return list[self:integer(1, #list)]
+end
@@ -1,72 +1,481 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    borrow::Cow,
+    collections::HashMap,
     error::Error,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use genmesh::Polygon;
+use genmesh::{Polygon, Triangle};
 use obj::{GenPolygon, Group, IndexTuple, Obj, Object};
 
+/// The number of bits per axis that quantized vertex positions have.
+const VERTEX_BITS: u32 = 16;
+
 pub struct Meshes {
     meshes: Vec<Obj<'static, Polygon<IndexTuple>>>,
+    /// Vertex positions that have replaced the positions in the mesh, to save
+    /// memory.
+    quantized: Vec<Option<QuantizedPositions>>,
+    mtl_materials: Vec<HashMap<String, MtlMaterial>>,
+    files: HashMap<PathBuf, (MeshId, Option<SystemTime>)>,
+    hair: Vec<Hair>,
+    hair_files: HashMap<PathBuf, (HairId, Option<SystemTime>)>,
+    points: Vec<Points>,
+    point_files: HashMap<PathBuf, (PointsId, Option<SystemTime>)>,
 }
 
 impl Meshes {
-    fn new() -> Self {
-        Meshes { meshes: Vec::new() }
+    pub(crate) fn new() -> Self {
+        Meshes {
+            meshes: Vec::new(),
+            quantized: Vec::new(),
+            mtl_materials: Vec::new(),
+            files: HashMap::new(),
+            hair: Vec::new(),
+            hair_files: HashMap::new(),
+            points: Vec::new(),
+            point_files: HashMap::new(),
+        }
     }
 
-    fn insert(&mut self, mesh: Obj<'static, Polygon<IndexTuple>>) -> MeshId {
+    fn insert(
+        &mut self,
+        mesh: Obj<'static, Polygon<IndexTuple>>,
+        mtl_materials: HashMap<String, MtlMaterial>,
+    ) -> MeshId {
         let id = MeshId(self.meshes.len());
         self.meshes.push(mesh);
+        self.quantized.push(None);
+        self.mtl_materials.push(mtl_materials);
         id
     }
 
+    /// The mesh's faces, normals and texture coordinates. The positions are
+    /// read with `positions`, since they may be quantized.
     pub fn get(&self, id: MeshId) -> &Obj<'static, Polygon<IndexTuple>> {
         self.meshes.get(id.0).expect("missing mesh")
     }
+
+    pub fn positions(&self, id: MeshId) -> Positions<'_> {
+        match self.quantized.get(id.0).expect("missing mesh") {
+            Some(quantized) => Positions::Quantized(quantized),
+            None => Positions::Full(&self.get(id).position),
+        }
+    }
+
+    /// The materials from the mesh's MTL files that its faces use, by name.
+    pub fn mtl_materials(&self, id: MeshId) -> Vec<(&str, &MtlMaterial)> {
+        let mtl_materials = self.mtl_materials.get(id.0).expect("missing mesh");
+        let mut used: Vec<(&str, &MtlMaterial)> = Vec::new();
+
+        for group in self
+            .get(id)
+            .objects
+            .iter()
+            .flat_map(|object| &object.groups)
+        {
+            let name = match &group.material {
+                Some(material) => &*material.name,
+                None => continue,
+            };
+
+            if let Some(material) = mtl_materials.get(name) {
+                if used.iter().all(|&(used_name, _)| used_name != name) {
+                    used.push((name, material));
+                }
+            }
+        }
+
+        used
+    }
+
+    fn insert_hair(&mut self, hair: Hair) -> HairId {
+        let id = HairId(self.hair.len());
+        self.hair.push(hair);
+        id
+    }
+
+    pub fn get_hair(&self, id: HairId) -> &Hair {
+        self.hair.get(id.0).expect("missing hair")
+    }
+
+    fn insert_points(&mut self, points: Points) -> PointsId {
+        let id = PointsId(self.points.len());
+        self.points.push(points);
+        id
+    }
+
+    pub fn get_points(&self, id: PointsId) -> &Points {
+        self.points.get(id.0).expect("missing points")
+    }
+
+    /// An estimate of how much memory the loaded mesh data occupies.
+    pub fn memory_size(&self) -> usize {
+        use std::mem::size_of;
+
+        let hair: usize = self
+            .hair
+            .iter()
+            .flat_map(|hair| &hair.strands)
+            .map(|strand| strand.capacity() * size_of::<[f32; 3]>())
+            .sum();
+
+        let meshes: usize = self
+            .meshes
+            .iter()
+            .zip(&self.quantized)
+            .map(|(mesh, quantized)| {
+                let polygons: usize = mesh
+                    .objects
+                    .iter()
+                    .flat_map(|object| &object.groups)
+                    .map(|group| group.polys.capacity())
+                    .sum();
+
+                let quantized = quantized.as_ref().map_or(0, |quantized| {
+                    quantized.steps.capacity() * size_of::<[u16; 3]>()
+                });
+
+                mesh.position.capacity() * size_of::<[f32; 3]>()
+                    + quantized
+                    + mesh.texture.capacity() * size_of::<[f32; 2]>()
+                    + mesh.normal.capacity() * size_of::<[f32; 3]>()
+                    + polygons * size_of::<Polygon<IndexTuple>>()
+            })
+            .sum();
+
+        let points: usize = self
+            .points
+            .iter()
+            .map(|points| points.positions.capacity() * size_of::<[f32; 3]>())
+            .sum();
+
+        meshes + hair + points
+    }
+
+    /// The number of triangles in a mesh.
+    pub fn triangle_count(&self, id: MeshId) -> usize {
+        self.get(id)
+            .objects
+            .iter()
+            .flat_map(|object| &object.groups)
+            .flat_map(|group| &group.polys)
+            .filter(|polygon| match polygon {
+                Polygon::PolyTri(_) => true,
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Replaces the vertex positions of each mesh with 16 bit integers, that
+    /// are steps along the sides of its bounding box. Triangles that collapse
+    /// into lines or points are removed. Meshes are only quantized once, and
+    /// the number of newly quantized meshes and removed triangles is
+    /// returned.
+    pub fn quantize(&mut self) -> (usize, usize) {
+        let mut quantized_meshes = 0;
+        let mut removed = 0;
+
+        for (mesh, quantized) in self.meshes.iter_mut().zip(&mut self.quantized) {
+            if quantized.is_some() || mesh.position.is_empty() {
+                continue;
+            }
+
+            let positions = QuantizedPositions::new(&mesh.position);
+            mesh.position = Vec::new();
+            quantized_meshes += 1;
+
+            for group in mesh
+                .objects
+                .iter_mut()
+                .flat_map(|object| &mut object.groups)
+            {
+                let count = group.polys.len();
+                group.polys.retain(|polygon| match *polygon {
+                    Polygon::PolyTri(Triangle {
+                        x: IndexTuple(x, _, _),
+                        y: IndexTuple(y, _, _),
+                        z: IndexTuple(z, _, _),
+                    }) => {
+                        let steps = &positions.steps;
+                        steps[x] != steps[y] && steps[y] != steps[z] && steps[z] != steps[x]
+                    }
+                    _ => true,
+                });
+                group.polys.shrink_to_fit();
+                removed += count - group.polys.len();
+            }
+
+            *quantized = Some(positions);
+        }
+
+        (quantized_meshes, removed)
+    }
+}
+
+/// The vertex positions of a mesh.
+#[derive(Copy, Clone)]
+pub enum Positions<'a> {
+    Full(&'a [[f32; 3]]),
+    Quantized(&'a QuantizedPositions),
+}
+
+impl<'a> Positions<'a> {
+    pub fn len(self) -> usize {
+        match self {
+            Positions::Full(positions) => positions.len(),
+            Positions::Quantized(positions) => positions.steps.len(),
+        }
+    }
+
+    pub fn get(self, index: usize) -> [f32; 3] {
+        match self {
+            Positions::Full(positions) => positions[index],
+            Positions::Quantized(positions) => positions.get(index),
+        }
+    }
+}
+
+/// Vertex positions that are snapped to a grid, where each position is
+/// `offset + step * scale`.
+pub struct QuantizedPositions {
+    offset: [f32; 3],
+    scale: [f32; 3],
+    steps: Vec<[u16; 3]>,
+}
+
+impl QuantizedPositions {
+    fn new(positions: &[[f32; 3]]) -> Self {
+        let max_step = ((1u32 << VERTEX_BITS) - 1) as f32;
+
+        let mut min = [std::f32::INFINITY; 3];
+        let mut max = [std::f32::NEG_INFINITY; 3];
+        for position in positions {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+            }
+        }
+
+        let mut scale = [0.0; 3];
+        for axis in 0..3 {
+            scale[axis] = (max[axis] - min[axis]) / max_step;
+        }
+
+        let steps = positions
+            .iter()
+            .map(|position| {
+                let mut step = [0; 3];
+                for axis in 0..3 {
+                    if scale[axis] > 0.0 {
+                        step[axis] = ((position[axis] - min[axis]) / scale[axis])
+                            .round()
+                            .max(0.0)
+                            .min(max_step) as u16;
+                    }
+                }
+                step
+            })
+            .collect();
+
+        QuantizedPositions {
+            offset: min,
+            scale,
+            steps,
+        }
+    }
+
+    fn get(&self, index: usize) -> [f32; 3] {
+        let step = self.steps[index];
+        let mut position = self.offset;
+        for axis in 0..3 {
+            position[axis] += step[axis] as f32 * self.scale[axis];
+        }
+        position
+    }
 }
 
 pub struct MeshLoader {
     meshes: Meshes,
-    file_map: HashMap<PathBuf, MeshId>,
+    /// The files from the previous load. Those that aren't loaded again are
+    /// removed from the meshes.
+    previous_files: HashMap<PathBuf, (MeshId, Option<SystemTime>)>,
+    previous_hair_files: HashMap<PathBuf, (HairId, Option<SystemTime>)>,
+    previous_point_files: HashMap<PathBuf, (PointsId, Option<SystemTime>)>,
     project_dir: PathBuf,
 }
 
 impl MeshLoader {
-    pub fn new(path: impl AsRef<Path>) -> Self {
+    /// Creates a loader that reuses the already loaded meshes, unless their
+    /// files have been modified since they were loaded.
+    pub fn new(path: impl AsRef<Path>, mut meshes: Meshes) -> Self {
         let project_dir = path.as_ref().into();
 
         MeshLoader {
-            meshes: Meshes::new(),
-            file_map: HashMap::new(),
+            previous_files: std::mem::take(&mut meshes.files),
+            previous_hair_files: std::mem::take(&mut meshes.hair_files),
+            previous_point_files: std::mem::take(&mut meshes.point_files),
+            meshes,
             project_dir,
         }
     }
 
     pub fn load(&mut self, path: impl AsRef<Path>) -> Result<MeshId, Box<dyn Error>> {
         let path = self.project_dir.join(path).canonicalize()?;
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if let Some(id) = find_loaded(
+            &mut self.meshes.files,
+            &self.previous_files,
+            &path,
+            modified,
+        ) {
+            return Ok(id);
+        }
+
+        let mesh = Obj::load(&path)
+            .map_err(|error| format!("could not load {}: {}", path.display(), error))?;
+        let mesh = own_materials(mesh);
 
-        match self.file_map.entry(path) {
-            Entry::Occupied(entry) => Ok(*entry.get()),
-            Entry::Vacant(entry) => {
-                let mesh = Obj::load(entry.key()).map_err(|error| {
-                    format!("could not load {}: {}", entry.key().display(), error)
-                })?;
-                let mesh = remove_materials(mesh);
-                let id = self.meshes.insert(mesh);
-                entry.insert(id);
-                Ok(id)
+        // Missing material libraries are skipped, so their materials have to
+        // be in the project instead.
+        let mut mtl_materials = HashMap::new();
+        for library in &mesh.material_libs {
+            let library_path = mesh.path.join(library);
+            if let Ok(content) = std::fs::read_to_string(&library_path) {
+                let library =
+                    MtlMaterial::parse_library(&content, &mesh.path).map_err(|error| {
+                        format!("could not load {}: {}", library_path.display(), error)
+                    })?;
+                mtl_materials.extend(library);
             }
         }
+
+        let id = self.meshes.insert(mesh, mtl_materials);
+        self.meshes.files.insert(path, (id, modified));
+        Ok(id)
+    }
+
+    pub fn mtl_materials(&self, id: MeshId) -> Vec<(&str, &MtlMaterial)> {
+        self.meshes.mtl_materials(id)
     }
 
+    /// Loads a hair file, that is reused in the same way as meshes.
+    pub fn load_hair(&mut self, path: impl AsRef<Path>) -> Result<HairId, Box<dyn Error>> {
+        let path = self.project_dir.join(path).canonicalize()?;
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if let Some(id) = find_loaded(
+            &mut self.meshes.hair_files,
+            &self.previous_hair_files,
+            &path,
+            modified,
+        ) {
+            return Ok(id);
+        }
+
+        let hair = std::fs::read_to_string(&path)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|content| Hair::parse(&content))
+            .map_err(|error| format!("could not load {}: {}", path.display(), error))?;
+        let id = self.meshes.insert_hair(hair);
+        self.meshes.hair_files.insert(path, (id, modified));
+        Ok(id)
+    }
+
+    /// Loads a point cloud file, that is reused in the same way as meshes.
+    pub fn load_points(&mut self, path: impl AsRef<Path>) -> Result<PointsId, Box<dyn Error>> {
+        let path = self.project_dir.join(path).canonicalize()?;
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if let Some(id) = find_loaded(
+            &mut self.meshes.point_files,
+            &self.previous_point_files,
+            &path,
+            modified,
+        ) {
+            return Ok(id);
+        }
+
+        let points = std::fs::read_to_string(&path)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|content| Points::parse(&content))
+            .map_err(|error| format!("could not load {}: {}", path.display(), error))?;
+        let id = self.meshes.insert_points(points);
+        self.meshes.point_files.insert(path, (id, modified));
+        Ok(id)
+    }
+
+    /// Returns the loaded meshes, where the meshes that are no longer used
+    /// have been emptied.
     pub fn into_meshes(self) -> Meshes {
-        self.meshes
+        let mut meshes = self.meshes;
+
+        for (path, (id, _)) in self.previous_files {
+            if meshes.files.get(&path).map(|&(id, _)| id) != Some(id) {
+                meshes.meshes[id.0] = Obj {
+                    position: Vec::new(),
+                    texture: Vec::new(),
+                    normal: Vec::new(),
+                    objects: Vec::new(),
+                    material_libs: Vec::new(),
+                    path: PathBuf::new(),
+                };
+                meshes.quantized[id.0] = None;
+                meshes.mtl_materials[id.0] = HashMap::new();
+            }
+        }
+
+        for (path, (id, _)) in self.previous_hair_files {
+            if meshes.hair_files.get(&path).map(|&(id, _)| id) != Some(id) {
+                meshes.hair[id.0].strands = Vec::new();
+            }
+        }
+
+        for (path, (id, _)) in self.previous_point_files {
+            if meshes.point_files.get(&path).map(|&(id, _)| id) != Some(id) {
+                meshes.points[id.0].positions = Vec::new();
+            }
+        }
+
+        meshes
     }
 }
 
-fn remove_materials<'a, T: GenPolygon>(obj: Obj<'a, T>) -> Obj<'static, T> {
+/// Finds a file that has already been loaded, either during this load or the
+/// previous one, unless it has been modified since then. Files from the
+/// previous load are moved into `files`.
+fn find_loaded<T: Copy>(
+    files: &mut HashMap<PathBuf, (T, Option<SystemTime>)>,
+    previous_files: &HashMap<PathBuf, (T, Option<SystemTime>)>,
+    path: &Path,
+    modified: Option<SystemTime>,
+) -> Option<T> {
+    if let Some(&(id, loaded_modified)) = files.get(path) {
+        if loaded_modified == modified {
+            return Some(id);
+        }
+    }
+
+    match previous_files.get(path) {
+        Some(&(id, loaded_modified)) if loaded_modified == modified => {
+            files.insert(path.into(), (id, modified));
+            Some(id)
+        }
+        _ => None,
+    }
+}
+
+/// Copies the materials from `usemtl` into the groups, so the mesh doesn't
+/// borrow from the loader.
+fn own_materials<'a, T: GenPolygon>(obj: Obj<'a, T>) -> Obj<'static, T> {
     let Obj {
         position,
         texture,
@@ -90,12 +499,16 @@ fn remove_materials<'a, T: GenPolygon>(obj: Obj<'a, T>) -> Obj<'static, T> {
                         .into_iter()
                         .map(|group| {
                             let Group {
-                                name, index, polys, ..
+                                name,
+                                index,
+                                material,
+                                polys,
                             } = group;
                             Group {
                                 name,
                                 index,
-                                material: None,
+                                material: material
+                                    .map(|material| Cow::Owned(material.into_owned())),
                                 polys,
                             }
                         })
@@ -111,3 +524,274 @@ fn remove_materials<'a, T: GenPolygon>(obj: Obj<'a, T>) -> Obj<'static, T> {
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[repr(transparent)]
 pub struct MeshId(usize);
+
+/// The parts of a material from an MTL file that are used for approximating
+/// it. Texture maps are only included if their files exist.
+#[derive(Default)]
+pub struct MtlMaterial {
+    pub kd: Option<[f32; 3]>,
+    pub ks: Option<[f32; 3]>,
+    pub ke: Option<[f32; 3]>,
+    pub ns: Option<f32>,
+    pub ni: Option<f32>,
+    /// The opacity, from either `d` or `Tr`.
+    pub d: Option<f32>,
+    pub illum: Option<u32>,
+    pub map_kd: Option<PathBuf>,
+    pub map_ke: Option<PathBuf>,
+    /// The bump map and its `-bm` multiplier.
+    pub map_bump: Option<(PathBuf, f32)>,
+}
+
+impl MtlMaterial {
+    /// Parses the materials in an MTL file, where texture paths are relative
+    /// to `directory`. Statements that aren't used are skipped.
+    fn parse_library(
+        content: &str,
+        directory: &Path,
+    ) -> Result<HashMap<String, Self>, Box<dyn Error>> {
+        let mut materials = HashMap::new();
+        let mut current: Option<(String, MtlMaterial)> = None;
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            let mut words = line.split_whitespace();
+            let statement = match words.next() {
+                Some(statement) if !statement.starts_with('#') => statement,
+                _ => continue,
+            };
+
+            if statement == "newmtl" {
+                materials.extend(current.take());
+
+                let name = line[statement.len()..].trim();
+                if name.is_empty() {
+                    return Err(format!("line {}: missing material name", index + 1).into());
+                }
+                current = Some((name.to_owned(), MtlMaterial::default()));
+                continue;
+            }
+
+            let material = match &mut current {
+                Some((_, material)) => material,
+                None => continue,
+            };
+
+            let result = match statement {
+                "Kd" => parse_mtl_color(words).map(|color| material.kd = color),
+                "Ks" => parse_mtl_color(words).map(|color| material.ks = color),
+                "Ke" => parse_mtl_color(words).map(|color| material.ke = color),
+                "Ns" => parse_mtl_number(words).map(|value| material.ns = Some(value)),
+                "Ni" => parse_mtl_number(words).map(|value| material.ni = Some(value)),
+                "d" => parse_mtl_number(words.filter(|&word| word != "-halo"))
+                    .map(|value| material.d = Some(value)),
+                "Tr" => parse_mtl_number(words).map(|value| material.d = Some(1.0 - value)),
+                "illum" => parse_mtl_number(words).map(|value| material.illum = Some(value as u32)),
+                "map_Kd" => parse_mtl_map(words, directory).map(|(map, _)| material.map_kd = map),
+                "map_Ke" => parse_mtl_map(words, directory).map(|(map, _)| material.map_ke = map),
+                "map_Bump" | "map_bump" | "bump" => parse_mtl_map(words, directory)
+                    .map(|(map, multiplier)| material.map_bump = map.map(|map| (map, multiplier))),
+                _ => Ok(()),
+            };
+
+            result.map_err(|error| format!("line {}: {}: {}", index + 1, statement, error))?;
+        }
+
+        materials.extend(current);
+        Ok(materials)
+    }
+}
+
+fn parse_mtl_number<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<f32, Box<dyn Error>> {
+    Ok(words.next().ok_or("missing value")?.parse()?)
+}
+
+/// Parses an `r g b` color, where `g` and `b` default to `r`. Spectral and
+/// XYZ colors are skipped.
+fn parse_mtl_color<'a>(
+    mut words: impl Iterator<Item = &'a str>,
+) -> Result<Option<[f32; 3]>, Box<dyn Error>> {
+    let red: f32 = match words.next() {
+        Some("spectral") | Some("xyz") => return Ok(None),
+        Some(word) => word.parse()?,
+        None => return Err("missing color".into()),
+    };
+    let green = words.next().map(str::parse).transpose()?.unwrap_or(red);
+    let blue = words.next().map(str::parse).transpose()?.unwrap_or(green);
+
+    Ok(Some([red, green, blue]))
+}
+
+/// Parses a texture map statement, and returns the path of the file, if it
+/// exists, and the `-bm` multiplier. Other options are skipped.
+fn parse_mtl_map<'a>(
+    words: impl Iterator<Item = &'a str>,
+    directory: &Path,
+) -> Result<(Option<PathBuf>, f32), Box<dyn Error>> {
+    let mut words = words.peekable();
+    let mut multiplier = 1.0;
+
+    while let Some(&option) = words.peek() {
+        match option {
+            "-bm" => {
+                words.next();
+                multiplier = parse_mtl_number(&mut words)?;
+            }
+            "-blendu" | "-blendv" | "-boost" | "-cc" | "-clamp" | "-imfchan" | "-texres"
+            | "-type" => {
+                words.nth(1);
+            }
+            "-mm" => {
+                words.nth(2);
+            }
+            // Offsets, scales and turbulence have one to three numbers.
+            "-o" | "-s" | "-t" => {
+                words.next();
+                for _ in 0..3 {
+                    if words
+                        .peek()
+                        .map_or(false, |word| word.parse::<f32>().is_ok())
+                    {
+                        words.next();
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+
+    let file = words.collect::<Vec<_>>().join(" ");
+    if file.is_empty() {
+        return Err("missing file name".into());
+    }
+
+    let path = directory.join(file.replace('\\', "/"));
+    Ok((if path.is_file() { Some(path) } else { None }, multiplier))
+}
+
+/// Hair strands, where each strand is a list of points from the root to the
+/// tip.
+pub struct Hair {
+    pub strands: Vec<Vec<[f32; 3]>>,
+}
+
+impl Hair {
+    /// Parses a text file with one strand per line, written as a list of
+    /// `x y z` coordinates. Empty lines and lines starting with `#` are
+    /// skipped.
+    fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let mut strands = Vec::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let numbers = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|error| format!("line {}: {}", index + 1, error))?;
+
+            if numbers.len() % 3 != 0 {
+                return Err(format!(
+                    "line {}: expected a list of x y z coordinates, got {} numbers",
+                    index + 1,
+                    numbers.len()
+                )
+                .into());
+            }
+            if numbers.len() < 6 {
+                return Err(
+                    format!("line {}: a strand needs at least two points", index + 1).into(),
+                );
+            }
+
+            strands.push(
+                numbers
+                    .chunks(3)
+                    .map(|point| [point[0], point[1], point[2]])
+                    .collect(),
+            );
+        }
+
+        Ok(Hair { strands })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct HairId(usize);
+
+/// The positions of the points in a point cloud.
+pub struct Points {
+    pub positions: Vec<[f32; 3]>,
+}
+
+impl Points {
+    /// Parses a text file with one point per line, where the first three
+    /// numbers are the `x y z` coordinates. The numbers may be separated by
+    /// spaces or commas, and any other numbers, like colors, are ignored.
+    /// Empty lines and lines starting with `#` are skipped.
+    fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let mut positions = Vec::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut numbers = line
+                .split(|character: char| character == ',' || character.is_whitespace())
+                .filter(|number| !number.is_empty())
+                .take(3)
+                .map(str::parse::<f32>);
+
+            let mut next = || match numbers.next() {
+                Some(Ok(number)) => Ok(number),
+                Some(Err(error)) => Err(format!("line {}: {}", index + 1, error)),
+                None => Err(format!("line {}: expected x y z coordinates", index + 1)),
+            };
+
+            positions.push([next()?, next()?, next()?]);
+        }
+
+        Ok(Points { positions })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct PointsId(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::{MeshLoader, Meshes};
+
+    #[test]
+    fn unused_meshes_are_removed() {
+        let directory =
+            std::env::temp_dir().join(format!("pyrite-mesh-reuse-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let triangle = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+        std::fs::write(directory.join("a.obj"), triangle).unwrap();
+        std::fs::write(directory.join("b.obj"), triangle).unwrap();
+
+        let mut loader = MeshLoader::new(&directory, Meshes::new());
+        let a = loader.load("a.obj").unwrap();
+        let b = loader.load("b.obj").unwrap();
+        let meshes = loader.into_meshes();
+
+        let mut loader = MeshLoader::new(&directory, meshes);
+        assert_eq!(loader.load("a.obj").unwrap(), a);
+        let meshes = loader.into_meshes();
+
+        assert_eq!(meshes.files.len(), 1);
+        assert_eq!(meshes.triangle_count(a), 1);
+        assert_eq!(meshes.triangle_count(b), 0);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
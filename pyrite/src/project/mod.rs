@@ -1,8 +1,12 @@
-use std::{collections::HashMap, error::Error, path::Path};
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+};
 
 use rlua::{FromLua, Lua};
 
-use cgmath::{Matrix4, SquareMatrix, Vector3};
+use cgmath::{Deg, InnerSpace, Matrix3, Matrix4, Quaternion, SquareMatrix, Vector3, VectorSpace};
 
 use path_slash::PathBufExt;
 
@@ -10,11 +14,12 @@ use crate::parse_enum;
 
 use eval_context::{EvalContext, Evaluate};
 use expressions::{ExpressionLoader, Expressions};
-use meshes::{MeshId, MeshLoader, Meshes};
+use meshes::{HairId, MeshId, MeshLoader, Meshes, PointsId};
 use parse_context::{Parse, ParseContext};
 use spectra::{Spectra, SpectrumLoader};
 use tables::Tables;
-use textures::{TextureLoader, Textures};
+use textures::{TextureCache, TextureLoader, Textures};
+use units::{Quantity, Units};
 
 pub mod eval_context;
 pub mod expressions;
@@ -23,9 +28,27 @@ mod parse_context;
 pub mod program;
 pub mod spectra;
 mod tables;
-mod textures;
+pub mod textures;
+mod units;
+
+/// A list of plugin files, or directories with plugin files, that are run
+/// after the project building library and before the project. They are Lua
+/// files that extend the library, and can't add new kinds of materials or
+/// shapes.
+const PLUGINS_VARIABLE: &str = "PYRITE_PLUGINS";
 
 pub fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn Error>> {
+    reload_project(path, Meshes::new(), &mut TextureCache::new(0))
+}
+
+/// Loads the project again, while reusing meshes and cached textures that
+/// haven't changed since the last time. The cache is refilled with the
+/// textures of the new project.
+pub fn reload_project<P: AsRef<Path>>(
+    path: P,
+    meshes: Meshes,
+    texture_cache: &mut TextureCache,
+) -> Result<ProjectData, Box<dyn Error>> {
     let project_dir = path
         .as_ref()
         .parent()
@@ -59,6 +82,17 @@ pub fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn
             .set_name("<pyrite>/lib.lua")?
             .exec()?;
 
+        // Run plugins, that may add to the library
+        for plugin in plugin_files()? {
+            let source = std::fs::read_to_string(&plugin).map_err(|error| {
+                format!("could not read plugin {}: {}", plugin.display(), error)
+            })?;
+            context
+                .load(&source)
+                .set_name(&*plugin.to_string_lossy())?
+                .exec()?;
+        }
+
         // Run project file
         let project_file = std::fs::read_to_string(&path)?;
         let project = context
@@ -73,16 +107,19 @@ pub fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn
 
         // Parse project config
         let mut expressions = ExpressionLoader::new();
-        let mut meshes = MeshLoader::new(project_dir);
-        let mut spectra = SpectrumLoader::new();
-        let mut textures = TextureLoader::new(project_dir);
+        let mut meshes = MeshLoader::new(project_dir, meshes);
+        let mut spectra = SpectrumLoader::new(project_dir);
+        let mut textures = TextureLoader::new(project_dir, texture_cache.take());
+        let project = rlua::Table::from_lua(project, context.clone())?;
+        let units = Units::from_project(&project)?;
         let parse_context = ParseContext::new(
             &mut expressions,
             &mut meshes,
             &mut spectra,
             &mut textures,
             &tables,
-            rlua::Table::from_lua(project, context.clone())?,
+            units,
+            project,
             &context,
         );
         let project = parse_context.parse()?;
@@ -93,6 +130,7 @@ pub fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn
                 &mut spectra,
                 &mut textures,
                 &tables,
+                units,
                 table,
                 &context,
             )
@@ -103,7 +141,8 @@ pub fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn
         let expressions = expressions.into_expressions();
         let meshes = meshes.into_meshes();
         let spectra = spectra.into_spectra();
-        let textures = textures.into_textures();
+        let (textures, cache) = textures.into_textures();
+        *texture_cache = cache;
 
         Ok(ProjectData {
             expressions,
@@ -115,6 +154,40 @@ pub fn load_project<'p, P: AsRef<Path>>(path: P) -> Result<ProjectData, Box<dyn
     })
 }
 
+/// Lists the plugin files from `PYRITE_PLUGINS`. Directories are expanded to
+/// their `.lua` files, in alphabetical order.
+fn plugin_files() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let paths = match std::env::var_os(PLUGINS_VARIABLE) {
+        Some(paths) => paths,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut files = Vec::new();
+
+    for path in std::env::split_paths(&paths) {
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        if path.is_dir() {
+            let mut directory_files = std::fs::read_dir(&path)
+                .map_err(|error| format!("{}: {}: {}", PLUGINS_VARIABLE, path.display(), error))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| {
+                    file.extension()
+                        .map_or(false, |extension| extension == "lua")
+                })
+                .collect::<Vec<_>>();
+            directory_files.sort();
+            files.extend(directory_files);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
 pub struct ProjectData {
     pub expressions: Expressions,
     pub meshes: Meshes,
@@ -147,8 +220,10 @@ pub struct Image {
     pub width: u32,
     pub height: u32,
     pub file: Option<String>,
+    pub frame_rate: Option<f32>,
     pub filter: Option<expressions::Expression>,
     pub white: Option<expressions::Expression>,
+    pub holdout: Option<bool>,
 }
 
 impl<'lua> Parse<'lua> for Image {
@@ -159,8 +234,10 @@ impl<'lua> Parse<'lua> for Image {
             width: context.expect_field("width")?,
             height: context.expect_field("height")?,
             file: context.expect_field("file")?,
+            frame_rate: context.expect_field("frame_rate")?,
             filter: context.parse_field("filter")?,
             white: context.parse_field("white")?,
+            holdout: context.expect_field("holdout")?,
         })
     }
 }
@@ -171,6 +248,7 @@ pub enum Camera {
         fov: self::expressions::Expression,
         focus_distance: Option<self::expressions::Expression>,
         aperture: Option<self::expressions::Expression>,
+        squeeze: Option<self::expressions::Expression>,
     },
 }
 
@@ -181,9 +259,10 @@ impl<'lua> Parse<'lua> for Camera {
         parse_enum!(context {
             "perspective" => Ok(Camera::Perspective {
                 transform: context.parse_field("transform")?,
-                fov: context.parse_field("fov")?,
-                focus_distance: context.parse_field("focus_distance")?,
-                aperture: context.parse_field("aperture")?,
+                fov: context.parse_quantity_field("fov", Quantity::Angle)?,
+                focus_distance: context.parse_quantity_field("focus_distance", Quantity::Length)?,
+                aperture: context.parse_quantity_field("aperture", Quantity::Length)?,
+                squeeze: context.parse_field("squeeze")?,
             }),
         })
     }
@@ -192,6 +271,7 @@ impl<'lua> Parse<'lua> for Camera {
 pub enum Renderer {
     Simple {
         shared: RendererShared,
+        max_spectrum_samples: Option<u32>,
     },
     Bidirectional {
         shared: RendererShared,
@@ -204,6 +284,31 @@ pub enum Renderer {
         photons: Option<usize>,
         photon_passes: Option<usize>,
     },
+    Preview {
+        shared: RendererShared,
+        ambient_occlusion: Option<f32>,
+        spherical_harmonics: Option<bool>,
+    },
+}
+
+impl Renderer {
+    pub fn shared(&self) -> &RendererShared {
+        match self {
+            Renderer::Simple { shared, .. }
+            | Renderer::Bidirectional { shared, .. }
+            | Renderer::PhotonMapping { shared, .. }
+            | Renderer::Preview { shared, .. } => shared,
+        }
+    }
+
+    pub fn shared_mut(&mut self) -> &mut RendererShared {
+        match self {
+            Renderer::Simple { shared, .. }
+            | Renderer::Bidirectional { shared, .. }
+            | Renderer::PhotonMapping { shared, .. }
+            | Renderer::Preview { shared, .. } => shared,
+        }
+    }
 }
 
 impl<'lua> Parse<'lua> for Renderer {
@@ -215,6 +320,7 @@ impl<'lua> Parse<'lua> for Renderer {
         parse_enum!(context {
             "simple" => Ok(Renderer::Simple {
                 shared,
+                max_spectrum_samples: context.expect_field("max_spectrum_samples")?,
             }),
             "bidirectional" => Ok(Renderer::Bidirectional {
                 shared,
@@ -226,6 +332,11 @@ impl<'lua> Parse<'lua> for Renderer {
                 photon_bounces: context.expect_field("photon_bounces")?,
                 photons: context.expect_field("photons")?,
                 photon_passes: context.expect_field("photon_passes")?,
+            }),
+            "preview" => Ok(Renderer::Preview {
+                shared,
+                ambient_occlusion: context.expect_field("ambient_occlusion")?,
+                spherical_harmonics: context.expect_field("spherical_harmonics")?,
             })
         })
     }
@@ -234,11 +345,14 @@ impl<'lua> Parse<'lua> for Renderer {
 pub struct RendererShared {
     pub threads: Option<usize>,
     pub bounces: Option<u32>,
-    pub pixel_samples: u32,
+    /// The total number of samples per pixel after each pass.
+    pub pixel_samples: Vec<u32>,
     pub light_samples: Option<usize>,
     pub spectrum_samples: Option<u32>,
     pub spectrum_resolution: Option<usize>,
-    pub tile_size: Option<usize>,
+    pub tile_size: Option<TileSize>,
+    pub throughput_cutoff: Option<f32>,
+    pub max_distance: Option<f32>,
 }
 
 impl RendererShared {
@@ -248,18 +362,84 @@ impl RendererShared {
         Ok(RendererShared {
             threads: context.expect_field("threads")?,
             bounces: context.expect_field("bounces")?,
-            pixel_samples: context.expect_field("pixel_samples")?,
+            pixel_samples: context.parse_field::<SampleSchedule>("pixel_samples")?.0,
             light_samples: context.expect_field("light_samples")?,
             spectrum_samples: context.expect_field("spectrum_samples")?,
             spectrum_resolution: context.expect_field("spectrum_resolution")?,
-            tile_size: context.expect_field("tile_size")?,
+            tile_size: context.parse_field("tile_size")?,
+            throughput_cutoff: context.expect_field("throughput_cutoff")?,
+            max_distance: context.expect_field("max_distance")?,
         })
     }
 }
 
+/// Either a single number of samples per pixel, or a list of increasing
+/// numbers, where the image is saved after reaching each of them.
+struct SampleSchedule(Vec<u32>);
+
+impl<'lua> Parse<'lua> for SampleSchedule {
+    type Input = rlua::Value<'lua>;
+
+    fn parse<'a>(context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+        if let Ok(samples) = context.expect_number() {
+            return Ok(SampleSchedule(vec![samples as u32]));
+        }
+
+        let schedule = context
+            .expect_table()
+            .map_err(|_| "expected a number or a list of numbers")?
+            .sequence_values::<u32>()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if schedule.is_empty() {
+            return Err("the list of sample counts is empty".into());
+        }
+
+        if schedule.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("the sample counts have to increase".into());
+        }
+
+        Ok(SampleSchedule(schedule))
+    }
+}
+
+/// The width and height of the tiles that the image is split into.
+pub enum TileSize {
+    Fixed(usize),
+    /// Tries a few sizes when the render starts, and keeps the fastest.
+    Auto,
+}
+
+impl<'lua> Parse<'lua> for TileSize {
+    type Input = rlua::Value<'lua>;
+
+    fn parse<'a>(context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+        if let Ok(size) = context.expect_number() {
+            if size < 1.0 {
+                return Err("the tile size has to be at least 1".into());
+            }
+
+            return Ok(TileSize::Fixed(size as usize));
+        }
+
+        match context.value() {
+            rlua::Value::String(name) if name.to_str()? == "auto" => Ok(TileSize::Auto),
+            value => Err(format!("expected a number or \"auto\" but found {:?}", value).into()),
+        }
+    }
+}
+
 pub struct World {
     pub sky: Option<self::expressions::Expression>,
+    /// The medium that surrounds everything, such as air or water.
+    pub medium: Option<Medium>,
+    /// The length of one unit, in meters.
+    pub scale: Option<f32>,
+    /// Multiplies the level of detail of all displaced meshes.
+    pub quality: Option<f32>,
     pub objects: Vec<WorldObject>,
+    /// Named objects that are only added to the scene through instances.
+    pub prototypes: HashMap<String, WorldObject>,
 }
 
 impl<'lua> Parse<'lua> for World {
@@ -268,27 +448,114 @@ impl<'lua> Parse<'lua> for World {
     fn parse<'a>(mut context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
         Ok(World {
             sky: context.parse_field("sky")?,
+            medium: context.parse_field("medium")?,
+            scale: context.expect_field("scale")?,
+            quality: context.expect_field("quality")?,
             objects: context.parse_array_field("objects")?,
+            prototypes: if context
+                .expect_field::<Option<rlua::Table>>("prototypes")?
+                .is_some()
+            {
+                context.parse_map_field("prototypes")?
+            } else {
+                HashMap::new()
+            },
         })
     }
 }
 
+pub enum Medium {
+    Vacuum,
+    Air,
+    Water,
+    /// A custom index of refraction.
+    Ior(f32),
+}
+
+impl Medium {
+    pub fn ior(&self) -> f32 {
+        match *self {
+            Medium::Vacuum => 1.0,
+            Medium::Air => 1.000293,
+            Medium::Water => 1.333,
+            Medium::Ior(ior) => ior,
+        }
+    }
+}
+
+impl<'lua> Parse<'lua> for Medium {
+    type Input = rlua::Value<'lua>;
+
+    fn parse<'a>(context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+        if let Ok(ior) = context.expect_number() {
+            return Ok(Medium::Ior(ior as f32));
+        }
+
+        match context.value() {
+            rlua::Value::String(name) => match name.to_str()? {
+                "vacuum" => Ok(Medium::Vacuum),
+                "air" => Ok(Medium::Air),
+                "water" => Ok(Medium::Water),
+                name => Err(format!("unknown medium: '{}'", name).into()),
+            },
+            value => {
+                Err(format!("expected a medium name or a number but found {:?}", value).into())
+            }
+        }
+    }
+}
+
 pub enum WorldObject {
     Sphere {
         position: self::expressions::Expression,
         radius: self::expressions::Expression,
+        transform: Option<Transform>,
         texture_scale: Option<self::expressions::Expression>,
         material: Material,
     },
     Plane {
         origin: self::expressions::Expression,
         normal: self::expressions::Expression,
+        size: Option<self::expressions::Expression>,
+        transform: Option<Transform>,
+        texture_scale: Option<self::expressions::Expression>,
+        material: Material,
+    },
+    Disk {
+        origin: self::expressions::Expression,
+        normal: self::expressions::Expression,
+        radius: self::expressions::Expression,
+        inner_radius: Option<self::expressions::Expression>,
+        transform: Option<Transform>,
+        texture_scale: Option<self::expressions::Expression>,
+        material: Material,
+    },
+    Torus {
+        position: self::expressions::Expression,
+        axis: Option<self::expressions::Expression>,
+        major_radius: self::expressions::Expression,
+        minor_radius: self::expressions::Expression,
+        transform: Option<Transform>,
+        texture_scale: Option<self::expressions::Expression>,
+        material: Material,
+    },
+    Box {
+        size: self::expressions::Expression,
+        transform: Option<Transform>,
         texture_scale: Option<self::expressions::Expression>,
         material: Material,
     },
     RayMarched {
         shape: Estimator,
         bounds: BoundingVolume,
+        transform: Option<Transform>,
+        material: Material,
+    },
+    Csg {
+        operation: CsgOperation,
+        first: Solid,
+        second: Solid,
+        transform: Option<Transform>,
         material: Material,
     },
     Mesh {
@@ -296,15 +563,68 @@ pub enum WorldObject {
         materials: HashMap<String, Material>,
         scale: Option<self::expressions::Expression>,
         transform: Option<Transform>,
+        /// Interpolates the vertex normals across the triangles. Defaults to
+        /// `true`.
+        smooth: Option<bool>,
+        displacement: Option<Displacement>,
+    },
+    /// A cubic Bézier curve, with a width that changes from `width` at the
+    /// start to `tip_width` at the end.
+    Curve {
+        points: Vec<self::expressions::Expression>,
+        width: self::expressions::Expression,
+        tip_width: Option<self::expressions::Expression>,
+        transform: Option<Transform>,
+        material: Material,
+    },
+    /// Hair strands from a file, that are turned into smooth curves.
+    Hair {
+        file: HairId,
+        width: self::expressions::Expression,
+        tip_width: Option<self::expressions::Expression>,
+        transform: Option<Transform>,
+        material: Material,
+    },
+    /// Points from a file, that are drawn as small spheres or disks.
+    PointCloud {
+        file: PointsId,
+        radius: self::expressions::Expression,
+        /// Either `"sphere"` or `"disk"`. Defaults to `"sphere"`.
+        point_shape: Option<String>,
+        transform: Option<Transform>,
+        material: Material,
+    },
+    /// A copy of a prototype, that shares its shapes.
+    Instance {
+        prototype: String,
+        transform: Option<Transform>,
+        material: Option<Material>,
     },
     DirectionalLight {
         direction: self::expressions::Expression,
-        width: self::expressions::Expression,
+        /// The cosine of half of the angular diameter.
+        width: Option<self::expressions::Expression>,
+        /// The angle across the light, in degrees.
+        angular_diameter: Option<self::expressions::Expression>,
         color: self::expressions::Expression,
+        /// Lets materials include or exclude the light.
+        name: Option<String>,
     },
     PointLight {
         position: self::expressions::Expression,
+        /// Makes the light a sphere, for soft shadows.
+        radius: Option<self::expressions::Expression>,
         color: self::expressions::Expression,
+        /// Lets materials include or exclude the light.
+        name: Option<String>,
+    },
+    /// A rectangle, like a window, that the sky is seen through. It's not
+    /// visible, but guides where the sky is sampled.
+    Portal {
+        origin: self::expressions::Expression,
+        normal: self::expressions::Expression,
+        size: self::expressions::Expression,
+        transform: Option<Transform>,
     },
 }
 
@@ -315,35 +635,202 @@ impl<'lua> Parse<'lua> for WorldObject {
         parse_enum!(context {
             "sphere" => Ok(WorldObject::Sphere {
                 position: context.parse_field("position")?,
-                radius: context.parse_field("radius")?,
+                radius: context.parse_quantity_field("radius", Quantity::Length)?,
+                transform: context.parse_field("transform")?,
                 texture_scale: context.parse_field("texture_scale")?,
                 material: context.parse_field("material")?,
             }),
             "plane" => Ok(WorldObject::Plane {
                 origin: context.parse_field("origin")?,
                 normal: context.parse_field("normal")?,
+                size: context.parse_field("size")?,
+                transform: context.parse_field("transform")?,
+                texture_scale: context.parse_field("texture_scale")?,
+                material: context.parse_field("material")?,
+            }),
+            "disk" => Ok(WorldObject::Disk {
+                origin: context.parse_field("origin")?,
+                normal: context.parse_field("normal")?,
+                radius: context.parse_quantity_field("radius", Quantity::Length)?,
+                inner_radius: context.parse_quantity_field("inner_radius", Quantity::Length)?,
+                transform: context.parse_field("transform")?,
+                texture_scale: context.parse_field("texture_scale")?,
+                material: context.parse_field("material")?,
+            }),
+            "torus" => Ok(WorldObject::Torus {
+                position: context.parse_field("position")?,
+                axis: context.parse_field("axis")?,
+                major_radius: context.parse_quantity_field("major_radius", Quantity::Length)?,
+                minor_radius: context.parse_quantity_field("minor_radius", Quantity::Length)?,
+                transform: context.parse_field("transform")?,
+                texture_scale: context.parse_field("texture_scale")?,
+                material: context.parse_field("material")?,
+            }),
+            "box" => Ok(WorldObject::Box {
+                size: context.parse_field("size")?,
+                transform: context.parse_field("transform")?,
                 texture_scale: context.parse_field("texture_scale")?,
                 material: context.parse_field("material")?,
             }),
             "ray_marched" => Ok(WorldObject::RayMarched {
                 shape: context.parse_field("shape")?,
                 bounds: context.parse_field("bounds")?,
+                transform: context.parse_field("transform")?,
                 material: context.parse_field("material")?,
             }),
-            "mesh" => Ok(WorldObject::Mesh {
-                file: context.meshes.load(context.expect_field::<String>("file")?)?,
-                materials: context.parse_map_field("materials")?,
-                scale: context.parse_field("scale")?,
+            "csg" => Ok(WorldObject::Csg {
+                operation: parse_enum!(context["operation"] {
+                    "union" => CsgOperation::Union,
+                    "intersection" => CsgOperation::Intersection,
+                    "difference" => CsgOperation::Difference,
+                }),
+                first: context.parse_field("first")?,
+                second: context.parse_field("second")?,
                 transform: context.parse_field("transform")?,
+                material: context.parse_field("material")?,
+            }),
+            "mesh" => {
+                let file = context.meshes.load(context.expect_field::<String>("file")?)?;
+                let mut materials = context.parse_map_field("materials")?;
+                import_mtl_materials(&mut context, file, &mut materials)?;
+
+                Ok(WorldObject::Mesh {
+                    file,
+                    materials,
+                    scale: context.parse_field("scale")?,
+                    transform: context.parse_field("transform")?,
+                    smooth: context.expect_field("smooth")?,
+                    displacement: context.parse_field("displacement")?,
+                })
+            },
+            "curve" => Ok(WorldObject::Curve {
+                points: context.parse_array_field("points")?,
+                width: context.parse_field("width")?,
+                tip_width: context.parse_field("tip_width")?,
+                transform: context.parse_field("transform")?,
+                material: context.parse_field("material")?,
+            }),
+            "hair" => Ok(WorldObject::Hair {
+                file: context
+                    .meshes
+                    .load_hair(context.expect_field::<String>("file")?)?,
+                width: context.parse_field("width")?,
+                tip_width: context.parse_field("tip_width")?,
+                transform: context.parse_field("transform")?,
+                material: context.parse_field("material")?,
+            }),
+            "point_cloud" => Ok(WorldObject::PointCloud {
+                file: context
+                    .meshes
+                    .load_points(context.expect_field::<String>("file")?)?,
+                radius: context.parse_quantity_field("radius", Quantity::Length)?,
+                point_shape: context.expect_field("point_shape")?,
+                transform: context.parse_field("transform")?,
+                material: context.parse_field("material")?,
+            }),
+            "instance" => Ok(WorldObject::Instance {
+                prototype: context.expect_field("prototype")?,
+                transform: context.parse_field("transform")?,
+                material: context.parse_field("material")?,
             }),
             "directional_light" => Ok(WorldObject::DirectionalLight {
                 direction: context.parse_field("direction")?,
                 width: context.parse_field("width")?,
+                angular_diameter: context
+                    .parse_quantity_field("angular_diameter", Quantity::Angle)?,
                 color: context.parse_field("color")?,
+                name: context.expect_field("name")?,
             }),
             "point_light" => Ok(WorldObject::PointLight {
                 position: context.parse_field("position")?,
+                radius: context.parse_quantity_field("radius", Quantity::Length)?,
                 color: context.parse_field("color")?,
+                name: context.expect_field("name")?,
+            }),
+            "portal" => Ok(WorldObject::Portal {
+                origin: context.parse_field("origin")?,
+                normal: context.parse_field("normal")?,
+                size: context.parse_field("size")?,
+                transform: context.parse_field("transform")?,
+            }),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl CsgOperation {
+    /// Checks if a point is inside the combined shape.
+    pub fn contains(self, inside_first: bool, inside_second: bool) -> bool {
+        match self {
+            CsgOperation::Union => inside_first || inside_second,
+            CsgOperation::Intersection => inside_first && inside_second,
+            CsgOperation::Difference => inside_first && !inside_second,
+        }
+    }
+}
+
+/// A closed shape that can be a part of a CSG shape.
+pub enum Solid {
+    Sphere {
+        position: self::expressions::Expression,
+        radius: self::expressions::Expression,
+        texture_scale: Option<self::expressions::Expression>,
+    },
+    Torus {
+        position: self::expressions::Expression,
+        axis: Option<self::expressions::Expression>,
+        major_radius: self::expressions::Expression,
+        minor_radius: self::expressions::Expression,
+        texture_scale: Option<self::expressions::Expression>,
+    },
+    Box {
+        size: self::expressions::Expression,
+        transform: Option<Transform>,
+        texture_scale: Option<self::expressions::Expression>,
+    },
+    Csg {
+        operation: CsgOperation,
+        first: Box<Solid>,
+        second: Box<Solid>,
+    },
+}
+
+impl<'lua> Parse<'lua> for Solid {
+    type Input = rlua::Table<'lua>;
+
+    fn parse<'a>(mut context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+        parse_enum!(context {
+            "sphere" => Ok(Solid::Sphere {
+                position: context.parse_field("position")?,
+                radius: context.parse_quantity_field("radius", Quantity::Length)?,
+                texture_scale: context.parse_field("texture_scale")?,
+            }),
+            "torus" => Ok(Solid::Torus {
+                position: context.parse_field("position")?,
+                axis: context.parse_field("axis")?,
+                major_radius: context.parse_quantity_field("major_radius", Quantity::Length)?,
+                minor_radius: context.parse_quantity_field("minor_radius", Quantity::Length)?,
+                texture_scale: context.parse_field("texture_scale")?,
+            }),
+            "box" => Ok(Solid::Box {
+                size: context.parse_field("size")?,
+                transform: context.parse_field("transform")?,
+                texture_scale: context.parse_field("texture_scale")?,
+            }),
+            "csg" => Ok(Solid::Csg {
+                operation: parse_enum!(context["operation"] {
+                    "union" => CsgOperation::Union,
+                    "intersection" => CsgOperation::Intersection,
+                    "difference" => CsgOperation::Difference,
+                }),
+                first: Box::new(context.parse_field("first")?),
+                second: Box::new(context.parse_field("second")?),
             }),
         })
     }
@@ -371,7 +858,7 @@ impl<'lua> Parse<'lua> for BoundingVolume {
             }),
             "sphere" => Ok(BoundingVolume::Sphere {
                 position: context.parse_field("position")?,
-                radius: context.parse_field("radius")?,
+                radius: context.parse_quantity_field("radius", Quantity::Length)?,
             }),
         })
     }
@@ -391,6 +878,9 @@ pub enum Estimator {
         slice_plane: self::expressions::Expression,
         variant: JuliaType,
     },
+    DistanceField {
+        distance: self::expressions::Expression,
+    },
 }
 
 impl<'lua> Parse<'lua> for Estimator {
@@ -411,6 +901,33 @@ impl<'lua> Parse<'lua> for Estimator {
                 slice_plane: context.parse_field("slice_plane")?,
                 variant: context.parse_field("variant")?,
             }),
+            "distance_field" => Ok(Estimator::DistanceField {
+                distance: context.parse_field("distance")?,
+            }),
+        })
+    }
+}
+
+/// Moves the vertices of a mesh along their normals, after subdividing its
+/// triangles until no edge is longer than `edge_length`.
+pub struct Displacement {
+    pub height: self::expressions::Expression,
+    pub edge_length: self::expressions::Expression,
+    /// Divides the edge length, on top of the world's quality.
+    pub quality: Option<self::expressions::Expression>,
+    /// The most number of times an edge can be split in half.
+    pub max_level: Option<u32>,
+}
+
+impl<'lua> Parse<'lua> for Displacement {
+    type Input = rlua::Table<'lua>;
+
+    fn parse<'a>(mut context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+        Ok(Displacement {
+            height: context.parse_field("height")?,
+            edge_length: context.parse_field("edge_length")?,
+            quality: context.parse_field("quality")?,
+            max_level: context.expect_field("max_level")?,
         })
     }
 }
@@ -432,6 +949,24 @@ impl<'lua> Parse<'lua> for JuliaType {
 pub struct Material {
     pub surface: SurfaceMaterial,
     pub normal_map: Option<expressions::Expression>,
+    /// A height, in texture coordinate units, that tilts the normal.
+    pub bump: Option<expressions::Expression>,
+    /// Lets rays pass through where it's less than 1, for cutouts.
+    pub opacity: Option<expressions::Expression>,
+    pub double_sided: bool,
+    /// Decides which material wins where refractive objects overlap.
+    pub priority: u32,
+    /// Overrides the renderer's cutoffs for paths that bounce off of the
+    /// material.
+    pub throughput_cutoff: Option<f32>,
+    pub max_distance: Option<f32>,
+    /// The names of the only lights that light the material directly.
+    pub include_lights: Option<Vec<String>>,
+    /// The names of lights that don't light the material directly.
+    pub exclude_lights: Option<Vec<String>>,
+    /// Lets materials include or exclude the surfaces with this material,
+    /// when it's emissive.
+    pub light_name: Option<String>,
 }
 
 impl<'lua> Parse<'lua> for Material {
@@ -441,26 +976,116 @@ impl<'lua> Parse<'lua> for Material {
         Ok(Material {
             surface: context.parse_field("surface")?,
             normal_map: context.parse_field("normal_map")?,
+            bump: context.parse_field("bump")?,
+            opacity: context.parse_field("opacity")?,
+            double_sided: context
+                .expect_field::<Option<bool>>("double_sided")?
+                .unwrap_or(true),
+            priority: context
+                .expect_field::<Option<u32>>("priority")?
+                .unwrap_or(0),
+            throughput_cutoff: context.expect_field("throughput_cutoff")?,
+            max_distance: context.expect_field("max_distance")?,
+            include_lights: context.expect_field("include_lights")?,
+            exclude_lights: context.expect_field("exclude_lights")?,
+            light_name: context.expect_field("light_name")?,
         })
     }
 }
 
+/// Reads a list of `{wavelength, value}` pairs, with increasing wavelengths.
+fn parse_spectrum_points<'lua>(
+    context: &mut ParseContext<'_, 'lua, rlua::Table<'lua>>,
+    name: &str,
+) -> Result<Vec<(f32, f32)>, Box<dyn Error>> {
+    context.with_field(name, |points: ParseContext<rlua::Value>| {
+        let points: Vec<(f32, f32)> = rlua_serde::from_value(points.value().clone())?;
+
+        if points.len() < 2 {
+            return Err("expected at least two points".into());
+        }
+        if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err("the wavelengths have to increase".into());
+        }
+
+        Ok(points)
+    })
+}
+
+/// Adds approximations of the MTL materials that the mesh's faces use, unless
+/// `materials` already has materials with the same names.
+fn import_mtl_materials<'lua>(
+    context: &mut ParseContext<'_, 'lua, rlua::Table<'lua>>,
+    file: MeshId,
+    materials: &mut HashMap<String, Material>,
+) -> Result<(), Box<dyn Error>> {
+    let lua = context.lua();
+    let mut imported = Vec::new();
+
+    for (name, mtl) in context.meshes.mtl_materials(file) {
+        if materials.contains_key(name) {
+            continue;
+        }
+
+        let properties = lua.create_table()?;
+        properties.set("kd", mtl.kd.map(|color| color.to_vec()))?;
+        properties.set("ks", mtl.ks.map(|color| color.to_vec()))?;
+        properties.set("ke", mtl.ke.map(|color| color.to_vec()))?;
+        properties.set("ns", mtl.ns)?;
+        properties.set("ni", mtl.ni)?;
+        properties.set("d", mtl.d)?;
+        properties.set("illum", mtl.illum)?;
+        properties.set("map_kd", mtl.map_kd.as_ref().and_then(|map| map.to_str()))?;
+        properties.set("map_ke", mtl.map_ke.as_ref().and_then(|map| map.to_str()))?;
+        if let Some((map, multiplier)) = &mtl.map_bump {
+            properties.set("map_bump", map.to_str())?;
+            properties.set("bump_multiplier", *multiplier)?;
+        }
+
+        imported.push((name.to_owned(), properties));
+    }
+
+    for (name, properties) in imported {
+        let material = context
+            .parse_library_call("mtl_material", properties)
+            .map_err(|error| format!("MTL material '{}': {}", name, error))?;
+        materials.insert(name, material);
+    }
+
+    Ok(())
+}
+
 pub enum SurfaceMaterial {
     Diffuse {
         color: self::expressions::Expression,
     },
     Emission {
         color: self::expressions::Expression,
+        /// The color on the back side, if it's different from the front.
+        back_color: Option<self::expressions::Expression>,
+        /// Scales the color to an intensity in physical units.
+        intensity: Option<(self::expressions::Expression, EmissionUnit)>,
     },
     Mirror {
         color: self::expressions::Expression,
     },
+    Glossy {
+        color: self::expressions::Expression,
+        roughness: self::expressions::Expression,
+        bitangent_roughness: Option<self::expressions::Expression>,
+        rotation: Option<self::expressions::Expression>,
+    },
+    BlinnPhong {
+        color: self::expressions::Expression,
+        exponent: self::expressions::Expression,
+    },
     Refractive {
         color: self::expressions::Expression,
         ior: self::expressions::Expression,
         dispersion: Option<self::expressions::Expression>,
         env_ior: Option<self::expressions::Expression>,
         env_dispersion: Option<self::expressions::Expression>,
+        roughness: Option<self::expressions::Expression>,
     },
     Mix {
         amount: self::expressions::Expression,
@@ -475,6 +1100,28 @@ pub enum SurfaceMaterial {
         reflect: Box<SurfaceMaterial>,
         refract: Box<SurfaceMaterial>,
     },
+    Measured {
+        brdf: std::sync::Arc<crate::merl::MeasuredBrdf>,
+        color: self::expressions::Expression,
+    },
+    /// Diffuse, but also absorbs light in the `excitation` band and emits
+    /// some of it again in the `emission` band.
+    Fluorescent {
+        color: self::expressions::Expression,
+        excitation: Vec<(f32, f32)>,
+        emission: Vec<(f32, f32)>,
+        efficiency: Option<self::expressions::Expression>,
+    },
+}
+
+#[derive(Copy, Clone)]
+pub enum EmissionUnit {
+    /// Watts of visible light, from the whole surface.
+    Watts,
+    /// Lumens, from the whole surface.
+    Lumens,
+    /// Candela per square meter.
+    Nits,
 }
 
 impl<'lua> Parse<'lua> for SurfaceMaterial {
@@ -485,11 +1132,34 @@ impl<'lua> Parse<'lua> for SurfaceMaterial {
             "diffuse" => Ok(SurfaceMaterial::Diffuse {
                 color: context.parse_field("color")?,
             }),
-            "emission" => Ok(SurfaceMaterial::Emission {
+            "emission" => {
+                let intensity = match context.parse_field("intensity")? {
+                    Some(intensity) => Some((intensity, parse_enum!(context["unit"] {
+                        "watts" => EmissionUnit::Watts,
+                        "lumens" => EmissionUnit::Lumens,
+                        "nits" => EmissionUnit::Nits,
+                    }))),
+                    None => None,
+                };
+
+                Ok(SurfaceMaterial::Emission {
+                    color: context.parse_field("color")?,
+                    back_color: context.parse_field("back_color")?,
+                    intensity,
+                })
+            },
+            "mirror" => Ok(SurfaceMaterial::Mirror {
+                color: context.parse_field("color")?,
+            }),
+            "glossy" => Ok(SurfaceMaterial::Glossy {
                 color: context.parse_field("color")?,
+                roughness: context.parse_field("roughness")?,
+                bitangent_roughness: context.parse_field("bitangent_roughness")?,
+                rotation: context.parse_field("rotation")?,
             }),
-            "mirror" => Ok(SurfaceMaterial::Mirror {
+            "blinn_phong" => Ok(SurfaceMaterial::BlinnPhong {
                 color: context.parse_field("color")?,
+                exponent: context.parse_field("exponent")?,
             }),
             "refractive" => Ok(SurfaceMaterial::Refractive {
                 color: context.parse_field("color")?,
@@ -497,6 +1167,7 @@ impl<'lua> Parse<'lua> for SurfaceMaterial {
                 env_ior: context.parse_field("env_ior")?,
                 dispersion: context.parse_field("dispersion")?,
                 env_dispersion: context.parse_field("env_dispersion")?,
+                roughness: context.parse_field("roughness")?,
             }),
             "mix" => Ok(SurfaceMaterial::Mix {
                 amount: context.parse_field("amount")?,
@@ -511,6 +1182,18 @@ impl<'lua> Parse<'lua> for SurfaceMaterial {
                 reflect: Box::new(context.parse_field("reflect")?),
                 refract: Box::new(context.parse_field("refract")?),
             }),
+            "measured" => Ok(SurfaceMaterial::Measured {
+                brdf: context
+                    .textures
+                    .load_measured_brdf(context.expect_field::<String>("path")?)?,
+                color: context.parse_field("color")?,
+            }),
+            "fluorescent" => Ok(SurfaceMaterial::Fluorescent {
+                color: context.parse_field("color")?,
+                excitation: parse_spectrum_points(&mut context, "excitation")?,
+                emission: parse_spectrum_points(&mut context, "emission")?,
+                efficiency: context.parse_field("efficiency")?,
+            }),
         })
     }
 }
@@ -521,6 +1204,23 @@ pub enum Transform {
         to: self::expressions::Expression,
         up: Option<self::expressions::Expression>,
     },
+    Keyframes {
+        keyframes: Vec<Keyframe>,
+    },
+    Translate {
+        offset: self::expressions::Expression,
+    },
+    Rotate {
+        axis: self::expressions::Expression,
+        degrees: self::expressions::Expression,
+    },
+    Scale {
+        factor: self::expressions::Expression,
+    },
+    /// Applies the transforms in order, starting with the first one.
+    Chain {
+        transforms: Vec<Transform>,
+    },
 }
 
 impl<'lua> Parse<'lua> for Transform {
@@ -532,7 +1232,31 @@ impl<'lua> Parse<'lua> for Transform {
                 from: context.parse_field("from")?,
                 to: context.parse_field("to")?,
                 up: context.parse_field("up")?,
-            })
+            }),
+            "keyframes" => {
+                let mut keyframes: Vec<Keyframe> = context.parse_array_field("keyframes")?;
+
+                if keyframes.is_empty() {
+                    return Err("keyframes: expected at least one keyframe".into());
+                }
+
+                keyframes.sort_by(|a, b| a.frame.partial_cmp(&b.frame).unwrap_or(std::cmp::Ordering::Equal));
+
+                Ok(Transform::Keyframes { keyframes })
+            },
+            "translate" => Ok(Transform::Translate {
+                offset: context.parse_field("offset")?,
+            }),
+            "rotate" => Ok(Transform::Rotate {
+                axis: context.parse_field("axis")?,
+                degrees: context.parse_quantity_field("degrees", Quantity::Angle)?,
+            }),
+            "scale" => Ok(Transform::Scale {
+                factor: context.parse_field("factor")?,
+            }),
+            "chain" => Ok(Transform::Chain {
+                transforms: context.parse_array_field("transforms")?,
+            }),
         })
     }
 }
@@ -550,6 +1274,147 @@ impl Evaluate<Matrix4<f32>> for Transform {
                     .invert()
                     .ok_or("could not invert view matrix")?
             }
+            Transform::Keyframes { keyframes } => {
+                let next = keyframes
+                    .iter()
+                    .position(|keyframe| keyframe.frame > context.frame);
+
+                match next {
+                    Some(0) => keyframes[0].transform.evaluate(context)?,
+                    Some(index) => {
+                        let previous = &keyframes[index - 1];
+                        let next = &keyframes[index];
+                        let amount =
+                            (context.frame - previous.frame) / (next.frame - previous.frame);
+
+                        interpolate_transforms(
+                            previous.transform.evaluate(context)?,
+                            next.transform.evaluate(context)?,
+                            amount,
+                        )
+                    }
+                    None => keyframes[keyframes.len() - 1].transform.evaluate(context)?,
+                }
+            }
+            Transform::Translate { offset } => Matrix4::from_translation(offset.evaluate(context)?),
+            Transform::Rotate { axis, degrees } => {
+                let axis: Vector3<f32> = axis.evaluate(context)?;
+                let degrees: f32 = degrees.evaluate(context)?;
+
+                if axis.magnitude2() == 0.0 {
+                    return Err("the rotation axis can't be zero".into());
+                }
+
+                Matrix4::from_axis_angle(axis.normalize(), Deg(degrees))
+            }
+            Transform::Scale { factor } => {
+                let factor: Vector3<f32> = factor.evaluate(context)?;
+                Matrix4::from_nonuniform_scale(factor.x, factor.y, factor.z)
+            }
+            Transform::Chain { transforms } => {
+                let mut matrix = Matrix4::identity();
+
+                for (i, transform) in transforms.iter().enumerate() {
+                    let step: Matrix4<f32> = transform
+                        .evaluate(context)
+                        .map_err(|error| format!("transforms[{}]: {}", i, error))?;
+                    matrix = step * matrix;
+                }
+
+                matrix
+            }
+        })
+    }
+}
+
+/// Splits the transforms into scale, rotation and position, and interpolates
+/// the scale and position linearly and the orientation spherically. Any
+/// shearing is ignored. Transforms that flatten the shape, such as a zero
+/// scale, have no orientation and are interpolated element by element.
+fn interpolate_transforms(from: Matrix4<f32>, to: Matrix4<f32>, amount: f32) -> Matrix4<f32> {
+    let decompose = |matrix: Matrix4<f32>| {
+        let mut scale = Vector3::new(
+            matrix.x.truncate().magnitude(),
+            matrix.y.truncate().magnitude(),
+            matrix.z.truncate().magnitude(),
+        );
+
+        let determinant = Matrix3::from_cols(
+            matrix.x.truncate(),
+            matrix.y.truncate(),
+            matrix.z.truncate(),
+        )
+        .determinant();
+
+        if !determinant.is_normal() {
+            return None;
+        }
+
+        // Mirroring is kept as a negative scale, so the rest is a rotation.
+        if determinant < 0.0 {
+            scale.x = -scale.x;
+        }
+
+        let rotation = Quaternion::from(Matrix3::from_cols(
+            matrix.x.truncate() / scale.x,
+            matrix.y.truncate() / scale.y,
+            matrix.z.truncate() / scale.z,
+        ));
+
+        Some((scale, rotation))
+    };
+
+    let ((from_scale, from_rotation), (to_scale, mut to_rotation)) =
+        match (decompose(from), decompose(to)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return from + (to - from) * amount,
+        };
+
+    // Take the shortest path between the orientations.
+    if from_rotation.dot(to_rotation) < 0.0 {
+        to_rotation = -to_rotation;
+    }
+
+    let position = from.w.truncate().lerp(to.w.truncate(), amount);
+    let scale = from_scale.lerp(to_scale, amount);
+
+    Matrix4::from_translation(position)
+        * Matrix4::from(from_rotation.slerp(to_rotation, amount))
+        * Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z)
+}
+
+pub struct Keyframe {
+    pub frame: f32,
+    pub transform: Transform,
+}
+
+impl<'lua> Parse<'lua> for Keyframe {
+    type Input = rlua::Table<'lua>;
+
+    fn parse<'a>(mut context: ParseContext<'a, 'lua, Self::Input>) -> Result<Self, Box<dyn Error>> {
+        Ok(Keyframe {
+            frame: context.expect_field("frame")?,
+            transform: context.parse_field("transform")?,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Matrix4, Vector3};
+
+    use super::interpolate_transforms;
+
+    #[test]
+    fn zero_scale_keyframes_are_interpolated() {
+        let from =
+            Matrix4::from_translation(Vector3::new(2.0, 0.0, 0.0)) * Matrix4::from_scale(0.0);
+        let to = Matrix4::from_scale(2.0);
+        let matrix = interpolate_transforms(from, to, 0.5);
+
+        assert_eq!(
+            matrix,
+            Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0)) * Matrix4::from_scale(1.0)
+        );
+    }
+}
@@ -3,8 +3,12 @@ use std::{collections::HashMap, error::Error};
 use rlua::FromLua;
 
 use super::{
-    expressions::ExpressionLoader, meshes::MeshLoader, spectra::SpectrumLoader, tables::Tables,
+    expressions::ExpressionLoader,
+    meshes::MeshLoader,
+    spectra::SpectrumLoader,
+    tables::Tables,
     textures::TextureLoader,
+    units::{Quantity, Units},
 };
 
 pub struct ParseContext<'a, 'lua: 'a, T> {
@@ -13,6 +17,7 @@ pub struct ParseContext<'a, 'lua: 'a, T> {
     pub spectra: &'a mut SpectrumLoader,
     pub textures: &'a mut TextureLoader,
     pub tables: &'a Tables,
+    pub units: Units,
 
     current_value: T,
     context: &'a rlua::Context<'lua>,
@@ -25,6 +30,7 @@ impl<'a, 'lua, T: FromLua<'lua>> ParseContext<'a, 'lua, T> {
         spectra: &'a mut SpectrumLoader,
         textures: &'a mut TextureLoader,
         tables: &'a Tables,
+        units: Units,
         value: T,
         context: &'a rlua::Context<'lua>,
     ) -> Self {
@@ -34,6 +40,7 @@ impl<'a, 'lua, T: FromLua<'lua>> ParseContext<'a, 'lua, T> {
             spectra,
             textures,
             tables,
+            units,
             current_value: value,
             context,
         }
@@ -42,6 +49,34 @@ impl<'a, 'lua, T: FromLua<'lua>> ParseContext<'a, 'lua, T> {
         &self.current_value
     }
 
+    pub fn lua(&self) -> rlua::Context<'lua> {
+        *self.context
+    }
+
+    /// Calls a function from the `_pyrite` library table and parses what it
+    /// returns, for values that are made from files instead of the project.
+    pub fn parse_library_call<A: rlua::ToLuaMulti<'lua>, U: Parse<'lua>>(
+        &mut self,
+        function: &str,
+        arguments: A,
+    ) -> Result<U, Box<dyn Error>> {
+        let library: rlua::Table<'lua> = self.context.globals().get("_pyrite")?;
+        let function: rlua::Function<'lua> = library.get(function)?;
+
+        ParseContext {
+            expressions: self.expressions,
+            meshes: self.meshes,
+            spectra: self.spectra,
+            textures: self.textures,
+            tables: self.tables,
+            units: self.units,
+
+            current_value: function.call(arguments)?,
+            context: self.context,
+        }
+        .parse()
+    }
+
     pub fn parse<U: Parse<'lua, Input = T>>(self) -> Result<U, Box<dyn Error>> {
         U::parse(self)
     }
@@ -56,6 +91,7 @@ impl<'a, 'lua, T: FromLua<'lua>> ParseContext<'a, 'lua, T> {
             spectra: self.spectra,
             textures: self.textures,
             tables: self.tables,
+            units: self.units,
 
             current_value: self.current_value.clone(),
             context: self.context,
@@ -86,6 +122,7 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Value<'lua>> {
             spectra: self.spectra,
             textures: self.textures,
             tables: self.tables,
+            units: self.units,
 
             current_value: U::from_lua(self.current_value, self.context.clone())?,
             context: self.context,
@@ -107,13 +144,23 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Table<'lua>> {
         parse: impl FnOnce(ParseContext<'_, 'lua, T>) -> Result<U, Box<dyn Error>>,
     ) -> Result<U, Box<dyn Error>> {
         let input = self.expect_field(name)?;
+        self.with_value(name, input, parse)
+    }
 
+    /// Parses `input` as if it was the field `name`.
+    fn with_value<T, U>(
+        &mut self,
+        name: &str,
+        input: T,
+        parse: impl FnOnce(ParseContext<'_, 'lua, T>) -> Result<U, Box<dyn Error>>,
+    ) -> Result<U, Box<dyn Error>> {
         let new_context = ParseContext {
             expressions: self.expressions,
             meshes: self.meshes,
             spectra: self.spectra,
             textures: self.textures,
             tables: self.tables,
+            units: self.units,
 
             current_value: input,
             context: self.context,
@@ -126,6 +173,31 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Table<'lua>> {
         self.with_field(name, T::parse)
     }
 
+    /// Same as `parse_field`, but the field can also be a string with a unit
+    /// for `quantity`, like `"35deg"`, that is converted first.
+    pub fn parse_quantity_field<T: Parse<'lua>>(
+        &mut self,
+        name: &str,
+        quantity: Quantity,
+    ) -> Result<T, Box<dyn Error>> {
+        let value = match self.expect_field(name)? {
+            rlua::Value::String(value) => {
+                let number = self
+                    .units
+                    .convert(value.to_str()?, quantity)
+                    .map_err(|error| format!("{}: {}", name, error))?;
+                rlua::Value::Number(number)
+            }
+            value => value,
+        };
+
+        // The converted number is parsed on its own, so the project's table
+        // is left as it is.
+        let input = T::Input::from_lua(value, self.context.clone())
+            .map_err(|error| format!("{}: {}", name, error))?;
+        self.with_value(name, input, T::parse)
+    }
+
     pub fn parse_array_field<T: Parse<'lua>>(
         &mut self,
         name: &str,
@@ -139,6 +211,7 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Table<'lua>> {
                     spectra,
                     textures,
                     tables,
+                    units,
                     current_value,
                     context,
                 } = context;
@@ -155,6 +228,7 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Table<'lua>> {
                             spectra,
                             textures,
                             tables,
+                            units,
 
                             current_value: value,
                             context,
@@ -182,6 +256,7 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Table<'lua>> {
                     spectra,
                     textures,
                     tables,
+                    units,
                     current_value,
                     context,
                 } = context;
@@ -197,6 +272,7 @@ impl<'a, 'lua> ParseContext<'a, 'lua, rlua::Table<'lua>> {
                             spectra,
                             textures,
                             tables,
+                            units,
 
                             current_value: value,
                             context,
@@ -5,10 +5,16 @@ use bumpalo::Bump;
 use super::{
     expressions::{BinaryOperator, ComplexExpression, Expression, Expressions, Vector},
     spectra::{Spectra, SpectrumId},
-    textures::{TextureId, Textures},
+    textures::{TextureId, Textures, VolumeId},
 };
-use crate::color::Light;
-use cgmath::{Point2, Vector3};
+use crate::{
+    color::Light,
+    noise::NoiseKind,
+    texture::{Footprint, TextureFilter, TextureWrap, UvTransform},
+};
+use cgmath::{Point2, Point3, Vector3};
+use collision::Aabb3;
+use palette::LinSrgba;
 
 pub(crate) type ProgramFn<I, T> = for<'a> fn(&'a mut Registers, &'a I, Resources<'a>) -> T;
 pub(crate) type InputFn<I> = for<'a> fn(&'a mut Registers, &'a I, Resources<'a>) -> Value;
@@ -58,7 +64,14 @@ impl<'p> ProgramCompiler<'p> {
 
         while let Some(entry) = stack.pop() {
             let id = match entry {
-                StackEntry::Expression(&Expression::Complex(id)) => id,
+                StackEntry::Expression(expression @ &Expression::Complex(id)) => {
+                    if let Some(number) = constant_number(expression, expressions) {
+                        instructions.push(Instruction::Push(T::from_number(number as f32)?.into()));
+                        continue;
+                    }
+
+                    id
+                }
                 StackEntry::Expression(&Expression::Number(number)) => {
                     instructions.push(Instruction::Push(T::from_number(number as f32)?.into()));
                     continue;
@@ -127,7 +140,18 @@ impl<'p> ProgramCompiler<'p> {
                         }
                     }
                 }
-                ComplexExpression::Rgb { red, green, blue } => {
+                ComplexExpression::Rgb {
+                    spectrum: Some(points),
+                    ..
+                } if T::spectrum().is_ok() => {
+                    instructions.push(Instruction::Push(Value::Spectrum(*points)));
+                    if let Some(spectrum) = T::spectrum()? {
+                        instructions.push(Instruction::Function(spectrum));
+                    }
+                }
+                ComplexExpression::Rgb {
+                    red, green, blue, ..
+                } => {
                     if let Some(rgb) = T::rgb()? {
                         stack.push(StackEntry::Function(rgb));
                     }
@@ -207,9 +231,97 @@ impl<'p> ProgramCompiler<'p> {
                         )),
                     }
                 }
-                ComplexExpression::Blackbody { temperature } => {
+                ComplexExpression::ConductorFresnel { n, k } => {
+                    stack.push(StackEntry::Function(T::conductor_fresnel()?));
+                    instructions.push(Instruction::Input(I::normal()?));
+                    instructions.push(Instruction::Input(I::incident()?));
+                    stack.push(StackEntry::Expression(k));
+                    stack.push(StackEntry::Expression(n));
+                }
+                ComplexExpression::Blackbody {
+                    temperature,
+                    normalize,
+                } => {
                     stack.push(StackEntry::Function(T::blackbody()?));
-                    stack.push(StackEntry::Expression(temperature));
+                    stack.push(StackEntry::Number(if *normalize { 1.0 } else { 0.0 }));
+                    match temperature {
+                        Expression::Number(number) => stack.push(StackEntry::Number(*number)),
+                        other => stack.push(StackEntry::Program(
+                            other,
+                            |this, expression, expressions| {
+                                this.compile_any::<I, f32>(expression, expressions)
+                            },
+                        )),
+                    }
+                }
+                ComplexExpression::AngleProfile { curve } => {
+                    instructions.push(Instruction::Push(Value::Spectrum(*curve)));
+                    instructions.push(Instruction::Input(I::normal()?));
+                    instructions.push(Instruction::Input(I::incident()?));
+                    instructions.push(Instruction::Function(T::angle_profile()?));
+                }
+                ComplexExpression::DirectionRegion {
+                    direction,
+                    angle,
+                    softness,
+                } => {
+                    stack.push(StackEntry::Function(T::direction_region()?));
+                    instructions.push(Instruction::Input(I::incident()?));
+                    stack.push(StackEntry::Program(
+                        direction,
+                        |this, expression, expressions| {
+                            this.compile_any::<I, Vector>(expression, expressions)
+                        },
+                    ));
+                    match softness {
+                        Expression::Number(number) => stack.push(StackEntry::Number(*number)),
+                        other => stack.push(StackEntry::Program(
+                            other,
+                            |this, expression, expressions| {
+                                this.compile_any::<I, f32>(expression, expressions)
+                            },
+                        )),
+                    }
+                    match angle {
+                        Expression::Number(number) => stack.push(StackEntry::Number(*number)),
+                        other => stack.push(StackEntry::Program(
+                            other,
+                            |this, expression, expressions| {
+                                this.compile_any::<I, f32>(expression, expressions)
+                            },
+                        )),
+                    }
+                }
+                ComplexExpression::DaylightSky { sun, turbidity } => {
+                    stack.push(StackEntry::Function(T::daylight_sky()?));
+                    instructions.push(Instruction::Input(I::incident()?));
+                    stack.push(StackEntry::Program(sun, |this, expression, expressions| {
+                        this.compile_any::<I, Vector>(expression, expressions)
+                    }));
+                    match turbidity {
+                        Expression::Number(number) => stack.push(StackEntry::Number(*number)),
+                        other => stack.push(StackEntry::Program(
+                            other,
+                            |this, expression, expressions| {
+                                this.compile_any::<I, f32>(expression, expressions)
+                            },
+                        )),
+                    }
+                }
+                ComplexExpression::Sunlight { sun, turbidity } => {
+                    stack.push(StackEntry::Function(T::sunlight()?));
+                    stack.push(StackEntry::Program(sun, |this, expression, expressions| {
+                        this.compile_any::<I, Vector>(expression, expressions)
+                    }));
+                    match turbidity {
+                        Expression::Number(number) => stack.push(StackEntry::Number(*number)),
+                        other => stack.push(StackEntry::Program(
+                            other,
+                            |this, expression, expressions| {
+                                this.compile_any::<I, f32>(expression, expressions)
+                            },
+                        )),
+                    }
                 }
                 ComplexExpression::Spectrum { points } => {
                     instructions.push(Instruction::Push(Value::Spectrum(*points)));
@@ -217,9 +329,66 @@ impl<'p> ProgramCompiler<'p> {
                         instructions.push(Instruction::Function(spectrum));
                     }
                 }
-                ComplexExpression::Texture { texture } => {
+                ComplexExpression::Texture {
+                    texture,
+                    filter,
+                    wrap,
+                    offset,
+                    scale,
+                    rotation,
+                } => {
                     instructions.push(Instruction::Push(Value::Texture(*texture)));
+                    instructions.push(Instruction::Push(Value::Number(filter.to_number())));
+                    instructions.push(Instruction::Push(Value::Number(wrap.to_number())));
                     instructions.push(Instruction::Input(I::texture_coordinates()?));
+                    instructions.push(Instruction::Input(I::texture_footprint()?));
+                    if let Some(texture) = T::texture()? {
+                        stack.push(StackEntry::Function(texture));
+                    }
+                    match rotation {
+                        Expression::Number(number) => stack.push(StackEntry::Number(*number)),
+                        other => stack.push(StackEntry::Program(
+                            other,
+                            |this, expression, expressions| {
+                                this.compile_any::<I, f32>(expression, expressions)
+                            },
+                        )),
+                    }
+                    stack.push(StackEntry::Program(
+                        scale,
+                        |this, expression, expressions| {
+                            this.compile_any::<I, Vector>(expression, expressions)
+                        },
+                    ));
+                    stack.push(StackEntry::Program(
+                        offset,
+                        |this, expression, expressions| {
+                            this.compile_any::<I, Vector>(expression, expressions)
+                        },
+                    ));
+                }
+                ComplexExpression::Environment { texture, rotation } => {
+                    instructions.push(Instruction::Push(Value::Texture(*texture)));
+                    instructions.push(Instruction::Push(Value::Number(
+                        TextureFilter::Bicubic.to_number(),
+                    )));
+                    instructions.push(Instruction::Push(Value::Number(
+                        TextureWrap::Repeat.to_number(),
+                    )));
+                    instructions.push(Instruction::Push(Value::Number(*rotation)));
+                    instructions.push(Instruction::Input(I::incident()?));
+                    instructions.push(Instruction::Input(|registers, _, _| {
+                        let incident: Vector3<f32> = registers.pop::<Vector>().into();
+                        let rotation: f32 = registers.pop();
+                        Value::Vector(crate::math::equirectangular(incident, rotation).into())
+                    }));
+                    instructions.push(Instruction::Push(Value::Vector(
+                        Footprint::default().into(),
+                    )));
+                    // It's not moved, scaled or rotated.
+                    instructions.push(Instruction::Push(Vector3::new(0.0, 0.0, 0.0).into()));
+                    instructions.push(Instruction::Push(Vector3::new(1.0, 1.0, 0.0).into()));
+                    instructions.push(Instruction::Push(Value::Number(0.0)));
                     if let Some(texture) = T::texture()? {
                         instructions.push(Instruction::Function(texture));
                     }
@@ -244,6 +413,38 @@ impl<'p> ProgramCompiler<'p> {
                         Value::Number(normal.x * 0.5 + 0.5)
                     }));
                 }
+                ComplexExpression::Noise {
+                    kind,
+                    scale,
+                    octaves,
+                    lacunarity,
+                    gain,
+                } => {
+                    instructions.push(Instruction::Push(Value::Number(kind.to_number())));
+                    instructions.push(Instruction::Push(Value::Number(*scale)));
+                    instructions.push(Instruction::Push(Value::Number(*octaves as f32)));
+                    instructions.push(Instruction::Push(Value::Number(*lacunarity)));
+                    instructions.push(Instruction::Push(Value::Number(*gain)));
+                    instructions.push(Instruction::Input(I::position()?));
+                    instructions.push(Instruction::Function(T::noise()?));
+                }
+                ComplexExpression::Volume { volume, bounds } => {
+                    instructions.push(Instruction::Push(Value::Volume(*volume)));
+                    instructions.push(Instruction::Push(bounds.min.into()));
+                    instructions.push(Instruction::Push(bounds.max.into()));
+                    instructions.push(Instruction::Input(I::position()?));
+                    instructions.push(Instruction::Function(T::volume()?));
+                }
+                ComplexExpression::Position
+                | ComplexExpression::Length { .. }
+                | ComplexExpression::Abs { .. }
+                | ComplexExpression::Min { .. }
+                | ComplexExpression::Max { .. }
+                | ComplexExpression::SmoothMin { .. } => {
+                    return Err(
+                        "distance field functions can only be used in distance fields".into(),
+                    )
+                }
             }
         }
 
@@ -252,6 +453,29 @@ impl<'p> ProgramCompiler<'p> {
         })
     }
 
+    /// Makes a program that multiplies the result of `program` with
+    /// `factor`, for scaling already compiled programs.
+    pub(crate) fn scale<I, T>(
+        &self,
+        program: Program<'p, I, T>,
+        factor: f32,
+    ) -> Result<Program<'p, I, T>, Box<dyn Error>>
+    where
+        I: ProgramInput,
+        T: ProgramValue<I> + Into<Value> + 'p,
+        AnyProgram<'p, I>: From<Program<'p, I, T>>,
+    {
+        let instructions = [
+            Instruction::Program(program.into()),
+            Instruction::Push(T::from_number(factor)?.into()),
+            Instruction::Function(T::mul()?),
+        ];
+
+        Ok(Program {
+            instructions: self.arena.alloc_slice_copy(&instructions),
+        })
+    }
+
     fn compile_any<I, T>(
         &self,
         expression: &Expression,
@@ -266,6 +490,37 @@ impl<'p> ProgramCompiler<'p> {
     }
 }
 
+/// Calculates the value of number expressions that don't depend on any
+/// input, so they can be pushed as a single number.
+fn constant_number(expression: &Expression, expressions: &Expressions) -> Option<f64> {
+    let id = match *expression {
+        Expression::Number(number) => return Some(number),
+        Expression::Complex(id) => id,
+    };
+
+    match expressions.get(id) {
+        ComplexExpression::Binary { operator, lhs, rhs } => {
+            let lhs = constant_number(lhs, expressions)?;
+            let rhs = constant_number(rhs, expressions)?;
+
+            Some(match operator {
+                BinaryOperator::Add => lhs + rhs,
+                BinaryOperator::Sub => lhs - rhs,
+                BinaryOperator::Mul => lhs * rhs,
+                BinaryOperator::Div => lhs / rhs,
+            })
+        }
+        ComplexExpression::Mix { amount, lhs, rhs } => {
+            let amount = constant_number(amount, expressions)?.min(1.0).max(0.0);
+            let lhs = constant_number(lhs, expressions)?;
+            let rhs = constant_number(rhs, expressions)?;
+
+            Some(lhs * (1.0 - amount) + rhs * amount)
+        }
+        _ => None,
+    }
+}
+
 fn into_constant_vector(
     x: &Expression,
     y: &Expression,
@@ -297,7 +552,14 @@ pub(crate) trait ProgramValue<I>: Copy + Send + Sized {
     fn div() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
     fn mix() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
     fn fresnel() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn conductor_fresnel() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn angle_profile() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn direction_region() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
     fn blackbody() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn daylight_sky() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn sunlight() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn noise() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
+    fn volume() -> Result<ProgramFn<I, Self>, Box<dyn Error>>;
 }
 
 impl<I> ProgramValue<I> for f32 {
@@ -321,10 +583,7 @@ impl<I> ProgramValue<I> for f32 {
     }
     fn texture() -> Result<Option<ProgramFn<I, Self>>, Box<dyn Error>> {
         Ok(Some(|registers, _, resources| {
-            let texture = resources.textures.get(registers.pop());
-            let uv: Vector = registers.pop();
-
-            texture.get_color(uv.into()).red
+            texture_color(registers, resources).red
         }))
     }
     fn add() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
@@ -372,17 +631,120 @@ impl<I> ProgramValue<I> for f32 {
             crate::math::fresnel(ior, env_ior, normal.into(), incident.into())
         })
     }
+    fn conductor_fresnel() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let incident: Vector = registers.pop();
+            let normal: Vector = registers.pop();
+            let k: f32 = registers.pop();
+            let n: f32 = registers.pop();
+            crate::math::conductor_fresnel(n, k, normal.into(), incident.into())
+        })
+    }
+    fn angle_profile() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, resources| {
+            let incident: Vector = registers.pop();
+            let normal: Vector = registers.pop();
+            let curve = resources.spectra.get(registers.pop());
+            curve.get(crate::math::incidence_angle(normal.into(), incident.into()))
+        })
+    }
+    fn direction_region() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let direction: Vector = registers.pop();
+            let incident: Vector = registers.pop();
+            let softness: f32 = registers.pop();
+            let angle: f32 = registers.pop();
+            crate::math::direction_region(direction.into(), angle, softness, incident.into())
+        })
+    }
     fn blackbody() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
         Err("black-body functions cannot be used as numbers".into())
     }
+    fn daylight_sky() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("daylight cannot be used as numbers".into())
+    }
+    fn sunlight() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Err("sunlight cannot be used as numbers".into())
+    }
+    fn noise() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, _| {
+            let position: Vector = registers.pop();
+            let gain: f32 = registers.pop();
+            let lacunarity: f32 = registers.pop();
+            let octaves: f32 = registers.pop();
+            let scale: f32 = registers.pop();
+            let kind: f32 = registers.pop();
+            crate::noise::fractal(
+                NoiseKind::from_number(kind),
+                position.into(),
+                scale,
+                octaves as u32,
+                lacunarity,
+                gain,
+            )
+        })
+    }
+    fn volume() -> Result<ProgramFn<I, Self>, Box<dyn Error>> {
+        Ok(|registers, _, resources| volume_value(registers, resources))
+    }
+}
+
+/// Pops the arguments of a 3D texture lookup, in the order they are pushed
+/// for `ComplexExpression::Volume`, and looks up the value.
+pub(crate) fn volume_value(registers: &mut Registers, resources: Resources<'_>) -> f32 {
+    let position: Vector = registers.pop();
+    let max: Vector = registers.pop();
+    let min: Vector = registers.pop();
+    let volume = resources.textures.get_volume(registers.pop());
+
+    volume.get(position.into(), Aabb3::new(min.into(), max.into()))
+}
+
+/// Pops the arguments of a texture lookup, in the order they are pushed for
+/// `ComplexExpression::Texture`, and looks up the color.
+pub(crate) fn texture_color(registers: &mut Registers, resources: Resources<'_>) -> LinSrgba {
+    let texture = resources.textures.get(registers.pop());
+    let rotation: f32 = registers.pop();
+    let wrap = TextureWrap::from_number(registers.pop());
+    let filter = TextureFilter::from_number(registers.pop());
+    let scale: Vector = registers.pop();
+    let offset: Vector = registers.pop();
+    let footprint: Vector = registers.pop();
+    let uv: Vector = registers.pop();
+
+    let transform = UvTransform {
+        offset: offset.into(),
+        scale: scale.into(),
+        rotation,
+    };
+
+    texture.get_color(
+        transform.point(uv.into()),
+        filter,
+        wrap,
+        transform.footprint(footprint.into()),
+    )
 }
 
 pub(crate) trait ProgramInput {
     fn normal() -> Result<InputFn<Self>, Box<dyn Error>>;
     fn incident() -> Result<InputFn<Self>, Box<dyn Error>>;
     fn texture_coordinates() -> Result<InputFn<Self>, Box<dyn Error>>;
+    /// The area around the texture coordinates that texture lookups should
+    /// cover.
+    fn texture_footprint() -> Result<InputFn<Self>, Box<dyn Error>>;
+    fn position() -> Result<InputFn<Self>, Box<dyn Error>>;
+
+    /// Identifies the surface point for inputs that are evaluated for many
+    /// wavelengths at the same point. Number and vector values are reused as
+    /// long as the key stays the same.
+    fn hit_key(&self) -> Option<HitKey> {
+        None
+    }
 }
 
+pub(crate) type HitKey = [u32; 11];
+
 pub struct Program<'p, I, T> {
     instructions: &'p [Instruction<'p, I, T>],
 }
@@ -415,7 +777,9 @@ pub enum Value {
     Number(f32),
     Spectrum(SpectrumId),
     Texture(TextureId),
+    Volume(VolumeId),
     Vector(Vector),
+    Light(Light),
 }
 
 impl Value {
@@ -424,7 +788,9 @@ impl Value {
             Value::Number(number) => number.push(registers),
             Value::Spectrum(spectrum) => spectrum.push(registers),
             Value::Texture(texture) => texture.push(registers),
+            Value::Volume(volume) => volume.push(registers),
             Value::Vector(vector) => vector.push(registers),
+            Value::Light(light) => light.push(registers),
         }
     }
 }
@@ -437,7 +803,7 @@ impl From<f32> for Value {
 
 impl From<Light> for Value {
     fn from(light: Light) -> Self {
-        Value::Number(light.value)
+        Value::Light(light)
     }
 }
 
@@ -465,6 +831,18 @@ impl From<Vector3<f32>> for Value {
     }
 }
 
+impl From<Point3<f32>> for Value {
+    fn from(point: Point3<f32>) -> Self {
+        Value::Vector(point.into())
+    }
+}
+
+impl From<Footprint> for Value {
+    fn from(footprint: Footprint) -> Self {
+        Value::Vector(footprint.into())
+    }
+}
+
 pub(crate) enum AnyProgram<'p, I> {
     Number(Program<'p, I, f32>),
     Vector(Program<'p, I, Vector>),
@@ -500,6 +878,10 @@ impl<'p, I> From<Program<'p, I, Light>> for AnyProgram<'p, I> {
 pub struct ExecutionContext<'p> {
     registers: Registers,
     resources: Resources<'p>,
+    cache_key: Option<HitKey>,
+    /// The results of number and vector programs for the current hit, where
+    /// each program is identified by the address of its instructions.
+    cache: Vec<(usize, Value)>,
 }
 
 impl<'p> ExecutionContext<'p> {
@@ -507,33 +889,68 @@ impl<'p> ExecutionContext<'p> {
         ExecutionContext {
             registers: Registers::new(),
             resources,
+            cache_key: None,
+            cache: Vec::with_capacity(16),
         }
     }
 
     #[inline]
     pub(crate) fn run<I, T>(&mut self, program: Program<'p, I, T>, input: &I) -> T
     where
+        I: ProgramInput,
         T: RegisterValue,
         AnyProgram<'p, I>: From<Program<'p, I, T>>,
     {
         self.registers.clear();
 
+        let key = input.hit_key();
+        if key.is_none() || key != self.cache_key {
+            self.cache.clear();
+        }
+        self.cache_key = key;
+
         self.run_program(program, input);
 
         self.registers.pop()
     }
 
-    fn run_any_program<I>(&mut self, program: AnyProgram<'p, I>, input: &I) {
+    fn run_any_program<I: ProgramInput>(&mut self, program: AnyProgram<'p, I>, input: &I) {
         match program {
-            AnyProgram::Number(program) => self.run_program(program, input),
-            AnyProgram::Vector(program) => self.run_program(program, input),
+            AnyProgram::Number(program) => self.run_cached_program(program, input),
+            AnyProgram::Vector(program) => self.run_cached_program(program, input),
             AnyProgram::Light(program) => self.run_program(program, input),
         }
     }
 
+    /// Runs a program that doesn't depend on the wavelength, or reuses its
+    /// result from an earlier run at the same hit.
+    fn run_cached_program<I, T>(&mut self, program: Program<'p, I, T>, input: &I)
+    where
+        I: ProgramInput,
+        T: RegisterValue + Into<Value>,
+        AnyProgram<'p, I>: From<Program<'p, I, T>>,
+    {
+        if self.cache_key.is_none() {
+            self.run_program(program, input);
+            return;
+        }
+
+        let id = program.instructions.as_ptr() as usize;
+        if let Some(&(_, value)) = self.cache.iter().find(|&&(cached, _)| cached == id) {
+            value.push(&mut self.registers);
+            return;
+        }
+
+        self.run_program(program, input);
+        let result: T = self.registers.pop();
+        result.clone().push(&mut self.registers);
+        self.cache.push((id, result.into()));
+    }
+
     #[inline]
     fn run_program<I, T>(&mut self, program: Program<'p, I, T>, input: &I)
     where
+        I: ProgramInput,
         T: RegisterValue,
         AnyProgram<'p, I>: From<Program<'p, I, T>>,
     {
@@ -561,6 +978,8 @@ pub struct Registers {
     vectors: Vec<Vector>,
     spectra: Vec<SpectrumId>,
     textures: Vec<TextureId>,
+    volumes: Vec<VolumeId>,
+    lights: Vec<Light>,
 }
 
 impl Registers {
@@ -570,6 +989,8 @@ impl Registers {
             vectors: Vec::with_capacity(100),
             spectra: Vec::with_capacity(100),
             textures: Vec::with_capacity(100),
+            volumes: Vec::with_capacity(100),
+            lights: Vec::with_capacity(100),
         }
     }
 
@@ -586,6 +1007,8 @@ impl Registers {
         self.vectors.clear();
         self.spectra.clear();
         self.textures.clear();
+        self.volumes.clear();
+        self.lights.clear();
     }
 }
 
@@ -621,17 +1044,13 @@ impl RegisterValue for Vector {
 
 impl RegisterValue for Light {
     fn push(self, registers: &mut Registers) {
-        registers.numbers.push(self.value);
+        registers.lights.push(self);
     }
     fn get(registers: &Registers, index: usize) -> Self {
-        Light {
-            value: registers.numbers[index],
-        }
+        registers.lights[index]
     }
     fn pop(registers: &mut Registers) -> Self {
-        Light {
-            value: registers.numbers.pop().unwrap(),
-        }
+        registers.lights.pop().unwrap()
     }
 }
 
@@ -658,3 +1077,15 @@ impl RegisterValue for TextureId {
         registers.textures.pop().unwrap()
     }
 }
+
+impl RegisterValue for VolumeId {
+    fn push(self, registers: &mut Registers) {
+        registers.volumes.push(self);
+    }
+    fn get(registers: &Registers, index: usize) -> Self {
+        registers.volumes[index]
+    }
+    fn pop(registers: &mut Registers) -> Self {
+        registers.volumes.pop().unwrap()
+    }
+}
@@ -2,15 +2,24 @@ use std::{
     borrow::Cow,
     collections::{hash_map::Entry, HashMap},
     error::Error,
+    path::{Path, PathBuf},
 };
 
 use super::{
     parse_context::{Parse, ParseContext},
     tables::TableId,
 };
+use palette::LinSrgb;
+
 use crate::{math::utils::Interpolated, parse_enum};
 
-#[derive(Clone)]
+/// The distance between the samples in baked spectra, in nanometers.
+const BAKED_STEP: f32 = 1.0;
+/// How far from a sample, in steps, a curve's point may be and still be
+/// baked.
+const BAKED_TOLERANCE: f32 = 0.001;
+
+#[derive(Clone, PartialEq)]
 pub enum Spectrum {
     Array {
         min: f32,
@@ -44,6 +53,82 @@ impl<'lua> Parse<'lua> for Spectrum {
 }
 
 impl Spectrum {
+    /// Turns a constant RGB color into a single spectrum, so it doesn't have
+    /// to be looked up for each of the primary colors.
+    pub fn from_rgb(color: LinSrgb) -> Self {
+        use crate::rgb::response::{BLUE, GREEN, RED};
+
+        let LinSrgb {
+            red, green, blue, ..
+        } = color;
+
+        Spectrum::tabulate(RED.span(), |wavelength| {
+            red * RED.get(wavelength) + green * GREEN.get(wavelength) + blue * BLUE.get(wavelength)
+        })
+    }
+
+    /// The CIE daylight illuminant with the correlated color `temperature`,
+    /// in kelvin, which is 1 at 560 nm.
+    pub fn daylight(temperature: f32) -> Self {
+        let (x, y) = crate::daylight::chromaticity(temperature);
+        Spectrum::tabulate((300.0, 830.0), |wavelength| {
+            crate::daylight::spectrum(x, y, wavelength)
+        })
+    }
+
+    /// Samples curves into evenly spaced arrays, where each lookup is a
+    /// single interpolation instead of a binary search. Curves with points
+    /// between the samples are kept as they are, since narrow peaks, like the
+    /// lines of fluorescent lights, would be smeared out.
+    pub fn bake(self) -> Self {
+        match self {
+            Spectrum::Curve { ref points } if !points.is_empty() && self.fits_samples() => {
+                let curve = Interpolated { points };
+                Spectrum::tabulate(self.span(), |wavelength| curve.get(wavelength))
+            }
+            spectrum => spectrum,
+        }
+    }
+
+    fn span(&self) -> (f32, f32) {
+        match self {
+            Spectrum::Array { min, max, .. } => (*min, *max),
+            Spectrum::Curve { points } => match (points.first(), points.last()) {
+                (Some(&(min, _)), Some(&(max, _))) => (min, max),
+                _ => (0.0, 0.0),
+            },
+        }
+    }
+
+    /// Checks if every point of a curve is at one of the samples of its
+    /// baked array, so baking doesn't change it.
+    fn fits_samples(&self) -> bool {
+        match self {
+            Spectrum::Array { .. } => true,
+            Spectrum::Curve { points } => {
+                let (min, max) = self.span();
+                let (_, step_size) = sample_steps(min, max);
+                points.iter().all(|&(wavelength, _)| {
+                    let step = (wavelength - min) / step_size;
+                    (step - step.round()).abs() <= BAKED_TOLERANCE
+                })
+            }
+        }
+    }
+
+    fn tabulate(span: (f32, f32), function: impl Fn(f32) -> f32) -> Self {
+        let (min, max) = span;
+        let (steps, step_size) = sample_steps(min, max);
+
+        Spectrum::Array {
+            min,
+            max,
+            points: (0..=steps)
+                .map(|step| function(min + step as f32 * step_size))
+                .collect(),
+        }
+    }
+
     pub fn get(&self, wavelength: f32) -> f32 {
         match self {
             Spectrum::Array { min, max, points } => {
@@ -75,6 +160,14 @@ impl Spectrum {
     }
 }
 
+/// The number of steps between `min` and `max` in a baked spectrum, and the
+/// size of each step, which is at most `BAKED_STEP`.
+fn sample_steps(min: f32, max: f32) -> (usize, f32) {
+    let steps = ((max - min) / BAKED_STEP).ceil().max(1.0) as usize;
+    (steps, (max - min) / steps as f32)
+}
+
+#[derive(PartialEq)]
 pub struct Spectra {
     spectra: Vec<Spectrum>,
 }
@@ -100,14 +193,63 @@ impl Spectra {
 pub struct SpectrumLoader {
     spectra: Spectra,
     table_map: HashMap<TableId, SpectrumId>,
+    project_dir: PathBuf,
 }
 
 impl SpectrumLoader {
-    pub fn new() -> Self {
+    pub fn new(path: impl AsRef<Path>) -> Self {
         SpectrumLoader {
             spectra: Spectra::new(),
             table_map: HashMap::new(),
+            project_dir: path.as_ref().into(),
+        }
+    }
+
+    /// Reads the complex index of refraction of a metal from a CSV file, with
+    /// the columns `wavelength`, `n` and `k`. The wavelengths are in
+    /// nanometers.
+    pub fn load_metal(&self, path: &str) -> Result<(Spectrum, Spectrum), Box<dyn Error>> {
+        let path = self.project_dir.join(path);
+        let file = std::fs::read_to_string(&path)
+            .map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+
+        let mut n = Vec::new();
+        let mut k = Vec::new();
+
+        for (index, line) in file.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let values: Result<Vec<f32>, _> =
+                line.split(',').map(|value| value.trim().parse()).collect();
+            match values.as_ref().map(|values| &values[..]) {
+                Ok(&[wavelength, n_value, k_value]) => {
+                    n.push((wavelength, n_value));
+                    k.push((wavelength, k_value));
+                }
+                // The first line may be a header.
+                Err(_) if index == 0 => {}
+                _ => {
+                    return Err(format!(
+                        "{}, line {}: expected a wavelength, n and k",
+                        path.display(),
+                        index + 1
+                    )
+                    .into())
+                }
+            }
+        }
+
+        if n.is_empty() {
+            return Err(format!("{} has no values", path.display()).into());
         }
+
+        n.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        k.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        Ok((Spectrum::Curve { points: n }, Spectrum::Curve { points: k }))
     }
 
     pub fn get(&self, table_id: TableId) -> Option<SpectrumId> {
@@ -125,7 +267,13 @@ impl SpectrumLoader {
         }
     }
 
-    pub fn into_spectra(self) -> Spectra {
+    /// Bakes the spectra into lookup tables, and finishes loading.
+    pub fn into_spectra(mut self) -> Spectra {
+        for spectrum in &mut self.spectra.spectra {
+            let original = std::mem::replace(spectrum, Spectrum::Curve { points: Vec::new() });
+            *spectrum = original.bake();
+        }
+
         self.spectra
     }
 }
@@ -133,3 +281,30 @@ impl SpectrumLoader {
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[repr(transparent)]
 pub struct SpectrumId(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::Spectrum;
+
+    #[test]
+    fn bake_keeps_narrow_lines() {
+        let line = Spectrum::Curve {
+            points: vec![
+                (400.0, 0.0),
+                (545.0, 0.0),
+                (545.4, 1.0),
+                (545.8, 0.0),
+                (700.0, 0.0),
+            ],
+        };
+        assert!(line.clone().bake() == line);
+        assert_eq!(line.bake().get(545.4), 1.0);
+
+        let smooth = Spectrum::Curve {
+            points: vec![(400.0, 0.0), (500.0, 1.0), (700.0, 0.5)],
+        };
+        let baked = smooth.clone().bake();
+        assert!(matches!(baked, Spectrum::Array { .. }));
+        assert!((baked.get(450.0) - smooth.get(450.0)).abs() < 1e-5);
+    }
+}
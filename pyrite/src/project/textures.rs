@@ -2,18 +2,33 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     error::Error,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
 };
 
-use crate::texture::{ColorEncoding, Texture};
+use collision::Aabb3;
 
+use crate::{
+    merl::MeasuredBrdf,
+    texture::{ColorEncoding, Texture},
+    volume::{RawVolume, Volume},
+};
+
+/// Images, and voxel grids for 3D textures.
 pub struct Textures {
-    textures: Vec<Texture>,
+    textures: Vec<Arc<Texture>>,
+    paths: Vec<PathBuf>,
+    volumes: Vec<Volume>,
+    volume_files: Vec<(PathBuf, Option<RawVolume>)>,
 }
 
 impl Textures {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Textures {
             textures: Vec::new(),
+            paths: Vec::new(),
+            volumes: Vec::new(),
+            volume_files: Vec::new(),
         }
     }
 
@@ -21,26 +36,117 @@ impl Textures {
         self.textures.get(id.0).expect("missing texture")
     }
 
-    fn insert(&mut self, texture: Texture) -> TextureId {
+    pub fn get_volume(&self, id: VolumeId) -> &Volume {
+        self.volumes.get(id.0).expect("missing volume")
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.textures
+            .iter()
+            .map(|texture| texture.memory_size())
+            .sum::<usize>()
+            + self.volumes.iter().map(Volume::memory_size).sum::<usize>()
+    }
+
+    /// Halves the size of each texture that is larger than `min_size` pixels
+    /// along both sides. Returns the number of resized textures.
+    pub fn downscale(&mut self, min_size: usize) -> usize {
+        let mut resized = 0;
+
+        for texture in &mut self.textures {
+            if texture.width() > min_size && texture.height() > min_size {
+                // Cached textures are shared, and must keep their full size.
+                Arc::make_mut(texture).downscale();
+                resized += 1;
+            }
+        }
+
+        resized
+    }
+
+    /// Checks if the same files were loaded, in the same order, so their IDs
+    /// are interchangeable.
+    pub fn has_same_files(&self, other: &Textures) -> bool {
+        self.paths == other.paths && self.volume_files == other.volume_files
+    }
+
+    fn insert(&mut self, texture: Arc<Texture>, path: PathBuf) -> TextureId {
         let id = self.textures.len();
         self.textures.push(texture);
+        self.paths.push(path);
         TextureId(id)
     }
+
+    fn insert_volume(&mut self, volume: Volume, file: (PathBuf, Option<RawVolume>)) -> VolumeId {
+        let id = self.volumes.len();
+        self.volumes.push(volume);
+        self.volume_files.push(file);
+        VolumeId(id)
+    }
+}
+
+/// Loaded textures that are kept between loads of a project, so they don't
+/// have to be read again if their files haven't been modified.
+pub struct TextureCache {
+    max_size: usize,
+    textures: HashMap<PathBuf, CachedTexture>,
+}
+
+impl TextureCache {
+    /// Creates an empty cache that keeps at most `max_size` bytes of
+    /// textures.
+    pub fn new(max_size: usize) -> Self {
+        TextureCache {
+            max_size,
+            textures: HashMap::new(),
+        }
+    }
+
+    /// Takes the cached textures, leaving this cache empty.
+    pub fn take(&mut self) -> TextureCache {
+        TextureCache {
+            max_size: self.max_size,
+            textures: std::mem::take(&mut self.textures),
+        }
+    }
+}
+
+struct CachedTexture {
+    texture: Arc<Texture>,
+    encoding: ColorEncoding,
+    modified: Option<SystemTime>,
 }
 
 pub struct TextureLoader {
     textures: Textures,
     file_map: HashMap<PathBuf, TextureId>,
+    /// The cache from the previous load, and the textures for the next.
+    cache: TextureCache,
+    loaded: Vec<(PathBuf, CachedTexture)>,
+    /// Raw files can be read in more than one way, so they are also
+    /// separated by how they are read.
+    volume_map: HashMap<(PathBuf, Option<RawVolume>), VolumeId>,
+    measured_brdfs: HashMap<PathBuf, Arc<MeasuredBrdf>>,
     project_dir: PathBuf,
 }
 
 impl TextureLoader {
-    pub fn new(path: impl AsRef<Path>) -> Self {
+    /// Creates a loader that reuses the cached textures, unless their files
+    /// have been modified since they were loaded.
+    pub fn new(path: impl AsRef<Path>, cache: TextureCache) -> Self {
         let project_dir = path.as_ref().into();
 
         TextureLoader {
             textures: Textures::new(),
             file_map: HashMap::new(),
+            cache,
+            loaded: Vec::new(),
+            volume_map: HashMap::new(),
+            measured_brdfs: HashMap::new(),
             project_dir,
         }
     }
@@ -55,21 +161,141 @@ impl TextureLoader {
         match self.file_map.entry(path) {
             Entry::Occupied(entry) => Ok(*entry.get()),
             Entry::Vacant(entry) => {
-                let texture = Texture::from_path(entry.key(), encoding).map_err(|error| {
-                    format!("could not load {}: {}", entry.key().display(), error)
-                })?;
-                let id = self.textures.insert(texture);
+                let modified = std::fs::metadata(entry.key())
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+
+                let texture = match self.cache.textures.remove(entry.key()) {
+                    Some(cached) if cached.encoding == encoding && cached.modified == modified => {
+                        cached.texture
+                    }
+                    _ => {
+                        let texture =
+                            Texture::from_path(entry.key(), encoding).map_err(|error| {
+                                format!("could not load {}: {}", entry.key().display(), error)
+                            })?;
+                        Arc::new(texture)
+                    }
+                };
+
+                self.loaded.push((
+                    entry.key().clone(),
+                    CachedTexture {
+                        texture: texture.clone(),
+                        encoding,
+                        modified,
+                    },
+                ));
+                let id = self.textures.insert(texture, entry.key().clone());
                 entry.insert(id);
                 Ok(id)
             }
         }
     }
 
-    pub fn into_textures(self) -> Textures {
-        self.textures
+    /// Loads a voxel grid, which is read as a `.vol` file if `raw` isn't set.
+    pub fn load_volume(
+        &mut self,
+        path: impl AsRef<Path>,
+        raw: Option<RawVolume>,
+    ) -> Result<VolumeId, Box<dyn Error>> {
+        let path = self.project_dir.join(path).canonicalize()?;
+
+        match self.volume_map.entry((path, raw)) {
+            Entry::Occupied(entry) => Ok(*entry.get()),
+            Entry::Vacant(entry) => {
+                let path = &entry.key().0;
+                let volume = Volume::from_path(path, raw)
+                    .map_err(|error| format!("could not load {}: {}", path.display(), error))?;
+                let id = self.textures.insert_volume(volume, entry.key().clone());
+                entry.insert(id);
+                Ok(id)
+            }
+        }
+    }
+
+    /// The bounds that a volume is placed within by default.
+    pub fn volume_bounds(&self, id: VolumeId) -> Aabb3<f32> {
+        self.textures.get_volume(id).bounds()
+    }
+
+    /// Loads a measured BRDF in the MERL format. They are tables, like
+    /// textures, and materials that use the same file share it.
+    pub fn load_measured_brdf(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Arc<MeasuredBrdf>, Box<dyn Error>> {
+        let path = self.project_dir.join(path).canonicalize()?;
+
+        match self.measured_brdfs.entry(path) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let brdf = MeasuredBrdf::load(entry.key()).map_err(|error| {
+                    format!("could not load {}: {}", entry.key().display(), error)
+                })?;
+                Ok(entry.insert(Arc::new(brdf)).clone())
+            }
+        }
+    }
+
+    /// Returns the loaded textures, and a cache with as many of them as fits,
+    /// in the order they were loaded. Textures that weren't used are dropped.
+    pub fn into_textures(self) -> (Textures, TextureCache) {
+        let mut cache = TextureCache::new(self.cache.max_size);
+        let mut size = 0;
+
+        for (path, cached) in self.loaded {
+            let texture_size = cached.texture.memory_size();
+
+            if size + texture_size <= cache.max_size {
+                size += texture_size;
+                cache.textures.insert(path, cached);
+            }
+        }
+
+        (self.textures, cache)
     }
 }
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[repr(transparent)]
 pub struct TextureId(usize);
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct VolumeId(usize);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{TextureCache, TextureLoader};
+    use crate::texture::ColorEncoding;
+
+    #[test]
+    fn cached_textures_are_reused() {
+        let directory =
+            std::env::temp_dir().join(format!("pyrite-texture-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        image::RgbImage::new(4, 4)
+            .save(directory.join("texture.png"))
+            .unwrap();
+
+        let load = |cache| {
+            let mut loader = TextureLoader::new(&directory, cache);
+            let id = loader.load("texture.png", ColorEncoding::Srgb).unwrap();
+            let (textures, cache) = loader.into_textures();
+            (textures.textures[id.0].clone(), cache)
+        };
+
+        let (first, cache) = load(TextureCache::new(1 << 20));
+        let (second, _) = load(cache);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let (first, cache) = load(TextureCache::new(0));
+        let (second, _) = load(cache);
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Values with units, like `"35deg"`, `"2.5cm"` or `"5600K"`, which are
+//! converted to degrees, scene units and kelvin when the project is parsed.
+
+use std::{error::Error, str::FromStr};
+
+#[derive(Copy, Clone)]
+pub enum Quantity {
+    Angle,
+    Length,
+    Temperature,
+}
+
+impl Quantity {
+    fn name(self) -> &'static str {
+        match self {
+            Quantity::Angle => "an angle",
+            Quantity::Length => "a length",
+            Quantity::Temperature => "a temperature",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Units {
+    /// The length of one scene unit, in meters.
+    pub scale: f64,
+}
+
+impl Units {
+    /// Reads the scale from the project's world, before anything else is
+    /// parsed.
+    pub fn from_project(project: &rlua::Table<'_>) -> Result<Self, Box<dyn Error>> {
+        let world: Option<rlua::Table<'_>> = project
+            .get("world")
+            .map_err(|error| format!("world: {}", error))?;
+        let scale: Option<f64> = match world {
+            Some(world) => world
+                .get("scale")
+                .map_err(|error| format!("world: scale: {}", error))?,
+            None => None,
+        };
+
+        Ok(Units {
+            scale: scale.unwrap_or(1.0),
+        })
+    }
+
+    /// Converts a number with a unit. Numbers without units are already in
+    /// degrees, scene units or kelvin.
+    pub fn convert(&self, value: &str, quantity: Quantity) -> Result<f64, Box<dyn Error>> {
+        let value = value.trim();
+
+        // The number is the longest prefix that parses, so exponents, like in
+        // `"1e3 mm"`, aren't mistaken for units.
+        let (number, unit) = value
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain(Some(value.len()))
+            .rev()
+            .find_map(|index| {
+                let number = f32::from_str(value[..index].trim()).ok()?;
+                Some((f64::from(number), value[index..].trim()))
+            })
+            .ok_or_else(|| format!("invalid number: '{}'", value))?;
+
+        let converted = match (quantity, unit) {
+            (_, "") => number,
+            (Quantity::Angle, "deg") | (Quantity::Angle, "°") => number,
+            (Quantity::Angle, "rad") => number.to_degrees(),
+            (Quantity::Length, "m") => number / self.scale,
+            (Quantity::Length, "cm") => number * 0.01 / self.scale,
+            (Quantity::Length, "mm") => number * 0.001 / self.scale,
+            (Quantity::Length, "km") => number * 1000.0 / self.scale,
+            (Quantity::Length, "in") => number * 0.0254 / self.scale,
+            (Quantity::Length, "ft") => number * 0.3048 / self.scale,
+            (Quantity::Temperature, "K") => number,
+            (Quantity::Temperature, "°C") | (Quantity::Temperature, "C") => number + 273.15,
+            (quantity, unit) => {
+                return Err(format!(
+                    "expected {}, but found the unit '{}'",
+                    quantity.name(),
+                    unit
+                )
+                .into())
+            }
+        };
+
+        if !converted.is_finite() {
+            return Err(format!("invalid number: '{}'", value).into());
+        }
+
+        Ok(converted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quantity, Units};
+
+    #[test]
+    fn convert_numbers_with_units() {
+        let units = Units { scale: 0.01 };
+
+        let convert = |value| units.convert(value, Quantity::Length).unwrap();
+        assert!((convert("1e3 mm") - 100.0).abs() < 1e-4);
+        assert!((convert("2.5cm") - 2.5).abs() < 1e-4);
+        assert!((convert("-1.5E-2m") + 1.5).abs() < 1e-4);
+        assert!((convert("7") - 7.0).abs() < 1e-4);
+
+        assert!(units.convert("1e", Quantity::Length).is_err());
+        assert!(units.convert("cm", Quantity::Length).is_err());
+        assert!(units.convert("5deg", Quantity::Length).is_err());
+    }
+}
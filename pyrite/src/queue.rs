@@ -0,0 +1,265 @@
+use std::{
+    convert::TryFrom,
+    error::Error,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use toml::{value::Table, Value};
+
+use crate::{cli, priority};
+
+struct QueueFile {
+    /// The default number of retries for each job.
+    retries: u32,
+    jobs: Vec<JobEntry>,
+}
+
+impl QueueFile {
+    fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let mut table: Table = toml::from_str(content)?;
+
+        let retries = take(&mut table, "retries", as_u32, "a positive number")?.unwrap_or(0);
+        let jobs = take(&mut table, "job", as_tables, "an array of tables")?
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(index, job)| {
+                JobEntry::parse(job).map_err(|error| format!("job[{}]: {}", index, error).into())
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        reject_remaining(&table)?;
+
+        Ok(QueueFile { retries, jobs })
+    }
+}
+
+struct JobEntry {
+    project: String,
+    name: Option<String>,
+    priority: i32,
+    retries: Option<u32>,
+    /// Command line options for this job, like `["--frame", "1..24"]`.
+    options: Vec<String>,
+}
+
+impl JobEntry {
+    fn parse(mut table: Table) -> Result<Self, Box<dyn Error>> {
+        let entry = JobEntry {
+            project: take(&mut table, "project", as_string, "a path")?
+                .ok_or("missing field: 'project'")?,
+            name: take(&mut table, "name", as_string, "a string")?,
+            priority: take(&mut table, "priority", as_i32, "a number")?.unwrap_or(0),
+            retries: take(&mut table, "retries", as_u32, "a positive number")?,
+            options: take(&mut table, "options", as_strings, "an array of strings")?
+                .unwrap_or_default(),
+        };
+        reject_remaining(&table)?;
+
+        Ok(entry)
+    }
+}
+
+/// Removes `key` from `table` and converts its value, if it's set.
+fn take<T>(
+    table: &mut Table,
+    key: &str,
+    convert: fn(Value) -> Option<T>,
+    expected: &str,
+) -> Result<Option<T>, Box<dyn Error>> {
+    match table.remove(key) {
+        Some(value) => match convert(value) {
+            Some(value) => Ok(Some(value)),
+            None => Err(format!("{}: expected {}", key, expected).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Fails if any keys are left in `table`, after the known ones were taken.
+fn reject_remaining(table: &Table) -> Result<(), Box<dyn Error>> {
+    match table.keys().next() {
+        Some(key) => Err(format!("unknown field: '{}'", key).into()),
+        None => Ok(()),
+    }
+}
+
+fn as_u32(value: Value) -> Option<u32> {
+    value
+        .as_integer()
+        .and_then(|value| u32::try_from(value).ok())
+}
+
+fn as_i32(value: Value) -> Option<i32> {
+    value
+        .as_integer()
+        .and_then(|value| i32::try_from(value).ok())
+}
+
+fn as_string(value: Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn as_strings(value: Value) -> Option<Vec<String>> {
+    match value {
+        Value::Array(values) => values.into_iter().map(as_string).collect(),
+        _ => None,
+    }
+}
+
+fn as_tables(value: Value) -> Option<Vec<Table>> {
+    match value {
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::Table(table) => Some(table),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+pub(crate) struct Job {
+    name: String,
+    options: cli::Options,
+    retries: u32,
+}
+
+/// Loads a queue file and sorts its jobs by priority. Jobs with the same
+/// priority keep their order from the file.
+pub(crate) fn load(path: &Path) -> Result<Vec<Job>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let queue = QueueFile::parse(&content)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut jobs = Vec::with_capacity(queue.jobs.len());
+
+    for (index, entry) in queue.jobs.into_iter().enumerate() {
+        let name = match entry.name {
+            Some(name) => name,
+            None => format!("{} ({})", entry.project, index + 1),
+        };
+
+        // Project paths are relative to the queue file.
+        let project_path = directory.join(&entry.project);
+        let args = entry
+            .options
+            .into_iter()
+            .chain(Some(project_path.to_string_lossy().into_owned()));
+        let options =
+            cli::Options::parse(args).map_err(|error| format!("job '{}': {}", name, error))?;
+
+        if options.watch {
+            return Err(format!("job '{}': --watch can't be used in a queue", name).into());
+        }
+
+        jobs.push((
+            entry.priority,
+            Job {
+                name,
+                options,
+                retries: entry.retries.unwrap_or(queue.retries),
+            },
+        ));
+    }
+
+    jobs.sort_by_key(|&(priority, _)| std::cmp::Reverse(priority));
+
+    Ok(jobs.into_iter().map(|(_, job)| job).collect())
+}
+
+struct JobResult {
+    name: String,
+    attempts: u32,
+    duration: Duration,
+    error: Option<Box<dyn Error>>,
+}
+
+/// Renders each job in order, retrying failed jobs, and prints a summary at
+/// the end. Returns `false` if any of the jobs failed.
+pub(crate) fn run(
+    jobs: Vec<Job>,
+    mut render: impl FnMut(&cli::Options) -> Result<(), Box<dyn Error>>,
+) -> bool {
+    let job_count = jobs.len();
+    let mut results = Vec::with_capacity(job_count);
+    let mut lowered_priority = false;
+
+    for (index, job) in jobs.into_iter().enumerate() {
+        // The priority can't be raised again, so it stays low for the rest
+        // of the queue.
+        if job.options.low_priority && !lowered_priority {
+            if let Err(error) = priority::lower_priority() {
+                eprintln!("warning: could not lower the priority: {}", error);
+            }
+            lowered_priority = true;
+        }
+
+        let start = Instant::now();
+        let mut attempts = 0;
+
+        let error = loop {
+            attempts += 1;
+            println!(
+                "job {} of {}: {} (attempt {} of {})",
+                index + 1,
+                job_count,
+                job.name,
+                attempts,
+                job.retries + 1
+            );
+
+            match render(&job.options) {
+                Ok(()) => break None,
+                Err(error) => {
+                    eprintln!("job '{}' failed: {}", job.name, error);
+
+                    if attempts > job.retries {
+                        break Some(error);
+                    }
+                }
+            }
+        };
+
+        results.push(JobResult {
+            name: job.name,
+            attempts,
+            duration: start.elapsed(),
+            error,
+        });
+    }
+
+    print_summary(&results);
+
+    results.iter().all(|result| result.error.is_none())
+}
+
+fn print_summary(results: &[JobResult]) {
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+
+    println!();
+    println!(
+        "queue summary: {} of {} jobs finished",
+        results.len() - failed,
+        results.len()
+    );
+
+    for result in results {
+        let status = match result.error {
+            Some(ref error) => format!("failed: {}", error),
+            None => "done".to_owned(),
+        };
+
+        println!(
+            "  {}: {} after {} attempt(s) in {:.1?}",
+            result.name, status, result.attempts, result.duration
+        );
+    }
+}
@@ -1,20 +1,27 @@
-use std::cmp::Ordering;
+use std::{
+    any::Any,
+    cmp::Ordering,
+    panic::{self, AssertUnwindSafe},
+};
 
 use cgmath::{EuclideanSpace, InnerSpace, Point2, Vector2};
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
 
 use crate::cameras::Camera;
-use crate::film::{Area, Sample};
+use crate::film::{Area, Film, Sample, SamplerState};
 use crate::{
+    color::{Wavelengths, LANES},
     project::program::ExecutionContext,
     tracer::{self, Bounce, BounceType, RenderContext},
 };
 
+/// Adds the light from a bounce to each sample and its reflectance. The
+/// colors are evaluated for up to `LANES` samples at a time.
 pub(crate) fn contribute<'a>(
     bounce: &Bounce<'a>,
-    sample: &mut Sample,
-    reflectance: &mut f32,
+    samples: &mut [(Sample, f32)],
     require_white: bool,
     exe: &mut ExecutionContext<'a>,
 ) -> bool {
@@ -23,9 +30,10 @@ pub(crate) fn contribute<'a>(
         ref light,
         color,
         incident,
-        position: _,
+        position,
         normal,
         texture,
+        footprint,
         probability,
         ref direct_light,
     } = bounce;
@@ -34,50 +42,76 @@ pub(crate) fn contribute<'a>(
         return false;
     }
 
-    let context = RenderContext {
-        wavelength: sample.wavelength,
-        incident,
-        normal,
-        texture,
-    };
+    for samples in samples.chunks_mut(LANES) {
+        // Fluorescence moves the rest of the path to another wavelength.
+        let wavelengths: Wavelengths = samples
+            .iter()
+            .map(|(sample, _)| light.shifted_wavelength().unwrap_or(sample.wavelength))
+            .collect();
+        let context = RenderContext {
+            wavelengths,
+            incident,
+            normal,
+            texture,
+            footprint,
+            position,
+        };
 
-    let c = exe.run(color, &context).value * probability;
+        let c = exe.run(color, &context);
 
-    if let BounceType::Emission = *ty {
-        sample.brightness += c * *reflectance;
-    } else {
-        *reflectance *= c;
-
-        for direct in direct_light {
-            let &tracer::DirectLight {
-                light: ref l_light,
-                color: l_color,
-                incident: l_incident,
-                normal: l_normal,
-                probability: l_probability,
-            } = direct;
-
-            if l_light.is_white() || !require_white {
-                let context = RenderContext {
-                    wavelength: sample.wavelength,
+        if let BounceType::Emission = *ty {
+            for ((sample, reflectance), c) in samples.iter_mut().zip(&c.values) {
+                sample.brightness += c * probability * *reflectance;
+            }
+        } else {
+            for ((_, reflectance), c) in samples.iter_mut().zip(&c.values) {
+                *reflectance *= c * probability;
+            }
+
+            for direct in direct_light {
+                let &tracer::DirectLight {
+                    light: ref l_light,
+                    color: l_color,
                     incident: l_incident,
                     normal: l_normal,
-                    texture,
-                };
+                    probability: l_probability,
+                } = direct;
+
+                if l_light.is_white() || !require_white {
+                    let context = RenderContext {
+                        wavelengths: match l_light.shifted_wavelength() {
+                            Some(wavelength) => samples.iter().map(|_| wavelength).collect(),
+                            None => wavelengths,
+                        },
+                        incident: l_incident,
+                        normal: l_normal,
+                        texture,
+                        footprint,
+                        position,
+                    };
 
-                let l_c = exe.run(l_color, &context).value * l_probability;
-                sample.brightness += l_c * *reflectance;
+                    let l_c = exe.run(l_color, &context);
+                    for ((sample, reflectance), l_c) in samples.iter_mut().zip(&l_c.values) {
+                        sample.brightness += l_c * l_probability * *reflectance;
+                    }
+                }
             }
-        }
 
-        *reflectance *= ty.brdf(incident, normal);
+            let brdf = ty.brdf(incident, normal);
+            for (_, reflectance) in samples {
+                *reflectance *= brdf;
+            }
+        }
     }
 
     true
 }
 
+#[derive(Clone)]
 pub struct Tile {
     pub area: Area<f32>,
+    /// The top left pixel.
+    pub position: Point2<usize>,
     pub width: usize,
     pub height: usize,
 }
@@ -126,19 +160,16 @@ impl Ord for Tile {
 }
 impl Eq for Tile {}
 
-pub(crate) fn make_tiles(
-    film_width: usize,
-    film_height: usize,
-    tile_size: usize,
-    camera: &Camera,
-) -> Vec<Tile> {
-    let mut tiles_x = film_width / tile_size;
-    if tiles_x * tile_size < film_width {
+pub(crate) fn make_tiles(film: &Film, tile_size: usize, camera: &Camera) -> Vec<Tile> {
+    let region = film.region();
+
+    let mut tiles_x = region.size.x / tile_size;
+    if tiles_x * tile_size < region.size.x {
         tiles_x += 1;
     }
 
-    let mut tiles_y = film_height / tile_size;
-    if tiles_y * tile_size < film_height {
+    let mut tiles_y = region.size.y / tile_size;
+    if tiles_y * tile_size < region.size.y {
         tiles_y += 1;
     }
 
@@ -146,13 +177,15 @@ pub(crate) fn make_tiles(
 
     for y in 0..tiles_y {
         for x in 0..tiles_x {
-            let start = Point2::new(x * tile_size, y * tile_size);
+            let offset = Vector2::new(x * tile_size, y * tile_size);
+            let start = region.from + offset;
             let size = Vector2::new(
-                (film_width - start.x).min(tile_size),
-                (film_height - start.y).min(tile_size),
+                (region.size.x - offset.x).min(tile_size),
+                (region.size.y - offset.y).min(tile_size),
             );
             tiles.push(Tile {
-                area: camera.to_view_area(&Area::new(start, size), film_width, film_height),
+                area: camera.to_view_area(&Area::new(start, size), film.width(), film.height()),
+                position: start,
                 width: size.x,
                 height: size.y,
             });
@@ -163,3 +196,69 @@ pub(crate) fn make_tiles(
 
     tiles
 }
+
+/// The random number generator for a tile in the next pass over the film.
+/// It's seeded from the film's sampler state, so resuming a render from a
+/// saved film continues its random sequences, instead of repeating them.
+/// Renderers that make several passes in one go have to advance the state
+/// between them.
+pub(crate) fn tile_rng(film: &Film, tile_index: usize) -> XorShiftRng {
+    let SamplerState { seed, samples } = film.sampler_state();
+    XorShiftRng::seed_from_u64(seed ^ (u64::from(samples) << 32) ^ tile_index as u64)
+}
+
+/// Runs `render` for the tile, and keeps the render going if it panics. The
+/// tile is tried once more, with a new random number generator, and skipped
+/// if it panics again. Returns `None` if it was skipped. Panics aren't caught
+/// if `strict` is set.
+pub(crate) fn isolate_tile<R, U>(
+    tile: &Tile,
+    strict: bool,
+    rng: R,
+    gen_rng: fn() -> R,
+    render: impl Fn(R, &Tile) -> U,
+) -> Option<U> {
+    if strict {
+        return Some(render(rng, tile));
+    }
+
+    let attempt = |rng| panic::catch_unwind(AssertUnwindSafe(|| render(rng, tile)));
+
+    let error = match attempt(rng) {
+        Ok(result) => return Some(result),
+        Err(error) => error,
+    };
+    println!(
+        "\rwarning: the {}x{} tile at {}, {} panicked ({}), trying again",
+        tile.width,
+        tile.height,
+        tile.position.x,
+        tile.position.y,
+        panic_message(&*error)
+    );
+
+    match attempt(gen_rng()) {
+        Ok(result) => Some(result),
+        Err(error) => {
+            println!(
+                "\rwarning: the {}x{} tile at {}, {} panicked again ({}), skipping it",
+                tile.width,
+                tile.height,
+                tile.position.x,
+                tile.position.y,
+                panic_message(&*error)
+            );
+            None
+        }
+    }
+}
+
+fn panic_message(error: &(dyn Any + Send)) -> &str {
+    if let Some(message) = error.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = error.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown error"
+    }
+}
@@ -4,20 +4,21 @@ use rand_xorshift::XorShiftRng;
 use cgmath::{EuclideanSpace, InnerSpace, Point2, Vector3};
 use collision::Ray3;
 
-use super::algorithm::{make_tiles, Tile};
+use super::algorithm::{isolate_tile, make_tiles, tile_rng, Tile};
 use crate::cameras::Camera;
 use crate::film::{Film, Sample};
 use crate::lamp::{RaySample, Surface};
 use crate::renderer::algorithm::contribute;
 use crate::renderer::{Renderer, Status, WorkPool};
-use crate::tracer::{trace, Bounce, BounceType, Light};
+use crate::tracer::{hits_surface, trace, Bounce, BounceType, Light};
 use crate::utils::pairs;
 use crate::{
-    math::DIST_EPSILON,
     project::program::{ExecutionContext, Resources},
+    texture::Footprint,
     world::World,
 };
 
+#[derive(Clone)]
 pub struct BidirParams {
     pub bounces: u32,
 }
@@ -36,7 +37,7 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
         XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
     }
 
-    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+    let tiles = make_tiles(film, renderer.tile_size, camera);
 
     let status_message = "rendering";
     on_status(Status {
@@ -48,9 +49,23 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
     let num_tiles = tiles.len();
 
     workers.do_work(
-        tiles.into_iter().map(|f| (f, gen_rng())),
+        tiles
+            .into_iter()
+            .enumerate()
+            .map(|(index, tile)| (tile, tile_rng(film, index))),
         |(tile, rng)| {
-            render_tile(rng, tile, film, camera, world, resources, renderer, config);
+            isolate_tile(&tile, renderer.strict, rng, gen_rng, |rng, tile| {
+                render_tile(
+                    rng,
+                    tile.clone(),
+                    film,
+                    camera,
+                    world,
+                    resources,
+                    renderer,
+                    config,
+                )
+            });
         },
         |_, _| {
             progress += 1;
@@ -75,6 +90,7 @@ fn render_tile<R: Rng>(
     let mut lamp_path = Vec::with_capacity(bidir_params.bounces as usize + 1);
     let mut camera_path = Vec::with_capacity(renderer.bounces as usize);
     let mut exe = ExecutionContext::new(resources);
+    let pixel_spread = camera.pixel_spread(film.width(), film.height());
 
     for _ in 0..(tile.area() * renderer.pixel_samples as usize) {
         lamp_path.clear();
@@ -98,16 +114,25 @@ fn render_tile<R: Rng>(
             let mut light = light.clone();
             let (color, normal, texture) = match surface {
                 Surface::Physical {
+                    position,
                     normal,
                     material,
                     texture,
                 } => {
-                    let color = material.get_emission(&mut light, -ray.direction, normal, &mut rng);
+                    let color = world.materials.get(material).get_emission(
+                        &mut light,
+                        -ray.direction,
+                        position,
+                        normal,
+                        texture,
+                        &mut rng,
+                        &mut exe,
+                    );
                     (color, normal, texture)
                 }
                 Surface::Color(color) => (Some(color), ray.direction, Point2::origin()),
             };
-            ray.origin += normal * DIST_EPSILON;
+            ray.origin += normal * world.ray_epsilon(ray.origin, 0.0);
 
             if let Some(color) = color {
                 lamp_path.push(Bounce {
@@ -118,6 +143,7 @@ fn render_tile<R: Rng>(
                     position: ray.origin,
                     normal,
                     texture,
+                    footprint: Footprint::default(),
                     probability: weight / probability,
                     direct_light: vec![],
                 });
@@ -130,6 +156,8 @@ fn render_tile<R: Rng>(
                     world,
                     bidir_params.bounces,
                     0,
+                    renderer.cutoffs,
+                    0.0,
                     &mut exe,
                 );
 
@@ -162,9 +190,17 @@ fn render_tile<R: Rng>(
             world,
             renderer.bounces,
             renderer.light_samples,
+            renderer.cutoffs,
+            pixel_spread,
             &mut exe,
         );
 
+        let covered = hits_surface(&camera_path);
+        film.expose_coverage(position, covered);
+        if film.holdout() && !covered {
+            camera_path.clear();
+        }
+
         let total = (camera_path.len() * lamp_path.len()) as f32;
         let weight = 1.0 / total;
 
@@ -192,15 +228,14 @@ fn render_tile<R: Rng>(
             .collect();
 
         for bounce in camera_path.drain(..) {
-            for &mut (ref mut sample, ref mut reflectance) in &mut additional_samples {
-                used_additional =
-                    contribute(&bounce, sample, reflectance, true, &mut exe) && used_additional;
-            }
-
-            {
-                let (ref mut sample, ref mut reflectance) = main_sample;
-                contribute(&bounce, sample, reflectance, false, &mut exe);
-            }
+            used_additional =
+                contribute(&bounce, &mut additional_samples, true, &mut exe) && used_additional;
+            contribute(
+                &bounce,
+                std::slice::from_mut(&mut main_sample),
+                false,
+                &mut exe,
+            );
 
             for mut contribution in connect_paths(
                 &bounce,
@@ -251,19 +286,21 @@ fn render_tile<R: Rng>(
                     }
 
                     for (i, bounce) in lamp_path[i..].iter().enumerate() {
-                        for &mut (ref mut sample, ref mut reflectance) in &mut additional_samples {
-                            used_additional =
-                                contribute(bounce, sample, reflectance, true, &mut exe)
-                                    && used_additional;
-                            if i == 0 {
-                                *reflectance *= brdf_in;
-                            }
-                        }
+                        used_additional =
+                            contribute(bounce, &mut additional_samples, true, &mut exe)
+                                && used_additional;
+                        contribute(
+                            bounce,
+                            std::slice::from_mut(&mut main_sample),
+                            false,
+                            &mut exe,
+                        );
 
-                        let (ref mut sample, ref mut reflectance) = main_sample;
-                        contribute(bounce, sample, reflectance, false, &mut exe);
                         if i == 0 {
-                            *reflectance *= brdf_in;
+                            for (_, reflectance) in &mut additional_samples {
+                                *reflectance *= brdf_in;
+                            }
+                            main_sample.1 *= brdf_in;
                         }
                     }
 
@@ -318,7 +355,7 @@ fn connect_paths<'a>(
 
         let hit = world.intersect(ray).map(|hit| hit.distance);
         if let Some(dist) = hit {
-            if dist < distance - DIST_EPSILON {
+            if dist < distance - world.ray_epsilon(lamp_bounce.position, distance) {
                 continue;
             }
         }
@@ -344,18 +381,14 @@ fn connect_paths<'a>(
         main.1 *= scale;
 
         for (i, bounce) in path[i..].iter().enumerate() {
-            for &mut (ref mut sample, ref mut reflectance) in &mut additional {
-                use_additional =
-                    contribute(bounce, sample, reflectance, true, exe) && use_additional;
-                if i == 0 {
-                    *reflectance *= brdf_in;
-                }
-            }
+            use_additional = contribute(bounce, &mut additional, true, exe) && use_additional;
+            contribute(bounce, std::slice::from_mut(&mut main), false, exe);
 
-            let (ref mut sample, ref mut reflectance) = main;
-            contribute(bounce, sample, reflectance, false, exe);
             if i == 0 {
-                *reflectance *= brdf_in;
+                for (_, reflectance) in &mut additional {
+                    *reflectance *= brdf_in;
+                }
+                main.1 *= brdf_in;
             }
         }
 
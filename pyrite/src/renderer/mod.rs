@@ -1,35 +1,65 @@
+use std::{error::Error, sync::Arc, time::Instant};
+
 use num_cpus;
 
 use crate::cameras;
 use crate::world;
 
-use crate::{film::Film, project::program::Resources};
+use crate::{
+    film::{Film, SamplerState},
+    priority::Throttle,
+    project::program::Resources,
+    tracer::Cutoffs,
+};
 
 mod algorithm;
 mod bidirectional;
 mod photon_mapping;
+pub(crate) mod pixel_trace;
+mod preview;
 mod simple;
 
+pub(crate) use photon_mapping::PhotonMaps;
+
 static DEFAULT_SPECTRUM_SPAN: (f32, f32) = (380.0, 780.0);
+const DEFAULT_TILE_SIZE: usize = 32;
 
+/// The tile sizes that are measured when the tile size is `"auto"`.
+const TILE_SIZE_CANDIDATES: &[usize] = &[16, 32, 64, 128];
+
+#[derive(Clone)]
 pub struct Renderer {
     pub threads: usize,
     bounces: u32,
     pixel_samples: u32,
+    /// The total number of samples per pixel after each pass.
+    sample_schedule: Vec<u32>,
     light_samples: usize,
     spectrum_samples: u32,
     pub spectrum_bins: usize,
     pub spectrum_span: (f32, f32),
     pub tile_size: usize,
+    /// Measure the candidate tile sizes before rendering.
+    auto_tile_size: bool,
+    /// Let panics in tiles stop the render, instead of skipping the tile.
+    pub strict: bool,
+    /// Limits for ending paths early, that materials can override.
+    cutoffs: Cutoffs,
     algorithm: Algorithm,
 }
 
 impl Renderer {
     pub fn from_project(project: crate::project::Renderer) -> Self {
         match project {
-            crate::project::Renderer::Simple { shared } => {
-                Self::from_shared(shared, Algorithm::Simple)
-            }
+            crate::project::Renderer::Simple {
+                shared,
+                max_spectrum_samples,
+            } => Self::from_shared(
+                shared,
+                Algorithm::Simple(simple::Config {
+                    max_spectrum_samples,
+                }),
+            ),
             crate::project::Renderer::Bidirectional {
                 shared,
                 light_bounces,
@@ -54,50 +84,237 @@ impl Renderer {
                     photon_passes: photon_passes.unwrap_or(1),
                 }),
             ),
+            crate::project::Renderer::Preview {
+                mut shared,
+                ambient_occlusion,
+                spherical_harmonics,
+            } => {
+                shared.bounces = shared.bounces.or(Some(preview::BOUNCES));
+                shared.light_samples = shared.light_samples.or(Some(preview::LIGHT_SAMPLES));
+                shared.spectrum_samples =
+                    shared.spectrum_samples.or(Some(preview::SPECTRUM_SAMPLES));
+                shared.spectrum_resolution = shared
+                    .spectrum_resolution
+                    .or(Some(preview::SPECTRUM_RESOLUTION));
+
+                Self::from_shared(
+                    shared,
+                    Algorithm::Preview(preview::Config {
+                        ambient_occlusion,
+                        spherical_harmonics: spherical_harmonics.unwrap_or(false),
+                    }),
+                )
+            }
+        }
+    }
+
+    /// A faster and simplified version of the renderer, for showing changes
+    /// before they are rendered in full. Returns `None` if it's already a
+    /// preview renderer.
+    pub fn preview(&self) -> Option<Self> {
+        if let Algorithm::Preview(_) = self.algorithm {
+            return None;
         }
+
+        Some(Self {
+            threads: self.threads,
+            bounces: self.bounces.min(preview::BOUNCES),
+            pixel_samples: self.pixel_samples.min(preview::PIXEL_SAMPLES),
+            sample_schedule: vec![self.pixel_samples.min(preview::PIXEL_SAMPLES)],
+            light_samples: self.light_samples.min(preview::LIGHT_SAMPLES),
+            spectrum_samples: self.spectrum_samples.min(preview::SPECTRUM_SAMPLES),
+            spectrum_bins: self.spectrum_bins.min(preview::SPECTRUM_RESOLUTION),
+            spectrum_span: self.spectrum_span,
+            tile_size: self.tile_size,
+            auto_tile_size: false,
+            strict: self.strict,
+            cutoffs: self.cutoffs,
+            algorithm: Algorithm::Preview(preview::Config::default()),
+        })
     }
 
     fn from_shared(shared: crate::project::RendererShared, algorithm: Algorithm) -> Self {
+        let (tile_size, auto_tile_size) = match shared.tile_size {
+            Some(crate::project::TileSize::Fixed(size)) => (size, false),
+            Some(crate::project::TileSize::Auto) => (DEFAULT_TILE_SIZE, true),
+            None => (DEFAULT_TILE_SIZE, false),
+        };
+
         Self {
-            threads: shared.threads.unwrap_or_else(|| num_cpus::get()),
+            threads: shared.threads.unwrap_or_else(default_threads),
             bounces: shared.bounces.unwrap_or(8),
-            pixel_samples: shared.pixel_samples,
+            pixel_samples: *shared
+                .pixel_samples
+                .last()
+                .expect("the sample schedule is empty"),
+            sample_schedule: shared.pixel_samples,
             light_samples: shared.light_samples.unwrap_or(4),
             spectrum_samples: shared.spectrum_samples.unwrap_or(10),
             spectrum_bins: shared.spectrum_resolution.unwrap_or(64),
             spectrum_span: DEFAULT_SPECTRUM_SPAN,
-            tile_size: shared.tile_size.unwrap_or(32),
+            tile_size,
+            auto_tile_size,
+            strict: false,
+            cutoffs: Cutoffs {
+                throughput: shared.throughput_cutoff.unwrap_or(0.0),
+                max_distance: shared.max_distance.unwrap_or(std::f32::INFINITY),
+            },
             algorithm,
         }
     }
 
-    pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
+    /// Splits the renderer into one renderer for each pass of the sample
+    /// schedule, that renders the samples that are added in that pass.
+    pub fn passes(&self) -> Vec<Renderer> {
+        let mut previous = 0;
+
+        self.sample_schedule
+            .iter()
+            .map(|&samples| {
+                let pass = Renderer {
+                    pixel_samples: samples - previous,
+                    sample_schedule: vec![samples - previous],
+                    ..self.clone()
+                };
+                previous = samples;
+                pass
+            })
+            .collect()
+    }
+
+    pub fn pixel_samples(&self) -> u32 {
+        self.pixel_samples
+    }
+
+    pub fn auto_tile_size(&self) -> bool {
+        self.auto_tile_size
+    }
+
+    /// The same renderer, but with only `samples` samples per pixel left in
+    /// its pass.
+    pub fn with_pixel_samples(&self, samples: u32) -> Renderer {
+        Renderer {
+            pixel_samples: samples,
+            sample_schedule: vec![samples],
+            ..self.clone()
+        }
+    }
+
+    /// Renders one sample per pixel of the first pass with each candidate
+    /// tile size, and returns a renderer that uses the fastest of them. The
+    /// samples go into the film and its sampler state is advanced, so they
+    /// count towards the pass. Sizes that would split the image into the same
+    /// tiles as a smaller size are skipped, and no more sizes are measured
+    /// than the pass has samples.
+    pub(crate) fn tune_tile_size<W: WorkPool, F: FnMut(Status<'_>)>(
         &self,
         film: &Film,
         workers: &mut W,
-        on_status: F,
+        mut on_status: F,
         camera: &cameras::Camera,
         world: &world::World,
         resources: Resources,
+    ) -> Renderer {
+        let region = film.region();
+        let largest = region.size.x.max(region.size.y);
+        let first_pass = self.sample_schedule.first().copied().unwrap_or(0);
+        let mut fastest: Option<(usize, f64)> = None;
+
+        for &tile_size in TILE_SIZE_CANDIDATES {
+            if fastest.is_some() && tile_size / 2 >= largest {
+                break;
+            }
+
+            let sampler = film.sampler_state();
+            if sampler.samples >= first_pass {
+                break;
+            }
+
+            let benchmark = Renderer {
+                pixel_samples: 1,
+                sample_schedule: vec![1],
+                tile_size,
+                auto_tile_size: false,
+                ..self.clone()
+            };
+
+            let message = format!("measuring tile size {}", tile_size);
+            let start = Instant::now();
+            benchmark.render(
+                film,
+                workers,
+                |status| {
+                    on_status(Status {
+                        progress: status.progress,
+                        message: &message,
+                    })
+                },
+                camera,
+                world,
+                resources,
+                &mut PhotonMaps::default(),
+            );
+            let time = start.elapsed().as_secs_f64();
+            film.set_sampler_state(SamplerState {
+                samples: sampler.samples + 1,
+                ..sampler
+            });
+
+            if fastest.map_or(true, |(_, fastest_time)| time < fastest_time) {
+                fastest = Some((tile_size, time));
+            }
+        }
+
+        Renderer {
+            tile_size: fastest.map_or(self.tile_size, |(tile_size, _)| tile_size),
+            auto_tile_size: false,
+            ..self.clone()
+        }
+    }
+
+    /// Renders the film. The photon maps are kept between calls, for
+    /// algorithms that use them.
+    pub(crate) fn render<'a, W: WorkPool, F: FnMut(Status<'_>)>(
+        &self,
+        film: &Film,
+        workers: &mut W,
+        on_status: F,
+        camera: &cameras::Camera,
+        world: &'a world::World<'a>,
+        resources: Resources<'a>,
+        photon_maps: &mut PhotonMaps<'a>,
     ) {
         match self.algorithm {
-            Algorithm::Simple => {
-                simple::render(film, workers, on_status, self, world, camera, resources)
-            }
+            Algorithm::Simple(ref config) => simple::render(
+                film, workers, on_status, self, config, world, camera, resources,
+            ),
             Algorithm::Bidirectional(ref config) => bidirectional::render(
                 film, workers, on_status, self, config, world, camera, resources,
             ),
             Algorithm::PhotonMapping(ref config) => photon_mapping::render(
+                film,
+                workers,
+                on_status,
+                self,
+                config,
+                world,
+                camera,
+                resources,
+                photon_maps,
+            ),
+            Algorithm::Preview(ref config) => preview::render(
                 film, workers, on_status, self, config, world, camera, resources,
             ),
         }
     }
 }
 
+#[derive(Clone)]
 pub enum Algorithm {
-    Simple,
+    Simple(simple::Config),
     Bidirectional(bidirectional::BidirParams),
     PhotonMapping(photon_mapping::Config),
+    Preview(preview::Config),
 }
 
 pub trait WorkPool {
@@ -113,7 +330,40 @@ pub trait WorkPool {
         R: FnMut(usize, U);
 }
 
-pub struct RayonPool;
+/// The number of physical cores, since hyper-threading (SMT) doesn't help much
+/// with rendering and mostly adds contention.
+pub fn default_threads() -> usize {
+    num_cpus::get_physical().max(1)
+}
+
+/// Runs all parallel work in one shared thread pool, so nested parallel
+/// phases don't create more threads than requested.
+#[derive(Clone)]
+pub struct RayonPool {
+    pool: Arc<rayon::ThreadPool>,
+    throttle: Option<Throttle>,
+}
+
+impl RayonPool {
+    /// `cpu_limit` is an optional percentage of CPU time that each thread
+    /// may use.
+    pub fn new(threads: usize, cpu_limit: Option<u32>) -> Result<Self, Box<dyn Error>> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|index| format!("pyrite worker {}", index))
+            .build()?;
+
+        Ok(RayonPool {
+            pool: Arc::new(pool),
+            throttle: cpu_limit.map(Throttle::new),
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+}
+
 impl WorkPool for RayonPool {
     fn do_work<I, T, U, W, R>(&mut self, work: I, worker: W, mut with_result: R)
     where
@@ -130,12 +380,23 @@ impl WorkPool for RayonPool {
 
         let (sender, receiver) = crossbeam::channel::unbounded();
 
+        let pool = &self.pool;
+        let throttle = &self.throttle;
+
         crossbeam::scope(|scope| {
             scope.spawn(move |_| {
-                work.into_iter()
-                    .enumerate()
-                    .par_bridge()
-                    .for_each(|(index, input)| sender.send((index, worker(input))).unwrap());
+                pool.install(|| {
+                    work.into_iter()
+                        .enumerate()
+                        .par_bridge()
+                        .for_each(|(index, input)| {
+                            let result = match throttle {
+                                Some(throttle) => throttle.run(|| worker(input)),
+                                None => worker(input),
+                            };
+                            sender.send((index, result)).unwrap();
+                        });
+                });
             });
 
             for (index, result) in receiver {
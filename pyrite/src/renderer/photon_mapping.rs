@@ -5,42 +5,48 @@ use rand_xorshift::XorShiftRng;
 
 use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector3};
 
-use super::algorithm::make_tiles;
+use super::algorithm::{isolate_tile, make_tiles, tile_rng};
 use crate::cameras::Camera;
-use crate::film::{DetachedPixel, Film, Sample};
+use crate::film::{DetachedPixel, Film, Sample, SamplerState};
 use crate::lamp::Surface;
 use crate::renderer::algorithm::contribute;
 use crate::renderer::{Renderer, Status, WorkPool};
 use crate::spatial::kd_tree::{self, KdTree};
 use crate::spatial::Dim3;
-use crate::tracer::{trace, Bounce, BounceType, Light, RenderContext};
+use crate::tracer::{hits_surface, trace, Bounce, BounceType, Light, RenderContext};
 use crate::utils::{pairs, BatchRange};
 use crate::{
-    math::DIST_EPSILON,
+    color::LANES,
     project::program::{ExecutionContext, Resources},
+    texture::Footprint,
     world::World,
 };
 
-pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
+/// Renders the film, using the photon maps in `photon_maps` if an earlier
+/// pass left them there. Otherwise, new photons are shot for each sample,
+/// and the last of them are kept for the next pass.
+pub(crate) fn render<'a, W: WorkPool, F: FnMut(Status<'_>)>(
     film: &Film,
     workers: &mut W,
     mut on_status: F,
     renderer: &Renderer,
     config: &Config,
-    world: &World,
+    world: &'a World<'a>,
     camera: &Camera,
-    resources: Resources,
+    resources: Resources<'a>,
+    photon_maps: &mut PhotonMaps<'a>,
 ) {
     fn gen_rng() -> XorShiftRng {
         XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
     }
 
-    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+    let tiles = make_tiles(film, renderer.tile_size, camera);
 
     let num_tiles = tiles.len();
     let mut progress;
 
     let num_passes = renderer.pixel_samples as usize * config.photon_passes;
+    let reuse = photon_maps.maps.len() == config.photon_passes;
 
     let photon_probability = 1.0
         / (renderer.bounces as f32 * config.photon_bounces as f32 * config.photon_passes as f32);
@@ -58,98 +64,117 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
         });
         progress = 0;
         workers.do_work(
-            tiles.iter().map(|f| (f, gen_rng())),
-            |(tile, mut rng)| {
-                let mut all_bounces = vec![];
-                let mut bounces = Vec::with_capacity(renderer.bounces as usize);
-                let mut exe = ExecutionContext::new(resources);
-
-                for _ in 0..tile.area() as usize {
-                    bounces.clear();
-
-                    let position = tile.sample_point(&mut rng);
-                    let ray = camera.ray_towards(&position, &mut rng);
-                    let wavelength = film.sample_wavelength(&mut rng);
-                    let light = Light::new(wavelength);
-
-                    trace(
-                        &mut bounces,
-                        &mut rng,
-                        ray,
-                        light,
-                        world,
-                        renderer.bounces,
-                        renderer.light_samples,
-                        &mut exe,
-                    );
-                    let p = 1.0 / renderer.bounces as f32;
-
-                    let mut sample = Sample {
-                        wavelength: wavelength,
-                        brightness: 0.0,
-                        weight: 1.0,
-                    };
-                    let mut reflectance = 1.0;
-
-                    let mut used_additional = true;
-                    let mut additional_samples: Vec<_> = (0..renderer.spectrum_samples - 1)
-                        .map(|_| {
-                            (
-                                Sample {
-                                    wavelength: film.sample_wavelength(&mut rng),
-                                    brightness: 0.0,
-                                    weight: 1.0,
-                                },
-                                1.0,
-                            )
-                        })
-                        .collect();
-
-                    let mut current = Parent::Source(position);
-                    for bounce in bounces.drain(..) {
-                        for &mut (ref mut sample, ref mut reflectance) in &mut additional_samples {
+            tiles
+                .iter()
+                .enumerate()
+                .map(|(index, tile)| (tile, tile_rng(film, index))),
+            |(tile, rng)| {
+                isolate_tile(tile, renderer.strict, rng, gen_rng, |mut rng, tile| {
+                    let mut all_bounces = vec![];
+                    let mut bounces = Vec::with_capacity(renderer.bounces as usize);
+                    let mut exe = ExecutionContext::new(resources);
+                    let pixel_spread = camera.pixel_spread(film.width(), film.height());
+
+                    for _ in 0..tile.area() as usize {
+                        bounces.clear();
+
+                        let position = tile.sample_point(&mut rng);
+                        let ray = camera.ray_towards(&position, &mut rng);
+                        let wavelength = film.sample_wavelength(&mut rng);
+                        let light = Light::new(wavelength);
+
+                        trace(
+                            &mut bounces,
+                            &mut rng,
+                            ray,
+                            light,
+                            world,
+                            renderer.bounces,
+                            renderer.light_samples,
+                            renderer.cutoffs,
+                            pixel_spread,
+                            &mut exe,
+                        );
+                        let covered = hits_surface(&bounces);
+                        film.expose_coverage(position, covered);
+                        if film.holdout() && !covered {
+                            bounces.clear();
+                        }
+                        let p = 1.0 / renderer.bounces as f32;
+
+                        let mut main_sample = (
+                            Sample {
+                                wavelength: wavelength,
+                                brightness: 0.0,
+                                weight: 1.0,
+                            },
+                            1.0,
+                        );
+
+                        let mut used_additional = true;
+                        let mut additional_samples: Vec<_> = (0..renderer.spectrum_samples - 1)
+                            .map(|_| {
+                                (
+                                    Sample {
+                                        wavelength: film.sample_wavelength(&mut rng),
+                                        brightness: 0.0,
+                                        weight: 1.0,
+                                    },
+                                    1.0,
+                                )
+                            })
+                            .collect();
+
+                        let mut current = Parent::Source(position);
+                        for bounce in bounces.drain(..) {
                             used_additional =
-                                contribute(&bounce, sample, reflectance, true, &mut exe)
+                                contribute(&bounce, &mut additional_samples, true, &mut exe)
                                     && used_additional;
-                        }
-                        contribute(&bounce, &mut sample, &mut reflectance, false, &mut exe);
-
-                        match bounce.ty {
-                            BounceType::Diffuse(_, _) => {
-                                let b = Arc::new(CameraBounce {
-                                    parent: current,
-                                    bounce: bounce,
-                                    pixel: film
-                                        .get_pixel_ref_f(position)
-                                        .expect("position out of bounds"),
-                                    probability: p,
-                                });
-                                current = Parent::Bounce(b.clone());
-                                all_bounces.push(b);
-                            }
-                            BounceType::Specular => {
-                                let b = Arc::new(CameraBounce {
-                                    parent: current,
-                                    bounce: bounce,
-                                    pixel: film
-                                        .get_pixel_ref_f(position)
-                                        .expect("position out of bounds"),
-                                    probability: p,
-                                });
-                                current = Parent::Bounce(b.clone());
+                            contribute(
+                                &bounce,
+                                std::slice::from_mut(&mut main_sample),
+                                false,
+                                &mut exe,
+                            );
+
+                            match bounce.ty {
+                                BounceType::Diffuse(_, _) => {
+                                    let b = Arc::new(CameraBounce {
+                                        parent: current,
+                                        bounce: bounce,
+                                        pixel: film
+                                            .get_pixel_ref_f(position)
+                                            .expect("position out of bounds"),
+                                        probability: p,
+                                    });
+                                    current = Parent::Bounce(b.clone());
+                                    all_bounces.push(b);
+                                }
+                                BounceType::Specular => {
+                                    let b = Arc::new(CameraBounce {
+                                        parent: current,
+                                        bounce: bounce,
+                                        pixel: film
+                                            .get_pixel_ref_f(position)
+                                            .expect("position out of bounds"),
+                                        probability: p,
+                                    });
+                                    current = Parent::Bounce(b.clone());
+                                }
+                                BounceType::Emission => break,
                             }
-                            BounceType::Emission => break,
                         }
-                    }
 
-                    film.expose(position, sample);
-                    if used_additional {
-                        for (sample, _) in additional_samples {
-                            film.expose(position, sample);
+                        film.expose(position, main_sample.0);
+                        if used_additional {
+                            for (sample, _) in additional_samples {
+                                film.expose(position, sample);
+                            }
                         }
                     }
-                }
-                all_bounces
+                    all_bounces
+                })
+                .unwrap_or_default()
             },
             |_i, bounces| {
                 camera_bounces.extend(bounces);
@@ -162,144 +187,157 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
         );
 
         for photon_pass in 0..config.photon_passes {
-            let mut light_bounces = Vec::with_capacity(config.photons);
-            let status_message = format!(
-                "(pass {}/{}): shooting photons",
-                photon_pass + pixel_pass as usize * config.photon_passes,
-                num_passes
-            );
-            on_status(Status {
-                progress: 0,
-                message: &status_message,
-            });
-            progress = 0;
-            workers.do_work(
-                BatchRange::new(0..config.photons, 5000).map(|batch| {
-                    let rng: XorShiftRng = gen_rng();
-                    (batch, rng)
-                }),
-                |(num_rays, mut rng)| {
-                    let mut processed = vec![];
-                    let mut bounces = Vec::with_capacity(renderer.bounces as usize);
-                    let mut exe = ExecutionContext::new(resources);
+            // The photon maps from an earlier pass are used as they are.
+            if !reuse {
+                let mut light_bounces = Vec::with_capacity(config.photons);
+                let status_message = format!(
+                    "(pass {}/{}): shooting photons",
+                    photon_pass + pixel_pass as usize * config.photon_passes,
+                    num_passes
+                );
+                on_status(Status {
+                    progress: 0,
+                    message: &status_message,
+                });
+                progress = 0;
+                workers.do_work(
+                    BatchRange::new(0..config.photons, 5000).map(|batch| {
+                        let rng: XorShiftRng = gen_rng();
+                        (batch, rng)
+                    }),
+                    |(num_rays, mut rng)| {
+                        let mut processed = vec![];
+                        let mut bounces = Vec::with_capacity(renderer.bounces as usize);
+                        let mut exe = ExecutionContext::new(resources);
+
+                        for _ in 0..num_rays {
+                            bounces.clear();
+
+                            let res = world.pick_lamp(&mut rng).and_then(|(lamp, p)| {
+                                lamp.sample_ray(&mut rng).map(|s| (lamp, p, s))
+                            });
+
+                            if let Some((_lamp, probability, mut ray_sample)) = res {
+                                let mut light = Light::new(film.sample_wavelength(&mut rng));
+
+                                let (color, normal, texture) = match ray_sample.surface {
+                                    Surface::Physical {
+                                        position,
+                                        normal,
+                                        material,
+                                        texture,
+                                    } => {
+                                        let color = world.materials.get(material).get_emission(
+                                            &mut light,
+                                            -ray_sample.ray.direction,
+                                            position,
+                                            normal,
+                                            texture,
+                                            &mut rng,
+                                            &mut exe,
+                                        );
+                                        (color, normal, texture)
+                                    }
+                                    Surface::Color(color) => {
+                                        (Some(color), ray_sample.ray.direction, Point2::origin())
+                                    }
+                                };
 
-                    for _ in 0..num_rays {
-                        bounces.clear();
+                                if let Some(color) = color {
+                                    ray_sample.ray.origin +=
+                                        normal * world.ray_epsilon(ray_sample.ray.origin, 0.0);
 
-                        let res = world
-                            .pick_lamp(&mut rng)
-                            .and_then(|(lamp, p)| lamp.sample_ray(&mut rng).map(|s| (lamp, p, s)));
-
-                        if let Some((_lamp, probability, mut ray_sample)) = res {
-                            let mut light = Light::new(film.sample_wavelength(&mut rng));
-
-                            let (color, normal, texture) = match ray_sample.surface {
-                                Surface::Physical {
-                                    normal,
-                                    material,
-                                    texture,
-                                } => {
-                                    let color = material.get_emission(
-                                        &mut light,
-                                        -ray_sample.ray.direction,
-                                        normal,
+                                    trace(
+                                        &mut bounces,
                                         &mut rng,
+                                        ray_sample.ray,
+                                        light.clone(),
+                                        world,
+                                        config.photon_bounces,
+                                        0,
+                                        renderer.cutoffs,
+                                        0.0,
+                                        &mut exe,
                                     );
-                                    (color, normal, texture)
-                                }
-                                Surface::Color(color) => {
-                                    (Some(color), ray_sample.ray.direction, Point2::origin())
-                                }
-                            };
-
-                            if let Some(color) = color {
-                                ray_sample.ray.origin += normal * DIST_EPSILON;
-
-                                trace(
-                                    &mut bounces,
-                                    &mut rng,
-                                    ray_sample.ray,
-                                    light.clone(),
-                                    world,
-                                    config.photon_bounces,
-                                    0,
-                                    &mut exe,
-                                );
-                                let p = 1.0 / config.photon_bounces as f32;
-
-                                let incident = bounces
-                                    .get(0)
-                                    .map(|b| -b.incident)
-                                    .unwrap_or(Vector3::new(0.0, 0.0, 0.0));
-
-                                let mut current = Arc::new(LightBounce {
-                                    parent: None,
-                                    bounce: Bounce {
-                                        ty: BounceType::Emission,
-                                        light,
-                                        color,
-                                        incident,
-                                        position: ray_sample.ray.origin,
-                                        normal,
-                                        texture,
-                                        probability: ray_sample.weight * probability,
-                                        direct_light: vec![],
-                                    },
-                                    probability: p,
-                                });
-
-                                if let Some(bounce) = bounces.get_mut(0) {
-                                    if let BounceType::Diffuse(_, ref mut o) = bounce.ty {
-                                        *o = -incident
+                                    let p = 1.0 / config.photon_bounces as f32;
+
+                                    let incident = bounces
+                                        .get(0)
+                                        .map(|b| -b.incident)
+                                        .unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+
+                                    let mut current = Arc::new(LightBounce {
+                                        parent: None,
+                                        bounce: Bounce {
+                                            ty: BounceType::Emission,
+                                            light,
+                                            color,
+                                            incident,
+                                            position: ray_sample.ray.origin,
+                                            normal,
+                                            texture,
+                                            footprint: Footprint::default(),
+                                            probability: ray_sample.weight * probability,
+                                            direct_light: vec![],
+                                        },
+                                        probability: p,
+                                    });
+
+                                    if let Some(bounce) = bounces.get_mut(0) {
+                                        if let BounceType::Diffuse(_, ref mut o) = bounce.ty {
+                                            *o = -incident
+                                        }
                                     }
-                                }
 
-                                pairs(&mut bounces, |to, from| {
-                                    to.incident = -from.incident;
-                                    if let BounceType::Diffuse(_, ref mut o) = from.ty {
-                                        *o = from.incident
-                                    }
-                                });
-
-                                for bounce in bounces.drain(..) {
-                                    match bounce.ty {
-                                        BounceType::Diffuse(_, _) => {
-                                            let b = Arc::new(LightBounce {
-                                                parent: Some(current),
-                                                bounce: bounce,
-                                                probability: p,
-                                            });
-                                            current = b.clone();
-                                            processed.push(b);
+                                    pairs(&mut bounces, |to, from| {
+                                        to.incident = -from.incident;
+                                        if let BounceType::Diffuse(_, ref mut o) = from.ty {
+                                            *o = from.incident
                                         }
-                                        BounceType::Specular => {
-                                            let b = Arc::new(LightBounce {
-                                                parent: Some(current),
-                                                bounce: bounce,
-                                                probability: p,
-                                            });
-                                            current = b.clone();
+                                    });
+
+                                    for bounce in bounces.drain(..) {
+                                        match bounce.ty {
+                                            BounceType::Diffuse(_, _) => {
+                                                let b = Arc::new(LightBounce {
+                                                    parent: Some(current),
+                                                    bounce: bounce,
+                                                    probability: p,
+                                                });
+                                                current = b.clone();
+                                                processed.push(b);
+                                            }
+                                            BounceType::Specular => {
+                                                let b = Arc::new(LightBounce {
+                                                    parent: Some(current),
+                                                    bounce: bounce,
+                                                    probability: p,
+                                                });
+                                                current = b.clone();
+                                            }
+                                            BounceType::Emission => break,
                                         }
-                                        BounceType::Emission => break,
                                     }
                                 }
                             }
                         }
-                    }
-
-                    (num_rays, processed)
-                },
-                |_, (n, bounces)| {
-                    light_bounces.extend(bounces);
-                    progress += n;
-                    on_status(Status {
-                        progress: ((progress as f32 / config.photons as f32) * 100.0) as u8,
-                        message: &status_message,
-                    });
-                },
-            );
 
-            let light_bounces = KdTree::new(light_bounces, 100);
+                        (num_rays, processed)
+                    },
+                    |_, (n, bounces)| {
+                        light_bounces.extend(bounces);
+                        progress += n;
+                        on_status(Status {
+                            progress: ((progress as f32 / config.photons as f32) * 100.0) as u8,
+                            message: &status_message,
+                        });
+                    },
+                );
+
+                photon_maps.maps.truncate(photon_pass);
+                photon_maps.maps.push(KdTree::new(light_bounces, 100));
+            }
+            let light_bounces = &photon_maps.maps[photon_pass];
 
             let status_message = format!(
                 "(pass {}/{}): gathering light",
@@ -400,9 +438,24 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
                 },
             );
         }
+
+        // Each pass traces one sample per pixel, so the next pass continues
+        // the random sequences, instead of tracing the same samples again.
+        let sampler = film.sampler_state();
+        film.set_sampler_state(SamplerState {
+            samples: sampler.samples + 1,
+            ..sampler
+        });
     }
 }
 
+/// Photon maps that are shared between the passes of a sample schedule.
+#[derive(Default)]
+pub(crate) struct PhotonMaps<'a> {
+    maps: Vec<KdTree<Arc<LightBounce<'a>>>>,
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub radius: f32,
     pub photons: usize,
@@ -437,8 +490,10 @@ impl<'a> CameraBounce<'a> {
                 light: _,
                 color,
                 incident,
+                position,
                 normal,
                 texture,
+                footprint,
                 probability,
                 ..
             } = &hit.bounce;
@@ -449,15 +504,22 @@ impl<'a> CameraBounce<'a> {
                 ty.brdf(incident, normal)
             };
 
-            for &mut (ref sample, ref mut reflectance) in &mut *samples {
+            for samples in samples.chunks_mut(LANES) {
                 let context = RenderContext {
-                    wavelength: sample.wavelength,
+                    wavelengths: samples
+                        .iter()
+                        .map(|(sample, _)| sample.wavelength)
+                        .collect(),
                     incident,
                     normal: normal,
                     texture,
+                    footprint,
+                    position,
                 };
-                let c = exe.run(color, &context).value;
-                *reflectance *= c * probability * brdf;
+                let c = exe.run(color, &context);
+                for ((_, reflectance), c) in samples.iter_mut().zip(&c.values) {
+                    *reflectance *= c * probability * brdf;
+                }
             }
 
             match hit.parent {
@@ -507,26 +569,37 @@ impl<'a> LightBounce<'a> {
                 light: _,
                 color,
                 incident,
+                position,
                 normal,
                 texture,
+                footprint,
                 probability,
                 ..
             } = &hit.bounce;
 
-            for &mut (ref mut sample, ref mut reflectance) in &mut *samples {
+            for samples in samples.chunks_mut(LANES) {
                 let context = RenderContext {
-                    wavelength: sample.wavelength,
+                    wavelengths: samples
+                        .iter()
+                        .map(|(sample, _)| sample.wavelength)
+                        .collect(),
                     incident,
                     normal,
                     texture,
+                    footprint,
+                    position,
                 };
 
-                let c = exe.run(color, &context).value * probability;
+                let c = exe.run(color, &context);
 
-                if let BounceType::Emission = *ty {
-                    sample.brightness = c * *reflectance;
-                } else {
-                    *reflectance *= c * ty.brdf(incident, normal);
+                for ((sample, reflectance), c) in samples.iter_mut().zip(&c.values) {
+                    let c = c * probability;
+
+                    if let BounceType::Emission = *ty {
+                        sample.brightness = c * *reflectance;
+                    } else {
+                        *reflectance *= c * ty.brdf(incident, normal);
+                    }
                 }
             }
 
@@ -0,0 +1,229 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use cgmath::{Point2, Point3, Vector2, Vector3};
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use crate::{
+    cameras::Camera,
+    film::{Area, Sample},
+    project::program::{ExecutionContext, Resources},
+    renderer::{algorithm::contribute, Renderer},
+    tracer::{trace, BounceType, Light},
+    world::World,
+};
+
+/// A path vertex, as seen from one of the samples of a traced pixel.
+pub(crate) struct TracedVertex {
+    pub sample: usize,
+    pub wavelength: f32,
+    pub bounce: usize,
+    pub kind: &'static str,
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub incident: Vector3<f32>,
+    pub probability: f32,
+    pub direct_light_samples: usize,
+    pub throughput: f32,
+    pub radiance: f32,
+}
+
+/// Traces the paths of a single pixel, using the project's bounce and sample
+/// counts, and records every vertex along the way. The paths are always
+/// traced like in the simple renderer.
+pub(crate) fn trace_pixel(
+    renderer: &Renderer,
+    pixel: Point2<usize>,
+    width: usize,
+    height: usize,
+    camera: &Camera,
+    world: &World,
+    resources: Resources,
+) -> Vec<TracedVertex> {
+    // A fixed seed makes it possible to compare traces between changes.
+    let mut rng = XorShiftRng::seed_from_u64(0);
+    let mut exe = ExecutionContext::new(resources);
+    let mut path = Vec::with_capacity(renderer.bounces as usize);
+    let mut vertices = Vec::new();
+
+    let area = camera.to_view_area(&Area::new(pixel, Vector2::new(1, 1)), width, height);
+    let (wavelength_start, wavelength_end) = renderer.spectrum_span;
+
+    for sample_index in 0..renderer.pixel_samples as usize {
+        path.clear();
+
+        let position = area.from
+            + Vector2::new(
+                area.size.x * rng.gen::<f32>(),
+                area.size.y * rng.gen::<f32>(),
+            );
+        let ray = camera.ray_towards(&position, &mut rng);
+        let wavelength = rng.gen_range(wavelength_start, wavelength_end);
+
+        trace(
+            &mut path,
+            &mut rng,
+            ray,
+            Light::new(wavelength),
+            world,
+            renderer.bounces,
+            renderer.light_samples,
+            renderer.cutoffs,
+            camera.pixel_spread(width, height),
+            &mut exe,
+        );
+
+        let mut sample = (
+            Sample {
+                wavelength,
+                brightness: 0.0,
+                weight: 1.0,
+            },
+            1.0,
+        );
+
+        for (bounce_index, bounce) in path.iter().enumerate() {
+            let throughput = sample.1;
+            contribute(bounce, std::slice::from_mut(&mut sample), false, &mut exe);
+
+            let kind = match bounce.ty {
+                BounceType::Diffuse(..) => "diffuse",
+                BounceType::Specular => "specular",
+                BounceType::Emission if !bounce.position.x.is_finite() => "sky",
+                BounceType::Emission => "emission",
+            };
+
+            vertices.push(TracedVertex {
+                sample: sample_index,
+                wavelength,
+                bounce: bounce_index,
+                kind,
+                position: bounce.position,
+                normal: bounce.normal,
+                incident: bounce.incident,
+                probability: bounce.probability,
+                direct_light_samples: bounce.direct_light.len(),
+                throughput,
+                radiance: sample.0.brightness,
+            });
+        }
+    }
+
+    vertices
+}
+
+/// Writes the vertices as CSV if the file name ends with `.csv`, and as JSON
+/// otherwise.
+pub(crate) fn write_trace(
+    path: &Path,
+    pixel: Point2<usize>,
+    vertices: &[TracedVertex],
+) -> Result<(), Box<dyn Error>> {
+    let is_csv = path
+        .extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("csv"));
+
+    let mut file = BufWriter::new(File::create(path)?);
+
+    if is_csv {
+        write_csv(&mut file, vertices)?;
+    } else {
+        write_json(&mut file, pixel, vertices)?;
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+fn write_csv(file: &mut impl Write, vertices: &[TracedVertex]) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        file,
+        "sample,wavelength,bounce,type,position_x,position_y,position_z,normal_x,normal_y,normal_z,incident_x,incident_y,incident_z,probability,direct_light_samples,throughput,radiance"
+    )?;
+
+    for vertex in vertices {
+        writeln!(
+            file,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            vertex.sample,
+            vertex.wavelength,
+            vertex.bounce,
+            vertex.kind,
+            vertex.position.x,
+            vertex.position.y,
+            vertex.position.z,
+            vertex.normal.x,
+            vertex.normal.y,
+            vertex.normal.z,
+            vertex.incident.x,
+            vertex.incident.y,
+            vertex.incident.z,
+            vertex.probability,
+            vertex.direct_light_samples,
+            vertex.throughput,
+            vertex.radiance
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_json(
+    file: &mut impl Write,
+    pixel: Point2<usize>,
+    vertices: &[TracedVertex],
+) -> Result<(), Box<dyn Error>> {
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"pixel\": [{}, {}],", pixel.x, pixel.y)?;
+    writeln!(file, "  \"vertices\": [")?;
+
+    for (i, vertex) in vertices.iter().enumerate() {
+        let separator = if i + 1 < vertices.len() { "," } else { "" };
+
+        writeln!(
+            file,
+            "    {{\"sample\": {}, \"wavelength\": {}, \"bounce\": {}, \"type\": \"{}\", \"position\": {}, \"normal\": {}, \"incident\": {}, \"probability\": {}, \"direct_light_samples\": {}, \"throughput\": {}, \"radiance\": {}}}{}",
+            vertex.sample,
+            json_number(vertex.wavelength),
+            vertex.bounce,
+            vertex.kind,
+            json_vector([vertex.position.x, vertex.position.y, vertex.position.z]),
+            json_vector([vertex.normal.x, vertex.normal.y, vertex.normal.z]),
+            json_vector([vertex.incident.x, vertex.incident.y, vertex.incident.z]),
+            json_number(vertex.probability),
+            vertex.direct_light_samples,
+            json_number(vertex.throughput),
+            json_number(vertex.radiance),
+            separator
+        )?;
+    }
+
+    writeln!(file, "  ]")?;
+    writeln!(file, "}}")?;
+
+    Ok(())
+}
+
+/// JSON doesn't have infinity or NaN, so they become `null`.
+fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".into()
+    }
+}
+
+fn json_vector(values: [f32; 3]) -> String {
+    format!(
+        "[{}, {}, {}]",
+        json_number(values[0]),
+        json_number(values[1]),
+        json_number(values[2])
+    )
+}
@@ -0,0 +1,377 @@
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector3};
+use collision::Ray3;
+
+use super::algorithm::{isolate_tile, make_tiles, tile_rng, Tile};
+use crate::cameras::Camera;
+use crate::color::{Wavelengths, LANES};
+use crate::film::{Film, Sample};
+use crate::math::{offset_ray, utils::sample_hemisphere};
+use crate::renderer::{simple, Renderer, Status, WorkPool};
+use crate::tracer::RenderContext;
+use crate::{
+    project::program::{ExecutionContext, Resources},
+    texture::Footprint,
+    world::World,
+};
+
+/// The most bounces, samples and spectrum bins that are used for previews.
+/// The project's settings are used if they are lower.
+pub(super) const BOUNCES: u32 = 3;
+pub(super) const PIXEL_SAMPLES: u32 = 4;
+pub(super) const LIGHT_SAMPLES: usize = 1;
+pub(super) const SPECTRUM_SAMPLES: u32 = 1;
+pub(super) const SPECTRUM_RESOLUTION: usize = 16;
+
+/// The resolution that skies without environment maps are projected into
+/// spherical harmonics with.
+const SKY_RESOLUTION: (usize, usize) = (128, 64);
+
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Renders ambient occlusion within this distance, instead of light, if
+    /// it's set.
+    pub ambient_occlusion: Option<f32>,
+    /// Lights white surfaces with a spherical harmonics approximation of the
+    /// sky, without shadows, instead of tracing paths.
+    pub spherical_harmonics: bool,
+}
+
+pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
+    film: &Film,
+    workers: &mut W,
+    mut on_status: F,
+    renderer: &Renderer,
+    config: &Config,
+    world: &World,
+    camera: &Camera,
+    resources: Resources,
+) {
+    fn gen_rng() -> XorShiftRng {
+        XorShiftRng::from_rng(rand::thread_rng()).expect("could not generate RNG")
+    }
+
+    let status_message = "rendering preview";
+    on_status(Status {
+        progress: 0,
+        message: &status_message,
+    });
+
+    let environment = if config.spherical_harmonics {
+        Some(SkyHarmonics::project(
+            workers,
+            film.wavelength_span(),
+            world,
+            resources,
+        ))
+    } else {
+        None
+    };
+
+    let tiles = make_tiles(film, renderer.tile_size, camera);
+
+    let mut progress: usize = 0;
+    let num_tiles = tiles.len();
+
+    workers.do_work(
+        tiles
+            .into_iter()
+            .enumerate()
+            .map(|(index, tile)| (tile, tile_rng(film, index))),
+        |(tile, rng)| {
+            isolate_tile(&tile, renderer.strict, rng, gen_rng, |rng, tile| {
+                let tile = tile.clone();
+                match (config.ambient_occlusion, &environment) {
+                    (Some(distance), _) => {
+                        render_occlusion_tile(rng, tile, film, camera, world, renderer, distance)
+                    }
+                    (None, Some(environment)) => render_environment_tile(
+                        rng,
+                        tile,
+                        film,
+                        camera,
+                        world,
+                        resources,
+                        renderer,
+                        environment,
+                    ),
+                    (None, None) => simple::render_tile(
+                        rng,
+                        tile,
+                        film,
+                        camera,
+                        world,
+                        resources,
+                        renderer,
+                        &simple::Config::default(),
+                    ),
+                }
+            });
+        },
+        |_, _| {
+            progress += 1;
+            on_status(Status {
+                progress: ((progress * 100) / num_tiles) as u8,
+                message: &status_message,
+            });
+        },
+    );
+}
+
+/// Surfaces are white, and only darkened by other surfaces within `distance`.
+fn render_occlusion_tile<R: Rng>(
+    mut rng: R,
+    tile: Tile,
+    film: &Film,
+    camera: &Camera,
+    world: &World,
+    renderer: &Renderer,
+    distance: f32,
+) {
+    for _ in 0..(tile.area() * renderer.pixel_samples as usize) {
+        let position = tile.sample_point(&mut rng);
+        let ray = camera.ray_towards(&position, &mut rng);
+
+        let hit = world.intersect(ray);
+        film.expose_coverage(position, hit.is_some());
+
+        let background = if film.holdout() { 0.0 } else { 1.0 };
+        let brightness = hit.map_or(background, |intersection| {
+            let hit_position = intersection.surface_point.position;
+            let normal = intersection
+                .surface_point
+                .get_surface_data()
+                .normal
+                .vector();
+            let normal = if normal.dot(ray.direction) < 0.0 {
+                normal
+            } else {
+                -normal
+            };
+
+            let direction = sample_hemisphere(&mut rng, normal);
+            let epsilon = world.ray_epsilon(hit_position, intersection.distance);
+            let occlusion_ray = offset_ray(Ray3::new(hit_position, direction), normal, epsilon);
+
+            match world.intersect(occlusion_ray) {
+                Some(hit) if hit.distance < distance => 0.0,
+                // The directions are uniformly distributed, so they are
+                // weighted like a diffuse surface.
+                _ => 2.0 * normal.dot(direction),
+            }
+        });
+
+        film.expose(
+            position,
+            Sample {
+                wavelength: film.sample_wavelength(&mut rng),
+                brightness,
+                weight: 1.0,
+            },
+        );
+    }
+}
+
+/// Surfaces are white and diffuse, and lit by the whole sky, as approximated
+/// by `environment`.
+fn render_environment_tile<R: Rng>(
+    mut rng: R,
+    tile: Tile,
+    film: &Film,
+    camera: &Camera,
+    world: &World,
+    resources: Resources,
+    renderer: &Renderer,
+    environment: &SkyHarmonics,
+) {
+    let mut exe = ExecutionContext::new(resources);
+
+    for _ in 0..(tile.area() * renderer.pixel_samples as usize) {
+        let position = tile.sample_point(&mut rng);
+        let ray = camera.ray_towards(&position, &mut rng);
+        let wavelength = film.sample_wavelength(&mut rng);
+
+        let hit = world.intersect(ray);
+        film.expose_coverage(position, hit.is_some());
+
+        let brightness = match hit {
+            Some(intersection) => {
+                let normal = intersection
+                    .surface_point
+                    .get_surface_data()
+                    .normal
+                    .vector();
+                let normal = if normal.dot(ray.direction) < 0.0 {
+                    normal
+                } else {
+                    -normal
+                };
+
+                environment.irradiance(normal, wavelength) / std::f32::consts::PI
+            }
+            None if film.holdout() => 0.0,
+            None => sky_radiance(world, &mut exe, ray.direction, &[wavelength])[0],
+        };
+
+        film.expose(
+            position,
+            Sample {
+                wavelength,
+                brightness,
+                weight: 1.0,
+            },
+        );
+    }
+}
+
+/// The sky, projected into the first three bands of spherical harmonics for
+/// a few wavelengths across the spectrum.
+struct SkyHarmonics {
+    wavelength_span: (f32, f32),
+    coefficients: Vec<[f32; 9]>,
+}
+
+impl SkyHarmonics {
+    /// Integrates the sky over the pixels of its environment map, one row at
+    /// a time.
+    fn project<W: WorkPool>(
+        workers: &mut W,
+        wavelength_span: (f32, f32),
+        world: &World,
+        resources: Resources,
+    ) -> Self {
+        let (from, to) = wavelength_span;
+        let bin_width = (to - from) / SPECTRUM_RESOLUTION as f32;
+        let wavelengths: Vec<f32> = (0..SPECTRUM_RESOLUTION)
+            .map(|bin| from + (bin as f32 + 0.5) * bin_width)
+            .collect();
+
+        let ((width, height), rotation) = match world.sky_sampler {
+            Some(ref sampler) => (sampler.resolution(), sampler.rotation()),
+            None => (SKY_RESOLUTION, 0.0),
+        };
+        let pixel_width = 2.0 * std::f32::consts::PI / width as f32;
+        let pixel_height = std::f32::consts::PI / height as f32;
+
+        let mut coefficients = vec![[0.0; 9]; SPECTRUM_RESOLUTION];
+
+        workers.do_work(
+            0..height,
+            |y| {
+                let mut exe = ExecutionContext::new(resources);
+                let mut row = vec![[0.0f32; 9]; SPECTRUM_RESOLUTION];
+
+                // The same directions as in `SkySampler`, through the middle
+                // of each pixel, which cover less of the sky near the poles.
+                let elevation = (0.5 - (y as f32 + 0.5) / height as f32) * std::f32::consts::PI;
+                let (sin_elevation, cos_elevation) = elevation.sin_cos();
+                let weight = pixel_width * pixel_height * cos_elevation;
+
+                for x in 0..width {
+                    let azimuth =
+                        (0.5 - (x as f32 + 0.5) / width as f32) * 2.0 * std::f32::consts::PI
+                            + rotation;
+                    let (sin_azimuth, cos_azimuth) = azimuth.sin_cos();
+                    let direction = Vector3::new(
+                        cos_elevation * cos_azimuth,
+                        cos_elevation * sin_azimuth,
+                        sin_elevation,
+                    );
+                    let basis = basis(direction);
+
+                    for (wavelengths, coefficients) in
+                        wavelengths.chunks(LANES).zip(row.chunks_mut(LANES))
+                    {
+                        let radiance = sky_radiance(world, &mut exe, direction, wavelengths);
+                        for (coefficients, &radiance) in coefficients.iter_mut().zip(&radiance) {
+                            for (coefficient, &basis) in coefficients.iter_mut().zip(&basis) {
+                                *coefficient += radiance * basis * weight;
+                            }
+                        }
+                    }
+                }
+
+                row
+            },
+            |_, row| {
+                for (coefficients, row) in coefficients.iter_mut().zip(row) {
+                    for (coefficient, value) in coefficients.iter_mut().zip(&row) {
+                        *coefficient += value;
+                    }
+                }
+            },
+        );
+
+        SkyHarmonics {
+            wavelength_span,
+            coefficients,
+        }
+    }
+
+    /// The light that reaches a surface with `normal` from the whole sky,
+    /// using Ramamoorthi and Hanrahan's convolution with the cosine lobe.
+    fn irradiance(&self, normal: Vector3<f32>, wavelength: f32) -> f32 {
+        const BANDS: [f32; 9] = [
+            std::f32::consts::PI,
+            2.0 * std::f32::consts::PI / 3.0,
+            2.0 * std::f32::consts::PI / 3.0,
+            2.0 * std::f32::consts::PI / 3.0,
+            std::f32::consts::PI / 4.0,
+            std::f32::consts::PI / 4.0,
+            std::f32::consts::PI / 4.0,
+            std::f32::consts::PI / 4.0,
+            std::f32::consts::PI / 4.0,
+        ];
+
+        let (from, to) = self.wavelength_span;
+        let bin = ((wavelength - from) / (to - from) * SPECTRUM_RESOLUTION as f32) as usize;
+        let coefficients = &self.coefficients[bin.min(SPECTRUM_RESOLUTION - 1)];
+
+        let irradiance: f32 = basis(normal)
+            .iter()
+            .zip(coefficients)
+            .zip(&BANDS)
+            .map(|((basis, coefficient), band)| basis * coefficient * band)
+            .sum();
+
+        irradiance.max(0.0)
+    }
+}
+
+/// The real spherical harmonics basis functions of the first three bands.
+fn basis(direction: Vector3<f32>) -> [f32; 9] {
+    let Vector3 { x, y, z } = direction;
+
+    [
+        0.282_095,
+        0.488_603 * y,
+        0.488_603 * z,
+        0.488_603 * x,
+        1.092_548 * x * y,
+        1.092_548 * y * z,
+        0.315_392 * (3.0 * z * z - 1.0),
+        1.092_548 * x * z,
+        0.546_274 * (x * x - y * y),
+    ]
+}
+
+/// The sky's radiance for up to `LANES` wavelengths, seen in `direction`.
+fn sky_radiance<'w>(
+    world: &'w World,
+    exe: &mut ExecutionContext<'w>,
+    direction: Vector3<f32>,
+    wavelengths: &[f32],
+) -> [f32; LANES] {
+    let context = RenderContext {
+        wavelengths: wavelengths.iter().cloned().collect::<Wavelengths>(),
+        incident: direction,
+        normal: -direction,
+        texture: Point2::origin(),
+        footprint: Footprint::default(),
+        position: Point3::origin(),
+    };
+
+    exe.run(world.sky, &context).values
+}
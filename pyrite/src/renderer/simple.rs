@@ -1,22 +1,48 @@
 use rand::{Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
 
-use super::algorithm::{make_tiles, Tile};
+use cgmath::Point2;
+use collision::Ray3;
+
+use super::algorithm::{isolate_tile, make_tiles, tile_rng, Tile};
 use crate::cameras::Camera;
 use crate::film::{Film, Sample};
 use crate::renderer::algorithm::contribute;
 use crate::renderer::{Renderer, Status, WorkPool};
-use crate::tracer::{trace, Light};
+use crate::shapes::Intersection;
+use crate::tracer::{hits_surface, trace_from_hit, Bounce, Light};
 use crate::{
     project::program::{ExecutionContext, Resources},
+    spatial::bvh::RayPacket,
     world::World,
+    xyz,
 };
 
+/// The number of camera rays that are traced together, to find their first
+/// hits with a single pass through the BVH.
+const PACKET_SIZE: usize = 64;
+
+/// The number of camera samples a pixel needs before its chromatic noise is
+/// trusted.
+const MIN_CHROMA_SAMPLES: f32 = 4.0;
+
+/// The relative chromatic noise where half of the maximum number of
+/// wavelengths is used.
+const HALF_CHROMA_NOISE: f32 = 0.5;
+
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Enables adaptive spectral sampling, with up to this many wavelengths
+    /// for each camera sample.
+    pub max_spectrum_samples: Option<u32>,
+}
+
 pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
     film: &Film,
     workers: &mut W,
     mut on_status: F,
     renderer: &Renderer,
+    config: &Config,
     world: &World,
     camera: &Camera,
     resources: Resources,
@@ -31,15 +57,29 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
         message: &status_message,
     });
 
-    let tiles = make_tiles(film.width(), film.height(), renderer.tile_size, camera);
+    let tiles = make_tiles(film, renderer.tile_size, camera);
 
     let mut progress: usize = 0;
     let num_tiles = tiles.len();
 
     workers.do_work(
-        tiles.into_iter().map(|f| (f, gen_rng())),
+        tiles
+            .into_iter()
+            .enumerate()
+            .map(|(index, tile)| (tile, tile_rng(film, index))),
         |(tile, rng)| {
-            render_tile(rng, tile, film, camera, world, resources, renderer);
+            isolate_tile(&tile, renderer.strict, rng, gen_rng, |rng, tile| {
+                render_tile(
+                    rng,
+                    tile.clone(),
+                    film,
+                    camera,
+                    world,
+                    resources,
+                    renderer,
+                    config,
+                )
+            });
         },
         |_, _| {
             progress += 1;
@@ -51,7 +91,7 @@ pub(crate) fn render<W: WorkPool, F: FnMut(Status<'_>)>(
     );
 }
 
-fn render_tile<R: Rng>(
+pub(super) fn render_tile<R: Rng>(
     mut rng: R,
     tile: Tile,
     film: &Film,
@@ -59,68 +99,275 @@ fn render_tile<R: Rng>(
     world: &World,
     resources: Resources,
     renderer: &Renderer,
+    config: &Config,
 ) {
+    let mut chroma = config
+        .max_spectrum_samples
+        .map(|max| ChromaStatistics::new(&tile, max));
     let mut additional_samples = Vec::with_capacity(renderer.spectrum_samples as usize - 1);
     let mut path = Vec::with_capacity(renderer.bounces as usize);
     let mut exe = ExecutionContext::new(resources);
+    let mut camera_samples = Vec::with_capacity(PACKET_SIZE);
+    let pixel_spread = camera.pixel_spread(film.width(), film.height());
+    let mut remaining = tile.area() * renderer.pixel_samples as usize;
 
-    for _ in 0..(tile.area() * renderer.pixel_samples as usize) {
-        additional_samples.clear();
+    while remaining > 0 {
+        let packet_size = remaining.min(PACKET_SIZE);
+        remaining -= packet_size;
+
+        camera_samples.clear();
+        camera_samples.extend((0..packet_size).map(|_| {
+            let position = tile.sample_point(&mut rng);
+            (position, camera.ray_towards(&position, &mut rng))
+        }));
+        let packet = RayPacket::new(camera_samples.iter().map(|&(_, ray)| ray).collect());
+        let first_hits = world.intersect_packet(&packet);
+
+        for (&(position, ray), first_hit) in camera_samples.iter().zip(first_hits) {
+            render_sample(
+                &mut rng,
+                position,
+                ray,
+                first_hit,
+                film,
+                world,
+                renderer,
+                &mut path,
+                &mut additional_samples,
+                chroma.as_mut(),
+                pixel_spread,
+                &mut exe,
+            );
+        }
+    }
+}
+
+fn render_sample<'w, R: Rng>(
+    rng: &mut R,
+    position: Point2<f32>,
+    ray: Ray3<f32>,
+    first_hit: Option<Intersection<'w>>,
+    film: &Film,
+    world: &'w World,
+    renderer: &Renderer,
+    path: &mut Vec<Bounce<'w>>,
+    additional_samples: &mut Vec<(Sample, f32)>,
+    chroma: Option<&mut ChromaStatistics>,
+    pixel_spread: f32,
+    exe: &mut ExecutionContext<'w>,
+) {
+    additional_samples.clear();
+    path.clear();
+
+    let wavelength = film.sample_wavelength(rng);
+    let light = Light::new(wavelength);
+    trace_from_hit(
+        path,
+        rng,
+        ray,
+        first_hit,
+        light,
+        world,
+        renderer.bounces,
+        renderer.light_samples,
+        renderer.cutoffs,
+        pixel_spread,
+        exe,
+    );
+
+    let covered = hits_surface(path);
+    if film.holdout() && !covered {
         path.clear();
+    }
 
-        let position = tile.sample_point(&mut rng);
-
-        let ray = camera.ray_towards(&position, &mut rng);
-        let wavelength = film.sample_wavelength(&mut rng);
-        let light = Light::new(wavelength);
-        trace(
-            &mut path,
-            &mut rng,
-            ray,
-            light,
-            world,
-            renderer.bounces,
-            renderer.light_samples,
-            &mut exe,
-        );
-
-        let mut main_sample = (
+    let mut main_sample = (
+        Sample {
+            wavelength,
+            brightness: 0.0,
+            weight: 1.0,
+        },
+        1.0,
+    );
+
+    let mut used_additional = true;
+    additional_samples.extend((0..renderer.spectrum_samples - 1).map(|_| {
+        (
             Sample {
-                wavelength,
+                wavelength: film.sample_wavelength(rng),
                 brightness: 0.0,
                 weight: 1.0,
             },
             1.0,
-        );
+        )
+    }));
+
+    for bounce in path.iter() {
+        used_additional = contribute(bounce, additional_samples, true, exe) && used_additional;
+        contribute(bounce, std::slice::from_mut(&mut main_sample), false, exe);
+    }
+
+    let mut color = ChromaSample::default();
+    color.add(&main_sample.0);
+
+    film.expose(position, main_sample.0);
+    film.expose_coverage(position, covered);
+
+    if used_additional {
+        if let Some(chroma) = chroma {
+            for (sample, _) in additional_samples.iter() {
+                color.add(sample);
+            }
+            chroma.add(position, color);
+        }
+
+        for (sample, _) in additional_samples.drain(..) {
+            film.expose(position, sample);
+        }
 
-        let mut used_additional = true;
-        additional_samples.extend((0..renderer.spectrum_samples - 1).map(|_| {
-            (
+        return;
+    }
+
+    // The path was split into colors, so the additional wavelengths couldn't
+    // follow it. More paths are traced from the same first hit where the
+    // pixel's colors are noisy.
+    if let Some(chroma) = chroma {
+        for _ in 1..chroma.wavelengths(position, renderer.spectrum_samples) {
+            path.clear();
+
+            let wavelength = film.sample_wavelength(rng);
+            trace_from_hit(
+                path,
+                rng,
+                ray,
+                first_hit,
+                Light::new(wavelength),
+                world,
+                renderer.bounces,
+                renderer.light_samples,
+                renderer.cutoffs,
+                pixel_spread,
+                exe,
+            );
+
+            let mut sample = (
                 Sample {
-                    wavelength: film.sample_wavelength(&mut rng),
+                    wavelength,
                     brightness: 0.0,
                     weight: 1.0,
                 },
                 1.0,
-            )
-        }));
-
-        for bounce in &path {
-            for &mut (ref mut sample, ref mut reflectance) in &mut additional_samples {
-                used_additional =
-                    contribute(bounce, sample, reflectance, true, &mut exe) && used_additional;
+            );
+            for bounce in path.iter() {
+                contribute(bounce, std::slice::from_mut(&mut sample), false, exe);
             }
 
-            let (ref mut sample, ref mut reflectance) = main_sample;
-            contribute(bounce, sample, reflectance, false, &mut exe);
+            color.add(&sample.0);
+            film.expose(position, sample.0);
         }
 
-        film.expose(position, main_sample.0);
+        chroma.add(position, color);
+    }
+}
 
-        if used_additional {
-            for (sample, _) in additional_samples.drain(..) {
-                film.expose(position, sample);
-            }
+/// The XYZ color of a camera sample, averaged over its wavelengths.
+#[derive(Default)]
+struct ChromaSample {
+    xyz: [f32; 3],
+    wavelengths: f32,
+}
+
+impl ChromaSample {
+    fn add(&mut self, sample: &Sample) {
+        self.xyz[0] += sample.brightness * xyz::response::X.get(sample.wavelength);
+        self.xyz[1] += sample.brightness * xyz::response::Y.get(sample.wavelength);
+        self.xyz[2] += sample.brightness * xyz::response::Z.get(sample.wavelength);
+        self.wavelengths += 1.0;
+    }
+}
+
+/// Running sums of the chroma of each pixel in a tile, for measuring how
+/// noisy its colors are. The chroma is measured as the difference between X
+/// and Y, and between Z and Y, which are both 0 for white.
+struct ChromaStatistics {
+    from: Point2<f32>,
+    scale: (f32, f32),
+    width: usize,
+    height: usize,
+    max_wavelengths: u32,
+    pixels: Vec<PixelChroma>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct PixelChroma {
+    samples: f32,
+    luminance: f32,
+    chroma: [f32; 2],
+    chroma_squared: [f32; 2],
+}
+
+impl ChromaStatistics {
+    fn new(tile: &Tile, max_wavelengths: u32) -> Self {
+        ChromaStatistics {
+            from: tile.area.from,
+            scale: (
+                tile.width as f32 / tile.area.size.x,
+                tile.height as f32 / tile.area.size.y,
+            ),
+            width: tile.width,
+            height: tile.height,
+            max_wavelengths: max_wavelengths.max(1),
+            pixels: vec![PixelChroma::default(); tile.area()],
+        }
+    }
+
+    fn pixel_index(&self, position: Point2<f32>) -> usize {
+        let x = ((position.x - self.from.x) * self.scale.0).max(0.0) as usize;
+        let y = ((position.y - self.from.y) * self.scale.1).max(0.0) as usize;
+        x.min(self.width - 1) + y.min(self.height - 1) * self.width
+    }
+
+    fn add(&mut self, position: Point2<f32>, color: ChromaSample) {
+        let index = self.pixel_index(position);
+        let pixel = &mut self.pixels[index];
+
+        let [x, y, z] = color.xyz;
+        let weight = 1.0 / color.wavelengths.max(1.0);
+        let chroma = [(x - y) * weight, (z - y) * weight];
+
+        pixel.samples += 1.0;
+        pixel.luminance += y * weight;
+        for (channel, &value) in chroma.iter().enumerate() {
+            pixel.chroma[channel] += value;
+            pixel.chroma_squared[channel] += value * value;
         }
     }
+
+    /// The number of wavelengths to trace for a camera sample through
+    /// `position`, from 1 in pixels without chromatic noise, to the maximum
+    /// in the noisiest pixels. New pixels start with `initial`.
+    fn wavelengths(&self, position: Point2<f32>, initial: u32) -> u32 {
+        let pixel = &self.pixels[self.pixel_index(position)];
+
+        if pixel.samples < MIN_CHROMA_SAMPLES {
+            return initial.min(self.max_wavelengths).max(1);
+        }
+
+        let luminance = pixel.luminance / pixel.samples;
+        if luminance <= 0.0 {
+            return 1;
+        }
+
+        let variance: f32 = (0..2)
+            .map(|channel| {
+                let mean = pixel.chroma[channel] / pixel.samples;
+                (pixel.chroma_squared[channel] / pixel.samples - mean * mean).max(0.0)
+            })
+            .sum();
+        let noise = variance.sqrt() / luminance;
+        let share = noise / (noise + HALF_CHROMA_NOISE);
+
+        ((self.max_wavelengths as f32 * share).round() as u32)
+            .max(1)
+            .min(self.max_wavelengths)
+    }
 }
@@ -0,0 +1,261 @@
+use cgmath::{InnerSpace, Point3, Vector3};
+use collision::Ray3;
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use crate::{
+    materials::MaterialId,
+    math::{offset_ray, ray_epsilon, utils::sample_hemisphere, DIST_EPSILON},
+    shapes::{PrimitiveId, Shape},
+    spatial::bvh::Bvh,
+    world::{intersect_shapes, WorldShape},
+};
+
+/// How far from the origin each scene is moved, to test the precision at
+/// large coordinates.
+const OFFSETS: [f32; 4] = [0.0, 100.0, 10_000.0, 100_000.0];
+
+const RAYS_PER_CASE: usize = 100_000;
+
+/// The highest acceptable rate of artifacts, without any offset.
+const MAX_ARTIFACT_RATE: f64 = 0.001;
+
+struct Case {
+    name: &'static str,
+    build: fn(Vector3<f32>) -> Vec<Shape>,
+    sample_ray: fn(&mut XorShiftRng, Vector3<f32>) -> Ray3<f32>,
+}
+
+const CASES: [Case; 3] = [
+    Case {
+        name: "thin triangles",
+        build: thin_triangles,
+        sample_ray: ray_towards_floor,
+    },
+    Case {
+        name: "coplanar faces",
+        build: coplanar_faces,
+        sample_ray: ray_towards_floor,
+    },
+    Case {
+        name: "closed box",
+        build: closed_box,
+        sample_ray: ray_from_box_center,
+    },
+];
+
+#[derive(Default)]
+struct Artifacts {
+    leaks: usize,
+    self_intersections: usize,
+}
+
+/// Traces rays through generated scenes that are known to cause precision
+/// problems, and reports how often rays leak through surfaces or hit the
+/// surface they start from. Returns `false` if there are too many artifacts
+/// when the scenes are at the origin.
+pub(crate) fn run() -> bool {
+    let mut passed = true;
+
+    println!(
+        "{:<16} {:>10} {:>10} {:>10}",
+        "case", "offset", "leaks", "acne"
+    );
+
+    for case in &CASES {
+        for &offset in &OFFSETS {
+            let offset_vector = Vector3::new(offset, offset, offset);
+            let shapes = (case.build)(offset_vector);
+            let bvh = Bvh::new(
+                shapes
+                    .iter()
+                    .enumerate()
+                    .map(|(primitive, shape)| WorldShape {
+                        shape,
+                        id: PrimitiveId {
+                            object: 0,
+                            primitive: primitive as u32,
+                        },
+                    })
+                    .collect(),
+            );
+
+            let mut rng = XorShiftRng::seed_from_u64(0);
+            let mut artifacts = Artifacts::default();
+
+            for _ in 0..RAYS_PER_CASE {
+                let ray = (case.sample_ray)(&mut rng, offset_vector);
+                trace_probe(&bvh, ray, &mut rng, &mut artifacts);
+            }
+
+            let leak_rate = artifacts.leaks as f64 / RAYS_PER_CASE as f64;
+            let acne_rate = artifacts.self_intersections as f64 / RAYS_PER_CASE as f64;
+
+            println!(
+                "{:<16} {:>10} {:>9.3}% {:>9.3}%",
+                case.name,
+                offset,
+                leak_rate * 100.0,
+                acne_rate * 100.0
+            );
+
+            if offset == 0.0 && (leak_rate > MAX_ARTIFACT_RATE || acne_rate > MAX_ARTIFACT_RATE) {
+                passed = false;
+            }
+        }
+    }
+
+    passed
+}
+
+/// Every probe ray is expected to hit a surface. A new ray is then sent
+/// back out from the hit, the same way as when a path bounces, and it should
+/// not hit the same surface again. Hitting the same primitive, or anything
+/// within the offset from the surface, counts as a self intersection, while
+/// other surfaces nearby, like adjacent walls, are real hits.
+fn trace_probe(
+    bvh: &Bvh<WorldShape>,
+    ray: Ray3<f32>,
+    rng: &mut impl Rng,
+    artifacts: &mut Artifacts,
+) {
+    let hit = match intersect_shapes(bvh, ray, DIST_EPSILON, std::f32::INFINITY, |_| true) {
+        Some(hit) => hit,
+        None => {
+            artifacts.leaks += 1;
+            return;
+        }
+    };
+
+    let position = hit.surface_point.position;
+    let normal = hit.surface_point.get_surface_data().normal.vector();
+    let normal = if normal.dot(ray.direction) < 0.0 {
+        normal
+    } else {
+        -normal
+    };
+
+    let epsilon = ray_epsilon(position, hit.distance, 1.0);
+    let bounce = offset_ray(
+        Ray3::new(position, sample_hemisphere(rng, normal)),
+        normal,
+        epsilon,
+    );
+    if let Some(bounce_hit) = intersect_shapes(bvh, bounce, 0.0, std::f32::INFINITY, |_| true) {
+        if bounce_hit.id == hit.id || bounce_hit.distance < epsilon {
+            artifacts.self_intersections += 1;
+        }
+    }
+}
+
+/// A 10x10 floor, made of long and thin triangles.
+fn thin_triangles(offset: Vector3<f32>) -> Vec<Shape> {
+    const STRIPS: usize = 500;
+    let width = 10.0 / STRIPS as f32;
+    let mut shapes = Vec::with_capacity(STRIPS * 2);
+
+    for i in 0..STRIPS {
+        let x1 = i as f32 * width;
+        let x2 = x1 + width;
+
+        let a1 = Point3::new(x1, 0.0, 0.0) + offset;
+        let a2 = Point3::new(x2, 0.0, 0.0) + offset;
+        let b1 = Point3::new(x1, 0.0, 10.0) + offset;
+        let b2 = Point3::new(x2, 0.0, 10.0) + offset;
+
+        shapes.push(Shape::flat_triangle(a1, b1, a2, MaterialId::default()));
+        shapes.push(Shape::flat_triangle(a2, b1, b2, MaterialId::default()));
+    }
+
+    shapes
+}
+
+/// Two overlapping 10x10 floors, that are split along different diagonals.
+fn coplanar_faces(offset: Vector3<f32>) -> Vec<Shape> {
+    let a = Point3::new(0.0, 0.0, 0.0) + offset;
+    let b = Point3::new(10.0, 0.0, 0.0) + offset;
+    let c = Point3::new(10.0, 0.0, 10.0) + offset;
+    let d = Point3::new(0.0, 0.0, 10.0) + offset;
+
+    vec![
+        Shape::flat_triangle(a, d, b, MaterialId::default()),
+        Shape::flat_triangle(b, d, c, MaterialId::default()),
+        Shape::flat_triangle(a, c, b, MaterialId::default()),
+        Shape::flat_triangle(a, d, c, MaterialId::default()),
+    ]
+}
+
+/// A closed 2x2x2 box, made of triangles.
+fn closed_box(offset: Vector3<f32>) -> Vec<Shape> {
+    let corner = |x: f32, y: f32, z: f32| Point3::new(x * 2.0, y * 2.0, z * 2.0) + offset;
+    let faces = [
+        [
+            corner(0.0, 0.0, 0.0),
+            corner(1.0, 0.0, 0.0),
+            corner(1.0, 0.0, 1.0),
+            corner(0.0, 0.0, 1.0),
+        ],
+        [
+            corner(0.0, 1.0, 0.0),
+            corner(0.0, 1.0, 1.0),
+            corner(1.0, 1.0, 1.0),
+            corner(1.0, 1.0, 0.0),
+        ],
+        [
+            corner(0.0, 0.0, 0.0),
+            corner(0.0, 0.0, 1.0),
+            corner(0.0, 1.0, 1.0),
+            corner(0.0, 1.0, 0.0),
+        ],
+        [
+            corner(1.0, 0.0, 0.0),
+            corner(1.0, 1.0, 0.0),
+            corner(1.0, 1.0, 1.0),
+            corner(1.0, 0.0, 1.0),
+        ],
+        [
+            corner(0.0, 0.0, 0.0),
+            corner(0.0, 1.0, 0.0),
+            corner(1.0, 1.0, 0.0),
+            corner(1.0, 0.0, 0.0),
+        ],
+        [
+            corner(0.0, 0.0, 1.0),
+            corner(1.0, 0.0, 1.0),
+            corner(1.0, 1.0, 1.0),
+            corner(0.0, 1.0, 1.0),
+        ],
+    ];
+
+    let mut shapes = Vec::with_capacity(12);
+    for &[a, b, c, d] in &faces {
+        shapes.push(Shape::flat_triangle(a, b, c, MaterialId::default()));
+        shapes.push(Shape::flat_triangle(a, c, d, MaterialId::default()));
+    }
+
+    shapes
+}
+
+/// A ray from above, towards a random point on the middle of the floor.
+fn ray_towards_floor(rng: &mut XorShiftRng, offset: Vector3<f32>) -> Ray3<f32> {
+    let origin = Point3::new(rng.gen_range(1.0, 9.0), 1.0, rng.gen_range(1.0, 9.0)) + offset;
+    let target = Point3::new(rng.gen_range(1.0, 9.0), 0.0, rng.gen_range(1.0, 9.0)) + offset;
+
+    Ray3::new(origin, (target - origin).normalize())
+}
+
+/// A ray in a random direction, from the middle of the box.
+fn ray_from_box_center(rng: &mut XorShiftRng, offset: Vector3<f32>) -> Ray3<f32> {
+    let origin = Point3::new(1.0, 1.0, 1.0) + offset;
+
+    Ray3::new(origin, crate::math::utils::sample_sphere(rng))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn self_test_passes() {
+        assert!(super::run(), "the self test found too many artifacts");
+    }
+}
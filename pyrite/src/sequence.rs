@@ -0,0 +1,105 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{utils::json_string, world::Statistics};
+
+/// A frame that has been rendered as part of a sequence.
+pub(crate) struct RenderedFrame {
+    pub frame: u32,
+    pub timecode: String,
+    pub file: PathBuf,
+    pub render_time: Duration,
+    pub triangles: usize,
+    pub shapes: usize,
+    pub lights: usize,
+}
+
+impl RenderedFrame {
+    pub fn new(
+        frame: u32,
+        frame_rate: f32,
+        file: PathBuf,
+        render_time: Duration,
+        statistics: &Statistics,
+    ) -> Self {
+        RenderedFrame {
+            frame,
+            timecode: timecode(frame, frame_rate),
+            file,
+            render_time,
+            triangles: statistics.triangles,
+            shapes: statistics.shapes,
+            lights: statistics.lights,
+        }
+    }
+}
+
+/// Formats a frame number as a non-drop-frame `hours:minutes:seconds:frames`
+/// timecode. Fractional frame rates, like 29.97, are rounded.
+pub(crate) fn timecode(frame: u32, frame_rate: f32) -> String {
+    let frames_per_second = (frame_rate.round() as u32).max(1);
+    let seconds = frame / frames_per_second;
+
+    format!(
+        "{:02}:{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60,
+        frame % frames_per_second
+    )
+}
+
+/// Writes a JSON file that lists the rendered frames of a sequence.
+pub(crate) fn write_manifest(
+    path: &Path,
+    frame_rate: f32,
+    frames: &[RenderedFrame],
+) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"frame_rate\": {},", frame_rate)?;
+    if let (Some(first), Some(last)) = (frames.first(), frames.last()) {
+        writeln!(file, "  \"first_frame\": {},", first.frame)?;
+        writeln!(file, "  \"last_frame\": {},", last.frame)?;
+    }
+    writeln!(file, "  \"frames\": [")?;
+
+    for (i, frame) in frames.iter().enumerate() {
+        let file_name = frame
+            .file
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        writeln!(file, "    {{")?;
+        writeln!(file, "      \"frame\": {},", frame.frame)?;
+        writeln!(file, "      \"timecode\": \"{}\",", frame.timecode)?;
+        writeln!(file, "      \"file\": {},", json_string(&file_name))?;
+        writeln!(
+            file,
+            "      \"render_seconds\": {:.3},",
+            frame.render_time.as_secs_f64()
+        )?;
+        writeln!(file, "      \"triangles\": {},", frame.triangles)?;
+        writeln!(file, "      \"shapes\": {},", frame.shapes)?;
+        writeln!(file, "      \"lights\": {}", frame.lights)?;
+
+        if i + 1 < frames.len() {
+            writeln!(file, "    }},")?;
+        } else {
+            writeln!(file, "    }}")?;
+        }
+    }
+
+    writeln!(file, "  ]")?;
+    writeln!(file, "}}")?;
+
+    file.flush()?;
+    Ok(())
+}
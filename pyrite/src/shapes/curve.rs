@@ -0,0 +1,252 @@
+use std::f32::INFINITY;
+
+use cgmath::{ElementWise, EuclideanSpace, InnerSpace, Matrix4, Point3, Transform, Vector3};
+use collision::{Aabb3, Ray3};
+
+use crate::math::utils::{average_scale, basis};
+
+/// The deepest recursion when intersecting a curve. Each level splits the
+/// curve in two.
+const MAX_DEPTH: u32 = 10;
+
+/// A cubic Bézier curve with a width that changes linearly along it. It's
+/// intersected as a flat ribbon that faces the ray, but shaded like a tube.
+#[derive(Clone)]
+pub struct Curve {
+    points: [Point3<f32>; 4],
+    widths: [f32; 2],
+}
+
+pub struct CurveHit {
+    pub distance: f32,
+    /// The position along the curve.
+    pub u: f32,
+    /// The position across the curve.
+    pub v: f32,
+    pub normal: Vector3<f32>,
+}
+
+impl Curve {
+    pub fn new(points: [Point3<f32>; 4], widths: [f32; 2]) -> Self {
+        Curve { points, widths }
+    }
+
+    /// Based on "Ray Tracing for Curves Primitive" by Nakamaru and Ohno,
+    /// where the curve is moved into a space where the ray starts at the
+    /// origin and points along Z. The curve is split until each part is
+    /// almost straight, and then tested as a line segment.
+    pub fn intersect(&self, ray: &Ray3<f32>) -> Option<CurveHit> {
+        let direction = ray.direction.normalize();
+        let (x_axis, y_axis) = basis(direction);
+        let to_ray_space = |point: Point3<f32>| {
+            let offset = point - ray.origin;
+            Vector3::new(
+                offset.dot(x_axis),
+                offset.dot(y_axis),
+                offset.dot(direction),
+            )
+        };
+        let points = [
+            to_ray_space(self.points[0]),
+            to_ray_space(self.points[1]),
+            to_ray_space(self.points[2]),
+            to_ray_space(self.points[3]),
+        ];
+
+        // Split until the parts deviate less than 5% of the width from a
+        // straight line.
+        let mut curvature: f32 = 0.0;
+        for i in 0..2 {
+            let deviation = points[i] - points[i + 1] * 2.0 + points[i + 2];
+            curvature = curvature
+                .max(deviation.x.abs())
+                .max(deviation.y.abs())
+                .max(deviation.z.abs());
+        }
+        let epsilon = self.max_width() * 0.05;
+        let depth = (std::f32::consts::SQRT_2 * 6.0 * curvature / (8.0 * epsilon)).log2() * 0.5;
+        let depth = if depth > 0.0 {
+            (depth.ceil() as u32).min(MAX_DEPTH)
+        } else {
+            0
+        };
+
+        let mut closest = None;
+        self.intersect_part(&points, &points, 0.0, 1.0, depth, &mut closest);
+        let (distance, u) = closest?;
+
+        // The normal is turned towards the sides of the ribbon, to make it
+        // look round.
+        let center = evaluate(&points, u);
+        let offset = -(x_axis * center.x + y_axis * center.y);
+        let tangent = self.tangent(u);
+        let facing = -direction + tangent * direction.dot(tangent);
+        let facing = if facing.magnitude2() > 0.0 {
+            facing.normalize()
+        } else {
+            basis(tangent).0
+        };
+        let side = tangent.cross(facing);
+        let across = (offset.dot(side) / (self.width(u) * 0.5))
+            .max(-1.0)
+            .min(1.0);
+
+        Some(CurveHit {
+            distance,
+            u,
+            v: 0.5 + across * 0.5,
+            normal: facing * (1.0 - across * across).sqrt() + side * across,
+        })
+    }
+
+    fn intersect_part(
+        &self,
+        curve: &[Vector3<f32>; 4],
+        points: &[Vector3<f32>; 4],
+        u0: f32,
+        u1: f32,
+        depth: u32,
+        closest: &mut Option<(f32, f32)>,
+    ) {
+        let max_distance = closest.map_or(INFINITY, |(distance, _)| distance);
+        let half_width = self.width(u0).max(self.width(u1)) * 0.5;
+        let (min, max) = bounds(points);
+
+        if min.x - half_width > 0.0
+            || max.x + half_width < 0.0
+            || min.y - half_width > 0.0
+            || max.y + half_width < 0.0
+            || max.z + half_width < 0.0
+            || min.z - half_width > max_distance
+        {
+            return;
+        }
+
+        if depth > 0 {
+            let (first, second) = split(points);
+            let middle = (u0 + u1) * 0.5;
+            self.intersect_part(curve, &first, u0, middle, depth - 1, closest);
+            self.intersect_part(curve, &second, middle, u1, depth - 1, closest);
+            return;
+        }
+
+        // The ends of the part are cut off perpendicular to the curve.
+        let start_edge =
+            (points[1].y - points[0].y) * -points[0].y + points[0].x * (points[0].x - points[1].x);
+        let end_edge =
+            (points[2].y - points[3].y) * -points[3].y + points[3].x * (points[3].x - points[2].x);
+        if start_edge < 0.0 || end_edge < 0.0 {
+            return;
+        }
+
+        // Find the closest point on the part, as if it was a straight line.
+        let segment = (points[3] - points[0]).truncate();
+        let length2 = segment.magnitude2();
+        if length2 == 0.0 {
+            return;
+        }
+        let w = -points[0].truncate().dot(segment) / length2;
+        let u = (u0 + (u1 - u0) * w).max(u0).min(u1);
+
+        let half_width = self.width(u) * 0.5;
+        let center = evaluate(curve, u);
+        if center.truncate().magnitude2() > half_width * half_width {
+            return;
+        }
+
+        if center.z > 0.0 && center.z < max_distance {
+            *closest = Some((center.z, u));
+        }
+    }
+
+    pub fn aabb(&self) -> Aabb3<f32> {
+        let points = [
+            self.points[0].to_vec(),
+            self.points[1].to_vec(),
+            self.points[2].to_vec(),
+            self.points[3].to_vec(),
+        ];
+        let (min, max) = bounds(&points);
+        let half_width = self.max_width() * 0.5;
+
+        Aabb3::new(
+            Point3::from_vec(min).sub_element_wise(half_width),
+            Point3::from_vec(max).add_element_wise(half_width),
+        )
+    }
+
+    pub fn scale(&mut self, scale: f32) {
+        for point in &mut self.points {
+            *point *= scale;
+        }
+        self.widths[0] *= scale;
+        self.widths[1] *= scale;
+    }
+
+    /// The width is scaled by the average scale of the transform.
+    pub fn transform(&mut self, transform: Matrix4<f32>) {
+        for point in &mut self.points {
+            *point = transform.transform_point(*point);
+        }
+
+        let scale = average_scale(transform);
+        self.widths[0] *= scale;
+        self.widths[1] *= scale;
+    }
+
+    fn width(&self, u: f32) -> f32 {
+        self.widths[0] * (1.0 - u) + self.widths[1] * u
+    }
+
+    fn max_width(&self) -> f32 {
+        self.widths[0].max(self.widths[1])
+    }
+
+    fn tangent(&self, u: f32) -> Vector3<f32> {
+        let [p0, p1, p2, p3] = self.points;
+        let v = 1.0 - u;
+        let tangent =
+            (p1 - p0) * (3.0 * v * v) + (p2 - p1) * (6.0 * v * u) + (p3 - p2) * (3.0 * u * u);
+
+        // The tangent disappears at the ends if they have repeated points.
+        if tangent.magnitude2() > 0.0 {
+            tangent.normalize()
+        } else {
+            (p3 - p0).normalize()
+        }
+    }
+}
+
+fn evaluate(points: &[Vector3<f32>; 4], u: f32) -> Vector3<f32> {
+    let v = 1.0 - u;
+    points[0] * (v * v * v)
+        + points[1] * (3.0 * v * v * u)
+        + points[2] * (3.0 * v * u * u)
+        + points[3] * (u * u * u)
+}
+
+/// Splits the curve in the middle, using De Casteljau's algorithm.
+fn split(points: &[Vector3<f32>; 4]) -> ([Vector3<f32>; 4], [Vector3<f32>; 4]) {
+    let [p0, p1, p2, p3] = *points;
+    let p01 = (p0 + p1) * 0.5;
+    let p12 = (p1 + p2) * 0.5;
+    let p23 = (p2 + p3) * 0.5;
+    let p012 = (p01 + p12) * 0.5;
+    let p123 = (p12 + p23) * 0.5;
+    let middle = (p012 + p123) * 0.5;
+
+    ([p0, p01, p012, middle], [middle, p123, p23, p3])
+}
+
+/// The curve stays within the bounds of its control points.
+fn bounds(points: &[Vector3<f32>; 4]) -> (Vector3<f32>, Vector3<f32>) {
+    let mut min = points[0];
+    let mut max = points[0];
+
+    for point in &points[1..] {
+        min = Vector3::new(min.x.min(point.x), min.y.min(point.y), min.z.min(point.z));
+        max = Vector3::new(max.x.max(point.x), max.y.max(point.y), max.z.max(point.z));
+    }
+
+    (min, max)
+}
@@ -0,0 +1,232 @@
+use std::{collections::HashMap, error::Error};
+
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector3};
+
+use crate::{
+    project::program::{ExecutionContext, InputFn, Program, ProgramInput, Resources},
+    texture::Footprint,
+};
+
+#[derive(Copy, Clone)]
+pub struct MeshVertex {
+    pub position: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub texture: Point2<f32>,
+}
+
+impl MeshVertex {
+    /// The result is the same in both directions, so the midpoint of a shared
+    /// edge is the same for both of its triangles.
+    fn midpoint(self, other: MeshVertex) -> MeshVertex {
+        MeshVertex {
+            position: Point3::from_vec((self.position.to_vec() + other.position.to_vec()) * 0.5),
+            normal: (self.normal + other.normal).normalize(),
+            texture: Point2::from_vec((self.texture.to_vec() + other.texture.to_vec()) * 0.5),
+        }
+    }
+
+    fn key(&self) -> [u32; 8] {
+        [
+            self.position.x.to_bits(),
+            self.position.y.to_bits(),
+            self.position.z.to_bits(),
+            self.normal.x.to_bits(),
+            self.normal.y.to_bits(),
+            self.normal.z.to_bits(),
+            self.texture.x.to_bits(),
+            self.texture.y.to_bits(),
+        ]
+    }
+}
+
+pub struct DisplacementInput {
+    normal: Vector3<f32>,
+    texture: Point2<f32>,
+    position: Point3<f32>,
+}
+
+impl ProgramInput for DisplacementInput {
+    fn normal() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.normal.into())
+    }
+    fn incident() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Err("the incident direction can't be used for displacement".into())
+    }
+    fn texture_coordinates() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.texture.into())
+    }
+    fn texture_footprint() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, _, _| Footprint::default().into())
+    }
+    fn position() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.position.into())
+    }
+}
+
+/// Subdivides the triangles until no edge is longer than `edge_length`, and
+/// moves each vertex along its normal by the value of `height`. Each edge is
+/// split based on its own length, so neighbouring triangles stay connected.
+/// The longest edge is split at most `max_level` times, if it's set.
+pub fn displace<'p>(
+    triangles: &[[MeshVertex; 3]],
+    height: Program<'p, DisplacementInput, f32>,
+    edge_length: f32,
+    max_level: Option<u32>,
+    smooth: bool,
+    resources: Resources<'p>,
+) -> Vec<[MeshVertex; 3]> {
+    // The limit is the same for every edge, to keep the mesh connected.
+    let edge_length = match max_level {
+        Some(level) => {
+            let mut longest = 0.0f32;
+            for &[a, b, c] in triangles {
+                longest = longest
+                    .max((b.position - a.position).magnitude())
+                    .max((c.position - b.position).magnitude())
+                    .max((a.position - c.position).magnitude());
+            }
+            edge_length.max(longest / 2.0f32.powi(level.min(30) as i32))
+        }
+        None => edge_length,
+    };
+
+    let max_length2 = edge_length * edge_length;
+    let mut subdivided = Vec::with_capacity(triangles.len());
+    for &triangle in triangles {
+        subdivide(triangle, max_length2, &mut subdivided);
+    }
+
+    let mut execution_context = ExecutionContext::new(resources);
+    let mut displaced_positions = HashMap::new();
+    let mut displace_vertex = |vertex: MeshVertex| {
+        let position = *displaced_positions.entry(vertex.key()).or_insert_with(|| {
+            let input = DisplacementInput {
+                normal: vertex.normal,
+                texture: vertex.texture,
+                position: vertex.position,
+            };
+            vertex.position + vertex.normal * execution_context.run(height, &input)
+        });
+
+        MeshVertex { position, ..vertex }
+    };
+
+    let mut displaced = Vec::with_capacity(subdivided.len());
+    for [v1, v2, v3] in subdivided {
+        let triangle = [
+            displace_vertex(v1),
+            displace_vertex(v2),
+            displace_vertex(v3),
+        ];
+
+        // Flattened triangles don't have a normal.
+        if face_normal(&triangle).magnitude2() > 0.0 {
+            displaced.push(triangle);
+        }
+    }
+
+    if smooth {
+        smooth_normals(&mut displaced);
+    } else {
+        for triangle in &mut displaced {
+            let normal = face_normal(triangle).normalize();
+            for vertex in triangle {
+                vertex.normal = normal;
+            }
+        }
+    }
+
+    displaced
+}
+
+fn subdivide(triangle: [MeshVertex; 3], max_length2: f32, output: &mut Vec<[MeshVertex; 3]>) {
+    let [a, b, c] = triangle;
+    let split =
+        |from: MeshVertex, to: MeshVertex| (to.position - from.position).magnitude2() > max_length2;
+
+    match (split(a, b), split(b, c), split(c, a)) {
+        (false, false, false) => output.push(triangle),
+        (true, false, false) => {
+            let ab = a.midpoint(b);
+            subdivide([a, ab, c], max_length2, output);
+            subdivide([ab, b, c], max_length2, output);
+        }
+        (false, true, false) => {
+            let bc = b.midpoint(c);
+            subdivide([a, b, bc], max_length2, output);
+            subdivide([a, bc, c], max_length2, output);
+        }
+        (false, false, true) => {
+            let ca = c.midpoint(a);
+            subdivide([a, b, ca], max_length2, output);
+            subdivide([ca, b, c], max_length2, output);
+        }
+        (true, true, false) => {
+            let ab = a.midpoint(b);
+            let bc = b.midpoint(c);
+            subdivide([ab, b, bc], max_length2, output);
+            subdivide([a, ab, bc], max_length2, output);
+            subdivide([a, bc, c], max_length2, output);
+        }
+        (false, true, true) => {
+            let bc = b.midpoint(c);
+            let ca = c.midpoint(a);
+            subdivide([a, b, bc], max_length2, output);
+            subdivide([a, bc, ca], max_length2, output);
+            subdivide([ca, bc, c], max_length2, output);
+        }
+        (true, false, true) => {
+            let ab = a.midpoint(b);
+            let ca = c.midpoint(a);
+            subdivide([a, ab, ca], max_length2, output);
+            subdivide([ab, b, c], max_length2, output);
+            subdivide([ab, c, ca], max_length2, output);
+        }
+        (true, true, true) => {
+            let ab = a.midpoint(b);
+            let bc = b.midpoint(c);
+            let ca = c.midpoint(a);
+            subdivide([a, ab, ca], max_length2, output);
+            subdivide([ab, b, bc], max_length2, output);
+            subdivide([ca, bc, c], max_length2, output);
+            subdivide([ab, bc, ca], max_length2, output);
+        }
+    }
+}
+
+/// Replaces the normals with the average of the surrounding triangles'
+/// normals. Vertices with different original normals are kept apart, to
+/// preserve hard edges.
+fn smooth_normals(triangles: &mut [[MeshVertex; 3]]) {
+    let key = |vertex: &MeshVertex| {
+        [
+            vertex.position.x.to_bits(),
+            vertex.position.y.to_bits(),
+            vertex.position.z.to_bits(),
+            vertex.normal.x.to_bits(),
+            vertex.normal.y.to_bits(),
+            vertex.normal.z.to_bits(),
+        ]
+    };
+
+    let mut normals = HashMap::new();
+    for triangle in triangles.iter() {
+        // The unnormalized normal gives larger triangles more influence.
+        let normal = face_normal(triangle);
+        for vertex in triangle {
+            *normals
+                .entry(key(vertex))
+                .or_insert(Vector3::new(0.0, 0.0, 0.0)) += normal;
+        }
+    }
+
+    for triangle in triangles {
+        for vertex in triangle {
+            vertex.normal = normals[&key(vertex)].normalize();
+        }
+    }
+}
+
+fn face_normal([v1, v2, v3]: &[MeshVertex; 3]) -> Vector3<f32> {
+    (v2.position - v1.position).cross(v3.position - v1.position)
+}
@@ -1,7 +1,13 @@
+use std::error::Error;
+
 use cgmath::{EuclideanSpace, InnerSpace, Point3, Quaternion, Vector3};
 
-use crate::tracer::ParametricValue;
+use crate::{
+    project::expressions::{BinaryOperator, ComplexExpression, Expression, Expressions},
+    tracer::ParametricValue,
+};
 
+#[derive(Clone, PartialEq)]
 pub struct Mandelbulb {
     pub iterations: u16,
     pub threshold: f32,
@@ -41,6 +47,7 @@ impl ParametricValue<Point3<f32>, f32> for Mandelbulb {
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub struct QuaternionJulia {
     pub iterations: u16,
     pub threshold: f32,
@@ -69,6 +76,7 @@ impl ParametricValue<Point3<f32>, f32> for QuaternionJulia {
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum QuatMul {
     Regular,
     Cubic,
@@ -105,3 +113,227 @@ fn bicomplex_mul(a: &Quaternion<f32>, b: &Quaternion<f32>) -> Quaternion<f32> {
     let w = x1 * w2 + y1 * z2 + z1 * y2 + w1 * x2;
     Quaternion::new(x, y, z, w)
 }
+
+/// A distance function, built from an expression of the surrounding position.
+#[derive(Clone, PartialEq)]
+pub struct DistanceField {
+    root: DistanceNode,
+}
+
+impl DistanceField {
+    pub fn new(expression: Expression, expressions: &Expressions) -> Result<Self, Box<dyn Error>> {
+        let root = DistanceNode::new(expression, expressions)?;
+
+        if root.is_vector() {
+            return Err("a distance field has to be a number, but found a vector".into());
+        }
+
+        Ok(DistanceField { root })
+    }
+}
+
+impl ParametricValue<Point3<f32>, f32> for DistanceField {
+    fn get(&self, point: &Point3<f32>) -> f32 {
+        self.root.get(point).number()
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum DistanceNode {
+    Number(f32),
+    Vector(Box<[DistanceNode; 3]>),
+    Position,
+    Binary(BinaryOperator, Box<DistanceNode>, Box<DistanceNode>),
+    Mix(Box<[DistanceNode; 3]>),
+    Length(Box<DistanceNode>),
+    Abs(Box<DistanceNode>),
+    Min(Box<DistanceNode>, Box<DistanceNode>),
+    Max(Box<DistanceNode>, Box<DistanceNode>),
+    SmoothMin(Box<[DistanceNode; 3]>),
+}
+
+impl DistanceNode {
+    fn new(expression: Expression, expressions: &Expressions) -> Result<Self, Box<dyn Error>> {
+        let id = match expression {
+            Expression::Number(number) => return Ok(DistanceNode::Number(number as f32)),
+            Expression::Complex(id) => id,
+        };
+
+        let node = |expression| DistanceNode::new(expression, expressions);
+        let number = |expression| {
+            let node = DistanceNode::new(expression, expressions)?;
+            if node.is_vector() {
+                Err("expected a number in a distance field, but found a vector")?
+            }
+            Ok::<_, Box<dyn Error>>(node)
+        };
+
+        let node = match *expressions.get(id) {
+            ComplexExpression::Vector { x, y, z, .. } => {
+                DistanceNode::Vector(Box::new([number(x)?, number(y)?, number(z)?]))
+            }
+            ComplexExpression::Binary { operator, lhs, rhs } => {
+                DistanceNode::Binary(operator, Box::new(node(lhs)?), Box::new(node(rhs)?))
+            }
+            ComplexExpression::Mix { amount, lhs, rhs } => {
+                DistanceNode::Mix(Box::new([number(amount)?, node(lhs)?, node(rhs)?]))
+            }
+            ComplexExpression::Position => DistanceNode::Position,
+            ComplexExpression::Length { vector } => DistanceNode::Length(Box::new(node(vector)?)),
+            ComplexExpression::Abs { value } => DistanceNode::Abs(Box::new(node(value)?)),
+            ComplexExpression::Min { lhs, rhs } => {
+                DistanceNode::Min(Box::new(node(lhs)?), Box::new(node(rhs)?))
+            }
+            ComplexExpression::Max { lhs, rhs } => {
+                DistanceNode::Max(Box::new(node(lhs)?), Box::new(node(rhs)?))
+            }
+            ComplexExpression::SmoothMin { lhs, rhs, radius } => {
+                DistanceNode::SmoothMin(Box::new([node(lhs)?, node(rhs)?, number(radius)?]))
+            }
+            ComplexExpression::Rgb { .. } => Err("RGB colors can't be used in distance fields")?,
+            ComplexExpression::Fresnel { .. } | ComplexExpression::ConductorFresnel { .. } => {
+                Err("Fresnel functions can't be used in distance fields")?
+            }
+            ComplexExpression::Blackbody { .. } => {
+                Err("black-body functions can't be used in distance fields")?
+            }
+            ComplexExpression::AngleProfile { .. } => {
+                Err("angle profiles can't be used in distance fields")?
+            }
+            ComplexExpression::DirectionRegion { .. } => {
+                Err("direction regions can't be used in distance fields")?
+            }
+            ComplexExpression::DaylightSky { .. } | ComplexExpression::Sunlight { .. } => {
+                Err("daylight can't be used in distance fields")?
+            }
+            ComplexExpression::Spectrum { .. } => Err("spectra can't be used in distance fields")?,
+            ComplexExpression::Texture { .. } => Err("textures can't be used in distance fields")?,
+            ComplexExpression::Noise { .. } => Err("noise can't be used in distance fields")?,
+            ComplexExpression::Volume { .. } => {
+                Err("3D textures can't be used in distance fields")?
+            }
+            ComplexExpression::Environment { .. } => {
+                Err("environment maps can't be used in distance fields")?
+            }
+            ComplexExpression::DebugNormal => {
+                Err("surface normals can't be used in distance fields")?
+            }
+        };
+
+        Ok(node)
+    }
+
+    fn is_vector(&self) -> bool {
+        match self {
+            DistanceNode::Number(_) | DistanceNode::Length(_) => false,
+            DistanceNode::Vector(_) | DistanceNode::Position => true,
+            DistanceNode::Binary(_, lhs, rhs)
+            | DistanceNode::Min(lhs, rhs)
+            | DistanceNode::Max(lhs, rhs) => lhs.is_vector() || rhs.is_vector(),
+            DistanceNode::Mix(nodes) => nodes[1].is_vector() || nodes[2].is_vector(),
+            DistanceNode::SmoothMin(nodes) => nodes[0].is_vector() || nodes[1].is_vector(),
+            DistanceNode::Abs(value) => value.is_vector(),
+        }
+    }
+
+    fn get(&self, point: &Point3<f32>) -> DistanceValue {
+        match self {
+            &DistanceNode::Number(number) => DistanceValue::Number(number),
+            DistanceNode::Vector(components) => DistanceValue::Vector(Vector3::new(
+                components[0].get(point).number(),
+                components[1].get(point).number(),
+                components[2].get(point).number(),
+            )),
+            DistanceNode::Position => DistanceValue::Vector(point.to_vec()),
+            DistanceNode::Binary(operator, lhs, rhs) => {
+                let function: fn(f32, f32) -> f32 = match operator {
+                    BinaryOperator::Add => |lhs, rhs| lhs + rhs,
+                    BinaryOperator::Sub => |lhs, rhs| lhs - rhs,
+                    BinaryOperator::Mul => |lhs, rhs| lhs * rhs,
+                    BinaryOperator::Div => |lhs, rhs| lhs / rhs,
+                };
+                lhs.get(point).zip(rhs.get(point), function)
+            }
+            DistanceNode::Mix(nodes) => {
+                let amount = nodes[0].get(point).number().min(1.0).max(0.0);
+                nodes[1].get(point).zip(nodes[2].get(point), |lhs, rhs| {
+                    lhs * (1.0 - amount) + rhs * amount
+                })
+            }
+            DistanceNode::Length(vector) => match vector.get(point) {
+                DistanceValue::Number(number) => DistanceValue::Number(number.abs()),
+                DistanceValue::Vector(vector) => DistanceValue::Number(vector.magnitude()),
+            },
+            DistanceNode::Abs(value) => match value.get(point) {
+                DistanceValue::Number(number) => DistanceValue::Number(number.abs()),
+                DistanceValue::Vector(vector) => DistanceValue::Vector(Vector3::new(
+                    vector.x.abs(),
+                    vector.y.abs(),
+                    vector.z.abs(),
+                )),
+            },
+            DistanceNode::Min(lhs, rhs) => lhs.get(point).zip(rhs.get(point), f32::min),
+            DistanceNode::Max(lhs, rhs) => lhs.get(point).zip(rhs.get(point), f32::max),
+            DistanceNode::SmoothMin(nodes) => {
+                let radius = nodes[2].get(point).number();
+                nodes[0]
+                    .get(point)
+                    .zip(nodes[1].get(point), |lhs, rhs| smooth_min(lhs, rhs, radius))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum DistanceValue {
+    Number(f32),
+    Vector(Vector3<f32>),
+}
+
+impl DistanceValue {
+    fn number(self) -> f32 {
+        match self {
+            DistanceValue::Number(number) => number,
+            DistanceValue::Vector(_) => {
+                unreachable!("vectors are rejected where numbers are expected when the distance field is built")
+            }
+        }
+    }
+
+    /// Applies `function` to each component. Numbers are repeated if they
+    /// are combined with vectors.
+    fn zip(self, other: DistanceValue, function: impl Fn(f32, f32) -> f32) -> DistanceValue {
+        match (self, other) {
+            (DistanceValue::Number(lhs), DistanceValue::Number(rhs)) => {
+                DistanceValue::Number(function(lhs, rhs))
+            }
+            (lhs, rhs) => {
+                let lhs = lhs.vector();
+                let rhs = rhs.vector();
+                DistanceValue::Vector(Vector3::new(
+                    function(lhs.x, rhs.x),
+                    function(lhs.y, rhs.y),
+                    function(lhs.z, rhs.z),
+                ))
+            }
+        }
+    }
+
+    fn vector(self) -> Vector3<f32> {
+        match self {
+            DistanceValue::Number(number) => Vector3::new(number, number, number),
+            DistanceValue::Vector(vector) => vector,
+        }
+    }
+}
+
+/// A polynomial smooth minimum, that blends the two values where they are
+/// within `radius` of each other.
+fn smooth_min(a: f32, b: f32, radius: f32) -> f32 {
+    if radius <= 0.0 {
+        return a.min(b);
+    }
+
+    let h = (radius - (a - b).abs()).max(0.0) / radius;
+    a.min(b) - h * h * radius * 0.25
+}
@@ -1,451 +1,1913 @@
 use std;
+
+use std::f32::INFINITY;
 use std::sync::Arc;
 
-use std::f32::INFINITY;
+use cgmath::{
+    ElementWise, EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point2, Point3, Quaternion,
+    Rad, SquareMatrix, Transform, Vector2, Vector3,
+};
+use collision::{Aabb, Aabb3, Continuous, Ray3, Union};
+
+use rand::{Rng, RngCore};
+
+use crate::tracer::ParametricValue;
+
+use crate::materials::MaterialId;
+use crate::math::{self, utils::Roots, DIST_EPSILON};
+use crate::project::CsgOperation;
+use crate::spatial::bvh::{Bounded, Bvh};
+
+const EPSILON: f32 = DIST_EPSILON;
+
+pub mod curve;
+pub mod displacement;
+pub mod distance_estimators;
+pub mod point_cloud;
+
+type DistanceEstimator = Box<dyn ParametricValue<Point3<f32>, f32>>;
+
+pub struct Vertex {
+    pub position: Point3<f32>,
+    pub normal: Normal,
+    pub texture: Point2<f32>,
+}
+
+pub(crate) enum Shape {
+    Sphere(Sphere),
+    Triangle(Triangle),
+    RayMarched(RayMarched),
+    Rectangle(Rectangle),
+    Disk(Disk),
+    Torus(Torus),
+    Cuboid(Cuboid),
+    Curve(Curve),
+    PointCloud(PointCloud),
+    Csg(Csg),
+    Transformed(Transformed),
+    Instance(Instance),
+}
+
+pub(crate) struct Sphere {
+    pub position: Point3<f32>,
+    pub radius: f32,
+    pub texture_scale: Vector2<f32>,
+    pub material: MaterialId,
+}
+
+pub(crate) struct Triangle {
+    pub v1: Vertex,
+    pub v2: Vertex,
+    pub v3: Vertex,
+    pub edge1: Vector3<f32>,
+    pub edge2: Vector3<f32>,
+    pub material: MaterialId,
+}
+
+pub(crate) struct RayMarched {
+    pub estimator: DistanceEstimator,
+    pub bounds: BoundingVolume,
+    pub material: MaterialId,
+}
+
+/// A bounded plane, centered on `origin`. Its sides follow the X and Y
+/// axes of the normal's space.
+pub(crate) struct Rectangle {
+    pub origin: Point3<f32>,
+    pub normal: Normal,
+    pub size: Vector2<f32>,
+    pub texture_scale: Vector2<f32>,
+    pub material: MaterialId,
+}
+
+/// A flat disk, optionally with a hole in the middle.
+pub(crate) struct Disk {
+    pub origin: Point3<f32>,
+    pub normal: Normal,
+    pub radius: f32,
+    pub inner_radius: f32,
+    pub texture_scale: Vector2<f32>,
+    pub material: MaterialId,
+}
+
+/// A ring shaped tube around `position`. The ring lies in the plane of
+/// the axis' X and Y directions.
+pub(crate) struct Torus {
+    pub position: Point3<f32>,
+    pub axis: Normal,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub texture_scale: Vector2<f32>,
+    pub material: MaterialId,
+}
+
+/// A box, centered on the origin of its local space.
+pub(crate) struct Cuboid {
+    pub half_size: Vector3<f32>,
+    pub to_world: Matrix4<f32>,
+    pub to_local: Matrix4<f32>,
+    pub texture_scale: Vector2<f32>,
+    pub material: MaterialId,
+}
+
+/// A curve, for hair and fur.
+pub(crate) struct Curve {
+    pub curve: curve::Curve,
+    pub material: MaterialId,
+}
+
+/// Many small spheres or disks.
+pub(crate) struct PointCloud {
+    pub cloud: point_cloud::PointCloud,
+    pub material: MaterialId,
+}
+
+/// Two solid shapes, combined into one.
+pub(crate) struct Csg {
+    pub operation: CsgOperation,
+    pub first: Box<Shape>,
+    pub second: Box<Shape>,
+}
+
+/// A shape in its own local space. Rays are moved into that space, using
+/// the cached inverse, before intersecting the shape.
+pub(crate) struct Transformed {
+    pub shape: Box<Shape>,
+    pub to_world: Matrix4<f32>,
+    pub to_local: Matrix4<f32>,
+}
+
+/// A transformed copy of a prototype, that shares its shapes and BVH with
+/// the other copies.
+pub(crate) struct Instance {
+    pub prototype: Arc<Prototype>,
+    pub to_world: Matrix4<f32>,
+    pub to_local: Matrix4<f32>,
+    pub material: Option<MaterialId>,
+}
+/// The parts of a shape that the BVH and the light sampling need, so any
+/// shape that implements it can be used in both.
+pub(crate) trait Primitive: Sync {
+    fn bounding_box(&self) -> Aabb3<f32>;
+
+    /// The area is infinite for shapes that can't be sampled.
+    fn surface_area(&self) -> f32;
+
+    /// Picks a random point on the surface, if the shape can be sampled.
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint>;
+
+    /// Picks a random point on the surface, with a preference for the parts
+    /// that are visible from `target`.
+    fn sample_towards(&self, rng: &mut dyn RngCore, target: &Point3<f32>) -> Option<Intersection> {
+        self.sample_point(rng).map(|surface_point| Intersection {
+            distance: (surface_point.position - target).magnitude(),
+            id: PrimitiveId::default(),
+            surface_point,
+        })
+    }
+
+    /// The solid angle that `sample_towards` covers, if it only picks
+    /// visible points.
+    fn solid_angle_towards(&self, _target: &Point3<f32>) -> Option<f32> {
+        None
+    }
+
+    /// The normal of the whole surface, if it's flat.
+    fn flat_normal(&self) -> Option<Vector3<f32>> {
+        None
+    }
+
+    fn material(&self) -> MaterialId;
+}
+
+impl<'a> Bounded for dyn Primitive + 'a {
+    fn aabb(&self) -> Aabb3<f32> {
+        self.bounding_box()
+    }
+}
+
+pub(crate) struct Prototype {
+    shapes: Bvh<Shape>,
+    bounds: Aabb3<f32>,
+}
+
+impl Prototype {
+    pub fn new(shapes: Vec<Shape>) -> Self {
+        let bounds = shapes
+            .iter()
+            .map(Bounded::aabb)
+            .fold(None, |bounds: Option<Aabb3<f32>>, aabb| {
+                Some(bounds.map_or(aabb, |bounds| bounds.union(&aabb)))
+            })
+            .unwrap_or_else(|| Aabb3::new(Point3::origin(), Point3::origin()));
+
+        Prototype {
+            shapes: Bvh::new(shapes),
+            bounds,
+        }
+    }
+
+    pub fn bvh(&self) -> &Bvh<Shape> {
+        &self.shapes
+    }
+}
+
+impl Shape {
+    /// A triangle with the same normal across the whole surface.
+    pub fn flat_triangle(
+        v1: Point3<f32>,
+        v2: Point3<f32>,
+        v3: Point3<f32>,
+        material: MaterialId,
+    ) -> Self {
+        let edge1 = v2 - v1;
+        let edge2 = v3 - v1;
+        let normal = Normal::from_vector(edge1.cross(edge2).normalize());
+        let vertex = |position| Vertex {
+            position,
+            normal,
+            texture: Point2::origin(),
+        };
+
+        Shape::Triangle(Triangle {
+            v1: vertex(v1),
+            v2: vertex(v2),
+            v3: vertex(v3),
+            edge1,
+            edge2,
+            material,
+        })
+    }
+
+    pub fn cuboid(
+        size: Vector3<f32>,
+        transform: Matrix4<f32>,
+        texture_scale: Vector2<f32>,
+        material: MaterialId,
+    ) -> Option<Self> {
+        Some(Shape::Cuboid(Cuboid {
+            half_size: size * 0.5,
+            to_world: transform,
+            to_local: transform.invert()?,
+            texture_scale,
+            material,
+        }))
+    }
+
+    pub fn curve(curve: curve::Curve, material: MaterialId) -> Self {
+        Shape::Curve(Curve { curve, material })
+    }
+
+    pub fn point_cloud(cloud: point_cloud::PointCloud, material: MaterialId) -> Self {
+        Shape::PointCloud(PointCloud { cloud, material })
+    }
+
+    /// Places a shape in a transformed space. Transforming an already
+    /// transformed shape combines the transforms.
+    pub fn transformed(shape: Shape, transform: Matrix4<f32>) -> Option<Self> {
+        match shape {
+            Shape::Transformed(Transformed {
+                shape, to_world, ..
+            }) => Shape::transformed(*shape, transform * to_world),
+            shape => Some(Shape::Transformed(Transformed {
+                shape: Box::new(shape),
+                to_world: transform,
+                to_local: transform.invert()?,
+            })),
+        }
+    }
+
+    /// Places a copy of a prototype in the scene. The material replaces the
+    /// prototype's materials, if there is one.
+    pub fn instance(
+        prototype: Arc<Prototype>,
+        transform: Matrix4<f32>,
+        material: Option<MaterialId>,
+    ) -> Option<Self> {
+        Some(Shape::Instance(Instance {
+            prototype,
+            to_world: transform,
+            to_local: transform.invert()?,
+            material,
+        }))
+    }
+
+    pub fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        match self {
+            Shape::Sphere(shape) => shape.ray_intersect(ray),
+            Shape::Triangle(shape) => shape.ray_intersect(ray),
+            Shape::RayMarched(shape) => shape.ray_intersect(ray),
+            Shape::Rectangle(shape) => shape.ray_intersect(ray),
+            Shape::Disk(shape) => shape.ray_intersect(ray),
+            Shape::Torus(shape) => shape.ray_intersect(ray),
+            Shape::Cuboid(shape) => shape.ray_intersect(ray),
+            Shape::Curve(shape) => shape.ray_intersect(ray),
+            Shape::PointCloud(shape) => shape.ray_intersect(ray),
+            Shape::Csg(_) => self
+                .solid_crossings(ray)
+                .into_iter()
+                .find(|crossing| crossing.distance > 0.0)
+                .map(|crossing| Intersection {
+                    distance: crossing.distance,
+                    id: PrimitiveId::default(),
+                    surface_point: SurfacePoint {
+                        position: ray.origin + ray.direction * crossing.distance,
+                        shape: ShapeSurfacePoint::Csg {
+                            shape: crossing.shape,
+                            inverted: crossing.inverted,
+                        },
+                    },
+                }),
+            Shape::Transformed(shape) => shape.ray_intersect(ray),
+            Shape::Instance(shape) => shape.ray_intersect(ray),
+        }
+    }
+
+    /// Finds every point where the line of the ray crosses the surface of a
+    /// solid shape, including the points behind the ray's origin.
+    fn solid_crossings(&self, ray: &Ray3<f32>) -> Vec<Crossing> {
+        match self {
+            &Shape::Sphere(Sphere {
+                position, radius, ..
+            }) => {
+                let offset = ray.origin - position;
+                let b = offset.dot(ray.direction);
+                let c = offset.magnitude2() - radius * radius;
+                let discriminant = b * b - c;
+                if discriminant < 0.0 {
+                    return vec![];
+                }
+
+                let root = discriminant.sqrt();
+                vec![
+                    Crossing::new(-b - root, true, self),
+                    Crossing::new(-b + root, false, self),
+                ]
+            }
+            Shape::Torus(torus) => {
+                // The line starts outside the torus, so every other crossing
+                // enters it.
+                torus
+                    .distances(ray, -INFINITY)
+                    .enumerate()
+                    .map(|(index, distance)| Crossing::new(distance, index % 2 == 0, self))
+                    .collect()
+            }
+            Shape::Cuboid(cuboid) => match cuboid.span(ray) {
+                Some((near, far)) => vec![
+                    Crossing::new(near, true, self),
+                    Crossing::new(far, false, self),
+                ],
+                None => vec![],
+            },
+            Shape::Csg(Csg {
+                operation,
+                first,
+                second,
+            }) => {
+                let first_crossings = first.solid_crossings(ray);
+                let second_crossings = second.solid_crossings(ray);
+
+                let mut crossings = Vec::new();
+                let mut first_crossings = first_crossings.into_iter().peekable();
+                let mut second_crossings = second_crossings.into_iter().peekable();
+                let mut inside_first = false;
+                let mut inside_second = false;
+                let mut inside = false;
+
+                loop {
+                    let take_first = match (first_crossings.peek(), second_crossings.peek()) {
+                        (Some(a), Some(b)) => a.distance <= b.distance,
+                        (Some(_), None) => true,
+                        (None, Some(_)) => false,
+                        (None, None) => break,
+                    };
+
+                    let mut crossing = if take_first {
+                        let crossing = first_crossings.next().unwrap();
+                        inside_first = crossing.entering;
+                        crossing
+                    } else {
+                        let mut crossing = second_crossings.next().unwrap();
+                        inside_second = crossing.entering;
+
+                        // The surface of a cut out shape faces inwards.
+                        if let CsgOperation::Difference = operation {
+                            crossing.inverted = !crossing.inverted;
+                        }
+                        crossing
+                    };
+
+                    let now_inside = operation.contains(inside_first, inside_second);
+                    if now_inside != inside {
+                        inside = now_inside;
+                        crossing.entering = inside;
+                        crossings.push(crossing);
+                    }
+                }
+
+                crossings
+            }
+            Shape::Triangle(_)
+            | Shape::RayMarched(_)
+            | Shape::Rectangle(_)
+            | Shape::Disk(_)
+            | Shape::Curve(_)
+            | Shape::PointCloud(_)
+            | Shape::Transformed(_)
+            | Shape::Instance(_) => panic!("only solid shapes can be combined"),
+        }
+    }
+
+    /// The surface data of a shape that's a part of a CSG or transformed
+    /// shape, where `position` is in the shape's own space.
+    fn part_surface_data(&self, position: Point3<f32>) -> SurfaceData {
+        match self {
+            Shape::Sphere(shape) => shape.surface_data(position),
+            Shape::Rectangle(shape) => shape.surface_data(position),
+            Shape::Disk(shape) => shape.surface_data(position),
+            Shape::Torus(shape) => shape.surface_data(position),
+            Shape::Cuboid(shape) => shape.surface_data(position),
+            Shape::RayMarched(shape) => shape.surface_data(position),
+            _ => panic!("unexpected shape inside a combined or transformed shape"),
+        }
+    }
+
+    pub fn scale(&mut self, scale: f32) {
+        match self {
+            Shape::Sphere(shape) => shape.scale(scale),
+            Shape::Triangle(shape) => shape.scale(scale),
+            Shape::RayMarched(_) => {}
+            Shape::Rectangle(shape) => shape.scale(scale),
+            Shape::Disk(shape) => shape.scale(scale),
+            Shape::Torus(shape) => shape.scale(scale),
+            Shape::Cuboid(shape) => shape.transform(Matrix4::from_scale(scale)),
+            Shape::Curve(shape) => shape.curve.scale(scale),
+            Shape::PointCloud(shape) => shape.cloud.transform(Matrix4::from_scale(scale)),
+            Shape::Csg(shape) => {
+                shape.first.scale(scale);
+                shape.second.scale(scale);
+            }
+            Shape::Transformed(shape) => shape.transform(Matrix4::from_scale(scale)),
+            Shape::Instance(shape) => shape.transform(Matrix4::from_scale(scale)),
+        }
+    }
+
+    pub fn transform(&mut self, transform: Matrix4<f32>) {
+        match self {
+            Shape::Sphere(shape) => {
+                shape.position = transform.transform_point(shape.position);
+            }
+            Shape::Triangle(shape) => shape.transform(transform),
+            Shape::RayMarched(_) => {}
+            Shape::Rectangle(shape) => {
+                shape.origin = transform.transform_point(shape.origin);
+                shape.normal = shape.normal.transform(transform);
+            }
+            Shape::Disk(shape) => {
+                shape.origin = transform.transform_point(shape.origin);
+                shape.normal = shape.normal.transform(transform);
+            }
+            Shape::Torus(shape) => {
+                shape.position = transform.transform_point(shape.position);
+                shape.axis = shape.axis.transform(transform);
+            }
+            Shape::Cuboid(shape) => shape.transform(transform),
+            Shape::Curve(shape) => shape.curve.transform(transform),
+            Shape::PointCloud(shape) => shape.cloud.transform(transform),
+            Shape::Csg(shape) => {
+                shape.first.transform(transform);
+                shape.second.transform(transform);
+            }
+            Shape::Transformed(shape) => shape.transform(transform),
+            Shape::Instance(shape) => shape.transform(transform),
+        }
+    }
+
+    /// Roughly how many texture coordinate units there are per world unit
+    /// along the surface, for picking the texture level that matches a ray's
+    /// footprint. It's 0 for shapes without texture coordinates.
+    fn texture_density(&self) -> f32 {
+        match self {
+            Shape::Sphere(shape) => shape.texture_density(),
+            Shape::Triangle(shape) => shape.texture_density(),
+            Shape::Rectangle(shape) => texture_density(1.0, 1.0, shape.texture_scale),
+            Shape::Disk(shape) => shape.texture_density(),
+            Shape::Torus(shape) => shape.texture_density(),
+            Shape::Cuboid(shape) => texture_density(1.0, 1.0, shape.texture_scale),
+            Shape::Csg(shape) => shape.first.texture_density(),
+            Shape::Transformed(shape) => shape.texture_density(),
+            Shape::RayMarched(_) | Shape::Curve(_) | Shape::PointCloud(_) | Shape::Instance(_) => {
+                0.0
+            }
+        }
+    }
+}
+
+/// The texture density of a surface that's `u_length` by `v_length` units
+/// in texture space, before scaling.
+fn texture_density(u_length: f32, v_length: f32, texture_scale: Vector2<f32>) -> f32 {
+    1.0 / (u_length * v_length * texture_scale.x * texture_scale.y)
+        .abs()
+        .sqrt()
+}
+
+impl Sphere {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let sphere = collision::Sphere {
+            radius: self.radius,
+            center: self.position,
+        };
+
+        sphere.intersection(ray).map(|intersection| Intersection {
+            distance: (intersection - ray.origin).magnitude(),
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: intersection,
+                shape: ShapeSurfacePoint::Sphere { shape: self },
+            },
+        })
+    }
+
+    fn scale(&mut self, scale: f32) {
+        self.radius *= scale;
+        self.position *= scale;
+    }
+
+    fn surface_data(&self, surface_position: Point3<f32>) -> SurfaceData {
+        let normal = (surface_position - self.position).normalize();
+        let latitude = normal.y.acos();
+        let longitude = normal.x.atan2(normal.z);
+
+        let rotation = Matrix3::from_angle_y(Rad(longitude))
+            * Matrix3::from_angle_x(Rad(latitude - std::f32::consts::PI * 0.5));
+
+        let texture_coordinates = Vector2::new(
+            longitude * std::f32::consts::FRAC_1_PI * 0.5,
+            1.0 - (latitude * std::f32::consts::FRAC_1_PI),
+        );
+
+        SurfaceData {
+            normal: Normal::new(normal, rotation.into()),
+            texture: Point2::from_vec(texture_coordinates.div_element_wise(self.texture_scale)),
+        }
+    }
+
+    fn texture_density(&self) -> f32 {
+        use std::f32::consts::PI;
+
+        texture_density(2.0 * PI * self.radius, PI * self.radius, self.texture_scale)
+    }
+}
+
+impl Triangle {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let Triangle {
+            ref v1,
+            edge1: e1,
+            edge2: e2,
+            ..
+        } = *self;
+
+        //Möller–Trumbore intersection algorithm
+        let p = ray.direction.cross(e2);
+        let det = e1.dot(p);
+
+        if det > -EPSILON && det < EPSILON {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let t = ray.origin - v1.position;
+        let u = t.dot(p) * inv_det;
+
+        //Outside triangle
+        if u < 0.0 || u > 1.0 {
+            return None;
+        }
+
+        let q = t.cross(e1);
+        let v = ray.direction.dot(q) * inv_det;
+
+        //Outside triangle
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let dist = e2.dot(q) * inv_det;
+        if dist > 0.0 {
+            let hit_position = ray.origin + ray.direction * dist;
+            Some(Intersection {
+                distance: dist,
+                id: PrimitiveId::default(),
+                surface_point: SurfacePoint {
+                    position: hit_position,
+                    shape: ShapeSurfacePoint::Triangle { shape: self, u, v },
+                },
+            })
+        } else {
+            None
+        }
+    }
+
+    fn scale(&mut self, scale: f32) {
+        self.v1.position *= scale;
+        self.v2.position *= scale;
+        self.v3.position *= scale;
+        self.edge1 = self.v2.position - self.v1.position;
+        self.edge2 = self.v3.position - self.v1.position;
+    }
+
+    fn transform(&mut self, transform: Matrix4<f32>) {
+        for vertex in [&mut self.v1, &mut self.v2, &mut self.v3] {
+            vertex.normal = vertex.normal.transform(transform);
+            vertex.position = transform.transform_point(vertex.position);
+        }
+        self.edge1 = self.v2.position - self.v1.position;
+        self.edge2 = self.v3.position - self.v1.position;
+    }
+
+    fn surface_data(&self, u: f32, v: f32) -> SurfaceData {
+        let Triangle { v1, v2, v3, .. } = self;
+        let normal = Normal::on_triangle(v1.normal, v2.normal, v3.normal, u, v);
+        let texture = (v1.texture * (1.0 - (u + v)))
+            .add_element_wise(v2.texture * u)
+            .add_element_wise(v3.texture * v);
+
+        SurfaceData { normal, texture }
+    }
+
+    fn texture_density(&self) -> f32 {
+        let texture_edge1 = self.v2.texture - self.v1.texture;
+        let texture_edge2 = self.v3.texture - self.v1.texture;
+        let texture_area = texture_edge1.perp_dot(texture_edge2).abs();
+        let area = self.edge1.cross(self.edge2).magnitude();
+
+        if area > 0.0 {
+            (texture_area / area).sqrt()
+        } else {
+            0.0
+        }
+    }
+}
+
+impl RayMarched {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let RayMarched {
+            ref estimator,
+            ref bounds,
+            ..
+        } = *self;
+
+        bounds.intersect(ray).and_then(|(min, max)| {
+            let origin = ray.origin + -bounds.center().to_vec();
+            let mut total_distance = min;
+            while total_distance < max {
+                let p = origin + ray.direction * total_distance;
+                let distance = estimator.get(&p);
+                total_distance += distance;
+                if distance < EPSILON || total_distance > max {
+                    //println!("dist: {}", distance);
+                    break;
+                }
+            }
+
+            if total_distance <= max {
+                let offset_position = origin + ray.direction * (total_distance - EPSILON);
+                let p = ray.origin + ray.direction * total_distance;
+
+                Some(Intersection {
+                    distance: total_distance,
+                    id: PrimitiveId::default(),
+                    surface_point: SurfacePoint {
+                        position: p,
+                        shape: ShapeSurfacePoint::RayMarched {
+                            shape: self,
+                            offset_position,
+                        },
+                    },
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    fn surface_data(&self, p: Point3<f32>) -> SurfaceData {
+        let estimator = &self.estimator;
+        let x_dir = Vector3::new(EPSILON, 0.0, 0.0);
+        let y_dir = Vector3::new(0.0, EPSILON, 0.0);
+        let z_dir = Vector3::new(0.0, 0.0, EPSILON);
+        let n = Vector3::new(
+            estimator.get(&(p + x_dir)) - estimator.get(&(p + -x_dir)),
+            estimator.get(&(p + y_dir)) - estimator.get(&(p + -y_dir)),
+            estimator.get(&(p + z_dir)) - estimator.get(&(p + -z_dir)),
+        )
+        .normalize();
+        SurfaceData {
+            normal: Normal::from_vector(n),
+            texture: Point2::origin(),
+        }
+    }
+}
+
+impl Rectangle {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let Rectangle {
+            origin,
+            normal,
+            size,
+            ..
+        } = *self;
+
+        let denominator = ray.direction.dot(normal.vector());
+
+        if denominator > -EPSILON && denominator < EPSILON {
+            return None;
+        }
+
+        let dist = (origin - ray.origin).dot(normal.vector()) / denominator;
+        if dist <= 0.0 {
+            return None;
+        }
+
+        let hit_position = ray.origin + ray.direction * dist;
+        let local = normal.into_space(hit_position - origin);
+
+        //Outside rectangle
+        if local.x.abs() > size.x * 0.5 || local.y.abs() > size.y * 0.5 {
+            return None;
+        }
+
+        Some(Intersection {
+            distance: dist,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: hit_position,
+                shape: ShapeSurfacePoint::Rectangle { shape: self },
+            },
+        })
+    }
+
+    fn scale(&mut self, scale: f32) {
+        self.origin *= scale;
+        self.size *= scale;
+    }
+
+    fn surface_data(&self, position: Point3<f32>) -> SurfaceData {
+        let local = self.normal.into_space(position - self.origin).truncate() + self.size * 0.5;
+
+        SurfaceData {
+            normal: self.normal,
+            texture: Point2::from_vec(local.div_element_wise(self.texture_scale)),
+        }
+    }
+}
+
+impl Disk {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let Disk {
+            origin,
+            normal,
+            radius,
+            inner_radius,
+            ..
+        } = *self;
+
+        let denominator = ray.direction.dot(normal.vector());
+
+        if denominator > -EPSILON && denominator < EPSILON {
+            return None;
+        }
+
+        let dist = (origin - ray.origin).dot(normal.vector()) / denominator;
+        if dist <= 0.0 {
+            return None;
+        }
+
+        let hit_position = ray.origin + ray.direction * dist;
+        let sq_distance = (hit_position - origin).magnitude2();
+
+        //Outside disk
+        if sq_distance > radius * radius || sq_distance < inner_radius * inner_radius {
+            return None;
+        }
+
+        Some(Intersection {
+            distance: dist,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: hit_position,
+                shape: ShapeSurfacePoint::Disk { shape: self },
+            },
+        })
+    }
+
+    fn scale(&mut self, scale: f32) {
+        self.origin *= scale;
+        self.radius *= scale;
+        self.inner_radius *= scale;
+    }
+
+    fn surface_data(&self, position: Point3<f32>) -> SurfaceData {
+        let local = self.normal.into_space(position - self.origin);
+        let angle = local.y.atan2(local.x);
+        let distance = local.truncate().magnitude();
+
+        let texture_coordinates = Vector2::new(
+            angle * std::f32::consts::FRAC_1_PI * 0.5 + 0.5,
+            (distance - self.inner_radius) / (self.radius - self.inner_radius),
+        );
+
+        SurfaceData {
+            normal: self.normal,
+            texture: Point2::from_vec(texture_coordinates.div_element_wise(self.texture_scale)),
+        }
+    }
+
+    fn texture_density(&self) -> f32 {
+        texture_density(
+            std::f32::consts::PI * (self.radius + self.inner_radius),
+            self.radius - self.inner_radius,
+            self.texture_scale,
+        )
+    }
+}
+
+impl Torus {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        self.distances(ray, 0.0)
+            .find(|&dist| dist > 0.0)
+            .map(|dist| Intersection {
+                distance: dist,
+                id: PrimitiveId::default(),
+                surface_point: SurfacePoint {
+                    position: ray.origin + ray.direction * dist,
+                    shape: ShapeSurfacePoint::Torus { shape: self },
+                },
+            })
+    }
+
+    /// The distances to where a ray crosses the surface, in increasing
+    /// order. Crossings before `min_distance` are skipped.
+    fn distances(&self, ray: &Ray3<f32>, min_distance: f32) -> impl Iterator<Item = f32> {
+        let (roots, enter) = self
+            .crossings(ray, min_distance)
+            .unwrap_or((Roots::default(), 0.0));
+
+        roots.into_iter().map(move |t| t as f32 + enter)
+    }
+
+    /// The crossings as distances from where the ray enters the bounding
+    /// sphere, and that distance.
+    fn crossings(&self, ray: &Ray3<f32>, min_distance: f32) -> Option<(Roots, f32)> {
+        let Torus {
+            position,
+            axis,
+            major_radius,
+            minor_radius,
+            ..
+        } = *self;
+
+        let origin = axis.into_space(ray.origin - position);
+        let direction = axis.into_space(ray.direction);
+        let outer_radius = major_radius + minor_radius;
+
+        // Limit the search to where the ray is within the bounding sphere, and
+        // start from there to keep the numbers small.
+        let b = origin.dot(direction);
+        let c = origin.magnitude2() - outer_radius * outer_radius;
+        let discriminant = b * b - c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let enter = (-b - discriminant.sqrt()).max(min_distance);
+        let exit = -b + discriminant.sqrt();
+        if exit <= min_distance + EPSILON {
+            return None;
+        }
+
+        let origin = origin + direction * enter;
+        let (ox, oy, oz) = (origin.x as f64, origin.y as f64, origin.z as f64);
+        let (dx, dy, dz) = (direction.x as f64, direction.y as f64, direction.z as f64);
+        let major_sq = (major_radius * major_radius) as f64;
+        let minor_sq = (minor_radius * minor_radius) as f64;
+
+        // (|p|² + R² - r²)² - 4R²(x² + y²) = 0, along the ray.
+        let a = dx * dx + dy * dy + dz * dz;
+        let b = 2.0 * (ox * dx + oy * dy + oz * dz);
+        let c = ox * ox + oy * oy + oz * oz + major_sq - minor_sq;
+        let coefficients = [
+            c * c - 4.0 * major_sq * (ox * ox + oy * oy),
+            2.0 * b * c - 8.0 * major_sq * (ox * dx + oy * dy),
+            b * b + 2.0 * a * c - 4.0 * major_sq * (dx * dx + dy * dy),
+            2.0 * a * b,
+            a * a,
+        ];
+
+        let roots = math::utils::polynomial_roots(&coefficients, 0.0, (exit - enter) as f64);
+        Some((roots, enter))
+    }
+
+    fn scale(&mut self, scale: f32) {
+        self.position *= scale;
+        self.major_radius *= scale;
+        self.minor_radius *= scale;
+    }
+
+    fn surface_data(&self, surface_position: Point3<f32>) -> SurfaceData {
+        let local = self.axis.into_space(surface_position - self.position);
+        let ring_direction = Vector3::new(local.x, local.y, 0.0).normalize();
+        let tube_offset = local - ring_direction * self.major_radius;
+        let normal = self.axis.from_space(tube_offset.normalize());
+
+        let ring_angle = local.y.atan2(local.x);
+        let tube_angle = tube_offset.z.atan2(tube_offset.dot(ring_direction));
+        let texture_coordinates = Vector2::new(
+            ring_angle * std::f32::consts::FRAC_1_PI * 0.5 + 0.5,
+            tube_angle * std::f32::consts::FRAC_1_PI * 0.5 + 0.5,
+        );
+
+        SurfaceData {
+            normal: Normal::from_vector(normal),
+            texture: Point2::from_vec(texture_coordinates.div_element_wise(self.texture_scale)),
+        }
+    }
+
+    fn texture_density(&self) -> f32 {
+        use std::f32::consts::PI;
+
+        texture_density(
+            2.0 * PI * self.major_radius,
+            2.0 * PI * self.minor_radius,
+            self.texture_scale,
+        )
+    }
+}
+
+impl Cuboid {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let (near, far) = self.span(ray)?;
+        if far <= 0.0 {
+            return None;
+        }
+
+        let dist = if near > 0.0 { near } else { far };
+
+        Some(Intersection {
+            distance: dist,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: ray.origin + ray.direction * dist,
+                shape: ShapeSurfacePoint::Cuboid { shape: self },
+            },
+        })
+    }
+
+    /// The distances to where a ray enters and exits the box, if it hits it.
+    fn span(&self, ray: &Ray3<f32>) -> Option<(f32, f32)> {
+        // The direction is not normalized in local space, so the distances are
+        // the same in both spaces.
+        let origin = self.to_local.transform_point(ray.origin);
+        let direction = self.to_local.transform_vector(ray.direction);
+
+        let mut near = -INFINITY;
+        let mut far = INFINITY;
+
+        for axis in 0..3 {
+            let a = (-self.half_size[axis] - origin[axis]) / direction[axis];
+            let b = (self.half_size[axis] - origin[axis]) / direction[axis];
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+        }
+
+        if far < near {
+            None
+        } else {
+            Some((near, far))
+        }
+    }
+
+    /// The world space area of one face per axis.
+    fn face_areas(&self) -> [f32; 3] {
+        let half_size = self.half_size;
+        let x = self
+            .to_world
+            .transform_vector(Vector3::new(half_size.x * 2.0, 0.0, 0.0));
+        let y = self
+            .to_world
+            .transform_vector(Vector3::new(0.0, half_size.y * 2.0, 0.0));
+        let z = self
+            .to_world
+            .transform_vector(Vector3::new(0.0, 0.0, half_size.z * 2.0));
+
+        [
+            y.cross(z).magnitude(),
+            z.cross(x).magnitude(),
+            x.cross(y).magnitude(),
+        ]
+    }
+
+    fn transform(&mut self, transform: Matrix4<f32>) {
+        self.to_world = transform * self.to_world;
+        self.to_local = self
+            .to_world
+            .invert()
+            .expect("a transformed box should be invertible");
+    }
+
+    fn surface_data(&self, position: Point3<f32>) -> SurfaceData {
+        let Cuboid {
+            half_size,
+            to_world,
+            to_local,
+            texture_scale,
+            ..
+        } = *self;
+
+        let local = to_local.transform_point(position);
+
+        // The face is the one that is closest to the point.
+        let relative = local.to_vec().div_element_wise(half_size);
+        let axis = (0..3)
+            .max_by(|&a, &b| {
+                relative[a]
+                    .abs()
+                    .partial_cmp(&relative[b].abs())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .unwrap_or(0);
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+
+        let mut local_normal = Vector3::new(0.0, 0.0, 0.0);
+        local_normal[axis] = relative[axis].signum();
+        let mut local_tangent = Vector3::new(0.0, 0.0, 0.0);
+        local_tangent[u_axis] = 1.0;
+
+        let normal = to_local
+            .transpose()
+            .transform_vector(local_normal)
+            .normalize();
+        let tangent = to_world.transform_vector(local_tangent).normalize();
+        let bitangent = normal.cross(tangent);
+
+        let texture_coordinates = Vector2::new(
+            local[u_axis] + half_size[u_axis],
+            local[v_axis] + half_size[v_axis],
+        );
+
+        SurfaceData {
+            normal: Normal::new(
+                normal,
+                Matrix3::from_cols(tangent, bitangent, normal).into(),
+            ),
+            texture: Point2::from_vec(texture_coordinates.div_element_wise(texture_scale)),
+        }
+    }
+}
+
+impl Curve {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        self.curve.intersect(ray).map(|hit| Intersection {
+            distance: hit.distance,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: ray.origin + ray.direction.normalize() * hit.distance,
+                shape: ShapeSurfacePoint::Curve {
+                    shape: self,
+                    normal: hit.normal,
+                    u: hit.u,
+                    v: hit.v,
+                },
+            },
+        })
+    }
+}
+
+impl PointCloud {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        self.cloud.intersect(ray).map(|hit| Intersection {
+            distance: hit.distance,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: ray.origin + ray.direction.normalize() * hit.distance,
+                shape: ShapeSurfacePoint::PointCloud {
+                    shape: self,
+                    normal: hit.normal,
+                },
+            },
+        })
+    }
+}
+
+impl Transformed {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        // The local direction is normalized, so the distances have to be
+        // scaled back to the world space.
+        let direction = self.to_local.transform_vector(ray.direction);
+        let scale = direction.magnitude();
+        let local_ray = Ray3::new(self.to_local.transform_point(ray.origin), direction / scale);
+
+        let intersection = self.shape.ray_intersect(&local_ray)?;
+        let distance = intersection.distance / scale;
+
+        Some(Intersection {
+            distance,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: ray.origin + ray.direction * distance,
+                shape: self.surface_point(intersection.surface_point),
+            },
+        })
+    }
+
+    /// Wraps a surface point from the local space of the shape.
+    fn surface_point<'a>(&'a self, local: SurfacePoint<'a>) -> ShapeSurfacePoint<'a> {
+        let (leaf, local_position, inverted) = match local.shape {
+            ShapeSurfacePoint::RayMarched {
+                offset_position, ..
+            } => (&*self.shape, offset_position, false),
+            ShapeSurfacePoint::Csg { shape, inverted } => (shape, local.position, inverted),
+            ShapeSurfacePoint::Sphere { .. }
+            | ShapeSurfacePoint::Rectangle { .. }
+            | ShapeSurfacePoint::Disk { .. }
+            | ShapeSurfacePoint::Torus { .. }
+            | ShapeSurfacePoint::Cuboid { .. } => (&*self.shape, local.position, false),
+            ShapeSurfacePoint::Triangle { .. }
+            | ShapeSurfacePoint::Plane { .. }
+            | ShapeSurfacePoint::Curve { .. }
+            | ShapeSurfacePoint::PointCloud { .. }
+            | ShapeSurfacePoint::Transformed { .. }
+            | ShapeSurfacePoint::Instance { .. } => panic!(
+                "triangles, planes, curves, point clouds, instances and transformed shapes can't be transformed"
+            ),
+        };
+
+        ShapeSurfacePoint::Transformed {
+            shape: self,
+            leaf,
+            local_position,
+            inverted,
+        }
+    }
+
+    fn transform(&mut self, transform: Matrix4<f32>) {
+        self.to_world = transform * self.to_world;
+        self.to_local = self
+            .to_world
+            .invert()
+            .expect("a transformed shape should be invertible");
+    }
+
+    fn surface_data(
+        &self,
+        leaf: &Shape,
+        local_position: Point3<f32>,
+        inverted: bool,
+    ) -> SurfaceData {
+        let mut surface_data = leaf.part_surface_data(local_position);
+
+        if inverted {
+            surface_data.normal = Normal::from_vector(-surface_data.normal.vector());
+        }
+
+        transform_surface_data(surface_data, self.to_world, self.to_local)
+    }
+
+    fn texture_density(&self) -> f32 {
+        self.shape.texture_density() / transform_scale(self.to_world)
+    }
+}
+
+impl Instance {
+    fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
+        let Instance {
+            ref prototype,
+            to_world,
+            to_local,
+            material,
+        } = *self;
+
+        let direction = to_local.transform_vector(ray.direction);
+        let scale = direction.magnitude();
+        let local_ray = Ray3::new(to_local.transform_point(ray.origin), direction / scale);
+
+        let mut closest: Option<Intersection> = None;
+        let mut closest_distance = INFINITY;
+        let mut candidates = prototype.shapes.ray_intersect(local_ray);
+        while let Some(shape) = candidates.next(closest_distance) {
+            if let Some(intersection) = shape.ray_intersect(&local_ray) {
+                if intersection.distance > 0.0 && intersection.distance < closest_distance {
+                    closest_distance = intersection.distance;
+                    closest = Some(intersection);
+                }
+            }
+        }
+
+        // The prototype's shapes can't be referred to from the surface point,
+        // so its surface data is stored right away.
+        let local = closest?.surface_point;
+        let distance = closest_distance / scale;
+        let surface_data = transform_surface_data(local.get_surface_data(), to_world, to_local);
+        let geometric_normal = to_local
+            .transpose()
+            .transform_vector(local.geometric_normal())
+            .normalize();
+
+        Some(Intersection {
+            distance,
+            id: PrimitiveId::default(),
+            surface_point: SurfacePoint {
+                position: ray.origin + ray.direction * distance,
+                shape: ShapeSurfacePoint::Instance {
+                    normal: surface_data.normal,
+                    geometric_normal,
+                    texture: surface_data.texture,
+                    texture_density: local.texture_density() / transform_scale(to_world),
+                    material: material.unwrap_or_else(|| local.get_material()),
+                },
+            },
+        })
+    }
+
+    fn transform(&mut self, transform: Matrix4<f32>) {
+        self.to_world = transform * self.to_world;
+        self.to_local = self
+            .to_world
+            .invert()
+            .expect("a transformed instance should be invertible");
+    }
+}
+
+/// The bounding box of a transformed bounding box.
+fn transform_aabb(local: Aabb3<f32>, to_world: Matrix4<f32>) -> Aabb3<f32> {
+    let corner = |x: f32, y: f32, z: f32| to_world.transform_point(Point3::new(x, y, z));
+
+    Aabb3::new(
+        corner(local.min.x, local.min.y, local.min.z),
+        corner(local.max.x, local.max.y, local.max.z),
+    )
+    .grow(corner(local.max.x, local.min.y, local.min.z))
+    .grow(corner(local.min.x, local.max.y, local.min.z))
+    .grow(corner(local.min.x, local.min.y, local.max.z))
+    .grow(corner(local.max.x, local.max.y, local.min.z))
+    .grow(corner(local.max.x, local.min.y, local.max.z))
+    .grow(corner(local.min.x, local.max.y, local.max.z))
+}
+
+/// The average factor that `to_world` scales lengths by.
+fn transform_scale(to_world: Matrix4<f32>) -> f32 {
+    let linear = Matrix3::from_cols(
+        to_world.x.truncate(),
+        to_world.y.truncate(),
+        to_world.z.truncate(),
+    );
+
+    linear.determinant().abs().cbrt()
+}
+
+/// Moves surface data from a local space to the world space. Normals are
+/// transformed with the inverse transpose, to stay perpendicular to the
+/// surface when it's scaled unevenly.
+fn transform_surface_data(
+    surface_data: SurfaceData,
+    to_world: Matrix4<f32>,
+    to_local: Matrix4<f32>,
+) -> SurfaceData {
+    let local_normal = surface_data.normal;
+    let normal = to_local
+        .transpose()
+        .transform_vector(local_normal.vector())
+        .normalize();
+    let tangent = to_world
+        .transform_vector(local_normal.from_space(Vector3::unit_x()))
+        .normalize();
+    let bitangent = normal.cross(tangent);
+
+    SurfaceData {
+        normal: Normal::new(
+            normal,
+            Matrix3::from_cols(tangent, bitangent, normal).into(),
+        ),
+        texture: surface_data.texture,
+    }
+}
+
+/// A point where a ray crosses the surface of a solid.
+struct Crossing<'a> {
+    distance: f32,
+    entering: bool,
+    shape: &'a Shape,
+    inverted: bool,
+}
+
+impl<'a> Crossing<'a> {
+    fn new(distance: f32, entering: bool, shape: &'a Shape) -> Self {
+        Crossing {
+            distance,
+            entering,
+            shape,
+            inverted: false,
+        }
+    }
+}
+
+impl Primitive for Shape {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        match self {
+            Shape::Sphere(shape) => shape.bounding_box(),
+            Shape::Triangle(shape) => shape.bounding_box(),
+            Shape::RayMarched(shape) => shape.bounding_box(),
+            Shape::Rectangle(shape) => shape.bounding_box(),
+            Shape::Disk(shape) => shape.bounding_box(),
+            Shape::Torus(shape) => shape.bounding_box(),
+            Shape::Cuboid(shape) => shape.bounding_box(),
+            Shape::Curve(shape) => shape.bounding_box(),
+            Shape::PointCloud(shape) => shape.bounding_box(),
+            Shape::Csg(shape) => shape.bounding_box(),
+            Shape::Transformed(shape) => shape.bounding_box(),
+            Shape::Instance(shape) => shape.bounding_box(),
+        }
+    }
+
+    fn surface_area(&self) -> f32 {
+        match self {
+            Shape::Sphere(shape) => shape.surface_area(),
+            Shape::Triangle(shape) => shape.surface_area(),
+            Shape::RayMarched(shape) => shape.surface_area(),
+            Shape::Rectangle(shape) => shape.surface_area(),
+            Shape::Disk(shape) => shape.surface_area(),
+            Shape::Torus(shape) => shape.surface_area(),
+            Shape::Cuboid(shape) => shape.surface_area(),
+            Shape::Curve(shape) => shape.surface_area(),
+            Shape::PointCloud(shape) => shape.surface_area(),
+            Shape::Csg(shape) => shape.surface_area(),
+            Shape::Transformed(shape) => shape.surface_area(),
+            Shape::Instance(shape) => shape.surface_area(),
+        }
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        match self {
+            Shape::Sphere(shape) => shape.sample_point(rng),
+            Shape::Triangle(shape) => shape.sample_point(rng),
+            Shape::RayMarched(shape) => shape.sample_point(rng),
+            Shape::Rectangle(shape) => shape.sample_point(rng),
+            Shape::Disk(shape) => shape.sample_point(rng),
+            Shape::Torus(shape) => shape.sample_point(rng),
+            Shape::Cuboid(shape) => shape.sample_point(rng),
+            Shape::Curve(shape) => shape.sample_point(rng),
+            Shape::PointCloud(shape) => shape.sample_point(rng),
+            Shape::Csg(shape) => shape.sample_point(rng),
+            Shape::Transformed(shape) => shape.sample_point(rng),
+            Shape::Instance(shape) => shape.sample_point(rng),
+        }
+    }
+
+    fn sample_towards(&self, rng: &mut dyn RngCore, target: &Point3<f32>) -> Option<Intersection> {
+        match self {
+            Shape::Sphere(shape) => shape.sample_towards(rng, target),
+            Shape::Triangle(shape) => shape.sample_towards(rng, target),
+            Shape::RayMarched(shape) => shape.sample_towards(rng, target),
+            Shape::Rectangle(shape) => shape.sample_towards(rng, target),
+            Shape::Disk(shape) => shape.sample_towards(rng, target),
+            Shape::Torus(shape) => shape.sample_towards(rng, target),
+            Shape::Cuboid(shape) => shape.sample_towards(rng, target),
+            Shape::Curve(shape) => shape.sample_towards(rng, target),
+            Shape::PointCloud(shape) => shape.sample_towards(rng, target),
+            Shape::Csg(shape) => shape.sample_towards(rng, target),
+            Shape::Transformed(shape) => shape.sample_towards(rng, target),
+            Shape::Instance(shape) => shape.sample_towards(rng, target),
+        }
+    }
+
+    fn solid_angle_towards(&self, target: &Point3<f32>) -> Option<f32> {
+        match self {
+            Shape::Sphere(shape) => shape.solid_angle_towards(target),
+            Shape::Triangle(shape) => shape.solid_angle_towards(target),
+            Shape::RayMarched(shape) => shape.solid_angle_towards(target),
+            Shape::Rectangle(shape) => shape.solid_angle_towards(target),
+            Shape::Disk(shape) => shape.solid_angle_towards(target),
+            Shape::Torus(shape) => shape.solid_angle_towards(target),
+            Shape::Cuboid(shape) => shape.solid_angle_towards(target),
+            Shape::Curve(shape) => shape.solid_angle_towards(target),
+            Shape::PointCloud(shape) => shape.solid_angle_towards(target),
+            Shape::Csg(shape) => shape.solid_angle_towards(target),
+            Shape::Transformed(shape) => shape.solid_angle_towards(target),
+            Shape::Instance(shape) => shape.solid_angle_towards(target),
+        }
+    }
 
-use cgmath::{
-    ElementWise, EuclideanSpace, InnerSpace, Matrix3, Matrix4, Point2, Point3, Quaternion, Rad,
-    Transform, Vector2, Vector3,
-};
-use collision::{Aabb, Aabb3, Continuous, Ray3};
+    fn flat_normal(&self) -> Option<Vector3<f32>> {
+        match self {
+            Shape::Sphere(shape) => shape.flat_normal(),
+            Shape::Triangle(shape) => shape.flat_normal(),
+            Shape::RayMarched(shape) => shape.flat_normal(),
+            Shape::Rectangle(shape) => shape.flat_normal(),
+            Shape::Disk(shape) => shape.flat_normal(),
+            Shape::Torus(shape) => shape.flat_normal(),
+            Shape::Cuboid(shape) => shape.flat_normal(),
+            Shape::Curve(shape) => shape.flat_normal(),
+            Shape::PointCloud(shape) => shape.flat_normal(),
+            Shape::Csg(shape) => shape.flat_normal(),
+            Shape::Transformed(shape) => shape.flat_normal(),
+            Shape::Instance(shape) => shape.flat_normal(),
+        }
+    }
 
-use rand::Rng;
+    fn material(&self) -> MaterialId {
+        match self {
+            Shape::Sphere(shape) => shape.material(),
+            Shape::Triangle(shape) => shape.material(),
+            Shape::RayMarched(shape) => shape.material(),
+            Shape::Rectangle(shape) => shape.material(),
+            Shape::Disk(shape) => shape.material(),
+            Shape::Torus(shape) => shape.material(),
+            Shape::Cuboid(shape) => shape.material(),
+            Shape::Curve(shape) => shape.material(),
+            Shape::PointCloud(shape) => shape.material(),
+            Shape::Csg(shape) => shape.material(),
+            Shape::Transformed(shape) => shape.material(),
+            Shape::Instance(shape) => shape.material(),
+        }
+    }
+}
 
-use crate::tracer::ParametricValue;
+impl Bounded for Shape {
+    fn aabb(&self) -> Aabb3<f32> {
+        self.bounding_box()
+    }
+}
 
-use crate::materials::Material;
-use crate::math::{self, DIST_EPSILON};
-use crate::spatial::bvh::Bounded;
+impl Primitive for Sphere {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        Aabb3::new(
+            self.position.sub_element_wise(self.radius),
+            self.position.add_element_wise(self.radius),
+        )
+    }
 
-pub(crate) use self::Shape::{RayMarched, Sphere, Triangle};
+    fn surface_area(&self) -> f32 {
+        self.radius * self.radius * 4.0 * std::f32::consts::PI
+    }
 
-const EPSILON: f32 = DIST_EPSILON;
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        let sphere_point = math::utils::sample_sphere(rng);
 
-pub mod distance_estimators;
+        Some(SurfacePoint {
+            position: self.position + sphere_point * self.radius,
+            shape: ShapeSurfacePoint::Sphere { shape: self },
+        })
+    }
 
-type DistanceEstimator = Box<dyn ParametricValue<Point3<f32>, f32>>;
+    fn sample_towards(&self, rng: &mut dyn RngCore, target: &Point3<f32>) -> Option<Intersection> {
+        let radius = (self.radius - DIST_EPSILON).max(0.0);
+        let dir = self.position - target;
+        let dist2 = dir.magnitude2();
 
-pub struct Vertex {
-    pub position: Point3<f32>,
-    pub normal: Normal,
-    pub texture: Point2<f32>,
-}
+        if dist2 > radius * radius {
+            let cos_theta_max = (1.0 - (radius * radius) / dist2).max(0.0).sqrt();
 
-pub(crate) enum Shape<'p> {
-    Sphere {
-        position: Point3<f32>,
-        radius: f32,
-        texture_scale: Vector2<f32>,
-        material: Material<'p>,
-    },
-    Triangle {
-        v1: Vertex,
-        v2: Vertex,
-        v3: Vertex,
-        edge1: Vector3<f32>,
-        edge2: Vector3<f32>,
-        material: Arc<Material<'p>>,
-    },
-    RayMarched {
-        estimator: DistanceEstimator,
-        bounds: BoundingVolume,
-        material: Material<'p>,
-    },
-}
+            let ray_dir = math::utils::sample_cone(rng, dir.normalize(), cos_theta_max);
 
-impl<'p> Shape<'p> {
-    pub fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
-        match *self {
-            Sphere {
-                ref position,
-                radius,
-                ..
-            } => {
-                let sphere = collision::Sphere {
-                    radius,
-                    center: position.clone(),
-                };
+            let intersection = self.ray_intersect(&Ray3::new(*target, ray_dir));
 
-                sphere.intersection(ray).map(|intersection| Intersection {
-                    distance: (intersection - ray.origin).magnitude(),
+            if let Some(intersection) = intersection {
+                Some(intersection)
+            } else {
+                // cheat
+                Some(Intersection {
+                    distance: 0.0,
+                    id: PrimitiveId::default(),
                     surface_point: SurfacePoint {
-                        position: intersection,
+                        position: *target,
                         shape: ShapeSurfacePoint::Sphere { shape: self },
                     },
                 })
             }
-            Triangle {
-                ref v1,
-                edge1: e1,
-                edge2: e2,
-                ..
-            } => {
-                //Möller–Trumbore intersection algorithm
-                let p = ray.direction.cross(e2);
-                let det = e1.dot(p);
-
-                if det > -EPSILON && det < EPSILON {
-                    return None;
-                }
+        } else {
+            self.sample_point(rng).map(|surface_point| Intersection {
+                distance: (surface_point.position - target).magnitude(),
+                id: PrimitiveId::default(),
+                surface_point,
+            })
+        }
+    }
 
-                let inv_det = 1.0 / det;
-                let t = ray.origin - v1.position;
-                let u = t.dot(p) * inv_det;
+    fn solid_angle_towards(&self, target: &Point3<f32>) -> Option<f32> {
+        let dist2 = (self.position - target).magnitude2();
+        if dist2 > self.radius * self.radius {
+            let cos_theta_max = (1.0 - (self.radius * self.radius) / dist2).max(0.0).sqrt();
+            Some(math::utils::solid_angle(cos_theta_max))
+        } else {
+            None
+        }
+    }
 
-                //Outside triangle
-                if u < 0.0 || u > 1.0 {
-                    return None;
-                }
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
 
-                let q = t.cross(e1);
-                let v = ray.direction.dot(q) * inv_det;
+impl Primitive for Triangle {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        Aabb3::new(self.v1.position, self.v2.position).grow(self.v3.position)
+    }
 
-                //Outside triangle
-                if v < 0.0 || u + v > 1.0 {
-                    return None;
-                }
+    fn surface_area(&self) -> f32 {
+        0.5 * self.edge1.cross(self.edge2).magnitude()
+    }
 
-                let dist = e2.dot(q) * inv_det;
-                if dist > EPSILON {
-                    let hit_position = ray.origin + ray.direction * dist;
-                    Some(Intersection {
-                        distance: dist,
-                        surface_point: SurfacePoint {
-                            position: hit_position,
-                            shape: ShapeSurfacePoint::Triangle { shape: self, u, v },
-                        },
-                    })
-                } else {
-                    None
-                }
-            }
-            RayMarched {
-                ref estimator,
-                ref bounds,
-                ..
-            } => bounds.intersect(ray).and_then(|(min, max)| {
-                let origin = ray.origin + -bounds.center().to_vec();
-                let mut total_distance = min;
-                while total_distance < max {
-                    let p = origin + ray.direction * total_distance;
-                    let distance = estimator.get(&p);
-                    total_distance += distance;
-                    if distance < EPSILON || total_distance > max {
-                        //println!("dist: {}", distance);
-                        break;
-                    }
-                }
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        let u: f32 = rng.gen();
+        let v = rng.gen();
 
-                if total_distance <= max {
-                    let offset_position = origin + ray.direction * (total_distance - EPSILON);
-                    let p = ray.origin + ray.direction * total_distance;
-
-                    Some(Intersection {
-                        distance: total_distance,
-                        surface_point: SurfacePoint {
-                            position: p,
-                            shape: ShapeSurfacePoint::RayMarched {
-                                shape: self,
-                                offset_position,
-                            },
-                        },
-                    })
-                } else {
-                    None
-                }
-            }),
-        }
+        let (u, v) = if u + v > 1.0 {
+            (1.0 - u, 1.0 - v)
+        } else {
+            (u, v)
+        };
+
+        let position = self.v1.position + self.edge1 * u + self.edge2 * v;
+
+        Some(SurfacePoint {
+            position,
+            shape: ShapeSurfacePoint::Triangle { shape: self, u, v },
+        })
     }
 
-    pub fn get_material(&self) -> &Material {
-        match *self {
-            Sphere { ref material, .. } => material,
-            Triangle { ref material, .. } => &**material,
-            RayMarched { ref material, .. } => material,
-        }
+    fn flat_normal(&self) -> Option<Vector3<f32>> {
+        Some(self.edge1.cross(self.edge2).normalize())
     }
 
-    pub fn sample_point(&self, rng: &mut impl Rng) -> Option<SurfacePoint> {
-        match *self {
-            Sphere {
-                ref position,
-                radius,
-                ..
-            } => {
-                let sphere_point = math::utils::sample_sphere(rng);
-
-                Some(SurfacePoint {
-                    position: position + sphere_point * radius,
-                    shape: ShapeSurfacePoint::Sphere { shape: self },
-                })
-            }
-            Triangle {
-                ref v1,
-                ref v2,
-                ref v3,
-                ..
-            } => {
-                let u: f32 = rng.gen();
-                let v = rng.gen();
-
-                let a = v2.position - v1.position;
-                let b = v3.position - v1.position;
-
-                let (u, v) = if u + v > 1.0 {
-                    (1.0 - u, 1.0 - v)
-                } else {
-                    (u, v)
-                };
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
 
-                let position = v1.position + a * u + b * v;
+impl Primitive for RayMarched {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        self.bounds.aabb()
+    }
 
-                Some(SurfacePoint {
-                    position,
-                    shape: ShapeSurfacePoint::Triangle { shape: self, u, v },
-                })
-            }
-            RayMarched { .. } => None,
-        }
+    fn surface_area(&self) -> f32 {
+        INFINITY
     }
 
-    pub fn sample_towards(&self, rng: &mut impl Rng, target: &Point3<f32>) -> Option<Intersection> {
-        match *self {
-            Sphere {
-                ref position,
-                radius,
-                ..
-            } => {
-                let radius = (radius - DIST_EPSILON).max(0.0);
-                let dir = position - target;
-                let dist2 = dir.magnitude2();
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        None
+    }
 
-                if dist2 > radius * radius {
-                    let cos_theta_max = (1.0 - (radius * radius) / dist2).max(0.0).sqrt();
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
 
-                    let ray_dir = math::utils::sample_cone(rng, dir.normalize(), cos_theta_max);
+impl Primitive for Rectangle {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        let Rectangle {
+            origin,
+            normal,
+            size,
+            ..
+        } = *self;
 
-                    let intersection = self.ray_intersect(&Ray3::new(*target, ray_dir));
+        let x = normal.from_space(Vector3::unit_x()) * size.x * 0.5;
+        let y = normal.from_space(Vector3::unit_y()) * size.y * 0.5;
 
-                    if let Some(intersection) = intersection {
-                        Some(intersection)
-                    } else {
-                        // cheat
-                        Some(Intersection {
-                            distance: 0.0,
-                            surface_point: SurfacePoint {
-                                position: *target,
-                                shape: ShapeSurfacePoint::Sphere { shape: self },
-                            },
-                        })
-                    }
-                } else {
-                    self.sample_point(rng).map(|surface_point| Intersection {
-                        distance: (surface_point.position - target).magnitude(),
-                        surface_point,
-                    })
-                }
-            }
-            _ => self.sample_point(rng).map(|surface_point| Intersection {
-                distance: (surface_point.position - target).magnitude(),
-                surface_point,
-            }),
-        }
+        Aabb3::new(origin + x + y, origin - x - y)
+            .grow(origin + x - y)
+            .grow(origin - x + y)
     }
 
-    pub fn solid_angle_towards(&self, target: &Point3<f32>) -> Option<f32> {
-        match *self {
-            Sphere {
-                ref position,
-                radius,
-                ..
-            } => {
-                let dist2 = (position - target).magnitude2();
-                if dist2 > radius * radius {
-                    let cos_theta_max = (1.0 - (radius * radius) / dist2).max(0.0).sqrt();
-                    let a = math::utils::solid_angle(cos_theta_max);
-                    Some(a)
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        }
+    fn surface_area(&self) -> f32 {
+        self.size.x * self.size.y
     }
 
-    pub fn surface_area(&self) -> f32 {
-        match *self {
-            Sphere { radius, .. } => radius * radius * 4.0 * std::f32::consts::PI,
-            Triangle {
-                ref v1,
-                ref v2,
-                ref v3,
-                ..
-            } => {
-                let a = v2.position - v1.position;
-                let b = v3.position - v1.position;
-                0.5 * a.cross(b).magnitude()
-            }
-            RayMarched { .. } => INFINITY,
-        }
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        let x = (rng.gen::<f32>() - 0.5) * self.size.x;
+        let y = (rng.gen::<f32>() - 0.5) * self.size.y;
+
+        Some(SurfacePoint {
+            position: self.origin + self.normal.from_space(Vector3::new(x, y, 0.0)),
+            shape: ShapeSurfacePoint::Rectangle { shape: self },
+        })
     }
 
-    pub fn scale(&mut self, scale: f32) {
-        match *self {
-            Sphere {
-                ref mut radius,
-                ref mut position,
-                ..
-            } => {
-                *radius *= scale;
-                *position *= scale;
-            }
-            Triangle {
-                ref mut v1,
-                ref mut v2,
-                ref mut v3,
-                ref mut edge1,
-                ref mut edge2,
-                material: _,
-            } => {
-                v1.position *= scale;
-                v2.position *= scale;
-                v3.position *= scale;
-                *edge1 = v2.position - v1.position;
-                *edge2 = v3.position - v1.position;
-            }
-            RayMarched { .. } => {}
-        }
+    fn flat_normal(&self) -> Option<Vector3<f32>> {
+        Some(self.normal.vector())
     }
 
-    pub fn transform(&mut self, transform: Matrix4<f32>) {
-        match *self {
-            Sphere {
-                ref mut position, ..
-            } => {
-                *position = transform.transform_point(*position);
-            }
-            Triangle {
-                ref mut v1,
-                ref mut v2,
-                ref mut v3,
-                ref mut edge1,
-                ref mut edge2,
-                material: _,
-            } => {
-                v1.normal = v1.normal.transform(transform);
-                v2.normal = v2.normal.transform(transform);
-                v3.normal = v3.normal.transform(transform);
-                v1.position = transform.transform_point(v1.position);
-                v2.position = transform.transform_point(v2.position);
-                v3.position = transform.transform_point(v3.position);
-                *edge1 = v2.position - v1.position;
-                *edge2 = v3.position - v1.position;
-            }
-            RayMarched { .. } => {}
-        }
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
+
+impl Primitive for Disk {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        // The disk reaches the furthest along the axes that are parallel to
+        // it.
+        let n = self.normal.vector();
+        let extent = Vector3::new(
+            (1.0 - n.x * n.x).max(0.0).sqrt(),
+            (1.0 - n.y * n.y).max(0.0).sqrt(),
+            (1.0 - n.z * n.z).max(0.0).sqrt(),
+        ) * self.radius;
+
+        Aabb3::new(self.origin - extent, self.origin + extent)
+    }
+
+    fn surface_area(&self) -> f32 {
+        (self.radius * self.radius - self.inner_radius * self.inner_radius) * std::f32::consts::PI
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        let inner_sq = self.inner_radius * self.inner_radius;
+        let r = (rng.gen::<f32>() * (self.radius * self.radius - inner_sq) + inner_sq).sqrt();
+        let angle = std::f32::consts::PI * 2.0 * rng.gen::<f32>();
+        let local = Vector3::new(r * angle.cos(), r * angle.sin(), 0.0);
+
+        Some(SurfacePoint {
+            position: self.origin + self.normal.from_space(local),
+            shape: ShapeSurfacePoint::Disk { shape: self },
+        })
     }
 
-    fn get_sphere_surface_data(&self, surface_position: Point3<f32>) -> SurfaceData {
-        if let &Sphere {
+    fn flat_normal(&self) -> Option<Vector3<f32>> {
+        Some(self.normal.vector())
+    }
+
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
+
+impl Primitive for Torus {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        let Torus {
             position,
-            texture_scale,
+            axis,
+            major_radius,
+            minor_radius,
+            ..
+        } = *self;
+
+        // The ring reaches the furthest along the axes that are parallel to
+        // it, and the tube adds its radius in all directions.
+        let n = axis.vector();
+        let outer_radius = major_radius + minor_radius;
+        let extent = Vector3::new(
+            outer_radius * (1.0 - n.x * n.x).max(0.0).sqrt() + minor_radius * n.x.abs(),
+            outer_radius * (1.0 - n.y * n.y).max(0.0).sqrt() + minor_radius * n.y.abs(),
+            outer_radius * (1.0 - n.z * n.z).max(0.0).sqrt() + minor_radius * n.z.abs(),
+        );
+
+        Aabb3::new(position - extent, position + extent)
+    }
+
+    fn surface_area(&self) -> f32 {
+        4.0 * std::f32::consts::PI * std::f32::consts::PI * self.major_radius * self.minor_radius
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        let Torus {
+            major_radius,
+            minor_radius,
             ..
-        } = self
-        {
-            let normal = (surface_position - position).normalize();
-            let latitude = normal.y.acos();
-            let longitude = normal.x.atan2(normal.z);
-
-            let rotation = Matrix3::from_angle_y(Rad(longitude))
-                * Matrix3::from_angle_x(Rad(latitude - std::f32::consts::PI * 0.5));
-
-            let texture_coordinates = Vector2::new(
-                longitude * std::f32::consts::FRAC_1_PI * 0.5,
-                1.0 - (latitude * std::f32::consts::FRAC_1_PI),
-            );
-
-            SurfaceData {
-                normal: Normal::new(normal, rotation.into()),
-                texture: Point2::from_vec(texture_coordinates.div_element_wise(texture_scale)),
+        } = *self;
+
+        // The outer side of the tube has more area than the inner side, so
+        // the tube angle is picked by rejection sampling.
+        let tube_angle = loop {
+            let angle = std::f32::consts::PI * 2.0 * rng.gen::<f32>();
+            let density = major_radius + minor_radius * angle.cos();
+            if rng.gen::<f32>() * (major_radius + minor_radius) <= density {
+                break angle;
             }
-        } else {
-            panic!("cannot get sphere surface data from another type of shape");
-        }
+        };
+        let ring_angle = std::f32::consts::PI * 2.0 * rng.gen::<f32>();
+
+        let distance = major_radius + minor_radius * tube_angle.cos();
+        let local = Vector3::new(
+            distance * ring_angle.cos(),
+            distance * ring_angle.sin(),
+            minor_radius * tube_angle.sin(),
+        );
+
+        Some(SurfacePoint {
+            position: self.position + self.axis.from_space(local),
+            shape: ShapeSurfacePoint::Torus { shape: self },
+        })
+    }
+
+    fn material(&self) -> MaterialId {
+        self.material
     }
+}
 
-    fn get_triangle_surface_data(&self, u: f32, v: f32) -> SurfaceData {
-        if let Triangle { v1, v2, v3, .. } = self {
-            let normal = Normal::on_triangle(v1.normal, v2.normal, v3.normal, u, v);
-            let texture = (v1.texture * (1.0 - (u + v)))
-                .add_element_wise(v2.texture * u)
-                .add_element_wise(v3.texture * v);
+impl Primitive for Cuboid {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        transform_aabb(
+            Aabb3::new(
+                Point3::from_vec(-self.half_size),
+                Point3::from_vec(self.half_size),
+            ),
+            self.to_world,
+        )
+    }
 
-            SurfaceData { normal, texture }
-        } else {
-            panic!("cannot get triangle surface data from another type of shape");
-        }
+    fn surface_area(&self) -> f32 {
+        let areas = self.face_areas();
+        2.0 * (areas[0] + areas[1] + areas[2])
     }
 
-    fn get_ray_marched_surface_data(&self, p: Point3<f32>) -> SurfaceData {
-        if let RayMarched { estimator, .. } = self {
-            let x_dir = Vector3::new(EPSILON, 0.0, 0.0);
-            let y_dir = Vector3::new(0.0, EPSILON, 0.0);
-            let z_dir = Vector3::new(0.0, 0.0, EPSILON);
-            let n = Vector3::new(
-                estimator.get(&(p + x_dir)) - estimator.get(&(p + -x_dir)),
-                estimator.get(&(p + y_dir)) - estimator.get(&(p + -y_dir)),
-                estimator.get(&(p + z_dir)) - estimator.get(&(p + -z_dir)),
-            )
-            .normalize();
-            SurfaceData {
-                normal: Normal::from_vector(n),
-                texture: Point2::origin(),
-            }
-        } else {
-            panic!("cannot get triangle surface data from another type of shape");
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        let half_size = self.half_size;
+
+        // Pick a face, based on its area in world space, and a point on that
+        // face.
+        let areas = self.face_areas();
+        let mut choice = rng.gen::<f32>() * (areas[0] + areas[1] + areas[2]);
+        let mut axis = 0;
+        while axis < 2 && choice >= areas[axis] {
+            choice -= areas[axis];
+            axis += 1;
         }
+
+        let mut local = Point3::new(
+            (rng.gen::<f32>() * 2.0 - 1.0) * half_size.x,
+            (rng.gen::<f32>() * 2.0 - 1.0) * half_size.y,
+            (rng.gen::<f32>() * 2.0 - 1.0) * half_size.z,
+        );
+        local[axis] = if rng.gen() {
+            half_size[axis]
+        } else {
+            -half_size[axis]
+        };
+
+        Some(SurfacePoint {
+            position: self.to_world.transform_point(local),
+            shape: ShapeSurfacePoint::Cuboid { shape: self },
+        })
+    }
+
+    fn material(&self) -> MaterialId {
+        self.material
     }
 }
 
-impl<'p> Bounded for Shape<'p> {
-    fn aabb(&self) -> Aabb3<f32> {
-        match *self {
-            Sphere {
-                position, radius, ..
-            } => Aabb3::new(
-                position.sub_element_wise(radius),
-                position.add_element_wise(radius),
-            ),
-            Triangle {
-                ref v1,
-                ref v2,
-                ref v3,
-                ..
-            } => {
-                let p1 = v1.position;
-                let p2 = v2.position;
-                let p3 = v3.position;
-
-                Aabb3::new(p1, p2).grow(p3)
+impl Primitive for Curve {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        self.curve.aabb()
+    }
+
+    fn surface_area(&self) -> f32 {
+        INFINITY
+    }
+
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        None
+    }
+
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
+
+impl Primitive for PointCloud {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        self.cloud.aabb()
+    }
+
+    fn surface_area(&self) -> f32 {
+        INFINITY
+    }
+
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        None
+    }
+
+    fn material(&self) -> MaterialId {
+        self.material
+    }
+}
+
+impl Primitive for Csg {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        let first = self.first.aabb();
+        let second = self.second.aabb();
+
+        match self.operation {
+            CsgOperation::Union => first.union(&second),
+            CsgOperation::Intersection => {
+                let min = Point3::new(
+                    first.min.x.max(second.min.x),
+                    first.min.y.max(second.min.y),
+                    first.min.z.max(second.min.z),
+                );
+                let max = Point3::new(
+                    first.max.x.min(second.max.x),
+                    first.max.y.min(second.max.y),
+                    first.max.z.min(second.max.z),
+                );
+
+                // The parts may not overlap at all.
+                if min.x <= max.x && min.y <= max.y && min.z <= max.z {
+                    Aabb3::new(min, max)
+                } else {
+                    Aabb3::new(min, min)
+                }
             }
-            RayMarched { ref bounds, .. } => bounds.aabb(),
+            CsgOperation::Difference => first,
         }
     }
+
+    fn surface_area(&self) -> f32 {
+        INFINITY
+    }
+
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        None
+    }
+
+    fn material(&self) -> MaterialId {
+        self.first.material()
+    }
+}
+
+impl Primitive for Transformed {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        transform_aabb(self.shape.aabb(), self.to_world)
+    }
+
+    fn surface_area(&self) -> f32 {
+        // Exact for uniform scaling, and an approximation otherwise.
+        self.shape.surface_area() * transform_scale(self.to_world).powi(2)
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        // The points are only evenly spread if the scaling is uniform.
+        let local = self.shape.sample_point(rng)?;
+
+        Some(SurfacePoint {
+            position: self.to_world.transform_point(local.position),
+            shape: self.surface_point(local),
+        })
+    }
+
+    fn flat_normal(&self) -> Option<Vector3<f32>> {
+        self.shape.flat_normal().map(|normal| {
+            self.to_local
+                .transpose()
+                .transform_vector(normal)
+                .normalize()
+        })
+    }
+
+    fn material(&self) -> MaterialId {
+        self.shape.material()
+    }
+}
+
+impl Primitive for Instance {
+    fn bounding_box(&self) -> Aabb3<f32> {
+        transform_aabb(self.prototype.bounds, self.to_world)
+    }
+
+    fn surface_area(&self) -> f32 {
+        INFINITY
+    }
+
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<SurfacePoint> {
+        None
+    }
+
+    fn material(&self) -> MaterialId {
+        self.material.unwrap_or_else(|| {
+            self.prototype
+                .shapes
+                .items()
+                .next()
+                .map_or_else(MaterialId::default, Shape::material)
+        })
+    }
 }
 
-pub(crate) struct Plane<'p> {
+pub(crate) struct Plane {
     pub shape: collision::Plane<f32>,
     pub normal: Normal,
     pub texture_scale: Vector2<f32>,
-    pub material: Material<'p>,
+    pub material: MaterialId,
 }
 
-impl<'p> Plane<'p> {
+impl Plane {
     pub fn ray_intersect(&self, ray: &Ray3<f32>) -> Option<Intersection> {
         let Plane { ref shape, .. } = self;
 
         shape.intersection(ray).map(|intersection| Intersection {
             distance: (intersection - ray.origin).magnitude(),
+            id: PrimitiveId::default(),
             surface_point: SurfacePoint {
                 position: intersection,
                 shape: ShapeSurfacePoint::Plane { shape: self },
@@ -474,9 +1936,21 @@ impl<'p> Plane<'p> {
 #[derive(Copy, Clone)]
 pub(crate) struct Intersection<'a> {
     pub distance: f32,
+    /// Filled in by the world, since the shapes don't know where they came
+    /// from.
+    pub id: PrimitiveId,
     pub surface_point: SurfacePoint<'a>,
 }
 
+/// Identifies the object that was hit, and the primitive within it, such as
+/// a triangle in a mesh. Both are numbered in the order they are defined, so
+/// they stay the same between renders of the same project.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub(crate) struct PrimitiveId {
+    pub object: u32,
+    pub primitive: u32,
+}
+
 #[derive(Copy, Clone)]
 pub(crate) struct SurfacePoint<'a> {
     pub position: Point3<f32>,
@@ -486,22 +1960,106 @@ pub(crate) struct SurfacePoint<'a> {
 impl<'a> SurfacePoint<'a> {
     pub fn get_surface_data(&self) -> SurfaceData {
         match self.shape {
-            ShapeSurfacePoint::Sphere { shape } => shape.get_sphere_surface_data(self.position),
+            ShapeSurfacePoint::Sphere { shape } => shape.surface_data(self.position),
             ShapeSurfacePoint::Plane { shape } => shape.get_surface_data(self.position),
-            ShapeSurfacePoint::Triangle { shape, u, v } => shape.get_triangle_surface_data(u, v),
+            ShapeSurfacePoint::Triangle { shape, u, v } => shape.surface_data(u, v),
+            ShapeSurfacePoint::Rectangle { shape } => shape.surface_data(self.position),
+            ShapeSurfacePoint::Disk { shape } => shape.surface_data(self.position),
+            ShapeSurfacePoint::Torus { shape } => shape.surface_data(self.position),
+            ShapeSurfacePoint::Cuboid { shape } => shape.surface_data(self.position),
+            ShapeSurfacePoint::Curve { normal, u, v, .. } => SurfaceData {
+                normal: Normal::from_vector(normal),
+                texture: Point2::new(u, v),
+            },
+            ShapeSurfacePoint::PointCloud { normal, .. } => SurfaceData {
+                normal: Normal::from_vector(normal),
+                texture: Point2::new(0.0, 0.0),
+            },
             ShapeSurfacePoint::RayMarched {
                 shape,
                 offset_position,
-            } => shape.get_ray_marched_surface_data(offset_position),
+            } => shape.surface_data(offset_position),
+            ShapeSurfacePoint::Csg { shape, inverted } => {
+                let mut surface_data = shape.part_surface_data(self.position);
+
+                if inverted {
+                    surface_data.normal = Normal::from_vector(-surface_data.normal.vector());
+                }
+
+                surface_data
+            }
+            ShapeSurfacePoint::Transformed {
+                shape,
+                leaf,
+                local_position,
+                inverted,
+            } => shape.surface_data(leaf, local_position, inverted),
+            ShapeSurfacePoint::Instance {
+                normal, texture, ..
+            } => SurfaceData { normal, texture },
+        }
+    }
+
+    /// The normal of the surface itself, without interpolated vertex normals
+    /// or other smoothing.
+    pub fn geometric_normal(&self) -> Vector3<f32> {
+        match self.shape {
+            ShapeSurfacePoint::Sphere { shape } => (self.position - shape.position).normalize(),
+            ShapeSurfacePoint::Plane { shape } => shape.normal.vector(),
+            ShapeSurfacePoint::Triangle { shape, .. } => shape.edge1.cross(shape.edge2).normalize(),
+            ShapeSurfacePoint::Rectangle { shape } => shape.normal.vector(),
+            ShapeSurfacePoint::Disk { shape } => shape.normal.vector(),
+            ShapeSurfacePoint::Curve { normal, .. }
+            | ShapeSurfacePoint::PointCloud { normal, .. } => normal,
+            ShapeSurfacePoint::Instance {
+                geometric_normal, ..
+            } => geometric_normal,
+            ShapeSurfacePoint::Torus { .. }
+            | ShapeSurfacePoint::Cuboid { .. }
+            | ShapeSurfacePoint::RayMarched { .. }
+            | ShapeSurfacePoint::Csg { .. }
+            | ShapeSurfacePoint::Transformed { .. } => self.get_surface_data().normal.vector(),
+        }
+    }
+
+    /// See `Shape::texture_density`.
+    pub fn texture_density(&self) -> f32 {
+        match self.shape {
+            ShapeSurfacePoint::Sphere { shape } => shape.texture_density(),
+            ShapeSurfacePoint::Triangle { shape, .. } => shape.texture_density(),
+            ShapeSurfacePoint::Rectangle { shape } => {
+                texture_density(1.0, 1.0, shape.texture_scale)
+            }
+            ShapeSurfacePoint::Disk { shape } => shape.texture_density(),
+            ShapeSurfacePoint::Torus { shape } => shape.texture_density(),
+            ShapeSurfacePoint::Cuboid { shape } => texture_density(1.0, 1.0, shape.texture_scale),
+            ShapeSurfacePoint::Csg { shape, .. } => shape.texture_density(),
+            ShapeSurfacePoint::Transformed { shape, .. } => shape.texture_density(),
+            ShapeSurfacePoint::Plane { shape } => texture_density(1.0, 1.0, shape.texture_scale),
+            ShapeSurfacePoint::Curve { .. }
+            | ShapeSurfacePoint::PointCloud { .. }
+            | ShapeSurfacePoint::RayMarched { .. } => 0.0,
+            ShapeSurfacePoint::Instance {
+                texture_density, ..
+            } => texture_density,
         }
     }
 
-    pub fn get_material(&self) -> &'a Material<'a> {
+    pub fn get_material(&self) -> MaterialId {
         match self.shape {
-            ShapeSurfacePoint::Sphere { shape } => shape.get_material(),
-            ShapeSurfacePoint::Plane { shape } => &shape.material,
-            ShapeSurfacePoint::Triangle { shape, .. } => shape.get_material(),
-            ShapeSurfacePoint::RayMarched { shape, .. } => shape.get_material(),
+            ShapeSurfacePoint::Sphere { shape } => shape.material,
+            ShapeSurfacePoint::Plane { shape } => shape.material,
+            ShapeSurfacePoint::Triangle { shape, .. } => shape.material,
+            ShapeSurfacePoint::Rectangle { shape } => shape.material,
+            ShapeSurfacePoint::Disk { shape } => shape.material,
+            ShapeSurfacePoint::Torus { shape } => shape.material,
+            ShapeSurfacePoint::Cuboid { shape } => shape.material,
+            ShapeSurfacePoint::Curve { shape, .. } => shape.material,
+            ShapeSurfacePoint::PointCloud { shape, .. } => shape.material,
+            ShapeSurfacePoint::RayMarched { shape, .. } => shape.material,
+            ShapeSurfacePoint::Csg { shape, .. } => shape.material(),
+            ShapeSurfacePoint::Transformed { shape, .. } => shape.material(),
+            ShapeSurfacePoint::Instance { material, .. } => material,
         }
     }
 }
@@ -509,20 +2067,67 @@ impl<'a> SurfacePoint<'a> {
 #[derive(Copy, Clone)]
 pub(crate) enum ShapeSurfacePoint<'a> {
     Sphere {
-        shape: &'a Shape<'a>,
+        shape: &'a Sphere,
     },
     Plane {
-        shape: &'a Plane<'a>,
+        shape: &'a Plane,
     },
     Triangle {
-        shape: &'a Shape<'a>,
+        shape: &'a Triangle,
+        u: f32,
+        v: f32,
+    },
+    Rectangle {
+        shape: &'a Rectangle,
+    },
+    Disk {
+        shape: &'a Disk,
+    },
+    Torus {
+        shape: &'a Torus,
+    },
+    Cuboid {
+        shape: &'a Cuboid,
+    },
+    /// A point on a curve, with the normal turned to make the curve look
+    /// round.
+    Curve {
+        shape: &'a Curve,
+        normal: Vector3<f32>,
         u: f32,
         v: f32,
     },
+    /// A point on one of the points in a point cloud. Points don't have
+    /// texture coordinates.
+    PointCloud {
+        shape: &'a PointCloud,
+        normal: Vector3<f32>,
+    },
     RayMarched {
-        shape: &'a Shape<'a>,
+        shape: &'a RayMarched,
         offset_position: Point3<f32>,
     },
+    /// A point on one of the solid parts of a CSG shape.
+    Csg {
+        shape: &'a Shape,
+        inverted: bool,
+    },
+    /// A point on `leaf`, which is a part of the transformed `shape`.
+    Transformed {
+        shape: &'a Transformed,
+        leaf: &'a Shape,
+        local_position: Point3<f32>,
+        inverted: bool,
+    },
+    /// A point on an instance, with the surface data of the prototype's
+    /// shape already moved to the world space.
+    Instance {
+        normal: Normal,
+        geometric_normal: Vector3<f32>,
+        texture: Point2<f32>,
+        texture_density: f32,
+        material: MaterialId,
+    },
 }
 
 pub(crate) struct SurfaceData {
@@ -563,6 +2168,11 @@ impl Normal {
         self.vector
     }
 
+    /// Follows the texture's X axis on surfaces with texture coordinates.
+    pub fn tangent(&self) -> Vector3<f32> {
+        self.from_space(Vector3::unit_x())
+    }
+
     pub fn from_space(&self, vector: Vector3<f32>) -> Vector3<f32> {
         self.from_space * vector
     }
@@ -585,6 +2195,7 @@ impl Normal {
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub enum BoundingVolume {
     Box(Point3<f32>, Point3<f32>),
     Sphere(Point3<f32>, f32),
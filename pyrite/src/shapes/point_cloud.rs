@@ -0,0 +1,149 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Transform, Vector3};
+use collision::{Aabb3, Continuous, Ray3, Union};
+
+use crate::{
+    math::utils::average_scale,
+    spatial::bvh::{Bounded, Bvh},
+};
+
+/// How each point is drawn.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PointShape {
+    Sphere,
+    /// A flat disk that always faces the ray.
+    Disk,
+}
+
+#[derive(Copy, Clone)]
+struct CloudPoint {
+    position: Point3<f32>,
+    radius: f32,
+}
+
+impl Bounded for CloudPoint {
+    fn aabb(&self) -> Aabb3<f32> {
+        let radius = Vector3::new(self.radius, self.radius, self.radius);
+        Aabb3::new(self.position - radius, self.position + radius)
+    }
+}
+
+/// A large number of small spheres or disks with the same size, that have
+/// their own BVH. They are much smaller than the same number of shapes.
+pub struct PointCloud {
+    points: Bvh<CloudPoint>,
+    point_shape: PointShape,
+    bounds: Aabb3<f32>,
+    len: usize,
+}
+
+pub struct PointHit {
+    pub distance: f32,
+    pub normal: Vector3<f32>,
+}
+
+impl PointCloud {
+    pub fn new(positions: &[Point3<f32>], radius: f32, point_shape: PointShape) -> Self {
+        let points: Vec<_> = positions
+            .iter()
+            .map(|&position| CloudPoint { position, radius })
+            .collect();
+
+        let bounds = points
+            .iter()
+            .map(Bounded::aabb)
+            .fold(None, |bounds: Option<Aabb3<f32>>, aabb| {
+                Some(bounds.map_or(aabb, |bounds| bounds.union(&aabb)))
+            })
+            .unwrap_or_else(|| Aabb3::new(Point3::origin(), Point3::origin()));
+
+        PointCloud {
+            len: points.len(),
+            points: Bvh::new(points),
+            point_shape,
+            bounds,
+        }
+    }
+
+    pub fn intersect(&self, ray: &Ray3<f32>) -> Option<PointHit> {
+        let mut closest: Option<PointHit> = None;
+        let mut closest_distance = std::f32::INFINITY;
+
+        let mut candidates = self.points.ray_intersect(*ray);
+        while let Some(point) = candidates.next(closest_distance) {
+            let hit = match self.point_shape {
+                PointShape::Sphere => intersect_sphere(point, ray),
+                PointShape::Disk => intersect_disk(point, ray),
+            };
+
+            if let Some(hit) = hit {
+                if hit.distance > 0.0 && hit.distance < closest_distance {
+                    closest_distance = hit.distance;
+                    closest = Some(hit);
+                }
+            }
+        }
+
+        closest
+    }
+
+    pub fn aabb(&self) -> Aabb3<f32> {
+        self.bounds
+    }
+
+    /// The number of points.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.points.memory_size()
+    }
+
+    /// Moves the points, and builds a new BVH for them.
+    pub fn transform(&mut self, transform: Matrix4<f32>) {
+        let positions: Vec<_> = self
+            .points
+            .items()
+            .map(|point| transform.transform_point(point.position))
+            .collect();
+        let radius = self.points.items().next().map_or(0.0, |point| point.radius);
+
+        *self = PointCloud::new(
+            &positions,
+            radius * average_scale(transform),
+            self.point_shape,
+        );
+    }
+}
+
+fn intersect_sphere(point: &CloudPoint, ray: &Ray3<f32>) -> Option<PointHit> {
+    let sphere = collision::Sphere {
+        radius: point.radius,
+        center: point.position,
+    };
+
+    sphere.intersection(ray).map(|intersection| PointHit {
+        distance: (intersection - ray.origin).magnitude(),
+        normal: (intersection - point.position).normalize(),
+    })
+}
+
+fn intersect_disk(point: &CloudPoint, ray: &Ray3<f32>) -> Option<PointHit> {
+    let direction = ray.direction.normalize();
+    let to_center = point.position - ray.origin;
+    let distance = to_center.dot(direction);
+
+    if distance <= 0.0 {
+        return None;
+    }
+
+    let offset = to_center - direction * distance;
+    if offset.magnitude2() > point.radius * point.radius {
+        return None;
+    }
+
+    Some(PointHit {
+        distance,
+        normal: -direction,
+    })
+}
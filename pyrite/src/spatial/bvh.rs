@@ -162,6 +162,96 @@ impl<T> Bvh<T> {
             ray,
         }
     }
+
+    /// Visits the items that any of the packet's rays may hit before their
+    /// `max_distances`, together with the indices of those rays. Whole
+    /// subtrees are skipped when the packet's bounds miss them, so the rays
+    /// are only tested one by one at the leaves. The distances can be
+    /// shortened by `visit` as closer hits are found.
+    pub fn packet_intersect(
+        &self,
+        packet: &RayPacket,
+        max_distances: &mut [f32],
+        mut visit: impl FnMut(&T, &[usize], &mut [f32]),
+    ) {
+        let mut hits = Vec::with_capacity(packet.rays.len());
+        let mut max_distance = max_distances.iter().cloned().fold(0.0, f32::max);
+        let mut index = 0;
+
+        while let Some(node) = self.nodes.get(index) {
+            if !packet.may_hit(&node.bounding_box, max_distance) {
+                index += 1 + node.subtree_size();
+                continue;
+            }
+
+            if let FlatBvhNodeType::Leaf { item } = &node.node_type {
+                hits.clear();
+                for (ray_index, (ray, &ray_distance)) in
+                    packet.rays.iter().zip(&*max_distances).enumerate()
+                {
+                    if let Some(intersection) = node.bounding_box.intersection(ray) {
+                        if (intersection - ray.origin).magnitude2() < ray_distance * ray_distance {
+                            hits.push(ray_index);
+                        }
+                    }
+                }
+
+                if !hits.is_empty() {
+                    visit(item, &hits, max_distances);
+                    max_distance = max_distances.iter().cloned().fold(0.0, f32::max);
+                }
+            }
+
+            index += 1;
+        }
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &T> {
+        self.nodes.iter().filter_map(|node| match node.node_type {
+            FlatBvhNodeType::Leaf { ref item } => Some(item),
+            FlatBvhNodeType::Node { .. } => None,
+        })
+    }
+
+    /// The bounding boxes of the nodes, together with their depth in the
+    /// tree, in depth first order. Nodes below `max_depth` are skipped.
+    pub fn bounding_boxes(&self, max_depth: Option<usize>) -> Vec<(usize, Aabb3<f32>)> {
+        let mut boxes = Vec::new();
+        let mut subtree_ends: Vec<usize> = Vec::new();
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            while subtree_ends.last().map_or(false, |&end| end <= index) {
+                subtree_ends.pop();
+            }
+
+            let depth = subtree_ends.len();
+            if max_depth.map_or(true, |max_depth| depth <= max_depth) {
+                boxes.push((depth, node.bounding_box));
+            }
+
+            if node.subtree_size() > 0 {
+                subtree_ends.push(index + 1 + node.subtree_size());
+            }
+        }
+
+        boxes
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The number of bytes used by the tree itself, not counting what the
+    /// items may point to.
+    pub fn memory_size(&self) -> usize {
+        self.nodes.capacity() * std::mem::size_of::<FlatBvhNode<T>>()
+    }
+
+    /// The number of bytes a tree with the given number of items is expected
+    /// to use. Each item has its own leaf node.
+    pub fn estimate_memory_size(items: usize) -> usize {
+        (items * 2).saturating_sub(1) * std::mem::size_of::<FlatBvhNode<T>>()
+    }
 }
 
 fn get_bucket_stats<T>(buckets: &[Option<(Vec<T>, Hull)>]) -> (usize, f32) {
@@ -204,7 +294,7 @@ pub struct Intersections<'a, T> {
 }
 
 impl<'a, T> Intersections<'a, T> {
-    pub fn next(&mut self, max_distance: f32) -> Option<&T> {
+    pub fn next(&mut self, max_distance: f32) -> Option<&'a T> {
         let max_sq_distance = max_distance * max_distance;
 
         // Contains the next node after skipping a few
@@ -231,6 +321,103 @@ impl<'a, T> Intersections<'a, T> {
     }
 }
 
+/// A group of rays that are traced together, such as neighboring camera rays,
+/// with intervals that bound all of their origins and directions.
+pub(crate) struct RayPacket {
+    rays: Vec<Ray3<f32>>,
+    origin_min: Point3<f32>,
+    origin_max: Point3<f32>,
+    /// The range of the inverted direction along each axis, if all rays
+    /// point the same way along it.
+    inverse_directions: [Option<(f32, f32)>; 3],
+}
+
+impl RayPacket {
+    pub fn new(rays: Vec<Ray3<f32>>) -> Self {
+        let mut origin_min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut origin_max = Point3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY);
+        let mut inverse_directions = [None; 3];
+
+        for axis in 0..3 {
+            let mut range: Option<(f32, f32)> = None;
+            let mut same_sign = true;
+
+            for ray in &rays {
+                origin_min[axis] = origin_min[axis].min(ray.origin[axis]);
+                origin_max[axis] = origin_max[axis].max(ray.origin[axis]);
+
+                let inverse = 1.0 / ray.direction[axis];
+                same_sign &=
+                    inverse.is_finite() && range.map_or(true, |(min, _)| inverse * min > 0.0);
+                if same_sign {
+                    range = Some(range.map_or((inverse, inverse), |(min, max)| {
+                        (min.min(inverse), max.max(inverse))
+                    }));
+                }
+            }
+
+            if same_sign {
+                inverse_directions[axis] = range;
+            }
+        }
+
+        RayPacket {
+            rays,
+            origin_min,
+            origin_max,
+            inverse_directions,
+        }
+    }
+
+    pub fn rays(&self) -> &[Ray3<f32>] {
+        &self.rays
+    }
+
+    /// Checks if any of the rays may hit `aabb` before `max_distance`, using
+    /// interval arithmetic on the slabs of the axes with a known direction.
+    fn may_hit(&self, aabb: &Aabb3<f32>, max_distance: f32) -> bool {
+        let mut near = 0.0f32;
+        let mut far = max_distance;
+
+        for axis in 0..3 {
+            if let Some((inverse_min, inverse_max)) = self.inverse_directions[axis] {
+                let (entry_plane, exit_plane) = if inverse_min > 0.0 {
+                    (aabb.min[axis], aabb.max[axis])
+                } else {
+                    (aabb.max[axis], aabb.min[axis])
+                };
+
+                let (entry, _) = multiply_intervals(
+                    (
+                        entry_plane - self.origin_max[axis],
+                        entry_plane - self.origin_min[axis],
+                    ),
+                    (inverse_min, inverse_max),
+                );
+                let (_, exit) = multiply_intervals(
+                    (
+                        exit_plane - self.origin_max[axis],
+                        exit_plane - self.origin_min[axis],
+                    ),
+                    (inverse_min, inverse_max),
+                );
+
+                near = near.max(entry);
+                far = far.min(exit);
+            }
+        }
+
+        near <= far
+    }
+}
+
+fn multiply_intervals(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    let products = [a.0 * b.0, a.0 * b.1, a.1 * b.0, a.1 * b.1];
+    let min = products.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = products.iter().cloned().fold(-f32::INFINITY, f32::max);
+    (min, max)
+}
+
 enum StackEntry<T> {
     Split { items: Vec<T>, hull: Hull },
     Join { bounding_box: Aabb3<f32> },
@@ -311,7 +498,7 @@ pub(crate) trait Bounded {
     fn aabb(&self) -> Aabb3<f32>;
 }
 
-impl<'a, T: Bounded> Bounded for &'a T {
+impl<'a, T: Bounded + ?Sized> Bounded for &'a T {
     fn aabb(&self) -> Aabb3<f32> {
         (*self).aabb()
     }
@@ -0,0 +1,267 @@
+//! A bounding volume hierarchy over the lights, for picking lights in
+//! proportion to how much they may contribute to a point, from "Importance
+//! Sampling of Many Lights with Adaptive Tree Splitting" by Conty Estevez and
+//! Kulla. Each node bounds the position, power and orientation of its lights,
+//! and the path down to a light is chosen by comparing the bounds of the two
+//! children.
+//!
+//! Surfaces emit light from both of their sides, so an orientation bounds the
+//! normals of the lights, without telling them apart from their opposites.
+
+use std::f32::consts::PI;
+
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Quaternion, Rad, Rotation, Rotation3, Vector3};
+use collision::{Aabb, Aabb3, Union};
+use rand::Rng;
+
+#[derive(Clone)]
+pub(crate) struct LightBounds {
+    pub bounds: Aabb3<f32>,
+    /// How much light it emits, as intensity times area for surfaces.
+    pub power: f32,
+    pub orientation: Orientation,
+}
+
+#[derive(Copy, Clone)]
+pub(crate) struct Orientation {
+    axis: Vector3<f32>,
+    /// The largest angle between the axis and any of the normals. Lights
+    /// that emit in every direction have an angle of pi.
+    spread: f32,
+}
+
+impl Orientation {
+    pub fn everywhere() -> Self {
+        Orientation {
+            axis: Vector3::unit_z(),
+            spread: PI,
+        }
+    }
+
+    /// A flat surface, or one that emits everywhere if the normal is broken.
+    pub fn flat(normal: Vector3<f32>) -> Self {
+        if normal.magnitude2().is_finite() && normal.magnitude2() > 0.0 {
+            Orientation {
+                axis: normal.normalize(),
+                spread: 0.0,
+            }
+        } else {
+            Orientation::everywhere()
+        }
+    }
+
+    fn union(self, other: Orientation) -> Orientation {
+        let (wide, narrow) = if self.spread >= other.spread {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        if wide.spread >= PI {
+            return wide;
+        }
+
+        // Normals and their opposites are the same, so the closest one is
+        // used.
+        let narrow_axis = if wide.axis.dot(narrow.axis) < 0.0 {
+            -narrow.axis
+        } else {
+            narrow.axis
+        };
+        let between = angle_between(wide.axis, narrow_axis);
+
+        if between + narrow.spread <= wide.spread {
+            return wide;
+        }
+
+        let spread = (wide.spread + between + narrow.spread) * 0.5;
+        if spread >= PI {
+            return Orientation::everywhere();
+        }
+
+        let rotation_axis = wide.axis.cross(narrow_axis);
+        if rotation_axis.magnitude2() <= 0.0 {
+            return Orientation::everywhere();
+        }
+
+        let rotation =
+            Quaternion::from_axis_angle(rotation_axis.normalize(), Rad(spread - wide.spread));
+        Orientation {
+            axis: rotation.rotate_vector(wide.axis),
+            spread,
+        }
+    }
+}
+
+impl LightBounds {
+    fn union(&self, other: &LightBounds) -> LightBounds {
+        LightBounds {
+            bounds: self.bounds.union(&other.bounds),
+            power: self.power + other.power,
+            orientation: self.orientation.union(other.orientation),
+        }
+    }
+
+    /// An upper bound of how much the lights may contribute to a surface at
+    /// `position`, with `normal`, relative to other lights. It's conservative,
+    /// so it's only 0 when none of the light can reach the surface.
+    fn importance(&self, position: Point3<f32>, normal: Vector3<f32>) -> f32 {
+        let center = self.bounds.center();
+        let radius = self.bounds.dim().magnitude() * 0.5;
+        let offset = position - center;
+        let distance = offset.magnitude();
+
+        // Points within the bounds could be anywhere relative to the lights.
+        if distance <= radius {
+            return self.power / (radius * radius).max(std::f32::MIN_POSITIVE);
+        }
+
+        let direction = offset / distance;
+        let bounds_angle = (radius / distance).asin();
+
+        let emitter_angle = angle_between(self.orientation.axis, direction);
+        let emitter_angle = emitter_angle.min(PI - emitter_angle);
+        let emitter_cos = (emitter_angle - self.orientation.spread - bounds_angle)
+            .max(0.0)
+            .cos();
+
+        let receiver_angle = angle_between(normal, -direction);
+        let receiver_angle = (receiver_angle - bounds_angle).max(0.0);
+        if receiver_angle >= PI * 0.5 {
+            return 0.0;
+        }
+
+        self.power * emitter_cos * receiver_angle.cos() / (distance * distance).max(radius * radius)
+    }
+}
+
+enum Node {
+    Leaf {
+        bounds: LightBounds,
+        light: usize,
+    },
+    /// The first child comes right after the branch.
+    Branch {
+        bounds: LightBounds,
+        second: usize,
+    },
+}
+
+impl Node {
+    fn bounds(&self) -> &LightBounds {
+        match *self {
+            Node::Leaf { ref bounds, .. } | Node::Branch { ref bounds, .. } => bounds,
+        }
+    }
+}
+
+pub(crate) struct LightTree {
+    nodes: Vec<Node>,
+}
+
+impl LightTree {
+    /// Builds a tree over the lights, which are referred to by their index.
+    /// Lights without any power are left out.
+    pub fn new(lights: Vec<LightBounds>) -> Self {
+        let mut lights: Vec<_> = lights
+            .into_iter()
+            .enumerate()
+            .filter(|(_, light)| light.power > 0.0 && light.power.is_finite())
+            .collect();
+
+        let mut nodes = Vec::with_capacity(lights.len() * 2);
+        if !lights.is_empty() {
+            build(&mut lights, &mut nodes);
+        }
+
+        LightTree { nodes }
+    }
+
+    /// Picks a light for a surface at `position`, with `normal`, and returns
+    /// its index and the probability of picking it.
+    pub fn sample(
+        &self,
+        rng: &mut impl Rng,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+    ) -> Option<(usize, f32)> {
+        let mut index = 0;
+        let mut probability = 1.0;
+
+        loop {
+            match *self.nodes.get(index)? {
+                Node::Leaf { light, .. } => return Some((light, probability)),
+                Node::Branch { second, .. } => {
+                    let first_importance =
+                        self.nodes[index + 1].bounds().importance(position, normal);
+                    let second_importance =
+                        self.nodes[second].bounds().importance(position, normal);
+                    let total = first_importance + second_importance;
+
+                    if !(total > 0.0 && total.is_finite()) {
+                        return None;
+                    }
+
+                    let first_probability = first_importance / total;
+                    if rng.gen::<f32>() < first_probability {
+                        index += 1;
+                        probability *= first_probability;
+                    } else {
+                        index = second;
+                        probability *= 1.0 - first_probability;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Splits the lights into two halves along the longest axis of their centers,
+/// until each node has one light.
+fn build(lights: &mut [(usize, LightBounds)], nodes: &mut Vec<Node>) {
+    let bounds = lights[1..]
+        .iter()
+        .fold(lights[0].1.clone(), |bounds, (_, light)| {
+            bounds.union(light)
+        });
+
+    if let [(light, _)] = *lights {
+        nodes.push(Node::Leaf { bounds, light });
+        return;
+    }
+
+    let centers = lights[1..].iter().fold(
+        Aabb3::new(lights[0].1.bounds.center(), lights[0].1.bounds.center()),
+        |centers, (_, light)| centers.grow(light.bounds.center()),
+    );
+    let size = centers.dim();
+    let axis = if size.x >= size.y && size.x >= size.z {
+        0
+    } else if size.y >= size.z {
+        1
+    } else {
+        2
+    };
+
+    lights.sort_by(|(_, a), (_, b)| {
+        let a = a.bounds.center().to_vec()[axis];
+        let b = b.bounds.center().to_vec()[axis];
+        a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let index = nodes.len();
+    nodes.push(Node::Branch { bounds, second: 0 });
+
+    let (first, second) = lights.split_at_mut(lights.len() / 2);
+    build(first, nodes);
+    let second_index = nodes.len();
+    build(second, nodes);
+
+    if let Node::Branch { ref mut second, .. } = nodes[index] {
+        *second = second_index;
+    }
+}
+
+fn angle_between(a: Vector3<f32>, b: Vector3<f32>) -> f32 {
+    a.dot(b).max(-1.0).min(1.0).acos()
+}
@@ -2,6 +2,7 @@ use cgmath::Point3;
 
 pub(crate) mod bvh;
 pub(crate) mod kd_tree;
+pub(crate) mod light_tree;
 
 pub trait Dimensions: Copy {
     fn first() -> Self;
@@ -0,0 +1,196 @@
+//! Sends developed images to a viewer over TCP, while rendering.
+//!
+//! Only the tiles that changed since the previous update are sent, with one
+//! message for each tile. Each message has these parts, where numbers are
+//! little endian:
+//!
+//! * The 8 bytes `PYRSTRM\0`.
+//! * The image's width and height, as `u32`.
+//! * The position and size of the tile, as four `u32`: x, y, width and
+//!   height.
+//! * The progress of the current pass, in percent, as a `u8`.
+//! * The length of the PNG data, as a `u32`, followed by the PNG encoded
+//!   pixels of the tile.
+//!
+//! An update where nothing changed is a single message with an empty tile and
+//! no PNG data, that only carries the progress.
+
+use std::{
+    error::Error,
+    io::Write,
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{film::Film, utils::split_authority};
+
+/// Identifies each update message.
+const STREAM_MAGIC: &[u8; 8] = b"PYRSTRM\0";
+
+/// How long to wait for the viewer before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The shortest time between updates while rendering.
+pub(crate) const INTERVAL: Duration = Duration::from_secs(2);
+
+/// The width and height of the tiles that are compared and sent.
+const TILE_SIZE: u32 = 64;
+
+/// A viewer address, like `tcp://workstation:7878`.
+#[derive(Clone)]
+pub(crate) struct StreamTarget {
+    host: String,
+    port: u16,
+}
+
+impl StreamTarget {
+    pub fn parse(url: &str) -> Result<Self, Box<dyn Error>> {
+        let authority = if url.starts_with("tcp://") {
+            &url["tcp://".len()..]
+        } else {
+            return Err(format!("expected a tcp:// URL, got '{}'", url).into());
+        };
+        let authority = authority.trim_end_matches('/');
+
+        let (host, port) = split_authority(authority, None)
+            .map_err(|error| format!("invalid URL '{}': {}", url, error))?;
+
+        Ok(StreamTarget { host, port })
+    }
+}
+
+/// A connection to a viewer. It's closed after the first error, so a viewer
+/// that goes away doesn't interrupt the render.
+pub(crate) struct Stream {
+    connection: Option<TcpStream>,
+    /// The pixels of the previous update, that the viewer already has.
+    previous: Option<image::RgbImage>,
+}
+
+impl Stream {
+    pub fn connect(target: &StreamTarget) -> Result<Self, Box<dyn Error>> {
+        let address = (&*target.host, target.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("could not resolve '{}'", target.host))?;
+
+        let connection = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        connection.set_write_timeout(Some(TIMEOUT))?;
+        connection.set_nodelay(true)?;
+
+        Ok(Stream {
+            connection: Some(connection),
+            previous: None,
+        })
+    }
+
+    /// Sends the tiles of the film's region that changed since the previous
+    /// update.
+    pub fn send(&mut self, film: &Film, pixels: &image::RgbImage, progress: u8) {
+        let connection = if let Some(connection) = &mut self.connection {
+            connection
+        } else {
+            return;
+        };
+
+        // Everything is sent again if the image changed size.
+        let previous = self
+            .previous
+            .as_ref()
+            .filter(|previous| previous.dimensions() == pixels.dimensions());
+
+        if let Err(error) = write_update(connection, film, pixels, previous, progress) {
+            println!("\rwarning: stopped streaming: {}", error);
+            self.connection = None;
+            self.previous = None;
+        } else {
+            self.previous = Some(pixels.clone());
+        }
+    }
+}
+
+fn write_update(
+    connection: &mut TcpStream,
+    film: &Film,
+    pixels: &image::RgbImage,
+    previous: Option<&image::RgbImage>,
+    progress: u8,
+) -> Result<(), Box<dyn Error>> {
+    let mut message = Vec::new();
+
+    for (x, y, width, height) in changed_tiles(pixels, previous) {
+        let tile = image::imageops::crop_imm(pixels, x, y, width, height).to_image();
+        let mut png = Vec::new();
+        image::png::PNGEncoder::new(&mut png).encode(
+            &tile,
+            width,
+            height,
+            image::ColorType::Rgb8,
+        )?;
+
+        write_message(&mut message, film, (x, y, width, height), progress, &png);
+    }
+
+    if message.is_empty() {
+        write_message(&mut message, film, (0, 0, 0, 0), progress, &[]);
+    }
+
+    connection.write_all(&message)?;
+    connection.flush()?;
+
+    Ok(())
+}
+
+/// Finds the tiles, as x, y, width and height, where `pixels` differ from
+/// `previous`. All of them are changed if there's no previous image.
+fn changed_tiles(
+    pixels: &image::RgbImage,
+    previous: Option<&image::RgbImage>,
+) -> Vec<(u32, u32, u32, u32)> {
+    let (image_width, image_height) = pixels.dimensions();
+    let mut tiles = Vec::new();
+
+    for y in (0..image_height).step_by(TILE_SIZE as usize) {
+        for x in (0..image_width).step_by(TILE_SIZE as usize) {
+            let width = TILE_SIZE.min(image_width - x);
+            let height = TILE_SIZE.min(image_height - y);
+
+            let changed = previous.map_or(true, |previous| {
+                (y..y + height).any(|y| {
+                    (x..x + width).any(|x| pixels.get_pixel(x, y) != previous.get_pixel(x, y))
+                })
+            });
+
+            if changed {
+                tiles.push((x, y, width, height));
+            }
+        }
+    }
+
+    tiles
+}
+
+/// Appends a message for a tile, at `x` and `y` in the film's region.
+fn write_message(
+    message: &mut Vec<u8>,
+    film: &Film,
+    (x, y, width, height): (u32, u32, u32, u32),
+    progress: u8,
+    png: &[u8],
+) {
+    let region = film.region();
+    message.extend_from_slice(STREAM_MAGIC);
+    for value in &[
+        film.width() as u32,
+        film.height() as u32,
+        region.from.x as u32 + x,
+        region.from.y as u32 + y,
+        width,
+        height,
+    ] {
+        message.extend_from_slice(&value.to_le_bytes());
+    }
+    message.push(progress);
+    message.extend_from_slice(&(png.len() as u32).to_le_bytes());
+    message.extend_from_slice(png);
+}
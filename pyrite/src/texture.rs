@@ -1,27 +1,65 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{error::Error, fs::File, io::BufReader, path::Path};
 
-use cgmath::Point2;
+use cgmath::{ElementWise, EuclideanSpace, InnerSpace, Point2, Vector2, Zero};
 use palette::{
     white_point::D65, Alpha, Component, IntoColor, IntoComponent, LinLuma, LinLumaa, LinSrgb,
     LinSrgba, Pixel, Srgb, SrgbLuma, SrgbLumaa, Srgba,
 };
 
-/// Linearized image data.
+/// The longest that an elliptical filter can be, relative to its width.
+const MAX_ECCENTRICITY: f32 = 8.0;
+
+/// Linearized image data, with smaller versions of it for looking up
+/// textures that are far away.
+#[derive(Clone)]
 pub struct Texture {
     format: TextureFormat,
-    width: usize,
-    height: usize,
-    data: Vec<f32>,
+    /// The full image, followed by levels that are half as large as the one
+    /// before, down to a single pixel.
+    levels: Vec<Level>,
 }
 
 impl Texture {
     pub fn from_path<P: AsRef<Path>>(
         path: P,
         encoding: ColorEncoding,
-    ) -> image::ImageResult<Texture> {
+    ) -> Result<Texture, Box<dyn Error>> {
         use image::GenericImageView;
 
         let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+
+        // High dynamic range images are always linear.
+        match extension.as_deref() {
+            Some("exr") => {
+                let image = crate::exr::read(path)?;
+                return Ok(Texture::new(
+                    TextureFormat::Rgb,
+                    image.width() as usize,
+                    image.height() as usize,
+                    image.into_raw(),
+                ));
+            }
+            Some("hdr") => {
+                let decoder = image::hdr::HdrDecoder::new(BufReader::new(File::open(path)?))?;
+                let metadata = decoder.metadata();
+                let pixels = decoder.read_image_hdr()?;
+                return Ok(Texture::new(
+                    TextureFormat::Rgb,
+                    metadata.width as usize,
+                    metadata.height as usize,
+                    pixels
+                        .into_iter()
+                        .flat_map(|pixel| pixel.0.to_vec())
+                        .collect(),
+                ));
+            }
+            _ => {}
+        }
+
         let image = image::load(
             BufReader::new(File::open(path)?),
             image::ImageFormat::from_path(path)?,
@@ -71,89 +109,450 @@ impl Texture {
             ),
         };
 
-        Ok(Texture {
-            format,
-            width: width as usize,
-            height: height as usize,
+        Ok(Texture::new(format, width as usize, height as usize, data))
+    }
+
+    /// Builds the chain of smaller levels from the full image.
+    fn new(format: TextureFormat, width: usize, height: usize, data: Vec<f32>) -> Texture {
+        let mut levels = vec![Level {
+            width,
+            height,
             data,
-        })
+        }];
+
+        while let Some(level) = levels
+            .last()
+            .filter(|level| level.width > 1 || level.height > 1)
+        {
+            let smaller = level.downscale();
+            levels.push(smaller);
+        }
+
+        Texture { format, levels }
     }
 
-    pub fn get_color(&self, position: Point2<f32>) -> LinSrgba {
-        let width_f = self.width as f32;
-        let height_f = self.height as f32;
-
-        let x = position.x * width_f - 0.5;
-        let x2 = x.floor();
-        let x1 = x2 - 1.0;
-        let x3 = x2 + 1.0;
-        let x4 = x2 + 2.0;
-
-        let y = 1.0 - (position.y * height_f - 0.5);
-        let y2 = y.floor();
-        let y1 = y2 - 1.0;
-        let y3 = y2 + 1.0;
-        let y4 = y2 + 2.0;
-
-        let x = x.rem_euclid(1.0);
-        let x1 = (x1.rem_euclid(width_f) as usize).min(self.width - 1);
-        let x2 = (x2.rem_euclid(width_f) as usize).min(self.width - 1);
-        let x3 = (x3.rem_euclid(width_f) as usize).min(self.width - 1);
-        let x4 = (x4.rem_euclid(width_f) as usize).min(self.width - 1);
-
-        let y = y.rem_euclid(1.0);
-        let y1 = (y1.rem_euclid(height_f) as usize).min(self.height - 1);
-        let y2 = (y2.rem_euclid(height_f) as usize).min(self.height - 1);
-        let y3 = (y3.rem_euclid(height_f) as usize).min(self.height - 1);
-        let y4 = (y4.rem_euclid(height_f) as usize).min(self.height - 1);
+    /// Looks up the color at `position`, blended over the `footprint`. Levels
+    /// where a pixel is about as large as the footprint are used, so small
+    /// details don't flicker when they are far away.
+    pub fn get_color(
+        &self,
+        position: Point2<f32>,
+        filter: TextureFilter,
+        wrap: TextureWrap,
+        footprint: Footprint,
+    ) -> LinSrgba {
+        let full_size = Vector2::new(self.width() as f32, self.height() as f32);
+        let major = footprint.major.mul_element_wise(full_size);
+        let minor = footprint.minor.mul_element_wise(full_size);
+
+        match filter {
+            TextureFilter::Bicubic | TextureFilter::Bilinear => {
+                let size = major.magnitude().max(minor.magnitude());
+                self.blend_levels(size, |level| match filter {
+                    TextureFilter::Bilinear => self.get_bilinear(level, position, wrap),
+                    _ => self.get_bicubic(level, position, wrap),
+                })
+            }
+            TextureFilter::Ewa => {
+                // Very long ellipses would cover too many pixels, so they are
+                // made wider, and more blurry, instead.
+                let (major, mut minor) = if major.magnitude2() >= minor.magnitude2() {
+                    (major, minor)
+                } else {
+                    (minor, major)
+                };
+                let (major_length, minor_length) = (major.magnitude(), minor.magnitude());
+                if minor_length * MAX_ECCENTRICITY < major_length {
+                    minor = if minor_length > 0.0 {
+                        minor * (major_length / (minor_length * MAX_ECCENTRICITY))
+                    } else {
+                        Vector2::new(-major.y, major.x) / MAX_ECCENTRICITY
+                    };
+                }
+
+                self.blend_levels(minor.magnitude(), |level| {
+                    let scale = 1.0 / (1 << level) as f32;
+                    self.get_ewa(level, position, major * scale, minor * scale, wrap)
+                })
+            }
+        }
+    }
+
+    /// Picks the two levels where a pixel is closest to `size` pixels of the
+    /// full image, and blends their colors.
+    fn blend_levels(&self, size: f32, mut get: impl FnMut(usize) -> LinSrgba) -> LinSrgba {
+        let max_level = (self.levels.len() - 1) as f32;
+        let level = size.log2().max(0.0).min(max_level);
+        let first = level.floor();
+        let fraction = level - first;
+
+        if fraction <= 0.0 {
+            get(first as usize)
+        } else {
+            get(first as usize) * (1.0 - fraction) + get(first as usize + 1) * fraction
+        }
+    }
+
+    fn get_bilinear(&self, level: usize, position: Point2<f32>, wrap: TextureWrap) -> LinSrgba {
+        let (x, y, column, row) = self.pixel_position(level, position, wrap);
+        let color_at =
+            |x_offset, y_offset| self.level_color_at(level, column(x_offset), row(y_offset));
+
+        let top = color_at(0, 0) * (1.0 - x) + color_at(1, 0) * x;
+        let bottom = color_at(0, 1) * (1.0 - x) + color_at(1, 1) * x;
+
+        top * (1.0 - y) + bottom * y
+    }
+
+    fn get_bicubic(&self, level: usize, position: Point2<f32>, wrap: TextureWrap) -> LinSrgba {
+        let (x, y, column, row) = self.pixel_position(level, position, wrap);
+        let color_at =
+            |x_offset, y_offset| self.level_color_at(level, column(x_offset), row(y_offset));
 
         let points = [
             [
-                self.color_at(x1, y1),
-                self.color_at(x2, y1),
-                self.color_at(x3, y1),
-                self.color_at(x4, y1),
+                color_at(-1, -1),
+                color_at(0, -1),
+                color_at(1, -1),
+                color_at(2, -1),
             ],
             [
-                self.color_at(x1, y2),
-                self.color_at(x2, y2),
-                self.color_at(x3, y2),
-                self.color_at(x4, y2),
+                color_at(-1, 0),
+                color_at(0, 0),
+                color_at(1, 0),
+                color_at(2, 0),
             ],
             [
-                self.color_at(x1, y3),
-                self.color_at(x2, y3),
-                self.color_at(x3, y3),
-                self.color_at(x4, y3),
+                color_at(-1, 1),
+                color_at(0, 1),
+                color_at(1, 1),
+                color_at(2, 1),
             ],
             [
-                self.color_at(x1, y4),
-                self.color_at(x2, y4),
-                self.color_at(x3, y4),
-                self.color_at(x4, y4),
+                color_at(-1, 2),
+                color_at(0, 2),
+                color_at(1, 2),
+                color_at(2, 2),
             ],
         ];
 
         bicubic_interpolate(points, x, y)
     }
 
-    fn color_at(&self, x: usize, y: usize) -> LinSrgba {
-        let index = x + y * self.width;
+    /// Finds the pixel before `position`, and how far past its center the
+    /// position is. The pixels around it are found with the returned column
+    /// and row functions, which take an offset in pixels.
+    fn pixel_position(
+        &self,
+        level: usize,
+        position: Point2<f32>,
+        wrap: TextureWrap,
+    ) -> (f32, f32, impl Fn(i64) -> usize, impl Fn(i64) -> usize) {
+        let Level { width, height, .. } = self.levels[level];
+
+        // The image's Y axis points down, unlike the texture coordinates.
+        let x = position.x * width as f32 - 0.5;
+        let y = (1.0 - position.y) * height as f32 - 0.5;
+        let (first_x, first_y) = (x.floor(), y.floor());
+
+        (
+            x - first_x,
+            y - first_y,
+            move |offset| wrap.pixel(first_x as i64 + offset, width),
+            move |offset| wrap.pixel(first_y as i64 + offset, height),
+        )
+    }
+
+    /// An elliptical weighted average, from "Creating Raster Omnimax Images
+    /// from Multiple Perspective Views Using the Elliptical Weighted Average
+    /// Filter" by Greene and Heckbert. The pixels within the ellipse, with
+    /// the axes `major` and `minor` in pixels, are weighted by a Gaussian
+    /// falloff from its center.
+    fn get_ewa(
+        &self,
+        level: usize,
+        position: Point2<f32>,
+        major: Vector2<f32>,
+        minor: Vector2<f32>,
+        wrap: TextureWrap,
+    ) -> LinSrgba {
+        let Level { width, height, .. } = self.levels[level];
+
+        // The image's Y axis points down, unlike the texture coordinates.
+        let x = position.x * width as f32 - 0.5;
+        let y = (1.0 - position.y) * height as f32 - 0.5;
+        let (major, minor) = (
+            Vector2::new(major.x, -major.y),
+            Vector2::new(minor.x, -minor.y),
+        );
+
+        // The ellipse is widened by a pixel, so it's never between pixels.
+        let mut a = major.y * major.y + minor.y * minor.y + 1.0;
+        let mut b = -2.0 * (major.x * major.y + minor.x * minor.y);
+        let mut c = major.x * major.x + minor.x * minor.x + 1.0;
+        let inverse_f = 1.0 / (a * c - b * b * 0.25);
+        a *= inverse_f;
+        b *= inverse_f;
+        c *= inverse_f;
+
+        let determinant = -b * b + 4.0 * a * c;
+        let extent_x = 2.0 * (determinant * c).sqrt() / determinant;
+        let extent_y = 2.0 * (determinant * a).sqrt() / determinant;
+
+        let mut sum = LinSrgba::new(0.0, 0.0, 0.0, 0.0);
+        let mut total_weight = 0.0;
+
+        for pixel_y in (y - extent_y).ceil() as i64..=(y + extent_y).floor() as i64 {
+            let offset_y = pixel_y as f32 - y;
+            let wrapped_y = wrap.pixel(pixel_y, height);
+
+            for pixel_x in (x - extent_x).ceil() as i64..=(x + extent_x).floor() as i64 {
+                let offset_x = pixel_x as f32 - x;
+                let radius2 =
+                    a * offset_x * offset_x + b * offset_x * offset_y + c * offset_y * offset_y;
+
+                if radius2 < 1.0 {
+                    let weight = (-2.0 * radius2).exp() - (-2.0f32).exp();
+                    let wrapped_x = wrap.pixel(pixel_x, width);
+                    sum = sum + self.level_color_at(level, wrapped_x, wrapped_y) * weight;
+                    total_weight += weight;
+                }
+            }
+        }
+
+        if total_weight > 0.0 {
+            sum * (1.0 / total_weight)
+        } else {
+            self.get_bilinear(level, position, wrap)
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.levels
+            .iter()
+            .map(|level| level.data.capacity() * std::mem::size_of::<f32>())
+            .sum()
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    /// Halves the width and height, by removing the full size level.
+    pub fn downscale(&mut self) {
+        if self.levels.len() > 1 {
+            self.levels.remove(0);
+        }
+    }
+
+    pub fn color_at(&self, x: usize, y: usize) -> LinSrgba {
+        self.level_color_at(0, x, y)
+    }
+
+    fn level_color_at(&self, level: usize, x: usize, y: usize) -> LinSrgba {
+        let level = &self.levels[level];
+        let index = x + y * level.width;
 
         match self.format {
-            TextureFormat::Mono => LinLuma::from_raw_slice(&self.data)[index].into_color(),
-            TextureFormat::MonoAlpha => LinLumaa::from_raw_slice(&self.data)[index].into_color(),
-            TextureFormat::Rgb => LinSrgb::from_raw_slice(&self.data)[index].into_color(),
-            TextureFormat::RgbAlpha => LinSrgba::from_raw_slice(&self.data)[index],
+            TextureFormat::Mono => LinLuma::from_raw_slice(&level.data)[index].into_color(),
+            TextureFormat::MonoAlpha => LinLumaa::from_raw_slice(&level.data)[index].into_color(),
+            TextureFormat::Rgb => LinSrgb::from_raw_slice(&level.data)[index].into_color(),
+            TextureFormat::RgbAlpha => LinSrgba::from_raw_slice(&level.data)[index],
         }
     }
 }
 
+/// One size of a texture.
+#[derive(Clone)]
+struct Level {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Level {
+    /// Halves the width and height, by averaging blocks of 2x2 pixels.
+    fn downscale(&self) -> Level {
+        let channels = self.data.len() / (self.width * self.height);
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut data = Vec::with_capacity(width * height * channels);
+
+        for y in 0..height {
+            for x in 0..width {
+                for channel in 0..channels {
+                    let mut sum = 0.0;
+                    let mut count = 0.0;
+
+                    for source_y in y * 2..(y * 2 + 2).min(self.height) {
+                        for source_x in x * 2..(x * 2 + 2).min(self.width) {
+                            let index = (source_x + source_y * self.width) * channels + channel;
+                            sum += self.data[index];
+                            count += 1.0;
+                        }
+                    }
+
+                    data.push(sum / count);
+                }
+            }
+        }
+
+        Level {
+            width,
+            height,
+            data,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub enum ColorEncoding {
     Linear,
     Srgb,
 }
 
+/// How pixels are blended between their centers. Bicubic filtering is
+/// smoother, while bilinear filtering is cheaper and doesn't overshoot
+/// around sharp edges.
+#[derive(Copy, Clone, PartialEq)]
+pub enum TextureFilter {
+    Bicubic,
+    Bilinear,
+    /// An elliptical filter, that follows the shape of the footprint. It's
+    /// sharper than the others on surfaces that are seen from the side.
+    Ewa,
+}
+
+impl TextureFilter {
+    /// Filters are passed to programs as numbers.
+    pub fn to_number(self) -> f32 {
+        match self {
+            TextureFilter::Bicubic => 0.0,
+            TextureFilter::Bilinear => 1.0,
+            TextureFilter::Ewa => 2.0,
+        }
+    }
+
+    pub fn from_number(number: f32) -> Self {
+        if number == 1.0 {
+            TextureFilter::Bilinear
+        } else if number == 2.0 {
+            TextureFilter::Ewa
+        } else {
+            TextureFilter::Bicubic
+        }
+    }
+}
+
+/// How texture coordinates outside of 0 to 1 are handled.
+#[derive(Copy, Clone, PartialEq)]
+pub enum TextureWrap {
+    Repeat,
+    /// Continues the edge pixels.
+    Clamp,
+    /// Repeats the texture, with every other copy flipped, so the edges
+    /// line up.
+    Mirror,
+}
+
+impl TextureWrap {
+    /// Wrap modes are passed to programs as numbers.
+    pub fn to_number(self) -> f32 {
+        match self {
+            TextureWrap::Repeat => 0.0,
+            TextureWrap::Clamp => 1.0,
+            TextureWrap::Mirror => 2.0,
+        }
+    }
+
+    pub fn from_number(number: f32) -> Self {
+        if number == 1.0 {
+            TextureWrap::Clamp
+        } else if number == 2.0 {
+            TextureWrap::Mirror
+        } else {
+            TextureWrap::Repeat
+        }
+    }
+
+    /// The pixel that `index` refers to, along a side that is `size` pixels
+    /// long.
+    fn pixel(self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        let index = match self {
+            TextureWrap::Repeat => index.rem_euclid(size),
+            TextureWrap::Clamp => index.max(0).min(size - 1),
+            TextureWrap::Mirror => {
+                let index = index.rem_euclid(size * 2);
+                if index < size {
+                    index
+                } else {
+                    size * 2 - 1 - index
+                }
+            }
+        };
+
+        index as usize
+    }
+}
+
+/// Places a texture on a surface, by scaling, rotating and moving the
+/// texture coordinates, in that order. A larger scale makes the texture
+/// repeat more often.
+#[derive(Copy, Clone)]
+pub struct UvTransform {
+    pub offset: Vector2<f32>,
+    pub scale: Vector2<f32>,
+    /// Counterclockwise, in degrees.
+    pub rotation: f32,
+}
+
+impl UvTransform {
+    pub fn point(&self, point: Point2<f32>) -> Point2<f32> {
+        Point2::from_vec(self.vector(point.to_vec()) + self.offset)
+    }
+
+    /// Transforms the axes of the footprint, which aren't moved.
+    pub fn footprint(&self, footprint: Footprint) -> Footprint {
+        Footprint {
+            major: self.vector(footprint.major),
+            minor: self.vector(footprint.minor),
+        }
+    }
+
+    fn vector(&self, vector: Vector2<f32>) -> Vector2<f32> {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let scaled = vector.mul_element_wise(self.scale);
+
+        Vector2::new(
+            scaled.x * cos - scaled.y * sin,
+            scaled.x * sin + scaled.y * cos,
+        )
+    }
+}
+
+/// The part of a texture that a lookup covers, as an ellipse in texture
+/// coordinates. The axes go from its center to its edge, and are 0 for
+/// lookups at a single point.
+#[derive(Copy, Clone)]
+pub struct Footprint {
+    pub major: Vector2<f32>,
+    pub minor: Vector2<f32>,
+}
+
+impl Default for Footprint {
+    fn default() -> Self {
+        Footprint {
+            major: Vector2::zero(),
+            minor: Vector2::zero(),
+        }
+    }
+}
+
 fn convert_pixels<C, T>(pixels: &[T], encoding: ColorEncoding) -> Vec<f32>
 where
     C: SourceColor + Pixel<T> + Copy,
@@ -281,6 +680,7 @@ impl IntoLinearFloats for LinBgr {
     }
 }
 
+#[derive(Copy, Clone)]
 enum TextureFormat {
     Mono,
     MonoAlpha,
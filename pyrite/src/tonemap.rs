@@ -0,0 +1,43 @@
+use std::error::Error;
+
+use palette::LinSrgb;
+
+/// Maps linear colors, that can be arbitrarily bright, to the range of the
+/// image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum Tonemap {
+    /// Cuts off everything above 1.
+    Clamp,
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+impl Tonemap {
+    pub fn parse(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "clamp" => Ok(Tonemap::Clamp),
+            "reinhard" => Ok(Tonemap::Reinhard),
+            "aces" => Ok(Tonemap::Aces),
+            name => Err(format!("unknown tone mapping: '{}'", name).into()),
+        }
+    }
+
+    pub fn apply(self, color: LinSrgb) -> LinSrgb {
+        let map = |value: f32| -> f32 {
+            let value = value.max(0.0);
+
+            match self {
+                Tonemap::Clamp => value.min(1.0),
+                Tonemap::Reinhard => value / (1.0 + value),
+                Tonemap::Aces => {
+                    let mapped =
+                        (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14);
+                    mapped.min(1.0)
+                }
+            }
+        };
+
+        LinSrgb::new(map(color.red), map(color.green), map(color.blue))
+    }
+}
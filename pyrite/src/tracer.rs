@@ -2,19 +2,22 @@ use std::{self, error::Error};
 
 use rand::Rng;
 
-use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector3};
+use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector2, Vector3};
 use collision::Ray3;
 
 use crate::{
     color,
     lamp::{self, Lamp},
-    math::DIST_EPSILON,
-    project::program::{ExecutionContext, InputFn, Program, ProgramInput},
+    materials::MaterialId,
+    math::{offset_ray, utils::solid_angle},
+    project::program::{ExecutionContext, HitKey, InputFn, Program, ProgramInput},
+    shapes::{Intersection, Normal},
+    texture::Footprint,
     world::World,
 };
 
 pub(crate) use self::Reflection::{Emit, Reflect};
-use color::WavelengthInput;
+use color::{WavelengthInput, Wavelengths};
 
 pub type Brdf = fn(ray_in: Vector3<f32>, ray_out: Vector3<f32>, normal: Vector3<f32>) -> f32;
 pub(crate) type LightProgram<'p> = Program<'p, RenderContext, color::Light>;
@@ -38,6 +41,8 @@ pub struct NormalInput {
     pub normal: Vector3<f32>,
     pub incident: Vector3<f32>,
     pub texture: Point2<f32>,
+    pub footprint: Footprint,
+    pub position: Point3<f32>,
 }
 
 impl ProgramInput for NormalInput {
@@ -50,13 +55,21 @@ impl ProgramInput for NormalInput {
     fn texture_coordinates() -> Result<InputFn<Self>, Box<dyn Error>> {
         Ok(|_, this, _| this.texture.into())
     }
+    fn texture_footprint() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.footprint.into())
+    }
+    fn position() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.position.into())
+    }
 }
 
 pub struct RenderContext {
-    pub wavelength: f32,
+    pub wavelengths: Wavelengths,
     pub normal: Vector3<f32>,
     pub incident: Vector3<f32>,
     pub texture: Point2<f32>,
+    pub footprint: Footprint,
+    pub position: Point3<f32>,
 }
 
 impl ProgramInput for RenderContext {
@@ -69,11 +82,32 @@ impl ProgramInput for RenderContext {
     fn texture_coordinates() -> Result<InputFn<Self>, Box<dyn Error>> {
         Ok(|_, this, _| this.texture.into())
     }
+    fn texture_footprint() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.footprint.into())
+    }
+    fn position() -> Result<InputFn<Self>, Box<dyn Error>> {
+        Ok(|_, this, _| this.position.into())
+    }
+    fn hit_key(&self) -> Option<HitKey> {
+        Some([
+            self.normal.x.to_bits(),
+            self.normal.y.to_bits(),
+            self.normal.z.to_bits(),
+            self.incident.x.to_bits(),
+            self.incident.y.to_bits(),
+            self.incident.z.to_bits(),
+            self.texture.x.to_bits(),
+            self.texture.y.to_bits(),
+            self.position.x.to_bits(),
+            self.position.y.to_bits(),
+            self.position.z.to_bits(),
+        ])
+    }
 }
 
 impl WavelengthInput for RenderContext {
-    fn wavelength(&self) -> f32 {
-        self.wavelength
+    fn wavelengths(&self) -> &[f32] {
+        self.wavelengths.as_slice()
     }
 }
 
@@ -85,6 +119,7 @@ pub(crate) struct Bounce<'a> {
     pub position: Point3<f32>,
     pub normal: Vector3<f32>,
     pub texture: Point2<f32>,
+    pub footprint: Footprint,
     pub probability: f32,
     pub direct_light: Vec<DirectLight<'a>>,
 }
@@ -121,10 +156,50 @@ pub(crate) struct DirectLight<'a> {
     pub probability: f32,
 }
 
+/// The probability density of the directions of diffuse bounces, which are
+/// picked evenly over the hemisphere.
+const DIFFUSE_PDF: f32 = 1.0 / (2.0 * std::f32::consts::PI);
+
+/// The largest number of nested media that are tracked along a path.
+const MAX_MEDIA: usize = 4;
+
+/// The most cutouts that a shadow ray passes through before it's blocked.
+const MAX_CUTOUTS: usize = 16;
+
+/// Texture footprints are limited to this much stretching, for rays that
+/// graze a surface.
+const MIN_FOOTPRINT_COS: f32 = 0.01;
+
+/// Single wavelength paths with less throughput than this may be ended early.
+const ROULETTE_THRESHOLD: f32 = 0.1;
+
+/// Limits for ending paths early, at the cost of some bias.
+#[derive(Copy, Clone)]
+pub(crate) struct Cutoffs {
+    /// Paths with less throughput than this are ended.
+    pub throughput: f32,
+    /// Anything farther away than this along a ray, including the sky, is
+    /// ignored.
+    pub max_distance: f32,
+}
+
+/// A refractive material that a path has entered.
+#[derive(Copy, Clone)]
+struct Medium {
+    material: MaterialId,
+    priority: u32,
+    ior: f32,
+}
+
 #[derive(Clone)]
 pub struct Light {
     wavelength: f32,
     white: bool,
+    media: [Option<Medium>; MAX_MEDIA],
+    outside_ior: f32,
+    /// Set when fluorescence has moved the path to another wavelength than
+    /// the one it's sampled for.
+    shifted: bool,
 }
 
 impl Light {
@@ -132,6 +207,86 @@ impl Light {
         Light {
             wavelength: wavelength,
             white: true,
+            media: [None; MAX_MEDIA],
+            outside_ior: 1.0,
+            shifted: false,
+        }
+    }
+
+    /// The index of refraction on the other side of the current surface.
+    pub fn outside_ior(&self) -> f32 {
+        self.outside_ior
+    }
+
+    /// Prepares for hitting a surface of a refractive material, by finding
+    /// the medium on its other side. Returns `false` if the surface should be
+    /// ignored, because a medium with a higher priority is already there.
+    pub(crate) fn hit_medium(
+        &mut self,
+        material: MaterialId,
+        priority: u32,
+        entering: bool,
+        default_ior: f32,
+    ) -> bool {
+        let others = self
+            .media
+            .iter()
+            .flatten()
+            .filter(|medium| entering || medium.material != material);
+        let current = others.fold(None, |current: Option<&Medium>, medium| match current {
+            Some(current) if current.priority > medium.priority => Some(current),
+            _ => Some(medium),
+        });
+
+        if current.map_or(false, |current| current.priority > priority) {
+            // The path is still leaving the material, even if the surface
+            // is hidden.
+            if !entering {
+                self.leave_medium(material);
+            }
+            return false;
+        }
+
+        self.outside_ior = current.map_or(default_ior, |current| current.ior);
+        true
+    }
+
+    /// Keeps track of the media after the path has passed through a surface.
+    /// The oldest medium is forgotten if there are too many.
+    pub(crate) fn cross_medium(
+        &mut self,
+        material: MaterialId,
+        priority: u32,
+        ior: f32,
+        entering: bool,
+    ) {
+        if entering {
+            if self.media[MAX_MEDIA - 1].is_some() {
+                self.media.rotate_left(1);
+                self.media[MAX_MEDIA - 1] = None;
+            }
+
+            if let Some(slot) = self.media.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(Medium {
+                    material,
+                    priority,
+                    ior,
+                });
+            }
+        } else {
+            self.leave_medium(material);
+        }
+    }
+
+    fn leave_medium(&mut self, material: MaterialId) {
+        let index = self
+            .media
+            .iter()
+            .rposition(|medium| medium.map_or(false, |medium| medium.material == material));
+
+        if let Some(index) = index {
+            self.media[index..].rotate_left(1);
+            self.media[MAX_MEDIA - 1] = None;
         }
     }
 
@@ -143,35 +298,164 @@ impl Light {
     pub fn is_white(&self) -> bool {
         self.white
     }
+
+    /// Continues the path at a shorter wavelength, where the light was
+    /// absorbed by a fluorescent material.
+    pub fn fluoresce(&mut self, wavelength: f32) {
+        self.wavelength = wavelength;
+        self.white = false;
+        self.shifted = true;
+    }
+
+    /// The wavelength that the rest of the path is evaluated for, if
+    /// fluorescence has changed it.
+    pub fn shifted_wavelength(&self) -> Option<f32> {
+        if self.shifted {
+            Some(self.wavelength)
+        } else {
+            None
+        }
+    }
 }
 
+/// Checks if a path from the camera hit a surface, instead of going straight
+/// to the background.
+pub(crate) fn hits_surface(path: &[Bounce<'_>]) -> bool {
+    path.first()
+        .map_or(false, |bounce| bounce.position.x.is_finite())
+}
+
+/// Traces a path from `ray`. The `pixel_spread` is how much the ray widens
+/// per unit of distance, for picking the level of detail of textures, and
+/// it's 0 for paths that don't start from the camera.
 pub(crate) fn trace<'w, R: Rng>(
+    path: &mut Vec<Bounce<'w>>,
+    rng: &mut R,
+    ray: Ray3<f32>,
+    light: Light,
+    world: &'w World,
+    bounces: u32,
+    light_samples: usize,
+    cutoffs: Cutoffs,
+    pixel_spread: f32,
+    exe: &mut ExecutionContext<'w>,
+) {
+    let first_hit = world.intersect_within(ray, cutoffs.max_distance);
+    trace_from_hit(
+        path,
+        rng,
+        ray,
+        first_hit,
+        light,
+        world,
+        bounces,
+        light_samples,
+        cutoffs,
+        pixel_spread,
+        exe,
+    );
+}
+
+/// Same as `trace`, but starts from an already found `first_hit` for `ray`,
+/// such as from a packet of camera rays.
+pub(crate) fn trace_from_hit<'w, R: Rng>(
     path: &mut Vec<Bounce<'w>>,
     rng: &mut R,
     mut ray: Ray3<f32>,
+    first_hit: Option<Intersection<'w>>,
     mut light: Light,
     world: &'w World,
     bounces: u32,
     light_samples: usize,
+    cutoffs: Cutoffs,
+    pixel_spread: f32,
     exe: &mut ExecutionContext<'w>,
 ) {
     let mut sample_light = true;
+    let mut first_hit = Some(first_hit);
+    let mut max_distance = cutoffs.max_distance;
+
+    // The ray is treated as a cone that keeps widening along the whole path,
+    // which is a rough but cheap estimate of the footprint.
+    let mut path_length = 0.0;
+
+    // The throughput is only tracked after the path has been split into a
+    // single wavelength, or when there's a cutoff, and the roulette weight
+    // is applied to the next bounce after surviving.
+    let mut throughput: Option<f32> = None;
+    let mut roulette_weight = 1.0;
 
     for _ in 0..bounces {
-        match world.intersect(ray) {
+        let hit = match first_hit.take() {
+            Some(hit) => hit.filter(|hit| hit.distance <= max_distance),
+            None => world.intersect_within(ray, max_distance),
+        };
+        match hit {
             Some(intersection) => {
-                let material = intersection.surface_point.get_material();
+                let material_id = intersection.surface_point.get_material();
+                let material = world.materials.get(material_id);
                 let surface_data = intersection.surface_point.get_surface_data();
+                let position = intersection.surface_point.position;
+                let epsilon = world.ray_epsilon(position, intersection.distance);
+
+                path_length += intersection.distance;
+                let footprint = texture_footprint(
+                    ray.direction,
+                    surface_data.normal,
+                    path_length * pixel_spread * 0.5 * intersection.surface_point.texture_density(),
+                );
 
                 let normal_input = NormalInput {
                     incident: ray.direction,
                     normal: surface_data.normal.vector(),
                     texture: surface_data.texture,
+                    footprint,
+                    position,
                 };
-                let normal = material.apply_normal_map(surface_data.normal, normal_input, exe);
-                let position = intersection.surface_point.position;
 
-                match material.reflect(&mut light, ray, position, normal, rng) {
+                // Cutouts let rays through at random, where they are
+                // transparent.
+                if material.passes_through(&normal_input, rng, exe) {
+                    ray = offset_ray(
+                        Ray3::new(position, ray.direction),
+                        surface_data.normal.vector(),
+                        epsilon,
+                    );
+                    continue;
+                }
+
+                // Refractive materials are nested media, where the inside of
+                // the one with the highest priority wins.
+                let entering = ray.direction.dot(surface_data.normal.vector()) < 0.0;
+                let inside_ior = material.ior(&mut light);
+                if inside_ior.is_some()
+                    && !light.hit_medium(
+                        material_id,
+                        material.priority(),
+                        entering,
+                        world.medium_ior,
+                    )
+                {
+                    ray = offset_ray(
+                        Ray3::new(position, ray.direction),
+                        surface_data.normal.vector(),
+                        epsilon,
+                    );
+                    continue;
+                }
+
+                let normal = material.shading_normal(surface_data.normal, normal_input, exe);
+
+                match material.reflect(
+                    &mut light,
+                    ray,
+                    position,
+                    normal,
+                    surface_data.normal.tangent(),
+                    surface_data.texture,
+                    rng,
+                    exe,
+                ) {
                     Reflect(out_ray, color, prob, brdf) => {
                         let direct_light = if let Some(brdf) = brdf {
                             trace_direct(
@@ -181,8 +465,12 @@ pub(crate) fn trace<'w, R: Rng>(
                                 ray.direction,
                                 position,
                                 normal,
+                                surface_data.normal.vector(),
+                                material.cutoffs(cutoffs).max_distance,
                                 world,
+                                material_id,
                                 brdf,
+                                exe,
                             )
                         } else {
                             vec![]
@@ -204,11 +492,72 @@ pub(crate) fn trace<'w, R: Rng>(
                             position,
                             normal,
                             texture: surface_data.texture,
-                            probability: prob,
+                            footprint,
+                            probability: prob * roulette_weight,
                             direct_light,
                         };
+                        roulette_weight = 1.0;
+
+                        if let Some(ior) = inside_ior {
+                            let goes_inwards =
+                                out_ray.direction.dot(surface_data.normal.vector()) < 0.0;
+                            if goes_inwards == entering {
+                                light.cross_medium(material_id, material.priority(), ior, entering);
+                            }
+                        }
+
+                        // Mapped normals can send reflected light into the
+                        // surface, where it's absorbed instead of leaking
+                        // through to the other side.
+                        let geometric_normal = surface_data.normal.vector();
+                        let passes_through = out_ray.direction.dot(geometric_normal)
+                            * ray.direction.dot(geometric_normal)
+                            > 0.0;
+                        if inside_ior.is_none() && passes_through {
+                            path.push(bounce);
+                            break;
+                        }
+
+                        let material_cutoffs = material.cutoffs(cutoffs);
+                        max_distance = material_cutoffs.max_distance;
+
+                        // A single wavelength can't share the path with any
+                        // other wavelengths, so it's ended at random when
+                        // most of its light has been absorbed, instead of
+                        // being traced to the full depth.
+                        if throughput.is_some()
+                            || !light.is_white()
+                            || material_cutoffs.throughput > 0.0
+                        {
+                            let new_throughput = match throughput {
+                                Some(throughput) => {
+                                    throughput * bounce_throughput(&bounce, light.wavelength, exe)
+                                }
+                                None => path
+                                    .iter()
+                                    .chain(Some(&bounce))
+                                    .map(|bounce| bounce_throughput(bounce, light.wavelength, exe))
+                                    .product(),
+                            };
+
+                            if new_throughput < material_cutoffs.throughput {
+                                path.push(bounce);
+                                break;
+                            }
 
-                        ray = out_ray;
+                            if !light.is_white() && new_throughput < ROULETTE_THRESHOLD {
+                                let survival = new_throughput / ROULETTE_THRESHOLD;
+                                if rng.gen::<f32>() >= survival {
+                                    path.push(bounce);
+                                    break;
+                                }
+
+                                roulette_weight = 1.0 / survival;
+                            }
+                            throughput = Some(new_throughput);
+                        }
+
+                        ray = offset_ray(out_ray, geometric_normal, epsilon);
                         path.push(bounce);
                     }
                     Emit(color) => {
@@ -221,7 +570,8 @@ pub(crate) fn trace<'w, R: Rng>(
                                 position,
                                 normal,
                                 texture: surface_data.texture,
-                                probability: 1.0,
+                                footprint,
+                                probability: roulette_weight,
                                 direct_light: vec![],
                             });
                         }
@@ -230,13 +580,29 @@ pub(crate) fn trace<'w, R: Rng>(
                     }
                 }
             }
+            None if max_distance.is_finite() => break,
             None => {
                 let directional = if sample_light {
                     trace_directional(ray.direction, world)
                 } else {
                     None
                 };
-                let color = directional.unwrap_or_else(|| world.sky);
+
+                // Diffuse bounces also sample the sky, through its portals or
+                // environment map, so the two ways of finding it share the weight.
+                let sky_weight = match world.sky_pdf(ray.origin, ray.direction) {
+                    Some(pdf) if !sample_light => {
+                        DIFFUSE_PDF / (light_samples as f32 * pdf + DIFFUSE_PDF)
+                    }
+                    _ => 1.0,
+                };
+
+                // Directional lights are given as irradiance, which is spread
+                // over their solid angle.
+                let (color, probability) = match directional {
+                    Some((color, solid_angle)) => (color, roulette_weight / solid_angle),
+                    None => (world.sky, roulette_weight * sky_weight),
+                };
                 path.push(Bounce {
                     ty: BounceType::Emission,
                     light,
@@ -245,7 +611,8 @@ pub(crate) fn trace<'w, R: Rng>(
                     position: Point3::from_vec(&ray.direction * std::f32::INFINITY),
                     normal: -ray.direction,
                     texture: Point2::origin(),
-                    probability: 1.0,
+                    footprint: Footprint::default(),
+                    probability,
                     direct_light: vec![],
                 });
 
@@ -255,6 +622,86 @@ pub(crate) fn trace<'w, R: Rng>(
     }
 }
 
+/// Checks if a shadow ray hits a surface before `max_distance`, that it
+/// doesn't pass through. Any such surface will do, so it doesn't look for the
+/// closest one, and it's blocked if it passes through too many cutouts.
+fn is_shadowed<'w>(
+    world: &'w World,
+    ray: Ray3<f32>,
+    max_distance: f32,
+    rng: &mut impl Rng,
+    exe: &mut ExecutionContext<'w>,
+) -> bool {
+    let mut cutouts = 0;
+
+    world.intersect_any(ray, max_distance, |intersection, material| {
+        if material.is_opaque() {
+            return true;
+        }
+
+        let surface_data = intersection.surface_point.get_surface_data();
+        let input = NormalInput {
+            incident: ray.direction,
+            normal: surface_data.normal.vector(),
+            texture: surface_data.texture,
+            footprint: Footprint::default(),
+            position: intersection.surface_point.position,
+        };
+
+        cutouts += 1;
+        cutouts == MAX_CUTOUTS || !material.passes_through(&input, rng, exe)
+    })
+}
+
+/// The part of a texture that is covered by a ray cone with `radius`, in
+/// texture coordinate units, where it hits a surface from `direction`. The
+/// circle is stretched along the direction that the surface is tilted away
+/// from the ray.
+fn texture_footprint(direction: Vector3<f32>, normal: Normal, radius: f32) -> Footprint {
+    if !(radius > 0.0 && radius.is_finite()) {
+        return Footprint::default();
+    }
+
+    let local = normal.into_space(direction);
+    let cos = local.z.abs().max(MIN_FOOTPRINT_COS);
+    let tilt = local.truncate();
+    let tilt = if tilt.magnitude2() > 0.0 {
+        tilt.normalize()
+    } else {
+        Vector2::unit_x()
+    };
+
+    Footprint {
+        major: tilt * (radius / cos),
+        minor: Vector2::new(-tilt.y, tilt.x) * radius,
+    }
+}
+
+/// The fraction of the light at `wavelength` that a bounce passes on.
+fn bounce_throughput<'w>(
+    bounce: &Bounce<'w>,
+    wavelength: f32,
+    exe: &mut ExecutionContext<'w>,
+) -> f32 {
+    let context = RenderContext {
+        wavelengths: std::iter::once(wavelength).collect(),
+        incident: bounce.incident,
+        normal: bounce.normal,
+        texture: bounce.texture,
+        footprint: bounce.footprint,
+        position: bounce.position,
+    };
+
+    exe.run(bounce.color, &context).values[0]
+        * bounce.probability
+        * bounce.ty.brdf(bounce.incident, bounce.normal)
+}
+
+/// Samples the lights that are visible from `position`. One of the lights
+/// is picked for all of the samples, and each distant light gets its own
+/// samples, since there's usually only the sun. Lights that the material
+/// isn't linked to, or that are farther away than `max_distance`, are
+/// skipped.
 fn trace_direct<'w, R: Rng>(
     rng: &mut R,
     samples: usize,
@@ -262,94 +709,153 @@ fn trace_direct<'w, R: Rng>(
     ray_in: Vector3<f32>,
     position: Point3<f32>,
     normal: Vector3<f32>,
+    geometric_normal: Vector3<f32>,
+    max_distance: f32,
     world: &'w World,
+    material_id: MaterialId,
     brdf: Brdf,
+    exe: &mut ExecutionContext<'w>,
 ) -> Vec<DirectLight<'w>> {
-    if let Some((lamp, probability)) = world.pick_lamp(rng) {
-        let normal = if ray_in.dot(normal) < 0.0 {
-            normal
-        } else {
-            -normal
-        };
-
+    let material = world.materials.get(material_id);
+    let normal = if ray_in.dot(normal) < 0.0 {
+        normal
+    } else {
+        -normal
+    };
+    let geometric_normal = if ray_in.dot(geometric_normal) < 0.0 {
+        geometric_normal
+    } else {
+        -geometric_normal
+    };
+
+    let picked = world.pick_lamp_towards(rng, position, normal, material_id);
+    let distant = world
+        .distant_lights
+        .iter()
+        .filter(|lamp| material.is_lit_by(lamp.link(&world.materials)))
+        .map(|lamp| (lamp, 1.0));
+
+    let mut direct_light = Vec::new();
+    for (lamp, probability) in picked.into_iter().chain(distant) {
         let probability = 1.0 / (samples as f32 * 2.0 * std::f32::consts::PI * probability);
 
-        (0..samples)
-            .filter_map(|_| {
-                let lamp::Sample {
-                    direction,
-                    sq_distance,
-                    surface,
-                    weight,
-                } = lamp.sample(rng, position);
-
-                let mut light = light.clone();
-
-                let ray_out = Ray3::new(position, direction);
-
-                let cos_out = normal.dot(ray_out.direction).max(0.0);
-
-                if cos_out > 0.0 {
-                    let hit_dist = world
-                        .intersect(ray_out)
-                        .map(|hit| hit.distance * hit.distance);
-
-                    let blocked = match (hit_dist, sq_distance) {
-                        (Some(hit), Some(lamp)) if hit >= lamp - DIST_EPSILON => false,
-                        (None, _) => false,
-                        _ => true,
-                    };
-
-                    if !blocked {
-                        let (color, target_normal) = match surface {
-                            lamp::Surface::Physical {
-                                normal: target_normal,
-                                material,
-                                ..
-                            } => {
-                                let color = material.get_emission(
-                                    &mut light,
-                                    ray_out.direction,
-                                    target_normal,
-                                    rng,
-                                );
-                                (color, target_normal)
-                            }
-                            lamp::Surface::Color(color) => {
-                                let target_normal = -ray_out.direction;
-                                (Some(color), target_normal)
-                            }
-                        };
-                        let scale = weight * probability * brdf(ray_in, normal, ray_out.direction);
+        for _ in 0..samples {
+            let lamp::Sample {
+                direction,
+                sq_distance,
+                surface,
+                weight,
+            } = lamp.sample(rng, position);
 
-                        return color.map(|color| DirectLight {
-                            light,
-                            color,
-                            incident: ray_out.direction,
-                            normal: target_normal,
-                            probability: scale,
-                        });
-                    }
+            let mut light = light.clone();
+
+            let ray_out = Ray3::new(position, direction);
+
+            let cos_out = normal.dot(ray_out.direction).max(0.0);
+            let above_surface = geometric_normal.dot(ray_out.direction) > 0.0;
+
+            if cos_out <= 0.0 || !above_surface {
+                continue;
+            }
+
+            // Distant lights are beyond any cutoff, like the sky.
+            let shadow_distance = match sq_distance.map(f32::sqrt) {
+                Some(lamp) if lamp <= max_distance => lamp - world.ray_epsilon(position, lamp),
+                Some(_) => continue,
+                None if max_distance.is_finite() => continue,
+                None => f32::INFINITY,
+            };
+
+            if is_shadowed(world, ray_out, shadow_distance, rng, exe) {
+                continue;
+            }
+
+            let (color, target_normal) = match surface {
+                lamp::Surface::Physical {
+                    position: target_position,
+                    normal: target_normal,
+                    material,
+                    texture,
+                } => {
+                    let color = world.materials.get(material).get_emission(
+                        &mut light,
+                        ray_out.direction,
+                        target_position,
+                        target_normal,
+                        texture,
+                        rng,
+                        exe,
+                    );
+                    (color, target_normal)
+                }
+                lamp::Surface::Color(color) => {
+                    let target_normal = -ray_out.direction;
+                    (Some(color), target_normal)
                 }
+            };
+            let scale = weight * probability * brdf(ray_in, normal, ray_out.direction);
 
-                None
-            })
-            .collect()
-    } else {
-        vec![]
+            if let Some(color) = color {
+                direct_light.push(DirectLight {
+                    light,
+                    color,
+                    incident: ray_out.direction,
+                    normal: target_normal,
+                    probability: scale,
+                });
+            }
+        }
+    }
+
+    if max_distance.is_finite() {
+        return direct_light;
     }
+
+    for _ in 0..samples {
+        let (direction, pdf) = match world.sample_sky(rng, position) {
+            Some(sample) => sample,
+            None => break,
+        };
+
+        if normal.dot(direction) <= 0.0 || geometric_normal.dot(direction) <= 0.0 {
+            continue;
+        }
+
+        let ray_out = Ray3::new(position, direction);
+        if is_shadowed(world, ray_out, f32::INFINITY, rng, exe) {
+            continue;
+        }
+
+        // Weighted against finding the sky by reflecting off of the
+        // surface, which happens with the diffuse probability.
+        let weight = 1.0 / (samples as f32 * pdf + DIFFUSE_PDF);
+        let scale = weight * brdf(ray_in, normal, direction) / (2.0 * std::f32::consts::PI);
+
+        direct_light.push(DirectLight {
+            light: light.clone(),
+            color: world.sky,
+            incident: direction,
+            normal: -direction,
+            probability: scale,
+        });
+    }
+
+    direct_light
 }
 
-fn trace_directional<'w>(ray: Vector3<f32>, world: &'w World) -> Option<LightProgram<'w>> {
-    for light in &world.lights {
+/// Finds the directional light that a ray from the sky hits, if any, and the
+/// solid angle it covers. Single directions can't be hit.
+fn trace_directional<'w>(ray: Vector3<f32>, world: &'w World) -> Option<(LightProgram<'w>, f32)> {
+    for light in &world.distant_lights {
         if let &Lamp::Directional {
             direction,
-            width,
+            cos_half,
             color,
+            ..
         } = light
         {
-            if direction.dot(ray) >= width {
-                return Some(color);
+            if cos_half < 1.0 && direction.dot(ray) >= cos_half {
+                return Some((color, solid_angle(cos_half)));
             }
         }
     }
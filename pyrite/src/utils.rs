@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::mem::transmute;
 use std::cmp::PartialOrd;
 use std::ops::{Range, Sub, Add};
@@ -48,3 +49,98 @@ impl<N> Iterator for BatchRange<N> where
         }
     }
 }
+
+/// Quotes and escapes a string for writing it into a JSON file.
+pub fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+/// Splits the authority part of a URL, like `example.com:8080` or
+/// `[::1]:8080`, into a host name and a port. IPv6 addresses have to be in
+/// brackets, which are removed from the host name. The port is optional if
+/// there's a `default_port`.
+pub fn split_authority(
+    authority: &str,
+    default_port: Option<u16>,
+) -> Result<(String, u16), Box<dyn Error>> {
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority
+            .find(']')
+            .ok_or_else(|| format!("missing ']' in '{}'", authority))?;
+        let rest = &authority[end + 1..];
+        let port = if rest.is_empty() {
+            None
+        } else if rest.starts_with(':') {
+            Some(&rest[1..])
+        } else {
+            return Err(format!("unexpected '{}' after the address", rest).into());
+        };
+
+        (&authority[1..end], port)
+    } else {
+        match authority.rfind(':') {
+            Some(index) => (&authority[..index], Some(&authority[index + 1..])),
+            None => (authority, None),
+        }
+    };
+
+    let port = match (port, default_port) {
+        (Some(port), _) => port
+            .parse()
+            .map_err(|_| format!("invalid port: '{}'", port))?,
+        (None, Some(port)) => port,
+        (None, None) => return Err(format!("missing port in '{}'", authority).into()),
+    };
+
+    if host.is_empty() {
+        return Err(format!("missing host name in '{}'", authority).into());
+    }
+
+    Ok((host.to_owned(), port))
+}
+
+/// Formats a host name and port for a URL or an HTTP header, with IPv6
+/// addresses in brackets.
+pub fn join_authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_authority;
+
+    #[test]
+    fn split_ipv6_authority() {
+        assert_eq!(
+            split_authority("[::1]:8080", None).unwrap(),
+            ("::1".to_owned(), 8080)
+        );
+        assert_eq!(
+            split_authority("[::1]", Some(80)).unwrap(),
+            ("::1".to_owned(), 80)
+        );
+        assert_eq!(
+            split_authority("example.com:7878", None).unwrap(),
+            ("example.com".to_owned(), 7878)
+        );
+        assert!(split_authority("[::1]", None).is_err());
+        assert!(split_authority("[::1", Some(80)).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Voxel grids, for 3D textures that are looked up by position. They are
+//! either raw files, with only the voxel values, or grid volumes in the
+//! `.vol` format from Mitsuba.
+//!
+//! A `.vol` file starts with the bytes "VOL" and the version 3, followed by
+//! the encoding, the resolution along X, Y and Z, and the number of
+//! channels, as `i32`. Then comes the bounding box, as the smallest and
+//! largest X, Y and Z, and the voxel values, as `f32`. The voxels are in the
+//! same order in both kinds of files, with X changing the fastest, then Y and
+//! then Z, and each value is a number of channels in a row.
+
+use std::{error::Error, path::Path};
+
+use cgmath::{ElementWise, EuclideanSpace, Point3, Vector3};
+use collision::Aabb3;
+
+/// The `.vol` encoding for 32 bit floats, which is the only one that is
+/// supported.
+const VOL_FLOAT32: i32 = 1;
+const VOL_HEADER_SIZE: usize = 48;
+
+/// One value per voxel, with the channels of the file averaged together.
+pub struct Volume {
+    resolution: [usize; 3],
+    /// Where the grid is placed, if the file says it.
+    bounds: Option<Aabb3<f32>>,
+    data: Vec<f32>,
+}
+
+impl Volume {
+    pub fn from_path(path: &Path, raw: Option<RawVolume>) -> Result<Volume, Box<dyn Error>> {
+        let data = std::fs::read(path)?;
+
+        match raw {
+            Some(raw) => Volume::from_raw(&data, raw),
+            None => Volume::from_vol(&data),
+        }
+    }
+
+    fn from_raw(data: &[u8], raw: RawVolume) -> Result<Volume, Box<dyn Error>> {
+        let expected = data_size(raw.resolution, raw.format.size())?;
+        if data.len() != expected {
+            return Err(format!(
+                "expected {} bytes for {}x{}x{} voxels, but found {}",
+                expected,
+                raw.resolution[0],
+                raw.resolution[1],
+                raw.resolution[2],
+                data.len()
+            )
+            .into());
+        }
+
+        let data = match raw.format {
+            RawFormat::U8 => data.iter().map(|&value| value as f32 / 255.0).collect(),
+            RawFormat::F32 => data.chunks(4).map(read_f32).collect(),
+        };
+
+        Ok(Volume {
+            resolution: raw.resolution,
+            bounds: None,
+            data,
+        })
+    }
+
+    fn from_vol(data: &[u8]) -> Result<Volume, Box<dyn Error>> {
+        if data.len() < VOL_HEADER_SIZE || &data[..3] != b"VOL" {
+            return Err("not a .vol file".into());
+        }
+        if data[3] != 3 {
+            return Err(format!("unsupported .vol version: {}", data[3]).into());
+        }
+
+        let header: Vec<i32> = data[4..24]
+            .chunks(4)
+            .map(|bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let (encoding, channels) = (header[0], header[4]);
+        if encoding != VOL_FLOAT32 {
+            return Err(format!(
+                "unsupported .vol encoding: {}, only 32 bit floats are supported",
+                encoding
+            )
+            .into());
+        }
+        if header[1..].iter().any(|&value| value <= 0) {
+            return Err("the resolution and channels must be positive".into());
+        }
+
+        let resolution = [header[1] as usize, header[2] as usize, header[3] as usize];
+        let channels = channels as usize;
+
+        let corners: Vec<f32> = data[24..VOL_HEADER_SIZE].chunks(4).map(read_f32).collect();
+        let bounds = Aabb3::new(
+            Point3::new(corners[0], corners[1], corners[2]),
+            Point3::new(corners[3], corners[4], corners[5]),
+        );
+
+        let values = &data[VOL_HEADER_SIZE..];
+        let expected = data_size(resolution, channels * 4)?;
+        if values.len() != expected {
+            return Err(format!(
+                "expected {} bytes of voxels, but found {}",
+                expected,
+                values.len()
+            )
+            .into());
+        }
+
+        let data = values
+            .chunks(channels * 4)
+            .map(|voxel| voxel.chunks(4).map(read_f32).sum::<f32>() / channels as f32)
+            .collect();
+
+        Ok(Volume {
+            resolution,
+            bounds: Some(bounds),
+            data,
+        })
+    }
+
+    /// The bounds from the file, or a unit cube at the origin.
+    pub fn bounds(&self) -> Aabb3<f32> {
+        self.bounds
+            .unwrap_or_else(|| Aabb3::new(Point3::origin(), Point3::new(1.0, 1.0, 1.0)))
+    }
+
+    /// Looks up the value at `position`, with trilinear interpolation, when
+    /// the grid is placed within `bounds`. It's 0 outside of the bounds.
+    pub fn get(&self, position: Point3<f32>, bounds: Aabb3<f32>) -> f32 {
+        let size = bounds.max - bounds.min;
+        let relative = (position - bounds.min).div_element_wise(size);
+        if !(0.0..=1.0).contains(&relative.x)
+            || !(0.0..=1.0).contains(&relative.y)
+            || !(0.0..=1.0).contains(&relative.z)
+        {
+            return 0.0;
+        }
+
+        let [width, height, depth] = self.resolution;
+        let voxel =
+            relative.mul_element_wise(Vector3::new(width as f32, height as f32, depth as f32))
+                - Vector3::new(0.5, 0.5, 0.5);
+        let first = voxel.map(f32::floor);
+        let fraction = voxel - first;
+
+        let index = |first: f32, offset: usize, size: usize| {
+            ((first.max(0.0) as usize) + offset).min(size - 1)
+        };
+        let value_at = |x_offset, y_offset, z_offset| {
+            let x = index(first.x, x_offset, width);
+            let y = index(first.y, y_offset, height);
+            let z = index(first.z, z_offset, depth);
+            self.data[x + (y + z * height) * width]
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        // Positions before the first voxel center use it for both sides.
+        let fraction = Vector3::new(
+            if voxel.x < 0.0 { 0.0 } else { fraction.x },
+            if voxel.y < 0.0 { 0.0 } else { fraction.y },
+            if voxel.z < 0.0 { 0.0 } else { fraction.z },
+        );
+
+        lerp(
+            lerp(
+                lerp(value_at(0, 0, 0), value_at(1, 0, 0), fraction.x),
+                lerp(value_at(0, 1, 0), value_at(1, 1, 0), fraction.x),
+                fraction.y,
+            ),
+            lerp(
+                lerp(value_at(0, 0, 1), value_at(1, 0, 1), fraction.x),
+                lerp(value_at(0, 1, 1), value_at(1, 1, 1), fraction.x),
+                fraction.y,
+            ),
+            fraction.z,
+        )
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.data.capacity() * std::mem::size_of::<f32>()
+    }
+}
+
+/// The number of bytes in a grid with `resolution` voxels of `voxel_size`
+/// bytes.
+fn data_size(resolution: [usize; 3], voxel_size: usize) -> Result<usize, Box<dyn Error>> {
+    resolution
+        .iter()
+        .try_fold(voxel_size, |size, &length| size.checked_mul(length))
+        .ok_or_else(|| {
+            format!(
+                "{}x{}x{} voxels are too many",
+                resolution[0], resolution[1], resolution[2]
+            )
+            .into()
+        })
+}
+
+/// How to read a raw file, which doesn't describe itself.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RawVolume {
+    pub resolution: [usize; 3],
+    pub format: RawFormat,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RawFormat {
+    /// Bytes, where 255 is 1.
+    U8,
+    /// Little endian 32 bit floats.
+    F32,
+}
+
+impl RawFormat {
+    fn size(self) -> usize {
+        match self {
+            RawFormat::U8 => 1,
+            RawFormat::F32 => 4,
+        }
+    }
+}
+
+fn read_f32(bytes: &[u8]) -> f32 {
+    f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawFormat, RawVolume, Volume};
+
+    #[test]
+    fn huge_raw_volumes_are_rejected() {
+        let raw = RawVolume {
+            resolution: [1 << 30, 1 << 30, 1 << 30],
+            format: RawFormat::F32,
+        };
+        assert!(Volume::from_raw(&[], raw).is_err());
+    }
+}
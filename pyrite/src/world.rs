@@ -1,4 +1,4 @@
-use std::{error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, sync::Arc};
 
 use rand::Rng;
 
@@ -6,361 +6,2511 @@ use genmesh;
 use obj;
 
 use cgmath::{
-    EuclideanSpace, InnerSpace, Matrix3, Matrix4, Point2, SquareMatrix, Vector2, Vector3,
+    ElementWise, EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point2, Point3,
+    SquareMatrix, Transform as _, Vector2, Vector3,
 };
-use collision::Ray3;
+use collision::{Aabb3, Ray3};
 
 use crate::{
-    lamp::Lamp,
-    materials::Material,
-    math::DIST_EPSILON,
+    color::{Wavelengths, LANES},
+    lamp::{Lamp, Portal, SkySampler},
+    materials::{Material, MaterialId, Materials},
+    math,
     project::{
         eval_context::{EvalContext, Evaluate, EvaluateOr},
-        expressions::{Expression, Expressions},
-        meshes::Meshes,
-        program::ProgramCompiler,
-        WorldObject,
+        expressions::{ComplexExpression, Expression, ExpressionSnapshot, Expressions},
+        meshes::{HairId, MeshId, Meshes, PointsId, Positions},
+        program::{ExecutionContext, ProgramCompiler, Resources},
+        CsgOperation, Medium, Solid, Transform, WorldObject,
     },
     shapes::{
-        distance_estimators::QuatMul, BoundingVolume, Intersection, Normal, Plane, Shape, Triangle,
-        Vertex,
+        curve::Curve,
+        displacement::{self, MeshVertex},
+        distance_estimators::{DistanceField, Mandelbulb, QuatMul, QuaternionJulia},
+        point_cloud::{PointCloud, PointShape},
+        BoundingVolume, Csg, Disk, Intersection, Normal, Plane, Primitive, PrimitiveId, Prototype,
+        RayMarched, Rectangle, Shape, Sphere, Torus, Triangle, Vertex,
     },
-    spatial::bvh::Bvh,
-    tracer::{LightProgram, ParametricValue},
+    spatial::{
+        bvh::{Bounded, Bvh, RayPacket},
+        light_tree::{LightBounds, LightTree, Orientation},
+    },
+    texture::Footprint,
+    tracer::{LightProgram, ParametricValue, RenderContext},
 };
 
-pub(crate) struct World<'p> {
-    pub sky: LightProgram<'p>,
-    pub lights: Vec<Lamp<'p>>,
-    pub planes: Vec<Plane<'p>>,
-    pub finite_objects: Bvh<&'p Shape<'p>>,
+pub(crate) struct World<'p> {
+    pub sky: LightProgram<'p>,
+    /// The lights that `pick_lamp` chooses from.
+    pub lights: Vec<Lamp<'p>>,
+    /// Picks lights that are likely to light a surface, for `pick_lamp_towards`.
+    light_tree: LightTree,
+    /// Light trees with only the lights that light each material with light
+    /// links.
+    linked_light_trees: HashMap<MaterialId, LightTree>,
+    /// Directional lights, which are always sampled, since they are usually
+    /// the sun.
+    pub distant_lights: Vec<Lamp<'p>>,
+    /// Samples the sky like a light, if it has an environment map.
+    pub sky_sampler: Option<SkySampler>,
+    /// Openings that the sky is sampled through, instead of the sampler.
+    pub portals: Vec<Portal>,
+    pub planes: Vec<(PrimitiveId, Plane)>,
+    pub finite_objects: Bvh<WorldShape<'p>>,
+    pub materials: Materials<'p>,
+    pub statistics: Statistics,
+    /// The index of refraction outside of all objects.
+    pub medium_ior: f32,
+    /// The length of one unit, in meters.
+    scale: f32,
+    geometry: WorldGeometry,
+}
+
+/// Numbers that describe the size and complexity of a built scene.
+#[derive(Default)]
+pub(crate) struct Statistics {
+    pub triangles: usize,
+    pub shapes: usize,
+    pub planes: usize,
+    pub mesh_instances: usize,
+    pub curves: usize,
+    pub points: usize,
+    pub lights: usize,
+    pub bvh_nodes: usize,
+    pub bvh_memory: usize,
+    pub shape_memory: usize,
+    pub instances: usize,
+    pub instanced_triangles: usize,
+}
+
+impl<'p> World<'p> {
+    pub fn from_project(
+        project: crate::project::World,
+        eval_context: EvalContext,
+        programs: ProgramCompiler<'p>,
+        expressions: &Expressions,
+        meshes: &Meshes,
+        resources: Resources<'p>,
+        allocator: &'p bumpalo::Bump,
+    ) -> Result<Self, Box<dyn Error>> {
+        let geometry = evaluate_world_geometry(&project, eval_context)?;
+        let (medium_ior, scale) = evaluate_units(&project)?;
+        let sky_sampler = sky_sampler(project.sky.as_ref(), expressions, resources);
+        let program_resources = ProgramResources {
+            programs,
+            expressions,
+            resources,
+        };
+        let Shading {
+            sky,
+            mut materials,
+            mut lights,
+            distant_lights,
+            portals,
+            object_materials,
+            prototype_materials,
+        } = Shading::from_project(project, eval_context, programs, expressions, meshes)?;
+
+        let mut objects: Vec<WorldShape> = Vec::new();
+        let mut shapes = Vec::new();
+        let mut planes = Vec::new();
+        let mut world_planes = Vec::new();
+        let mut statistics = Statistics::default();
+
+        // The prototypes are built first, with their own BVHs, and shared
+        // between their instances.
+        let mut prototypes = HashMap::new();
+        let mut prototype_statistics = Statistics::default();
+        for ((name, object), object_materials) in
+            geometry.prototypes.iter().zip(prototype_materials)
+        {
+            build_shapes(
+                object,
+                &object_materials,
+                &materials,
+                meshes,
+                program_resources,
+                &prototypes,
+                &mut prototype_statistics,
+                &mut shapes,
+                &mut planes,
+            )
+            .map_err(|error| format!("prototypes.{}: {}", name, error))?;
+
+            if shapes
+                .iter()
+                .any(|shape| materials.get(shape.material()).is_emissive())
+            {
+                println!(
+                    "warning: emissive shapes in prototype '{}' can't be sampled as lights",
+                    name
+                );
+            }
+
+            statistics.shape_memory += shapes.len() * std::mem::size_of::<Shape>();
+            let prototype = Prototype::new(std::mem::take(&mut shapes));
+            statistics.bvh_nodes += prototype.bvh().node_count();
+            statistics.bvh_memory += prototype.bvh().memory_size();
+            prototypes.insert(name.clone(), Arc::new(prototype));
+        }
+        statistics.instanced_triangles = prototype_statistics.triangles;
+
+        for (object_index, (object, object_materials)) in
+            geometry.objects.iter().zip(object_materials).enumerate()
+        {
+            build_shapes(
+                object,
+                &object_materials,
+                &materials,
+                meshes,
+                program_resources,
+                &prototypes,
+                &mut statistics,
+                &mut shapes,
+                &mut planes,
+            )?;
+
+            let id = |primitive: usize| PrimitiveId {
+                object: object_index as u32,
+                primitive: primitive as u32,
+            };
+            objects.extend(
+                shapes
+                    .drain(..)
+                    .enumerate()
+                    .map(|(primitive, shape)| WorldShape {
+                        shape: allocator.alloc(shape),
+                        id: id(primitive),
+                    }),
+            );
+            world_planes.extend(
+                planes
+                    .drain(..)
+                    .enumerate()
+                    .map(|(primitive, plane)| (id(primitive), plane)),
+            );
+        }
+        let planes = world_planes;
+
+        convert_emission_units(
+            objects.iter().map(|object| object.shape),
+            &mut materials,
+            scale,
+            programs,
+            resources,
+        )?;
+        lights.extend(emissive_shapes(
+            objects.iter().map(|object| object.shape),
+            &materials,
+        ));
+        let (light_tree, linked_light_trees) = light_trees(&lights, &materials, resources);
+
+        println!(
+            "the scene contains {} objects",
+            planes.len() + objects.len()
+        );
+        statistics.shapes = objects.len() - statistics.triangles - statistics.curves;
+        statistics.planes = planes.len();
+        statistics.lights = lights.len() + distant_lights.len();
+        statistics.shape_memory += objects.len() * std::mem::size_of::<Shape>()
+            + planes.len() * std::mem::size_of::<Plane>();
+
+        println!("building BVH... ");
+        let tree = Bvh::new(objects);
+        println!("done building BVH");
+
+        statistics.bvh_nodes += tree.node_count();
+        statistics.bvh_memory += tree.memory_size();
+
+        Ok(World {
+            sky,
+            lights,
+            light_tree,
+            linked_light_trees,
+            distant_lights,
+            sky_sampler,
+            portals,
+            planes,
+            finite_objects: tree,
+            materials,
+            statistics,
+            medium_ior,
+            scale,
+            geometry,
+        })
+    }
+
+    /// Checks if a reloaded project describes the same geometry as this
+    /// world, meaning that only its shading needs to be replaced.
+    pub fn has_same_geometry(
+        &self,
+        project: &crate::project::World,
+        eval_context: EvalContext,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(evaluate_world_geometry(project, eval_context)? == self.geometry)
+    }
+
+    /// Replaces the materials, lights and sky, while keeping the shapes and
+    /// the BVH. The project is expected to have the same geometry as before.
+    pub fn reload_shading(
+        &mut self,
+        project: crate::project::World,
+        eval_context: EvalContext,
+        programs: ProgramCompiler<'p>,
+        expressions: &Expressions,
+        meshes: &Meshes,
+        resources: Resources<'p>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (medium_ior, scale) = evaluate_units(&project)?;
+        let sky_sampler = sky_sampler(project.sky.as_ref(), expressions, resources);
+        let Shading {
+            sky,
+            mut materials,
+            mut lights,
+            distant_lights,
+            portals,
+            ..
+        } = Shading::from_project(project, eval_context, programs, expressions, meshes)?;
+
+        convert_emission_units(
+            self.finite_objects.items().map(|object| object.shape),
+            &mut materials,
+            scale,
+            programs,
+            resources,
+        )?;
+
+        lights.extend(emissive_shapes(
+            self.finite_objects.items().map(|object| object.shape),
+            &materials,
+        ));
+
+        self.sky = sky;
+        self.materials = materials;
+        self.medium_ior = medium_ior;
+        self.scale = scale;
+        self.statistics.lights = lights.len() + distant_lights.len();
+        let (light_tree, linked_light_trees) = light_trees(&lights, &self.materials, resources);
+        self.light_tree = light_tree;
+        self.linked_light_trees = linked_light_trees;
+        self.lights = lights;
+        self.distant_lights = distant_lights;
+        self.sky_sampler = sky_sampler;
+        self.portals = portals;
+
+        Ok(())
+    }
+
+    pub fn intersect(&self, ray: Ray3<f32>) -> Option<Intersection> {
+        self.intersect_within(ray, f32::INFINITY)
+    }
+
+    /// Same as `intersect`, but ignores anything farther away than
+    /// `max_distance`.
+    pub fn intersect_within(&self, ray: Ray3<f32>, max_distance: f32) -> Option<Intersection> {
+        let min_distance = self.ray_epsilon(ray.origin, 0.0);
+        let result = self.intersect_planes(ray, min_distance, max_distance);
+        let closest_distance = result
+            .as_ref()
+            .map_or(max_distance, |intersection| intersection.distance);
+
+        intersect_shapes(
+            &self.finite_objects,
+            ray,
+            min_distance,
+            closest_distance,
+            |intersection| self.is_visible_side(intersection, &ray),
+        )
+        .or(result)
+    }
+
+    /// Checks if the ray hits anything before `max_distance` that `blocks`
+    /// accepts, given the surface's material. It stops at the first such hit,
+    /// in any order, instead of looking for the closest one, which is all that
+    /// shadow rays need.
+    pub fn intersect_any(
+        &self,
+        ray: Ray3<f32>,
+        max_distance: f32,
+        mut blocks: impl FnMut(&Intersection, &Material<'p>) -> bool,
+    ) -> bool {
+        let min_distance = self.ray_epsilon(ray.origin, 0.0);
+        let mut hits = |intersection: &Intersection| {
+            if intersection.distance <= min_distance {
+                return false;
+            }
+
+            let material = self
+                .materials
+                .get(intersection.surface_point.get_material());
+            is_visible_side(material, intersection, &ray) && blocks(intersection, material)
+        };
+
+        for &(id, ref plane) in &self.planes {
+            if let Some(mut intersection) = plane.ray_intersect(&ray) {
+                intersection.id = id;
+                if intersection.distance < max_distance && hits(&intersection) {
+                    return true;
+                }
+            }
+        }
+
+        let mut objects = self.finite_objects.ray_intersect(ray);
+        while let Some(&object) = objects.next(max_distance) {
+            // The far side of a closed shape may still block the ray, if its
+            // near side is culled or lets the ray through.
+            let mut shape_ray = ray;
+            let mut traveled = 0.0;
+            while let Some(mut intersection) = object.shape.ray_intersect(&shape_ray) {
+                intersection.id = object.id;
+                intersection.distance += traveled;
+                if intersection.distance >= max_distance {
+                    break;
+                }
+
+                if hits(&intersection) {
+                    return true;
+                }
+
+                let position = intersection.surface_point.position;
+                let epsilon = self.ray_epsilon(position, intersection.distance);
+                traveled = intersection.distance + epsilon;
+                shape_ray = Ray3::new(position + ray.direction * epsilon, ray.direction);
+            }
+        }
+
+        false
+    }
+
+    /// Finds the closest hit for each ray in the packet, like `intersect`,
+    /// but with a single pass through the BVH for all of them.
+    pub fn intersect_packet(&self, packet: &RayPacket) -> Vec<Option<Intersection>> {
+        let rays = packet.rays();
+        let min_distances: Vec<f32> = rays
+            .iter()
+            .map(|ray| self.ray_epsilon(ray.origin, 0.0))
+            .collect();
+        let mut results: Vec<Option<Intersection>> = rays
+            .iter()
+            .zip(&min_distances)
+            .map(|(&ray, &min_distance)| self.intersect_planes(ray, min_distance, f32::INFINITY))
+            .collect();
+        let mut closest_distances: Vec<f32> = results
+            .iter()
+            .map(|result| {
+                result
+                    .as_ref()
+                    .map_or(f32::INFINITY, |intersection| intersection.distance)
+            })
+            .collect();
+
+        self.finite_objects.packet_intersect(
+            packet,
+            &mut closest_distances,
+            |object, hits, closest_distances| {
+                for &index in hits {
+                    let ray = rays[index];
+                    if let Some(mut intersection) = object.shape.ray_intersect(&ray) {
+                        intersection.id = object.id;
+                        if intersection.distance > min_distances[index]
+                            && intersection.distance < closest_distances[index]
+                            && self.is_visible_side(&intersection, &ray)
+                        {
+                            closest_distances[index] = intersection.distance;
+                            results[index] = Some(intersection);
+                        }
+                    }
+                }
+            },
+        );
+
+        results
+    }
+
+    fn intersect_planes(
+        &self,
+        ray: Ray3<f32>,
+        min_distance: f32,
+        max_distance: f32,
+    ) -> Option<Intersection> {
+        let mut result = None;
+        let mut closest_distance = max_distance;
+
+        for &(id, ref plane) in &self.planes {
+            if let Some(mut intersection) = plane.ray_intersect(&ray) {
+                intersection.id = id;
+                if intersection.distance > min_distance
+                    && intersection.distance < closest_distance
+                    && self.is_visible_side(&intersection, &ray)
+                {
+                    closest_distance = intersection.distance;
+                    result = Some(intersection);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// How far a ray has to travel from a hit at `position`, that was
+    /// `distance` away from the previous ray's origin, before it can hit
+    /// anything.
+    pub fn ray_epsilon(&self, position: Point3<f32>, distance: f32) -> f32 {
+        math::ray_epsilon(position, distance, self.scale)
+    }
+
+    /// Back faces of one sided materials are skipped.
+    fn is_visible_side(&self, intersection: &Intersection, ray: &Ray3<f32>) -> bool {
+        let material = self
+            .materials
+            .get(intersection.surface_point.get_material());
+
+        is_visible_side(material, intersection, ray)
+    }
+
+    pub fn pick_lamp(&self, rng: &mut impl Rng) -> Option<(&Lamp, f32)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        self.lights
+            .get(rng.gen_range(0, self.lights.len()))
+            .map(|l| (l, 1.0 / self.lights.len() as f32))
+    }
+
+    /// Picks a light for a surface at `position`, with `normal` and
+    /// `material`, in proportion to how much it may light it. Only lights that
+    /// are linked to the material are picked.
+    pub fn pick_lamp_towards(
+        &self,
+        rng: &mut impl Rng,
+        position: Point3<f32>,
+        normal: Vector3<f32>,
+        material: MaterialId,
+    ) -> Option<(&Lamp, f32)> {
+        self.linked_light_trees
+            .get(&material)
+            .unwrap_or(&self.light_tree)
+            .sample(rng, position, normal)
+            .map(|(index, probability)| (&self.lights[index], probability))
+    }
+
+    /// Picks a direction towards the sky from `position`, through one of the
+    /// portals, or from the sky sampler if there are none. The probability
+    /// density is over solid angle.
+    pub fn sample_sky(
+        &self,
+        rng: &mut impl Rng,
+        position: Point3<f32>,
+    ) -> Option<(Vector3<f32>, f32)> {
+        if self.portals.is_empty() {
+            return self.sky_sampler.as_ref().map(|sampler| sampler.sample(rng));
+        }
+
+        let portal = &self.portals[rng.gen_range(0, self.portals.len())];
+        let (direction, _) = portal.sample(rng, position);
+        Some((direction, self.portal_pdf(position, direction)))
+    }
+
+    /// The probability density of `sample_sky` picking `direction`, or
+    /// `None` if the sky isn't sampled at all.
+    pub fn sky_pdf(&self, position: Point3<f32>, direction: Vector3<f32>) -> Option<f32> {
+        if self.portals.is_empty() {
+            return self
+                .sky_sampler
+                .as_ref()
+                .map(|sampler| sampler.pdf(direction));
+        }
+
+        Some(self.portal_pdf(position, direction))
+    }
+
+    /// Portals may overlap, as seen from `position`, so the density is the
+    /// average of all of them.
+    fn portal_pdf(&self, position: Point3<f32>, direction: Vector3<f32>) -> f32 {
+        let sum: f32 = self
+            .portals
+            .iter()
+            .map(|portal| portal.pdf(position, direction))
+            .sum();
+        sum / self.portals.len() as f32
+    }
+
+    /// The combined spectrum of the sky, the lamps and the emissive
+    /// materials, at `steps` evenly spaced wavelengths within `span`. Each
+    /// light is normalized, so narrow and dim lights are as likely to be
+    /// sampled as bright ones. Half of the result is a flat spectrum, to keep
+    /// every wavelength possible. Lights that depend on the surface are
+    /// evaluated for a surface that faces the incoming ray.
+    pub fn emission_spectrum(
+        &self,
+        span: (f32, f32),
+        steps: usize,
+        resources: Resources<'p>,
+    ) -> Vec<f32> {
+        let mut colors = vec![self.sky];
+        for lamp in self.lights.iter().chain(&self.distant_lights) {
+            match *lamp {
+                Lamp::Directional { color, .. } | Lamp::Point { color, .. } => colors.push(color),
+                Lamp::Shape(_) => {}
+            }
+        }
+        for material in self.materials.iter() {
+            material.emission_colors(&mut colors);
+        }
+
+        let (start, end) = span;
+        let step_size = (end - start) / steps as f32;
+        let mut exe = ExecutionContext::new(resources);
+        let mut spectrum = vec![0.0; steps];
+        let mut light = vec![0.0; steps];
+
+        for color in colors {
+            for (chunk_index, chunk) in light.chunks_mut(LANES).enumerate() {
+                let first = chunk_index * LANES;
+                let input = RenderContext {
+                    wavelengths: (first..first + chunk.len())
+                        .map(|step| start + (step as f32 + 0.5) * step_size)
+                        .collect::<Wavelengths>(),
+                    normal: Vector3::new(0.0, 0.0, 1.0),
+                    incident: Vector3::new(0.0, 0.0, -1.0),
+                    texture: Point2::new(0.0, 0.0),
+                    footprint: Footprint::default(),
+                    position: Point3::origin(),
+                };
+                let values = exe.run(color, &input).values;
+                for (value, &result) in chunk.iter_mut().zip(&values) {
+                    *value = result.abs();
+                }
+            }
+
+            let sum: f32 = light.iter().sum();
+            if sum > 0.0 && sum.is_finite() {
+                for (total, value) in spectrum.iter_mut().zip(&light) {
+                    *total += value / sum;
+                }
+            }
+        }
+
+        let sum: f32 = spectrum.iter().sum();
+        if sum > 0.0 {
+            for value in &mut spectrum {
+                *value = *value / sum + 1.0 / steps as f32;
+            }
+        }
+
+        spectrum
+    }
+}
+
+/// A shape in the world, and the IDs that its intersections get.
+#[derive(Copy, Clone)]
+pub(crate) struct WorldShape<'p> {
+    pub shape: &'p Shape,
+    pub id: PrimitiveId,
+}
+
+impl<'p> Bounded for WorldShape<'p> {
+    fn aabb(&self) -> collision::Aabb3<f32> {
+        self.shape.aabb()
+    }
+}
+
+/// Finds the closest intersection with the shapes that is between
+/// `min_distance` and `closest_distance`, and that is accepted by `accept`.
+/// The minimum distance keeps rays from hitting the surface they start from.
+pub(crate) fn intersect_shapes<'a>(
+    shapes: &Bvh<WorldShape<'a>>,
+    ray: Ray3<f32>,
+    min_distance: f32,
+    mut closest_distance: f32,
+    accept: impl Fn(&Intersection<'a>) -> bool,
+) -> Option<Intersection<'a>> {
+    let mut result = None;
+
+    let mut intersections = shapes.ray_intersect(ray);
+    while let Some(&object) = intersections.next(closest_distance) {
+        if let Some(mut intersection) = object.shape.ray_intersect(&ray) {
+            intersection.id = object.id;
+            if intersection.distance > min_distance
+                && intersection.distance < closest_distance
+                && accept(&intersection)
+            {
+                closest_distance = intersection.distance;
+                result = Some(intersection);
+            }
+        }
+    }
+
+    result
+}
+
+/// Back faces of one sided materials are skipped.
+fn is_visible_side(material: &Material, intersection: &Intersection, ray: &Ray3<f32>) -> bool {
+    material.is_double_sided()
+        || intersection
+            .surface_point
+            .geometric_normal()
+            .dot(ray.direction)
+            < 0.0
+}
+
+/// Programs and resources for evaluating expressions while building shapes.
+#[derive(Copy, Clone)]
+struct ProgramResources<'a, 'p> {
+    programs: ProgramCompiler<'p>,
+    expressions: &'a Expressions,
+    resources: Resources<'a>,
+}
+
+/// Builds the shapes of an object. Infinite planes are kept separate, since
+/// they can't be added to a BVH.
+fn build_shapes(
+    object: &ObjectGeometry,
+    object_materials: &[MaterialId],
+    materials: &Materials,
+    meshes: &Meshes,
+    program_resources: ProgramResources,
+    prototypes: &HashMap<String, Arc<Prototype>>,
+    statistics: &mut Statistics,
+    shapes: &mut Vec<Shape>,
+    planes: &mut Vec<Plane>,
+) -> Result<(), Box<dyn Error>> {
+    match *object {
+        ObjectGeometry::Sphere {
+            position,
+            radius,
+            transform,
+            texture_scale,
+        } => {
+            let shape = place_shape(
+                Shape::Sphere(Sphere {
+                    position,
+                    radius,
+                    texture_scale,
+                    material: object_materials[0],
+                }),
+                transform,
+            );
+
+            shapes.push(shape);
+        }
+        ObjectGeometry::Plane {
+            origin,
+            normal,
+            size,
+            transform,
+            texture_scale,
+        } => {
+            // Infinite planes stay infinite, so they are transformed
+            // directly instead.
+            let (origin, normal) = match (size, transform) {
+                (None, Some(transform)) => {
+                    let to_local = transform
+                        .invert()
+                        .expect("the transform should have been checked when evaluating it");
+                    (
+                        transform.transform_point(origin),
+                        to_local.transpose().transform_vector(normal).normalize(),
+                    )
+                }
+                _ => (origin, normal),
+            };
+
+            let (binormal, tangent) = crate::math::utils::basis(normal);
+            let material = object_materials[0];
+            let normal = Normal::new(normal, Matrix3::from_cols(binormal, tangent, normal).into());
+
+            if let Some(size) = size {
+                let shape = place_shape(
+                    Shape::Rectangle(Rectangle {
+                        origin,
+                        normal,
+                        size,
+                        texture_scale,
+                        material,
+                    }),
+                    transform,
+                );
+
+                shapes.push(shape);
+            } else {
+                let shape = Plane {
+                    shape: collision::Plane::from_point_normal(origin, normal.vector()),
+                    normal,
+                    texture_scale,
+                    material,
+                };
+
+                if materials.get(material).is_emissive() {
+                    println!(
+                        "warning: emissive infinite planes may not always produce correct results"
+                    );
+                }
+                planes.push(shape);
+            }
+        }
+        ObjectGeometry::Disk {
+            origin,
+            normal,
+            radius,
+            inner_radius,
+            transform,
+            texture_scale,
+        } => {
+            let (binormal, tangent) = crate::math::utils::basis(normal);
+
+            let shape = place_shape(
+                Shape::Disk(Disk {
+                    origin,
+                    normal: Normal::new(
+                        normal,
+                        Matrix3::from_cols(binormal, tangent, normal).into(),
+                    ),
+                    radius,
+                    inner_radius,
+                    texture_scale,
+                    material: object_materials[0],
+                }),
+                transform,
+            );
+
+            shapes.push(shape);
+        }
+        ObjectGeometry::Torus {
+            position,
+            axis,
+            major_radius,
+            minor_radius,
+            transform,
+            texture_scale,
+        } => {
+            let (binormal, tangent) = crate::math::utils::basis(axis);
+
+            let shape = place_shape(
+                Shape::Torus(Torus {
+                    position,
+                    axis: Normal::new(axis, Matrix3::from_cols(binormal, tangent, axis).into()),
+                    major_radius,
+                    minor_radius,
+                    texture_scale,
+                    material: object_materials[0],
+                }),
+                transform,
+            );
+
+            shapes.push(shape);
+        }
+        ObjectGeometry::Box {
+            size,
+            transform,
+            texture_scale,
+        } => {
+            let shape = Shape::cuboid(size, transform, texture_scale, object_materials[0])
+                .ok_or("could not invert the transform of a box")?;
+
+            shapes.push(shape);
+        }
+        ObjectGeometry::Csg {
+            operation,
+            ref first,
+            ref second,
+            transform,
+        } => {
+            let material = object_materials[0];
+
+            let shape = place_shape(
+                Shape::Csg(Csg {
+                    operation,
+                    first: Box::new(build_solid(first, material)?),
+                    second: Box::new(build_solid(second, material)?),
+                }),
+                transform,
+            );
+
+            if materials.get(material).is_emissive() {
+                println!("warning: emissive CSG shapes can't be sampled as lights");
+            }
+            shapes.push(shape);
+        }
+        ObjectGeometry::RayMarched {
+            ref estimator,
+            ref bounds,
+            transform,
+        } => {
+            let material = object_materials[0];
+
+            let estimator = match estimator {
+                EstimatorGeometry::Mandelbulb(mandelbulb) => {
+                    Box::new(mandelbulb.clone()) as Box<dyn ParametricValue<_, _>>
+                }
+                EstimatorGeometry::QuaternionJulia(julia) => {
+                    Box::new(julia.clone()) as Box<dyn ParametricValue<_, _>>
+                }
+                EstimatorGeometry::DistanceField(field) => {
+                    Box::new(field.clone()) as Box<dyn ParametricValue<_, _>>
+                }
+            };
+
+            let shape = place_shape(
+                Shape::RayMarched(RayMarched {
+                    bounds: bounds.clone(),
+                    estimator,
+                    material,
+                }),
+                transform,
+            );
+
+            if materials.get(material).is_emissive() {
+                println!("warning: emissive, distance estimated shapes may not always produce correct results");
+            }
+            shapes.push(shape);
+        }
+        ObjectGeometry::Mesh {
+            file,
+            scale,
+            transform,
+            smooth,
+            ref displacement,
+        } => {
+            let obj = meshes.get(file);
+            let positions = meshes.positions(file);
+            let smooth = smooth.unwrap_or_else(|| !obj.normal.is_empty());
+            let vertex_normals = if smooth {
+                Some(vertex_normals(obj, positions))
+            } else {
+                None
+            };
+            let height = displacement
+                .as_ref()
+                .map(|displacement| {
+                    program_resources
+                        .programs
+                        .compile(&displacement.height, program_resources.expressions)
+                })
+                .transpose()
+                .map_err(|error| format!("displacement: {}", error))?;
+
+            statistics.mesh_instances += 1;
+            let mut group_materials = object_materials.iter();
+            for object in &obj.objects {
+                println!("adding object '{}'", object.name);
+
+                for group in object.groups.iter().filter(|group| !group.polys.is_empty()) {
+                    let material = *group_materials
+                        .next()
+                        .expect("each group should have a material");
+
+                    let mut triangles = Vec::new();
+                    for shape in &group.polys {
+                        match *shape {
+                            genmesh::Polygon::PolyTri(genmesh::Triangle { x, y, z }) => {
+                                triangles.push(mesh_vertices(
+                                    obj,
+                                    positions,
+                                    x,
+                                    y,
+                                    z,
+                                    vertex_normals.as_deref(),
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if let (Some(displacement), Some(height)) = (displacement, height) {
+                        triangles = displacement::displace(
+                            &triangles,
+                            height,
+                            displacement.edge_length,
+                            displacement.max_level,
+                            smooth,
+                            program_resources.resources,
+                        );
+                    }
+
+                    for vertices in triangles {
+                        let mut triangle = make_triangle(vertices, material);
+                        triangle.scale(scale);
+                        triangle.transform(transform);
+                        statistics.triangles += 1;
+
+                        shapes.push(triangle);
+                    }
+                }
+            }
+        }
+        ObjectGeometry::Curve {
+            points,
+            widths,
+            transform,
+        } => {
+            // Curves are transformed directly, since their width is in world
+            // units.
+            let mut curve = Curve::new(points, widths);
+            if let Some(transform) = transform {
+                curve.transform(transform);
+            }
+
+            statistics.curves += 1;
+            shapes.push(Shape::curve(curve, object_materials[0]));
+        }
+        ObjectGeometry::Hair {
+            file,
+            widths,
+            transform,
+        } => {
+            let material = object_materials[0];
+
+            for strand in &meshes.get_hair(file).strands {
+                for mut curve in strand_curves(strand, widths) {
+                    curve.transform(transform);
+                    statistics.curves += 1;
+                    shapes.push(Shape::curve(curve, material));
+                }
+            }
+        }
+        ObjectGeometry::PointCloud {
+            file,
+            radius,
+            point_shape,
+            transform,
+        } => {
+            let positions: Vec<_> = meshes
+                .get_points(file)
+                .positions
+                .iter()
+                .map(|&position| transform.transform_point(position.into()))
+                .collect();
+            let radius = radius * crate::math::utils::average_scale(transform);
+            let cloud = PointCloud::new(&positions, radius, point_shape);
+
+            statistics.points += cloud.len();
+            statistics.shape_memory += cloud.memory_size();
+            shapes.push(Shape::point_cloud(cloud, object_materials[0]));
+        }
+        ObjectGeometry::Instance {
+            ref prototype,
+            transform,
+        } => {
+            let prototype = prototypes
+                .get(prototype)
+                .ok_or_else(|| format!("unknown prototype: '{}'", prototype))?;
+            let material = object_materials.first().cloned();
+
+            if material.map_or(false, |material| materials.get(material).is_emissive()) {
+                println!("warning: emissive instances can't be sampled as lights");
+            }
+
+            statistics.instances += 1;
+            shapes.push(
+                Shape::instance(prototype.clone(), transform, material)
+                    .expect("the transform should have been checked when evaluating it"),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The evaluated geometry of the objects and the prototypes, where the
+/// prototypes are sorted by name.
+#[derive(PartialEq)]
+struct WorldGeometry {
+    objects: Vec<ObjectGeometry>,
+    prototypes: Vec<(String, ObjectGeometry)>,
+}
+
+/// The evaluated shape parameters of an object, used for building its shapes
+/// and for detecting if the geometry has changed between reloads.
+#[derive(PartialEq)]
+enum ObjectGeometry {
+    Sphere {
+        position: Point3<f32>,
+        radius: f32,
+        transform: Option<Matrix4<f32>>,
+        texture_scale: Vector2<f32>,
+    },
+    Plane {
+        origin: Point3<f32>,
+        normal: Vector3<f32>,
+        size: Option<Vector2<f32>>,
+        transform: Option<Matrix4<f32>>,
+        texture_scale: Vector2<f32>,
+    },
+    Disk {
+        origin: Point3<f32>,
+        normal: Vector3<f32>,
+        radius: f32,
+        inner_radius: f32,
+        transform: Option<Matrix4<f32>>,
+        texture_scale: Vector2<f32>,
+    },
+    Torus {
+        position: Point3<f32>,
+        axis: Vector3<f32>,
+        major_radius: f32,
+        minor_radius: f32,
+        transform: Option<Matrix4<f32>>,
+        texture_scale: Vector2<f32>,
+    },
+    Box {
+        size: Vector3<f32>,
+        transform: Matrix4<f32>,
+        texture_scale: Vector2<f32>,
+    },
+    Csg {
+        operation: CsgOperation,
+        first: SolidGeometry,
+        second: SolidGeometry,
+        transform: Option<Matrix4<f32>>,
+    },
+    RayMarched {
+        estimator: EstimatorGeometry,
+        bounds: BoundingVolume,
+        transform: Option<Matrix4<f32>>,
+    },
+    Mesh {
+        file: MeshId,
+        scale: f32,
+        transform: Matrix4<f32>,
+        /// Defaults to whether the file has vertex normals.
+        smooth: Option<bool>,
+        displacement: Option<DisplacementGeometry>,
+    },
+    Curve {
+        points: [Point3<f32>; 4],
+        widths: [f32; 2],
+        transform: Option<Matrix4<f32>>,
+    },
+    Hair {
+        file: HairId,
+        widths: [f32; 2],
+        transform: Matrix4<f32>,
+    },
+    PointCloud {
+        file: PointsId,
+        radius: f32,
+        point_shape: PointShape,
+        transform: Matrix4<f32>,
+    },
+    Instance {
+        prototype: String,
+        transform: Matrix4<f32>,
+    },
+}
+
+struct DisplacementGeometry {
+    height: Expression,
+    /// The height expression's ID is only valid in its own project, so it's
+    /// compared by its copy.
+    height_snapshot: ExpressionSnapshot,
+    edge_length: f32,
+    max_level: Option<u32>,
+}
+
+impl PartialEq for DisplacementGeometry {
+    fn eq(&self, other: &Self) -> bool {
+        self.height_snapshot == other.height_snapshot
+            && self.edge_length == other.edge_length
+            && self.max_level == other.max_level
+    }
+}
+
+#[derive(PartialEq)]
+enum SolidGeometry {
+    Sphere {
+        position: Point3<f32>,
+        radius: f32,
+        texture_scale: Vector2<f32>,
+    },
+    Torus {
+        position: Point3<f32>,
+        axis: Vector3<f32>,
+        major_radius: f32,
+        minor_radius: f32,
+        texture_scale: Vector2<f32>,
+    },
+    Box {
+        size: Vector3<f32>,
+        transform: Matrix4<f32>,
+        texture_scale: Vector2<f32>,
+    },
+    Csg {
+        operation: CsgOperation,
+        first: Box<SolidGeometry>,
+        second: Box<SolidGeometry>,
+    },
+}
+
+#[derive(PartialEq)]
+enum EstimatorGeometry {
+    Mandelbulb(Mandelbulb),
+    QuaternionJulia(QuaternionJulia),
+    DistanceField(DistanceField),
+}
+
+/// Evaluates the geometry of the objects, where each object has a label for
+/// error messages.
+fn evaluate_geometry<'a>(
+    objects: impl IntoIterator<Item = (String, &'a WorldObject)>,
+    quality: f32,
+    eval_context: EvalContext,
+) -> Result<Vec<ObjectGeometry>, Box<dyn Error>> {
+    let mut geometry = Vec::new();
+
+    for (label, object) in objects {
+        match object {
+            WorldObject::Sphere {
+                position,
+                radius,
+                transform,
+                texture_scale,
+                ..
+            } => {
+                let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+                geometry.push(ObjectGeometry::Sphere {
+                    position: position.evaluate(eval_context)?,
+                    radius: radius.evaluate(eval_context)?,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                    texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+                });
+            }
+            WorldObject::Plane {
+                origin,
+                normal,
+                size,
+                transform,
+                texture_scale,
+                ..
+            } => {
+                let normal: Vector3<f32> = normal.evaluate(eval_context)?;
+                let size: Option<Vector2<f32>> = size.evaluate(eval_context)?;
+                let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+                if let Some(size) = size {
+                    if size.x <= 0.0 || size.y <= 0.0 {
+                        return Err(format!(
+                            "{}: the plane size must be positive, got {:?}",
+                            label, size
+                        )
+                        .into());
+                    }
+                }
+
+                geometry.push(ObjectGeometry::Plane {
+                    origin: origin.evaluate(eval_context)?,
+                    normal: normal.normalize(),
+                    size,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                    texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+                });
+            }
+            WorldObject::Disk {
+                origin,
+                normal,
+                radius,
+                inner_radius,
+                transform,
+                texture_scale,
+                ..
+            } => {
+                let normal: Vector3<f32> = normal.evaluate(eval_context)?;
+                let radius: f32 = radius.evaluate(eval_context)?;
+                let inner_radius = inner_radius.evaluate_or(eval_context, 0.0)?;
+                let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+                if inner_radius < 0.0 || inner_radius >= radius {
+                    return Err(format!(
+                        "{}: the inner radius of a disk must be between 0 and its radius, got {} and {}",
+                        label, inner_radius, radius
+                    )
+                    .into());
+                }
+
+                geometry.push(ObjectGeometry::Disk {
+                    origin: origin.evaluate(eval_context)?,
+                    normal: normal.normalize(),
+                    radius,
+                    inner_radius,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                    texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+                });
+            }
+            WorldObject::Torus {
+                position,
+                axis,
+                major_radius,
+                minor_radius,
+                transform,
+                texture_scale,
+                ..
+            } => {
+                let axis: Option<Vector3<f32>> = axis.evaluate(eval_context)?;
+                let major_radius: f32 = major_radius.evaluate(eval_context)?;
+                let minor_radius: f32 = minor_radius.evaluate(eval_context)?;
+                let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+                if minor_radius <= 0.0 || major_radius <= 0.0 {
+                    return Err(format!(
+                        "{}: the radii of a torus must be positive, got {} and {}",
+                        label, major_radius, minor_radius
+                    )
+                    .into());
+                }
+
+                geometry.push(ObjectGeometry::Torus {
+                    position: position.evaluate(eval_context)?,
+                    axis: axis.unwrap_or(Vector3::new(0.0, 1.0, 0.0)).normalize(),
+                    major_radius,
+                    minor_radius,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                    texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+                });
+            }
+            WorldObject::Box {
+                size,
+                transform,
+                texture_scale,
+                ..
+            } => {
+                let size: Vector3<f32> = size.evaluate(eval_context)?;
+                let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+                if size.x <= 0.0 || size.y <= 0.0 || size.z <= 0.0 {
+                    return Err(format!(
+                        "{}: the box size must be positive, got {:?}",
+                        label, size
+                    )
+                    .into());
+                }
+
+                geometry.push(ObjectGeometry::Box {
+                    size,
+                    transform: transform.evaluate_or_else(eval_context, Matrix4::identity)?,
+                    texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+                });
+            }
+            WorldObject::Csg {
+                operation,
+                first,
+                second,
+                transform,
+                ..
+            } => {
+                let first = evaluate_solid(first, eval_context)
+                    .map_err(|error| format!("{}: first: {}", label, error))?;
+                let second = evaluate_solid(second, eval_context)
+                    .map_err(|error| format!("{}: second: {}", label, error))?;
+
+                geometry.push(ObjectGeometry::Csg {
+                    operation: *operation,
+                    first,
+                    second,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                });
+            }
+            WorldObject::RayMarched {
+                shape,
+                bounds,
+                transform,
+                ..
+            } => {
+                let bounds = match bounds {
+                    crate::project::BoundingVolume::Box { min, max } => BoundingVolume::Box(
+                        min.evaluate(eval_context)?,
+                        max.evaluate(eval_context)?,
+                    ),
+                    crate::project::BoundingVolume::Sphere { position, radius } => {
+                        BoundingVolume::Sphere(
+                            position.evaluate(eval_context)?,
+                            radius.evaluate(eval_context)?,
+                        )
+                    }
+                };
+
+                let estimator = match shape {
+                    crate::project::Estimator::Mandelbulb {
+                        iterations,
+                        threshold,
+                        power,
+                        constant,
+                    } => EstimatorGeometry::Mandelbulb(Mandelbulb {
+                        iterations: iterations.evaluate(eval_context)?,
+                        threshold: threshold.evaluate(eval_context)?,
+                        power: power.evaluate(eval_context)?,
+                        constant: constant.evaluate(eval_context)?,
+                    }),
+                    crate::project::Estimator::QuaternionJulia {
+                        iterations,
+                        threshold,
+                        constant,
+                        slice_plane,
+                        variant,
+                    } => EstimatorGeometry::QuaternionJulia(QuaternionJulia {
+                        iterations: iterations.evaluate(eval_context)?,
+                        threshold: threshold.evaluate(eval_context)?,
+                        constant: constant.evaluate(eval_context)?,
+                        slice_plane: slice_plane.evaluate(eval_context)?,
+                        ty: match &*variant.name {
+                            "regular" => QuatMul::Regular,
+                            "cubic" => QuatMul::Cubic,
+                            "bicomplex" => QuatMul::Bicomplex,
+                            name => {
+                                return Err(format!(
+                                    "{}: unexpected Julia fractal variant: {}",
+                                    label, name
+                                )
+                                .into())
+                            }
+                        },
+                    }),
+                    crate::project::Estimator::DistanceField { distance } => {
+                        EstimatorGeometry::DistanceField(
+                            DistanceField::new(*distance, eval_context.expressions)
+                                .map_err(|error| format!("{}: {}", label, error))?,
+                        )
+                    }
+                };
+
+                geometry.push(ObjectGeometry::RayMarched {
+                    estimator,
+                    bounds,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                });
+            }
+            WorldObject::Mesh {
+                file,
+                scale,
+                transform,
+                smooth,
+                displacement,
+                ..
+            } => {
+                let displacement = match displacement {
+                    Some(displacement) => {
+                        let edge_length: f32 = displacement.edge_length.evaluate(eval_context)?;
+                        if !(edge_length > 0.0) {
+                            return Err(format!(
+                                "{}: the displacement edge length must be positive, got {}",
+                                label, edge_length
+                            )
+                            .into());
+                        }
+
+                        let object_quality: f32 =
+                            displacement.quality.evaluate_or(eval_context, 1.0)?;
+                        if !(object_quality > 0.0) {
+                            return Err(format!(
+                                "{}: the displacement quality must be positive, got {}",
+                                label, object_quality
+                            )
+                            .into());
+                        }
+
+                        Some(DisplacementGeometry {
+                            height: displacement.height,
+                            height_snapshot: eval_context.expressions.snapshot(displacement.height),
+                            edge_length: edge_length / (object_quality * quality),
+                            max_level: displacement.max_level,
+                        })
+                    }
+                    None => None,
+                };
+
+                geometry.push(ObjectGeometry::Mesh {
+                    file: *file,
+                    scale: scale.evaluate_or(eval_context, 1.0)?,
+                    transform: transform.evaluate_or_else(eval_context, Matrix4::identity)?,
+                    smooth: *smooth,
+                    displacement,
+                });
+            }
+            WorldObject::Curve {
+                points,
+                width,
+                tip_width,
+                transform,
+                ..
+            } => {
+                if points.len() != 4 {
+                    return Err(
+                        format!("{}: a curve needs 4 points, got {}", label, points.len()).into(),
+                    );
+                }
+
+                geometry.push(ObjectGeometry::Curve {
+                    points: [
+                        points[0].evaluate(eval_context)?,
+                        points[1].evaluate(eval_context)?,
+                        points[2].evaluate(eval_context)?,
+                        points[3].evaluate(eval_context)?,
+                    ],
+                    widths: evaluate_widths(width, tip_width, &label, eval_context)?,
+                    transform: evaluate_transform(transform, &label, eval_context)?,
+                });
+            }
+            WorldObject::Hair {
+                file,
+                width,
+                tip_width,
+                transform,
+                ..
+            } => {
+                geometry.push(ObjectGeometry::Hair {
+                    file: *file,
+                    widths: evaluate_widths(width, tip_width, &label, eval_context)?,
+                    transform: transform.evaluate_or_else(eval_context, Matrix4::identity)?,
+                });
+            }
+            WorldObject::PointCloud {
+                file,
+                radius,
+                point_shape,
+                transform,
+                ..
+            } => {
+                let radius: f32 = radius.evaluate(eval_context)?;
+                if !(radius > 0.0) {
+                    return Err(format!(
+                        "{}: the point radius must be positive, got {}",
+                        label, radius
+                    )
+                    .into());
+                }
+
+                let point_shape = match point_shape.as_deref() {
+                    None | Some("sphere") => PointShape::Sphere,
+                    Some("disk") => PointShape::Disk,
+                    Some(name) => {
+                        return Err(format!(
+                            "{}: unexpected point shape '{}', expected 'sphere' or 'disk'",
+                            label, name
+                        )
+                        .into())
+                    }
+                };
+
+                geometry.push(ObjectGeometry::PointCloud {
+                    file: *file,
+                    radius,
+                    point_shape,
+                    transform: transform.evaluate_or_else(eval_context, Matrix4::identity)?,
+                });
+            }
+            WorldObject::Instance {
+                prototype,
+                transform,
+                ..
+            } => {
+                geometry.push(ObjectGeometry::Instance {
+                    prototype: prototype.clone(),
+                    transform: evaluate_transform(transform, &label, eval_context)?
+                        .unwrap_or_else(Matrix4::identity),
+                });
+            }
+            WorldObject::DirectionalLight { .. }
+            | WorldObject::PointLight { .. }
+            | WorldObject::Portal { .. } => {}
+        }
+    }
+
+    Ok(geometry)
 }
 
-impl<'p> World<'p> {
-    pub fn from_project(
+/// The index of refraction of the surrounding medium, and the length of a
+/// unit in meters.
+fn evaluate_units(project: &crate::project::World) -> Result<(f32, f32), Box<dyn Error>> {
+    let medium_ior = project.medium.as_ref().map_or(1.0, Medium::ior);
+    if !(medium_ior > 0.0) {
+        return Err("medium: the index of refraction must be positive".into());
+    }
+
+    let scale = project.scale.unwrap_or(1.0);
+    if !(scale > 0.0) {
+        return Err("scale: the length of a unit must be positive".into());
+    }
+
+    Ok((medium_ior, scale))
+}
+
+fn evaluate_world_geometry(
+    project: &crate::project::World,
+    eval_context: EvalContext,
+) -> Result<WorldGeometry, Box<dyn Error>> {
+    let quality = project.quality.unwrap_or(1.0);
+    if !(quality > 0.0) {
+        return Err(format!("the quality must be positive, got {}", quality).into());
+    }
+
+    let objects = evaluate_geometry(
+        project
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (format!("objects[{}]", i), object)),
+        quality,
+        eval_context,
+    )?;
+
+    for (i, object) in project.objects.iter().enumerate() {
+        if let WorldObject::Instance { prototype, .. } = object {
+            if !project.prototypes.contains_key(prototype) {
+                return Err(format!("objects[{}]: unknown prototype: '{}'", i, prototype).into());
+            }
+        }
+    }
+
+    let mut names: Vec<_> = project.prototypes.keys().collect();
+    names.sort();
+
+    let mut prototypes = Vec::with_capacity(names.len());
+    for name in names {
+        let label = format!("prototypes.{}", name);
+        let object = &project.prototypes[name];
+
+        match object {
+            WorldObject::Instance { .. } => {
+                return Err(format!("{}: prototypes can't be instances", label).into())
+            }
+            WorldObject::DirectionalLight { .. } | WorldObject::PointLight { .. } => {
+                return Err(format!("{}: lights can't be prototypes", label).into())
+            }
+            WorldObject::Portal { .. } => {
+                return Err(format!("{}: portals can't be prototypes", label).into())
+            }
+            WorldObject::Plane { size: None, .. } => {
+                return Err(format!("{}: infinite planes can't be prototypes", label).into())
+            }
+            _ => {}
+        }
+
+        let mut geometry =
+            evaluate_geometry(std::iter::once((label, object)), quality, eval_context)?;
+        prototypes.push((name.clone(), geometry.remove(0)));
+    }
+
+    Ok(WorldGeometry {
+        objects,
+        prototypes,
+    })
+}
+
+fn evaluate_transform(
+    transform: &Option<Transform>,
+    label: &str,
+    eval_context: EvalContext,
+) -> Result<Option<Matrix4<f32>>, Box<dyn Error>> {
+    let transform: Option<Matrix4<f32>> = transform
+        .evaluate(eval_context)
+        .map_err(|error| format!("{}: transform: {}", label, error))?;
+
+    if let Some(transform) = transform {
+        if transform.invert().is_none() {
+            return Err(format!("{}: the transform can't be inverted", label).into());
+        }
+    }
+
+    Ok(transform)
+}
+
+/// Evaluates the width at the start and end of a curve, where the end has
+/// the same width as the start by default.
+fn evaluate_widths(
+    width: &Expression,
+    tip_width: &Option<Expression>,
+    label: &str,
+    eval_context: EvalContext,
+) -> Result<[f32; 2], Box<dyn Error>> {
+    let width: f32 = width.evaluate(eval_context)?;
+    let tip_width = tip_width.evaluate_or(eval_context, width)?;
+
+    if !(width > 0.0) || !(tip_width >= 0.0) {
+        return Err(format!(
+            "{}: the curve width must be positive, got {} and {}",
+            label, width, tip_width
+        )
+        .into());
+    }
+
+    Ok([width, tip_width])
+}
+
+/// Turns a hair strand into Catmull-Rom splines, expressed as Bézier curves.
+/// The width changes evenly from the root to the tip.
+fn strand_curves(strand: &[[f32; 3]], [width, tip_width]: [f32; 2]) -> Vec<Curve> {
+    let points: Vec<Point3<f32>> = strand.iter().map(|&point| point.into()).collect();
+    let segments = points.len() - 1;
+
+    // The ends are extended in a straight line.
+    let point = |index: isize| -> Point3<f32> {
+        if index < 0 {
+            points[0] + (points[0] - points[1])
+        } else if index as usize > segments {
+            points[segments] + (points[segments] - points[segments - 1])
+        } else {
+            points[index as usize]
+        }
+    };
+    let width_at = |index: usize| {
+        let amount = index as f32 / segments as f32;
+        width * (1.0 - amount) + tip_width * amount
+    };
+
+    (0..segments)
+        .map(|segment| {
+            let index = segment as isize;
+            let p0 = point(index - 1);
+            let p1 = point(index);
+            let p2 = point(index + 1);
+            let p3 = point(index + 2);
+
+            Curve::new(
+                [p1, p1 + (p2 - p0) / 6.0, p2 - (p3 - p1) / 6.0, p2],
+                [width_at(segment), width_at(segment + 1)],
+            )
+        })
+        .collect()
+}
+
+/// Moves a shape into its transformed space, if it has a transform.
+fn place_shape(shape: Shape, transform: Option<Matrix4<f32>>) -> Shape {
+    match transform {
+        Some(transform) => Shape::transformed(shape, transform)
+            .expect("the transform should have been checked when evaluating it"),
+        None => shape,
+    }
+}
+
+fn evaluate_solid(
+    solid: &Solid,
+    eval_context: EvalContext,
+) -> Result<SolidGeometry, Box<dyn Error>> {
+    match solid {
+        Solid::Sphere {
+            position,
+            radius,
+            texture_scale,
+        } => {
+            let radius: f32 = radius.evaluate(eval_context)?;
+            let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+            if radius <= 0.0 {
+                return Err(
+                    format!("the radius of a sphere must be positive, got {}", radius).into(),
+                );
+            }
+
+            Ok(SolidGeometry::Sphere {
+                position: position.evaluate(eval_context)?,
+                radius,
+                texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+            })
+        }
+        Solid::Torus {
+            position,
+            axis,
+            major_radius,
+            minor_radius,
+            texture_scale,
+        } => {
+            let axis: Option<Vector3<f32>> = axis.evaluate(eval_context)?;
+            let major_radius: f32 = major_radius.evaluate(eval_context)?;
+            let minor_radius: f32 = minor_radius.evaluate(eval_context)?;
+            let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+            if minor_radius <= 0.0 || major_radius <= 0.0 {
+                return Err(format!(
+                    "the radii of a torus must be positive, got {} and {}",
+                    major_radius, minor_radius
+                )
+                .into());
+            }
+
+            Ok(SolidGeometry::Torus {
+                position: position.evaluate(eval_context)?,
+                axis: axis.unwrap_or(Vector3::new(0.0, 1.0, 0.0)).normalize(),
+                major_radius,
+                minor_radius,
+                texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+            })
+        }
+        Solid::Box {
+            size,
+            transform,
+            texture_scale,
+        } => {
+            let size: Vector3<f32> = size.evaluate(eval_context)?;
+            let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+
+            if size.x <= 0.0 || size.y <= 0.0 || size.z <= 0.0 {
+                return Err(format!("the box size must be positive, got {:?}", size).into());
+            }
+
+            Ok(SolidGeometry::Box {
+                size,
+                transform: transform.evaluate_or_else(eval_context, Matrix4::identity)?,
+                texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
+            })
+        }
+        Solid::Csg {
+            operation,
+            first,
+            second,
+        } => Ok(SolidGeometry::Csg {
+            operation: *operation,
+            first: Box::new(
+                evaluate_solid(first, eval_context).map_err(|error| format!("first: {}", error))?,
+            ),
+            second: Box::new(
+                evaluate_solid(second, eval_context)
+                    .map_err(|error| format!("second: {}", error))?,
+            ),
+        }),
+    }
+}
+
+fn build_solid(solid: &SolidGeometry, material: MaterialId) -> Result<Shape, Box<dyn Error>> {
+    match *solid {
+        SolidGeometry::Sphere {
+            position,
+            radius,
+            texture_scale,
+        } => Ok(Shape::Sphere(Sphere {
+            position,
+            radius,
+            texture_scale,
+            material,
+        })),
+        SolidGeometry::Torus {
+            position,
+            axis,
+            major_radius,
+            minor_radius,
+            texture_scale,
+        } => {
+            let (binormal, tangent) = crate::math::utils::basis(axis);
+
+            Ok(Shape::Torus(Torus {
+                position,
+                axis: Normal::new(axis, Matrix3::from_cols(binormal, tangent, axis).into()),
+                major_radius,
+                minor_radius,
+                texture_scale,
+                material,
+            }))
+        }
+        SolidGeometry::Box {
+            size,
+            transform,
+            texture_scale,
+        } => Ok(Shape::cuboid(size, transform, texture_scale, material)
+            .ok_or("could not invert the transform of a box")?),
+        SolidGeometry::Csg {
+            operation,
+            ref first,
+            ref second,
+        } => Ok(Shape::Csg(Csg {
+            operation,
+            first: Box::new(build_solid(first, material)?),
+            second: Box::new(build_solid(second, material)?),
+        })),
+    }
+}
+
+/// Builds a sampler for the first environment map in the sky. The sky's own
+/// color is still used for the samples, so it may be scaled or mixed with
+/// other colors.
+fn sky_sampler(
+    sky: Option<&Expression>,
+    expressions: &Expressions,
+    resources: Resources,
+) -> Option<SkySampler> {
+    let id = match sky? {
+        Expression::Number(_) => return None,
+        &Expression::Complex(id) => id,
+    };
+
+    match *expressions.get(id) {
+        ComplexExpression::Environment { texture, rotation } => {
+            SkySampler::new(resources.textures.get(texture), rotation)
+        }
+        ComplexExpression::Binary { lhs, rhs, .. } | ComplexExpression::Mix { lhs, rhs, .. } => {
+            sky_sampler(Some(&lhs), expressions, resources)
+                .or_else(|| sky_sampler(Some(&rhs), expressions, resources))
+        }
+        _ => None,
+    }
+}
+
+/// Everything that can be replaced without rebuilding the shapes.
+struct Shading<'p> {
+    sky: LightProgram<'p>,
+    materials: Materials<'p>,
+    lights: Vec<Lamp<'p>>,
+    distant_lights: Vec<Lamp<'p>>,
+    portals: Vec<Portal>,
+
+    /// The materials of each object with geometry, in the same order as the
+    /// evaluated geometry. Meshes have one material per group of faces.
+    object_materials: Vec<Vec<MaterialId>>,
+
+    /// The materials of each prototype, sorted by name.
+    prototype_materials: Vec<Vec<MaterialId>>,
+}
+
+impl<'p> Shading<'p> {
+    fn from_project(
         project: crate::project::World,
         eval_context: EvalContext,
         programs: ProgramCompiler<'p>,
         expressions: &Expressions,
         meshes: &Meshes,
-        allocator: &'p bumpalo::Bump,
     ) -> Result<Self, Box<dyn Error>> {
         let sky = programs.compile(&project.sky.unwrap_or(Expression::Number(0.0)), expressions)?;
+        let light_names = light_names(&project)?;
 
-        let mut objects: Vec<&Shape> = Vec::new();
-        let mut planes = Vec::new();
+        let mut materials = Materials::new();
         let mut lights = Vec::new();
+        let mut distant_lights = Vec::new();
+        let mut portals = Vec::new();
+        let mut object_materials = Vec::new();
 
         for (i, object) in project.objects.into_iter().enumerate() {
             match object {
-                WorldObject::Sphere {
+                WorldObject::DirectionalLight {
+                    direction,
+                    width,
+                    angular_diameter,
+                    color,
+                    name,
+                } => {
+                    // A width of 0 or less is a single direction.
+                    let cos_half = match (angular_diameter, width) {
+                        (Some(_), Some(_)) => {
+                            return Err(format!(
+                            "objects[{}]: a light can't have both a width and an angular diameter",
+                            i
+                        )
+                            .into())
+                        }
+                        (Some(angular_diameter), None) => {
+                            let angular_diameter: f32 = angular_diameter.evaluate(eval_context)?;
+                            if !(0.0..=180.0).contains(&angular_diameter) {
+                                return Err(format!(
+                                    "objects[{}]: the angular diameter must be between 0 and 180 degrees, got {}",
+                                    i, angular_diameter
+                                )
+                                .into());
+                            }
+                            (angular_diameter.to_radians() * 0.5).cos()
+                        }
+                        (None, Some(width)) => {
+                            let width: f32 = width.evaluate(eval_context)?;
+                            if width > 0.0 {
+                                width.min(1.0)
+                            } else {
+                                1.0
+                            }
+                        }
+                        (None, None) => 1.0,
+                    };
+
+                    let direction: Vector3<f32> = direction.evaluate(eval_context)?;
+                    distant_lights.push(Lamp::Directional {
+                        direction: direction.normalize(),
+                        cos_half,
+                        color: programs.compile(&color, expressions)?,
+                        link: name.map(|name| light_names[&name]),
+                    })
+                }
+                WorldObject::PointLight {
                     position,
                     radius,
-                    texture_scale,
-                    material,
+                    color,
+                    name,
                 } => {
-                    let material =
-                        Material::from_project(material, eval_context, programs, expressions)?;
-                    let emissive = material.is_emissive();
-                    let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
+                    let radius: f32 = radius.evaluate_or(eval_context, 0.0)?;
+                    if !(radius >= 0.0) {
+                        return Err(format!(
+                            "objects[{}]: the light radius can't be negative, got {}",
+                            i, radius
+                        )
+                        .into());
+                    }
 
-                    let shape = allocator.alloc(Shape::Sphere {
+                    lights.push(Lamp::Point {
                         position: position.evaluate(eval_context)?,
-                        radius: radius.evaluate(eval_context)?,
-                        texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
-                        material,
-                    });
-
-                    if emissive {
-                        lights.push(Lamp::Shape(shape));
-                    }
-                    objects.push(shape);
+                        radius,
+                        color: programs.compile(&color, expressions)?,
+                        link: name.map(|name| light_names[&name]),
+                    })
                 }
-                WorldObject::Plane {
+                WorldObject::Portal {
                     origin,
                     normal,
-                    texture_scale,
-                    material,
+                    size,
+                    transform,
                 } => {
+                    let label = format!("objects[{}]", i);
+                    let origin: Point3<f32> = origin.evaluate(eval_context)?;
                     let normal: Vector3<f32> = normal.evaluate(eval_context)?;
-                    let normal = normal.normalize();
-                    let (binormal, tangent) = crate::math::utils::basis(normal);
-
-                    let material =
-                        Material::from_project(material, eval_context, programs, expressions)?;
-                    let emissive = material.is_emissive();
-                    let texture_scale: Option<_> = texture_scale.evaluate(eval_context)?;
-
-                    let shape = Plane {
-                        shape: collision::Plane::from_point_normal(
-                            origin.evaluate(eval_context)?,
-                            normal,
-                        ),
-                        normal: Normal::new(
-                            normal,
-                            Matrix3::from_cols(binormal, tangent, normal).into(),
-                        ),
-                        texture_scale: texture_scale.unwrap_or(Vector2::new(1.0, 1.0)),
-                        material,
-                    };
-
-                    if emissive {
-                        println!("warning: emissive planes may not always produce correct results");
+                    let size: Vector2<f32> = size.evaluate(eval_context)?;
+                    if !(size.x > 0.0 && size.y > 0.0) {
+                        return Err(format!(
+                            "{}: the portal size must be positive, got {:?}",
+                            label, size
+                        )
+                        .into());
                     }
-                    planes.push(shape);
+
+                    let (binormal, tangent) = crate::math::utils::basis(normal.normalize());
+                    let width = binormal * size.x;
+                    let height = tangent * size.y;
+                    let corner = origin - (width + height) * 0.5;
+
+                    portals.push(
+                        match evaluate_transform(&transform, &label, eval_context)? {
+                            Some(transform) => Portal::new(
+                                transform.transform_point(corner),
+                                transform.transform_vector(width),
+                                transform.transform_vector(height),
+                            ),
+                            None => Portal::new(corner, width, height),
+                        },
+                    );
                 }
-                WorldObject::RayMarched {
-                    shape,
-                    bounds,
-                    material,
-                } => {
-                    let material =
-                        Material::from_project(material, eval_context, programs, expressions)?;
-                    let emissive = material.is_emissive();
-
-                    let bounds = match bounds {
-                        crate::project::BoundingVolume::Box { min, max } => BoundingVolume::Box(
-                            min.evaluate(eval_context)?,
-                            max.evaluate(eval_context)?,
-                        ),
-                        crate::project::BoundingVolume::Sphere { position, radius } => {
-                            BoundingVolume::Sphere(
-                                position.evaluate(eval_context)?,
-                                radius.evaluate(eval_context)?,
-                            )
-                        }
-                    };
+                object => object_materials.push(insert_object_materials(
+                    object,
+                    &format!("objects[{}]", i),
+                    &mut materials,
+                    eval_context,
+                    programs,
+                    expressions,
+                    meshes,
+                    &light_names,
+                )?),
+            }
+        }
 
-                    let estimator = match shape {
-                        crate::project::Estimator::Mandelbulb {
-                            iterations,
-                            threshold,
-                            power,
-                            constant,
-                        } => Box::new(crate::shapes::distance_estimators::Mandelbulb {
-                            iterations: iterations.evaluate(eval_context)?,
-                            threshold: threshold.evaluate(eval_context)?,
-                            power: power.evaluate(eval_context)?,
-                            constant: constant.map(|e| e.evaluate(eval_context)).transpose()?,
-                        }) as Box<dyn ParametricValue<_, _>>,
-                        crate::project::Estimator::QuaternionJulia {
-                            iterations,
-                            threshold,
-                            constant,
-                            slice_plane,
-                            variant,
-                        } => Box::new(crate::shapes::distance_estimators::QuaternionJulia {
-                            iterations: iterations.evaluate(eval_context)?,
-                            threshold: threshold.evaluate(eval_context)?,
-                            constant: constant.evaluate(eval_context)?,
-                            slice_plane: slice_plane.evaluate(eval_context)?,
-                            ty: match &*variant.name {
-                                "regular" => QuatMul::Regular,
-                                "cubic" => QuatMul::Cubic,
-                                "bicomplex" => QuatMul::Bicomplex,
-                                name => {
-                                    return Err(format!(
-                                        "unexpected Julia fractal variant: {}",
-                                        name
-                                    )
-                                    .into())
-                                }
-                            },
-                        }) as Box<dyn ParametricValue<_, _>>,
-                    };
+        let mut prototypes: Vec<_> = project.prototypes.into_iter().collect();
+        prototypes.sort_by(|(a, _), (b, _)| a.cmp(b));
 
-                    let shape = allocator.alloc(Shape::RayMarched {
-                        bounds,
-                        estimator,
-                        material,
-                    });
+        let mut prototype_materials = Vec::with_capacity(prototypes.len());
+        for (name, object) in prototypes {
+            prototype_materials.push(insert_object_materials(
+                object,
+                &format!("prototypes.{}", name),
+                &mut materials,
+                eval_context,
+                programs,
+                expressions,
+                meshes,
+                &light_names,
+            )?);
+        }
+
+        Ok(Shading {
+            sky,
+            materials,
+            lights,
+            distant_lights,
+            portals,
+            object_materials,
+            prototype_materials,
+        })
+    }
+}
+
+/// Gives each named light and emissive material an index, for light
+/// linking. The names have to be unique.
+fn light_names(project: &crate::project::World) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+    let mut names = HashMap::new();
+    let mut add_name = |name: &String, label: &str| {
+        let index = names.len();
+        if names.insert(name.clone(), index).is_some() {
+            return Err(format!(
+                "{}: there is already a light named '{}'",
+                label, name
+            ));
+        }
+        Ok(())
+    };
 
-                    if emissive {
-                        println!("warning: emissive, distance estimated shapes may not always produce correct results");
+    let objects = project
+        .objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (format!("objects[{}]", i), object));
+    let prototypes = project
+        .prototypes
+        .iter()
+        .map(|(name, object)| (format!("prototypes.{}", name), object));
+
+    for (label, object) in objects.chain(prototypes) {
+        match object {
+            WorldObject::DirectionalLight {
+                name: Some(name), ..
+            }
+            | WorldObject::PointLight {
+                name: Some(name), ..
+            } => add_name(name, &label)?,
+            object => {
+                for material in project_materials(object) {
+                    if let Some(name) = &material.light_name {
+                        add_name(name, &label)?;
                     }
-                    objects.push(shape);
                 }
-                WorldObject::Mesh {
-                    file,
-                    mut materials,
-                    scale,
-                    transform,
-                } => {
-                    let transform =
-                        transform.evaluate_or_else(eval_context, || Matrix4::identity())?;
-                    let scale = scale.evaluate_or(eval_context, 1.0)?;
-                    let obj = meshes.get(file);
-                    for object in &obj.objects {
-                        println!("adding object '{}'", object.name);
-
-                        let (object_material, emissive) = match materials.remove(&object.name) {
-                            Some(m) => {
-                                let material =
-                                    Material::from_project(m, eval_context, programs, expressions)?;
-                                let emissive = material.is_emissive();
-                                (Arc::new(material), emissive)
-                            }
-                            None => {
-                                return Err(format!(
-                                    "objects[{}]: missing material for '{}'",
-                                    i, object.name
-                                )
-                                .into())
-                            }
-                        };
-
-                        for group in &object.groups {
-                            for shape in &group.polys {
-                                match *shape {
-                                    genmesh::Polygon::PolyTri(genmesh::Triangle { x, y, z }) => {
-                                        let mut triangle =
-                                            make_triangle(obj, x, y, z, object_material.clone());
-                                        triangle.scale(scale);
-                                        triangle.transform(transform);
-                                        let triangle = allocator.alloc(triangle);
-                                        if emissive {
-                                            lights.push(Lamp::Shape(triangle));
-                                        }
-
-                                        objects.push(triangle);
-                                    }
-                                    _ => {}
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// The materials of an object with geometry.
+fn project_materials(object: &WorldObject) -> Vec<&crate::project::Material> {
+    match object {
+        WorldObject::Sphere { material, .. }
+        | WorldObject::Plane { material, .. }
+        | WorldObject::Disk { material, .. }
+        | WorldObject::Torus { material, .. }
+        | WorldObject::Box { material, .. }
+        | WorldObject::Csg { material, .. }
+        | WorldObject::RayMarched { material, .. }
+        | WorldObject::Curve { material, .. }
+        | WorldObject::Hair { material, .. }
+        | WorldObject::PointCloud { material, .. } => vec![material],
+        WorldObject::Mesh { materials, .. } => materials.values().collect(),
+        WorldObject::Instance { material, .. } => material.iter().collect(),
+        WorldObject::DirectionalLight { .. }
+        | WorldObject::PointLight { .. }
+        | WorldObject::Portal { .. } => Vec::new(),
+    }
+}
+
+/// Adds the materials of an object with geometry, and returns their IDs.
+fn insert_object_materials<'p>(
+    object: WorldObject,
+    label: &str,
+    materials: &mut Materials<'p>,
+    eval_context: EvalContext,
+    programs: ProgramCompiler<'p>,
+    expressions: &Expressions,
+    meshes: &Meshes,
+    light_names: &HashMap<String, usize>,
+) -> Result<Vec<MaterialId>, Box<dyn Error>> {
+    match object {
+        WorldObject::Sphere { material, .. }
+        | WorldObject::Plane { material, .. }
+        | WorldObject::Disk { material, .. }
+        | WorldObject::Torus { material, .. }
+        | WorldObject::Box { material, .. }
+        | WorldObject::Csg { material, .. }
+        | WorldObject::RayMarched { material, .. }
+        | WorldObject::Curve { material, .. }
+        | WorldObject::Hair { material, .. }
+        | WorldObject::PointCloud { material, .. } => {
+            let material =
+                Material::from_project(material, eval_context, programs, expressions, light_names)?;
+            Ok(vec![materials.insert(material)])
+        }
+        WorldObject::Mesh {
+            file,
+            materials: mut mesh_materials,
+            ..
+        } => {
+            // Each group of faces uses the material for its name from
+            // `usemtl`, or for the object's name, and groups with the same
+            // name share the material.
+            let mut ids = Vec::new();
+            let mut inserted: HashMap<String, MaterialId> = HashMap::new();
+
+            for object in &meshes.get(file).objects {
+                for group in object.groups.iter().filter(|group| !group.polys.is_empty()) {
+                    let group_material = group.material.as_ref().map(|material| &material.name);
+                    let name = group_material
+                        .filter(|&name| {
+                            inserted.contains_key(name) || mesh_materials.contains_key(name)
+                        })
+                        .unwrap_or(&object.name);
+
+                    if let Some(&id) = inserted.get(name) {
+                        ids.push(id);
+                        continue;
+                    }
+
+                    let material = match mesh_materials.remove(name) {
+                        Some(m) => Material::from_project(
+                            m,
+                            eval_context,
+                            programs,
+                            expressions,
+                            light_names,
+                        )?,
+                        None => {
+                            let names = match group_material {
+                                Some(group_material) => {
+                                    format!("'{}' or '{}'", group_material, object.name)
                                 }
-                            }
+                                None => format!("'{}'", object.name),
+                            };
+                            return Err(format!("{}: missing material for {}", label, names).into());
                         }
-                    }
+                    };
+
+                    let id = materials.insert(material);
+                    inserted.insert(name.clone(), id);
+                    ids.push(id);
                 }
-                WorldObject::DirectionalLight {
-                    direction,
-                    width,
-                    color,
-                } => lights.push(Lamp::Directional {
-                    direction: direction.evaluate(eval_context)?,
-                    width: width.evaluate(eval_context)?,
-                    color: programs.compile(&color, expressions)?,
-                }),
-                WorldObject::PointLight { position, color } => lights.push(Lamp::Point(
-                    position.evaluate(eval_context)?,
-                    programs.compile(&color, expressions)?,
-                )),
             }
+
+            Ok(ids)
         }
+        WorldObject::Instance { material, .. } => {
+            // Instances without a material use the prototype's materials.
+            let mut ids = Vec::new();
 
-        println!(
-            "the scene contains {} objects",
-            planes.len() + objects.len()
-        );
-        println!("building BVH... ");
-        let tree = Bvh::new(objects);
-        println!("done building BVH");
+            if let Some(material) = material {
+                let material = Material::from_project(
+                    material,
+                    eval_context,
+                    programs,
+                    expressions,
+                    light_names,
+                )?;
+                ids.push(materials.insert(material));
+            }
 
-        Ok(World {
-            sky,
-            lights,
-            planes,
-            finite_objects: tree,
-        })
+            Ok(ids)
+        }
+        WorldObject::DirectionalLight { .. }
+        | WorldObject::PointLight { .. }
+        | WorldObject::Portal { .. } => Ok(Vec::new()),
     }
+}
 
-    pub fn intersect(&self, ray: Ray3<f32>) -> Option<Intersection> {
-        let mut result = None;
-        let mut closest_distance = f32::INFINITY;
+/// Scales the emission with physical units to the total area of the shapes
+/// with each material. The area is converted from the world's units to
+/// square meters.
+fn convert_emission_units<'p>(
+    shapes: impl Iterator<Item = &'p Shape>,
+    materials: &mut Materials<'p>,
+    scale: f32,
+    programs: ProgramCompiler<'p>,
+    resources: Resources<'p>,
+) -> Result<(), Box<dyn Error>> {
+    let mut areas: HashMap<MaterialId, f32> = HashMap::new();
+    for shape in shapes {
+        *areas.entry(shape.material()).or_insert(0.0) += shape.surface_area() * scale * scale;
+    }
 
-        for plane in &self.planes {
-            if let Some(intersection) = plane.ray_intersect(&ray) {
-                if intersection.distance > DIST_EPSILON && intersection.distance < closest_distance
-                {
-                    closest_distance = intersection.distance;
-                    result = Some(intersection);
+    let mut exe = ExecutionContext::new(resources);
+    materials.convert_emission_units(
+        |material| {
+            areas
+                .get(&material)
+                .cloned()
+                .filter(|&area| area > 0.0 && area.is_finite())
+        },
+        programs,
+        &mut exe,
+    )
+}
+
+fn emissive_shapes<'p>(
+    shapes: impl Iterator<Item = &'p Shape>,
+    materials: &Materials<'p>,
+) -> Vec<Lamp<'p>> {
+    shapes
+        .filter(|shape| materials.get(shape.material()).is_emissive())
+        .map(|shape| Lamp::Shape(shape))
+        .collect()
+}
+
+/// Builds the light tree, with the power of each light estimated from the
+/// average of its colors. Colors that vary over the surface are only
+/// evaluated in one spot, so lights that look black there get the average
+/// power of the others, to not leave them out. Materials with light links get
+/// their own trees, without the lights that don't light them.
+fn light_trees<'p>(
+    lights: &[Lamp<'p>],
+    materials: &Materials<'p>,
+    resources: Resources<'p>,
+) -> (LightTree, HashMap<MaterialId, LightTree>) {
+    let mut exe = ExecutionContext::new(resources);
+    let mut material_colors: HashMap<MaterialId, f32> = HashMap::new();
+    let mut colors = Vec::new();
+
+    let mut bounds: Vec<LightBounds> = lights
+        .iter()
+        .map(|lamp| match *lamp {
+            Lamp::Directional { color, .. } => LightBounds {
+                bounds: Aabb3::new(Point3::origin(), Point3::origin()),
+                power: average_color(color, &mut exe),
+                orientation: Orientation::everywhere(),
+            },
+            Lamp::Point {
+                position,
+                radius,
+                color,
+                ..
+            } => LightBounds {
+                bounds: Aabb3::new(
+                    position.sub_element_wise(radius),
+                    position.add_element_wise(radius),
+                ),
+                power: average_color(color, &mut exe) * 4.0 * std::f32::consts::PI,
+                orientation: Orientation::everywhere(),
+            },
+            Lamp::Shape(shape) => {
+                let material = shape.material();
+                let color = *material_colors.entry(material).or_insert_with(|| {
+                    colors.clear();
+                    materials.get(material).emission_colors(&mut colors);
+                    colors
+                        .iter()
+                        .map(|&color| average_color(color, &mut exe))
+                        .sum()
+                });
+
+                LightBounds {
+                    bounds: shape.bounding_box(),
+                    power: color * shape.surface_area(),
+                    orientation: shape
+                        .flat_normal()
+                        .map_or_else(Orientation::everywhere, Orientation::flat),
                 }
             }
+        })
+        .collect();
+
+    let visible: Vec<f32> = bounds
+        .iter()
+        .map(|light| light.power)
+        .filter(|&power| power > 0.0 && power.is_finite())
+        .collect();
+    let average_power = if visible.is_empty() {
+        1.0
+    } else {
+        visible.iter().sum::<f32>() / visible.len() as f32
+    };
+    for light in &mut bounds {
+        if !(light.power > 0.0 && light.power.is_finite()) {
+            light.power = average_power;
         }
+    }
 
-        let mut intersections = self.finite_objects.ray_intersect(ray);
-        while let Some(&object) = intersections.next(closest_distance) {
-            if let Some(intersection) = object.ray_intersect(&ray) {
-                if intersection.distance > DIST_EPSILON && intersection.distance < closest_distance
-                {
-                    closest_distance = intersection.distance;
-                    result = Some(intersection);
+    // Lights without power are left out of the trees.
+    let linked_trees = materials
+        .ids()
+        .filter(|&id| materials.get(id).has_light_links())
+        .map(|id| {
+            let material = materials.get(id);
+            let bounds = bounds
+                .iter()
+                .zip(lights)
+                .map(|(light, lamp)| LightBounds {
+                    power: if material.is_lit_by(lamp.link(materials)) {
+                        light.power
+                    } else {
+                        0.0
+                    },
+                    ..light.clone()
+                })
+                .collect();
+            (id, LightTree::new(bounds))
+        })
+        .collect();
+
+    (LightTree::new(bounds), linked_trees)
+}
+
+/// The average of a color in the visible spectrum, as seen from straight
+/// above.
+fn average_color<'p>(color: LightProgram<'p>, exe: &mut ExecutionContext<'p>) -> f32 {
+    const STEPS: usize = 32;
+    let (start, end) = (400.0, 700.0);
+    let step_size = (end - start) / STEPS as f32;
+    let mut sum = 0.0;
+
+    for first in (0..STEPS).step_by(LANES) {
+        let input = RenderContext {
+            wavelengths: (first..STEPS.min(first + LANES))
+                .map(|step| start + (step as f32 + 0.5) * step_size)
+                .collect::<Wavelengths>(),
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            incident: Vector3::new(0.0, 0.0, -1.0),
+            texture: Point2::new(0.0, 0.0),
+            footprint: Footprint::default(),
+            position: Point3::origin(),
+        };
+        sum += exe
+            .run(color, &input)
+            .values
+            .iter()
+            .map(|value| value.abs())
+            .sum::<f32>();
+    }
+
+    sum / STEPS as f32
+}
+
+/// Aligns the tangent space with the texture coordinates, as far as a rotation
+/// allows. Mirrored texture coordinates only align the tangent, and degenerate
+/// texture coordinates fall back to an arbitrary tangent.
+fn tangent_space(normal: Vector3<f32>, tangent: Vector3<f32>, bitangent: Vector3<f32>) -> Normal {
+    let mut tangent = tangent - normal * normal.dot(tangent);
+
+    if !(tangent.magnitude2() > 0.0 && tangent.magnitude2().is_finite()) {
+        tangent = bitangent.cross(normal);
+    }
+
+    if !(tangent.magnitude2() > 0.0 && tangent.magnitude2().is_finite()) {
+        return Normal::from_vector(normal);
+    }
+
+    let tangent = tangent.normalize();
+    let bitangent = normal.cross(tangent);
+
+    Normal::new(
+        normal,
+        Matrix3::from_cols(tangent, bitangent, normal).into(),
+    )
+}
+
+/// Calculates a normal for each vertex position, by averaging the normals of
+/// the triangles around it. Larger triangles have more influence.
+fn vertex_normals(
+    obj: &obj::Obj<'_, genmesh::Polygon<obj::IndexTuple>>,
+    positions: Positions<'_>,
+) -> Vec<Vector3<f32>> {
+    let mut normals = vec![Vector3::new(0.0, 0.0, 0.0); positions.len()];
+
+    for object in &obj.objects {
+        for group in &object.groups {
+            for shape in &group.polys {
+                if let genmesh::Polygon::PolyTri(genmesh::Triangle { x, y, z }) = *shape {
+                    let v1: Point3<f32> = positions.get(x.0).into();
+                    let v2: Point3<f32> = positions.get(y.0).into();
+                    let v3: Point3<f32> = positions.get(z.0).into();
+                    let normal = (v2 - v1).cross(v3 - v1);
+
+                    normals[x.0] += normal;
+                    normals[y.0] += normal;
+                    normals[z.0] += normal;
                 }
             }
         }
-
-        result
     }
 
-    pub fn pick_lamp(&self, rng: &mut impl Rng) -> Option<(&Lamp, f32)> {
-        self.lights
-            .get(rng.gen_range(0, self.lights.len()))
-            .map(|l| (l, 1.0 / self.lights.len() as f32))
-    }
+    normals
 }
 
-fn make_triangle<'p, M: obj::GenPolygon>(
+/// Reads the vertices of a triangle, with interpolated normals if
+/// `vertex_normals` is set, and flat normals otherwise. The normals from the
+/// file are preferred over `vertex_normals`.
+fn mesh_vertices<M: obj::GenPolygon>(
     obj: &obj::Obj<'_, M>,
-    obj::IndexTuple(v1, t1, n1): obj::IndexTuple,
-    obj::IndexTuple(v2, t2, n2): obj::IndexTuple,
-    obj::IndexTuple(v3, t3, n3): obj::IndexTuple,
-    material: Arc<Material<'p>>,
-) -> Shape<'p> {
-    let v1 = obj.position[v1].into();
-    let v2 = obj.position[v2].into();
-    let v3 = obj.position[v3].into();
-
-    let (n1, n2, n3) = match (n1, n2, n3) {
-        (Some(n1), Some(n2), Some(n3)) => {
+    positions: Positions<'_>,
+    obj::IndexTuple(i1, t1, n1): obj::IndexTuple,
+    obj::IndexTuple(i2, t2, n2): obj::IndexTuple,
+    obj::IndexTuple(i3, t3, n3): obj::IndexTuple,
+    vertex_normals: Option<&[Vector3<f32>]>,
+) -> [MeshVertex; 3] {
+    let v1 = positions.get(i1).into();
+    let v2 = positions.get(i2).into();
+    let v3 = positions.get(i3).into();
+
+    let a: Vector3<_> = v2 - v1;
+    let b = v3 - v1;
+    let face_normal = a.cross(b).normalize();
+
+    let (n1, n2, n3) = match (vertex_normals, n1, n2, n3) {
+        (None, ..) => (face_normal, face_normal, face_normal),
+        (Some(_), Some(n1), Some(n2), Some(n3)) => {
             let n1 = obj.normal[n1].into();
             let n2 = obj.normal[n2].into();
             let n3 = obj.normal[n3].into();
             (n1, n2, n3)
         }
-        _ => {
-            let a: Vector3<_> = v2 - v1;
-            let b = v3 - v1;
-            let normal = a.cross(b).normalize();
-            (normal, normal, normal)
+        (Some(normals), ..) => {
+            let vertex_normal = |index: usize| {
+                let normal = normals[index];
+                if normal.magnitude2() > 0.0 {
+                    normal.normalize()
+                } else {
+                    face_normal
+                }
+            };
+            (vertex_normal(i1), vertex_normal(i2), vertex_normal(i3))
         }
     };
 
-    let t1 = t1
-        .map(|t1| obj.texture[t1].into())
-        .unwrap_or(Point2::origin());
-    let t2 = t2
-        .map(|t2| obj.texture[t2].into())
-        .unwrap_or(Point2::origin());
-    let t3 = t3
-        .map(|t3| obj.texture[t3].into())
-        .unwrap_or(Point2::origin());
+    let (t1, t2, t3) = match (t1, t2, t3) {
+        (Some(t1), Some(t2), Some(t3)) => (
+            obj.texture[t1].into(),
+            obj.texture[t2].into(),
+            obj.texture[t3].into(),
+        ),
+        // Faces without texture coordinates are textured with their
+        // barycentric coordinates.
+        _ => (
+            Point2::new(0.0, 0.0),
+            Point2::new(1.0, 0.0),
+            Point2::new(0.0, 1.0),
+        ),
+    };
+
+    [
+        MeshVertex {
+            position: v1,
+            normal: n1,
+            texture: t1,
+        },
+        MeshVertex {
+            position: v2,
+            normal: n2,
+            texture: t2,
+        },
+        MeshVertex {
+            position: v3,
+            normal: n3,
+            texture: t3,
+        },
+    ]
+}
 
-    let delta_position1 = v2 - v1;
-    let delta_position2 = v3 - v1;
+fn make_triangle([v1, v2, v3]: [MeshVertex; 3], material: MaterialId) -> Shape {
+    let delta_position1 = v2.position - v1.position;
+    let delta_position2 = v3.position - v1.position;
 
-    let delta_texture1 = t2 - t1;
-    let delta_texture2 = t3 - t1;
+    let delta_texture1 = v2.texture - v1.texture;
+    let delta_texture2 = v3.texture - v1.texture;
 
     let r = 1.0 / (delta_texture1.x * delta_texture2.y - delta_texture1.y * delta_texture2.x);
     let tangent = (delta_position1 * delta_texture2.y - delta_position2 * delta_texture1.y) * r;
     let bitangent = (delta_position2 * delta_texture1.x - delta_position1 * delta_texture2.x) * r;
 
-    Triangle {
+    Shape::Triangle(Triangle {
         v1: Vertex {
-            position: v1,
-            normal: Normal::new(n1, Matrix3::from_cols(tangent, bitangent, n1).into()),
-            texture: t1,
+            position: v1.position,
+            normal: tangent_space(v1.normal, tangent, bitangent),
+            texture: v1.texture,
         },
         v2: Vertex {
-            position: v2,
-            normal: Normal::new(n2, Matrix3::from_cols(tangent, bitangent, n2).into()),
-            texture: t2,
+            position: v2.position,
+            normal: tangent_space(v2.normal, tangent, bitangent),
+            texture: v2.texture,
         },
         v3: Vertex {
-            position: v3,
-            normal: Normal::new(n3, Matrix3::from_cols(tangent, bitangent, n3).into()),
-            texture: t3,
+            position: v3.position,
+            normal: tangent_space(v3.normal, tangent, bitangent),
+            texture: v3.texture,
         },
         edge1: delta_position1,
         edge2: delta_position2,
         material,
-    }
+    })
 }